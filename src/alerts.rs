@@ -0,0 +1,567 @@
+use std::{fmt::Display, sync::OnceLock};
+
+use axum::{
+    extract::{Query, State},
+    response::Response,
+};
+use chrono::{DateTime, Duration, Utc};
+use redis::AsyncCommands;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache_schema::record_key,
+    history::{load_history, RiskHistoryPoint},
+    redis_connection::shared_connection,
+    registry::ProtocolAssessment,
+    risk_model::{json_response, timings_requested, Protocol, RiskCalculationError, RiskResponse},
+    state::AppState,
+    timings::{timed, with_timings, Timing},
+};
+
+/// Rules used when `DELTA_ALERTS` isn't set
+const DEFAULT_DELTA_ALERTS: &str = "utilization_rate:+15:6,deposit_concentration:x2:24";
+/// Fired alerts kept in the log, older ones are dropped
+const MAX_STORED_ALERTS: isize = 500;
+/// Alerts returned when `limit` isn't given
+const DEFAULT_ALERTS_LIMIT: usize = 50;
+/// Webhooks not answering by then are given up on, so they don't hold up the refresh
+const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+/// Log of fired alerts, newest first
+///
+/// Once the history they were computed from is gone alerts can't be
+/// recomputed, so like the penalties the key isn't versioned.
+fn alerts_key() -> String {
+    record_key("risk_alerts")
+}
+
+/// Set while a rule's alert is in effect for a scope, so it only fires once per window
+fn active_key(scope: &str, rule: &DeltaRule) -> String {
+    format!("{}:active:{}:{}", alerts_key(), scope, rule)
+}
+
+static DELTA_RULES: OnceLock<Vec<DeltaRule>> = OnceLock::new();
+static ALERT_WEBHOOK: OnceLock<AlertWebhook> = OnceLock::new();
+
+/// Reads the webhook URL in `var`, `None` when it isn't set
+pub fn webhook_url_from_env(var: &str) -> Result<Option<String>, RiskCalculationError> {
+    match std::env::var(var) {
+        Ok(url) => {
+            reqwest::Url::parse(&url)
+                .map_err(|e| RiskCalculationError::ParseError(format!("{}: {}", var, e)))?;
+            Ok(Some(url))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Posts `payload` as JSON to `url`, its summary in `text` for chat webhooks
+pub async fn post_webhook(
+    url: &str,
+    text: String,
+    payload: &impl Serialize,
+) -> Result<(), RiskCalculationError> {
+    reqwest::Client::new()
+        .post(url)
+        .timeout(std::time::Duration::from_secs(WEBHOOK_TIMEOUT_SECS))
+        .json(&serde_json::json!({ "text": text, "payload": payload }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(RiskCalculationError::from)?;
+    Ok(())
+}
+
+/// Where fired alerts are pushed, from `ALERT_WEBHOOK_URL`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AlertWebhook {
+    /// Alerts are only stored when it isn't set
+    pub url: Option<String>,
+}
+
+impl AlertWebhook {
+    /// The webhook read at first use
+    pub fn global() -> &'static Self {
+        ALERT_WEBHOOK.get_or_init(|| Self::from_env().expect("ALERT_WEBHOOK_URL must be valid"))
+    }
+
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        Ok(AlertWebhook {
+            url: webhook_url_from_env("ALERT_WEBHOOK_URL")?,
+        })
+    }
+
+    /// Pushes an alert, failures are only logged since the alert is stored
+    pub async fn notify(&self, text: String, alert: &impl Serialize) {
+        let Some(url) = &self.url else {
+            return;
+        };
+        if let Err(e) = post_webhook(url, text, alert).await {
+            tracing::error!("Failed to push alert: {}", e);
+        }
+    }
+}
+
+/// Sub-metric a rule watches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertMetric {
+    /// In percent
+    UtilizationRate,
+    /// Share of the largest deposit, between 0 and 1
+    DepositConcentration,
+    LiquidityRisk,
+    VolatilityRisk,
+    ProtocolRisk,
+    OracleRisk,
+    OverallRisk,
+}
+
+impl AlertMetric {
+    const ALL: [AlertMetric; 7] = [
+        AlertMetric::UtilizationRate,
+        AlertMetric::DepositConcentration,
+        AlertMetric::LiquidityRisk,
+        AlertMetric::VolatilityRisk,
+        AlertMetric::ProtocolRisk,
+        AlertMetric::OracleRisk,
+        AlertMetric::OverallRisk,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertMetric::UtilizationRate => "utilization_rate",
+            AlertMetric::DepositConcentration => "deposit_concentration",
+            AlertMetric::LiquidityRisk => "liquidity_risk",
+            AlertMetric::VolatilityRisk => "volatility_risk",
+            AlertMetric::ProtocolRisk => "protocol_risk",
+            AlertMetric::OracleRisk => "oracle_risk",
+            AlertMetric::OverallRisk => "overall_risk",
+        }
+    }
+
+    pub fn value(&self, metrics: &RiskResponse) -> f64 {
+        match self {
+            AlertMetric::UtilizationRate => metrics.liquidity_risk.utilization_rate,
+            AlertMetric::DepositConcentration => metrics.liquidity_risk.deposit_concentration,
+            AlertMetric::LiquidityRisk => metrics.liquidity_risk.liquidity_risk,
+            AlertMetric::VolatilityRisk => metrics.volatility_risk.volatility_risk,
+            AlertMetric::ProtocolRisk => metrics.protocol_risk.protocol_risk,
+            AlertMetric::OracleRisk => metrics.oracle_risk.oracle_risk,
+            AlertMetric::OverallRisk => metrics.overall_risk.overall_risk,
+        }
+    }
+}
+
+/// How much a metric has to move for a rule to fire
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "threshold", rename_all = "snake_case")]
+pub enum DeltaChange {
+    /// Difference in the metric's unit, negative thresholds watch for drops
+    Points(f64),
+    /// Ratio to the earlier value, thresholds below 1 watch for drops
+    Ratio(f64),
+}
+
+/// Fires when `metric` moved by `change` within `window_hours`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DeltaRule {
+    pub metric: AlertMetric,
+    pub change: DeltaChange,
+    pub window_hours: i64,
+}
+
+impl Display for DeltaRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.change {
+            DeltaChange::Points(points) => write!(
+                f,
+                "{}:{:+}:{}",
+                self.metric.as_str(),
+                points,
+                self.window_hours
+            ),
+            DeltaChange::Ratio(ratio) => {
+                write!(
+                    f,
+                    "{}:x{}:{}",
+                    self.metric.as_str(),
+                    ratio,
+                    self.window_hours
+                )
+            }
+        }
+    }
+}
+
+impl DeltaRule {
+    /// The configured rules
+    pub fn global() -> &'static [DeltaRule] {
+        DELTA_RULES.get_or_init(|| Self::from_env().expect("DELTA_ALERTS must be valid"))
+    }
+
+    /// Reads `DELTA_ALERTS` as comma separated `metric:change:window_hours` rules
+    ///
+    /// `change` is `+15` or `-15` points, or `x2` for a ratio, e.g.
+    /// `utilization_rate:+15:6,deposit_concentration:x2:24`.
+    pub fn from_env() -> Result<Vec<Self>, RiskCalculationError> {
+        let rules = std::env::var("DELTA_ALERTS").unwrap_or(DEFAULT_DELTA_ALERTS.to_string());
+        rules
+            .split(',')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+
+    pub fn parse(rule: &str) -> Result<Self, RiskCalculationError> {
+        let parse_error = || {
+            RiskCalculationError::ParseError(format!(
+                "DELTA_ALERTS rules must be metric:(+|-)points|x ratio:window_hours: {:?}",
+                rule
+            ))
+        };
+        let [metric, change, window_hours] = rule.trim().split(':').collect::<Vec<_>>()[..] else {
+            return Err(parse_error());
+        };
+        let metric = AlertMetric::ALL
+            .into_iter()
+            .find(|candidate| candidate.as_str() == metric.trim())
+            .ok_or_else(parse_error)?;
+        let change = change.trim();
+        let change = match change.strip_prefix('x') {
+            Some(ratio) => match ratio.parse::<f64>() {
+                Ok(ratio) if ratio > 0.0 && ratio != 1.0 => DeltaChange::Ratio(ratio),
+                _ => return Err(parse_error()),
+            },
+            None if change.starts_with(['+', '-']) => match change.parse::<f64>() {
+                Ok(points) if points != 0.0 => DeltaChange::Points(points),
+                _ => return Err(parse_error()),
+            },
+            None => return Err(parse_error()),
+        };
+        let window_hours: i64 = window_hours.trim().parse().map_err(|_| parse_error())?;
+        if window_hours <= 0 {
+            return Err(parse_error());
+        }
+        Ok(DeltaRule {
+            metric,
+            change,
+            window_hours,
+        })
+    }
+
+    /// Compares the latest point to the extreme of the window before it
+    ///
+    /// Rises are measured from the window's lowest value and drops from its
+    /// highest, so a move within the window fires even when it started after
+    /// the window's first point. `history` is oldest first and may contain
+    /// `latest` itself.
+    pub fn evaluate(
+        &self,
+        history: &[RiskHistoryPoint],
+        latest: &RiskHistoryPoint,
+    ) -> Option<DeltaObservation> {
+        let window_start = latest.computed_at - Duration::hours(self.window_hours);
+        let rising = match self.change {
+            DeltaChange::Points(points) => points > 0.0,
+            DeltaChange::Ratio(ratio) => ratio > 1.0,
+        };
+        let window = history
+            .iter()
+            .filter(|point| {
+                point.computed_at >= window_start && point.computed_at < latest.computed_at
+            })
+            // Ratios to nothing are meaningless
+            .filter(|point| {
+                !matches!(self.change, DeltaChange::Ratio(_))
+                    || self.metric.value(&point.risk_metrics) > 0.0
+            });
+        let by_value = |a: &&RiskHistoryPoint, b: &&RiskHistoryPoint| {
+            self.metric
+                .value(&a.risk_metrics)
+                .total_cmp(&self.metric.value(&b.risk_metrics))
+        };
+        let baseline = if rising {
+            window.min_by(by_value)
+        } else {
+            window.max_by(by_value)
+        }?;
+        let from_value = self.metric.value(&baseline.risk_metrics);
+        let to_value = self.metric.value(&latest.risk_metrics);
+        let fired = match self.change {
+            DeltaChange::Points(points) if points > 0.0 => to_value - from_value >= points,
+            DeltaChange::Points(points) => to_value - from_value <= points,
+            // Ratios to nothing are meaningless
+            DeltaChange::Ratio(_) if from_value <= 0.0 => false,
+            DeltaChange::Ratio(ratio) if ratio > 1.0 => to_value / from_value >= ratio,
+            DeltaChange::Ratio(ratio) => to_value / from_value <= ratio,
+        };
+        fired.then_some(DeltaObservation {
+            from: baseline.computed_at,
+            from_value,
+            to_value,
+        })
+    }
+}
+
+/// The move that made a rule fire
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DeltaObservation {
+    /// When the earlier value was computed
+    pub from: DateTime<Utc>,
+    pub from_value: f64,
+    pub to_value: f64,
+}
+
+/// A rule that fired for a protocol
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DeltaAlert {
+    pub protocol: Protocol,
+    pub scope: String,
+    pub rule: DeltaRule,
+    pub fired_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub observation: DeltaObservation,
+}
+
+/// Evaluates every configured rule against the history of the assessed protocols
+///
+/// Called by the refresher once the assessments are in the history. An alert
+/// fires once per rule and scope within the rule's window, fired alerts are
+/// logged, pushed to the [`AlertWebhook`] and stored.
+pub async fn evaluate_delta_alerts(
+    redis_client: &redis::Client,
+    computed_at: DateTime<Utc>,
+    assessments: &[ProtocolAssessment],
+) -> Result<Vec<DeltaAlert>, RiskCalculationError> {
+    let rules = DeltaRule::global();
+    let Some(longest_window) = rules.iter().map(|rule| rule.window_hours).max() else {
+        return Ok(Vec::new());
+    };
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+
+    let mut fired = Vec::new();
+    for assessment in assessments {
+        let history = load_history(
+            redis_client,
+            &assessment.scope,
+            computed_at - Duration::hours(longest_window),
+            computed_at,
+        )
+        .await?;
+        let latest = RiskHistoryPoint {
+            computed_at,
+            risk_metrics: assessment.risk_metrics.clone(),
+        };
+        for rule in rules {
+            let Some(observation) = rule.evaluate(&history, &latest) else {
+                continue;
+            };
+            let newly_active: bool = redis::cmd("SET")
+                .arg(active_key(&assessment.scope, rule))
+                .arg(computed_at.timestamp())
+                .arg("NX")
+                .arg("EX")
+                .arg(rule.window_hours * 3600)
+                .query_async::<Option<String>>(&mut connection)
+                .await
+                .map_err(RiskCalculationError::from)?
+                .is_some();
+            if !newly_active {
+                continue;
+            }
+            let summary = format!(
+                "{:?} {} moved from {:.4} to {:.4} since {} ({})",
+                assessment.protocol,
+                rule.metric.as_str(),
+                observation.from_value,
+                observation.to_value,
+                observation.from,
+                rule
+            );
+            tracing::warn!("{}", summary);
+            let alert = DeltaAlert {
+                protocol: assessment.protocol.clone(),
+                scope: assessment.scope.clone(),
+                rule: *rule,
+                fired_at: computed_at,
+                observation,
+            };
+            AlertWebhook::global().notify(summary, &alert).await;
+            fired.push(alert);
+        }
+    }
+
+    if !fired.is_empty() {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for alert in &fired {
+            let body = serde_json::to_string(alert).map_err(RiskCalculationError::SerdeError)?;
+            pipe.lpush(alerts_key(), body).ignore();
+        }
+        pipe.ltrim(alerts_key(), 0, MAX_STORED_ALERTS - 1).ignore();
+        let _: () = pipe
+            .query_async(&mut connection)
+            .await
+            .map_err(RiskCalculationError::from)?;
+    }
+    Ok(fired)
+}
+
+/// The most recently fired alerts, newest first
+pub async fn load_alerts(
+    redis_client: &redis::Client,
+    limit: usize,
+) -> Result<Vec<DeltaAlert>, RiskCalculationError> {
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let alerts: Vec<String> = timed(
+        Timing::CacheRead,
+        connection.lrange(alerts_key(), 0, limit as isize - 1),
+    )
+    .await
+    .map_err(RiskCalculationError::from)?;
+    alerts
+        .iter()
+        .map(|alert| serde_json::from_str(alert).map_err(RiskCalculationError::SerdeError))
+        .collect()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AlertsQuery {
+    /// Number of alerts to return, 50 by default
+    pub limit: Option<usize>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+/// Body of `/alerts`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct AlertsResponse {
+    /// The configured rules
+    pub rules: Vec<DeltaRule>,
+    /// Newest first
+    pub alerts: Vec<DeltaAlert>,
+}
+
+pub async fn alerts(State(state): State<AppState>, Query(query): Query<AlertsQuery>) -> Response {
+    let (result, timings) = with_timings(async {
+        let alerts = load_alerts(
+            &state.redis_client,
+            query.limit.unwrap_or(DEFAULT_ALERTS_LIMIT),
+        )
+        .await?;
+        Ok::<_, RiskCalculationError>(AlertsResponse {
+            rules: DeltaRule::global().to_vec(),
+            alerts,
+        })
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::risk_model::{LiquidityRiskMetrics, SCHEMA_VERSION};
+
+    fn point(hour: i64, utilization_rate: f64, deposit_concentration: f64) -> RiskHistoryPoint {
+        RiskHistoryPoint {
+            computed_at: DateTime::from_timestamp(1_714_521_600 + hour * 3600, 0).unwrap(),
+            risk_metrics: RiskResponse {
+                schema_version: SCHEMA_VERSION,
+                liquidity_risk: LiquidityRiskMetrics {
+                    total_borrows: utilization_rate,
+                    total_supply: 100.0,
+                    utilization_rate,
+                    deposit_concentration,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_parse_delta_rules() {
+        let rule = DeltaRule::parse("utilization_rate:+15:6").unwrap();
+        assert_eq!(rule.metric, AlertMetric::UtilizationRate);
+        assert_eq!(rule.change, DeltaChange::Points(15.0));
+        assert_eq!(rule.window_hours, 6);
+        assert_eq!(rule.to_string(), "utilization_rate:+15:6");
+
+        let rule = DeltaRule::parse(" deposit_concentration:x2:24 ").unwrap();
+        assert_eq!(rule.change, DeltaChange::Ratio(2.0));
+        assert_eq!(rule.to_string(), "deposit_concentration:x2:24");
+
+        for invalid in [
+            "utilization_rate:15:6",
+            "utilization:+15:6",
+            "utilization_rate:x1:6",
+            "utilization_rate:+15:0",
+            "utilization_rate:+15",
+        ] {
+            assert!(DeltaRule::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_evaluate_delta_rules() {
+        let history = [
+            point(0, 60.0, 0.1),
+            point(2, 65.0, 0.1),
+            point(4, 70.0, 0.15),
+            point(6, 76.0, 0.22),
+        ];
+        let latest = &history[3];
+
+        // Compared to the lowest point within the window
+        let rise = DeltaRule::parse("utilization_rate:+15:6").unwrap();
+        let observation = rise.evaluate(&history, latest).unwrap();
+        assert_eq!(observation.from, history[0].computed_at);
+        assert_eq!(observation.from_value, 60.0);
+        assert_eq!(observation.to_value, 76.0);
+        // The first point is outside a 4 hour window
+        assert!(DeltaRule::parse("utilization_rate:+15:4")
+            .unwrap()
+            .evaluate(&history, latest)
+            .is_none());
+
+        let doubled = DeltaRule::parse("deposit_concentration:x2:24").unwrap();
+        assert!(doubled.evaluate(&history, latest).is_some());
+        assert!(doubled.evaluate(&history[2..], latest).is_none());
+        let halved = DeltaRule::parse("deposit_concentration:x0.5:24").unwrap();
+        assert!(halved.evaluate(&history, latest).is_none());
+        let drop = DeltaRule::parse("utilization_rate:-10:6").unwrap();
+        assert!(drop.evaluate(&history, latest).is_none());
+
+        // Nothing to compare to
+        assert!(rise.evaluate(&history[3..], latest).is_none());
+
+        // A dip after the window's first point is the baseline of a rise, a
+        // peak after it the baseline of a drop
+        let history = [
+            point(0, 60.0, 0.1),
+            point(2, 55.0, 0.3),
+            point(4, 72.0, 0.1),
+            point(6, 71.0, 0.12),
+        ];
+        let latest = &history[3];
+        let observation = rise.evaluate(&history, latest).unwrap();
+        assert_eq!(observation.from, history[1].computed_at);
+        assert_eq!(observation.from_value, 55.0);
+        let halved = DeltaRule::parse("deposit_concentration:x0.5:24").unwrap();
+        assert_eq!(
+            halved.evaluate(&history, latest).unwrap().from,
+            history[1].computed_at
+        );
+    }
+}
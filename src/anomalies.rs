@@ -0,0 +1,370 @@
+use std::sync::OnceLock;
+
+use axum::{
+    extract::{Path, Query, State},
+    response::Response,
+};
+use chrono::{DateTime, Duration, Utc};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alerts::AlertWebhook,
+    cache_schema::versioned_key,
+    kamino::reserve::KaminoReserveConfig,
+    market_history::MarketPoint,
+    redis_connection::shared_connection,
+    registry::{ProtocolRegistry, RegisteredProtocol},
+    risk_model::{json_response, timings_requested, Protocol, RiskCalculationError},
+    state::AppState,
+    timings::{timed, with_timings, Timing},
+};
+
+static ANOMALY_CONFIG: OnceLock<AnomalyConfig> = OnceLock::new();
+
+/// How long flagged anomalies are kept, like the market history
+const ANOMALY_RETENTION_DAYS: i64 = 90;
+/// Range returned when `from` isn't given
+const DEFAULT_ANOMALY_DAYS: i64 = 7;
+/// Points newly evaluated by each refresh, the history is re-fetched over a day
+const EVALUATED_POINTS: usize = 24;
+/// Trailing deviations below this are a flat series, which nothing stands out of
+const MIN_STD_DEV: f64 = 1e-9;
+
+/// When a utilization point is flagged, from the environment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnomalyConfig {
+    /// Standard deviations from the trailing mean from which a point is flagged
+    pub z_threshold: f64,
+    /// Points the mean and deviation are taken over, hours of the Kamino
+    /// history and days of marginfi's
+    pub window: usize,
+}
+
+impl Default for AnomalyConfig {
+    fn default() -> Self {
+        AnomalyConfig {
+            z_threshold: 3.0,
+            window: 24,
+        }
+    }
+}
+
+impl AnomalyConfig {
+    /// The configuration read at first use
+    pub fn global() -> &'static Self {
+        ANOMALY_CONFIG.get_or_init(|| {
+            Self::from_env().expect("utilization anomaly configuration must be valid")
+        })
+    }
+
+    /// Reads `ANOMALY_Z_SCORE` and `ANOMALY_WINDOW`, the defaults where not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let default = Self::default();
+        let z_threshold = match std::env::var("ANOMALY_Z_SCORE") {
+            Ok(value) => value
+                .parse::<f64>()
+                .ok()
+                .filter(|z| z.is_finite() && *z > 0.0)
+                .ok_or_else(|| {
+                    RiskCalculationError::ParseError(format!(
+                        "ANOMALY_Z_SCORE must be a positive number, got {:?}",
+                        value
+                    ))
+                })?,
+            Err(_) => default.z_threshold,
+        };
+        let window = match std::env::var("ANOMALY_WINDOW") {
+            Ok(value) => value
+                .parse::<usize>()
+                .ok()
+                .filter(|window| *window >= 2)
+                .ok_or_else(|| {
+                    RiskCalculationError::ParseError(format!(
+                        "ANOMALY_WINDOW must be at least 2 points, got {:?}",
+                        value
+                    ))
+                })?,
+            Err(_) => default.window,
+        };
+        Ok(AnomalyConfig {
+            z_threshold,
+            window,
+        })
+    }
+}
+
+/// A point whose utilization is far from the trailing ones
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UtilizationAnomaly {
+    pub timestamp: DateTime<Utc>,
+    pub utilization_percent: f64,
+    pub trailing_mean: f64,
+    pub trailing_std_dev: f64,
+    /// Negative for drops
+    pub z_score: f64,
+}
+
+/// Flags the points of `points`, oldest first, deviating more than the
+/// threshold from the `window` points before them
+///
+/// The first `window` points have no full window and are never flagged.
+pub fn detect_spikes(points: &[MarketPoint], config: &AnomalyConfig) -> Vec<UtilizationAnomaly> {
+    points
+        .windows(config.window + 1)
+        .filter_map(|window| {
+            let (point, trailing) = window.split_last()?;
+            let n = trailing.len() as f64;
+            let mean = trailing
+                .iter()
+                .map(|point| point.utilization_percent)
+                .sum::<f64>()
+                / n;
+            let variance = trailing
+                .iter()
+                .map(|point| (point.utilization_percent - mean).powi(2))
+                .sum::<f64>()
+                / n;
+            let std_dev = variance.sqrt();
+            if std_dev < MIN_STD_DEV {
+                return None;
+            }
+            let z_score = (point.utilization_percent - mean) / std_dev;
+            (z_score.abs() > config.z_threshold).then_some(UtilizationAnomaly {
+                timestamp: point.timestamp,
+                utilization_percent: point.utilization_percent,
+                trailing_mean: mean,
+                trailing_std_dev: std_dev,
+                z_score,
+            })
+        })
+        .collect()
+}
+
+/// Sorted set of a scope's anomalies, scored by the unix timestamp of each
+fn anomalies_key(scope: &str) -> String {
+    versioned_key(&format!("anomalies:{}", scope))
+}
+
+/// Reads the anomalies of a scope between `from` and `to` (inclusive), oldest first
+pub async fn load_anomalies(
+    redis_client: &redis::Client,
+    scope: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<UtilizationAnomaly>, RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let anomalies: Vec<String> = timed(
+        Timing::CacheRead,
+        connection.zrangebyscore(anomalies_key(scope), from.timestamp(), to.timestamp()),
+    )
+    .await
+    .map_err(RiskCalculationError::from)?;
+    anomalies
+        .iter()
+        .map(|anomaly| serde_json::from_str(anomaly).map_err(RiskCalculationError::SerdeError))
+        .collect()
+}
+
+/// Stores `anomalies`, replacing stored ones of the same timestamps
+async fn record_anomalies(
+    redis_client: &redis::Client,
+    scope: &str,
+    anomalies: &[UtilizationAnomaly],
+) -> Result<(), RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let key = anomalies_key(scope);
+    let retention_start = Utc::now() - Duration::days(ANOMALY_RETENTION_DAYS);
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for anomaly in anomalies {
+        let timestamp = anomaly.timestamp.timestamp();
+        pipe.zrembyscore(&key, timestamp, timestamp)
+            .ignore()
+            .zadd(
+                &key,
+                serde_json::to_string(anomaly).map_err(RiskCalculationError::SerdeError)?,
+                timestamp,
+            )
+            .ignore();
+    }
+    pipe.zrembyscore(&key, "-inf", format!("({}", retention_start.timestamp()))
+        .ignore();
+    let _: () = pipe
+        .query_async(&mut connection)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    Ok(())
+}
+
+/// Flags utilization spikes in the recent market history of a protocol
+///
+/// Anomalies that weren't flagged before are stored and pushed to the
+/// [`AlertWebhook`], re-evaluated ones only replace the stored ones.
+async fn detect_protocol_anomalies(
+    redis_client: &redis::Client,
+    registered: &RegisteredProtocol,
+    config: &AnomalyConfig,
+) -> Result<Vec<UtilizationAnomaly>, RiskCalculationError> {
+    let scope = registered.scope();
+    let protocol = registered.protocol();
+    // Daily for marginfi, so the lookback covers as many points as for Kamino
+    let point_length = match protocol {
+        Protocol::Marginfy => Duration::days(1),
+        _ => Duration::hours(1),
+    };
+    let from = Utc::now() - point_length * (config.window + EVALUATED_POINTS) as i32;
+    let points = registered.market_history(from).await?;
+    let anomalies = detect_spikes(&points, config);
+    let Some(first) = anomalies.first() else {
+        return Ok(Vec::new());
+    };
+    let flagged = load_anomalies(redis_client, &scope, first.timestamp, Utc::now()).await?;
+    record_anomalies(redis_client, &scope, &anomalies).await?;
+    let new: Vec<UtilizationAnomaly> = anomalies
+        .into_iter()
+        .filter(|anomaly| {
+            !flagged
+                .iter()
+                .any(|flagged| flagged.timestamp == anomaly.timestamp)
+        })
+        .collect();
+    for anomaly in &new {
+        let summary = format!(
+            "{:?} utilization {:.2}% at {} is {:.1} standard deviations from the trailing {:.2}%",
+            protocol,
+            anomaly.utilization_percent,
+            anomaly.timestamp,
+            anomaly.z_score,
+            anomaly.trailing_mean
+        );
+        tracing::warn!("{}", summary);
+        AlertWebhook::global()
+            .notify(
+                summary,
+                &serde_json::json!({
+                    "protocol": protocol,
+                    "scope": scope,
+                    "anomaly": anomaly,
+                }),
+            )
+            .await;
+    }
+    Ok(new)
+}
+
+/// Flags utilization spikes of every protocol, called by the hourly refresh
+///
+/// A protocol failing is logged, the others are still evaluated.
+pub async fn detect_anomalies(
+    redis_client: &redis::Client,
+    registry: &ProtocolRegistry,
+) -> Vec<UtilizationAnomaly> {
+    let config = AnomalyConfig::global();
+    let mut flagged = Vec::new();
+    for protocol in registry.protocols() {
+        let Some(registered) = registry.registered(&protocol) else {
+            continue;
+        };
+        match detect_protocol_anomalies(redis_client, registered, config).await {
+            Ok(anomalies) => flagged.extend(anomalies),
+            Err(e) => tracing::error!("Failed to detect {:?} anomalies: {}", protocol, e),
+        }
+    }
+    flagged
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AnomaliesQuery {
+    /// Start of the range, defaults to a week before `to`
+    pub from: Option<DateTime<Utc>>,
+    /// End of the range, defaults to now
+    pub to: Option<DateTime<Utc>>,
+    /// Kamino lending market, requires `reserve`
+    pub market: Option<String>,
+    /// Kamino reserve, requires `market`
+    pub reserve: Option<String>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+/// Utilization spikes flagged for a protocol, oldest first
+pub async fn protocol_anomalies(
+    State(state): State<AppState>,
+    Path(protocol): Path<String>,
+    Query(query): Query<AnomaliesQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let protocol = Protocol::from_param(&protocol)?;
+        let scope = RegisteredProtocol::for_protocol(
+            &protocol,
+            state.redis_client.clone(),
+            KaminoReserveConfig::from_params(
+                query.market.as_deref(),
+                query.reserve.as_deref(),
+                &state.config.kamino_reserve,
+            )?,
+        )?
+        .scope();
+        let to = query.to.unwrap_or_else(Utc::now);
+        let from = query
+            .from
+            .unwrap_or(to - Duration::days(DEFAULT_ANOMALY_DAYS));
+        let anomalies = load_anomalies(&state.redis_client, &scope, from, to).await?;
+        Ok::<_, RiskCalculationError>(serde_json::json!({
+            "protocol": protocol,
+            "scope": scope,
+            "config": {
+                "z_threshold": AnomalyConfig::global().z_threshold,
+                "window": AnomalyConfig::global().window,
+            },
+            "anomalies": anomalies,
+        }))
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(utilization: &[f64]) -> Vec<MarketPoint> {
+        utilization
+            .iter()
+            .enumerate()
+            .map(|(hour, utilization_percent)| MarketPoint {
+                timestamp: DateTime::from_timestamp(1_714_521_600 + hour as i64 * 3600, 0).unwrap(),
+                supply_apy_percent: 5.0,
+                utilization_percent: *utilization_percent,
+                tvl_usd: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_detect_spikes() {
+        let config = AnomalyConfig {
+            z_threshold: 3.0,
+            window: 4,
+        };
+        let series = points(&[80.0, 81.0, 79.0, 80.0, 80.5, 95.0, 80.0]);
+        let anomalies = detect_spikes(&series, &config);
+        assert_eq!(anomalies.len(), 1);
+        let spike = &anomalies[0];
+        assert_eq!(spike.timestamp, series[5].timestamp);
+        assert!(spike.z_score > 3.0);
+        assert!((spike.trailing_mean - 80.125).abs() < 1e-9);
+
+        // Flat trailing windows have no deviation to compare with
+        assert!(detect_spikes(&points(&[80.0, 80.0, 80.0, 80.0, 90.0]), &config).is_empty());
+        assert!(detect_spikes(&series[..4], &config).is_empty());
+        let drop = detect_spikes(&points(&[80.0, 81.0, 79.0, 80.0, 60.0]), &config);
+        assert!(drop[0].z_score < -3.0);
+    }
+}
@@ -0,0 +1,173 @@
+use std::{fmt, str::FromStr};
+
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{cluster::Cluster, risk_model::RiskCalculationError};
+
+/// Token a portfolio holds, amounts of different assets are never added up
+///
+/// Serialized as its lowercase symbol so assets can key maps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum Asset {
+    /// What portfolios held before they had more than one asset
+    #[default]
+    Usdc,
+    Sol,
+}
+
+impl Asset {
+    pub const ALL: [Asset; 2] = [Asset::Usdc, Asset::Sol];
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Asset::Usdc => "USDC",
+            Asset::Sol => "SOL",
+        }
+    }
+
+    /// Decimals of the mint, native units per token are 10^decimals
+    pub fn decimals(&self) -> u8 {
+        match self {
+            Asset::Usdc => 6,
+            Asset::Sol => 9,
+        }
+    }
+
+    /// Mint of the asset on `cluster`, wrapped SOL for SOL
+    pub fn mint_on(&self, cluster: Cluster) -> &'static str {
+        match (self, cluster) {
+            (Asset::Usdc, Cluster::MainnetBeta) => "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v",
+            (Asset::Usdc, Cluster::Devnet) => "4zMMC9srt5Ri5X14GAgXhaHii3GnPAEERYPJgZJDncDU",
+            (Asset::Sol, _) => "So11111111111111111111111111111111111111112",
+        }
+    }
+
+    /// Mint of the asset on the cluster read at startup
+    pub fn mint(&self) -> Pubkey {
+        Pubkey::from_str(self.mint_on(Cluster::global())).expect("asset mints are valid")
+    }
+
+    /// The asset minted by `mint` on the cluster read at startup
+    pub fn from_mint(mint: &Pubkey) -> Option<Self> {
+        Self::ALL.into_iter().find(|asset| asset.mint() == *mint)
+    }
+
+    /// Reads an asset from its symbol, case insensitive, or its mint
+    pub fn from_param(asset: &str) -> Result<Self, RiskCalculationError> {
+        let asset = asset.trim();
+        Self::ALL
+            .into_iter()
+            .find(|candidate| {
+                candidate.symbol().eq_ignore_ascii_case(asset)
+                    || candidate.mint_on(Cluster::global()) == asset
+            })
+            .ok_or(RiskCalculationError::InvalidParameter(format!(
+                "unknown asset {:?}, expected usdc, sol or their mint",
+                asset
+            )))
+    }
+
+    /// `amount` native units in tokens
+    pub fn to_ui_amount(&self, amount: u64) -> f64 {
+        amount as f64 / 10f64.powi(self.decimals() as i32)
+    }
+
+    /// `ui_amount` tokens in native units, rounded down to the smallest unit
+    pub fn from_ui_amount(&self, ui_amount: f64) -> Result<u64, RiskCalculationError> {
+        let amount = (ui_amount * 10f64.powi(self.decimals() as i32)).floor();
+        if !amount.is_finite() || amount < 0.0 || amount > u64::MAX as f64 {
+            return Err(RiskCalculationError::InvalidParameter(format!(
+                "{} is not a valid amount of {}",
+                ui_amount,
+                self.symbol()
+            )));
+        }
+        Ok(amount as u64)
+    }
+
+    /// `amount` native units as tokens with the symbol, e.g. `1.50M USDC`
+    pub fn format_amount(&self, amount: u64) -> String {
+        let tokens = self.to_ui_amount(amount);
+        let scaled = if tokens >= 1e9 {
+            format!("{:.2}B", tokens / 1e9)
+        } else if tokens >= 1e6 {
+            format!("{:.2}M", tokens / 1e6)
+        } else if tokens >= 1e3 {
+            format!("{:.2}K", tokens / 1e3)
+        } else {
+            format!("{:.2}", tokens)
+        };
+        format!("{} {}", scaled, self.symbol())
+    }
+}
+
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.symbol())
+    }
+}
+
+impl From<Asset> for String {
+    fn from(asset: Asset) -> Self {
+        asset.symbol().to_lowercase()
+    }
+}
+
+impl TryFrom<String> for Asset {
+    type Error = RiskCalculationError;
+
+    fn try_from(asset: String) -> Result<Self, Self::Error> {
+        Self::from_param(&asset)
+    }
+}
+
+impl JsonSchema for Asset {
+    fn schema_name() -> String {
+        "Asset".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let mut schema = String::json_schema(gen).into_object();
+        schema.metadata().description =
+            Some("usdc or sol, or the mint of either, usdc by default".to_string());
+        schema.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_asset() {
+        assert_eq!(Asset::from_param("USDC").unwrap(), Asset::Usdc);
+        assert_eq!(Asset::from_mint(&Asset::Sol.mint()), Some(Asset::Sol));
+        assert_eq!(Asset::from_mint(&Pubkey::new_unique()), None);
+        assert_eq!(
+            Asset::from_param("So11111111111111111111111111111111111111112").unwrap(),
+            Asset::Sol
+        );
+        assert!(Asset::from_param("bonk").is_err());
+        for asset in Asset::ALL {
+            assert!(Pubkey::from_str(asset.mint_on(Cluster::Devnet)).is_ok());
+        }
+
+        assert_eq!(Asset::Usdc.from_ui_amount(1.5).unwrap(), 1_500_000);
+        assert_eq!(Asset::Sol.from_ui_amount(1.5).unwrap(), 1_500_000_000);
+        assert!(Asset::Sol.from_ui_amount(-1.0).is_err());
+        assert!(Asset::Sol.from_ui_amount(f64::NAN).is_err());
+        assert_eq!(Asset::Sol.to_ui_amount(2_500_000_000), 2.5);
+        assert_eq!(Asset::Usdc.format_amount(1_500_000_000_000), "1.50M USDC");
+
+        let holdings =
+            serde_json::to_string(&std::collections::HashMap::from([(Asset::Sol, 1)])).unwrap();
+        assert_eq!(holdings, r#"{"sol":1}"#);
+        assert_eq!(
+            serde_json::from_str::<Asset>(r#""usdc""#).unwrap(),
+            Asset::Usdc
+        );
+    }
+}
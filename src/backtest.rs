@@ -0,0 +1,438 @@
+use axum::{
+    extract::{Query, State},
+    response::Response,
+};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    liquidity_risk::{calculate_tvl_trend, score_liquidity},
+    market_history::{kamino_history, MarketPoint},
+    risk_model::{json_response, timings_requested, RiskCalculationError, RiskScore},
+    state::AppState,
+    timings::with_timings,
+    volatility_risk::{calculate_volatility_surface, Lookback},
+    weights::{LiquidityWeights, RiskWeightsConfig},
+};
+
+/// History before the first scored hour, so its volatility covers every lookback
+const WARMUP_HOURS: i64 = Lookback::LONGEST_HOURS as i64;
+/// Utilization above this leaves too little liquidity for withdrawals
+pub const UTILIZATION_EVENT_PERCENT: f64 = 95.0;
+/// Supply APY falling below this share of its high of the last day
+const APY_CRASH_RATIO: f64 = 0.5;
+const APY_CRASH_WINDOW_HOURS: usize = 24;
+/// Points the TVL change is taken over, the Kamino history being hourly
+const TVL_CHANGE_POINTS: usize = 24 + 1;
+
+const DEFAULT_BACKTEST_DAYS: i64 = 30;
+const DEFAULT_THRESHOLD: f64 = 50.0;
+const DEFAULT_HORIZON_HOURS: i64 = 24;
+
+/// A realized event the risk score should have warned about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdverseEvent {
+    /// Utilization rose above 95%
+    UtilizationSpike,
+    /// Supply APY halved within a day
+    ApyCrash,
+}
+
+/// Risk of the reserve as it would have been scored at one hour
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoredHour {
+    pub timestamp: DateTime<Utc>,
+    pub liquidity_risk: f64,
+    pub volatility_risk: Option<f64>,
+    pub overall_risk: f64,
+    /// Events starting at this hour
+    pub events: Vec<AdverseEvent>,
+}
+
+/// Scores every hour of `points` from `from` on with the history before it
+///
+/// Only the market driven pillars are replayed, protocol and oracle risk not
+/// being recorded over time, and the overall score is the partial score of
+/// both. Deposit concentration isn't in the market history either, so its
+/// weight is left out of the liquidity pillar. `points` must be hourly.
+pub fn score_hours(
+    points: &[MarketPoint],
+    from: DateTime<Utc>,
+    weights: &RiskWeightsConfig,
+) -> Vec<ScoredHour> {
+    let liquidity_weights = LiquidityWeights {
+        deposit_concentration: 0.0,
+        ..weights.liquidity
+    };
+    let window_hours = Lookback::LONGEST_HOURS as usize;
+
+    points
+        .iter()
+        .enumerate()
+        .filter(|(_, point)| point.timestamp >= from)
+        .map(|(i, point)| {
+            let history = &points[(i + 1).saturating_sub(window_hours)..=i];
+            let yields: Vec<f64> = history.iter().map(|p| p.supply_apy_percent).collect();
+            let utilization_rates: Vec<f64> =
+                history.iter().map(|p| p.utilization_percent).collect();
+            let volatility_risk = calculate_volatility_surface(
+                &yields,
+                &utilization_rates,
+                1,
+                weights.volatility.apy,
+                weights.volatility.utilization,
+                &weights.volatility_blend,
+            )
+            .map(|volatility| volatility.volatility_risk);
+
+            let tvl_change_24h_pct = history[history.len().saturating_sub(TVL_CHANGE_POINTS)..]
+                .iter()
+                .map(|p| p.tvl_usd)
+                .collect::<Option<Vec<f64>>>()
+                .and_then(|tvl| calculate_tvl_trend(&tvl, 1))
+                .map(|trend| trend.tvl_change_24h_pct);
+            let liquidity_risk = score_liquidity(
+                0.0,
+                point.utilization_percent,
+                tvl_change_24h_pct,
+                &liquidity_weights,
+            );
+            let overall_risk =
+                RiskScore::from_raw(weights, Some(liquidity_risk), volatility_risk, None, None)
+                    .unwrap_or_default()
+                    .overall_risk;
+
+            ScoredHour {
+                timestamp: point.timestamp,
+                liquidity_risk,
+                volatility_risk,
+                overall_risk,
+                events: adverse_events(&points[..=i]),
+            }
+        })
+        .collect()
+}
+
+/// Events starting at the last of `history`
+///
+/// An event lasting several hours only counts at its first.
+fn adverse_events(history: &[MarketPoint]) -> Vec<AdverseEvent> {
+    let spike = |i: usize| history[i].utilization_percent > UTILIZATION_EVENT_PERCENT;
+    let crash = |i: usize| {
+        let high = history[i.saturating_sub(APY_CRASH_WINDOW_HOURS)..i]
+            .iter()
+            .map(|p| p.supply_apy_percent)
+            .fold(f64::NAN, f64::max);
+        high > 0.0 && history[i].supply_apy_percent < high * APY_CRASH_RATIO
+    };
+    let Some(last) = history.len().checked_sub(1) else {
+        return Vec::new();
+    };
+    let started =
+        |happening: &dyn Fn(usize) -> bool| happening(last) && (last == 0 || !happening(last - 1));
+
+    let mut events = Vec::new();
+    if started(&spike) {
+        events.push(AdverseEvent::UtilizationSpike);
+    }
+    if started(&crash) {
+        events.push(AdverseEvent::ApyCrash);
+    }
+    events
+}
+
+/// How well the scores warned about the events that followed them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BacktestSummary {
+    pub hours: usize,
+    pub events: usize,
+    /// Risk from which an hour counts as a warning
+    pub threshold: f64,
+    /// How far ahead of an event a warning counts
+    pub horizon_hours: i64,
+    /// Mean overall risk of the hours an event followed within the horizon
+    pub mean_risk_before_events: Option<f64>,
+    /// Mean overall risk of the other hours
+    pub mean_risk_otherwise: Option<f64>,
+    /// Share of the warnings an event followed
+    pub precision: Option<f64>,
+    /// Share of the events a warning preceded
+    pub recall: Option<f64>,
+    /// Probability an hour followed by an event scores above one that isn't, 0.5 being chance
+    pub auc: Option<f64>,
+}
+
+fn mean(values: &[f64]) -> Option<f64> {
+    (!values.is_empty()).then(|| values.iter().sum::<f64>() / values.len() as f64)
+}
+
+/// Compares the scores with the events that followed them
+///
+/// Hours whose horizon reaches past the last scored hour aren't evaluated, as
+/// what followed them isn't known, nor are events without a horizon of scored
+/// hours before them.
+pub fn summarize(scored: &[ScoredHour], threshold: f64, horizon_hours: i64) -> BacktestSummary {
+    // Saturating, a horizon past the representable times covers them all
+    let horizon = Duration::try_hours(horizon_hours).unwrap_or(Duration::MAX);
+    let horizon_after = |time: DateTime<Utc>| {
+        time.checked_add_signed(horizon)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    };
+    let horizon_before = |time: DateTime<Utc>| {
+        time.checked_sub_signed(horizon)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC)
+    };
+    let event_times: Vec<DateTime<Utc>> = scored
+        .iter()
+        .filter(|hour| !hour.events.is_empty())
+        .map(|hour| hour.timestamp)
+        .collect();
+    let (first, last) = match (scored.first(), scored.last()) {
+        (Some(first), Some(last)) => (first.timestamp, last.timestamp),
+        _ => (DateTime::<Utc>::MIN_UTC, DateTime::<Utc>::MIN_UTC),
+    };
+
+    let (mut before_events, mut otherwise) = (Vec::new(), Vec::new());
+    for hour in scored
+        .iter()
+        .filter(|hour| horizon_after(hour.timestamp) <= last)
+    {
+        let followed = event_times
+            .iter()
+            .any(|event| *event > hour.timestamp && *event <= horizon_after(hour.timestamp));
+        if followed {
+            before_events.push(hour.overall_risk);
+        } else {
+            otherwise.push(hour.overall_risk);
+        }
+    }
+
+    let warnings = before_events
+        .iter()
+        .chain(&otherwise)
+        .filter(|risk| **risk >= threshold)
+        .count();
+    let warned = before_events
+        .iter()
+        .filter(|risk| **risk >= threshold)
+        .count();
+    let evaluated_events: Vec<&DateTime<Utc>> = event_times
+        .iter()
+        .filter(|event| horizon_before(**event) >= first)
+        .collect();
+    let warned_events = evaluated_events
+        .iter()
+        .filter(|event| {
+            scored.iter().any(|hour| {
+                hour.timestamp < ***event
+                    && hour.timestamp >= horizon_before(***event)
+                    && hour.overall_risk >= threshold
+            })
+        })
+        .count();
+
+    let auc = (!before_events.is_empty() && !otherwise.is_empty()).then(|| {
+        let wins = before_events
+            .iter()
+            .flat_map(|positive| {
+                otherwise.iter().map(move |negative| {
+                    if positive > negative {
+                        1.0
+                    } else if positive == negative {
+                        0.5
+                    } else {
+                        0.0
+                    }
+                })
+            })
+            .sum::<f64>();
+        wins / (before_events.len() * otherwise.len()) as f64
+    });
+
+    BacktestSummary {
+        hours: scored.len(),
+        events: scored.iter().map(|hour| hour.events.len()).sum(),
+        threshold,
+        horizon_hours,
+        mean_risk_before_events: mean(&before_events),
+        mean_risk_otherwise: mean(&otherwise),
+        precision: (warnings > 0).then(|| warned as f64 / warnings as f64),
+        recall: (!evaluated_events.is_empty())
+            .then(|| warned_events as f64 / evaluated_events.len() as f64),
+        auc,
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BacktestReport {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub summary: BacktestSummary,
+    pub hours: Vec<ScoredHour>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BacktestQuery {
+    /// Start of the replay, defaults to 30 days before `to`
+    pub from: Option<DateTime<Utc>>,
+    /// End of the replay, defaults to now
+    pub to: Option<DateTime<Utc>>,
+    /// Risk from which an hour counts as a warning, defaults to 50
+    pub threshold: Option<f64>,
+    /// How far ahead of an event a warning counts, defaults to 24
+    pub horizon_hours: Option<i64>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+impl BacktestQuery {
+    pub fn range(&self) -> Result<(DateTime<Utc>, DateTime<Utc>), RiskCalculationError> {
+        let to = self.to.unwrap_or_else(Utc::now);
+        let from = match self.from {
+            Some(from) => from,
+            None => to
+                .checked_sub_signed(Duration::days(DEFAULT_BACKTEST_DAYS))
+                .ok_or_else(|| {
+                    RiskCalculationError::InvalidParameter(format!("to is out of range: {}", to))
+                })?,
+        };
+        if from >= to {
+            return Err(RiskCalculationError::InvalidParameter(format!(
+                "from ({}) must be before to ({})",
+                from, to
+            )));
+        }
+        Ok((from, to))
+    }
+}
+
+/// Replays the configured Kamino reserve between `from` and `to`
+pub async fn backtest(
+    state: &AppState,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    threshold: f64,
+    horizon_hours: i64,
+) -> Result<BacktestReport, RiskCalculationError> {
+    // A longer horizon than the range can't be evaluated on any hour
+    let range_hours = (to - from).num_hours();
+    if horizon_hours <= 0 || horizon_hours > range_hours {
+        return Err(RiskCalculationError::InvalidParameter(format!(
+            "horizon_hours must be between 1 and the {} hours backtested: {}",
+            range_hours, horizon_hours
+        )));
+    }
+    let warmup_from = from
+        .checked_sub_signed(Duration::hours(WARMUP_HOURS))
+        .ok_or_else(|| {
+            RiskCalculationError::InvalidParameter(format!("from is out of range: {}", from))
+        })?;
+    let points = kamino_history(
+        &state.redis_client,
+        &state.config.kamino_reserve,
+        warmup_from,
+        to,
+    )
+    .await?;
+    let hours = score_hours(&points, from, RiskWeightsConfig::global());
+    Ok(BacktestReport {
+        from,
+        to,
+        summary: summarize(&hours, threshold, horizon_hours),
+        hours,
+    })
+}
+
+pub async fn backtest_handler(
+    State(state): State<AppState>,
+    Query(query): Query<BacktestQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let (from, to) = query.range()?;
+        backtest(
+            &state,
+            from,
+            to,
+            query.threshold.unwrap_or(DEFAULT_THRESHOLD),
+            query.horizon_hours.unwrap_or(DEFAULT_HORIZON_HOURS),
+        )
+        .await
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_backtest() {
+        let start = Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap();
+        // Calm but for a morning on the fourth day, when utilization climbs to a
+        // spike and the APY crashes
+        let points: Vec<MarketPoint> = (0..24 * 5)
+            .map(|hour| {
+                let stressed = (24 * 3..24 * 3 + 18).contains(&hour);
+                MarketPoint {
+                    timestamp: start + Duration::hours(hour),
+                    supply_apy_percent: if stressed && hour >= 24 * 3 + 12 {
+                        2.0
+                    } else {
+                        6.0
+                    },
+                    utilization_percent: if stressed {
+                        90.0 + (hour - 24 * 3) as f64 / 2.0
+                    } else {
+                        60.0
+                    },
+                    tvl_usd: None,
+                }
+            })
+            .collect();
+        let weights = RiskWeightsConfig::default();
+        let scored = score_hours(&points, start + Duration::days(1), &weights);
+        assert_eq!(scored.len(), 24 * 4);
+
+        let events: Vec<(i64, AdverseEvent)> = scored
+            .iter()
+            .flat_map(|hour| {
+                let offset = (hour.timestamp - start).num_hours();
+                hour.events.iter().map(move |event| (offset, *event))
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                (24 * 3 + 11, AdverseEvent::UtilizationSpike),
+                (24 * 3 + 12, AdverseEvent::ApyCrash),
+            ]
+        );
+        // The climb towards the spike scores above the calm
+        assert!(scored[50].overall_risk > scored[0].overall_risk);
+
+        let summary = summarize(&scored, scored[50].overall_risk, 24);
+        assert_eq!(summary.events, 2);
+        assert_eq!(summary.recall, Some(1.0));
+        assert!(summary.auc.unwrap() > 0.5);
+        assert!(summary.mean_risk_before_events > summary.mean_risk_otherwise);
+        // Horizons past any representable time evaluate nothing, without overflowing
+        let unbounded = summarize(&scored, 0.0, i64::MAX);
+        assert_eq!((unbounded.precision, unbounded.recall), (None, None));
+
+        let query = |from, to| BacktestQuery {
+            from,
+            to,
+            threshold: None,
+            horizon_hours: None,
+            debug: None,
+        };
+        assert!(query(Some(start), Some(start)).range().is_err());
+        let (from, to) = query(None, Some(start)).range().unwrap();
+        assert_eq!(to - from, Duration::days(DEFAULT_BACKTEST_DAYS));
+        assert!(query(None, Some(DateTime::<Utc>::MIN_UTC)).range().is_err());
+    }
+}
@@ -0,0 +1,288 @@
+use std::sync::OnceLock;
+
+use axum::{
+    extract::{Query, State},
+    response::Response,
+    Json,
+};
+use futures::future::join_all;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+
+use crate::{
+    kamino::reserve::KaminoReserveConfig,
+    registry::RegisteredProtocol,
+    risk_model::{
+        json_response, timings_requested, DebugQuery, Protocol, RiskCalculationError, RiskResponse,
+    },
+    server::parse_positive_env,
+    state::AppState,
+    timings::{spawn_timed, with_timings},
+};
+
+static BATCH_CONFIG: OnceLock<BatchConfig> = OnceLock::new();
+static BATCH_PERMITS: OnceLock<Semaphore> = OnceLock::new();
+
+/// Limits of `POST /risk_model/batch`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchConfig {
+    /// Most targets a single request may ask for
+    pub max_targets: usize,
+    /// Targets assessed at once, shared by every batch request in flight
+    pub concurrency: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        BatchConfig {
+            max_targets: 50,
+            concurrency: 4,
+        }
+    }
+}
+
+impl BatchConfig {
+    /// The configuration read at first use
+    pub fn global() -> &'static Self {
+        BATCH_CONFIG
+            .get_or_init(|| Self::from_env().expect("batch limits must be positive integers"))
+    }
+
+    /// Reads `RISK_BATCH_MAX_TARGETS` and `RISK_BATCH_CONCURRENCY`, the defaults
+    /// for those not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let default = BatchConfig::default();
+        Ok(BatchConfig {
+            max_targets: parse_positive_env("RISK_BATCH_MAX_TARGETS")?
+                .unwrap_or(default.max_targets),
+            concurrency: parse_positive_env("RISK_BATCH_CONCURRENCY")?
+                .unwrap_or(default.concurrency),
+        })
+    }
+
+    fn permits(&self) -> &'static Semaphore {
+        BATCH_PERMITS.get_or_init(|| Semaphore::new(self.concurrency))
+    }
+}
+
+/// A protocol, and for Kamino optionally the reserve, to assess
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct BatchTarget {
+    /// Case insensitive, e.g. `kamino` or `marginfi`
+    pub protocol: String,
+    /// Kamino lending market, the configured reserve's if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub market: Option<String>,
+    /// Kamino reserve, given together with `market`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reserve: Option<String>,
+}
+
+impl BatchTarget {
+    /// The protocol assessing this target, with the default reserve when none is given
+    pub(crate) fn resolve(
+        &self,
+        redis_client: redis::Client,
+        default_reserve: &KaminoReserveConfig,
+    ) -> Result<RegisteredProtocol, RiskCalculationError> {
+        let protocol = Protocol::from_param(&self.protocol)?;
+        if protocol != Protocol::Kamino && (self.market.is_some() || self.reserve.is_some()) {
+            return Err(RiskCalculationError::InvalidParameter(format!(
+                "market and reserve only select Kamino reserves, not {:?}",
+                protocol
+            )));
+        }
+        let reserve = KaminoReserveConfig::from_params(
+            self.market.as_deref(),
+            self.reserve.as_deref(),
+            default_reserve,
+        )
+        .map_err(|e| RiskCalculationError::InvalidParameter(e.to_string()))?;
+        RegisteredProtocol::for_protocol(&protocol, redis_client, reserve)
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct BatchRequest {
+    pub targets: Vec<BatchTarget>,
+}
+
+/// Why a target couldn't be assessed, as in error responses
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BatchError {
+    pub error: String,
+    pub code: String,
+}
+
+impl From<&RiskCalculationError> for BatchError {
+    fn from(error: &RiskCalculationError) -> Self {
+        BatchError {
+            error: error.to_string(),
+            code: error.code().to_string(),
+        }
+    }
+}
+
+/// Risk of a single target, or why it couldn't be assessed
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct BatchResult {
+    #[serde(flatten)]
+    pub target: BatchTarget,
+    /// See [`RegisteredProtocol::scope`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub risk_metrics: Option<RiskResponse>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<BatchError>,
+}
+
+/// Results in the order of the request's targets
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    pub results: Vec<BatchResult>,
+}
+
+/// Checks the request against `config` before anything is assessed
+fn validate(request: &BatchRequest, config: &BatchConfig) -> Result<(), RiskCalculationError> {
+    if request.targets.is_empty() {
+        return Err(RiskCalculationError::InvalidParameter(
+            "targets must not be empty".to_string(),
+        ));
+    }
+    if request.targets.len() > config.max_targets {
+        return Err(RiskCalculationError::InvalidParameter(format!(
+            "at most {} targets may be assessed at once, got {}",
+            config.max_targets,
+            request.targets.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Assesses every target concurrently, within the permits shared by all batches
+///
+/// A failing target doesn't fail the batch, its result carries the error instead.
+pub async fn assess_batch(
+    state: &AppState,
+    request: BatchRequest,
+) -> Result<BatchResponse, RiskCalculationError> {
+    let config = BatchConfig::global();
+    validate(&request, config)?;
+    let permits = config.permits();
+    let tasks = request.targets.into_iter().map(|target| {
+        let resolved = target.resolve(state.redis_client.clone(), &state.config.kamino_reserve);
+        let privacy = state.config.privacy.clone();
+        async move {
+            let registered = match resolved {
+                Ok(registered) => registered,
+                Err(e) => {
+                    return BatchResult {
+                        target,
+                        scope: None,
+                        risk_metrics: None,
+                        error: Some(BatchError::from(&e)),
+                    }
+                }
+            };
+            let scope = registered.scope();
+            let result = match permits.acquire().await {
+                Ok(_permit) => spawn_timed(async move { registered.assess().await })
+                    .await
+                    .unwrap_or_else(|e| {
+                        Err(RiskCalculationError::CustomError(format!(
+                            "Assessment task failed: {}",
+                            e
+                        )))
+                    }),
+                Err(e) => Err(RiskCalculationError::CustomError(e.to_string())),
+            };
+            match result {
+                Ok(mut risk_metrics) => {
+                    privacy.apply_to_liquidity(&mut risk_metrics.liquidity_risk);
+                    BatchResult {
+                        target,
+                        scope: Some(scope),
+                        risk_metrics: Some(risk_metrics),
+                        error: None,
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Failed to compute risk for {}: {}", scope, e);
+                    BatchResult {
+                        target,
+                        scope: Some(scope),
+                        risk_metrics: None,
+                        error: Some(BatchError::from(&e)),
+                    }
+                }
+            }
+        }
+    });
+    Ok(BatchResponse {
+        results: join_all(tasks).await,
+    })
+}
+
+/// Risk of several protocols or reserves in one round trip
+pub async fn risk_model_batch(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+    Json(request): Json<BatchRequest>,
+) -> Response {
+    let (result, timings) = with_timings(assess_batch(&state, request)).await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(protocol: &str, market: Option<&str>, reserve: Option<&str>) -> BatchTarget {
+        BatchTarget {
+            protocol: protocol.to_string(),
+            market: market.map(str::to_string),
+            reserve: reserve.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_batch_targets() {
+        let redis_client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let default = KaminoReserveConfig::usdc();
+
+        let kamino = target("kamino", None, None)
+            .resolve(redis_client.clone(), &default)
+            .unwrap();
+        assert_eq!(kamino.protocol(), Protocol::Kamino);
+        assert!(target("MarginFi", None, None)
+            .resolve(redis_client.clone(), &default)
+            .is_ok());
+
+        let errors = [
+            target("marginfi", Some("market"), Some("reserve")),
+            target("kamino", Some(&default.market.to_string()), None),
+            target("solend", None, None),
+        ];
+        for target in errors {
+            let error = target
+                .resolve(redis_client.clone(), &default)
+                .map(|registered| registered.protocol())
+                .unwrap_err();
+            assert_eq!(error.code(), "invalid_parameter", "{:?}", target);
+        }
+
+        let config = BatchConfig {
+            max_targets: 2,
+            concurrency: 1,
+        };
+        let request = |count| BatchRequest {
+            targets: vec![target("kamino", None, None); count],
+        };
+        assert!(validate(&request(2), &config).is_ok());
+        assert!(validate(&request(0), &config).is_err());
+        assert!(validate(&request(3), &config).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use std::fmt::{self, Display};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// A share in basis points, 10,000 being 100%
+///
+/// Arithmetic goes through `u128` and is checked, so applying a share to any
+/// `u64` amount can't silently overflow or truncate to garbage.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Default,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+)]
+#[serde(transparent)]
+pub struct Bps(pub u64);
+
+impl Bps {
+    pub const ZERO: Bps = Bps(0);
+    /// 100%
+    pub const FULL: Bps = Bps(10_000);
+
+    /// `numerator / denominator` in basis points, rounded down
+    ///
+    /// `None` if `denominator` is zero or the share doesn't fit in a `u64`.
+    pub fn from_parts(numerator: u64, denominator: u64) -> Option<Bps> {
+        if denominator == 0 {
+            return None;
+        }
+        let bps = numerator as u128 * Self::FULL.0 as u128 / denominator as u128;
+        u64::try_from(bps).ok().map(Bps)
+    }
+
+    /// This share of `amount`, rounded down
+    ///
+    /// `None` only if a share above 100% takes the result past `u64::MAX`.
+    pub fn apply_to(&self, amount: u64) -> Option<u64> {
+        let part = amount as u128 * self.0 as u128 / Self::FULL.0 as u128;
+        u64::try_from(part).ok()
+    }
+
+    /// What's left of 100% after this share, zero if it's above 100%
+    pub fn remainder(&self) -> Bps {
+        Bps(Self::FULL.0.saturating_sub(self.0))
+    }
+}
+
+impl Display for Bps {
+    /// As a percentage with one (truncated) decimal, e.g. `12.3%`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}%", self.0 / 100, (self.0 % 100) / 10)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bps_arithmetic() {
+        assert_eq!(Bps::from_parts(1, 3), Some(Bps(3_333)));
+        assert_eq!(Bps::from_parts(5, 0), None);
+        assert_eq!(Bps::from_parts(u64::MAX, u64::MAX), Some(Bps::FULL));
+        // 10,000 times u64::MAX doesn't fit
+        assert_eq!(Bps::from_parts(u64::MAX, 1), None);
+
+        assert_eq!(Bps(3_333).apply_to(1_000_000), Some(333_300));
+        assert_eq!(Bps::FULL.apply_to(u64::MAX), Some(u64::MAX));
+        assert_eq!(Bps(5_000).apply_to(u64::MAX), Some(u64::MAX / 2));
+        assert_eq!(Bps(20_000).apply_to(u64::MAX), None);
+
+        assert_eq!(Bps(2_500).remainder(), Bps(7_500));
+        assert_eq!(Bps(12_000).remainder(), Bps::ZERO);
+    }
+
+    #[test]
+    fn test_bps_display_and_serde() {
+        assert_eq!(Bps(1_234).to_string(), "12.3%");
+        assert_eq!(Bps(5).to_string(), "0.0%");
+        assert_eq!(Bps::FULL.to_string(), "100.0%");
+        assert_eq!(serde_json::to_string(&Bps(2_500)).unwrap(), "2500");
+        assert_eq!(serde_json::from_str::<Bps>("2500").unwrap(), Bps(2_500));
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    future::Future,
+    sync::OnceLock,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use redis::AsyncCommands;
+
+use crate::{
+    cache_lock::lock_key,
+    redis_connection::shared_connection,
+    risk_model::RiskCalculationError,
+    upstream::{self, Upstream},
+};
+
+static CACHE_KIND: OnceLock<CacheKind> = OnceLock::new();
+static MEMORY_CACHE: OnceLock<MemoryCache> = OnceLock::new();
+
+/// Store of cached values, each expiring after its own TTL
+pub trait Cache: Sync {
+    /// Reads `key`, `None` when it was never set or expired
+    fn get(
+        &self,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<String>, RiskCalculationError>> + Send;
+    /// Sets `key` to `value` for `ttl_seconds`
+    fn set_with_ttl(
+        &self,
+        key: &str,
+        value: &str,
+        ttl_seconds: u64,
+    ) -> impl Future<Output = Result<(), RiskCalculationError>> + Send;
+}
+
+impl Cache for redis::Client {
+    async fn get(&self, key: &str) -> Result<Option<String>, RiskCalculationError> {
+        upstream::call(Upstream::Redis, async {
+            let mut connection = shared_connection(self).await?;
+            connection.get(key).await
+        })
+        .await
+    }
+
+    async fn set_with_ttl(
+        &self,
+        key: &str,
+        value: &str,
+        ttl_seconds: u64,
+    ) -> Result<(), RiskCalculationError> {
+        upstream::call(Upstream::Redis, async {
+            let mut connection = shared_connection(self).await?;
+            connection.set_ex::<_, _, ()>(key, value, ttl_seconds).await
+        })
+        .await
+    }
+}
+
+/// Cache held in the process's memory, for development and tests without Redis
+///
+/// Expired values are dropped when they're next read.
+#[derive(Debug, Default)]
+pub struct MemoryCache {
+    entries: DashMap<String, (String, Instant)>,
+}
+
+impl MemoryCache {
+    /// The cache shared by everything in the process
+    pub fn global() -> &'static Self {
+        MEMORY_CACHE.get_or_init(Self::default)
+    }
+
+    /// Removes the keys matching the glob `pattern`, returning how many there were
+    pub fn remove_matching(&self, pattern: &str) -> usize {
+        let mut removed = 0;
+        self.entries.retain(|key, _| {
+            let matches = glob_match(pattern, key);
+            removed += usize::from(matches);
+            !matches
+        });
+        removed
+    }
+}
+
+/// Whether `key` matches `pattern`, of the `*` and `?` wildcards of redis' SCAN
+fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // Where the last `*` was and how much of the key it swallows so far
+    let mut star = None;
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, k));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == key[k] => {
+                p += 1;
+                k += 1;
+            }
+            _ => match star {
+                Some((star_p, star_k)) => {
+                    star = Some((star_p, star_k + 1));
+                    p = star_p + 1;
+                    k = star_k + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+impl Cache for MemoryCache {
+    async fn get(&self, key: &str) -> Result<Option<String>, RiskCalculationError> {
+        let now = Instant::now();
+        // The entry is read in its own statement, removing it while it's borrowed would deadlock
+        let value = self.entries.get(key).map(|entry| entry.clone());
+        match value {
+            Some((value, expires_at)) if expires_at > now => Ok(Some(value)),
+            Some(_) => {
+                self.entries
+                    .remove_if(key, |_, (_, expires_at)| *expires_at <= now);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn set_with_ttl(
+        &self,
+        key: &str,
+        value: &str,
+        ttl_seconds: u64,
+    ) -> Result<(), RiskCalculationError> {
+        self.entries.insert(
+            key.to_string(),
+            (
+                value.to_string(),
+                Instant::now() + Duration::from_secs(ttl_seconds),
+            ),
+        );
+        Ok(())
+    }
+}
+
+/// Where cached values are kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheKind {
+    #[default]
+    Redis,
+    /// [`MemoryCache`], cached values aren't shared between instances
+    Memory,
+}
+
+impl CacheKind {
+    /// The kind read at startup
+    pub fn global() -> &'static Self {
+        CACHE_KIND.get_or_init(|| Self::from_env().expect("CACHE_BACKEND must be valid"))
+    }
+
+    /// Reads `CACHE_BACKEND`, `redis` when not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        match std::env::var("CACHE_BACKEND").as_deref() {
+            Err(_) | Ok("redis") => Ok(CacheKind::Redis),
+            Ok("memory") => Ok(CacheKind::Memory),
+            Ok(other) => Err(RiskCalculationError::ParseError(format!(
+                "invalid CACHE_BACKEND {:?}, expected redis or memory",
+                other
+            ))),
+        }
+    }
+}
+
+/// The configured cache
+#[derive(Debug, Clone, Copy)]
+pub enum CacheBackend<'a> {
+    Redis(&'a redis::Client),
+    Memory(&'static MemoryCache),
+}
+
+impl<'a> CacheBackend<'a> {
+    /// The cache of [`CacheKind::global`], `redis_client` when it's Redis
+    pub fn new(redis_client: &'a redis::Client) -> Self {
+        match CacheKind::global() {
+            CacheKind::Redis => CacheBackend::Redis(redis_client),
+            CacheKind::Memory => CacheBackend::Memory(MemoryCache::global()),
+        }
+    }
+
+    /// Redis to take locks shared between instances in, none for the memory cache
+    pub fn redis_client(&self) -> Option<&'a redis::Client> {
+        match self {
+            CacheBackend::Redis(redis_client) => Some(redis_client),
+            CacheBackend::Memory(_) => None,
+        }
+    }
+
+    /// Deletes the keys matching the glob `pattern`, returning how many there were
+    ///
+    /// Locks of values being computed are kept, see [`crate::cache_lock`].
+    pub async fn delete_matching(&self, pattern: &str) -> Result<usize, RiskCalculationError> {
+        let redis_client = match self {
+            CacheBackend::Redis(redis_client) => redis_client,
+            CacheBackend::Memory(cache) => return Ok(cache.remove_matching(pattern)),
+        };
+        upstream::call(Upstream::Redis, async {
+            let mut connection = shared_connection(redis_client).await?;
+            let mut keys = Vec::new();
+            let mut scan: redis::AsyncIter<String> = connection.scan_match(pattern).await?;
+            while let Some(key) = scan.next_item().await {
+                if !key.ends_with(&lock_key("")) {
+                    keys.push(key);
+                }
+            }
+            drop(scan);
+            if !keys.is_empty() {
+                connection.del::<_, ()>(&keys).await?;
+            }
+            Ok::<_, redis::RedisError>(keys.len())
+        })
+        .await
+    }
+}
+
+impl Cache for CacheBackend<'_> {
+    async fn get(&self, key: &str) -> Result<Option<String>, RiskCalculationError> {
+        match self {
+            CacheBackend::Redis(redis_client) => Cache::get(*redis_client, key).await,
+            CacheBackend::Memory(cache) => cache.get(key).await,
+        }
+    }
+
+    async fn set_with_ttl(
+        &self,
+        key: &str,
+        value: &str,
+        ttl_seconds: u64,
+    ) -> Result<(), RiskCalculationError> {
+        match self {
+            CacheBackend::Redis(redis_client) => {
+                Cache::set_with_ttl(*redis_client, key, value, ttl_seconds).await
+            }
+            CacheBackend::Memory(cache) => cache.set_with_ttl(key, value, ttl_seconds).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_cache() {
+        let cache = MemoryCache::default();
+        assert_eq!(cache.get("key").await.unwrap(), None);
+        cache.set_with_ttl("key", "value", 60).await.unwrap();
+        assert_eq!(cache.get("key").await.unwrap().as_deref(), Some("value"));
+
+        cache.set_with_ttl("key", "expired", 0).await.unwrap();
+        assert_eq!(cache.get("key").await.unwrap(), None);
+        assert!(cache.entries.is_empty());
+    }
+}
@@ -0,0 +1,242 @@
+use axum::{
+    extract::{Query, State},
+    response::Response,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::CacheBackend,
+    cache_schema::versioned_key,
+    multisig::{authorize, AdminAction, MultisigApproval},
+    risk_model::{
+        json_response, timings_requested, DebugQuery, Protocol, RiskCalculationError,
+        RISK_RESPONSE_KEY,
+    },
+    scheduler::refresh_all,
+    state::AppState,
+    timings::with_timings,
+};
+
+/// Protocols whose cached values are invalidated when none is given
+const CACHED_PROTOCOLS: [Protocol; 2] = [Protocol::Kamino, Protocol::Marginfy];
+
+/// Params of an invalidation's approval
+#[derive(Debug, Default, Deserialize)]
+pub struct InvalidateRequest {
+    /// Only the cached values of this protocol, every protocol's if not given
+    #[serde(default)]
+    pub protocol: Option<Protocol>,
+    /// Glob of the keys within the protocols' cache namespaces, e.g.
+    /// `liquidity:*`, every key if not given
+    #[serde(default)]
+    pub pattern: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct InvalidateSummary {
+    pub deleted: usize,
+}
+
+/// Params of a warm-up's approval
+#[derive(Debug, Default, Deserialize)]
+pub struct WarmRequest {
+    /// Drop every protocol's cached values first, so nothing is reused
+    #[serde(default)]
+    pub invalidate: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WarmSummary {
+    /// Cached values dropped before refreshing
+    pub deleted: usize,
+    /// The snapshot now served
+    pub snapshot_id: String,
+}
+
+/// Start of the cache namespaces of `protocol`, whichever reserve or bank they're of
+fn namespace_prefix(protocol: &Protocol) -> Result<&'static str, RiskCalculationError> {
+    match protocol {
+        Protocol::Kamino => Ok("kamino"),
+        Protocol::Marginfy => Ok("marginfi"),
+        other => Err(RiskCalculationError::InvalidParameter(format!(
+            "{:?} is not supported",
+            other
+        ))),
+    }
+}
+
+/// Patterns of the keys an invalidation deletes
+///
+/// Namespaces are of a reserve or bank, e.g. `kamino:<reserve>`. The store of
+/// scanned obligations is outside them, so it isn't matched.
+fn invalidation_patterns(request: &InvalidateRequest) -> Result<Vec<String>, RiskCalculationError> {
+    let protocols = match &request.protocol {
+        Some(protocol) => vec![protocol.clone()],
+        None => CACHED_PROTOCOLS.to_vec(),
+    };
+    let pattern = request.pattern.as_deref().unwrap_or("*");
+    protocols
+        .iter()
+        .map(|protocol| {
+            Ok(versioned_key(&format!(
+                "{}:*:{}",
+                namespace_prefix(protocol)?,
+                pattern
+            )))
+        })
+        .collect()
+}
+
+/// Deletes the cached values the request is scoped to, returning how many there were
+pub async fn invalidate_cache(
+    cache: &CacheBackend<'_>,
+    request: &InvalidateRequest,
+) -> Result<usize, RiskCalculationError> {
+    let mut deleted = 0;
+    for pattern in invalidation_patterns(request)? {
+        deleted += cache.delete_matching(&pattern).await?;
+    }
+    Ok(deleted)
+}
+
+/// Deletes the assembled risk responses of `protocol`, e.g. once its risk was
+/// raised, so they're assembled again with the raise
+pub async fn invalidate_risk_responses(
+    cache: &CacheBackend<'_>,
+    protocol: &Protocol,
+) -> Result<usize, RiskCalculationError> {
+    if !CACHED_PROTOCOLS.contains(protocol) {
+        return Ok(0);
+    }
+    invalidate_cache(
+        cache,
+        &InvalidateRequest {
+            protocol: Some(protocol.clone()),
+            pattern: Some(RISK_RESPONSE_KEY.to_string()),
+        },
+    )
+    .await
+}
+
+/// Admin command: drops cached values, so they're fetched again when next needed
+///
+/// The body is a [`MultisigApproval`] of an [`InvalidateRequest`]. Stored
+/// snapshots are still served until the next refresh, see [`warm`].
+pub async fn invalidate(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+    Json(approval): Json<MultisigApproval>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let request: InvalidateRequest =
+            authorize(&state.redis_client, &approval, AdminAction::InvalidateCache).await?;
+        let cache = CacheBackend::new(&state.redis_client);
+        let deleted = invalidate_cache(&cache, &request).await?;
+        tracing::info!("Invalidated {} cached values", deleted);
+        Ok(InvalidateSummary { deleted })
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+/// Admin command: refreshes the risk snapshots now rather than at the next hour
+///
+/// The body is a [`MultisigApproval`] of a [`WarmRequest`]. Responds once the
+/// snapshot and the precomputed responses are stored.
+pub async fn warm(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+    Json(approval): Json<MultisigApproval>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let request: WarmRequest =
+            authorize(&state.redis_client, &approval, AdminAction::WarmCache).await?;
+        let deleted = if request.invalidate {
+            let cache = CacheBackend::new(&state.redis_client);
+            invalidate_cache(&cache, &InvalidateRequest::default()).await?
+        } else {
+            0
+        };
+        let snapshot_id = refresh_all(&state.redis_client).await?;
+        tracing::info!("Warmed caches, snapshot {}", snapshot_id);
+        Ok(WarmSummary {
+            deleted,
+            snapshot_id,
+        })
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{Cache, MemoryCache};
+
+    #[tokio::test]
+    async fn test_invalidate_cache() {
+        let memory: &'static MemoryCache = Box::leak(Box::default());
+        let cache = CacheBackend::Memory(memory);
+        for key in [
+            "kamino:reserve:liquidity:caps",
+            "kamino:reserve:volatility",
+            "kamino:reserve:risk_response",
+            "marginfi:bank:risk_response",
+            "obligation_deposits:kamino",
+            "obligation_deposits:kamino:market",
+            "marginfi:bank:liquidity",
+            "price:SOL",
+        ] {
+            cache
+                .set_with_ttl(&versioned_key(key), "{}", 60)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            invalidate_risk_responses(&cache, &Protocol::Kamino)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            invalidate_risk_responses(&cache, &Protocol::Solend)
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(cache
+            .get(&versioned_key("marginfi:bank:risk_response"))
+            .await
+            .unwrap()
+            .is_some());
+
+        let liquidity = InvalidateRequest {
+            protocol: Some(Protocol::Kamino),
+            pattern: Some("liquidity:*".to_string()),
+        };
+        assert_eq!(invalidate_cache(&cache, &liquidity).await.unwrap(), 1);
+        assert_eq!(
+            invalidate_cache(&cache, &InvalidateRequest::default())
+                .await
+                .unwrap(),
+            3
+        );
+        for key in [
+            "obligation_deposits:kamino",
+            "obligation_deposits:kamino:market",
+            "price:SOL",
+        ] {
+            assert!(cache.get(&versioned_key(key)).await.unwrap().is_some());
+        }
+
+        let unsupported = InvalidateRequest {
+            protocol: Some(Protocol::Solend),
+            pattern: None,
+        };
+        assert!(invalidate_cache(&cache, &unsupported).await.is_err());
+    }
+}
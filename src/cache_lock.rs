@@ -0,0 +1,209 @@
+use std::{future::Future, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{
+    redis_builder::RedisConnection, redis_connection::shared_connection,
+    risk_model::RiskCalculationError,
+};
+
+/// Longest a lock is held, longer than the slowest account scan, and longest
+/// a request waits for it
+pub const LOCK_TTL: Duration = Duration::from_secs(120);
+/// How often waiting requests look for the fresh value
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Deletes the lock only if it's still ours, it may have expired and been taken since
+const RELEASE_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Lock taken while the value of the cache key `key` is computed
+pub fn lock_key(key: &str) -> String {
+    format!("{}:lock", key)
+}
+
+/// Computes a missing cache value once across concurrent requests
+///
+/// When an hourly value expires, every request arriving before it's stored
+/// again would otherwise start the same expensive computation. The request
+/// that takes the Redis lock on `key` runs `compute`, the others poll `cached`
+/// until the value shows up. If the holder fails, the next request to take the
+/// lock computes instead, and a request gives up waiting after the lock's TTL.
+/// Without Redis every request computes, like without the cache. Caches local
+/// to the process pass no `redis_client` and always compute.
+pub async fn compute_once<T, C, CFut, F>(
+    redis_client: Option<&redis::Client>,
+    key: &str,
+    cached: C,
+    compute: F,
+) -> Result<T, RiskCalculationError>
+where
+    C: Fn() -> CFut,
+    CFut: Future<Output = Option<T>>,
+    F: Future<Output = Result<T, RiskCalculationError>>,
+{
+    let Some(redis_client) = redis_client else {
+        return compute.await;
+    };
+    let lock_key = lock_key(key);
+    let token = rand::random::<u64>().to_string();
+    let mut connection = match shared_connection(redis_client).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            tracing::warn!("Computing {} without a lock: {}", key, e);
+            return compute.await;
+        }
+    };
+
+    let deadline = Instant::now() + LOCK_TTL;
+    loop {
+        match try_lock(&mut connection, &lock_key, &token).await {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => {
+                tracing::warn!("Computing {} without a lock: {}", key, e);
+                return compute.await;
+            }
+        }
+        if Instant::now() >= deadline {
+            tracing::warn!("Gave up waiting for {}, computing it", key);
+            return compute.await;
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+        if let Some(value) = cached().await {
+            return Ok(value);
+        }
+    }
+
+    // The previous holder may have stored the value right before releasing
+    let result = match cached().await {
+        Some(value) => Ok(value),
+        None => compute.await,
+    };
+    release(&mut connection, &lock_key, &token).await;
+    result
+}
+
+/// Runs `compute` unless another request holds the lock on `key`
+///
+/// For refreshes that can be skipped when one is already running, e.g.
+/// revalidating a stale value. Returns `None` when skipped. Without a
+/// `redis_client` there's nothing to share the lock with, and it computes.
+pub async fn compute_if_unlocked<T, F>(
+    redis_client: Option<&redis::Client>,
+    key: &str,
+    compute: F,
+) -> Result<Option<T>, RiskCalculationError>
+where
+    F: Future<Output = Result<T, RiskCalculationError>>,
+{
+    let Some(redis_client) = redis_client else {
+        return compute.await.map(Some);
+    };
+    let lock_key = lock_key(key);
+    let token = rand::random::<u64>().to_string();
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    if !try_lock(&mut connection, &lock_key, &token)
+        .await
+        .map_err(RiskCalculationError::from)?
+    {
+        return Ok(None);
+    }
+    let result = compute.await;
+    release(&mut connection, &lock_key, &token).await;
+    result.map(Some)
+}
+
+/// Runs `compute` holding the lock on `key`, waiting for it when another request holds it
+///
+/// For changes that must not interleave, e.g. two writes to the same record.
+/// Fails with a conflict when the lock isn't released within its TTL, and
+/// when Redis can't be reached, as the lock can't be taken then.
+pub async fn with_lock<T, F>(
+    redis_client: Option<&redis::Client>,
+    key: &str,
+    compute: F,
+) -> Result<T, RiskCalculationError>
+where
+    F: Future<Output = Result<T, RiskCalculationError>>,
+{
+    let Some(redis_client) = redis_client else {
+        return compute.await;
+    };
+    let lock_key = lock_key(key);
+    let token = rand::random::<u64>().to_string();
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let deadline = Instant::now() + LOCK_TTL;
+    while !try_lock(&mut connection, &lock_key, &token)
+        .await
+        .map_err(RiskCalculationError::from)?
+    {
+        if Instant::now() >= deadline {
+            return Err(RiskCalculationError::Conflict(format!(
+                "{} is still locked",
+                key
+            )));
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+    let result = compute.await;
+    release(&mut connection, &lock_key, &token).await;
+    result
+}
+
+async fn try_lock(
+    connection: &mut RedisConnection,
+    lock_key: &str,
+    token: &str,
+) -> redis::RedisResult<bool> {
+    Ok(redis::cmd("SET")
+        .arg(lock_key)
+        .arg(token)
+        .arg("NX")
+        .arg("PX")
+        .arg(LOCK_TTL.as_millis() as u64)
+        .query_async::<Option<String>>(connection)
+        .await?
+        .is_some())
+}
+
+async fn release(connection: &mut RedisConnection, lock_key: &str, token: &str) {
+    let released: Result<i64, _> = redis::Script::new(RELEASE_SCRIPT)
+        .key(lock_key)
+        .arg(token)
+        .invoke_async(connection)
+        .await;
+    if let Err(e) = released {
+        tracing::warn!(
+            "Failed to release {}, it expires on its own: {}",
+            lock_key,
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_computes_without_redis() {
+        // Nothing listens on port 1
+        let redis_client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+        let value = compute_once(Some(&redis_client), "v2:key", || async { None }, async {
+            Ok(1)
+        })
+        .await
+        .unwrap();
+        assert_eq!(value, 1);
+        assert_eq!(lock_key("v2:key"), "v2:key:lock");
+    }
+}
@@ -0,0 +1,211 @@
+use redis::AsyncCommands;
+
+use crate::{
+    cluster::Cluster,
+    redis_builder::{key_hash_tag, RedisTopology},
+    redis_connection::shared_connection,
+    risk_model::RiskCalculationError,
+};
+
+/// Version of the format of everything this service stores in redis
+///
+/// Bump it whenever a cached value's serialization changes incompatibly. Keys
+/// are prefixed with the version, so a new deployment never parses values
+/// written by an older one, and [`migrate`] cleans up the old keys on startup.
+pub const CACHE_SCHEMA_VERSION: u32 = 2;
+
+/// Stores the schema version the keys in redis currently have
+const SCHEMA_VERSION_KEY: &str = "cache_schema_version";
+
+/// Prefixes that were used before keys were versioned
+const LEGACY_PREFIXES: [&str; 4] = ["kamino:", "marginfi:", "risk_snapshot:", "risk_history:"];
+
+/// Prefixes `key` with the current schema version, within the cluster's namespace
+///
+/// On a Redis Cluster it's also prefixed with the hash tag putting every key
+/// in the same slot, see [`RedisTopology::Cluster`].
+pub fn versioned_key(key: &str) -> String {
+    slotted(format!(
+        "v{}:{}",
+        CACHE_SCHEMA_VERSION,
+        Cluster::global().namespaced_key(key)
+    ))
+}
+
+/// `key` within the cluster's namespace, for records that can't be
+/// recomputed and so aren't versioned
+///
+/// Hash tagged like [`versioned_key`] on a Redis Cluster.
+pub fn record_key(key: &str) -> String {
+    slotted(Cluster::global().namespaced_key(key))
+}
+
+/// Prefixes `key` with the hash tag of every key on a Redis Cluster
+fn slotted(key: String) -> String {
+    if RedisTopology::global().is_cluster() {
+        format!("{{{}}}:{}", key_hash_tag(), key)
+    } else {
+        key
+    }
+}
+
+/// What the migration does with an existing key
+#[derive(Debug, PartialEq, Eq)]
+enum MigrationAction {
+    Keep,
+    Delete,
+    /// Merge the sorted set into the current version's key
+    Convert(String),
+}
+
+/// Decides how an existing key is migrated to the current schema
+///
+/// Cached values are cheap to recompute and are dropped. Risk history can't be
+/// recomputed, so it's converted: points only gain fields with defaults, so old
+/// points still parse and are merged into the current version's history.
+/// Keys this service didn't write are kept.
+fn migration_action(key: &str) -> MigrationAction {
+    let key = key
+        .strip_prefix(&format!("{{{}}}:", key_hash_tag()))
+        .unwrap_or(key);
+    let unversioned = match key.strip_prefix('v').and_then(|rest| rest.split_once(':')) {
+        Some((version, rest)) if version.parse::<u32>().is_ok() => {
+            if version == CACHE_SCHEMA_VERSION.to_string() {
+                return MigrationAction::Keep;
+            }
+            rest
+        }
+        _ => {
+            if !LEGACY_PREFIXES.iter().any(|prefix| key.starts_with(prefix)) {
+                return MigrationAction::Keep;
+            }
+            key
+        }
+    };
+    if unversioned.starts_with("risk_history:") {
+        MigrationAction::Convert(versioned_key(unversioned))
+    } else {
+        MigrationAction::Delete
+    }
+}
+
+/// Brings the keys in redis to the current schema version
+///
+/// Runs once per schema change: does nothing when the stored version matches.
+pub async fn migrate(redis_client: &redis::Client) -> Result<(), RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let stored_version: Option<u32> = connection
+        .get(SCHEMA_VERSION_KEY)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    if stored_version == Some(CACHE_SCHEMA_VERSION) {
+        return Ok(());
+    }
+    tracing::info!(
+        "Migrating cache from schema {:?} to {}",
+        stored_version,
+        CACHE_SCHEMA_VERSION
+    );
+
+    let mut keys: Vec<String> = Vec::new();
+    {
+        let mut iter = connection
+            .scan::<String>()
+            .await
+            .map_err(RiskCalculationError::from)?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+    }
+
+    let (mut deleted, mut converted) = (0, 0);
+    for key in keys {
+        match migration_action(&key) {
+            MigrationAction::Keep => {}
+            MigrationAction::Delete => {
+                let _: () = connection
+                    .del(&key)
+                    .await
+                    .map_err(RiskCalculationError::from)?;
+                deleted += 1;
+            }
+            MigrationAction::Convert(target) => {
+                let _: () = redis::pipe()
+                    .atomic()
+                    .zunionstore(&target, &[&target, &key])
+                    .ignore()
+                    .del(&key)
+                    .ignore()
+                    .query_async(&mut connection)
+                    .await
+                    .map_err(RiskCalculationError::from)?;
+                converted += 1;
+            }
+        }
+    }
+
+    let _: () = connection
+        .set(SCHEMA_VERSION_KEY, CACHE_SCHEMA_VERSION)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    tracing::info!(
+        "Cache migrated: {} keys deleted, {} converted",
+        deleted,
+        converted
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migration_action() {
+        assert_eq!(
+            versioned_key("risk_history:marginfi"),
+            "v2:risk_history:marginfi"
+        );
+        assert_eq!(
+            migration_action("v2:kamino:x:deposits:total"),
+            MigrationAction::Keep
+        );
+        assert_eq!(
+            migration_action("kamino:x:deposits:total"),
+            MigrationAction::Delete
+        );
+        assert_eq!(
+            migration_action("v1:risk_snapshot:kamino:latest"),
+            MigrationAction::Delete
+        );
+        assert_eq!(
+            migration_action("risk_history:marginfi"),
+            MigrationAction::Convert("v2:risk_history:marginfi".to_string())
+        );
+        assert_eq!(
+            migration_action("v1:risk_history:marginfi"),
+            MigrationAction::Convert("v2:risk_history:marginfi".to_string())
+        );
+        assert_eq!(migration_action("someone_elses_key"), MigrationAction::Keep);
+        // Portfolio events are the source of truth, never dropped
+        assert_eq!(
+            migration_action(&crate::portfolio_events::events_key("wallet")),
+            MigrationAction::Keep
+        );
+        assert_eq!(
+            migration_action("cache_schema_version"),
+            MigrationAction::Keep
+        );
+        // Keys of a Redis Cluster are read without their hash tag
+        assert_eq!(
+            migration_action("{risk_model}:v1:kamino:x:deposits:total"),
+            MigrationAction::Delete
+        );
+        assert_eq!(
+            migration_action("{risk_model}:portfolio_events:wallet"),
+            MigrationAction::Keep
+        );
+    }
+}
@@ -0,0 +1,205 @@
+use std::str::FromStr;
+
+use clap::{Parser, Subcommand};
+use solana_sdk::pubkey::Pubkey;
+
+use chrono::{Duration, Utc};
+
+use crate::{
+    backtest, market_history, portfolio,
+    registry::{ProtocolAssessment, RegisteredProtocol},
+    risk_model::{Protocol, RiskCalculationError},
+    server,
+};
+
+/// Risk of Solana lending protocols, served over HTTP or computed once
+#[derive(Debug, Parser)]
+#[command(name = "risk_model", version)]
+pub struct Cli {
+    /// Serves the API when left out
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Serves the API until SIGINT or SIGTERM
+    Serve,
+    /// Backfills the market history of a fresh deployment
+    Bootstrap,
+    /// Computes the risk of one protocol and prints it
+    Compute {
+        /// kamino or marginfi
+        #[arg(long, value_parser = parse_protocol)]
+        protocol: Protocol,
+        /// Prints the assessment as JSON instead of a summary
+        #[arg(long)]
+        json: bool,
+    },
+    /// Rebalances a wallet's portfolio to its target weights and prints the plan as JSON
+    Rebalance {
+        #[arg(long, value_parser = parse_wallet)]
+        wallet: Pubkey,
+        /// Prints the plan without recording the rebalance
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Replays the Kamino reserve's history and prints how well its risk warned of adverse events
+    Backtest {
+        /// Days replayed up to now
+        #[arg(long, default_value_t = 30)]
+        days: i64,
+        /// Risk from which an hour counts as a warning
+        #[arg(long, default_value_t = 50.0)]
+        threshold: f64,
+        /// How far ahead of an event a warning counts
+        #[arg(long, default_value_t = 24)]
+        horizon_hours: i64,
+        /// Prints every scored hour too
+        #[arg(long)]
+        hours: bool,
+    },
+}
+
+impl Command {
+    /// Whether the command prints its result to stdout, where logs would mix with it
+    pub fn prints_result(&self) -> bool {
+        matches!(
+            self,
+            Command::Compute { .. } | Command::Rebalance { .. } | Command::Backtest { .. }
+        )
+    }
+}
+
+fn parse_protocol(protocol: &str) -> Result<Protocol, String> {
+    Protocol::from_param(protocol).map_err(|e| e.to_string())
+}
+
+fn parse_wallet(wallet: &str) -> Result<Pubkey, String> {
+    Pubkey::from_str(wallet).map_err(|e| e.to_string())
+}
+
+/// Runs `command`, or serves the API without one
+pub async fn run(command: Option<Command>) -> Result<(), RiskCalculationError> {
+    match command.unwrap_or(Command::Serve) {
+        Command::Serve => {
+            server::run().await;
+            Ok(())
+        }
+        Command::Bootstrap => {
+            let state = server::start().await;
+            market_history::bootstrap(&state.redis_client).await
+        }
+        Command::Compute { protocol, json } => {
+            let state = server::start().await;
+            let registered = RegisteredProtocol::for_protocol(
+                &protocol,
+                state.redis_client.clone(),
+                state.config.kamino_reserve.clone(),
+            )?;
+            let assessment = ProtocolAssessment {
+                protocol,
+                scope: registered.scope(),
+                risk_metrics: registered.assess().await?,
+            };
+            if json {
+                println!("{}", serde_json::to_string_pretty(&assessment)?);
+            } else {
+                println!("{}", summary(&assessment));
+            }
+            Ok(())
+        }
+        Command::Rebalance { wallet, dry_run } => {
+            let state = server::start().await;
+            let plan = portfolio::rebalance_wallet(&state, &wallet, dry_run).await?;
+            println!("{}", serde_json::to_string_pretty(&plan)?);
+            Ok(())
+        }
+        Command::Backtest {
+            days,
+            threshold,
+            horizon_hours,
+            hours,
+        } => {
+            let state = server::start().await;
+            let to = Utc::now();
+            let report = backtest::backtest(
+                &state,
+                to - Duration::days(days),
+                to,
+                threshold,
+                horizon_hours,
+            )
+            .await?;
+            if hours {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("{}", serde_json::to_string_pretty(&report.summary)?);
+            }
+            Ok(())
+        }
+    }
+}
+
+fn summary(assessment: &ProtocolAssessment) -> String {
+    let metrics = &assessment.risk_metrics;
+    format!(
+        "{:?} ({})\noverall     {:.4}\nliquidity   {:.4}\nvolatility  {:.4}\nprotocol    {:.4}\noracle      {:.4}",
+        assessment.protocol,
+        assessment.scope,
+        metrics.overall_risk.overall_risk,
+        metrics.liquidity_risk.liquidity_risk,
+        metrics.volatility_risk.volatility_risk,
+        metrics.protocol_risk.protocol_risk,
+        metrics.oracle_risk.oracle_risk,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        let cli = Cli::try_parse_from(["risk_model"]).unwrap();
+        assert!(cli.command.is_none());
+
+        let cli = Cli::try_parse_from(["risk_model", "compute", "--protocol", "Kamino", "--json"])
+            .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Compute {
+                protocol: Protocol::Kamino,
+                json: true
+            })
+        ));
+
+        let wallet = Pubkey::new_unique();
+        let cli = Cli::try_parse_from([
+            "risk_model",
+            "rebalance",
+            "--wallet",
+            &wallet.to_string(),
+            "--dry-run",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Rebalance { wallet: parsed, dry_run: true }) if parsed == wallet
+        ));
+
+        let cli = Cli::try_parse_from(["risk_model", "backtest", "--days", "60"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Backtest {
+                days: 60,
+                horizon_hours: 24,
+                hours: false,
+                ..
+            })
+        ));
+
+        assert!(Cli::try_parse_from(["risk_model", "compute", "--protocol", "aave"]).is_err());
+        assert!(Cli::try_parse_from(["risk_model", "rebalance", "--wallet", "nope"]).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+
+use crate::upstream_fixtures::UpstreamFixtures;
+
+static PROCESS_CLOCK: OnceLock<Box<dyn Clock>> = OnceLock::new();
+
+/// Source of the current time
+///
+/// Time-dependent logic reads the time from a clock rather than the system so
+/// tests can set it instead of sleeping.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock stopped at a given time
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+/// The clock upstream requests are made with
+///
+/// Stopped at the recording's time while upstream responses are recorded or
+/// replayed, so the same requests are made, see [`UpstreamFixtures`]. The
+/// system's otherwise.
+pub fn process_clock() -> &'static dyn Clock {
+    PROCESS_CLOCK
+        .get_or_init(|| match UpstreamFixtures::global().now() {
+            Some(recorded_at) => Box::new(FixedClock(recorded_at)),
+            None => Box::new(SystemClock),
+        })
+        .as_ref()
+}
+
+/// Seconds from `now` to the start of the next hour, 3600 on the hour
+pub fn seconds_until_next_hour(now: DateTime<Utc>) -> u64 {
+    3600 - now.timestamp().rem_euclid(3600) as u64
+}
+
+/// Seconds from `now` to the next midnight UTC, a whole day at midnight
+pub fn seconds_until_next_day(now: DateTime<Utc>) -> u64 {
+    86_400 - now.timestamp().rem_euclid(86_400) as u64
+}
+
+/// A clock only moving when told to
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock {
+    now: std::sync::Mutex<DateTime<Utc>>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        MockClock {
+            now: std::sync::Mutex::new(now),
+        }
+    }
+
+    /// A clock stopped at `rfc3339`
+    pub fn at(rfc3339: &str) -> Self {
+        Self::new(
+            DateTime::parse_from_rfc3339(rfc3339)
+                .expect("mock clock time must be RFC 3339")
+                .with_timezone(&Utc),
+        )
+    }
+
+    pub fn advance(&self, by: std::time::Duration) {
+        let by =
+            chrono::Duration::from_std(by).expect("mock clock advance must fit a chrono duration");
+        *self.now.lock().unwrap() += by;
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_seconds_until_next_hour() {
+        let clock = MockClock::at("2024-05-01T10:00:00Z");
+        assert_eq!(seconds_until_next_hour(clock.now()), 3600);
+        clock.advance(Duration::from_secs(59 * 60 + 59));
+        assert_eq!(seconds_until_next_hour(clock.now()), 1);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(seconds_until_next_hour(clock.now()), 3600);
+        clock.set(DateTime::from_timestamp(90, 0).unwrap());
+        assert_eq!(seconds_until_next_hour(clock.now()), 3510);
+        assert_eq!(seconds_until_next_day(clock.now()), 86_310);
+    }
+}
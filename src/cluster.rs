@@ -0,0 +1,138 @@
+use std::sync::OnceLock;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::risk_model::RiskCalculationError;
+
+static CLUSTER: OnceLock<Cluster> = OnceLock::new();
+
+/// Solana cluster a deployment runs against
+///
+/// Everything chain specific follows it: RPC endpoints, program ids, the
+/// accounts assessed by default and the redis keys, so a devnet deployment of
+/// the whole stack can share infrastructure with mainnet without touching its data.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Cluster {
+    #[default]
+    MainnetBeta,
+    Devnet,
+}
+
+impl Cluster {
+    /// The cluster read at startup
+    pub fn global() -> Self {
+        *CLUSTER.get_or_init(|| Self::from_env().expect("SOLANA_CLUSTER must be valid"))
+    }
+
+    /// Reads `SOLANA_CLUSTER`, `mainnet-beta` or `devnet`, mainnet if not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        match std::env::var("SOLANA_CLUSTER") {
+            Ok(cluster) => Self::parse(&cluster),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn parse(cluster: &str) -> Result<Self, RiskCalculationError> {
+        match cluster.trim() {
+            "mainnet-beta" | "mainnet" => Ok(Cluster::MainnetBeta),
+            "devnet" => Ok(Cluster::Devnet),
+            other => Err(RiskCalculationError::ParseError(format!(
+                "SOLANA_CLUSTER must be mainnet-beta or devnet: {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Name of the cluster as Solana tooling and the Kamino API spell it
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Cluster::MainnetBeta => "mainnet-beta",
+            Cluster::Devnet => "devnet",
+        }
+    }
+
+    /// Helius RPC endpoint of the cluster
+    pub fn rpc_url(&self, api_key: &str) -> String {
+        let host = match self {
+            Cluster::MainnetBeta => "mainnet.helius-rpc.com",
+            Cluster::Devnet => "devnet.helius-rpc.com",
+        };
+        format!("https://{}?api-key={}", host, api_key)
+    }
+
+    /// Helius websocket endpoint of the cluster, for subscriptions
+    pub fn ws_url(&self, api_key: &str) -> String {
+        self.rpc_url(api_key).replacen("https://", "wss://", 1)
+    }
+
+    /// Kamino lending program, deployed under the same id on both clusters
+    pub fn kamino_program_id(&self) -> &'static str {
+        "KLend2g3cP87fffoy8q1mQqGKjrxjC8boSyAYavgmjD"
+    }
+
+    pub fn marginfi_program_id(&self) -> &'static str {
+        match self {
+            Cluster::MainnetBeta => "MFv2hWf31Z9kbCa1snEPYctwafyhdvnV7FZnsebVacA",
+            Cluster::Devnet => "neetcne3Ctrrud7vLdt2ypMm21gZHGN2mCmqWaMVcBQ",
+        }
+    }
+
+    /// Scopes a redis key to the cluster
+    ///
+    /// Mainnet keeps the keys it always had, so existing data stays where it is.
+    pub fn namespaced_key(&self, key: &str) -> String {
+        match self {
+            Cluster::MainnetBeta => key.to_string(),
+            Cluster::Devnet => format!("{}:{}", self.as_str(), key),
+        }
+    }
+}
+
+/// `HELIUS_API_KEY`, not ready without it
+///
+/// Not required at startup, the RPC pool can run on `RPC_FALLBACK_URLS` alone.
+fn helius_api_key() -> Result<String, RiskCalculationError> {
+    std::env::var("HELIUS_API_KEY")
+        .map_err(|_| RiskCalculationError::NotReady("HELIUS_API_KEY is not set".to_string()))
+}
+
+/// RPC endpoint of the configured cluster
+pub fn rpc_url() -> Result<String, RiskCalculationError> {
+    Ok(Cluster::global().rpc_url(&helius_api_key()?))
+}
+
+/// Websocket endpoint of the configured cluster
+pub fn ws_url() -> Result<String, RiskCalculationError> {
+    Ok(Cluster::global().ws_url(&helius_api_key()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cluster() {
+        assert_eq!(Cluster::parse("devnet").unwrap(), Cluster::Devnet);
+        assert_eq!(Cluster::parse("mainnet").unwrap(), Cluster::MainnetBeta);
+        assert!(Cluster::parse("testnet").is_err());
+
+        assert_eq!(
+            Cluster::Devnet.rpc_url("key"),
+            "https://devnet.helius-rpc.com?api-key=key"
+        );
+        assert_eq!(
+            Cluster::Devnet.ws_url("key"),
+            "wss://devnet.helius-rpc.com?api-key=key"
+        );
+        assert_eq!(
+            Cluster::MainnetBeta.namespaced_key("risk_alerts"),
+            "risk_alerts"
+        );
+        assert_eq!(
+            Cluster::Devnet.namespaced_key("risk_alerts"),
+            "devnet:risk_alerts"
+        );
+    }
+}
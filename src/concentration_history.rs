@@ -0,0 +1,202 @@
+use axum::{
+    extract::{Query, State},
+    response::Response,
+};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache_schema::versioned_key,
+    kamino::{reserve::KaminoReserveConfig, sources::LiveSources, KaminoRisk},
+    redis_connection::shared_connection,
+    risk_model::{json_response, timings_requested, RiskCalculationError},
+    state::AppState,
+    timings::with_timings,
+};
+
+/// How long concentration snapshots are kept, long enough to follow whales over weeks
+const CONCENTRATION_HISTORY_DAYS: i64 = 90;
+/// Range served when the request doesn't set one
+const DEFAULT_HISTORY_DAYS: i64 = 7;
+
+/// Deposit concentration of a reserve at one hour
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConcentrationSnapshot {
+    /// Start of the hour
+    pub timestamp: DateTime<Utc>,
+    pub largest_deposit: u128,
+    pub total_deposits: u128,
+    /// Herfindahl-Hirschman index of the deposit shares, `None` when only the
+    /// largest and total deposits were cached
+    pub hhi: Option<f64>,
+}
+
+impl ConcentrationSnapshot {
+    pub fn of_current_hour(largest_deposit: u128, total_deposits: u128, hhi: Option<f64>) -> Self {
+        ConcentrationSnapshot {
+            timestamp: Utc::now()
+                .duration_trunc(Duration::hours(1))
+                .unwrap_or_else(|_| Utc::now()),
+            largest_deposit,
+            total_deposits,
+            hhi,
+        }
+    }
+
+    /// Share of the largest deposit, between 0 and 1
+    pub fn concentration(&self) -> Option<f64> {
+        (self.total_deposits > 0).then(|| self.largest_deposit as f64 / self.total_deposits as f64)
+    }
+}
+
+/// Sorted set of a scope's concentration snapshots, scored by the unix timestamp of each hour
+pub fn concentration_history_key(scope: &str) -> String {
+    versioned_key(&format!("concentration_history:{}", scope))
+}
+
+/// Stores the snapshot of an hour, replacing an earlier one of the same hour
+///
+/// Snapshots older than the retention are dropped.
+pub async fn record_concentration(
+    redis_client: &redis::Client,
+    scope: &str,
+    snapshot: &ConcentrationSnapshot,
+) -> Result<(), RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let key = concentration_history_key(scope);
+    let timestamp = snapshot.timestamp.timestamp();
+    let retention_start = Utc::now() - Duration::days(CONCENTRATION_HISTORY_DAYS);
+
+    let _: () = redis::pipe()
+        .atomic()
+        .zrembyscore(&key, timestamp, timestamp)
+        .ignore()
+        .zadd(
+            &key,
+            serde_json::to_string(snapshot).map_err(RiskCalculationError::SerdeError)?,
+            timestamp,
+        )
+        .ignore()
+        .zrembyscore(&key, "-inf", format!("({}", retention_start.timestamp()))
+        .ignore()
+        .query_async(&mut connection)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    Ok(())
+}
+
+/// Reads the snapshots of a scope between `from` and `to` (inclusive), oldest first
+pub async fn load_concentration_history(
+    redis_client: &redis::Client,
+    scope: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<ConcentrationSnapshot>, RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let snapshots: Vec<String> = connection
+        .zrangebyscore(
+            concentration_history_key(scope),
+            from.timestamp(),
+            to.timestamp(),
+        )
+        .await
+        .map_err(RiskCalculationError::from)?;
+    snapshots
+        .iter()
+        .map(|snapshot| serde_json::from_str(snapshot).map_err(RiskCalculationError::SerdeError))
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConcentrationHistoryQuery {
+    /// Start of the range, defaults to a week before `to`
+    pub from: Option<DateTime<Utc>>,
+    /// End of the range, defaults to now
+    pub to: Option<DateTime<Utc>>,
+    /// Kamino lending market, requires `reserve`
+    pub market: Option<String>,
+    /// Kamino reserve, requires `market`
+    pub reserve: Option<String>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+/// Hourly deposit concentration of a Kamino reserve, `/risk_model/kamino/concentration/history`
+pub async fn kamino_concentration_history(
+    State(state): State<AppState>,
+    Query(query): Query<ConcentrationHistoryQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let scope = KaminoRisk {
+            redis_client: state.redis_client.clone(),
+            reserve: KaminoReserveConfig::from_params(
+                query.market.as_deref(),
+                query.reserve.as_deref(),
+                &state.config.kamino_reserve,
+            )?,
+            sources: LiveSources,
+        }
+        .scope();
+        let to = query.to.unwrap_or_else(Utc::now);
+        let from = query
+            .from
+            .unwrap_or(to - Duration::days(DEFAULT_HISTORY_DAYS));
+        let mut snapshots =
+            load_concentration_history(&state.redis_client, &scope, from, to).await?;
+        for snapshot in &mut snapshots {
+            snapshot.largest_deposit = state.config.privacy.amount(snapshot.largest_deposit);
+        }
+        let points: Vec<serde_json::Value> = snapshots
+            .iter()
+            .map(|snapshot| {
+                serde_json::json!({
+                    "timestamp": snapshot.timestamp,
+                    "largest_deposit": snapshot.largest_deposit,
+                    "total_deposits": snapshot.total_deposits,
+                    "deposit_concentration": snapshot.concentration(),
+                    "hhi": snapshot.hhi,
+                })
+            })
+            .collect();
+
+        Ok::<_, RiskCalculationError>(serde_json::json!({
+            "scope": scope,
+            "from": from,
+            "to": to,
+            "points": points,
+        }))
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::liquidity_risk::calculate_deposit_hhi;
+
+    #[test]
+    fn test_concentration_snapshot() {
+        let hhi = calculate_deposit_hhi(&[600, 300, 100]);
+        assert!((hhi.unwrap() - 0.46).abs() < 1e-12);
+        assert_eq!(calculate_deposit_hhi(&[]), None);
+
+        let snapshot = ConcentrationSnapshot::of_current_hour(600, 1_000, hhi);
+        assert_eq!(snapshot.timestamp.timestamp() % 3600, 0);
+        assert_eq!(snapshot.concentration(), Some(0.6));
+        assert_eq!(
+            ConcentrationSnapshot::of_current_hour(0, 0, None).concentration(),
+            None
+        );
+        assert_eq!(
+            concentration_history_key("kamino-reserve"),
+            "v2:concentration_history:kamino-reserve"
+        );
+    }
+}
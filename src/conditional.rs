@@ -0,0 +1,180 @@
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, DurationRound, Utc};
+
+use crate::encoding::ResponseFormat;
+
+/// Format of `Last-Modified` and `If-Modified-Since`
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// What a client revalidates a cached response with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validators {
+    /// Weak, bodies of the same version differ in e.g. their age
+    pub etag: String,
+    pub last_modified: DateTime<Utc>,
+}
+
+impl Validators {
+    /// Validators of the response to `variant`, e.g. the query string, built
+    /// from data identified by `version` and modified at `last_modified`
+    ///
+    /// The negotiated [`ResponseFormat`] is part of the variant, so a JSON copy
+    /// never revalidates a MessagePack or CBOR request.
+    pub fn new(version: &str, variant: &str, last_modified: DateTime<Utc>) -> Self {
+        let format = ResponseFormat::negotiated().content_type();
+        let hash =
+            solana_sdk::hash::hashv(&[version.as_bytes(), variant.as_bytes(), format.as_bytes()]);
+        Validators {
+            etag: format!("W/\"{}\"", &hash.to_string()[..16]),
+            last_modified: last_modified
+                .duration_trunc(chrono::Duration::seconds(1))
+                .unwrap_or(last_modified),
+        }
+    }
+
+    /// Whether the client's cached response is still current
+    ///
+    /// `If-None-Match` takes precedence over `If-Modified-Since`, as in RFC 9110.
+    pub fn not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH) {
+            let Ok(if_none_match) = if_none_match.to_str() else {
+                return false;
+            };
+            let opaque = |etag: &str| etag.trim().trim_start_matches("W/").to_string();
+            return if_none_match
+                .split(',')
+                .any(|etag| etag.trim() == "*" || opaque(etag) == opaque(&self.etag));
+        }
+        headers
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|since| since.to_str().ok())
+            .and_then(|since| DateTime::parse_from_rfc2822(since).ok())
+            .is_some_and(|since| self.last_modified <= since)
+    }
+
+    fn insert(&self, headers: &mut HeaderMap) {
+        if let Ok(etag) = HeaderValue::from_str(&self.etag) {
+            headers.insert(header::ETAG, etag);
+        }
+        if let Ok(last_modified) =
+            HeaderValue::from_str(&self.last_modified.format(HTTP_DATE_FORMAT).to_string())
+        {
+            headers.insert(header::LAST_MODIFIED, last_modified);
+        }
+    }
+}
+
+/// Adds `validators` to a successful `response`, or answers 304 without a body
+/// when the client's copy is still current
+///
+/// `Vary: Accept` tells caches the body depends on the negotiated format.
+pub fn conditional_response(
+    validators: Option<Validators>,
+    request_headers: &HeaderMap,
+    response: Response,
+) -> Response {
+    let Some(validators) = validators else {
+        return response;
+    };
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let mut response = if validators.not_modified(request_headers) {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        response
+    };
+    validators.insert(response.headers_mut());
+    response
+        .headers_mut()
+        .insert(header::VARY, HeaderValue::from_static("accept"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validators() {
+        let computed_at: DateTime<Utc> = "2024-05-01T13:00:00.250Z".parse().unwrap();
+        let validators = Validators::new("snapshot-1", "lang=en", computed_at);
+        assert_eq!(
+            validators,
+            Validators::new("snapshot-1", "lang=en", computed_at)
+        );
+        assert_ne!(
+            validators.etag,
+            Validators::new("snapshot-1", "lang=es", computed_at).etag
+        );
+
+        let mut headers = HeaderMap::new();
+        assert!(!validators.not_modified(&headers));
+        headers.insert(
+            header::IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Wed, 01 May 2024 13:00:00 GMT"),
+        );
+        assert!(validators.not_modified(&headers));
+        headers.insert(
+            header::IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Wed, 01 May 2024 12:59:59 GMT"),
+        );
+        assert!(!validators.not_modified(&headers));
+
+        // The ETag wins over the date
+        let opaque = validators.etag.trim_start_matches("W/").to_string();
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_str(&format!("\"other\", {}", opaque)).unwrap(),
+        );
+        assert!(validators.not_modified(&headers));
+        headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        assert!(!validators.not_modified(&headers));
+
+        let response = conditional_response(
+            Some(validators.clone()),
+            &HeaderMap::new(),
+            StatusCode::OK.into_response(),
+        );
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::VARY], "accept");
+        assert_eq!(
+            response.headers()[header::LAST_MODIFIED],
+            "Wed, 01 May 2024 13:00:00 GMT"
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_str(&validators.etag).unwrap(),
+        );
+        let response =
+            conditional_response(Some(validators), &headers, StatusCode::OK.into_response());
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[tokio::test]
+    async fn test_validators_depend_on_format() {
+        let computed_at: DateTime<Utc> = "2024-05-01T13:00:00Z".parse().unwrap();
+        let etag = |accept: &'static str| async move {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_static(accept));
+            crate::encoding::with_format(ResponseFormat::from_headers(&headers), async {
+                Validators::new("snapshot-1", "lang=en", computed_at).etag
+            })
+            .await
+        };
+        let json = etag("application/json").await;
+        assert_eq!(
+            json,
+            Validators::new("snapshot-1", "lang=en", computed_at).etag
+        );
+        assert_ne!(json, etag("application/msgpack").await);
+        assert_ne!(
+            etag("application/msgpack").await,
+            etag("application/cbor").await
+        );
+    }
+}
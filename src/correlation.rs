@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, HashMap};
+
+use axum::{
+    extract::{Query, State},
+    response::Response,
+    Json,
+};
+use chrono::{Duration, NaiveDate, Utc};
+use futures::future::join_all;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    market_history::MarketPoint,
+    registry::ProtocolAssessment,
+    risk_model::{json_response, timings_requested, DebugQuery, Protocol, RiskCalculationError},
+    state::AppState,
+    timings::with_timings,
+};
+
+/// History the correlations are computed over
+const CORRELATION_DAYS: i64 = 30;
+/// Fewer common days than this say nothing about how two protocols move together
+const MIN_COMMON_DAYS: usize = 7;
+
+/// How two protocols moved together over the common days of their history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PairCorrelation {
+    pub protocols: (Protocol, Protocol),
+    /// Pearson correlation of the daily utilization, `None` without enough common days
+    pub utilization: Option<f64>,
+    /// Pearson correlation of the daily supply APY, `None` without enough common days
+    pub apy: Option<f64>,
+    pub common_days: usize,
+}
+
+impl PairCorrelation {
+    /// Correlation of the risks of the pair, between 0 and 1
+    ///
+    /// The stronger of both correlations, as either makes the protocols stress
+    /// at the same time. Unknown correlations count as full, negative ones as
+    /// none, so diversification is never credited without evidence.
+    pub fn risk_correlation(&self) -> f64 {
+        match (self.utilization, self.apy) {
+            (None, None) => 1.0,
+            (utilization, apy) => utilization
+                .unwrap_or(f64::MIN)
+                .max(apy.unwrap_or(f64::MIN))
+                .clamp(0.0, 1.0),
+        }
+    }
+}
+
+/// Pearson correlation of two series of equal length
+///
+/// `None` when either series is constant, as the correlation is undefined then.
+pub fn pearson(a: &[f64], b: &[f64]) -> Option<f64> {
+    if a.len() != b.len() || a.is_empty() {
+        return None;
+    }
+    let n = a.len() as f64;
+    let mean_a = a.iter().sum::<f64>() / n;
+    let mean_b = b.iter().sum::<f64>() / n;
+    let (mut covariance, mut variance_a, mut variance_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        covariance += (x - mean_a) * (y - mean_b);
+        variance_a += (x - mean_a).powi(2);
+        variance_b += (y - mean_b).powi(2);
+    }
+    if variance_a <= f64::EPSILON || variance_b <= f64::EPSILON {
+        return None;
+    }
+    Some(covariance / (variance_a * variance_b).sqrt())
+}
+
+/// Mean `(utilization, supply APY)` of each day
+///
+/// Brings hourly and daily histories onto the same grid.
+pub fn daily_means(points: &[MarketPoint]) -> BTreeMap<NaiveDate, (f64, f64)> {
+    let mut sums: BTreeMap<NaiveDate, (f64, f64, usize)> = BTreeMap::new();
+    for point in points {
+        let day = sums.entry(point.timestamp.date_naive()).or_default();
+        day.0 += point.utilization_percent;
+        day.1 += point.supply_apy_percent;
+        day.2 += 1;
+    }
+    sums.into_iter()
+        .map(|(date, (utilization, apy, count))| {
+            (date, (utilization / count as f64, apy / count as f64))
+        })
+        .collect()
+}
+
+/// Correlations of every pair of `histories`, on the days both have points
+pub fn pairwise_correlations(histories: &[(Protocol, Vec<MarketPoint>)]) -> Vec<PairCorrelation> {
+    let daily: Vec<_> = histories
+        .iter()
+        .map(|(protocol, points)| (protocol, daily_means(points)))
+        .collect();
+
+    let mut correlations = Vec::new();
+    for (i, (protocol_a, days_a)) in daily.iter().enumerate() {
+        for (protocol_b, days_b) in &daily[i + 1..] {
+            let common: Vec<((f64, f64), (f64, f64))> = days_a
+                .iter()
+                .filter_map(|(date, a)| days_b.get(date).map(|b| (*a, *b)))
+                .collect();
+            let correlation = |pick: fn(&(f64, f64)) -> f64| {
+                if common.len() < MIN_COMMON_DAYS {
+                    return None;
+                }
+                let a: Vec<f64> = common.iter().map(|(a, _)| pick(a)).collect();
+                let b: Vec<f64> = common.iter().map(|(_, b)| pick(b)).collect();
+                pearson(&a, &b)
+            };
+            correlations.push(PairCorrelation {
+                protocols: ((*protocol_a).clone(), (*protocol_b).clone()),
+                utilization: correlation(|day| day.0),
+                apy: correlation(|day| day.1),
+                common_days: common.len(),
+            });
+        }
+    }
+    correlations
+}
+
+/// Risk of a single allocation within the portfolio
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AllocationRisk {
+    pub protocol: Protocol,
+    /// Share of the portfolio, between 0 and 1
+    pub weight: f64,
+    pub overall_risk: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortfolioRisk {
+    pub allocations: Vec<AllocationRisk>,
+    /// Allocation weighted overall risk, the portfolio risk if every protocol moved together
+    pub weighted_risk: f64,
+    /// Risk with the correlations between the protocols accounted for
+    pub portfolio_risk: f64,
+    /// How much of the weighted risk the correlations take off
+    pub diversification_benefit: f64,
+    pub correlations: Vec<PairCorrelation>,
+}
+
+/// Scores an allocation as `sqrt(Σᵢ Σⱼ wᵢ wⱼ rᵢ rⱼ ρᵢⱼ)`
+///
+/// `wᵢ` is the share of protocol `i`, `rᵢ` its overall risk and `ρᵢⱼ` the
+/// [`PairCorrelation::risk_correlation`] of a pair. Fully correlated protocols
+/// score the weighted risk, uncorrelated ones less. Protocols without an
+/// assessment are rejected, their risk being unknown.
+pub fn portfolio_risk(
+    allocation: &HashMap<Protocol, u64>,
+    ranking: &[ProtocolAssessment],
+    correlations: Vec<PairCorrelation>,
+) -> Result<PortfolioRisk, RiskCalculationError> {
+    let total = allocation.values().sum::<u64>();
+    if total == 0 {
+        return Err(RiskCalculationError::InvalidParameter(
+            "allocation must not be empty".to_string(),
+        ));
+    }
+    let mut allocations = allocation
+        .iter()
+        .filter(|(_, amount)| **amount > 0)
+        .map(|(protocol, amount)| {
+            let overall_risk = ranking
+                .iter()
+                .find(|assessment| assessment.protocol == *protocol)
+                .map(|assessment| assessment.risk_metrics.overall_risk.overall_risk)
+                .ok_or(RiskCalculationError::InvalidParameter(format!(
+                    "{:?} has no risk assessment",
+                    protocol
+                )))?;
+            Ok(AllocationRisk {
+                protocol: protocol.clone(),
+                weight: *amount as f64 / total as f64,
+                overall_risk,
+            })
+        })
+        .collect::<Result<Vec<_>, RiskCalculationError>>()?;
+    allocations.sort_by(|a, b| b.weight.total_cmp(&a.weight));
+
+    let correlation = |a: &Protocol, b: &Protocol| {
+        if a == b {
+            return 1.0;
+        }
+        correlations
+            .iter()
+            .find(|pair| {
+                pair.protocols == (a.clone(), b.clone()) || pair.protocols == (b.clone(), a.clone())
+            })
+            .map_or(1.0, PairCorrelation::risk_correlation)
+    };
+    let mut variance = 0.0;
+    for a in &allocations {
+        for b in &allocations {
+            variance += a.weight
+                * b.weight
+                * a.overall_risk
+                * b.overall_risk
+                * correlation(&a.protocol, &b.protocol);
+        }
+    }
+    let weighted_risk = allocations
+        .iter()
+        .map(|allocation| allocation.weight * allocation.overall_risk)
+        .sum::<f64>();
+    let portfolio_risk = variance.sqrt();
+
+    Ok(PortfolioRisk {
+        allocations,
+        weighted_risk,
+        portfolio_risk,
+        diversification_benefit: weighted_risk - portfolio_risk,
+        correlations,
+    })
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct PortfolioRiskRequest {
+    /// Amount, or basis points, allocated to each protocol
+    pub allocation: HashMap<Protocol, u64>,
+}
+
+/// Correlation aware risk of an allocation across protocols
+///
+/// Correlations are computed over the last days of each protocol's market
+/// history, risks are those of the latest snapshot.
+pub async fn portfolio_risk_handler(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+    Json(request): Json<PortfolioRiskRequest>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let snapshot = state.registry.cached_snapshot().await?;
+        let from = Utc::now() - Duration::days(CORRELATION_DAYS);
+        let registered = request
+            .allocation
+            .keys()
+            .map(|protocol| {
+                state
+                    .registry
+                    .registered(protocol)
+                    .ok_or(RiskCalculationError::InvalidParameter(format!(
+                        "{:?} is not supported",
+                        protocol
+                    )))
+            })
+            .collect::<Result<Vec<_>, RiskCalculationError>>()?;
+        let histories = join_all(registered.iter().map(|registered| async move {
+            let history = registered.market_history(from).await.unwrap_or_else(|e| {
+                // Without history the pair counts as fully correlated
+                tracing::error!(
+                    "Failed to load market history of {:?}: {}",
+                    registered.protocol(),
+                    e
+                );
+                Vec::new()
+            });
+            (registered.protocol(), history)
+        }))
+        .await;
+
+        let risk = portfolio_risk(
+            &request.allocation,
+            &snapshot.comparison.ranking,
+            pairwise_correlations(&histories),
+        )?;
+        Ok::<_, RiskCalculationError>(serde_json::json!({
+            "snapshot_id": snapshot.snapshot_id,
+            "correlation_days": CORRELATION_DAYS,
+            "risk": risk,
+        }))
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::risk_model::{ProtocolRiskMetrics, RiskResponse, RiskScore, SCHEMA_VERSION};
+    use chrono::{DateTime, TimeZone};
+
+    fn history(days: i64, utilization: impl Fn(i64) -> f64) -> Vec<MarketPoint> {
+        let start: DateTime<Utc> = Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap();
+        (0..days * 2)
+            .map(|half_day| MarketPoint {
+                timestamp: start + Duration::hours(12 * half_day),
+                supply_apy_percent: 5.0,
+                utilization_percent: utilization(half_day / 2),
+                tvl_usd: None,
+            })
+            .collect()
+    }
+
+    fn assessment(protocol: Protocol, overall_risk: f64) -> ProtocolAssessment {
+        ProtocolAssessment {
+            protocol,
+            scope: String::new(),
+            risk_metrics: RiskResponse {
+                schema_version: SCHEMA_VERSION,
+                protocol_risk: ProtocolRiskMetrics {
+                    protocol_risk: 0.0,
+                    penalties: Vec::new(),
+                    components: Vec::new(),
+                },
+                overall_risk: RiskScore {
+                    overall_risk,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_portfolio_risk() {
+        assert_eq!(pearson(&[1.0, 2.0, 3.0], &[2.0, 4.0, 6.0]), Some(1.0));
+        assert_eq!(pearson(&[1.0, 2.0, 3.0], &[3.0, 2.0, 1.0]), Some(-1.0));
+        assert_eq!(pearson(&[1.0, 1.0, 1.0], &[1.0, 2.0, 3.0]), None);
+
+        // Half-daily points are averaged per day, the APY stays constant
+        let kamino = history(10, |day| 50.0 + day as f64);
+        let correlations = pairwise_correlations(&[
+            (Protocol::Kamino, kamino.clone()),
+            (Protocol::Marginfy, history(10, |day| 80.0 - day as f64)),
+        ]);
+        assert_eq!(correlations.len(), 1);
+        assert_eq!(correlations[0].common_days, 10);
+        assert!((correlations[0].utilization.unwrap() + 1.0).abs() < 1e-9);
+        assert_eq!(correlations[0].apy, None);
+        assert_eq!(correlations[0].risk_correlation(), 0.0);
+
+        let too_short = pairwise_correlations(&[
+            (Protocol::Kamino, kamino),
+            (Protocol::Marginfy, history(3, |day| day as f64)),
+        ]);
+        assert_eq!(too_short[0].utilization, None);
+        assert_eq!(too_short[0].risk_correlation(), 1.0);
+
+        let ranking = [
+            assessment(Protocol::Kamino, 40.0),
+            assessment(Protocol::Marginfy, 60.0),
+        ];
+        let allocation = HashMap::from([(Protocol::Kamino, 5_000), (Protocol::Marginfy, 5_000)]);
+
+        // Uncorrelated protocols diversify
+        let risk = portfolio_risk(&allocation, &ranking, correlations).unwrap();
+        assert!((risk.weighted_risk - 50.0).abs() < 1e-9);
+        assert!((risk.portfolio_risk - (20f64.powi(2) + 30f64.powi(2)).sqrt()).abs() < 1e-9);
+        assert!(risk.diversification_benefit > 0.0);
+
+        // Unknown correlations count as full, leaving the weighted risk
+        let risk = portfolio_risk(&allocation, &ranking, too_short).unwrap();
+        assert!((risk.portfolio_risk - risk.weighted_risk).abs() < 1e-9);
+
+        let unassessed = HashMap::from([(Protocol::Drift, 1)]);
+        assert!(portfolio_risk(&unassessed, &ranking, Vec::new()).is_err());
+        assert!(portfolio_risk(&HashMap::new(), &ranking, Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::{risk_model::RiskCalculationError, upstream_fixtures::get_text};
+
+/// DefiLlama yields API, used where protocols don't publish the data themselves
+/// and as an independent source to cross-check protocol data against
+const DEFILLAMA_POOLS_URL: &str = "https://yields.llama.fi/pools";
+/// Daily history of a pool
+const DEFILLAMA_CHART_URL: &str = "https://yields.llama.fi/chart";
+
+#[derive(Debug, Deserialize)]
+struct PoolsResponse {
+    data: Vec<Pool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pool {
+    pub pool: String,
+    pub chain: String,
+    pub project: String,
+    pub symbol: String,
+    pub total_supply_usd: Option<f64>,
+    pub total_borrow_usd: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChartResponse {
+    data: Vec<ChartEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChartEntry {
+    timestamp: DateTime<Utc>,
+    tvl_usd: Option<f64>,
+}
+
+/// Looks up a Solana lending pool of `project` (DefiLlama slug) for the token `symbol`
+pub async fn find_pool(project: &str, symbol: &str) -> Result<Pool, RiskCalculationError> {
+    let raw_data = get_text(DEFILLAMA_POOLS_URL).await?;
+    let pools: PoolsResponse =
+        serde_json::from_str(&raw_data).map_err(RiskCalculationError::SerdeError)?;
+    pools
+        .data
+        .into_iter()
+        .find(|pool| pool.project == project && pool.chain == "Solana" && pool.symbol == symbol)
+        .ok_or(RiskCalculationError::UpstreamUnavailable(format!(
+            "{} {} pool not found on DefiLlama",
+            project, symbol
+        )))
+}
+
+/// Current `(total_borrows, total_supply)` of a stablecoin pool
+///
+/// DefiLlama reports USD values, which equal token units for USD stablecoins only.
+pub async fn get_stablecoin_borrows_and_supply(
+    project: &str,
+    symbol: &str,
+) -> Result<(f64, f64), RiskCalculationError> {
+    let pool = find_pool(project, symbol).await?;
+    match (pool.total_borrow_usd, pool.total_supply_usd) {
+        (Some(borrows), Some(supply)) => Ok((borrows, supply)),
+        _ => Err(RiskCalculationError::UpstreamUnavailable(format!(
+            "DefiLlama has no supply data for {} {}",
+            project, symbol
+        ))),
+    }
+}
+
+/// Daily TVL in USD of a Solana lending pool, oldest first
+pub async fn fetch_tvl_history(
+    project: &str,
+    symbol: &str,
+) -> Result<Vec<(DateTime<Utc>, f64)>, RiskCalculationError> {
+    let pool_id = find_pool(project, symbol).await?.pool;
+    let raw_data = get_text(&format!("{}/{}", DEFILLAMA_CHART_URL, pool_id)).await?;
+    let chart: ChartResponse =
+        serde_json::from_str(&raw_data).map_err(RiskCalculationError::SerdeError)?;
+    Ok(chart
+        .data
+        .into_iter()
+        .filter_map(|entry| Some((entry.timestamp, entry.tvl_usd?)))
+        .collect())
+}
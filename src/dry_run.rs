@@ -0,0 +1,258 @@
+use axum::{extract::Query, response::Response, Json};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    liquidity_risk::{
+        calculate_borrower_concentration, calculate_tvl_trend, liquidity_metrics, with_caps,
+        ReserveCaps,
+    },
+    oracle_risk::OracleRiskMetrics,
+    risk_model::{
+        json_response, timings_requested, DebugQuery, ProtocolRiskMetrics, RiskCalculationError,
+        RiskResponse, RiskScore, SCHEMA_VERSION,
+    },
+    timings::{timed_sync, with_timings, Timing},
+    volatility_risk::calculate_volatility_surface,
+    weights::RiskWeightsConfig,
+};
+
+fn default_sample_interval_hours() -> u64 {
+    1
+}
+
+/// Raw inputs of every pillar, in the units the data pipeline produces them
+#[derive(Debug, Deserialize)]
+pub struct ComputeRequest {
+    /// Deposit of every depositor, in native units
+    pub deposits: Vec<u128>,
+    /// What every borrower owes, borrower concentration is left out without it
+    #[serde(default)]
+    pub borrows: Vec<f64>,
+    pub total_borrows: f64,
+    pub total_supply: f64,
+    /// APY series in percent, oldest first
+    pub yields_percent: Vec<f64>,
+    /// Utilization series in percent, oldest first
+    pub utilization_rates_percent: Vec<f64>,
+    /// Total supply series, oldest first, the TVL trend is left out without a day of it
+    #[serde(default)]
+    pub supplies: Vec<f64>,
+    /// Caps and available liquidity of the reserve, left out of the score without them
+    #[serde(default)]
+    pub caps: Option<ReserveCaps>,
+    /// Hours between two points of the series
+    #[serde(default = "default_sample_interval_hours")]
+    pub sample_interval_hours: u64,
+    /// Protocol and oracle risk aren't derived from raw data, they're taken as given
+    #[serde(default)]
+    pub protocol_risk: f64,
+    #[serde(default)]
+    pub oracle_risk: f64,
+    /// The configured weights if not given
+    pub weights: Option<RiskWeightsConfig>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ComputeResponse {
+    /// The weights the metrics were computed with
+    pub weights: RiskWeightsConfig,
+    pub risk_metrics: RiskResponse,
+}
+
+/// Computes every metric from `request` with the same functions the protocol
+/// implementations use on fetched data
+pub fn compute(request: &ComputeRequest) -> Result<ComputeResponse, RiskCalculationError> {
+    let weights = match &request.weights {
+        Some(weights) => {
+            weights.validate()?;
+            weights.clone()
+        }
+        None => RiskWeightsConfig::global().clone(),
+    };
+
+    let largest_deposit =
+        *request
+            .deposits
+            .iter()
+            .max()
+            .ok_or(RiskCalculationError::InvalidParameter(
+                "No deposits found".to_string(),
+            ))?;
+    if request.sample_interval_hours == 0 {
+        return Err(RiskCalculationError::InvalidParameter(
+            "sample_interval_hours must be positive".to_string(),
+        ));
+    }
+    let liquidity_risk = liquidity_metrics(
+        largest_deposit,
+        request.deposits.iter().sum::<u128>(),
+        request.total_borrows,
+        request.total_supply,
+        calculate_borrower_concentration(&request.borrows),
+        calculate_tvl_trend(&request.supplies, request.sample_interval_hours),
+        &weights.liquidity,
+    )?;
+    let liquidity_risk = match request.caps {
+        Some(caps) => with_caps(liquidity_risk, caps),
+        None => liquidity_risk,
+    };
+    let volatility_risk = calculate_volatility_surface(
+        &request.yields_percent,
+        &request.utilization_rates_percent,
+        request.sample_interval_hours,
+        weights.volatility.apy,
+        weights.volatility.utilization,
+        &weights.volatility_blend,
+    )
+    .ok_or(RiskCalculationError::InvalidParameter(
+        "Insufficient data".to_string(),
+    ))?;
+
+    let overall_risk = RiskScore::from_raw(
+        &weights,
+        Some(liquidity_risk.liquidity_risk),
+        Some(volatility_risk.volatility_risk),
+        Some(request.protocol_risk),
+        Some(request.oracle_risk),
+    )
+    .ok_or(RiskCalculationError::InvalidParameter(
+        "every risk weight is 0".to_string(),
+    ))?;
+    Ok(ComputeResponse {
+        weights,
+        risk_metrics: RiskResponse {
+            schema_version: SCHEMA_VERSION,
+            liquidity_risk,
+            volatility_risk,
+            protocol_risk: ProtocolRiskMetrics {
+                protocol_risk: request.protocol_risk,
+                ..Default::default()
+            },
+            oracle_risk: OracleRiskMetrics {
+                oracle_risk: request.oracle_risk,
+                ..Default::default()
+            },
+            overall_risk,
+            computed_at: Some(Utc::now()),
+            stale: false,
+        },
+    })
+}
+
+/// Dry run: computes the risk metrics of the supplied data without fetching or caching anything
+pub async fn compute_risk(
+    Query(query): Query<DebugQuery>,
+    Json(request): Json<ComputeRequest>,
+) -> Response {
+    let (result, timings) =
+        with_timings(async { timed_sync(Timing::Compute, || compute(&request)) }).await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> ComputeRequest {
+        // A month of hourly points
+        let series = |base: f64| (0..720).map(|i| base + (i % 24) as f64 * 0.1).collect();
+        ComputeRequest {
+            deposits: vec![600, 300, 100],
+            borrows: Vec::new(),
+            total_borrows: 80.0,
+            total_supply: 100.0,
+            yields_percent: series(5.0),
+            utilization_rates_percent: series(80.0),
+            supplies: Vec::new(),
+            caps: None,
+            sample_interval_hours: 1,
+            protocol_risk: 30.0,
+            oracle_risk: 10.0,
+            weights: Some(RiskWeightsConfig::default()),
+        }
+    }
+
+    #[test]
+    fn test_compute() {
+        let response = compute(&request()).unwrap();
+        let liquidity = &response.risk_metrics.liquidity_risk;
+        assert_eq!(liquidity.deposit_concentration, 0.6);
+        assert_eq!(liquidity.utilization_rate, 80.0);
+        // Concentration is scored in percent, like utilization
+        assert!((liquidity.liquidity_risk - (0.6 * 80.0 + 0.4 * 60.0)).abs() < 1e-9);
+        assert!(liquidity.borrower_concentration.is_none());
+
+        let volatility = &response.risk_metrics.volatility_risk;
+        let expected = response.weights.overall.score(
+            liquidity.liquidity_risk,
+            response
+                .weights
+                .normalization
+                .volatility
+                .apply(volatility.volatility_risk),
+            30.0,
+            10.0,
+        );
+        assert!((response.risk_metrics.overall_risk.overall_risk - expected).abs() < 1e-9);
+
+        // A whale borrower raises the scored concentration
+        let with_borrows = compute(&ComputeRequest {
+            borrows: vec![70.0, 10.0],
+            ..request()
+        })
+        .unwrap();
+        assert!(with_borrows.risk_metrics.liquidity_risk.liquidity_risk > liquidity.liquidity_risk);
+
+        // So does a day of outflows
+        let mut supplies = vec![125.0; 24];
+        supplies.push(100.0);
+        let with_outflow = compute(&ComputeRequest {
+            supplies,
+            ..request()
+        })
+        .unwrap();
+        let outflow = &with_outflow.risk_metrics.liquidity_risk;
+        assert_eq!(outflow.tvl_change_24h_pct, Some(-20.0));
+        assert!(outflow.tvl_change_7d_pct.is_none());
+        assert!(outflow.liquidity_risk > liquidity.liquidity_risk);
+
+        // And nearing a cap, here borrows at 90% of theirs
+        let near_cap = compute(&ComputeRequest {
+            caps: Some(ReserveCaps {
+                available_liquidity: 20.0,
+                supply_cap: None,
+                borrow_cap: Some(88.888_888_888_888_89),
+            }),
+            ..request()
+        })
+        .unwrap();
+        let near_cap = &near_cap.risk_metrics.liquidity_risk;
+        assert_eq!(near_cap.available_liquidity, Some(20.0));
+        assert_eq!(near_cap.supply_cap_utilization, None);
+        assert!((near_cap.borrow_cap_utilization.unwrap() - 90.0).abs() < 1e-9);
+        assert!((near_cap.liquidity_risk - liquidity.liquidity_risk - 12.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_compute_rejects_invalid_inputs() {
+        assert!(compute(&ComputeRequest {
+            deposits: Vec::new(),
+            ..request()
+        })
+        .is_err());
+        assert!(compute(&ComputeRequest {
+            yields_percent: vec![5.0],
+            ..request()
+        })
+        .is_err());
+        let mut weights = RiskWeightsConfig::default();
+        weights.overall.liquidity = 0.9;
+        assert!(compute(&ComputeRequest {
+            weights: Some(weights),
+            ..request()
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::Value;
+
+tokio::task_local! {
+    static NEGOTIATED: ResponseFormat;
+}
+
+/// Response body formats negotiated through the `Accept` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl ResponseFormat {
+    /// The first supported media type of the `Accept` header, JSON if there's none
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .and_then(|accept| {
+                accept.split(',').find_map(|media_type| {
+                    match media_type.split(';').next().unwrap_or_default().trim() {
+                        "application/json" => Some(ResponseFormat::Json),
+                        "application/msgpack" | "application/x-msgpack" => {
+                            Some(ResponseFormat::MessagePack)
+                        }
+                        "application/cbor" => Some(ResponseFormat::Cbor),
+                        _ => None,
+                    }
+                })
+            })
+            .unwrap_or(ResponseFormat::Json)
+    }
+
+    /// The format negotiated for the request being served, JSON outside of one
+    pub fn negotiated() -> Self {
+        NEGOTIATED
+            .try_with(|format| *format)
+            .unwrap_or(ResponseFormat::Json)
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ResponseFormat::Json => "application/json",
+            ResponseFormat::MessagePack => "application/msgpack",
+            ResponseFormat::Cbor => "application/cbor",
+        }
+    }
+
+    pub fn encode(&self, value: &Value) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            // Serializing a `Value` can't fail
+            ResponseFormat::Json => out = serde_json::to_vec(value).unwrap_or_default(),
+            ResponseFormat::MessagePack => write_msgpack(&mut out, value),
+            ResponseFormat::Cbor => write_cbor(&mut out, value),
+        }
+        out
+    }
+}
+
+/// Serves a request in the format its `Accept` header negotiates, see
+/// [`ResponseFormat::negotiated`]
+pub async fn negotiate_format(request: Request, next: Next) -> Response {
+    let format = ResponseFormat::from_headers(request.headers());
+    NEGOTIATED.scope(format, next.run(request)).await
+}
+
+/// Runs `future` as if its request had negotiated `format`
+#[cfg(test)]
+pub(crate) async fn with_format<F: std::future::Future>(
+    format: ResponseFormat,
+    future: F,
+) -> F::Output {
+    NEGOTIATED.scope(format, future).await
+}
+
+fn write_msgpack(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(b) => out.push(if *b { 0xc3 } else { 0xc2 }),
+        Value::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                match n {
+                    0..=0x7f => out.push(n as u8),
+                    0x80..=0xff => out.extend([0xcc, n as u8]),
+                    0x100..=0xffff => {
+                        out.push(0xcd);
+                        out.extend((n as u16).to_be_bytes());
+                    }
+                    0x1_0000..=0xffff_ffff => {
+                        out.push(0xce);
+                        out.extend((n as u32).to_be_bytes());
+                    }
+                    _ => {
+                        out.push(0xcf);
+                        out.extend(n.to_be_bytes());
+                    }
+                }
+            } else if let Some(n) = n.as_i64() {
+                // Only negative integers get here
+                if n >= -32 {
+                    out.push(n as u8);
+                } else {
+                    out.push(0xd3);
+                    out.extend(n.to_be_bytes());
+                }
+            } else {
+                out.push(0xcb);
+                out.extend(n.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            write_msgpack_len(out, s.len(), 0xa0, 32, [0xd9, 0xda, 0xdb]);
+            out.extend(s.as_bytes());
+        }
+        Value::Array(items) => {
+            write_msgpack_len(out, items.len(), 0x90, 16, [0, 0xdc, 0xdd]);
+            items.iter().for_each(|item| write_msgpack(out, item));
+        }
+        Value::Object(entries) => {
+            write_msgpack_len(out, entries.len(), 0x80, 16, [0, 0xde, 0xdf]);
+            for (key, item) in entries {
+                write_msgpack(out, &Value::String(key.clone()));
+                write_msgpack(out, item);
+            }
+        }
+    }
+}
+
+/// Writes a length with the `fix` prefix below `fix_limit`, otherwise with the
+/// 8, 16 or 32 bit marker (a 0 marker means the width doesn't exist for the type)
+fn write_msgpack_len(out: &mut Vec<u8>, len: usize, fix: u8, fix_limit: usize, markers: [u8; 3]) {
+    if len < fix_limit {
+        out.push(fix | len as u8);
+    } else if len <= 0xff && markers[0] != 0 {
+        out.extend([markers[0], len as u8]);
+    } else if len <= 0xffff {
+        out.push(markers[1]);
+        out.extend((len as u16).to_be_bytes());
+    } else {
+        out.push(markers[2]);
+        out.extend((len as u32).to_be_bytes());
+    }
+}
+
+fn write_cbor(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(b) => out.push(if *b { 0xf5 } else { 0xf4 }),
+        Value::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                write_cbor_head(out, 0, n);
+            } else if let Some(n) = n.as_i64() {
+                write_cbor_head(out, 1, (-1 - n) as u64);
+            } else {
+                out.push(0xfb);
+                out.extend(n.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            write_cbor_head(out, 3, s.len() as u64);
+            out.extend(s.as_bytes());
+        }
+        Value::Array(items) => {
+            write_cbor_head(out, 4, items.len() as u64);
+            items.iter().for_each(|item| write_cbor(out, item));
+        }
+        Value::Object(entries) => {
+            write_cbor_head(out, 5, entries.len() as u64);
+            for (key, item) in entries {
+                write_cbor(out, &Value::String(key.clone()));
+                write_cbor(out, item);
+            }
+        }
+    }
+}
+
+fn write_cbor_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    match n {
+        0..=23 => out.push(major | n as u8),
+        24..=0xff => out.extend([major | 24, n as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(n.to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_negotiated_format() {
+        assert_eq!(ResponseFormat::negotiated(), ResponseFormat::Json);
+        let negotiated = NEGOTIATED
+            .scope(ResponseFormat::Cbor, async { ResponseFormat::negotiated() })
+            .await;
+        assert_eq!(negotiated, ResponseFormat::Cbor);
+    }
+
+    #[test]
+    fn test_format_negotiation() {
+        let format = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+            ResponseFormat::from_headers(&headers)
+        };
+        assert_eq!(
+            ResponseFormat::from_headers(&HeaderMap::new()),
+            ResponseFormat::Json
+        );
+        assert_eq!(
+            format("text/html, application/cbor;q=0.9"),
+            ResponseFormat::Cbor
+        );
+        assert_eq!(format("application/msgpack"), ResponseFormat::MessagePack);
+        assert_eq!(format("*/*"), ResponseFormat::Json);
+    }
+
+    #[test]
+    fn test_encoding() {
+        let value = serde_json::json!({ "a": [1, -1, null, 300, 1.5] });
+        assert_eq!(
+            ResponseFormat::MessagePack.encode(&value),
+            [
+                vec![0x81, 0xa1, b'a', 0x95, 0x01, 0xff, 0xc0, 0xcd, 0x01, 0x2c, 0xcb],
+                1.5f64.to_be_bytes().to_vec()
+            ]
+            .concat()
+        );
+        assert_eq!(
+            ResponseFormat::Cbor.encode(&value),
+            [
+                vec![0xa1, 0x61, b'a', 0x85, 0x01, 0x20, 0xf6, 0x19, 0x01, 0x2c, 0xfb],
+                1.5f64.to_be_bytes().to_vec()
+            ]
+            .concat()
+        );
+    }
+}
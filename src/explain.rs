@@ -0,0 +1,319 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    liquidity_risk::scored_concentration,
+    registry::ProtocolAssessment,
+    risk_model::{OverallRiskWeights, Pillar, RiskCalculationError},
+};
+
+/// Language of generated explanations
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Locale {
+    #[default]
+    En,
+    Es,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 2] = [Locale::En, Locale::Es];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Es => "es",
+        }
+    }
+
+    /// Reads a `lang` query parameter, English when not given
+    pub fn from_param(lang: Option<&str>) -> Result<Self, RiskCalculationError> {
+        match lang {
+            None | Some("en") => Ok(Locale::En),
+            Some("es") => Ok(Locale::Es),
+            Some(other) => Err(RiskCalculationError::InvalidParameter(format!(
+                "unsupported lang {:?}, expected en or es",
+                other
+            ))),
+        }
+    }
+}
+
+/// How much a pillar adds to the overall score
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PillarAttribution {
+    pub pillar: Pillar,
+    pub score: f64,
+    pub weight: f64,
+    /// `weight * score`, the contributions sum to the overall score before penalties
+    pub contribution: f64,
+    /// Share of the overall score, between 0 and 1
+    pub share: f64,
+}
+
+/// Breaks an assessment's overall score down by pillar, largest contribution first
+pub fn attribute(
+    assessment: &ProtocolAssessment,
+    weights: &OverallRiskWeights,
+) -> Vec<PillarAttribution> {
+    let sub_scores = assessment.sub_scores();
+    let pillars = [
+        (
+            Pillar::Liquidity,
+            sub_scores.liquidity_risk,
+            weights.liquidity,
+        ),
+        (
+            Pillar::Volatility,
+            sub_scores.volatility_risk,
+            weights.volatility,
+        ),
+        (Pillar::Protocol, sub_scores.protocol_risk, weights.protocol),
+        (Pillar::Oracle, sub_scores.oracle_risk, weights.oracle),
+    ];
+    let total: f64 = pillars
+        .iter()
+        .map(|(_, score, weight)| score * weight)
+        .sum();
+    let mut attribution: Vec<PillarAttribution> = pillars
+        .into_iter()
+        .map(|(pillar, score, weight)| PillarAttribution {
+            pillar,
+            score,
+            weight,
+            contribution: score * weight,
+            share: if total > 0.0 {
+                score * weight / total
+            } else {
+                0.0
+            },
+        })
+        .collect();
+    attribution.sort_by(|a, b| b.contribution.total_cmp(&a.contribution));
+    attribution
+}
+
+/// Band of a score between 0 and 100
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Level {
+    Low,
+    Moderate,
+    Elevated,
+}
+
+impl Level {
+    /// Same bands as the pillar scores: up to 33 is low, above 66 high
+    fn of_score(score: f64) -> Self {
+        if score <= 33.0 {
+            Level::Low
+        } else if score <= 66.0 {
+            Level::Moderate
+        } else {
+            Level::Elevated
+        }
+    }
+
+    /// Concentration is a share, a quarter held by one account already stands out
+    fn of_concentration(share: f64) -> Self {
+        if share < 0.1 {
+            Level::Low
+        } else if share < 0.25 {
+            Level::Moderate
+        } else {
+            Level::Elevated
+        }
+    }
+
+    fn word(&self, locale: Locale) -> &'static str {
+        match (locale, self) {
+            (Locale::En, Level::Low) => "low",
+            (Locale::En, Level::Moderate) => "moderate",
+            (Locale::En, Level::Elevated) => "elevated",
+            (Locale::Es, Level::Low) => "bajo",
+            (Locale::Es, Level::Moderate) => "moderado",
+            (Locale::Es, Level::Elevated) => "elevado",
+        }
+    }
+}
+
+/// What drives a pillar's score, e.g. "92% utilization"
+fn driver(pillar: Pillar, assessment: &ProtocolAssessment, locale: Locale) -> String {
+    let metrics = &assessment.risk_metrics;
+    match pillar {
+        Pillar::Liquidity => match locale {
+            Locale::En => format!(
+                "{:.0}% utilization",
+                metrics.liquidity_risk.utilization_rate
+            ),
+            Locale::Es => format!(
+                "una utilización del {:.0}%",
+                metrics.liquidity_risk.utilization_rate
+            ),
+        },
+        Pillar::Volatility => match locale {
+            Locale::En => format!(
+                "APY volatility (σ {:.2})",
+                metrics.volatility_risk.sigma_apy
+            ),
+            Locale::Es => format!(
+                "la volatilidad del APY (σ {:.2})",
+                metrics.volatility_risk.sigma_apy
+            ),
+        },
+        Pillar::Protocol => {
+            let penalties = metrics.protocol_risk.penalties.len();
+            match (locale, penalties) {
+                (Locale::En, 0) => "protocol risk".to_string(),
+                (Locale::En, n) => {
+                    format!("protocol risk raised by {} active incident penalties", n)
+                }
+                (Locale::Es, 0) => "el riesgo del protocolo".to_string(),
+                (Locale::Es, n) => format!(
+                    "el riesgo del protocolo, elevado por {} penalizaciones activas",
+                    n
+                ),
+            }
+        }
+        Pillar::Oracle => {
+            let worst = metrics
+                .oracle_risk
+                .feeds
+                .iter()
+                .max_by(|a, b| a.oracle_risk.total_cmp(&b.oracle_risk));
+            match (locale, worst) {
+                (Locale::En, Some(feed)) => format!("the {} price feed", feed.asset),
+                (Locale::En, None) => "oracle quality".to_string(),
+                (Locale::Es, Some(feed)) => format!("el oráculo de precio de {}", feed.asset),
+                (Locale::Es, None) => "la calidad de los oráculos".to_string(),
+            }
+        }
+    }
+}
+
+/// Short paragraph explaining why `chosen` was picked and what drives its risk
+///
+/// `attribution` is the chosen protocol's breakdown from [`attribute`].
+pub fn explain_choice(
+    chosen: &ProtocolAssessment,
+    attribution: &[PillarAttribution],
+    evaluated: usize,
+    locale: Locale,
+) -> String {
+    let overall_risk = chosen.risk_metrics.overall_risk.overall_risk;
+    let level = Level::of_score(overall_risk).word(locale);
+    let concentration = scored_concentration(
+        chosen.risk_metrics.liquidity_risk.deposit_concentration,
+        chosen
+            .risk_metrics
+            .liquidity_risk
+            .borrower_concentration
+            .as_ref(),
+    );
+    let concentration_level = Level::of_concentration(concentration).word(locale);
+
+    let mut paragraph = match locale {
+        Locale::En => format!(
+            "{:?} shows the lowest overall risk ({:.1}) among {} evaluated protocols.",
+            chosen.protocol, overall_risk, evaluated
+        ),
+        Locale::Es => format!(
+            "{:?} presenta el menor riesgo total ({:.1}) entre {} protocolos evaluados.",
+            chosen.protocol, overall_risk, evaluated
+        ),
+    };
+    if let Some(main) = attribution.first().filter(|main| main.contribution > 0.0) {
+        let driver = driver(main.pillar, chosen, locale);
+        let share = main.share * 100.0;
+        paragraph.push(' ');
+        paragraph.push_str(&match locale {
+            Locale::En => format!(
+                "Risk is {} mainly due to {} ({:.0}% of the score); concentration is {} at {:.0}%.",
+                level,
+                driver,
+                share,
+                concentration_level,
+                concentration * 100.0
+            ),
+            Locale::Es => format!(
+                "El riesgo es {} principalmente por {} ({:.0}% de la puntuación); la concentración es {}, del {:.0}%.",
+                level,
+                driver,
+                share,
+                concentration_level,
+                concentration * 100.0
+            ),
+        });
+    }
+    paragraph
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::risk_model::{
+        LiquidityRiskMetrics, Protocol, ProtocolRiskMetrics, RiskResponse, RiskScore,
+        VolatilityRiskMetrics, SCHEMA_VERSION,
+    };
+
+    fn assessment() -> ProtocolAssessment {
+        ProtocolAssessment {
+            protocol: Protocol::Kamino,
+            scope: String::new(),
+            risk_metrics: RiskResponse {
+                schema_version: SCHEMA_VERSION,
+                liquidity_risk: LiquidityRiskMetrics {
+                    total_borrows: 92.0,
+                    total_supply: 100.0,
+                    utilization_rate: 92.0,
+                    largest_deposit: 15,
+                    total_deposits: 100,
+                    deposit_concentration: 0.15,
+                    liquidity_risk: 55.26,
+                    ..Default::default()
+                },
+                volatility_risk: VolatilityRiskMetrics {
+                    sigma_apy: 0.5,
+                    sigma_utilization: 1.0,
+                    volatility_risk: 0.65,
+                    ..Default::default()
+                },
+                protocol_risk: ProtocolRiskMetrics {
+                    protocol_risk: 20.0,
+                    ..Default::default()
+                },
+                overall_risk: RiskScore {
+                    overall_risk: 24.5,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_attribute() {
+        let attribution = attribute(&assessment(), &OverallRiskWeights::default());
+        assert_eq!(attribution[0].pillar, Pillar::Liquidity);
+        assert!((attribution[0].contribution - 55.26 * 0.35).abs() < 1e-9);
+        assert!((attribution.iter().map(|a| a.share).sum::<f64>() - 1.0).abs() < 1e-9);
+        assert_eq!(attribution[3].pillar, Pillar::Oracle);
+    }
+
+    #[test]
+    fn test_explain_choice() {
+        let assessment = assessment();
+        let attribution = attribute(&assessment, &OverallRiskWeights::default());
+        let explanation = explain_choice(&assessment, &attribution, 2, Locale::En);
+        assert_eq!(
+            explanation,
+            "Kamino shows the lowest overall risk (24.5) among 2 evaluated protocols. \
+             Risk is low mainly due to 92% utilization (72% of the score); \
+             concentration is moderate at 15%."
+        );
+        let spanish = explain_choice(&assessment, &attribution, 2, Locale::Es);
+        assert!(spanish.contains("utilización del 92%"));
+
+        assert_eq!(Locale::from_param(None).unwrap(), Locale::En);
+        assert!(Locale::from_param(Some("fr")).is_err());
+    }
+}
@@ -0,0 +1,192 @@
+use std::str::FromStr;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use solana_account_decoder::UiAccountData;
+use solana_client::rpc_request::TokenAccountsFilter;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{cluster::rpc_url, risk_model::RiskCalculationError};
+
+/// Lamports budgeted per leg when `PLAN_FEE_LAMPORTS` isn't set, the base fee
+/// plus a priority fee
+const DEFAULT_FEE_LAMPORTS_PER_LEG: u64 = 50_000;
+/// Lamports the signer keeps so its account stays rent exempt
+const RENT_EXEMPT_LAMPORTS: u64 = 890_880;
+
+/// Account that signs the planned transactions
+///
+/// `VAULT_AUTHORITY` when funds are held by a vault, otherwise the user's wallet.
+pub fn plan_signer(wallet: &Pubkey) -> Result<Pubkey, RiskCalculationError> {
+    match std::env::var("VAULT_AUTHORITY") {
+        Ok(authority) => Pubkey::from_str(authority.trim())
+            .map_err(|e| RiskCalculationError::ParseError(format!("VAULT_AUTHORITY: {}", e))),
+        Err(_) => Ok(*wallet),
+    }
+}
+
+/// Reads `PLAN_FEE_LAMPORTS`, the fee budget of a single leg
+pub fn fee_lamports_per_leg() -> Result<u64, RiskCalculationError> {
+    match std::env::var("PLAN_FEE_LAMPORTS") {
+        Ok(fee) => fee
+            .trim()
+            .parse()
+            .map_err(|e| RiskCalculationError::ParseError(format!("PLAN_FEE_LAMPORTS: {}", e))),
+        Err(_) => Ok(DEFAULT_FEE_LAMPORTS_PER_LEG),
+    }
+}
+
+/// What the signer holds to pay for a plan, in native units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignerBalances {
+    pub lamports: u64,
+    pub deposit_asset: u64,
+}
+
+/// Whether a planned leg can be executed with the signer's balances
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct Feasibility {
+    pub feasible: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Feasibility {
+    fn feasible() -> Self {
+        Feasibility {
+            feasible: true,
+            reason: None,
+        }
+    }
+
+    fn infeasible(reason: String) -> Self {
+        Feasibility {
+            feasible: false,
+            reason: Some(reason),
+        }
+    }
+}
+
+/// A leg of a plan, amounts in native units of the deposit asset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanLeg {
+    Deposit(u64),
+    Withdraw(u64),
+}
+
+/// Checks every leg of a plan against the signer's balances
+///
+/// Withdrawals are executed first, so what they free up funds the deposits.
+/// Legs are funded in order and an infeasible leg spends nothing, so the legs
+/// after it are checked as if it were skipped.
+pub fn check_legs(
+    balances: &SignerBalances,
+    legs: &[PlanLeg],
+    fee_lamports_per_leg: u64,
+) -> Vec<Feasibility> {
+    let mut lamports = balances.lamports.saturating_sub(RENT_EXEMPT_LAMPORTS);
+    let mut deposit_asset = legs
+        .iter()
+        .filter_map(|leg| match leg {
+            PlanLeg::Withdraw(amount) => Some(*amount),
+            PlanLeg::Deposit(_) => None,
+        })
+        .fold(balances.deposit_asset, u64::saturating_add);
+
+    legs.iter()
+        .map(|leg| {
+            if lamports < fee_lamports_per_leg {
+                return Feasibility::infeasible(format!(
+                    "insufficient SOL for fees: {} lamports left, {} needed",
+                    lamports, fee_lamports_per_leg
+                ));
+            }
+            if let PlanLeg::Deposit(amount) = leg {
+                if deposit_asset < *amount {
+                    return Feasibility::infeasible(format!(
+                        "insufficient deposit asset: {} left, {} needed",
+                        deposit_asset, amount
+                    ));
+                }
+                deposit_asset -= amount;
+            }
+            lamports -= fee_lamports_per_leg;
+            Feasibility::feasible()
+        })
+        .collect()
+}
+
+/// SOL and deposit asset `signer` holds
+pub async fn fetch_signer_balances(
+    signer: &Pubkey,
+    deposit_mint: &Pubkey,
+) -> Result<SignerBalances, RiskCalculationError> {
+    let rpc_url = rpc_url()?;
+    let client = crate::upstream_fixtures::rpc_client(rpc_url);
+    let (lamports, token_accounts) = futures::try_join!(
+        client.get_balance(signer),
+        client.get_token_accounts_by_owner(signer, TokenAccountsFilter::Mint(*deposit_mint)),
+    )
+    .map_err(RiskCalculationError::from)?;
+    let deposit_asset = token_accounts
+        .iter()
+        .filter_map(|keyed_account| match &keyed_account.account.data {
+            UiAccountData::Json(parsed) => parsed.parsed["info"]["tokenAmount"]["amount"]
+                .as_str()
+                .and_then(|amount| amount.parse::<u64>().ok()),
+            _ => None,
+        })
+        .sum();
+    Ok(SignerBalances {
+        lamports,
+        deposit_asset,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_legs() {
+        let fee = 10_000;
+        let balances = SignerBalances {
+            lamports: RENT_EXEMPT_LAMPORTS + 3 * fee,
+            deposit_asset: 100,
+        };
+        // The withdrawal funds the second deposit
+        let checked = check_legs(
+            &balances,
+            &[
+                PlanLeg::Deposit(100),
+                PlanLeg::Deposit(50),
+                PlanLeg::Withdraw(60),
+            ],
+            fee,
+        );
+        assert!(checked.iter().all(|leg| leg.feasible));
+
+        let checked = check_legs(
+            &balances,
+            &[
+                PlanLeg::Deposit(150),
+                PlanLeg::Deposit(80),
+                PlanLeg::Withdraw(10),
+                PlanLeg::Withdraw(10),
+            ],
+            fee,
+        );
+        let feasible: Vec<bool> = checked.iter().map(|leg| leg.feasible).collect();
+        assert_eq!(feasible, vec![false, true, true, true]);
+        assert!(checked[0]
+            .reason
+            .as_ref()
+            .unwrap()
+            .contains("insufficient deposit asset"));
+
+        // Only three fees are covered
+        let checked = check_legs(&balances, &[PlanLeg::Withdraw(1); 4], fee);
+        assert!(!checked[3].feasible);
+        assert!(checked[3].reason.as_ref().unwrap().contains("SOL"));
+    }
+}
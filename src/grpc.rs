@@ -0,0 +1,462 @@
+use std::{convert::Infallible, future::Future, net::SocketAddr, str::FromStr};
+
+use futures::StreamExt;
+use solana_sdk::pubkey::Pubkey;
+use tonic::{
+    body::BoxBody,
+    codec::ProstCodec,
+    codegen::{http, BoxFuture, BoxStream, Context, Poll, Service},
+    server::{Grpc, NamedService},
+    Code, Request, Response, Status,
+};
+
+use crate::{
+    batch::BatchTarget,
+    portfolio::rebalance_wallet,
+    portfolio_events::{PortfolioStore, RedisPortfolioStore},
+    rebalancing::{
+        PoolDelta, PoolTransfer, ProfileRebalance, RebalancePlan, SkipReason, SkippedTransfer,
+        UserPortfolio,
+    },
+    risk_model::{RiskCalculationError, RiskResponse},
+    risk_stream::{self, ProtocolScores, RiskUpdate},
+    shutdown,
+    state::AppState,
+};
+
+/// Messages of `proto/risk_model.proto`, generated by `build.rs`
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/risk_model.v1.rs"));
+}
+
+/// Where the gRPC server listens, next to the HTTP server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GrpcConfig {
+    pub port: u16,
+}
+
+impl GrpcConfig {
+    /// Reads `GRPC_PORT`, `None` when it's not set and gRPC isn't served
+    pub fn from_env() -> Result<Option<Self>, RiskCalculationError> {
+        match std::env::var("GRPC_PORT") {
+            Ok(port) => port
+                .trim()
+                .parse()
+                .map(|port| Some(GrpcConfig { port }))
+                .map_err(|e| RiskCalculationError::ParseError(format!("GRPC_PORT: {}", e))),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+impl From<RiskCalculationError> for Status {
+    /// Same classes as the HTTP statuses, the error code is sent in the
+    /// `error-code` metadata
+    fn from(error: RiskCalculationError) -> Self {
+        let code = match error.status_code().as_u16() {
+            400 => Code::InvalidArgument,
+            403 => Code::PermissionDenied,
+            404 => Code::NotFound,
+            409 => Code::Aborted,
+            502 | 503 => Code::Unavailable,
+            _ => Code::Internal,
+        };
+        let mut status = Status::new(code, error.to_string());
+        status.metadata_mut().insert(
+            "error-code",
+            error.code().parse().expect("ASCII error code"),
+        );
+        status
+    }
+}
+
+impl proto::RiskResponse {
+    fn new(protocol: &str, scope: String, risk: &RiskResponse) -> Self {
+        let liquidity = &risk.liquidity_risk;
+        let volatility = &risk.volatility_risk;
+        proto::RiskResponse {
+            protocol: protocol.to_string(),
+            scope,
+            overall_risk: risk.overall_risk.overall_risk,
+            liquidity: Some(proto::LiquidityRisk {
+                liquidity_risk: liquidity.liquidity_risk,
+                utilization_rate: liquidity.utilization_rate,
+                deposit_concentration: liquidity.deposit_concentration,
+                total_supply: liquidity.total_supply,
+                total_borrows: liquidity.total_borrows,
+                tvl_change_24h_pct: liquidity.tvl_change_24h_pct,
+                tvl_change_7d_pct: liquidity.tvl_change_7d_pct,
+                available_liquidity: liquidity.available_liquidity,
+                supply_cap_utilization: liquidity.supply_cap_utilization,
+                borrow_cap_utilization: liquidity.borrow_cap_utilization,
+            }),
+            volatility: Some(proto::VolatilityRisk {
+                volatility_risk: volatility.volatility_risk,
+                sigma_apy: volatility.sigma_apy,
+                sigma_utilization: volatility.sigma_utilization,
+                current_apy: volatility.current_apy,
+            }),
+            protocol_risk: risk.protocol_risk.protocol_risk,
+            oracle_risk: risk.oracle_risk.oracle_risk,
+            computed_at: risk.computed_at.map(|computed_at| computed_at.timestamp()),
+            stale: risk.stale,
+            schema_version: risk.schema_version,
+        }
+    }
+}
+
+impl From<&ProtocolScores> for proto::ProtocolScores {
+    fn from(scores: &ProtocolScores) -> Self {
+        proto::ProtocolScores {
+            protocol: scores.protocol.as_str().to_string(),
+            overall_risk: scores.overall_risk,
+            liquidity_risk: scores.liquidity_risk,
+            volatility_risk: scores.volatility_risk,
+            protocol_risk: scores.protocol_risk,
+            oracle_risk: scores.oracle_risk,
+        }
+    }
+}
+
+impl From<&RiskUpdate> for proto::RiskUpdate {
+    fn from(update: &RiskUpdate) -> Self {
+        proto::RiskUpdate {
+            snapshot_id: update.snapshot_id.clone(),
+            computed_at: update.computed_at.timestamp(),
+            ranking: update.ranking.iter().map(Into::into).collect(),
+            unavailable: update
+                .unavailable
+                .iter()
+                .map(|protocol| protocol.as_str().to_string())
+                .collect(),
+        }
+    }
+}
+
+impl From<&UserPortfolio> for proto::Portfolio {
+    fn from(portfolio: &UserPortfolio) -> Self {
+        proto::Portfolio {
+            wallet: portfolio.user_wallet.to_string(),
+            allocations: portfolio
+                .assets
+                .values()
+                .flat_map(|profiles| profiles.values())
+                .map(|allocation| proto::ProfileAllocation {
+                    asset: allocation.asset.into(),
+                    profile: allocation.risk_profile.to_param(),
+                    total_amount: allocation.total_amount,
+                    pool_allocations: allocation
+                        .pool_allocations
+                        .iter()
+                        .map(|(protocol, amount)| (protocol.as_str().to_string(), *amount))
+                        .collect(),
+                })
+                .collect(),
+            last_rebalance: portfolio
+                .last_rebalance
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs() as i64),
+        }
+    }
+}
+
+impl From<&PoolTransfer> for proto::PoolTransfer {
+    fn from(transfer: &PoolTransfer) -> Self {
+        proto::PoolTransfer {
+            from: transfer.from.as_str().to_string(),
+            to: transfer.to.as_str().to_string(),
+            amount: transfer.amount,
+        }
+    }
+}
+
+impl From<&SkippedTransfer> for proto::SkippedTransfer {
+    fn from(skipped: &SkippedTransfer) -> Self {
+        let mut message = proto::SkippedTransfer {
+            transfer: Some((&skipped.transfer).into()),
+            ..Default::default()
+        };
+        match skipped.reason {
+            SkipReason::BelowMinimum {
+                min_transfer_amount,
+            } => {
+                message.reason = "below_minimum".to_string();
+                message.min_transfer_amount = Some(min_transfer_amount);
+            }
+            SkipReason::CostExceedsBenefit { cost, benefit } => {
+                message.reason = "cost_exceeds_benefit".to_string();
+                message.cost = Some(cost);
+                message.benefit = Some(benefit);
+            }
+        }
+        message
+    }
+}
+
+impl From<&PoolDelta> for proto::PoolDelta {
+    fn from(delta: &PoolDelta) -> Self {
+        proto::PoolDelta {
+            protocol: delta.protocol.as_str().to_string(),
+            current: delta.current,
+            target: delta.target,
+            delta: delta.delta,
+        }
+    }
+}
+
+impl From<&ProfileRebalance> for proto::ProfileRebalance {
+    fn from(rebalance: &ProfileRebalance) -> Self {
+        proto::ProfileRebalance {
+            profile: rebalance.profile.to_param(),
+            asset: rebalance.asset.into(),
+            target_weights: rebalance
+                .target_weights
+                .iter()
+                .map(|(protocol, bps)| proto::TargetWeight {
+                    protocol: protocol.as_str().to_string(),
+                    bps: bps.0,
+                })
+                .collect(),
+            deltas: rebalance.deltas.iter().map(Into::into).collect(),
+            transfers: rebalance.transfers.iter().map(Into::into).collect(),
+            skipped: rebalance.skipped.iter().map(Into::into).collect(),
+            redistribution_note: rebalance.redistribution_note.clone(),
+            applied_caps: rebalance
+                .applied_caps
+                .iter()
+                .map(|cap| proto::AppliedCap {
+                    protocol: cap.protocol.as_str().to_string(),
+                    recommended_bps: cap.recommended.0,
+                    cap_bps: cap.cap.0,
+                    max_amount: cap.max_amount,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<&RebalancePlan> for proto::RebalancePlan {
+    fn from(plan: &RebalancePlan) -> Self {
+        proto::RebalancePlan {
+            profiles: plan.profiles.iter().map(Into::into).collect(),
+        }
+    }
+}
+
+fn parse_wallet(wallet: &str) -> Result<Pubkey, RiskCalculationError> {
+    Pubkey::from_str(wallet)
+        .map_err(|e| RiskCalculationError::InvalidParameter(format!("wallet: {}", e)))
+}
+
+async fn get_risk(
+    state: AppState,
+    request: Request<proto::GetRiskRequest>,
+) -> Result<Response<proto::RiskResponse>, Status> {
+    let request = request.into_inner();
+    let registered = BatchTarget {
+        protocol: request.protocol,
+        market: request.market,
+        reserve: request.reserve,
+    }
+    .resolve(state.redis_client.clone(), &state.config.kamino_reserve)?;
+    let mut risk = registered.assess().await?;
+    state
+        .config
+        .privacy
+        .apply_to_liquidity(&mut risk.liquidity_risk);
+    Ok(Response::new(proto::RiskResponse::new(
+        registered.protocol().as_str(),
+        registered.scope(),
+        &risk,
+    )))
+}
+
+async fn stream_risk(
+    state: AppState,
+    _request: Request<proto::StreamRiskRequest>,
+) -> Result<Response<BoxStream<proto::RiskUpdate>>, Status> {
+    let updates = risk_stream::subscribe(state.shutdown)
+        .map(|update| proto::RiskUpdate::from(&update))
+        .map(Ok);
+    Ok(Response::new(Box::pin(updates)))
+}
+
+async fn get_portfolio(
+    state: AppState,
+    request: Request<proto::WalletRequest>,
+) -> Result<Response<proto::Portfolio>, Status> {
+    let wallet = parse_wallet(&request.into_inner().wallet)?;
+    let portfolio = RedisPortfolioStore::new(state.redis_client.clone())
+        .load(&wallet)
+        .await?
+        .unwrap_or_else(|| UserPortfolio::new(wallet));
+    Ok(Response::new((&portfolio).into()))
+}
+
+async fn rebalance(
+    state: AppState,
+    request: Request<proto::RebalanceRequest>,
+) -> Result<Response<proto::RebalancePlan>, Status> {
+    let request = request.into_inner();
+    let wallet = parse_wallet(&request.wallet)?;
+    let plan = rebalance_wallet(&state, &wallet, request.dry_run).await?;
+    Ok(Response::new((&plan).into()))
+}
+
+/// Adapts an RPC handler to the service tonic's [`Grpc`] calls per request
+struct Method<F>(F);
+
+impl<F, Fut, M1, M2> Service<Request<M1>> for Method<F>
+where
+    F: FnMut(Request<M1>) -> Fut,
+    Fut: Future<Output = Result<Response<M2>, Status>> + Send + 'static,
+{
+    type Response = Response<M2>;
+    type Error = Status;
+    type Future = BoxFuture<Response<M2>, Status>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Status>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<M1>) -> Self::Future {
+        Box::pin((self.0)(request))
+    }
+}
+
+/// `risk_model.v1.RiskModel`, routing each request to its RPC
+#[derive(Clone)]
+pub struct RiskModelService {
+    state: AppState,
+}
+
+impl RiskModelService {
+    pub fn new(state: AppState) -> Self {
+        RiskModelService { state }
+    }
+}
+
+impl NamedService for RiskModelService {
+    const NAME: &'static str = "risk_model.v1.RiskModel";
+}
+
+impl Service<http::Request<BoxBody>> for RiskModelService {
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Infallible>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+        let state = self.state.clone();
+        match request.uri().path() {
+            "/risk_model.v1.RiskModel/GetRisk" => Box::pin(async move {
+                let method = Method(move |request| get_risk(state.clone(), request));
+                Ok(Grpc::new(ProstCodec::default())
+                    .unary(method, request)
+                    .await)
+            }),
+            "/risk_model.v1.RiskModel/StreamRisk" => Box::pin(async move {
+                let method = Method(move |request| stream_risk(state.clone(), request));
+                Ok(Grpc::new(ProstCodec::default())
+                    .server_streaming(method, request)
+                    .await)
+            }),
+            "/risk_model.v1.RiskModel/GetPortfolio" => Box::pin(async move {
+                let method = Method(move |request| get_portfolio(state.clone(), request));
+                Ok(Grpc::new(ProstCodec::default())
+                    .unary(method, request)
+                    .await)
+            }),
+            "/risk_model.v1.RiskModel/Rebalance" => Box::pin(async move {
+                let method = Method(move |request| rebalance(state.clone(), request));
+                Ok(Grpc::new(ProstCodec::default())
+                    .unary(method, request)
+                    .await)
+            }),
+            path => {
+                let status = Status::unimplemented(format!("unknown method {}", path));
+                Box::pin(async move { Ok(status.into_http()) })
+            }
+        }
+    }
+}
+
+/// Serves the gRPC API on `addr` in the background until `state.shutdown` is
+/// cancelled, shutdown then waits for the in-flight calls
+pub fn spawn_grpc_server(state: AppState, addr: SocketAddr) -> tokio::task::JoinHandle<()> {
+    let shutdown = state.shutdown.clone();
+    shutdown::spawn_background(async move {
+        tracing::info!("🚀 gRPC server running on {}", addr);
+        let served = tonic::transport::Server::builder()
+            .add_service(RiskModelService::new(state))
+            .serve_with_shutdown(addr, shutdown.cancelled_owned())
+            .await;
+        if let Err(e) = served {
+            tracing::error!("gRPC server failed: {}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+    use crate::{
+        assets::Asset,
+        bps::Bps,
+        risk_model::{Protocol, RiskProfile},
+    };
+
+    #[test]
+    fn test_rebalance_plan_message() {
+        let transfer = PoolTransfer {
+            from: Protocol::Kamino,
+            to: Protocol::Marginfy,
+            amount: 1_000,
+        };
+        let plan = RebalancePlan {
+            profiles: vec![ProfileRebalance {
+                profile: RiskProfile::custom(35.5, 6_000).unwrap(),
+                asset: Asset::Usdc,
+                target_weights: vec![(Protocol::Marginfy, Bps(10_000))],
+                deltas: vec![],
+                transfers: vec![transfer.clone()],
+                skipped: vec![SkippedTransfer {
+                    transfer,
+                    reason: SkipReason::BelowMinimum {
+                        min_transfer_amount: 5_000,
+                    },
+                }],
+                redistribution_note: None,
+                applied_caps: Vec::new(),
+            }],
+        };
+        let message = proto::RebalancePlan::from(&plan);
+        let decoded = proto::RebalancePlan::decode(message.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, message);
+
+        let profile = &decoded.profiles[0];
+        assert_eq!(profile.profile, "custom:35.5:6000");
+        assert_eq!(profile.asset, "usdc");
+        assert_eq!(profile.target_weights[0].protocol, "marginfi");
+        assert_eq!(profile.transfers[0].from, "kamino");
+        assert_eq!(profile.skipped[0].reason, "below_minimum");
+        assert_eq!(profile.skipped[0].min_transfer_amount, Some(5_000));
+        assert_eq!(profile.skipped[0].cost, None);
+
+        let status = Status::from(RiskCalculationError::InvalidParameter("wallet".to_string()));
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(
+            status.metadata().get("error-code").unwrap(),
+            "invalid_parameter"
+        );
+        assert_eq!(
+            Status::from(RiskCalculationError::NotReady(String::new())).code(),
+            Code::Unavailable
+        );
+    }
+}
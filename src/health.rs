@@ -0,0 +1,127 @@
+use std::{future::Future, time::Duration};
+
+use axum::{extract::State, http::StatusCode, response::IntoResponse, response::Response, Json};
+use serde::Serialize;
+
+use crate::{
+    cluster::Cluster, redis_connection::shared_connection, risk_model::RiskCalculationError,
+    state::AppState,
+};
+
+/// A backend that doesn't answer within this is considered unreachable
+const CHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Outcome of checking one backend
+#[derive(Debug, Serialize)]
+pub struct DependencyStatus {
+    pub name: &'static str,
+    pub ok: bool,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Body of `/ready`
+#[derive(Debug, Serialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    /// Cluster the deployment runs against
+    pub cluster: Cluster,
+    pub dependencies: Vec<DependencyStatus>,
+}
+
+impl ReadinessReport {
+    pub fn new(dependencies: Vec<DependencyStatus>) -> Self {
+        ReadinessReport {
+            ready: dependencies.iter().all(|dependency| dependency.ok),
+            cluster: Cluster::global(),
+            dependencies,
+        }
+    }
+
+    pub fn status_code(&self) -> StatusCode {
+        if self.ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
+async fn check<F>(name: &'static str, future: F) -> DependencyStatus
+where
+    F: Future<Output = Result<(), RiskCalculationError>>,
+{
+    let started = std::time::Instant::now();
+    let error = match tokio::time::timeout(CHECK_TIMEOUT, future).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("no answer within {:?}", CHECK_TIMEOUT)),
+    };
+    DependencyStatus {
+        name,
+        ok: error.is_none(),
+        latency_ms: started.elapsed().as_micros() as f64 / 1000.0,
+        error,
+    }
+}
+
+async fn ping_redis(redis_client: &redis::Client) -> Result<(), RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let _: String = redis::cmd("PING")
+        .query_async(&mut connection)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    Ok(())
+}
+
+/// `getHealth` is the cheapest call the RPC node answers
+async fn ping_helius() -> Result<(), RiskCalculationError> {
+    let client = crate::upstream_fixtures::rpc_client(crate::cluster::rpc_url()?);
+    client
+        .get_health()
+        .await
+        .map_err(RiskCalculationError::from)
+}
+
+/// Liveness: the process is up and serving requests
+pub async fn health() -> Response {
+    Json(serde_json::json!({
+        "status": "ok",
+        "cluster": Cluster::global(),
+    }))
+    .into_response()
+}
+
+/// Readiness: every backend the risk computation depends on is reachable
+pub async fn ready(State(state): State<AppState>) -> Response {
+    let (redis, helius) = tokio::join!(
+        check("redis", ping_redis(&state.redis_client)),
+        check("helius_rpc", ping_helius())
+    );
+    let report = ReadinessReport::new(vec![redis, helius]);
+    (report.status_code(), Json(report)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_readiness_report() {
+        let ok = check("ok", async { Ok(()) }).await;
+        let failing = check("failing", async {
+            Err(RiskCalculationError::CustomError("down".to_string()))
+        })
+        .await;
+        assert!(ok.ok);
+        assert_eq!(failing.error.as_deref(), Some("Custom error: down"));
+
+        assert_eq!(ReadinessReport::new(vec![ok]).status_code(), StatusCode::OK);
+        let report = ReadinessReport::new(vec![failing]);
+        assert!(!report.ready);
+        assert_eq!(report.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,517 @@
+use arrow_array::{
+    ArrayRef, BooleanArray, Float64Array, RecordBatch, StringArray, TimestampMillisecondArray,
+};
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, DurationRound, Utc};
+use futures::stream;
+use parquet::arrow::ArrowWriter;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::{
+    cache_schema::versioned_key,
+    kamino::reserve::KaminoReserveConfig,
+    redis_connection::shared_connection,
+    registry::{ProtocolAssessment, RegisteredProtocol},
+    risk_model::{json_response, timings_requested, Protocol, RiskCalculationError, RiskResponse},
+    state::AppState,
+    timings::{timed, with_timings, Timing},
+};
+
+/// How long risk history is kept
+const HISTORY_RETENTION_DAYS: i64 = 90;
+/// Range returned when `from` isn't given
+const DEFAULT_HISTORY_DAYS: i64 = 7;
+
+/// One computed risk response of a protocol
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RiskHistoryPoint {
+    pub computed_at: DateTime<Utc>,
+    pub risk_metrics: RiskResponse,
+}
+
+/// Sorted set of a scope's history, scored by the unix timestamp of each point
+pub fn history_key(scope: &str) -> String {
+    versioned_key(&format!("risk_history:{}", scope))
+}
+
+/// Appends the assessments of a comparison to their protocols' history
+///
+/// Only one point is kept per protocol and hour: a point recomputed within the
+/// same hour replaces the earlier one. Points older than the retention are dropped.
+pub async fn record_history(
+    redis_client: &redis::Client,
+    computed_at: DateTime<Utc>,
+    assessments: &[ProtocolAssessment],
+) -> Result<(), RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let hour_start = computed_at
+        .duration_trunc(Duration::hours(1))
+        .map_err(|e| RiskCalculationError::CustomError(e.to_string()))?;
+    let retention_start = computed_at - Duration::days(HISTORY_RETENTION_DAYS);
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for assessment in assessments {
+        let key = history_key(&assessment.scope);
+        let point = serde_json::to_string(&RiskHistoryPoint {
+            computed_at,
+            risk_metrics: assessment.risk_metrics.clone(),
+        })
+        .map_err(RiskCalculationError::SerdeError)?;
+        pipe.zrembyscore(&key, "-inf", format!("({}", retention_start.timestamp()))
+            .ignore()
+            .zrembyscore(
+                &key,
+                hour_start.timestamp(),
+                format!("({}", (hour_start + Duration::hours(1)).timestamp()),
+            )
+            .ignore()
+            .zadd(&key, point, computed_at.timestamp())
+            .ignore();
+    }
+    let _: () = pipe
+        .query_async(&mut connection)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    Ok(())
+}
+
+/// Reads the history of a scope between `from` and `to` (inclusive), oldest first
+pub async fn load_history(
+    redis_client: &redis::Client,
+    scope: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<RiskHistoryPoint>, RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let points: Vec<String> = timed(
+        Timing::CacheRead,
+        connection.zrangebyscore(history_key(scope), from.timestamp(), to.timestamp()),
+    )
+    .await
+    .map_err(RiskCalculationError::from)?;
+    points
+        .iter()
+        .map(|point| serde_json::from_str(point).map_err(RiskCalculationError::SerdeError))
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RiskHistoryQuery {
+    /// `kamino` or `marginfi`
+    pub protocol: String,
+    /// Start of the range, defaults to a week before `to`
+    pub from: Option<DateTime<Utc>>,
+    /// End of the range, defaults to now
+    pub to: Option<DateTime<Utc>>,
+    /// Kamino lending market, requires `reserve`
+    pub market: Option<String>,
+    /// Kamino reserve, requires `market`
+    pub reserve: Option<String>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+impl RiskHistoryQuery {
+    fn protocol(&self) -> Result<Protocol, RiskCalculationError> {
+        match Protocol::from_param(&self.protocol)? {
+            protocol @ (Protocol::Kamino | Protocol::Marginfy) => Ok(protocol),
+            other => Err(RiskCalculationError::NotFound(format!(
+                "no risk history for {:?}",
+                other
+            ))),
+        }
+    }
+}
+
+/// History of the query's scope, with the privacy mode applied
+struct QueriedHistory {
+    protocol: Protocol,
+    scope: String,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    points: Vec<RiskHistoryPoint>,
+}
+
+async fn query_history(
+    state: &AppState,
+    query: &RiskHistoryQuery,
+) -> Result<QueriedHistory, RiskCalculationError> {
+    let protocol = query.protocol()?;
+    let scope = RegisteredProtocol::for_protocol(
+        &protocol,
+        state.redis_client.clone(),
+        KaminoReserveConfig::from_params(
+            query.market.as_deref(),
+            query.reserve.as_deref(),
+            &state.config.kamino_reserve,
+        )?,
+    )?
+    .scope();
+    let to = query.to.unwrap_or_else(Utc::now);
+    let from = query
+        .from
+        .unwrap_or(to - Duration::days(DEFAULT_HISTORY_DAYS));
+    let mut points = load_history(&state.redis_client, &scope, from, to).await?;
+    for point in &mut points {
+        state
+            .config
+            .privacy
+            .apply_to_liquidity(&mut point.risk_metrics.liquidity_risk);
+    }
+    Ok(QueriedHistory {
+        protocol,
+        scope,
+        from,
+        to,
+        points,
+    })
+}
+
+pub async fn risk_history(
+    State(state): State<AppState>,
+    Query(query): Query<RiskHistoryQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let history = query_history(&state, &query).await?;
+
+        Ok::<_, RiskCalculationError>(serde_json::json!({
+            "protocol": history.protocol,
+            "scope": history.scope,
+            "from": history.from,
+            "to": history.to,
+            "points": history.points,
+        }))
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+/// File formats of `GET /risk_history/export`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RiskHistoryExportQuery {
+    pub format: ExportFormat,
+    #[serde(flatten)]
+    pub history: RiskHistoryQuery,
+}
+
+/// Columns of the exports, one row per point
+const EXPORT_COLUMNS: [&str; 21] = [
+    "computed_at",
+    "protocol",
+    "scope",
+    "overall_risk",
+    "liquidity_risk",
+    "volatility_risk",
+    "protocol_risk",
+    "oracle_risk",
+    "utilization_rate",
+    "total_supply",
+    "total_borrows",
+    "deposit_concentration",
+    "tvl_change_24h_pct",
+    "tvl_change_7d_pct",
+    "available_liquidity",
+    "supply_cap_utilization",
+    "borrow_cap_utilization",
+    "sigma_apy",
+    "sigma_utilization",
+    "current_apy",
+    "stale",
+];
+
+/// A CSV row of `point`, in the order of [`EXPORT_COLUMNS`]
+///
+/// Protocols and scopes are identifiers without commas or quotes, values are
+/// written unquoted. Missing values are empty.
+fn csv_row(protocol: &Protocol, scope: &str, point: &RiskHistoryPoint) -> String {
+    let optional = |value: Option<f64>| value.map(|value| value.to_string()).unwrap_or_default();
+    let risk = &point.risk_metrics;
+    let liquidity = &risk.liquidity_risk;
+    let volatility = &risk.volatility_risk;
+    let fields = [
+        point.computed_at.to_rfc3339(),
+        protocol.as_str().to_string(),
+        scope.to_string(),
+        risk.overall_risk.overall_risk.to_string(),
+        liquidity.liquidity_risk.to_string(),
+        volatility.volatility_risk.to_string(),
+        risk.protocol_risk.protocol_risk.to_string(),
+        risk.oracle_risk.oracle_risk.to_string(),
+        liquidity.utilization_rate.to_string(),
+        liquidity.total_supply.to_string(),
+        liquidity.total_borrows.to_string(),
+        liquidity.deposit_concentration.to_string(),
+        optional(liquidity.tvl_change_24h_pct),
+        optional(liquidity.tvl_change_7d_pct),
+        optional(liquidity.available_liquidity),
+        optional(liquidity.supply_cap_utilization),
+        optional(liquidity.borrow_cap_utilization),
+        volatility.sigma_apy.to_string(),
+        volatility.sigma_utilization.to_string(),
+        optional(volatility.current_apy),
+        risk.stale.to_string(),
+    ];
+    format!("{}\n", fields.join(","))
+}
+
+/// A Parquet file of `points`, with the columns of [`EXPORT_COLUMNS`]
+///
+/// Times are UTC milliseconds, missing values are nulls.
+fn parquet_file(
+    protocol: &Protocol,
+    scope: &str,
+    points: &[RiskHistoryPoint],
+) -> Result<Vec<u8>, RiskCalculationError> {
+    let values = |value: fn(&RiskResponse) -> f64| -> ArrayRef {
+        Arc::new(Float64Array::from_iter_values(
+            points.iter().map(|point| value(&point.risk_metrics)),
+        ))
+    };
+    let optional = |value: fn(&RiskResponse) -> Option<f64>| -> ArrayRef {
+        Arc::new(Float64Array::from_iter(
+            points.iter().map(|point| value(&point.risk_metrics)),
+        ))
+    };
+    let columns: [ArrayRef; 21] = [
+        Arc::new(
+            TimestampMillisecondArray::from_iter_values(
+                points
+                    .iter()
+                    .map(|point| point.computed_at.timestamp_millis()),
+            )
+            .with_timezone("UTC"),
+        ),
+        Arc::new(StringArray::from(vec![protocol.as_str(); points.len()])),
+        Arc::new(StringArray::from(vec![scope; points.len()])),
+        values(|risk| risk.overall_risk.overall_risk),
+        values(|risk| risk.liquidity_risk.liquidity_risk),
+        values(|risk| risk.volatility_risk.volatility_risk),
+        values(|risk| risk.protocol_risk.protocol_risk),
+        values(|risk| risk.oracle_risk.oracle_risk),
+        values(|risk| risk.liquidity_risk.utilization_rate),
+        values(|risk| risk.liquidity_risk.total_supply),
+        values(|risk| risk.liquidity_risk.total_borrows),
+        values(|risk| risk.liquidity_risk.deposit_concentration),
+        optional(|risk| risk.liquidity_risk.tvl_change_24h_pct),
+        optional(|risk| risk.liquidity_risk.tvl_change_7d_pct),
+        optional(|risk| risk.liquidity_risk.available_liquidity),
+        optional(|risk| risk.liquidity_risk.supply_cap_utilization),
+        optional(|risk| risk.liquidity_risk.borrow_cap_utilization),
+        values(|risk| risk.volatility_risk.sigma_apy),
+        values(|risk| risk.volatility_risk.sigma_utilization),
+        optional(|risk| risk.volatility_risk.current_apy),
+        Arc::new(BooleanArray::from_iter(
+            points.iter().map(|point| Some(point.risk_metrics.stale)),
+        )),
+    ];
+    let encode = || {
+        let batch = RecordBatch::try_from_iter(EXPORT_COLUMNS.into_iter().zip(columns))?;
+        let mut writer = ArrowWriter::try_new(Vec::new(), batch.schema(), None)?;
+        writer.write(&batch)?;
+        writer.into_inner()
+    };
+    encode().map_err(|e: parquet::errors::ParquetError| {
+        RiskCalculationError::CustomError(format!("Failed to encode parquet: {}", e))
+    })
+}
+
+/// Risk history as a file analysts can load directly, e.g. with
+/// `pandas.read_csv` or `pandas.read_parquet`
+///
+/// The CSV is streamed a row at a time, oldest point first. The Parquet file
+/// is encoded whole, as one row group.
+pub async fn export_history(
+    State(state): State<AppState>,
+    Query(query): Query<RiskHistoryExportQuery>,
+) -> Response {
+    let history = match query_history(&state, &query.history).await {
+        Ok(history) => history,
+        Err(e) => return json_response::<()>(Err(e), None),
+    };
+    let filename = format!(
+        "risk_history_{}_{}_{}.{}",
+        history.scope.replace(':', "_"),
+        history.from.format("%Y%m%dT%H%M%SZ"),
+        history.to.format("%Y%m%dT%H%M%SZ"),
+        query.format.extension()
+    );
+    let QueriedHistory {
+        protocol,
+        scope,
+        points,
+        ..
+    } = history;
+    let body = match query.format {
+        ExportFormat::Csv => {
+            let header = format!("{}\n", EXPORT_COLUMNS.join(","));
+            let rows = points
+                .into_iter()
+                .map(move |point| csv_row(&protocol, &scope, &point));
+            Body::from_stream(stream::iter(
+                std::iter::once(header)
+                    .chain(rows)
+                    .map(Ok::<_, std::convert::Infallible>),
+            ))
+        }
+        ExportFormat::Parquet => match parquet_file(&protocol, &scope, &points) {
+            Ok(file) => Body::from(file),
+            Err(e) => return json_response::<()>(Err(e), None),
+        },
+    };
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                query.format.content_type().to_string(),
+            ),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point() -> RiskHistoryPoint {
+        serde_json::from_value(serde_json::json!({
+            "computed_at": "2024-05-01T00:00:00Z",
+            "risk_metrics": {
+                "liquidity_risk": {
+                    "total_borrows": 80.0,
+                    "total_supply": 100.0,
+                    "utilization_rate": 0.8,
+                    "largest_deposit": 10,
+                    "total_deposits": 100,
+                    "deposit_concentration": 0.1,
+                    "liquidity_risk": 40.0,
+                },
+                "volatility_risk": {
+                    "sigma_apy": 0.5,
+                    "sigma_utilization": 0.1,
+                    "volatility_risk": 20.0,
+                },
+                "protocol_risk": { "protocol_risk": 10.0 },
+                "overall_risk": { "overall_risk": 25.5 },
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_risk_history_query() {
+        let parse = |uri: &str| {
+            Query::<RiskHistoryQuery>::try_from_uri(&uri.parse().unwrap())
+                .unwrap()
+                .0
+        };
+        let query = parse(
+            "/risk_history?protocol=Kamino&from=2024-05-01T00:00:00Z&to=2024-05-08T00:00:00Z",
+        );
+        assert_eq!(query.protocol().unwrap(), Protocol::Kamino);
+        assert_eq!(query.from.unwrap().timestamp(), 1714521600);
+
+        assert!(parse("/risk_history?protocol=solend").protocol().is_err());
+        assert_eq!(history_key("marginfi"), "v2:risk_history:marginfi");
+
+        let export = Query::<RiskHistoryExportQuery>::try_from_uri(
+            &"/risk_history/export?format=csv&protocol=kamino&to=2024-05-08T00:00:00Z"
+                .parse()
+                .unwrap(),
+        )
+        .unwrap()
+        .0;
+        assert_eq!(export.format, ExportFormat::Csv);
+        assert_eq!(export.history.protocol().unwrap(), Protocol::Kamino);
+        assert_eq!(export.history.to.unwrap().timestamp(), 1715126400);
+        assert!(Query::<RiskHistoryExportQuery>::try_from_uri(
+            &"/risk_history/export?format=xlsx&protocol=kamino"
+                .parse()
+                .unwrap()
+        )
+        .is_err());
+
+        let point = point();
+        let row = csv_row(&Protocol::Kamino, "kamino:market:reserve", &point);
+        assert_eq!(
+            row,
+            "2024-05-01T00:00:00+00:00,kamino,kamino:market:reserve,25.5,40,20,10,0,0.8,100,80,0.1,,,,,,0.5,0.1,,false\n"
+        );
+        assert_eq!(row.split(',').count(), EXPORT_COLUMNS.len());
+    }
+
+    #[test]
+    fn test_parquet_file() {
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let file = parquet_file(&Protocol::Kamino, "kamino:market:reserve", &[point()]).unwrap();
+        let path =
+            std::env::temp_dir().join(format!("risk_history_{}.parquet", std::process::id()));
+        std::fs::write(&path, file).unwrap();
+        let reader = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<RecordBatch> = reader.collect::<Result<_, _>>().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let batch = &batches[0];
+        assert_eq!(batch.num_rows(), 1);
+        assert_eq!(batch.num_columns(), EXPORT_COLUMNS.len());
+        let overall = batch
+            .column_by_name("overall_risk")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap();
+        assert_eq!(overall.value(0), 25.5);
+        assert!(batch.column_by_name("current_apy").unwrap().is_null(0));
+        let computed_at = batch
+            .column_by_name("computed_at")
+            .unwrap()
+            .as_any()
+            .downcast_ref::<TimestampMillisecondArray>()
+            .unwrap();
+        assert_eq!(computed_at.value(0), 1_714_521_600_000);
+    }
+}
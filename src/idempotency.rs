@@ -0,0 +1,254 @@
+use std::future::Future;
+
+use axum::{
+    http::{HeaderMap, HeaderValue},
+    response::Response,
+};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache_lock::LOCK_TTL,
+    cache_schema::record_key,
+    redis_connection::shared_connection,
+    risk_model::{json_response, RiskCalculationError},
+    timings::TimingsReport,
+};
+
+/// Header clients set to make retries of a request safe
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Set on responses replayed for a key seen before
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+/// How long a key replays its response
+const IDEMPOTENCY_TTL_SECONDS: u64 = 24 * 60 * 60;
+/// How long a key stays claimed by a request that never finishes, e.g. when
+/// the instance dies, before a retry can run it again
+///
+/// Claimed before the wallet lock, which a request waits for up to
+/// [`LOCK_TTL`] and then holds up to [`LOCK_TTL`], so the claim must outlive
+/// both or a retry could run the operation a second time.
+const IN_FLIGHT_TTL_SECONDS: u64 = 3 * LOCK_TTL.as_secs();
+const MAX_KEY_LENGTH: usize = 255;
+
+/// What's stored under an idempotency key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum IdempotencyRecord {
+    InFlight {
+        fingerprint: String,
+    },
+    Completed {
+        fingerprint: String,
+        response: serde_json::Value,
+    },
+}
+
+/// Response of an operation run at most once per key
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdempotentResponse {
+    pub response: serde_json::Value,
+    /// Whether the response is the one of an earlier request with the same key
+    pub replayed: bool,
+}
+
+/// Redis key of the client's `key` for `operation` on `wallet`
+///
+/// The records stand for operations that already ran and can't be recomputed,
+/// so the key isn't versioned: a schema bump must not let retries run them again.
+pub fn idempotency_key(operation: &str, wallet: &str, key: &str) -> String {
+    record_key(&format!("idempotency:{}:{}:{}", operation, wallet, key))
+}
+
+/// The `Idempotency-Key` header, `None` when the client didn't set one
+pub fn key_from_headers(headers: &HeaderMap) -> Result<Option<String>, RiskCalculationError> {
+    let Some(key) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+    match key.to_str().map(str::trim) {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => Ok(Some(key.to_string())),
+        _ => Err(RiskCalculationError::InvalidParameter(format!(
+            "Idempotency-Key must be 1 to {} visible ASCII characters",
+            MAX_KEY_LENGTH
+        ))),
+    }
+}
+
+/// Response replayed to a request finding `record` under its key
+fn resolve(
+    record: &IdempotencyRecord,
+    fingerprint: &str,
+) -> Result<serde_json::Value, RiskCalculationError> {
+    let (IdempotencyRecord::InFlight {
+        fingerprint: recorded,
+    }
+    | IdempotencyRecord::Completed {
+        fingerprint: recorded,
+        ..
+    }) = record;
+    if recorded != fingerprint {
+        return Err(RiskCalculationError::InvalidParameter(
+            "Idempotency-Key was already used with a different request".to_string(),
+        ));
+    }
+    match record {
+        IdempotencyRecord::InFlight { .. } => Err(RiskCalculationError::Conflict(
+            "a request with this Idempotency-Key is still being processed".to_string(),
+        )),
+        IdempotencyRecord::Completed { response, .. } => Ok(response.clone()),
+    }
+}
+
+/// Runs `operation` at most once per `key`, replaying its response to retries
+///
+/// The key is claimed before running, so concurrent retries get a conflict
+/// instead of running twice. `fingerprint` identifies the request, reusing a
+/// key for another request is rejected. Failures release the key, so a failed
+/// request can be retried. Without a key, `operation` just runs.
+pub async fn idempotent<T, F>(
+    redis_client: &redis::Client,
+    key: Option<String>,
+    fingerprint: String,
+    operation: F,
+) -> Result<IdempotentResponse, RiskCalculationError>
+where
+    T: Serialize,
+    F: Future<Output = Result<T, RiskCalculationError>>,
+{
+    let to_value =
+        |response| serde_json::to_value(response).map_err(RiskCalculationError::SerdeError);
+    let Some(key) = key else {
+        return Ok(IdempotentResponse {
+            response: to_value(operation.await?)?,
+            replayed: false,
+        });
+    };
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let in_flight = serde_json::to_string(&IdempotencyRecord::InFlight {
+        fingerprint: fingerprint.clone(),
+    })
+    .map_err(RiskCalculationError::SerdeError)?;
+
+    loop {
+        let claimed: Option<String> = redis::cmd("SET")
+            .arg(&key)
+            .arg(&in_flight)
+            .arg("NX")
+            .arg("EX")
+            .arg(IN_FLIGHT_TTL_SECONDS)
+            .query_async(&mut connection)
+            .await
+            .map_err(RiskCalculationError::from)?;
+        if claimed.is_some() {
+            break;
+        }
+        let record: Option<String> = connection
+            .get(&key)
+            .await
+            .map_err(RiskCalculationError::from)?;
+        // The key expired since the claim failed, claim it again
+        let Some(record) = record else {
+            continue;
+        };
+        let record = serde_json::from_str(&record).map_err(RiskCalculationError::SerdeError)?;
+        return Ok(IdempotentResponse {
+            response: resolve(&record, &fingerprint)?,
+            replayed: true,
+        });
+    }
+
+    let response = match operation.await.and_then(to_value) {
+        Ok(response) => response,
+        Err(e) => {
+            if let Err(release_error) = connection.del::<_, ()>(&key).await {
+                tracing::error!(
+                    "Failed to release idempotency key {}: {}",
+                    key,
+                    release_error
+                );
+            }
+            return Err(e);
+        }
+    };
+    let completed = serde_json::to_string(&IdempotencyRecord::Completed {
+        fingerprint,
+        response: response.clone(),
+    })
+    .map_err(RiskCalculationError::SerdeError)?;
+    // The operation already happened, a retry after a failure to record it would repeat it
+    if let Err(e) = connection
+        .set_ex::<_, _, ()>(&key, completed, IDEMPOTENCY_TTL_SECONDS)
+        .await
+    {
+        tracing::error!(
+            "Failed to record the response of idempotency key {}: {}",
+            key,
+            e
+        );
+    }
+    Ok(IdempotentResponse {
+        response,
+        replayed: false,
+    })
+}
+
+/// Like [`json_response`], flagging replayed responses with the `Idempotent-Replayed` header
+pub fn idempotent_response(
+    result: Result<IdempotentResponse, RiskCalculationError>,
+    timings: Option<TimingsReport>,
+) -> Response {
+    let replayed = matches!(result, Ok(IdempotentResponse { replayed: true, .. }));
+    let mut response = json_response(result.map(|result| result.response), timings);
+    if replayed {
+        response
+            .headers_mut()
+            .insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_idempotency_record() {
+        let completed = IdempotencyRecord::Completed {
+            fingerprint: "deposit 100".to_string(),
+            response: serde_json::json!({ "asset": "usdc" }),
+        };
+        assert_eq!(
+            resolve(&completed, "deposit 100").unwrap(),
+            serde_json::json!({ "asset": "usdc" })
+        );
+        assert_eq!(
+            resolve(&completed, "deposit 200").unwrap_err().code(),
+            "invalid_parameter"
+        );
+        let in_flight = IdempotencyRecord::InFlight {
+            fingerprint: "deposit 100".to_string(),
+        };
+        assert_eq!(
+            resolve(&in_flight, "deposit 100").unwrap_err().code(),
+            "conflict"
+        );
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(key_from_headers(&headers).unwrap(), None);
+        headers.insert(
+            IDEMPOTENCY_KEY_HEADER,
+            HeaderValue::from_static(" retry-1 "),
+        );
+        assert_eq!(
+            key_from_headers(&headers).unwrap().as_deref(),
+            Some("retry-1")
+        );
+        headers.insert(IDEMPOTENCY_KEY_HEADER, HeaderValue::from_static(""));
+        assert!(key_from_headers(&headers).is_err());
+        assert_eq!(
+            idempotency_key("deposit", "wallet", "retry-1"),
+            "idempotency:deposit:wallet:retry-1"
+        );
+    }
+}
@@ -0,0 +1,298 @@
+use axum::{
+    extract::{Query, State},
+    response::Response,
+    Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use redis::AsyncCommands;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::CacheBackend,
+    cache_admin::invalidate_risk_responses,
+    cache_schema::record_key,
+    multisig::{authorize, AdminAction, MultisigApproval},
+    precomputed::drop_precomputed,
+    redis_connection::shared_connection,
+    risk_model::{json_response, timings_requested, DebugQuery, Protocol, RiskCalculationError},
+    scheduler::refresh_all,
+    state::AppState,
+    timings::{timed, with_timings, Timing},
+};
+
+/// Days a penalty takes to decay back to baseline when not configured
+const DEFAULT_DECAY_DAYS: f64 = 14.0;
+/// Share of the bump left when an exponential decay ends, it's cleared after
+const EXPONENTIAL_DECAY_FLOOR: f64 = 0.01;
+
+/// Penalties of a protocol, a redis list appended to in order
+///
+/// Penalties can't be recomputed, so like the portfolio event log the key isn't
+/// versioned and survives cache migrations.
+pub fn penalties_key(protocol: &Protocol) -> String {
+    record_key(&format!("protocol_penalties:{:?}", protocol))
+}
+
+/// What raised a protocol's risk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PenaltySource {
+    IncidentFeed,
+    UpgradeMonitor,
+    Manual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DecayCurve {
+    /// Halves at a constant rate, down to 1% of the bump when the decay ends
+    Exponential,
+    Linear,
+}
+
+/// How a penalty fades back to the protocol's baseline risk
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DecaySchedule {
+    pub curve: DecayCurve,
+    pub duration_days: f64,
+}
+
+impl Default for DecaySchedule {
+    fn default() -> Self {
+        DecaySchedule {
+            curve: DecayCurve::Exponential,
+            duration_days: DEFAULT_DECAY_DAYS,
+        }
+    }
+}
+
+impl DecaySchedule {
+    /// Reads `PENALTY_DECAY` as `curve,days`, e.g. `exponential,14`
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let Ok(schedule) = std::env::var("PENALTY_DECAY") else {
+            return Ok(Self::default());
+        };
+        let parse_error = || {
+            RiskCalculationError::ParseError(format!(
+                "PENALTY_DECAY must be exponential|linear,<days>: {:?}",
+                schedule
+            ))
+        };
+        let (curve, days) = schedule.split_once(',').ok_or_else(parse_error)?;
+        let curve = match curve.trim() {
+            "exponential" => DecayCurve::Exponential,
+            "linear" => DecayCurve::Linear,
+            _ => return Err(parse_error()),
+        };
+        let duration_days: f64 = days.trim().parse().map_err(|_| parse_error())?;
+        if duration_days <= 0.0 {
+            return Err(parse_error());
+        }
+        Ok(DecaySchedule {
+            curve,
+            duration_days,
+        })
+    }
+
+    /// Share of the bump left `elapsed` after it was applied
+    pub fn remaining(&self, elapsed: Duration) -> f64 {
+        let progress = elapsed.num_seconds() as f64 / (self.duration_days * 86_400.0);
+        if progress >= 1.0 {
+            return 0.0;
+        }
+        let progress = progress.max(0.0);
+        match self.curve {
+            DecayCurve::Exponential => EXPONENTIAL_DECAY_FLOOR.powf(progress),
+            DecayCurve::Linear => 1.0 - progress,
+        }
+    }
+}
+
+/// A temporary increase of a protocol's risk
+///
+/// The schedule is stored with the penalty, so changing the configured decay
+/// doesn't rewrite penalties that were already applied.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RiskPenalty {
+    pub source: PenaltySource,
+    pub reason: String,
+    /// Added to the protocol risk when applied
+    pub bump: f64,
+    pub applied_at: DateTime<Utc>,
+    pub schedule: DecaySchedule,
+}
+
+impl RiskPenalty {
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.applied_at + Duration::seconds((self.schedule.duration_days * 86_400.0) as i64)
+    }
+
+    /// The part of the bump still in effect at `now`
+    pub fn current_bump(&self, now: DateTime<Utc>) -> f64 {
+        self.bump * self.schedule.remaining(now - self.applied_at)
+    }
+}
+
+/// A penalty as it currently affects the protocol risk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PenaltyStatus {
+    #[serde(flatten)]
+    pub penalty: RiskPenalty,
+    pub current_bump: f64,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Penalties of `penalties` that are still in effect at `now`
+pub fn active_penalties(penalties: Vec<RiskPenalty>, now: DateTime<Utc>) -> Vec<PenaltyStatus> {
+    penalties
+        .into_iter()
+        .filter(|penalty| penalty.applied_at <= now && now < penalty.expires_at())
+        .map(|penalty| PenaltyStatus {
+            current_bump: penalty.current_bump(now),
+            expires_at: penalty.expires_at(),
+            penalty,
+        })
+        .collect()
+}
+
+pub async fn record_penalty(
+    redis_client: &redis::Client,
+    protocol: &Protocol,
+    penalty: &RiskPenalty,
+) -> Result<(), RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let body = serde_json::to_string(penalty).map_err(RiskCalculationError::SerdeError)?;
+    let _: () = connection
+        .rpush(penalties_key(protocol), body)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    Ok(())
+}
+
+/// Penalties of `protocol` in effect at `now`
+pub async fn load_penalties(
+    redis_client: &redis::Client,
+    protocol: &Protocol,
+    now: DateTime<Utc>,
+) -> Result<Vec<PenaltyStatus>, RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let penalties: Vec<String> = timed(
+        Timing::CacheRead,
+        connection.lrange(penalties_key(protocol), 0, -1),
+    )
+    .await
+    .map_err(RiskCalculationError::from)?;
+    let penalties = penalties
+        .iter()
+        .map(|penalty| serde_json::from_str(penalty).map_err(RiskCalculationError::SerdeError))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(active_penalties(penalties, now))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PenaltyRequest {
+    pub protocol: Protocol,
+    pub source: PenaltySource,
+    pub reason: String,
+    pub bump: f64,
+    /// The configured schedule if not given
+    pub schedule: Option<DecaySchedule>,
+}
+
+/// Admin command: raises a protocol's risk, e.g. on an incident the feeds missed
+///
+/// The body is a [`MultisigApproval`] of a [`PenaltyRequest`]. Cached and
+/// precomputed responses are dropped and the snapshots refreshed in the
+/// background, so the raise is served without waiting for the next hour.
+pub async fn add_penalty(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+    Json(approval): Json<MultisigApproval>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let request: PenaltyRequest =
+            authorize(&state.redis_client, &approval, AdminAction::AddPenalty).await?;
+        if request.bump <= 0.0 {
+            return Err(RiskCalculationError::InvalidParameter(
+                "bump must be positive".to_string(),
+            ));
+        }
+        let penalty = RiskPenalty {
+            source: request.source,
+            reason: request.reason.clone(),
+            bump: request.bump,
+            applied_at: Utc::now(),
+            schedule: request.schedule.unwrap_or(state.config.penalty_decay),
+        };
+        record_penalty(&state.redis_client, &request.protocol, &penalty).await?;
+        let cache = CacheBackend::new(&state.redis_client);
+        if let Err(e) = invalidate_risk_responses(&cache, &request.protocol).await {
+            tracing::error!("Failed to invalidate cached risk responses: {}", e);
+        }
+        if let Err(e) = drop_precomputed(&state.redis_client).await {
+            tracing::error!("Failed to drop precomputed responses: {}", e);
+        }
+        let redis_client = state.redis_client.clone();
+        tokio::spawn(async move {
+            if let Err(e) = refresh_all(&redis_client).await {
+                tracing::error!("Failed to refresh risk snapshots after a penalty: {}", e);
+            }
+        });
+        tracing::info!(
+            "Raised {:?} risk by {} ({:?}: {})",
+            request.protocol,
+            penalty.bump,
+            penalty.source,
+            penalty.reason
+        );
+        Ok(PenaltyStatus {
+            current_bump: penalty.bump,
+            expires_at: penalty.expires_at(),
+            penalty,
+        })
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_penalty_decay() {
+        let applied_at = Utc::now();
+        let penalty = RiskPenalty {
+            source: PenaltySource::IncidentFeed,
+            reason: "oracle exploit".to_string(),
+            bump: 20.0,
+            applied_at,
+            schedule: DecaySchedule::default(),
+        };
+        assert_eq!(penalty.current_bump(applied_at), 20.0);
+        // Half way through the 14 days, 10% of the bump is left
+        assert!((penalty.current_bump(applied_at + Duration::days(7)) - 2.0).abs() < 1e-6);
+        assert_eq!(penalty.current_bump(applied_at + Duration::days(14)), 0.0);
+
+        let linear = RiskPenalty {
+            schedule: DecaySchedule {
+                curve: DecayCurve::Linear,
+                duration_days: 10.0,
+            },
+            ..penalty.clone()
+        };
+        assert!((linear.current_bump(applied_at + Duration::days(5)) - 10.0).abs() < 1e-6);
+
+        let now = applied_at + Duration::days(12);
+        let active = active_penalties(vec![penalty, linear], now);
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].penalty.schedule.curve, DecayCurve::Exponential);
+        assert_eq!(active[0].expires_at, applied_at + Duration::days(14));
+    }
+}
@@ -1,164 +1,440 @@
 use anchor_client::solana_sdk::pubkey::Pubkey;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use solana_account_decoder::UiDataSliceConfig;
 use solana_client::{
     rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
     rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
 };
-use std::str::FromStr;
+use std::{collections::HashMap, str::FromStr};
 
-use crate::risk_model::RiskCalculationError;
+use crate::{
+    cluster::Cluster,
+    risk_model::RiskCalculationError,
+    rpc_pool::{ChunkedFetch, DataCompleteness, RpcPool},
+};
 
-pub async fn fetch_deposits() -> Result<Vec<u128>, RiskCalculationError> {
-    let rpc_url = format!(
-        "https://mainnet.helius-rpc.com?api-key={}",
-        std::env::var("HELIUS_API_KEY").expect("HELIUS_API_KEY must be set")
-    );
-    let program_id = "KLend2g3cP87fffoy8q1mQqGKjrxjC8boSyAYavgmjD";
-    let client = solana_client::nonblocking::rpc_client::RpcClient::new(rpc_url.to_string());
-    // First get all account public keys without data
-
-    let fetched_accounts: Vec<Pubkey> = client
-        .get_program_accounts_with_config(
-            &Pubkey::from_str(program_id)
-                .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
-            RpcProgramAccountsConfig {
-                filters: Some(vec![
-                    RpcFilterType::DataSize(3336 + 8),
-                    RpcFilterType::Memcmp(Memcmp::new(
-                        0,
-                        MemcmpEncodedBytes::Bytes(vec![168, 206, 141, 106, 88, 76, 172, 167]),
-                    )),
-                ]),
-                account_config: RpcAccountInfoConfig {
-                    encoding: None,
-                    data_slice: Some(UiDataSliceConfig {
-                        offset: 0,
-                        length: 8,
-                    }),
-                    commitment: None,
-                    min_context_slot: None,
+use super::{
+    deposit_store::{self, StoredObligation},
+    reserve::KaminoReserveConfig,
+};
+
+/// Anchor discriminator of KLend obligation accounts
+pub(super) const OBLIGATION_DISCRIMINATOR: [u8; 8] = [168, 206, 141, 106, 88, 76, 172, 167];
+/// Size of an obligation account, discriminator included
+pub(super) const OBLIGATION_SIZE: u64 = 3336 + 8;
+/// `tag` of the obligations this layout is for, KLend bumps it with the struct
+const OBLIGATION_LAYOUT_VERSION: u64 = 0;
+// Offsets within an obligation account, from the `Obligation` of the KLend IDL
+const TAG_OFFSET: usize = 8;
+/// After the discriminator, `tag` and `lastUpdate`
+pub(super) const LENDING_MARKET_OFFSET: usize = 8 + 8 + 16;
+/// After the discriminator, `tag`, `lastUpdate` and `lendingMarket`
+pub(super) const OWNER_OFFSET: usize = 8 + 8 + 16 + 32;
+/// After the discriminator, `tag`, `lastUpdate`, `lendingMarket` and `owner`
+pub(super) const DEPOSITS_OFFSET: usize = 8 + 8 + 16 + 32 + 32;
+pub(super) const DEPOSIT_LEGS: usize = 8;
+/// Size of an `ObligationCollateral`
+pub(super) const DEPOSIT_LEG_SIZE: usize = 136;
+// Offsets within a deposit leg
+pub(super) const DEPOSIT_RESERVE_OFFSET: usize = 0;
+pub(super) const DEPOSITED_AMOUNT_OFFSET: usize = 32;
+/// Part of an obligation account up to the end of its deposits, header included
+/// so the layout can be checked
+pub const DEPOSITS_SLICE: UiDataSliceConfig = UiDataSliceConfig {
+    offset: 0,
+    length: DEPOSITS_OFFSET + DEPOSIT_LEGS * DEPOSIT_LEG_SIZE,
+};
+/// `last_update.slot` of an obligation account, which changes whenever its deposits do
+const LAST_UPDATE_SLICE: UiDataSliceConfig = UiDataSliceConfig {
+    offset: 8 + 8,
+    length: 8,
+};
+
+/// Obligation account data that doesn't have the layout deposits are decoded with
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("obligation {obligation} doesn't match the KLend layout: {mismatch}")]
+pub struct ObligationLayoutError {
+    pub obligation: Pubkey,
+    pub mismatch: LayoutMismatch,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum LayoutMismatch {
+    #[error("{0} bytes, expected {}", DEPOSITS_SLICE.length)]
+    Length(usize),
+    #[error("discriminator {0:?}")]
+    Discriminator([u8; 8]),
+    #[error("layout version {0}, expected {}", OBLIGATION_LAYOUT_VERSION)]
+    Version(u64),
+}
+
+/// Amount an obligation deposited in a reserve
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ObligationDeposit {
+    pub reserve: Pubkey,
+    pub amount: u64,
+}
+
+/// Filters matching the obligation accounts of the Kamino program, only the
+/// ones of `market` when given
+pub fn obligation_filters(market: Option<&Pubkey>) -> Vec<RpcFilterType> {
+    let mut filters = vec![
+        RpcFilterType::DataSize(OBLIGATION_SIZE),
+        RpcFilterType::Memcmp(Memcmp::new(
+            0,
+            MemcmpEncodedBytes::Bytes(OBLIGATION_DISCRIMINATOR.to_vec()),
+        )),
+    ];
+    if let Some(market) = market {
+        filters.push(RpcFilterType::Memcmp(Memcmp::new(
+            LENDING_MARKET_OFFSET,
+            MemcmpEncodedBytes::Bytes(market.to_bytes().to_vec()),
+        )));
+    }
+    filters
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Non-empty deposits of `obligation`, from its account data sliced at [`DEPOSITS_SLICE`]
+pub fn decode_deposits(
+    obligation: &Pubkey,
+    data: &[u8],
+) -> Result<Vec<ObligationDeposit>, ObligationLayoutError> {
+    let mismatch = |mismatch| ObligationLayoutError {
+        obligation: *obligation,
+        mismatch,
+    };
+    if data.len() != DEPOSITS_SLICE.length {
+        return Err(mismatch(LayoutMismatch::Length(data.len())));
+    }
+    if data[..8] != OBLIGATION_DISCRIMINATOR {
+        let mut discriminator = [0u8; 8];
+        discriminator.copy_from_slice(&data[..8]);
+        return Err(mismatch(LayoutMismatch::Discriminator(discriminator)));
+    }
+    let version = read_u64(data, TAG_OFFSET);
+    if version != OBLIGATION_LAYOUT_VERSION {
+        return Err(mismatch(LayoutMismatch::Version(version)));
+    }
+    Ok((0..DEPOSIT_LEGS)
+        .map(|leg| DEPOSITS_OFFSET + leg * DEPOSIT_LEG_SIZE)
+        .map(|leg| ObligationDeposit {
+            reserve: Pubkey::new_from_array(
+                data[leg + DEPOSIT_RESERVE_OFFSET..leg + DEPOSIT_RESERVE_OFFSET + 32]
+                    .try_into()
+                    .expect("a pubkey is 32 bytes"),
+            ),
+            amount: read_u64(data, leg + DEPOSITED_AMOUNT_OFFSET),
+        })
+        .filter(|deposit| deposit.amount > 0)
+        .collect())
+}
+
+/// Total an obligation deposited in `reserve`
+pub fn reserve_deposit(deposits: &[ObligationDeposit], reserve: &Pubkey) -> u128 {
+    deposits
+        .iter()
+        .filter(|deposit| deposit.reserve == *reserve)
+        .map(|deposit| deposit.amount as u128)
+        .fold(0u128, |acc, amount| acc.saturating_add(amount))
+}
+
+/// Fetches the deposits every obligation holds in the given reserve
+///
+/// See [`fetch_obligation_deposits`] for `redis_client` and the completeness.
+pub async fn fetch_deposits(
+    reserve: &KaminoReserveConfig,
+    redis_client: Option<&redis::Client>,
+) -> Result<(Vec<u128>, DataCompleteness), RiskCalculationError> {
+    let (deposits, completeness) = fetch_deposits_by_obligation(reserve, redis_client).await?;
+    Ok((
+        deposits.into_iter().map(|(_, deposit)| deposit).collect(),
+        completeness,
+    ))
+}
+
+/// Like [`fetch_deposits`], keeping the obligation each deposit is held by
+pub async fn fetch_deposits_by_obligation(
+    reserve: &KaminoReserveConfig,
+    redis_client: Option<&redis::Client>,
+) -> Result<(Vec<(Pubkey, u128)>, DataCompleteness), RiskCalculationError> {
+    // A reserve only has deposits of obligations of its market
+    let (obligations, completeness) =
+        fetch_obligation_deposits(Some(&reserve.market), redis_client).await?;
+    let deposits = obligations
+        .iter()
+        .map(|(obligation, deposits)| (*obligation, reserve_deposit(deposits, &reserve.reserve)))
+        .filter(|(_, deposit)| *deposit > 0)
+        .collect();
+    Ok((deposits, completeness))
+}
+
+/// Fetches the deposits of every obligation of `market` with any, in any
+/// reserve, of every market when `None`
+///
+/// With `redis_client`, the deposits are kept in redis between scans along with
+/// the slot each obligation was last updated at. Scans then only read that slot
+/// of every obligation and refetch the deposits of the ones that changed, instead
+/// of all of them. Without it, or when the stored deposits can't be read, every
+/// obligation is fetched. Every market scanned is stored apart.
+///
+/// The stored deposits of obligations in chunks that failed to be refetched
+/// stand in for them, the completeness counts the others as missing among
+/// every obligation scanned, along with the obligations that couldn't be decoded.
+pub async fn fetch_obligation_deposits(
+    market: Option<&Pubkey>,
+    redis_client: Option<&redis::Client>,
+) -> Result<(Vec<(Pubkey, Vec<ObligationDeposit>)>, DataCompleteness), RiskCalculationError> {
+    let pool = RpcPool::global();
+    let program_id = Pubkey::from_str(Cluster::global().kamino_program_id())
+        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
+    // First get the slot every obligation was last updated at, without the rest of its data
+    let slots: Vec<(Pubkey, u64)> = pool
+        .call(|client| {
+            client.get_program_accounts_with_config(
+                &program_id,
+                RpcProgramAccountsConfig {
+                    filters: Some(obligation_filters(market)),
+                    account_config: RpcAccountInfoConfig {
+                        encoding: None,
+                        data_slice: Some(LAST_UPDATE_SLICE),
+                        commitment: None,
+                        min_context_slot: None,
+                    },
+                    with_context: None,
                 },
-                with_context: None,
-            },
-        )
+            )
+        })
         .await
-        .map_err(|e| RiskCalculationError::RpcCallError(e))?
+        .map_err(RiskCalculationError::from)?
         .into_iter()
-        .map(|(pk, _)| pk)
+        .map(|(pk, account)| {
+            // Obligations without a readable slot are always refetched
+            let slot = account.data.try_into().map_or(0, u64::from_le_bytes);
+            (pk, slot)
+        })
         .collect();
-    // println!("Total Accounts {:?}", accounts.len());
-
-    // Process accounts in chunks
-    const CHUNK_SIZE: usize = 100;
-    let futures = fetched_accounts
-        .chunks(CHUNK_SIZE)
-        .map(|chunk| {
-            let pubkeys: Vec<Pubkey> = chunk.to_vec();
-            let rpc_url = rpc_url.to_string();
-            tokio::spawn(async move {
-                let client = solana_client::nonblocking::rpc_client::RpcClient::new(rpc_url);
-                let account_infos = client
-                    .get_multiple_accounts_with_config(
+
+    let mut stored = match redis_client {
+        Some(redis_client) => deposit_store::load(redis_client, market)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!("Failed to load stored obligation deposits: {}", e);
+                HashMap::new()
+            }),
+        None => HashMap::new(),
+    };
+    let changed = deposit_store::changed(&slots, &stored);
+    tracing::info!(
+        "Refetching {} of {} obligations",
+        changed.len(),
+        slots.len()
+    );
+    let (refetched, failed, undecodable) = fetch_accounts_deposits(&changed).await;
+    // Undecodable obligations changed since they were stored, their stored
+    // deposits are stale and don't stand in for them
+    for obligation in &undecodable {
+        stored.remove(obligation);
+    }
+    let completeness = ChunkedFetch::global().check_completeness(
+        "Kamino obligations",
+        DataCompleteness {
+            accounts: slots.len(),
+            missing_accounts: failed
+                .iter()
+                .filter(|obligation| !stored.contains_key(obligation))
+                .count()
+                + undecodable.len(),
+        },
+    )?;
+
+    if let Some(redis_client) = redis_client {
+        let slot_of: HashMap<_, _> = slots.iter().copied().collect();
+        let updates: Vec<_> = refetched
+            .iter()
+            .map(|(obligation, deposits)| {
+                (
+                    *obligation,
+                    StoredObligation {
+                        slot: slot_of[obligation],
+                        deposits: deposits.clone(),
+                    },
+                )
+            })
+            .collect();
+        let closed = deposit_store::closed(&slots, &stored);
+        if let Err(e) = deposit_store::save(redis_client, market, &updates, &closed).await {
+            tracing::error!("Failed to store obligation deposits: {}", e);
+        }
+    }
+
+    let mut refetched: HashMap<_, _> = refetched.into_iter().collect();
+    let deposits = slots
+        .iter()
+        .filter_map(|(obligation, _)| match refetched.remove(obligation) {
+            Some(deposits) => Some((*obligation, deposits)),
+            // Unchanged, or failed to be refetched, the stored deposits are the latest known
+            None => stored
+                .remove(obligation)
+                .map(|stored| (*obligation, stored.deposits)),
+        })
+        .filter(|(_, deposits)| !deposits.is_empty())
+        .collect();
+    Ok((deposits, completeness))
+}
+
+/// Fetches the deposits of `obligations`, empty for ones without any
+///
+/// Obligations in chunks that fail to be fetched are left out and returned
+/// apart, see [`ChunkedFetch::fetch_partial`], followed by the obligations
+/// that were fetched but couldn't be decoded.
+async fn fetch_accounts_deposits(
+    obligations: &[Pubkey],
+) -> (
+    Vec<(Pubkey, Vec<ObligationDeposit>)>,
+    Vec<Pubkey>,
+    Vec<Pubkey>,
+) {
+    let pool = RpcPool::global();
+    let (fetched, failed) = ChunkedFetch::global()
+        .fetch_partial("Kamino obligations", obligations, |pubkeys| async move {
+            let account_infos = pool
+                .call(|client| {
+                    client.get_multiple_accounts_with_config(
                         &pubkeys,
                         RpcAccountInfoConfig {
-                            data_slice: Some(UiDataSliceConfig {
-                                offset: 88 + 8,
-                                length: 1088,
-                            }),
+                            data_slice: Some(DEPOSITS_SLICE),
                             encoding: None,
                             commitment: None,
                             min_context_slot: None,
                         },
                     )
-                    .await?;
-                let mut chunk_deposits = Vec::new();
-                for mut account_info in account_infos.value.into_iter().flatten() {
-                    [168, 206, 141, 106, 88, 76, 172, 167]
-                        .iter()
-                        .enumerate()
-                        .for_each(|(i, &byte)| account_info.data[i] = byte);
-                    let obligation: Obligation = match account_info.deserialize_data() {
-                        Err(err) => {
-                            tracing::error!("Error while deserializing obligation: {}", err);
-                            continue;
-                        }
-                        Ok(data) => data,
-                    };
-                    let user_total_deposits = obligation
-                        .deposits
-                        .iter()
-                        .filter(|collateral| collateral.deposit_reserve != Pubkey::default())
-                        .map(|collateral| collateral.deposited_amount as u128)
-                        .fold(0u128, |acc, amount| acc.saturating_add(amount));
-
-                    if user_total_deposits > 0 {
-                        chunk_deposits.push(user_total_deposits);
-                    }
-                }
-                Ok::<Vec<u128>, solana_client::client_error::ClientError>(chunk_deposits)
-            })
+                })
+                .await?;
+            let mut chunk_deposits = Vec::new();
+            for (pubkey, account_info) in pubkeys.into_iter().zip(account_infos.value) {
+                let Some(account_info) = account_info else {
+                    continue;
+                };
+                let deposits = decode_deposits(&pubkey, &account_info.data)
+                    .inspect_err(|err| tracing::error!("Error while decoding obligation: {}", err))
+                    .ok();
+                chunk_deposits.push((pubkey, deposits));
+            }
+            Ok(chunk_deposits)
         })
-        .collect::<Vec<_>>();
+        .await;
+    let (decoded, undecodable): (Vec<_>, Vec<_>) = fetched
+        .into_iter()
+        .partition(|(_, deposits)| deposits.is_some());
+    (
+        decoded
+            .into_iter()
+            .filter_map(|(pubkey, deposits)| Some((pubkey, deposits?)))
+            .collect(),
+        failed,
+        undecodable.into_iter().map(|(pubkey, _)| pubkey).collect(),
+    )
+}
 
-    let mut deposits_by_user = Vec::new();
-    let mut total_deposits: u128 = 0;
-    let mut error_count = 0;
-    for handle in futures {
-        match handle
-            .await
-            .map_err(|e| RiskCalculationError::CustomError(e.to_string()))?
-        {
-            Ok(chunk_deposits) => {
-                deposits_by_user.extend(chunk_deposits.clone());
-                for deposit in chunk_deposits {
-                    total_deposits = total_deposits.saturating_add(deposit);
-                }
-            }
-            Err(e) => {
-                tracing::error!("Error: {}", e);
-                error_count += 1;
-            }
+#[cfg(test)]
+mod tests {
+    use crate::{
+        kamino::sources::{fixtures::FixtureSources, DepositSource},
+        liquidity_risk::calculate_concentration,
+    };
+
+    use super::*;
+
+    fn obligation_data(tag: u64, deposits: &[(Pubkey, u64)]) -> Vec<u8> {
+        obligation_data_in(&Pubkey::default(), tag, deposits)
+    }
+
+    fn obligation_data_in(market: &Pubkey, tag: u64, deposits: &[(Pubkey, u64)]) -> Vec<u8> {
+        let mut data = vec![0u8; DEPOSITS_SLICE.length];
+        data[..8].copy_from_slice(&OBLIGATION_DISCRIMINATOR);
+        data[TAG_OFFSET..TAG_OFFSET + 8].copy_from_slice(&tag.to_le_bytes());
+        data[LENDING_MARKET_OFFSET..LENDING_MARKET_OFFSET + 32].copy_from_slice(market.as_ref());
+        for (leg, (reserve, amount)) in deposits.iter().enumerate() {
+            let leg = DEPOSITS_OFFSET + leg * DEPOSIT_LEG_SIZE;
+            data[leg..leg + 32].copy_from_slice(reserve.as_ref());
+            data[leg + DEPOSITED_AMOUNT_OFFSET..leg + DEPOSITED_AMOUNT_OFFSET + 8]
+                .copy_from_slice(&amount.to_le_bytes());
         }
+        data
     }
 
-    tracing::info!("error_count {:?}", error_count);
-    tracing::info!("success_count {:?}", fetched_accounts.len() - error_count);
-    Ok(deposits_by_user)
-}
+    #[test]
+    fn test_decode_deposits() {
+        let obligation = Pubkey::new_unique();
+        let (usdc, sol) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let data = obligation_data(0, &[(usdc, 5), (Pubkey::new_unique(), 0), (sol, 7)]);
+        assert_eq!(
+            decode_deposits(&obligation, &data).unwrap(),
+            vec![
+                ObligationDeposit {
+                    reserve: usdc,
+                    amount: 5
+                },
+                ObligationDeposit {
+                    reserve: sol,
+                    amount: 7
+                },
+            ]
+        );
 
-#[derive(Debug, Default, Deserialize)]
-struct Obligation {
-    pub deposits: [ObligationCollateral; 8],
-}
-#[allow(unused)]
-#[derive(Debug, Default, Deserialize)]
-struct ObligationCollateral {
-    pub deposit_reserve: Pubkey,
-    pub deposited_amount: u64,
-    pub market_value_sf: u128,
-    pub borrowed_amount_against_this_collateral_in_elevation_group: u64,
-    pub padding: [u64; 9],
-}
+        let mismatch = |data: &[u8]| decode_deposits(&obligation, data).unwrap_err();
+        assert_eq!(
+            mismatch(&data[..100]),
+            ObligationLayoutError {
+                obligation,
+                mismatch: LayoutMismatch::Length(100)
+            }
+        );
+        assert_eq!(
+            mismatch(&obligation_data(1, &[])).mismatch,
+            LayoutMismatch::Version(1)
+        );
+        let mut foreign = obligation_data(0, &[]);
+        foreign[0] = 0;
+        assert!(matches!(
+            mismatch(&foreign).mismatch,
+            LayoutMismatch::Discriminator(_)
+        ));
+    }
 
-#[cfg(test)]
-mod tests {
-    use crate::liquidity_risk::calculate_concentration;
+    #[test]
+    fn test_market_filter() {
+        let (main, jlp) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let matches = |filters: &[RpcFilterType], data: &[u8]| {
+            filters.iter().all(|filter| match filter {
+                RpcFilterType::Memcmp(memcmp) => memcmp.bytes_match(data),
+                _ => true,
+            })
+        };
+        let in_main = obligation_data_in(&main, 0, &[]);
+        assert!(matches(&obligation_filters(Some(&main)), &in_main));
+        assert!(!matches(&obligation_filters(Some(&jlp)), &in_main));
+        assert!(matches(&obligation_filters(None), &in_main));
+    }
 
-    use super::*;
-    // Example usage
     #[tokio::test]
-    async fn test() {
-        match fetch_deposits().await {
-            Ok(deposits) => {
-                let deposit_concentration = calculate_concentration(deposits)
-                    .ok_or(RiskCalculationError::CustomError(
-                        "No deposits found".to_string(),
-                    ))
-                    .unwrap();
-                println!("Deposit Concentration: {:?}", deposit_concentration)
-            }
-            Err(e) => eprintln!("Error calculating deposit concentration: {:?}", e),
-        }
+    async fn test_fixture_concentration() {
+        let (deposits, completeness) = FixtureSources::usdc()
+            .deposits(&KaminoReserveConfig::usdc(), None)
+            .await
+            .unwrap();
+        assert!(completeness.is_complete());
+        let largest = *deposits.iter().max().unwrap();
+        let total = deposits.iter().sum::<u128>();
+        let concentration = calculate_concentration(deposits).unwrap();
+        assert!(concentration > 0.0 && concentration < 1.0);
+        assert!((concentration - largest as f64 / total as f64).abs() < 1e-6);
+        assert_eq!(calculate_concentration(Vec::new()), None);
     }
 }
@@ -0,0 +1,270 @@
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use anchor_client::solana_sdk::{account::Account, pubkey::Pubkey};
+use dashmap::DashMap;
+use futures::StreamExt;
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::{
+    nonblocking::pubsub_client::PubsubClient,
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_response::{Response, RpcKeyedAccount},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    cache::CacheBackend,
+    cluster::{ws_url, Cluster},
+    risk_model::RiskCalculationError,
+    shutdown,
+};
+
+use super::deposit_conc::{
+    decode_deposits, fetch_obligation_deposits, obligation_filters, reserve_deposit,
+    ObligationDeposit, DEPOSITS_SLICE,
+};
+
+/// Delay before resubscribing after the subscription failed or closed
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+static DEPOSIT_INDEX: OnceLock<DepositIndex> = OnceLock::new();
+
+/// Deposits of every Kamino obligation, kept up to date by a `programSubscribe`
+/// subscription instead of rescanning the program every hour
+///
+/// Enabled with `OBLIGATION_SUBSCRIPTION=true`. The index is seeded by one
+/// scan once subscribed and only served while the subscription is up.
+#[derive(Debug, Default)]
+pub struct DepositIndex {
+    obligations: DashMap<Pubkey, Vec<ObligationDeposit>>,
+    live: AtomicBool,
+}
+
+impl DepositIndex {
+    fn global() -> &'static Self {
+        DEPOSIT_INDEX.get_or_init(Self::default)
+    }
+
+    /// The index when it mirrors the chain, none before it's seeded and while resubscribing
+    pub fn live() -> Option<&'static Self> {
+        DEPOSIT_INDEX
+            .get()
+            .filter(|index| index.live.load(Ordering::Acquire))
+    }
+
+    /// Reads `OBLIGATION_SUBSCRIPTION`, disabled if not set
+    pub fn enabled() -> Result<bool, RiskCalculationError> {
+        match std::env::var("OBLIGATION_SUBSCRIPTION") {
+            Ok(enabled) => enabled.trim().parse().map_err(|_| {
+                RiskCalculationError::ParseError(format!(
+                    "OBLIGATION_SUBSCRIPTION must be true or false: {:?}",
+                    enabled
+                ))
+            }),
+            Err(_) => Ok(false),
+        }
+    }
+
+    /// Non-zero deposits of every obligation in `reserve`, like [`super::deposit_conc::fetch_deposits`]
+    pub fn deposits(&self, reserve: &Pubkey) -> Vec<u128> {
+        self.deposits_by_obligation(reserve)
+            .into_iter()
+            .map(|(_, deposit)| deposit)
+            .collect()
+    }
+
+    /// Like [`Self::deposits`], keeping the obligation each deposit is held by
+    pub fn deposits_by_obligation(&self, reserve: &Pubkey) -> Vec<(Pubkey, u128)> {
+        self.obligations
+            .iter()
+            .map(|obligation| {
+                (
+                    *obligation.key(),
+                    reserve_deposit(obligation.value(), reserve),
+                )
+            })
+            .filter(|(_, deposit)| *deposit > 0)
+            .collect()
+    }
+
+    /// Replaces the deposits of `obligation`, removing it when it has none left
+    fn update(&self, obligation: Pubkey, deposits: Vec<ObligationDeposit>) {
+        if deposits.is_empty() {
+            self.obligations.remove(&obligation);
+        } else {
+            self.obligations.insert(obligation, deposits);
+        }
+    }
+
+    /// Replaces the index with a scan, except the obligations `updated` since subscribing
+    fn seed(&self, scanned: Vec<(Pubkey, Vec<ObligationDeposit>)>, updated: &HashSet<Pubkey>) {
+        self.obligations
+            .retain(|obligation, _| updated.contains(obligation));
+        for (obligation, deposits) in scanned {
+            if !updated.contains(&obligation) {
+                self.update(obligation, deposits);
+            }
+        }
+    }
+
+    /// Applies an account notification, returning the obligation it's for
+    fn apply(&self, notification: Response<RpcKeyedAccount>) -> Option<Pubkey> {
+        let keyed = notification.value;
+        let obligation = Pubkey::from_str(&keyed.pubkey).ok()?;
+        let account: Account = keyed.account.decode()?;
+        // Closed obligations are notified with no lamports left
+        let deposits = if account.lamports == 0 {
+            Vec::new()
+        } else {
+            match decode_deposits(&obligation, &account.data) {
+                Ok(deposits) => deposits,
+                Err(e) => {
+                    tracing::error!("Error while decoding obligation update: {}", e);
+                    return None;
+                }
+            }
+        };
+        self.update(obligation, deposits);
+        Some(obligation)
+    }
+}
+
+/// Follows obligation updates until `shutdown`, resubscribing whenever the subscription drops
+///
+/// The index is seeded from the deposits stored in `redis_client`, when redis is the cache.
+pub fn spawn_subscription(
+    redis_client: redis::Client,
+    shutdown: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    shutdown::spawn_background(async move {
+        let index = DepositIndex::global();
+        let store = CacheBackend::new(&redis_client).redis_client();
+        while !shutdown.is_cancelled() {
+            if let Err(e) = follow(index, store, &shutdown).await {
+                tracing::error!("Obligation subscription failed, resubscribing: {}", e);
+            }
+            index.live.store(false, Ordering::Release);
+            tokio::select! {
+                _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+                _ = shutdown.cancelled() => {}
+            }
+        }
+        tracing::info!("Obligation subscription stopped");
+    })
+}
+
+async fn follow(
+    index: &DepositIndex,
+    store: Option<&redis::Client>,
+    shutdown: &CancellationToken,
+) -> Result<(), RiskCalculationError> {
+    let unavailable = |e: solana_client::pubsub_client::PubsubClientError| {
+        RiskCalculationError::UpstreamUnavailable(format!("obligation subscription: {}", e))
+    };
+    let program_id = Pubkey::from_str(Cluster::global().kamino_program_id())
+        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
+    let client = PubsubClient::new(&ws_url()?).await.map_err(unavailable)?;
+    let (mut updates, unsubscribe) = client
+        .program_subscribe(
+            &program_id,
+            Some(RpcProgramAccountsConfig {
+                filters: Some(obligation_filters(None)),
+                account_config: RpcAccountInfoConfig {
+                    encoding: Some(UiAccountEncoding::Base64),
+                    data_slice: Some(DEPOSITS_SLICE),
+                    commitment: None,
+                    min_context_slot: None,
+                },
+                with_context: None,
+            }),
+        )
+        .await
+        .map_err(unavailable)?;
+
+    // Subscribed first so no update is missed, updates during the scan win over it
+    tracing::info!("Seeding the deposit index...");
+    let scan = fetch_obligation_deposits(None, store);
+    tokio::pin!(scan);
+    let mut updated = HashSet::new();
+    let scanned = loop {
+        tokio::select! {
+            scanned = &mut scan => break scanned?.0,
+            update = updates.next() => match update {
+                Some(update) => updated.extend(index.apply(update)),
+                None => return Err(closed()),
+            },
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+    };
+    index.seed(scanned, &updated);
+    index.live.store(true, Ordering::Release);
+    tracing::info!(
+        "Deposit index live with {} obligations",
+        index.obligations.len()
+    );
+
+    loop {
+        tokio::select! {
+            update = updates.next() => match update {
+                Some(update) => {
+                    index.apply(update);
+                }
+                None => return Err(closed()),
+            },
+            _ = shutdown.cancelled() => {
+                unsubscribe().await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn closed() -> RiskCalculationError {
+    RiskCalculationError::UpstreamUnavailable("obligation subscription closed".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deposit(reserve: Pubkey, amount: u64) -> ObligationDeposit {
+        ObligationDeposit { reserve, amount }
+    }
+
+    #[test]
+    fn test_deposit_index() {
+        let (usdc, sol) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let (first, second, third) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let index = DepositIndex::default();
+        // Updated while the scan ran, so newer than its result
+        index.update(first, vec![deposit(usdc, 5)]);
+        index.update(third, vec![deposit(usdc, 7)]);
+        index.seed(
+            vec![
+                (first, vec![deposit(usdc, 1)]),
+                (second, vec![deposit(usdc, 2), deposit(sol, 3)]),
+            ],
+            &HashSet::from([first]),
+        );
+
+        let mut deposits = index.deposits(&usdc);
+        deposits.sort();
+        assert_eq!(deposits, vec![2, 5]);
+        assert_eq!(index.deposits(&sol), vec![3]);
+
+        index.update(second, vec![]);
+        assert_eq!(index.deposits(&usdc), vec![5]);
+        assert!(index.deposits(&sol).is_empty());
+    }
+}
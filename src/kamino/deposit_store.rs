@@ -0,0 +1,159 @@
+use std::collections::{HashMap, HashSet};
+
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache_schema::versioned_key, redis_connection::shared_connection,
+    risk_model::RiskCalculationError,
+};
+
+use super::deposit_conc::ObligationDeposit;
+
+/// Obligations written to redis per command, so no single command gets huge
+const WRITE_BATCH: usize = 1000;
+
+/// Deposits of an obligation as of the slot it was last updated at
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredObligation {
+    /// `last_update.slot` of the obligation, bumped by KLend whenever it's refreshed
+    pub slot: u64,
+    /// Empty for obligations with only borrows, so they aren't refetched either
+    pub deposits: Vec<ObligationDeposit>,
+}
+
+/// Hash of every obligation's [`StoredObligation`], by obligation, of `market`
+/// or of every market when `None`
+///
+/// Kept out of the `kamino:` namespaces so cache invalidations don't match it.
+fn deposits_key(market: Option<&Pubkey>) -> String {
+    match market {
+        Some(market) => versioned_key(&format!("obligation_deposits:kamino:{}", market)),
+        None => versioned_key("obligation_deposits:kamino"),
+    }
+}
+
+/// Deposits of every obligation of `market` as of its previous scan
+pub async fn load(
+    redis_client: &redis::Client,
+    market: Option<&Pubkey>,
+) -> Result<HashMap<Pubkey, StoredObligation>, RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let stored: HashMap<String, Vec<u8>> = connection
+        .hgetall(deposits_key(market))
+        .await
+        .map_err(RiskCalculationError::from)?;
+    stored
+        .into_iter()
+        .map(|(obligation, stored)| {
+            Ok((
+                obligation
+                    .parse::<Pubkey>()
+                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                bincode::deserialize(&stored)
+                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+            ))
+        })
+        .collect()
+}
+
+/// Writes the obligations refetched by a scan and removes the closed ones
+pub async fn save(
+    redis_client: &redis::Client,
+    market: Option<&Pubkey>,
+    refetched: &[(Pubkey, StoredObligation)],
+    closed: &[Pubkey],
+) -> Result<(), RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    for batch in refetched.chunks(WRITE_BATCH) {
+        let fields = batch
+            .iter()
+            .map(|(obligation, stored)| {
+                Ok((
+                    obligation.to_string(),
+                    bincode::serialize(stored)
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                ))
+            })
+            .collect::<Result<Vec<_>, RiskCalculationError>>()?;
+        let _: () = connection
+            .hset_multiple(deposits_key(market), &fields)
+            .await
+            .map_err(RiskCalculationError::from)?;
+    }
+    for batch in closed.chunks(WRITE_BATCH) {
+        let fields: Vec<String> = batch.iter().map(Pubkey::to_string).collect();
+        let _: () = connection
+            .hdel(deposits_key(market), fields)
+            .await
+            .map_err(RiskCalculationError::from)?;
+    }
+    Ok(())
+}
+
+/// Obligations whose slot differs from the stored one, or that weren't stored yet
+pub fn changed(slots: &[(Pubkey, u64)], stored: &HashMap<Pubkey, StoredObligation>) -> Vec<Pubkey> {
+    slots
+        .iter()
+        .filter(|(obligation, slot)| {
+            stored.get(obligation).map(|stored| stored.slot) != Some(*slot)
+        })
+        .map(|(obligation, _)| *obligation)
+        .collect()
+}
+
+/// Stored obligations the program no longer has
+pub fn closed(slots: &[(Pubkey, u64)], stored: &HashMap<Pubkey, StoredObligation>) -> Vec<Pubkey> {
+    let open: HashSet<_> = slots.iter().map(|(obligation, _)| obligation).collect();
+    stored
+        .keys()
+        .filter(|obligation| !open.contains(obligation))
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_and_closed() {
+        let (unchanged, updated, new, gone) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let stored_at = |slot| StoredObligation {
+            slot,
+            deposits: vec![],
+        };
+        let stored = HashMap::from([
+            (unchanged, stored_at(10)),
+            (updated, stored_at(10)),
+            (gone, stored_at(10)),
+        ]);
+        let slots = [(unchanged, 10), (updated, 12), (new, 11)];
+
+        assert_eq!(changed(&slots, &stored), vec![updated, new]);
+        assert_eq!(closed(&slots, &stored), vec![gone]);
+
+        let stored_obligation = StoredObligation {
+            slot: 12,
+            deposits: vec![ObligationDeposit {
+                reserve: Pubkey::new_unique(),
+                amount: 5,
+            }],
+        };
+        let encoded = bincode::serialize(&stored_obligation).unwrap();
+        assert_eq!(
+            bincode::deserialize::<StoredObligation>(&encoded).unwrap(),
+            stored_obligation
+        );
+    }
+}
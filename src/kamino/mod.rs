@@ -1,125 +1,379 @@
-use deposit_conc::fetch_deposits;
+use deposit_index::DepositIndex;
+use obligations::fetch_obligations;
+use reserve::KaminoReserveConfig;
+use serde::{Deserialize, Serialize};
+use sources::{DepositSource, LiveSources, UtilizationSource, YieldHistorySource};
 use tracing::info;
-use utilization_rate::get_total_borrows_and_supply;
-use yield_data::fetch_yield_and_utilization_rates;
 
 use crate::{
-    liquidity_risk::{calculate_liquidity_risk, calculate_utilization_rate},
+    cache::CacheBackend,
+    concentration_history::{record_concentration, ConcentrationSnapshot},
+    liquidity_risk::{
+        calculate_borrower_concentration, calculate_deposit_hhi, calculate_liquidation_risk,
+        calculate_tvl_trend, concentration_total, liquidity_metrics, with_caps,
+        BorrowerConcentration, ConcentrationDenominator, LiquidationRiskMetrics, ReserveCaps,
+        TvlTrend,
+    },
+    oracle_risk::{fetch_oracle_risk, OracleRiskMetrics},
+    protocol_rubric::ProtocolRubric,
+    quorum::QuorumReport,
     risk_model::{
-        get_seconds_until_next_hour, LiquidityRiskMetrics, ProtocolRisk, ProtocolRiskMetrics,
-        RiskCalculationError, VolatilityRiskMetrics,
+        LiquidityRiskMetrics, Protocol, ProtocolRisk, ProtocolRiskMetrics, RiskCalculationError,
+        VolatilityRiskMetrics,
     },
-    volatility_risk::calculate_lending_pool_risk,
+    timings::{timed, timed_sync, Timing},
+    top_depositors::DepositRanking,
+    upstream::{self, Upstream},
+    volatility_risk::calculate_volatility_surface,
 };
 
 mod deposit_conc;
+pub mod deposit_index;
+mod deposit_store;
+pub mod obligations;
+pub mod positions;
+pub mod reserve;
+pub mod reserve_account;
+pub mod sources;
 mod utilization_rate;
-mod yield_data;
-pub struct KaminoRisk {
+pub mod yield_data;
+#[derive(Clone)]
+pub struct KaminoRisk<S = LiveSources> {
     pub redis_client: redis::Client,
+    pub reserve: KaminoReserveConfig,
+    /// Where deposits, metrics history and utilization are read from
+    pub sources: S,
+}
+
+/// What the obligation scan yields, cached together as it comes from one scan
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ObligationMetrics {
+    liquidation_risk: Option<LiquidationRiskMetrics>,
+    borrower_concentration: Option<BorrowerConcentration>,
+}
+
+impl<S: DepositSource + YieldHistorySource + UtilizationSource> KaminoRisk<S> {
+    /// See [`crate::registry::RegisteredProtocol::scope`]
+    pub fn scope(&self) -> String {
+        format!("kamino-{}", self.reserve.reserve)
+    }
+
+    /// Health and concentration of the borrowers, cached as long as the liquidity pillar
+    ///
+    /// Health is of the whole market, as collateral is shared across its
+    /// reserves, while concentration is of the reserve's borrows only.
+    async fn obligation_metrics(&self) -> Result<ObligationMetrics, RiskCalculationError> {
+        let cache_key = "obligations:metrics";
+        if let Ok(cached) = self.cache_get(cache_key).await {
+            return serde_json::from_str(&cached)
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()));
+        }
+
+        info!("Fetching obligations...");
+        let obligations = timed(
+            Timing::Rpc,
+            upstream::call(Upstream::Rpc, fetch_obligations(&self.reserve)),
+        )
+        .await?;
+        let metrics = timed_sync(Timing::Compute, || {
+            let health: Vec<_> = obligations.iter().map(|o| o.health).collect();
+            let borrows: Vec<_> = obligations
+                .iter()
+                .map(|o| o.borrowed_from(&self.reserve.reserve))
+                .collect();
+            ObligationMetrics {
+                liquidation_risk: calculate_liquidation_risk(&health),
+                borrower_concentration: calculate_borrower_concentration(&borrows),
+            }
+        });
+        self.cache_set_liquidity_input(
+            cache_key,
+            &serde_json::to_string(&metrics)
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+        )
+        .await?;
+        Ok(metrics)
+    }
+
+    /// Largest deposits of the reserve, cached until the next hour
+    pub async fn deposit_ranking(&self) -> Result<DepositRanking, RiskCalculationError> {
+        if let Some(index) = DepositIndex::live() {
+            return Ok(DepositRanking::from_deposits(
+                index.deposits_by_obligation(&self.reserve.reserve),
+            ));
+        }
+        let cache_key = "deposits:ranking";
+        if let Ok(cached) = self.cache_get(cache_key).await {
+            return serde_json::from_str(&cached)
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()));
+        }
+
+        info!("Fetching deposits by obligation...");
+        let store = CacheBackend::new(&self.redis_client).redis_client();
+        let ranking = DepositRanking::from_deposits(
+            self.sources
+                .deposits_by_obligation(&self.reserve, store)
+                .await?
+                .0,
+        );
+        self.cache_set_until_next_hour(
+            cache_key,
+            &serde_json::to_string(&ranking)
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+        )
+        .await?;
+        Ok(ranking)
+    }
+
+    /// Net deposit flow of the reserve, cached until the next hour
+    ///
+    /// `None` with less than a day of history.
+    async fn tvl_trend(&self) -> Result<Option<TvlTrend>, RiskCalculationError> {
+        let cache_key = "liquidity:tvl_trend";
+        if let Ok(cached) = self.cache_get(cache_key).await {
+            return serde_json::from_str(&cached)
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()));
+        }
+
+        info!("Fetching supply history...");
+        // One more hour than the longest change, for the point it's relative to
+        let supplies = self
+            .sources
+            .supply_history(&self.reserve, 24 * 7 + 1)
+            .await?;
+        // The Kamino metrics history is hourly
+        let trend = calculate_tvl_trend(&supplies, 1);
+        self.cache_set_until_next_hour(
+            cache_key,
+            &serde_json::to_string(&trend)
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+        )
+        .await?;
+        Ok(trend)
+    }
+
+    /// Caps and available liquidity of the reserve, cached as long as the liquidity pillar
+    async fn reserve_caps(&self) -> Result<ReserveCaps, RiskCalculationError> {
+        let cache_key = "liquidity:caps";
+        if let Ok(cached) = self.cache_get(cache_key).await {
+            return serde_json::from_str(&cached)
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()));
+        }
+
+        info!("Fetching reserve caps...");
+        let caps = self.sources.reserve_caps(&self.reserve).await?;
+        self.cache_set_liquidity_input(
+            cache_key,
+            &serde_json::to_string(&caps)
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+        )
+        .await?;
+        Ok(caps)
+    }
+
+    /// Collateral minted by the reserve, cached as long as the liquidity pillar
+    async fn collateral_supply(&self) -> Result<u128, RiskCalculationError> {
+        let cache_key = "deposits:reserve_supply";
+        if let Ok(cached) = self.cache_get(cache_key).await {
+            return cached
+                .parse::<u128>()
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()));
+        }
+
+        info!("Fetching the reserve's collateral supply...");
+        let supply = self.sources.collateral_supply(&self.reserve).await?;
+        self.cache_set_liquidity_input(cache_key, &supply.to_string())
+            .await?;
+        Ok(supply)
+    }
 }
-use redis::AsyncCommands;
-
-impl ProtocolRisk for KaminoRisk {
-    const W_LIQ_D_CONC: f64 = 0.4;
-    const W_LIQ_UTIL: f64 = 0.6;
-    const W_VOL_APY: f64 = 0.7;
-    const W_VOL_UTIL: f64 = 0.3;
-    const W_LIQUIDITY: f64 = 0.4;
-    const W_VOLATILITY: f64 = 0.3;
-    const W_PROTOCOL: f64 = 0.3;
+
+/// Largest, total and HHI of the deposits in a reserve
+fn summarize_deposits(deposits: &[u128]) -> Result<(u128, u128, f64), RiskCalculationError> {
+    let largest = *deposits
+        .iter()
+        .max()
+        .ok_or(RiskCalculationError::CustomError(
+            "No deposits found".to_string(),
+        ))?;
+    let hhi = calculate_deposit_hhi(deposits).unwrap_or_default();
+    Ok((largest, deposits.iter().sum::<u128>(), hhi))
+}
+
+impl<S: DepositSource + YieldHistorySource + UtilizationSource> ProtocolRisk for KaminoRisk<S> {
     fn redis_client(&self) -> &redis::Client {
         &self.redis_client
     }
+    fn protocol(&self) -> Protocol {
+        Protocol::Kamino
+    }
+    fn cache_namespace(&self) -> String {
+        self.reserve.cache_namespace()
+    }
     async fn calculate_liquidity_risk(&self) -> Result<LiquidityRiskMetrics, RiskCalculationError> {
         // Try to get cached deposit data
         let largest_deposit_key = "deposits:largest";
         let total_deposits_key = "deposits:total";
 
-        let (largest_deposit, total_deposits) = if let (Ok(largest), Ok(total)) = (
-            self.redis_get(largest_deposit_key).await,
-            self.redis_get(total_deposits_key).await,
-        ) {
-            (
-                largest
-                    .parse::<u128>()
-                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
-                total
-                    .parse::<u128>()
-                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
-            )
-        } else {
-            info!("Fetching deposits...");
-            let deposits = fetch_deposits().await?;
-            let largest = *deposits
-                .iter()
-                .max()
-                .ok_or(RiskCalculationError::CustomError(
-                    "No deposits found".to_string(),
-                ))?;
-            let total = deposits.iter().sum::<u128>();
-
-            // Cache deposits data
-            self.redis_set_until_next_hour(largest_deposit_key, &largest.to_string())
-                .await?;
-            self.redis_set_until_next_hour(total_deposits_key, &total.to_string())
+        let deposits_hhi_key = "deposits:hhi";
+        let completeness_key = "deposits:completeness";
+
+        let (largest_deposit, total_deposits, deposits_hhi, data_completeness) =
+            if let Some(index) = DepositIndex::live() {
+                // Follows every deposit, fresher than any cached scan
+                let (largest, total, hhi) =
+                    summarize_deposits(&index.deposits(&self.reserve.reserve))?;
+                (largest, total, Some(hhi), None)
+            } else if let (Ok(largest), Ok(total)) = (
+                self.cache_get(largest_deposit_key).await,
+                self.cache_get(total_deposits_key).await,
+            ) {
+                (
+                    largest
+                        .parse::<u128>()
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                    total
+                        .parse::<u128>()
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                    // Missing from caches written before it
+                    self.cache_get(deposits_hhi_key)
+                        .await
+                        .ok()
+                        .and_then(|hhi| hhi.parse::<f64>().ok()),
+                    self.cache_get(completeness_key)
+                        .await
+                        .ok()
+                        .and_then(|completeness| serde_json::from_str(&completeness).ok()),
+                )
+            } else {
+                info!("Fetching deposits...");
+                let store = CacheBackend::new(&self.redis_client).redis_client();
+                let (deposits, completeness) = self.sources.deposits(&self.reserve, store).await?;
+                if !completeness.is_complete() {
+                    tracing::warn!(
+                        "Deposits scan missing {} of {} obligations",
+                        completeness.missing_accounts,
+                        completeness.accounts
+                    );
+                }
+                let (largest, total, hhi) = summarize_deposits(&deposits)?;
+
+                // Cache deposits data
+                self.cache_set_liquidity_input(largest_deposit_key, &largest.to_string())
+                    .await?;
+                self.cache_set_liquidity_input(total_deposits_key, &total.to_string())
+                    .await?;
+                self.cache_set_liquidity_input(deposits_hhi_key, &hhi.to_string())
+                    .await?;
+                self.cache_set_liquidity_input(
+                    completeness_key,
+                    &serde_json::to_string(&completeness)
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                )
                 .await?;
 
-            (largest, total)
-        };
+                (largest, total, Some(hhi), Some(completeness))
+            };
 
-        // Try to get cached borrows and supply data
-        let total_borrows_key = "utilization:total_borrows";
-        let total_supply_key = "utilization:total_supply";
-
-        let (total_borrows, total_supply) = if let (Ok(borrows), Ok(supply)) = (
-            self.redis_get(total_borrows_key).await,
-            self.redis_get(total_supply_key).await,
-        ) {
-            (
-                borrows
-                    .parse::<f64>()
-                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
-                supply
-                    .parse::<f64>()
+        // Kept for trend analysis, a failed write doesn't fail the pillar
+        if let Err(e) = record_concentration(
+            &self.redis_client,
+            &self.scope(),
+            &ConcentrationSnapshot::of_current_hour(largest_deposit, total_deposits, deposits_hhi),
+        )
+        .await
+        {
+            tracing::error!("Failed to record the deposit concentration: {}", e);
+        }
+
+        // Try to get the cached borrows and supply quorum
+        let quorum_key = "utilization:quorum";
+
+        let data_quorum: QuorumReport = if let Ok(cached) = self.cache_get(quorum_key).await {
+            serde_json::from_str(&cached)
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?
+        } else {
+            info!("Fetching borrows and supply from all sources...");
+            let data_quorum = self
+                .sources
+                .borrows_and_supply_quorum(&self.reserve)
+                .await?;
+
+            // Cache the published figure together with how the sources compared
+            self.cache_set_liquidity_input(
+                quorum_key,
+                &serde_json::to_string(&data_quorum)
                     .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
             )
-        } else {
-            info!("Fetching borrows and supply...");
-            let (borrows, supply) = get_total_borrows_and_supply().await?;
+            .await?;
 
-            // Cache borrows and supply data
-            self.redis_set_until_next_hour(total_borrows_key, &borrows.to_string())
-                .await?;
-            self.redis_set_until_next_hour(total_supply_key, &supply.to_string())
-                .await?;
+            data_quorum
+        };
+        let (total_borrows, total_supply) = (data_quorum.total_borrows, data_quorum.total_supply);
 
-            (borrows, supply)
+        // Borrower metrics are reported alongside the score, a failed scan doesn't fail the pillar
+        let obligation_metrics = self.obligation_metrics().await.unwrap_or_else(|e| {
+            tracing::error!("Failed to compute borrower metrics: {}", e);
+            ObligationMetrics::default()
+        });
+
+        // A failed history fetch only leaves the TVL trend out of the score
+        let tvl_trend = self.tvl_trend().await.unwrap_or_else(|e| {
+            tracing::error!("Failed to compute the TVL trend: {}", e);
+            None
+        });
+
+        // A failed account read only leaves the cap penalty out of the score
+        let caps = match self.reserve_caps().await {
+            Ok(caps) => Some(caps),
+            Err(e) => {
+                tracing::error!("Failed to read the reserve caps: {}", e);
+                None
+            }
         };
 
-        // Calculate final values using cached data
-        let deposit_concentration = (largest_deposit as f64) / (total_deposits as f64);
-        let utilization_rate = calculate_utilization_rate(total_borrows, total_supply).ok_or(
-            RiskCalculationError::CustomError("Total supply is 0".to_string()),
-        )?;
+        // Only read when configured, a failed read falls back to the scanned total
+        let denominator = ConcentrationDenominator::global();
+        let reserve_collateral_supply = match denominator {
+            ConcentrationDenominator::Scanned => None,
+            ConcentrationDenominator::ReserveSupply => self.collateral_supply().await.map_or_else(
+                |e| {
+                    tracing::error!("Failed to read the reserve's collateral supply: {}", e);
+                    None
+                },
+                Some,
+            ),
+        };
+        let (concentration_total, concentration_denominator) =
+            concentration_total(denominator, total_deposits, reserve_collateral_supply);
 
         // Calculate final liquidity risk (not cached)
         info!("Calculating liquidity risk...");
-        let liquidity_risk = calculate_liquidity_risk(
-            deposit_concentration,
-            utilization_rate,
-            Self::W_LIQ_UTIL,
-            Self::W_LIQ_D_CONC,
-        );
+        let metrics = timed_sync(Timing::Compute, || {
+            liquidity_metrics(
+                largest_deposit,
+                concentration_total,
+                total_borrows,
+                total_supply,
+                obligation_metrics.borrower_concentration,
+                tvl_trend,
+                &self.weights().liquidity,
+            )
+            .map(|metrics| match caps {
+                Some(caps) => with_caps(metrics, caps),
+                None => metrics,
+            })
+        })?;
 
+        // Both totals are reported so the scan can be checked against the reserve
         Ok(LiquidityRiskMetrics {
-            total_borrows,
-            total_supply,
-            utilization_rate,
-            largest_deposit,
             total_deposits,
-            deposit_concentration,
-            liquidity_risk,
+            reserve_collateral_supply,
+            concentration_denominator,
+            data_completeness,
+            data_quorum: Some(data_quorum),
+            liquidation_risk: obligation_metrics.liquidation_risk,
+            ..metrics
         })
     }
 
@@ -129,116 +383,119 @@ impl ProtocolRisk for KaminoRisk {
         // Try to get cached yield and utilization data
         let yields_key = "volatility:yields";
         let utilization_rates_key = "volatility:utilization_rates";
+        let history_window_key = "volatility:history_window";
 
-        let (yields_percent, utilization_rates_percent) = if let (Ok(yields), Ok(util_rates)) = (
-            self.redis_get(yields_key).await,
-            self.redis_get(utilization_rates_key).await,
-        ) {
-            (
-                serde_json::from_str(&yields)
-                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
-                serde_json::from_str(&util_rates)
-                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
-            )
-        } else {
-            info!("Fetching yield and utilization rates...");
-            let data = fetch_yield_and_utilization_rates().await?;
+        let (yields_percent, utilization_rates_percent, history_window) =
+            if let (Ok(yields), Ok(util_rates)) = (
+                self.cache_get(yields_key).await,
+                self.cache_get(utilization_rates_key).await,
+            ) {
+                (
+                    serde_json::from_str(&yields)
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                    serde_json::from_str(&util_rates)
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                    // Not cached along the history before it was kept
+                    self.cache_get(history_window_key)
+                        .await
+                        .ok()
+                        .and_then(|window| serde_json::from_str(&window).ok()),
+                )
+            } else {
+                info!("Fetching yield and utilization rates...");
+                let data = self
+                    .sources
+                    .yield_and_utilization_rates(&self.reserve)
+                    .await?;
 
-            // Cache the data
-            self.redis_set_until_next_hour(
-                yields_key,
-                &serde_json::to_string(&data.yields_percent)
-                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
-            )
-            .await?;
-            self.redis_set_until_next_hour(
-                utilization_rates_key,
-                &serde_json::to_string(&data.utilization_rates_percent)
-                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
-            )
-            .await?;
+                // Cache the data
+                self.cache_set_until_next_hour(
+                    yields_key,
+                    &serde_json::to_string(&data.yields_percent)
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                )
+                .await?;
+                self.cache_set_until_next_hour(
+                    utilization_rates_key,
+                    &serde_json::to_string(&data.utilization_rates_percent)
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                )
+                .await?;
+                self.cache_set_until_next_hour(
+                    history_window_key,
+                    &serde_json::to_string(&data.history_window)
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                )
+                .await?;
 
-            (data.yields_percent, data.utilization_rates_percent)
-        };
+                (
+                    data.yields_percent,
+                    data.utilization_rates_percent,
+                    Some(data.history_window),
+                )
+            };
 
         // Calculate volatility risk using cached data (not cached)
         info!("Calculating volatility risk...");
-        let volatility_risk = calculate_lending_pool_risk(
-            yields_percent,
-            utilization_rates_percent,
-            Self::W_VOL_APY,
-            Self::W_VOL_UTIL,
-        )
+        timed_sync(Timing::Compute, || {
+            // The Kamino metrics history is hourly
+            calculate_volatility_surface(
+                &yields_percent,
+                &utilization_rates_percent,
+                1,
+                self.weights().volatility.apy,
+                self.weights().volatility.utilization,
+                &self.weights().volatility_blend,
+            )
+        })
+        .map(|metrics| VolatilityRiskMetrics {
+            history_window,
+            ..metrics
+        })
         .ok_or(RiskCalculationError::CustomError(
             "Insufficient data".to_string(),
-        ))?;
-
-        Ok(VolatilityRiskMetrics {
-            sigma_apy: volatility_risk.sigma_apy,
-            sigma_utilization: volatility_risk.sigma_utilization,
-            volatility_risk: volatility_risk.volatility_risk,
-        })
+        ))
     }
 
     async fn calculate_protocol_risk(&self) -> Result<ProtocolRiskMetrics, RiskCalculationError> {
-        let mut connection = self
-            .redis_client
-            .get_multiplexed_async_connection()
-            .await
-            .map_err(|e| RiskCalculationError::RedisError(e))?;
-
-        let cache_key = "protocol_risk";
-
-        if let Ok(cached_result) = connection.get::<_, String>(cache_key).await {
-            return Ok(ProtocolRiskMetrics {
-                protocol_risk: cached_result
-                    .parse::<f64>()
-                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
-            });
-        }
-
-        // Constant protocol risk for Kamino
-        let protocol_risk = 0.508;
-
-        // Cache the result for 1 hour
-        let _: () = connection
-            .set_ex(
-                cache_key,
-                protocol_risk.to_string(),
-                get_seconds_until_next_hour(),
-            )
-            .await
-            .map_err(|e| RiskCalculationError::RedisError(e))?;
+        ProtocolRubric::global().protocol_risk(&Protocol::Kamino, self.clock().now().date_naive())
+    }
 
-        Ok(ProtocolRiskMetrics { protocol_risk })
+    async fn calculate_oracle_risk(&self) -> Result<OracleRiskMetrics, RiskCalculationError> {
+        fetch_oracle_risk(&[self.sources.price_feed(&self.reserve).await?]).await
     }
 }
 
 #[cfg(test)]
 mod kamino_tests {
     use super::{
-        utilization_rate::get_total_borrows_and_supply,
-        yield_data::fetch_yield_and_utilization_rates,
+        reserve::KaminoReserveConfig,
+        sources::{fixtures::FixtureSources, DepositSource, UtilizationSource, YieldHistorySource},
     };
     use crate::{
-        kamino::deposit_conc::fetch_deposits,
         liquidity_risk::{
-            calculate_concentration, calculate_liquidity_risk, calculate_utilization_rate,
+            calculate_concentration, calculate_liquidity_risk, calculate_tvl_trend,
+            calculate_utilization_rate,
         },
         volatility_risk::calculate_lending_pool_risk,
     };
     #[tokio::test]
     async fn test_liquidity_risk() {
+        let sources = FixtureSources::usdc();
+        let reserve = KaminoReserveConfig::usdc();
         let utilization_weight = 0.6;
         let deposit_concentration_weight = 0.4;
         // Get deposit concentration
-        let deposits = fetch_deposits().await.unwrap();
+        let (deposits, _) = sources.deposits(&reserve, None).await.unwrap();
         let deposit_concentration = calculate_concentration(deposits).unwrap();
         tracing::info!("Deposit Concentration: {:?}", deposit_concentration);
+        assert!(deposit_concentration > 0.0 && deposit_concentration < 100.0);
         // Get utilization rate
-        let (total_borrows, total_supply) = get_total_borrows_and_supply().await.unwrap();
-        let utilization_rate = calculate_utilization_rate(total_borrows, total_supply).unwrap();
+        let quorum = sources.borrows_and_supply_quorum(&reserve).await.unwrap();
+        let utilization_rate =
+            calculate_utilization_rate(quorum.total_borrows, quorum.total_supply).unwrap();
         tracing::info!("Utilization Rate: {:?}", utilization_rate);
+        assert!(utilization_rate > 70.0 && utilization_rate < 90.0);
 
         let liquidity_risk = calculate_liquidity_risk(
             deposit_concentration,
@@ -247,11 +504,21 @@ mod kamino_tests {
             deposit_concentration_weight,
         );
         tracing::info!("Liquidity Risk: {:?}", liquidity_risk);
+        assert!((0.0..=100.0).contains(&liquidity_risk));
+
+        // The fixture holds exactly a week of history
+        let supplies = sources.supply_history(&reserve, 24 * 7).await.unwrap();
+        assert_eq!(supplies.len(), 24 * 7 + 1);
+        let trend = calculate_tvl_trend(&supplies, 1).unwrap();
+        assert!(trend.tvl_change_7d_pct.is_some());
     }
 
     #[tokio::test]
     async fn test_calculate_sigma_apy() {
-        let data = fetch_yield_and_utilization_rates().await.unwrap();
+        let data = FixtureSources::usdc()
+            .yield_and_utilization_rates(&KaminoReserveConfig::usdc())
+            .await
+            .unwrap();
         println!(
             "Yields (APY in %) \nTotal: ({}) \nStart: {:?} \nEnd: {:?} \nValues: {}",
             data.yields_percent.len(),
@@ -266,6 +533,8 @@ mod kamino_tests {
             data.end,
             serde_json::to_string_pretty(&data.utilization_rates_percent).unwrap()
         );
+        assert_eq!(data.yields_percent.len(), 24 * 7 + 1);
+        assert_eq!(data.history_window.filled_gaps, 0);
         let risk = calculate_lending_pool_risk(
             data.yields_percent,
             data.utilization_rates_percent,
@@ -273,5 +542,7 @@ mod kamino_tests {
             0.3,
         );
         println!("Risk: {:?}", risk);
+        let risk = risk.unwrap();
+        assert!(risk.sigma_apy > 0.0 && risk.sigma_utilization > 0.0);
     }
 }
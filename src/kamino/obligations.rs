@@ -0,0 +1,187 @@
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use solana_account_decoder::UiDataSliceConfig;
+use solana_client::{
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
+};
+use std::str::FromStr;
+
+use crate::{
+    cluster::{rpc_url, Cluster},
+    liquidity_risk::AccountHealth,
+    risk_model::RiskCalculationError,
+};
+
+use super::reserve::KaminoReserveConfig;
+
+const OBLIGATION_DISCRIMINATOR: [u8; 8] = [168, 206, 141, 106, 88, 76, 172, 167];
+/// Offset of the obligation's lending market, after the discriminator and tag
+const LENDING_MARKET_OFFSET: usize = 8 + 8 + 16;
+/// Offset of `depositedValueSf`, the slice fetched ends after `unhealthyBorrowValueSf`
+const VALUES_OFFSET: usize = 1192;
+const VALUES_LENGTH: usize = 1080;
+// Offsets of the obligation values within the fetched slice, the borrows are in between
+const DEPOSITED_VALUE_OFFSET: usize = 0;
+const ADJUSTED_DEBT_VALUE_OFFSET: usize = 1016;
+const BORROWED_VALUE_OFFSET: usize = 1032;
+const UNHEALTHY_BORROW_VALUE_OFFSET: usize = 1064;
+const BORROWS_OFFSET: usize = 16;
+const BORROW_LEGS: usize = 5;
+/// Size of an `ObligationLiquidity`
+const BORROW_LEG_SIZE: usize = 200;
+// Offsets within a borrow leg
+const BORROW_RESERVE_OFFSET: usize = 0;
+const BORROWED_AMOUNT_OFFSET: usize = 88;
+/// Kamino scaled fractions have 60 fractional bits
+const SCALED_FRACTION_BITS: i32 = 60;
+
+/// Reads a scaled fraction as a USD value
+fn read_scaled_fraction(data: &[u8], offset: usize) -> f64 {
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&data[offset..offset + 16]);
+    u128::from_le_bytes(bytes) as f64 / 2f64.powi(SCALED_FRACTION_BITS)
+}
+
+/// A borrow of an obligation from one reserve
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ObligationBorrow {
+    pub reserve: Pubkey,
+    /// In the reserve's token, including accrued interest
+    pub borrowed_amount: f64,
+}
+
+/// The values and borrows of an obligation
+#[derive(Debug, Clone, PartialEq)]
+pub struct ObligationSummary {
+    pub health: AccountHealth,
+    pub borrows: Vec<ObligationBorrow>,
+}
+
+impl ObligationSummary {
+    /// What the obligation owes `reserve`
+    pub fn borrowed_from(&self, reserve: &Pubkey) -> f64 {
+        self.borrows
+            .iter()
+            .filter(|borrow| borrow.reserve == *reserve)
+            .map(|borrow| borrow.borrowed_amount)
+            .sum()
+    }
+}
+
+/// Parses the slice of an obligation starting at `depositedValueSf`
+pub fn parse_obligation(data: &[u8]) -> Result<ObligationSummary, RiskCalculationError> {
+    if data.len() < VALUES_LENGTH {
+        return Err(RiskCalculationError::ParseError(format!(
+            "obligation values are {} bytes, expected {}",
+            data.len(),
+            VALUES_LENGTH
+        )));
+    }
+    let borrows = (0..BORROW_LEGS)
+        .map(|leg| BORROWS_OFFSET + leg * BORROW_LEG_SIZE)
+        .filter_map(|leg| {
+            let reserve = Pubkey::try_from(
+                &data[leg + BORROW_RESERVE_OFFSET..leg + BORROW_RESERVE_OFFSET + 32],
+            )
+            .ok()?;
+            let borrowed_amount = read_scaled_fraction(data, leg + BORROWED_AMOUNT_OFFSET);
+            // Unused legs are zeroed
+            (borrowed_amount > 0.0).then_some(ObligationBorrow {
+                reserve,
+                borrowed_amount,
+            })
+        })
+        .collect();
+    Ok(ObligationSummary {
+        health: AccountHealth {
+            deposited_value: read_scaled_fraction(data, DEPOSITED_VALUE_OFFSET),
+            borrowed_value: read_scaled_fraction(data, BORROWED_VALUE_OFFSET),
+            adjusted_debt_value: read_scaled_fraction(data, ADJUSTED_DEBT_VALUE_OFFSET),
+            unhealthy_borrow_value: read_scaled_fraction(data, UNHEALTHY_BORROW_VALUE_OFFSET),
+        },
+        borrows,
+    })
+}
+
+/// Fetches the collateral, debt and borrows of every obligation in the reserve's market
+///
+/// Values are as of the obligations' last refresh, which Kamino does on every
+/// interaction, so idle obligations may be somewhat out of date.
+pub async fn fetch_obligations(
+    reserve: &KaminoReserveConfig,
+) -> Result<Vec<ObligationSummary>, RiskCalculationError> {
+    let rpc_url = rpc_url()?;
+    let program_id = Cluster::global().kamino_program_id();
+    let client = crate::upstream_fixtures::rpc_client(rpc_url);
+
+    let accounts = client
+        .get_program_accounts_with_config(
+            &Pubkey::from_str(program_id)
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+            RpcProgramAccountsConfig {
+                filters: Some(vec![
+                    RpcFilterType::DataSize(3336 + 8),
+                    RpcFilterType::Memcmp(Memcmp::new(
+                        0,
+                        MemcmpEncodedBytes::Bytes(OBLIGATION_DISCRIMINATOR.to_vec()),
+                    )),
+                    RpcFilterType::Memcmp(Memcmp::new(
+                        LENDING_MARKET_OFFSET,
+                        MemcmpEncodedBytes::Bytes(reserve.market.to_bytes().to_vec()),
+                    )),
+                ]),
+                account_config: RpcAccountInfoConfig {
+                    encoding: None,
+                    data_slice: Some(UiDataSliceConfig {
+                        offset: VALUES_OFFSET,
+                        length: VALUES_LENGTH,
+                    }),
+                    commitment: None,
+                    min_context_slot: None,
+                },
+                with_context: None,
+            },
+        )
+        .await
+        .map_err(RiskCalculationError::from)?;
+
+    let mut obligations = Vec::with_capacity(accounts.len());
+    for (pubkey, account) in accounts {
+        match parse_obligation(&account.data) {
+            Ok(obligation) => obligations.push(obligation),
+            Err(e) => tracing::error!("Error while parsing obligation {}: {}", pubkey, e),
+        }
+    }
+    Ok(obligations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_obligation() {
+        let mut data = vec![0u8; VALUES_LENGTH];
+        let mut write = |offset: usize, value: u128| {
+            data[offset..offset + 16]
+                .copy_from_slice(&(value << SCALED_FRACTION_BITS).to_le_bytes())
+        };
+        write(DEPOSITED_VALUE_OFFSET, 1_000);
+        write(ADJUSTED_DEBT_VALUE_OFFSET, 800);
+        write(BORROWED_VALUE_OFFSET, 700);
+        write(UNHEALTHY_BORROW_VALUE_OFFSET, 820);
+        let reserve = Pubkey::new_unique();
+        let second_leg = BORROWS_OFFSET + BORROW_LEG_SIZE;
+        write(second_leg + BORROWED_AMOUNT_OFFSET, 650);
+        data[second_leg..second_leg + 32].copy_from_slice(reserve.as_ref());
+
+        let obligation = parse_obligation(&data).unwrap();
+        assert_eq!(obligation.health.deposited_value, 1_000.0);
+        assert_eq!(obligation.health.borrowed_value, 700.0);
+        assert_eq!(obligation.health.health_factor(), Some(1.025));
+        assert_eq!(obligation.borrows.len(), 1);
+        assert_eq!(obligation.borrowed_from(&reserve), 650.0);
+        assert_eq!(obligation.borrowed_from(&Pubkey::new_unique()), 0.0);
+        assert!(parse_obligation(&data[..100]).is_err());
+    }
+}
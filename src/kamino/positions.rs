@@ -0,0 +1,132 @@
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use solana_account_decoder::{UiAccountData, UiDataSliceConfig};
+use solana_client::{
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
+    rpc_request::TokenAccountsFilter,
+};
+use std::str::FromStr;
+
+use crate::{
+    cluster::{rpc_url, Cluster},
+    risk_model::RiskCalculationError,
+};
+
+use super::{
+    deposit_conc::{
+        DEPOSITED_AMOUNT_OFFSET, DEPOSITS_OFFSET, DEPOSIT_LEGS, DEPOSIT_LEG_SIZE,
+        DEPOSIT_RESERVE_OFFSET, LENDING_MARKET_OFFSET, OBLIGATION_DISCRIMINATOR, OBLIGATION_SIZE,
+        OWNER_OFFSET,
+    },
+    reserve::KaminoReserveConfig,
+    reserve_account::fetch_reserve_account,
+};
+
+/// Amount a wallet has supplied to a Kamino reserve, in native liquidity units
+///
+/// Counts collateral deposited in the wallet's obligations as well as kTokens
+/// (reserve collateral tokens) held directly in the wallet.
+pub async fn fetch_wallet_deposit(
+    wallet: &Pubkey,
+    reserve: &KaminoReserveConfig,
+) -> Result<u64, RiskCalculationError> {
+    let rpc_url = rpc_url()?;
+    let client = crate::upstream_fixtures::rpc_client(rpc_url);
+    let reserve_account = fetch_reserve_account(reserve).await?;
+    let program_id = Pubkey::from_str(Cluster::global().kamino_program_id())
+        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
+
+    let obligations = client
+        .get_program_accounts_with_config(
+            &program_id,
+            RpcProgramAccountsConfig {
+                filters: Some(vec![
+                    RpcFilterType::DataSize(OBLIGATION_SIZE),
+                    RpcFilterType::Memcmp(Memcmp::new(
+                        0,
+                        MemcmpEncodedBytes::Bytes(OBLIGATION_DISCRIMINATOR.to_vec()),
+                    )),
+                    RpcFilterType::Memcmp(Memcmp::new(
+                        LENDING_MARKET_OFFSET,
+                        MemcmpEncodedBytes::Bytes(reserve.market.to_bytes().to_vec()),
+                    )),
+                    RpcFilterType::Memcmp(Memcmp::new(
+                        OWNER_OFFSET,
+                        MemcmpEncodedBytes::Bytes(wallet.to_bytes().to_vec()),
+                    )),
+                ]),
+                account_config: RpcAccountInfoConfig {
+                    encoding: None,
+                    data_slice: Some(UiDataSliceConfig {
+                        offset: DEPOSITS_OFFSET,
+                        length: DEPOSIT_LEG_SIZE * DEPOSIT_LEGS,
+                    }),
+                    commitment: None,
+                    min_context_slot: None,
+                },
+                with_context: None,
+            },
+        )
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let obligation_collateral: u64 = obligations
+        .iter()
+        .map(|(_, account)| reserve_collateral(&account.data, &reserve.reserve))
+        .sum();
+
+    let token_accounts = client
+        .get_token_accounts_by_owner(
+            wallet,
+            TokenAccountsFilter::Mint(reserve_account.collateral_mint),
+        )
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let wallet_collateral: u64 = token_accounts
+        .iter()
+        .filter_map(|keyed_account| match &keyed_account.account.data {
+            UiAccountData::Json(parsed) => parsed.parsed["info"]["tokenAmount"]["amount"]
+                .as_str()
+                .and_then(|amount| amount.parse::<u64>().ok()),
+            _ => None,
+        })
+        .sum();
+
+    Ok(reserve_account.collateral_to_liquidity(obligation_collateral + wallet_collateral))
+}
+
+/// Collateral an obligation holds in `reserve`, given its raw deposits array
+fn reserve_collateral(deposits: &[u8], reserve: &Pubkey) -> u64 {
+    deposits
+        .chunks_exact(DEPOSIT_LEG_SIZE)
+        .filter(|collateral| {
+            &collateral[DEPOSIT_RESERVE_OFFSET..DEPOSIT_RESERVE_OFFSET + 32] == reserve.as_ref()
+        })
+        .map(|collateral| {
+            let mut amount = [0u8; 8];
+            amount
+                .copy_from_slice(&collateral[DEPOSITED_AMOUNT_OFFSET..DEPOSITED_AMOUNT_OFFSET + 8]);
+            u64::from_le_bytes(amount)
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_collateral() {
+        let reserve = Pubkey::new_unique();
+        let mut deposits = vec![0u8; DEPOSIT_LEG_SIZE * DEPOSIT_LEGS];
+        // A later leg of the reserve is read at its own offset
+        for (i, (pk, amount)) in [(reserve, 40u64), (Pubkey::new_unique(), 7), (reserve, 2)]
+            .iter()
+            .enumerate()
+        {
+            let collateral = &mut deposits[i * DEPOSIT_LEG_SIZE..(i + 1) * DEPOSIT_LEG_SIZE];
+            collateral[..32].copy_from_slice(pk.as_ref());
+            collateral[32..40].copy_from_slice(&amount.to_le_bytes());
+        }
+        assert_eq!(reserve_collateral(&deposits, &reserve), 42);
+    }
+}
@@ -0,0 +1,109 @@
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+use crate::{cluster::Cluster, risk_model::RiskCalculationError};
+
+/// Kamino main lending market
+pub const KAMINO_MAIN_MARKET: &str = "H6rHXmXoCQvq8Ue81MqNh7ow5ysPa1dSozwW3PU1dDH6";
+/// USDC reserve of the Kamino main market
+pub const KAMINO_USDC_RESERVE: &str = "6gTJfuPHEg6uRAijRkMqNc9kan4sVZejKMxmvx2grT1p";
+
+/// Identifies the Kamino lending market and reserve risk is computed for
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KaminoReserveConfig {
+    pub market: Pubkey,
+    pub reserve: Pubkey,
+}
+
+impl KaminoReserveConfig {
+    pub fn new(market: &str, reserve: &str) -> Result<Self, RiskCalculationError> {
+        Ok(KaminoReserveConfig {
+            market: Pubkey::from_str(market)
+                .map_err(|e| RiskCalculationError::InvalidParameter(format!("market: {}", e)))?,
+            reserve: Pubkey::from_str(reserve)
+                .map_err(|e| RiskCalculationError::InvalidParameter(format!("reserve: {}", e)))?,
+        })
+    }
+
+    /// The USDC reserve of the main market
+    pub fn usdc() -> Self {
+        Self::new(KAMINO_MAIN_MARKET, KAMINO_USDC_RESERVE).expect("valid USDC reserve")
+    }
+
+    /// Reads `KAMINO_MARKET` and `KAMINO_RESERVE`
+    ///
+    /// Mainnet defaults to the USDC reserve, other clusters have no default
+    /// and need both.
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        match (
+            std::env::var("KAMINO_MARKET"),
+            std::env::var("KAMINO_RESERVE"),
+        ) {
+            (Ok(market), Ok(reserve)) => Self::new(&market, &reserve),
+            _ if Cluster::global() == Cluster::MainnetBeta => Ok(Self::usdc()),
+            _ => Err(RiskCalculationError::ParseError(format!(
+                "KAMINO_MARKET and KAMINO_RESERVE must be set on {}",
+                Cluster::global().as_str()
+            ))),
+        }
+    }
+
+    /// Reserve selected by optional request parameters, falling back to `default`
+    pub fn from_params(
+        market: Option<&str>,
+        reserve: Option<&str>,
+        default: &Self,
+    ) -> Result<Self, RiskCalculationError> {
+        match (market, reserve) {
+            (Some(market), Some(reserve)) => Self::new(market, reserve),
+            (None, None) => Ok(default.clone()),
+            _ => Err(RiskCalculationError::ParseError(
+                "market and reserve must be given together".to_string(),
+            )),
+        }
+    }
+
+    /// Cache namespace of this reserve, so data of different reserves never mixes
+    pub fn cache_namespace(&self) -> String {
+        format!("kamino:{}", self.reserve)
+    }
+
+    /// Kamino API url of the hourly metrics history of this reserve between `start` and `end`
+    pub fn metrics_history_url(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> String {
+        format!(
+            "https://api.kamino.finance/kamino-market/{}/reserves/{}/metrics/history?env={}&start={}&end={}&frequency=hour",
+            self.market,
+            self.reserve,
+            Cluster::global().as_str(),
+            start.to_rfc3339_opts(SecondsFormat::Secs, true),
+            end.to_rfc3339_opts(SecondsFormat::Secs, true)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_config() {
+        let usdc = KaminoReserveConfig::usdc();
+        assert_eq!(
+            usdc.cache_namespace(),
+            format!("kamino:{}", KAMINO_USDC_RESERVE)
+        );
+        assert!(KaminoReserveConfig::new(KAMINO_MAIN_MARKET, "not-a-pubkey").is_err());
+        assert!(KaminoReserveConfig::from_params(Some(KAMINO_MAIN_MARKET), None, &usdc).is_err());
+        assert_eq!(
+            KaminoReserveConfig::from_params(None, None, &usdc).unwrap(),
+            usdc
+        );
+        // The hour is kept, not only the day
+        let start: DateTime<Utc> = "2024-05-01T13:00:00Z".parse().unwrap();
+        assert!(usdc
+            .metrics_history_url(start, start + chrono::Duration::hours(24))
+            .contains("&start=2024-05-01T13:00:00Z&end=2024-05-02T13:00:00Z&"));
+    }
+}
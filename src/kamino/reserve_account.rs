@@ -0,0 +1,235 @@
+use anchor_client::solana_sdk::pubkey::Pubkey;
+
+use crate::{cluster::rpc_url, liquidity_risk::ReserveCaps, risk_model::RiskCalculationError};
+
+use super::reserve::KaminoReserveConfig;
+
+/// Size of the klend `Reserve` account including the discriminator
+pub const RESERVE_ACCOUNT_SIZE: usize = 8624;
+
+// Byte offsets into the reserve account data (including the 8 byte discriminator), see `klend.json`
+const LENDING_MARKET_OFFSET: usize = 32;
+const LIQUIDITY_MINT_OFFSET: usize = 128;
+const LIQUIDITY_SUPPLY_VAULT_OFFSET: usize = 160;
+const AVAILABLE_AMOUNT_OFFSET: usize = 224;
+const BORROWED_AMOUNT_SF_OFFSET: usize = 232;
+const MINT_DECIMALS_OFFSET: usize = 272;
+const ACCUMULATED_PROTOCOL_FEES_SF_OFFSET: usize = 344;
+const ACCUMULATED_REFERRER_FEES_SF_OFFSET: usize = 360;
+const PENDING_REFERRER_FEES_SF_OFFSET: usize = 376;
+const LIQUIDITY_TOKEN_PROGRAM_OFFSET: usize = 408;
+const COLLATERAL_MINT_OFFSET: usize = 2560;
+const COLLATERAL_MINT_TOTAL_SUPPLY_OFFSET: usize = 2592;
+const DEPOSIT_LIMIT_OFFSET: usize = 5016;
+const BORROW_LIMIT_OFFSET: usize = 5024;
+const SCOPE_PRICE_FEED_OFFSET: usize = 5112;
+const SWITCHBOARD_PRICE_OFFSET: usize = 5160;
+const SWITCHBOARD_TWAP_OFFSET: usize = 5192;
+const PYTH_PRICE_OFFSET: usize = 5224;
+
+/// klend stores fractions as `u128` with 60 fractional bits
+const SCALED_FRACTION_BITS: i32 = 60;
+
+/// The subset of the klend `Reserve` account used by the risk model
+///
+/// All amounts are in native units of the liquidity (or collateral) mint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReserveAccount {
+    pub lending_market: Pubkey,
+    pub liquidity_mint: Pubkey,
+    pub collateral_mint: Pubkey,
+    pub mint_decimals: u64,
+    pub available_amount: u64,
+    pub borrowed_amount: f64,
+    pub accumulated_fees: f64,
+    pub collateral_mint_total_supply: u64,
+    pub deposit_limit: u64,
+    pub borrow_limit: u64,
+    /// Token account holding the reserve's liquidity
+    pub liquidity_supply_vault: Pubkey,
+    /// Token program of the liquidity mint
+    pub liquidity_token_program: Pubkey,
+    pub oracles: ReserveOracles,
+}
+
+/// Price accounts `refresh_reserve` reads, the default pubkey when not configured
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReserveOracles {
+    pub pyth: Pubkey,
+    pub switchboard_price: Pubkey,
+    pub switchboard_twap: Pubkey,
+    pub scope_prices: Pubkey,
+}
+
+impl ReserveAccount {
+    pub fn from_account_data(data: &[u8]) -> Result<Self, RiskCalculationError> {
+        if data.len() != RESERVE_ACCOUNT_SIZE {
+            return Err(RiskCalculationError::ParseError(format!(
+                "Reserve account has {} bytes, expected {}",
+                data.len(),
+                RESERVE_ACCOUNT_SIZE
+            )));
+        }
+        Ok(ReserveAccount {
+            lending_market: read_pubkey(data, LENDING_MARKET_OFFSET),
+            liquidity_mint: read_pubkey(data, LIQUIDITY_MINT_OFFSET),
+            collateral_mint: read_pubkey(data, COLLATERAL_MINT_OFFSET),
+            mint_decimals: read_u64(data, MINT_DECIMALS_OFFSET),
+            available_amount: read_u64(data, AVAILABLE_AMOUNT_OFFSET),
+            borrowed_amount: read_scaled_fraction(data, BORROWED_AMOUNT_SF_OFFSET),
+            accumulated_fees: read_scaled_fraction(data, ACCUMULATED_PROTOCOL_FEES_SF_OFFSET)
+                + read_scaled_fraction(data, ACCUMULATED_REFERRER_FEES_SF_OFFSET)
+                + read_scaled_fraction(data, PENDING_REFERRER_FEES_SF_OFFSET),
+            collateral_mint_total_supply: read_u64(data, COLLATERAL_MINT_TOTAL_SUPPLY_OFFSET),
+            deposit_limit: read_u64(data, DEPOSIT_LIMIT_OFFSET),
+            borrow_limit: read_u64(data, BORROW_LIMIT_OFFSET),
+            liquidity_supply_vault: read_pubkey(data, LIQUIDITY_SUPPLY_VAULT_OFFSET),
+            liquidity_token_program: read_pubkey(data, LIQUIDITY_TOKEN_PROGRAM_OFFSET),
+            oracles: ReserveOracles {
+                pyth: read_pubkey(data, PYTH_PRICE_OFFSET),
+                switchboard_price: read_pubkey(data, SWITCHBOARD_PRICE_OFFSET),
+                switchboard_twap: read_pubkey(data, SWITCHBOARD_TWAP_OFFSET),
+                scope_prices: read_pubkey(data, SCOPE_PRICE_FEED_OFFSET),
+            },
+        })
+    }
+
+    /// Total liquidity supplied to the reserve, net of fees owed to the protocol
+    pub fn total_supply(&self) -> f64 {
+        (self.available_amount as f64 + self.borrowed_amount - self.accumulated_fees).max(0.0)
+    }
+
+    /// Liquidity tokens one collateral (kToken) unit is worth
+    pub fn collateral_exchange_rate(&self) -> f64 {
+        if self.collateral_mint_total_supply == 0 {
+            return 1.0;
+        }
+        self.total_supply() / self.collateral_mint_total_supply as f64
+    }
+
+    /// Deposit and borrow limits and the liquidity not lent out, in token units
+    ///
+    /// A limit of 0 disables deposits or borrows rather than capping them, and
+    /// `u64::MAX` leaves them uncapped, neither is a cap.
+    pub fn caps(&self) -> ReserveCaps {
+        let scale = 10f64.powi(self.mint_decimals as i32);
+        let cap = |limit: u64| (limit != 0 && limit != u64::MAX).then(|| limit as f64 / scale);
+        ReserveCaps {
+            available_liquidity: self.available_amount as f64 / scale,
+            supply_cap: cap(self.deposit_limit),
+            borrow_cap: cap(self.borrow_limit),
+        }
+    }
+
+    /// Pyth price account of the liquidity, `None` when priced by another oracle
+    pub fn pyth_price_account(&self) -> Option<Pubkey> {
+        (self.oracles.pyth != Pubkey::default()).then_some(self.oracles.pyth)
+    }
+
+    /// Converts native collateral units into native liquidity units
+    pub fn collateral_to_liquidity(&self, collateral_amount: u64) -> u64 {
+        (collateral_amount as f64 * self.collateral_exchange_rate()) as u64
+    }
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn read_scaled_fraction(data: &[u8], offset: usize) -> f64 {
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&data[offset..offset + 16]);
+    u128::from_le_bytes(bytes) as f64 / 2f64.powi(SCALED_FRACTION_BITS)
+}
+
+fn read_pubkey(data: &[u8], offset: usize) -> Pubkey {
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&data[offset..offset + 32]);
+    Pubkey::new_from_array(bytes)
+}
+
+#[cfg(test)]
+impl ReserveAccount {
+    /// A reserve of `config`'s market with nothing supplied, its mints and
+    /// vault are unique pubkeys and no oracle is configured
+    pub fn empty(config: &KaminoReserveConfig) -> Self {
+        ReserveAccount {
+            lending_market: config.market,
+            liquidity_mint: Pubkey::new_unique(),
+            collateral_mint: Pubkey::new_unique(),
+            mint_decimals: 6,
+            available_amount: 0,
+            borrowed_amount: 0.0,
+            accumulated_fees: 0.0,
+            collateral_mint_total_supply: 0,
+            deposit_limit: 0,
+            borrow_limit: 0,
+            liquidity_supply_vault: Pubkey::new_unique(),
+            liquidity_token_program: crate::tx_builder::token_program(),
+            oracles: Default::default(),
+        }
+    }
+}
+
+pub async fn fetch_reserve_account(
+    reserve: &KaminoReserveConfig,
+) -> Result<ReserveAccount, RiskCalculationError> {
+    let rpc_url = rpc_url()?;
+    let client = crate::upstream_fixtures::rpc_client(rpc_url);
+    let data = client
+        .get_account_data(&reserve.reserve)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let account = ReserveAccount::from_account_data(&data)?;
+    if account.lending_market != reserve.market {
+        return Err(RiskCalculationError::CustomError(format!(
+            "Reserve {} does not belong to market {}",
+            reserve.reserve, reserve.market
+        )));
+    }
+    Ok(account)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reserve_account() {
+        let market = Pubkey::new_unique();
+        let mut data = vec![0u8; RESERVE_ACCOUNT_SIZE];
+        data[LENDING_MARKET_OFFSET..LENDING_MARKET_OFFSET + 32].copy_from_slice(market.as_ref());
+        data[MINT_DECIMALS_OFFSET..MINT_DECIMALS_OFFSET + 8].copy_from_slice(&6u64.to_le_bytes());
+        data[AVAILABLE_AMOUNT_OFFSET..AVAILABLE_AMOUNT_OFFSET + 8]
+            .copy_from_slice(&700u64.to_le_bytes());
+        data[BORROWED_AMOUNT_SF_OFFSET..BORROWED_AMOUNT_SF_OFFSET + 16]
+            .copy_from_slice(&(400u128 << 60).to_le_bytes());
+        data[ACCUMULATED_PROTOCOL_FEES_SF_OFFSET..ACCUMULATED_PROTOCOL_FEES_SF_OFFSET + 16]
+            .copy_from_slice(&(100u128 << 60).to_le_bytes());
+        data[COLLATERAL_MINT_TOTAL_SUPPLY_OFFSET..COLLATERAL_MINT_TOTAL_SUPPLY_OFFSET + 8]
+            .copy_from_slice(&800u64.to_le_bytes());
+        data[DEPOSIT_LIMIT_OFFSET..DEPOSIT_LIMIT_OFFSET + 8]
+            .copy_from_slice(&2_000_000u64.to_le_bytes());
+        data[BORROW_LIMIT_OFFSET..BORROW_LIMIT_OFFSET + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let scope = Pubkey::new_unique();
+        data[SCOPE_PRICE_FEED_OFFSET..SCOPE_PRICE_FEED_OFFSET + 32].copy_from_slice(scope.as_ref());
+
+        let reserve = ReserveAccount::from_account_data(&data).unwrap();
+        assert_eq!(reserve.lending_market, market);
+        assert_eq!(reserve.oracles.scope_prices, scope);
+        assert_eq!(reserve.oracles.pyth, Pubkey::default());
+        assert_eq!(reserve.pyth_price_account(), None);
+        assert_eq!(reserve.mint_decimals, 6);
+        assert_eq!(reserve.total_supply(), 1000.0);
+        assert_eq!(reserve.collateral_exchange_rate(), 1.25);
+        assert_eq!(reserve.collateral_to_liquidity(80), 100);
+        let caps = reserve.caps();
+        assert_eq!(caps.available_liquidity, 0.0007);
+        assert_eq!(caps.supply_cap, Some(2.0));
+        assert_eq!(caps.borrow_cap, None);
+        assert!(ReserveAccount::from_account_data(&data[1..]).is_err());
+    }
+}
@@ -0,0 +1,341 @@
+use std::future::Future;
+
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{
+    liquidity_risk::ReserveCaps,
+    oracle_risk::OracleFeed,
+    quorum::QuorumReport,
+    risk_model::RiskCalculationError,
+    rpc_pool::DataCompleteness,
+    timings::{timed, Timing},
+    upstream::{self, Upstream},
+};
+
+use super::{
+    deposit_conc::{fetch_deposits, fetch_deposits_by_obligation},
+    reserve::KaminoReserveConfig,
+    reserve_account::fetch_reserve_account,
+    utilization_rate::get_total_borrows_and_supply_quorum,
+    yield_data::{fetch_supply_history, fetch_yield_and_utilization_rates, YieldData},
+};
+
+/// Where the deposits of a reserve are read from
+pub trait DepositSource: Send + Sync {
+    /// Deposits every obligation holds in `reserve` and how much of the scan was fetched
+    ///
+    /// `store` keeps the scanned obligations between scans, see
+    /// [`fetch_deposits_by_obligation`].
+    fn deposits_by_obligation(
+        &self,
+        reserve: &KaminoReserveConfig,
+        store: Option<&redis::Client>,
+    ) -> impl Future<Output = Result<(Vec<(Pubkey, u128)>, DataCompleteness), RiskCalculationError>> + Send;
+    /// Like [`DepositSource::deposits_by_obligation`], without the obligations
+    fn deposits(
+        &self,
+        reserve: &KaminoReserveConfig,
+        store: Option<&redis::Client>,
+    ) -> impl Future<Output = Result<(Vec<u128>, DataCompleteness), RiskCalculationError>> + Send
+    {
+        async move {
+            let (deposits, completeness) = self.deposits_by_obligation(reserve, store).await?;
+            Ok((
+                deposits.into_iter().map(|(_, deposit)| deposit).collect(),
+                completeness,
+            ))
+        }
+    }
+    /// Collateral minted by `reserve`, what the deposits of every obligation sum to
+    fn collateral_supply(
+        &self,
+        reserve: &KaminoReserveConfig,
+    ) -> impl Future<Output = Result<u128, RiskCalculationError>> + Send;
+}
+
+/// Where the hourly metrics history of a reserve is read from
+pub trait YieldHistorySource: Send + Sync {
+    /// Yields and utilization rates over the longest volatility lookback
+    fn yield_and_utilization_rates(
+        &self,
+        reserve: &KaminoReserveConfig,
+    ) -> impl Future<Output = Result<YieldData, RiskCalculationError>> + Send;
+    /// Total supply over the last `hours`, oldest first
+    fn supply_history(
+        &self,
+        reserve: &KaminoReserveConfig,
+        hours: u64,
+    ) -> impl Future<Output = Result<Vec<f64>, RiskCalculationError>> + Send;
+}
+
+/// Where the current borrows and supply of a reserve are read from
+pub trait UtilizationSource: Send + Sync {
+    /// Borrows and supply as the sources agree on them, see [`crate::quorum`]
+    fn borrows_and_supply_quorum(
+        &self,
+        reserve: &KaminoReserveConfig,
+    ) -> impl Future<Output = Result<QuorumReport, RiskCalculationError>> + Send;
+    /// Supply and borrow caps and the liquidity not lent out
+    fn reserve_caps(
+        &self,
+        reserve: &KaminoReserveConfig,
+    ) -> impl Future<Output = Result<ReserveCaps, RiskCalculationError>> + Send;
+    /// Pyth feed of the reserve's liquidity, see [`OracleFeed::of_mint`]
+    fn price_feed(
+        &self,
+        reserve: &KaminoReserveConfig,
+    ) -> impl Future<Output = Result<OracleFeed, RiskCalculationError>> + Send;
+}
+
+/// The chain, the Kamino API and DefiLlama
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LiveSources;
+
+impl DepositSource for LiveSources {
+    async fn deposits_by_obligation(
+        &self,
+        reserve: &KaminoReserveConfig,
+        store: Option<&redis::Client>,
+    ) -> Result<(Vec<(Pubkey, u128)>, DataCompleteness), RiskCalculationError> {
+        timed(
+            Timing::Rpc,
+            upstream::call(Upstream::Rpc, fetch_deposits_by_obligation(reserve, store)),
+        )
+        .await
+    }
+
+    async fn deposits(
+        &self,
+        reserve: &KaminoReserveConfig,
+        store: Option<&redis::Client>,
+    ) -> Result<(Vec<u128>, DataCompleteness), RiskCalculationError> {
+        timed(
+            Timing::Rpc,
+            upstream::call(Upstream::Rpc, fetch_deposits(reserve, store)),
+        )
+        .await
+    }
+
+    async fn collateral_supply(
+        &self,
+        reserve: &KaminoReserveConfig,
+    ) -> Result<u128, RiskCalculationError> {
+        let account = timed(
+            Timing::Rpc,
+            upstream::call(Upstream::Rpc, fetch_reserve_account(reserve)),
+        )
+        .await?;
+        Ok(account.collateral_mint_total_supply as u128)
+    }
+}
+
+impl YieldHistorySource for LiveSources {
+    async fn yield_and_utilization_rates(
+        &self,
+        reserve: &KaminoReserveConfig,
+    ) -> Result<YieldData, RiskCalculationError> {
+        timed(
+            Timing::ExternalApi,
+            upstream::call(
+                Upstream::KaminoApi,
+                fetch_yield_and_utilization_rates(reserve),
+            ),
+        )
+        .await
+    }
+
+    async fn supply_history(
+        &self,
+        reserve: &KaminoReserveConfig,
+        hours: u64,
+    ) -> Result<Vec<f64>, RiskCalculationError> {
+        timed(
+            Timing::ExternalApi,
+            upstream::call(Upstream::KaminoApi, fetch_supply_history(reserve, hours)),
+        )
+        .await
+    }
+}
+
+impl UtilizationSource for LiveSources {
+    async fn borrows_and_supply_quorum(
+        &self,
+        reserve: &KaminoReserveConfig,
+    ) -> Result<QuorumReport, RiskCalculationError> {
+        get_total_borrows_and_supply_quorum(reserve).await
+    }
+
+    async fn reserve_caps(
+        &self,
+        reserve: &KaminoReserveConfig,
+    ) -> Result<ReserveCaps, RiskCalculationError> {
+        let account = timed(
+            Timing::Rpc,
+            upstream::call(Upstream::Rpc, fetch_reserve_account(reserve)),
+        )
+        .await?;
+        Ok(account.caps())
+    }
+
+    async fn price_feed(
+        &self,
+        reserve: &KaminoReserveConfig,
+    ) -> Result<OracleFeed, RiskCalculationError> {
+        let account = timed(
+            Timing::Rpc,
+            upstream::call(Upstream::Rpc, fetch_reserve_account(reserve)),
+        )
+        .await?;
+        OracleFeed::of_mint(&account.liquidity_mint, account.pyth_price_account())
+    }
+}
+
+/// Sources replaying the recorded USDC reserve in `fixtures/`
+#[cfg(test)]
+pub mod fixtures {
+    use std::str::FromStr;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::{
+        assets::Asset,
+        kamino::{
+            utilization_rate::latest_borrows_and_supply,
+            yield_data::{supply_history, yield_data, MetricsResponse},
+        },
+        quorum::{resolve, DataSource, SourceReading, QUORUM_SIZE, QUORUM_TOLERANCE},
+    };
+
+    /// A week of the hourly metrics history, in the format of the Kamino API
+    const METRICS_HISTORY: &str = include_str!("fixtures/usdc_metrics_history.json");
+    /// Deposits of the largest obligations
+    const DEPOSITS: &str = include_str!("fixtures/usdc_deposits.json");
+
+    #[derive(Deserialize)]
+    struct FixtureDeposit {
+        obligation: String,
+        amount: u128,
+    }
+
+    #[derive(Debug)]
+    pub struct FixtureSources {
+        pub deposits: Vec<(Pubkey, u128)>,
+        pub metrics: MetricsResponse,
+        /// Caps the supply and borrows of the history are under
+        pub supply_cap: Option<f64>,
+        pub borrow_cap: Option<f64>,
+        /// Collateral minted by the reserve, more than the deposits of the
+        /// largest obligations sum to
+        pub collateral_supply: u128,
+    }
+
+    impl FixtureSources {
+        pub fn usdc() -> Self {
+            let deposits: Vec<FixtureDeposit> =
+                serde_json::from_str(DEPOSITS).expect("deposits fixture must be valid");
+            FixtureSources {
+                deposits: deposits
+                    .into_iter()
+                    .map(|deposit| {
+                        let obligation = Pubkey::from_str(&deposit.obligation)
+                            .expect("fixture obligations must be pubkeys");
+                        (obligation, deposit.amount)
+                    })
+                    .collect(),
+                metrics: serde_json::from_str(METRICS_HISTORY)
+                    .expect("metrics history fixture must be valid"),
+                supply_cap: Some(300_000_000.0),
+                borrow_cap: Some(250_000_000.0),
+                collateral_supply: 160_000_000_000_000,
+            }
+        }
+    }
+
+    impl DepositSource for FixtureSources {
+        async fn deposits_by_obligation(
+            &self,
+            _reserve: &KaminoReserveConfig,
+            _store: Option<&redis::Client>,
+        ) -> Result<(Vec<(Pubkey, u128)>, DataCompleteness), RiskCalculationError> {
+            Ok((self.deposits.clone(), DataCompleteness::default()))
+        }
+
+        async fn collateral_supply(
+            &self,
+            _reserve: &KaminoReserveConfig,
+        ) -> Result<u128, RiskCalculationError> {
+            Ok(self.collateral_supply)
+        }
+    }
+
+    impl YieldHistorySource for FixtureSources {
+        async fn yield_and_utilization_rates(
+            &self,
+            _reserve: &KaminoReserveConfig,
+        ) -> Result<YieldData, RiskCalculationError> {
+            let timestamps = self
+                .metrics
+                .history
+                .iter()
+                .map(|entry| entry.timestamp())
+                .collect::<Result<Vec<_>, _>>()?;
+            let (Some(start), Some(end)) = (timestamps.first(), timestamps.last()) else {
+                return Err(RiskCalculationError::UpstreamUnavailable(
+                    "No yield data available".to_string(),
+                ));
+            };
+            yield_data(&self.metrics, *start, *end)
+        }
+
+        async fn supply_history(
+            &self,
+            _reserve: &KaminoReserveConfig,
+            hours: u64,
+        ) -> Result<Vec<f64>, RiskCalculationError> {
+            let supplies = supply_history(&self.metrics)?;
+            let skip = supplies.len().saturating_sub(hours as usize + 1);
+            Ok(supplies[skip..].to_vec())
+        }
+    }
+
+    impl UtilizationSource for FixtureSources {
+        async fn borrows_and_supply_quorum(
+            &self,
+            _reserve: &KaminoReserveConfig,
+        ) -> Result<QuorumReport, RiskCalculationError> {
+            let (total_borrows, total_supply) = latest_borrows_and_supply(&self.metrics)?;
+            let reading = SourceReading {
+                total_borrows,
+                total_supply,
+            };
+            resolve(
+                vec![
+                    (DataSource::OnChain, Ok(reading)),
+                    (DataSource::ProtocolApi, Ok(reading)),
+                ],
+                QUORUM_TOLERANCE,
+                QUORUM_SIZE,
+            )
+        }
+
+        async fn reserve_caps(
+            &self,
+            _reserve: &KaminoReserveConfig,
+        ) -> Result<ReserveCaps, RiskCalculationError> {
+            let (total_borrows, total_supply) = latest_borrows_and_supply(&self.metrics)?;
+            Ok(ReserveCaps {
+                available_liquidity: total_supply - total_borrows,
+                supply_cap: self.supply_cap,
+                borrow_cap: self.borrow_cap,
+            })
+        }
+
+        async fn price_feed(
+            &self,
+            _reserve: &KaminoReserveConfig,
+        ) -> Result<OracleFeed, RiskCalculationError> {
+            OracleFeed::of_mint(&Asset::Usdc.mint(), None)
+        }
+    }
+}
@@ -1,11 +1,26 @@
-use chrono::{Timelike, Utc};
+use chrono::Timelike;
 
-use crate::risk_model::RiskCalculationError;
+use crate::{
+    clock::process_clock,
+    defillama::get_stablecoin_borrows_and_supply,
+    quorum::{resolve, DataSource, QuorumReport, SourceReading, QUORUM_SIZE, QUORUM_TOLERANCE},
+    risk_model::RiskCalculationError,
+    timings::{timed, Timing},
+    upstream::{self, Upstream},
+    upstream_fixtures::get_text,
+};
 
-use super::yield_data::{Metrics, MetricsResponse};
+use super::{
+    reserve::KaminoReserveConfig,
+    reserve_account::fetch_reserve_account,
+    yield_data::{Metrics, MetricsResponse},
+};
 
-pub async fn get_total_borrows_and_supply() -> Result<(f64, f64), RiskCalculationError> {
-    let nearest_hour = Utc::now()
+pub async fn get_total_borrows_and_supply(
+    reserve: &KaminoReserveConfig,
+) -> Result<(f64, f64), RiskCalculationError> {
+    let nearest_hour = process_clock()
+        .now()
         .with_minute(0)
         .unwrap()
         .with_second(0)
@@ -13,22 +28,18 @@ pub async fn get_total_borrows_and_supply() -> Result<(f64, f64), RiskCalculatio
         .with_nanosecond(0)
         .unwrap();
     let start = nearest_hour - chrono::Duration::hours(24);
-    let url = format!(
-        "https://api.kamino.finance/kamino-market/H6rHXmXoCQvq8Ue81MqNh7ow5ysPa1dSozwW3PU1dDH6/reserves/6gTJfuPHEg6uRAijRkMqNc9kan4sVZejKMxmvx2grT1p/metrics/history?env=mainnet-beta&start={}Z&end={}Z&frequency=hour",
-        start.format("%Y-%m-%d"),
-        nearest_hour.format("%Y-%m-%d")
-    );
+    let url = reserve.metrics_history_url(start, nearest_hour);
 
-    let response = reqwest::get(&url)
-        .await
-        .map_err(|e| RiskCalculationError::RequestError(e))?;
-    let raw_data = response
-        .text()
-        .await
-        .map_err(|e| RiskCalculationError::RequestError(e))?;
+    let raw_data = get_text(&url).await?;
     let metrics_data: MetricsResponse =
-        serde_json::from_str(&raw_data).map_err(|e| RiskCalculationError::SerdeError(e))?;
+        serde_json::from_str(&raw_data).map_err(RiskCalculationError::SerdeError)?;
+    latest_borrows_and_supply(&metrics_data)
+}
 
+/// Borrows and supply of the last entry of `metrics_data`
+pub fn latest_borrows_and_supply(
+    metrics_data: &MetricsResponse,
+) -> Result<(f64, f64), RiskCalculationError> {
     // Get the latest utilization rat
     let Metrics {
         ref total_borrows,
@@ -38,8 +49,8 @@ pub async fn get_total_borrows_and_supply() -> Result<(f64, f64), RiskCalculatio
         .history
         .iter()
         .last()
-        .ok_or(RiskCalculationError::CustomError(
-            "No history data available".to_string(),
+        .ok_or(RiskCalculationError::StaleData(
+            "Kamino API has no metrics from the last 24 hours".to_string(),
         ))?
         .metrics;
     let total_borrows = total_borrows
@@ -50,3 +61,51 @@ pub async fn get_total_borrows_and_supply() -> Result<(f64, f64), RiskCalculatio
         .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
     Ok((total_borrows, total_supply))
 }
+
+/// Reads borrows and supply on-chain, from the Kamino API and, for the main USDC
+/// reserve, from DefiLlama, and publishes the figure the sources agree on
+pub async fn get_total_borrows_and_supply_quorum(
+    reserve: &KaminoReserveConfig,
+) -> Result<QuorumReport, RiskCalculationError> {
+    let (on_chain, api) = futures::join!(
+        timed(
+            Timing::Rpc,
+            upstream::call(Upstream::Rpc, fetch_reserve_account(reserve))
+        ),
+        timed(
+            Timing::ExternalApi,
+            upstream::call(Upstream::KaminoApi, get_total_borrows_and_supply(reserve))
+        ),
+    );
+    let on_chain = on_chain.map(|account| {
+        let scale = 10f64.powi(account.mint_decimals as i32);
+        SourceReading {
+            total_borrows: account.borrowed_amount / scale,
+            total_supply: account.total_supply() / scale,
+        }
+    });
+    let mut readings = vec![
+        (DataSource::OnChain, on_chain),
+        (DataSource::ProtocolApi, api.map(to_reading)),
+    ];
+    // DefiLlama only tracks the main market and reports USD values
+    if *reserve == KaminoReserveConfig::usdc() {
+        let defillama = timed(
+            Timing::ExternalApi,
+            upstream::call(
+                Upstream::DefiLlama,
+                get_stablecoin_borrows_and_supply("kamino-lend", "USDC"),
+            ),
+        )
+        .await;
+        readings.push((DataSource::DefiLlama, defillama.map(to_reading)));
+    }
+    resolve(readings, QUORUM_TOLERANCE, QUORUM_SIZE)
+}
+
+fn to_reading((total_borrows, total_supply): (f64, f64)) -> SourceReading {
+    SourceReading {
+        total_borrows,
+        total_supply,
+    }
+}
@@ -2,7 +2,15 @@
 use chrono::{DateTime, Timelike, Utc};
 use serde::Deserialize;
 
-use crate::risk_model::RiskCalculationError;
+use crate::{
+    clock::process_clock,
+    market_history::MarketPoint,
+    risk_model::RiskCalculationError,
+    upstream_fixtures::get_text,
+    volatility_risk::{fill_history_gaps, HistoryWindow, Lookback},
+};
+
+use super::reserve::KaminoReserveConfig;
 
 #[derive(Debug, Deserialize)]
 pub struct MetricsResponse {
@@ -16,6 +24,14 @@ pub struct HistoryEntry {
     pub metrics: Metrics,
 }
 
+impl HistoryEntry {
+    pub fn timestamp(&self) -> Result<DateTime<Utc>, RiskCalculationError> {
+        Ok(DateTime::parse_from_rfc3339(&self.timestamp)
+            .map_err(|e| RiskCalculationError::ParseError(format!("timestamp: {}", e)))?
+            .with_timezone(&Utc))
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Metrics {
     #[serde(rename = "borrowInterestAPY")]
@@ -34,68 +50,144 @@ pub struct YieldData {
     pub end: DateTime<Utc>,
     pub yields_percent: Vec<f64>,
     pub utilization_rates_percent: Vec<f64>,
+    /// The history actually returned, with its gaps filled
+    pub history_window: HistoryWindow,
 }
 
-pub async fn fetch_yield_and_utilization_rates() -> Result<YieldData, RiskCalculationError> {
-    let end = Utc::now()
-        .with_minute(0)
-        .unwrap()
-        .with_second(0)
-        .unwrap()
-        .with_nanosecond(0)
-        .unwrap();
-    let start = end - chrono::Duration::hours(24);
-    let url = format!(
-        "https://api.kamino.finance/kamino-market/H6rHXmXoCQvq8Ue81MqNh7ow5ysPa1dSozwW3PU1dDH6/reserves/6gTJfuPHEg6uRAijRkMqNc9kan4sVZejKMxmvx2grT1p/metrics/history?env=mainnet-beta&start={}Z&end={}Z&frequency=hour",
-        start.format("%Y-%m-%d"),
-        end.format("%Y-%m-%d")
-    );
-
-    let response = reqwest::get(&url)
-        .await
-        .map_err(|e| RiskCalculationError::RequestError(e))?;
-    let raw_data = response
-        .text()
-        .await
-        .map_err(|e| RiskCalculationError::RequestError(e))?;
-    let metrics_data: MetricsResponse =
-        serde_json::from_str(&raw_data).map_err(|e| RiskCalculationError::SerdeError(e))?;
-
-    let mut yields: Vec<f64> = Vec::new();
-    let mut utilization_rates: Vec<f64> = Vec::new();
-
-    for entry in metrics_data.history {
-        yields.push(entry.metrics.supply_interest_apy * 100.0); // Convert to percentage
-
-        // Calculate utilization rate
-        let total_borrows = entry
-            .metrics
+impl Metrics {
+    pub fn utilization_rate_percent(&self) -> Result<f64, RiskCalculationError> {
+        let total_borrows = self
             .total_borrows
             .parse::<f64>()
             .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
-        let total_supply = entry
-            .metrics
+        let total_supply = self
             .total_supply
             .parse::<f64>()
             .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
-        let utilization_rate = if total_supply > 0.0 {
+        Ok(if total_supply > 0.0 {
             (total_borrows / total_supply) * 100.0 // Convert to percentage
         } else {
             0.0
-        };
-        utilization_rates.push(utilization_rate);
+        })
     }
+}
+
+/// Hourly metrics of the reserve between `start` and `end`
+///
+/// Entries the API returns outside of the range are dropped.
+pub async fn fetch_metrics_history(
+    reserve: &KaminoReserveConfig,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<MetricsResponse, RiskCalculationError> {
+    let url = reserve.metrics_history_url(start, end);
+
+    let raw_data = get_text(&url).await?;
+    let mut metrics: MetricsResponse =
+        serde_json::from_str(&raw_data).map_err(RiskCalculationError::SerdeError)?;
+    let returned = metrics.history.len();
+    metrics.history.retain(|entry| {
+        entry
+            .timestamp()
+            .is_ok_and(|timestamp| (start..=end).contains(&timestamp))
+    });
+    if metrics.history.len() < returned {
+        tracing::warn!(
+            "Dropped {} Kamino history entries outside of {} to {}",
+            returned - metrics.history.len(),
+            start,
+            end
+        );
+    }
+    Ok(metrics)
+}
+
+/// Start of the current hour, the last point of the hourly history
+fn current_hour() -> DateTime<Utc> {
+    process_clock()
+        .now()
+        .with_minute(0)
+        .unwrap()
+        .with_second(0)
+        .unwrap()
+        .with_nanosecond(0)
+        .unwrap()
+}
+
+/// Hourly total supply of the reserve over the last `hours`, oldest first
+pub async fn fetch_supply_history(
+    reserve: &KaminoReserveConfig,
+    hours: u64,
+) -> Result<Vec<f64>, RiskCalculationError> {
+    let end = current_hour();
+    let start = end - chrono::Duration::hours(hours as i64);
+    supply_history(&fetch_metrics_history(reserve, start, end).await?)
+}
+
+/// Total supply of every entry of `metrics`, oldest first
+pub fn supply_history(metrics: &MetricsResponse) -> Result<Vec<f64>, RiskCalculationError> {
+    metrics
+        .history
+        .iter()
+        .map(|entry| {
+            entry
+                .metrics
+                .total_supply
+                .parse::<f64>()
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()))
+        })
+        .collect()
+}
+
+pub async fn fetch_yield_and_utilization_rates(
+    reserve: &KaminoReserveConfig,
+) -> Result<YieldData, RiskCalculationError> {
+    let end = current_hour();
+    // Enough hourly history for the longest volatility lookback
+    let start = end - chrono::Duration::hours(Lookback::LONGEST_HOURS as i64);
+    yield_data(
+        &fetch_metrics_history(reserve, start, end).await?,
+        start,
+        end,
+    )
+}
 
-    if yields.is_empty() {
-        return Err(RiskCalculationError::CustomError(
+/// Yields and utilization rates of the hourly `metrics` between `start` and `end`,
+/// with the missing hours filled
+pub fn yield_data(
+    metrics: &MetricsResponse,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<YieldData, RiskCalculationError> {
+    let points = metrics
+        .history
+        .iter()
+        .map(|entry| {
+            Ok(MarketPoint {
+                timestamp: entry.timestamp()?,
+                supply_apy_percent: entry.metrics.supply_interest_apy * 100.0, // Convert to percentage
+                utilization_percent: entry.metrics.utilization_rate_percent()?,
+                tvl_usd: None,
+            })
+        })
+        .collect::<Result<Vec<_>, RiskCalculationError>>()?;
+    let Some((points, history_window)) = fill_history_gaps(&points, 1) else {
+        return Err(RiskCalculationError::UpstreamUnavailable(
             "No yield data available".to_string(),
         ));
+    };
+    if history_window.filled_gaps > 0 {
+        tracing::warn!(
+            "Filled {} missing hours of the Kamino history",
+            history_window.filled_gaps
+        );
     }
 
     Ok(YieldData {
         start,
         end,
-        yields_percent: yields,
-        utilization_rates_percent: utilization_rates,
+        yields_percent: points.iter().map(|p| p.supply_apy_percent).collect(),
+        utilization_rates_percent: points.iter().map(|p| p.utilization_percent).collect(),
+        history_window,
     })
 }
@@ -0,0 +1,303 @@
+use axum::{
+    extract::{Query, State},
+    response::Response,
+};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    kamino::{reserve::KaminoReserveConfig, sources::LiveSources, KaminoRisk},
+    risk_model::{
+        json_response, timings_requested, ProtocolRisk, RiskCalculationError, RiskResponse,
+    },
+    state::AppState,
+    timings::with_timings,
+};
+
+/// Name of the market of the default reserve when `KAMINO_MARKETS` isn't set
+const DEFAULT_MARKET_NAME: &str = "main";
+/// `market` selecting every configured market
+const ALL_MARKETS: &str = "all";
+
+/// A Kamino lending market, assessed on one of its reserves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KaminoMarket {
+    /// Short name selecting the market in requests, e.g. `jlp`
+    pub name: String,
+    pub reserve: KaminoReserveConfig,
+}
+
+impl KaminoMarket {
+    /// Reads `KAMINO_MARKETS` as comma separated `name=market:reserve` entries,
+    /// the market of `default` alone when not set
+    ///
+    /// The reserves should hold the same asset, e.g. the USDC reserve of every
+    /// market, as the aggregate weighs them by their supply in token units.
+    pub fn from_env(default: &KaminoReserveConfig) -> Result<Vec<Self>, RiskCalculationError> {
+        match std::env::var("KAMINO_MARKETS") {
+            Ok(markets) => Self::parse_all(&markets),
+            Err(_) => Ok(vec![KaminoMarket {
+                name: DEFAULT_MARKET_NAME.to_string(),
+                reserve: default.clone(),
+            }]),
+        }
+    }
+
+    pub fn parse_all(markets: &str) -> Result<Vec<Self>, RiskCalculationError> {
+        let markets = markets
+            .split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let invalid = || {
+                    RiskCalculationError::ParseError(format!(
+                        "KAMINO_MARKETS entries must be name=market:reserve: {:?}",
+                        entry
+                    ))
+                };
+                let (name, pubkeys) = entry.trim().split_once('=').ok_or_else(invalid)?;
+                let (market, reserve) = pubkeys.split_once(':').ok_or_else(invalid)?;
+                if name.is_empty() || name == ALL_MARKETS {
+                    return Err(invalid());
+                }
+                Ok(KaminoMarket {
+                    name: name.to_lowercase(),
+                    reserve: KaminoReserveConfig::new(market, reserve)?,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if markets.is_empty() {
+            return Err(RiskCalculationError::ParseError(
+                "KAMINO_MARKETS must list at least one market".to_string(),
+            ));
+        }
+        Ok(markets)
+    }
+}
+
+/// Markets of `markets` selected by `market`: all of them, or the one with
+/// that name or lending market pubkey
+pub fn select_markets<'a>(
+    markets: &'a [KaminoMarket],
+    market: &str,
+) -> Result<Vec<&'a KaminoMarket>, RiskCalculationError> {
+    if market.eq_ignore_ascii_case(ALL_MARKETS) {
+        return Ok(markets.iter().collect());
+    }
+    markets
+        .iter()
+        .find(|known| {
+            known.name.eq_ignore_ascii_case(market) || known.reserve.market.to_string() == market
+        })
+        .map(|known| vec![known])
+        .ok_or_else(|| {
+            RiskCalculationError::InvalidParameter(format!("unknown Kamino market {:?}", market))
+        })
+}
+
+/// Pillar and overall scores of a market, or the aggregate of several
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MarketScores {
+    pub overall_risk: f64,
+    pub liquidity_risk: f64,
+    pub volatility_risk: f64,
+    pub protocol_risk: f64,
+    pub oracle_risk: f64,
+}
+
+impl MarketScores {
+    pub fn of(risk: &RiskResponse) -> Self {
+        MarketScores {
+            overall_risk: risk.overall_risk.overall_risk,
+            liquidity_risk: risk.liquidity_risk.liquidity_risk,
+            volatility_risk: risk.volatility_risk.volatility_risk,
+            protocol_risk: risk.protocol_risk.protocol_risk,
+            oracle_risk: risk.oracle_risk.oracle_risk,
+        }
+    }
+}
+
+/// Risk of one market
+#[derive(Debug, Serialize)]
+pub struct MarketRisk {
+    pub name: String,
+    pub market: String,
+    pub reserve: String,
+    /// Total supply of the reserve, in token units, what the aggregate weighs it by
+    pub tvl: f64,
+    pub risk_metrics: RiskResponse,
+}
+
+/// A market whose risk could not be computed
+#[derive(Debug, Serialize)]
+pub struct UnavailableMarket {
+    pub name: String,
+    pub error: String,
+}
+
+/// Scores of the markets averaged by their TVL
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct AggregateRisk {
+    pub tvl: f64,
+    #[serde(flatten)]
+    pub scores: MarketScores,
+}
+
+/// TVL weighted average of `markets`' scores, `None` without any TVL
+pub fn aggregate(markets: &[(f64, MarketScores)]) -> Option<AggregateRisk> {
+    let tvl: f64 = markets.iter().map(|(tvl, _)| tvl).sum();
+    if tvl <= 0.0 {
+        return None;
+    }
+    let weighted = |score: fn(&MarketScores) -> f64| {
+        markets
+            .iter()
+            .map(|(market_tvl, scores)| market_tvl * score(scores))
+            .sum::<f64>()
+            / tvl
+    };
+    Some(AggregateRisk {
+        tvl,
+        scores: MarketScores {
+            overall_risk: weighted(|scores| scores.overall_risk),
+            liquidity_risk: weighted(|scores| scores.liquidity_risk),
+            volatility_risk: weighted(|scores| scores.volatility_risk),
+            protocol_risk: weighted(|scores| scores.protocol_risk),
+            oracle_risk: weighted(|scores| scores.oracle_risk),
+        },
+    })
+}
+
+#[derive(Debug, Serialize)]
+pub struct KaminoMarketsRisk {
+    pub markets: Vec<MarketRisk>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unavailable_markets: Vec<UnavailableMarket>,
+    /// Of the available markets, `None` when none is
+    pub aggregate: Option<AggregateRisk>,
+}
+
+/// Assesses every market concurrently, each scanning only its own obligations
+pub async fn assess_markets(
+    redis_client: &redis::Client,
+    markets: &[&KaminoMarket],
+) -> KaminoMarketsRisk {
+    let assessments = join_all(markets.iter().map(|market| async move {
+        let kamino = KaminoRisk {
+            redis_client: redis_client.clone(),
+            reserve: market.reserve.clone(),
+            sources: LiveSources,
+        };
+        (market, kamino.calculate_all().await)
+    }))
+    .await;
+
+    let mut risk = KaminoMarketsRisk {
+        markets: Vec::new(),
+        unavailable_markets: Vec::new(),
+        aggregate: None,
+    };
+    for (market, assessment) in assessments {
+        match assessment {
+            Ok(risk_metrics) => risk.markets.push(MarketRisk {
+                name: market.name.clone(),
+                market: market.reserve.market.to_string(),
+                reserve: market.reserve.reserve.to_string(),
+                tvl: risk_metrics.liquidity_risk.total_supply,
+                risk_metrics,
+            }),
+            Err(e) => {
+                tracing::error!("Failed to assess Kamino market {}: {}", market.name, e);
+                risk.unavailable_markets.push(UnavailableMarket {
+                    name: market.name.clone(),
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+    let weighted: Vec<_> = risk
+        .markets
+        .iter()
+        .map(|market| (market.tvl, MarketScores::of(&market.risk_metrics)))
+        .collect();
+    risk.aggregate = aggregate(&weighted);
+    risk
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KaminoMarketsQuery {
+    /// `all`, the default, or the name or lending market of a configured market
+    pub market: Option<String>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+/// `GET /risk_model/kamino`: risk of every configured Kamino market and their aggregate
+pub async fn kamino_markets_risk(
+    State(state): State<AppState>,
+    Query(query): Query<KaminoMarketsQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let markets = select_markets(
+            &state.config.kamino_markets,
+            query.market.as_deref().unwrap_or(ALL_MARKETS),
+        )?;
+        let mut risk = assess_markets(&state.redis_client, &markets).await;
+        if risk.markets.is_empty() {
+            return Err(RiskCalculationError::UpstreamUnavailable(
+                "No Kamino market could be assessed".to_string(),
+            ));
+        }
+        for market in &mut risk.markets {
+            state
+                .config
+                .privacy
+                .apply_to_liquidity(&mut market.risk_metrics.liquidity_risk);
+        }
+        Ok(risk)
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kamino::reserve::{KAMINO_MAIN_MARKET, KAMINO_USDC_RESERVE};
+    use solana_sdk::pubkey::Pubkey;
+
+    #[test]
+    fn test_markets() {
+        let markets = KaminoMarket::parse_all(&format!(
+            "main={}:{}, JLP={}:{}",
+            KAMINO_MAIN_MARKET,
+            KAMINO_USDC_RESERVE,
+            Pubkey::new_unique(),
+            Pubkey::new_unique()
+        ))
+        .unwrap();
+        assert_eq!(markets.len(), 2);
+        assert_eq!(markets[0].reserve, KaminoReserveConfig::usdc());
+        assert_eq!(select_markets(&markets, "all").unwrap().len(), 2);
+        assert_eq!(select_markets(&markets, "jlp").unwrap()[0].name, "jlp");
+        assert_eq!(
+            select_markets(&markets, KAMINO_MAIN_MARKET).unwrap()[0].name,
+            "main"
+        );
+        assert!(select_markets(&markets, "altcoin").is_err());
+        assert!(KaminoMarket::parse_all("main").is_err());
+        assert!(KaminoMarket::parse_all("").is_err());
+
+        let scores = |risk: f64| MarketScores {
+            overall_risk: risk,
+            liquidity_risk: risk,
+            volatility_risk: risk,
+            protocol_risk: risk,
+            oracle_risk: risk,
+        };
+        let weighted = aggregate(&[(300.0, scores(20.0)), (100.0, scores(60.0))]).unwrap();
+        assert_eq!(weighted.tvl, 400.0);
+        assert_eq!(weighted.scores, scores(30.0));
+        assert!(aggregate(&[(0.0, scores(20.0))]).is_none());
+    }
+}
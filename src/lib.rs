@@ -0,0 +1,142 @@
+//! Risk scores of Solana lending protocols and the rebalancing engine
+//! allocating deposits across them
+//!
+//! The public API is made of:
+//!
+//! - [`risk`], the risk pillars, their formulas and weights
+//! - [`rebalancing`], allocation of portfolios across protocols and the
+//!   transfers between them
+//! - [`protocols`], the assessed lending protocols
+//! - [`cache`], the cache risk scores are stored in
+//! - [`server`], the HTTP service run by the `risk_model` binary
+//! - [`cli`], the subcommands of the `risk_model` binary
+//! - [`telemetry`], the logs and traces of the `risk_model` binary
+//!
+//! They follow semver, everything else is internal to the service.
+
+// The OpenAPI document is a single `json!` invocation
+#![recursion_limit = "256"]
+
+mod alerts;
+mod anomalies;
+mod assets;
+mod backtest;
+mod batch;
+mod bps;
+pub mod cache;
+mod cache_admin;
+mod cache_lock;
+mod cache_schema;
+pub mod cli;
+mod clock;
+mod cluster;
+mod concentration_history;
+mod conditional;
+mod correlation;
+mod defillama;
+mod dry_run;
+mod encoding;
+mod explain;
+mod feasibility;
+mod grpc;
+mod health;
+mod history;
+mod idempotency;
+mod incidents;
+mod kamino;
+mod kamino_markets;
+mod liquidity_depth;
+mod liquidity_risk;
+mod marginfi;
+mod market_history;
+mod multisig;
+mod openapi;
+mod optimizer;
+mod oracle_risk;
+mod portfolio;
+mod portfolio_events;
+mod precomputed;
+mod prices;
+mod privacy;
+mod profiles;
+mod proposals;
+mod protocol_rubric;
+mod quorum;
+mod rebalance_worker;
+pub mod rebalancing;
+mod redis_builder;
+mod redis_connection;
+mod registry;
+mod reports;
+mod risk_model;
+mod risk_stream;
+mod rpc_budget;
+mod rpc_pool;
+mod scheduler;
+pub mod server;
+mod shutdown;
+mod simulation;
+mod snapshot;
+mod state;
+mod state_export;
+mod strategy;
+mod stress;
+pub mod telemetry;
+mod timings;
+mod top_depositors;
+mod tx_builder;
+mod upstream;
+mod upstream_fixtures;
+mod volatility_risk;
+mod weight_smoothing;
+mod weights;
+
+/// The liquidity, volatility, protocol and oracle risk pillars and how they
+/// combine into the overall risk of a protocol
+///
+/// Every score is between 0 and 100, higher being riskier.
+pub mod risk {
+    pub use crate::explain::{attribute, explain_choice, Locale, PillarAttribution};
+    pub use crate::liquidity_risk::{
+        calculate_borrower_concentration, calculate_concentration, calculate_liquidation_risk,
+        calculate_liquidity_risk, calculate_tvl_trend, calculate_utilization_rate,
+        liquidity_metrics, outflow_risk, score_liquidity, scored_concentration, AccountHealth,
+        BorrowerConcentration, HealthFactorBucket, LiquidationRiskMetrics, TvlTrend,
+        NEAR_LIQUIDATION_HEALTH_FACTOR,
+    };
+    pub use crate::oracle_risk::{
+        calculate_feed_risk, calculate_oracle_risk, OracleFeed, OracleFeedMetrics,
+        OracleRiskMetrics, PythPrice,
+    };
+    pub use crate::protocol_rubric::{
+        AdminConfig, Audit, ComponentScore, Exploit, ProtocolProfile, ProtocolRubric,
+        RubricComponent,
+    };
+    pub use crate::risk_model::{
+        what_if, CacheMode, LiquidityRiskMetrics, Normalization, NormalizedScores,
+        OverallRiskWeights, PartialRiskResponse, Pillar, PillarError, Protocol,
+        ProtocolRiskMetrics, ProtocolSubScores, RiskCalculationError, RiskProfile, RiskResponse,
+        RiskScore, ScoreNormalization, SubScoreTtls, VolatilityRiskMetrics, WeightPreset,
+        WhatIfResult,
+    };
+    pub use crate::volatility_risk::{
+        calculate_downside_risk, calculate_lending_pool_risk, calculate_volatility_surface,
+        calculate_volatility_surface_from, Confidence, DownsideRisk, HistoryWindow, Lookback,
+        LookbackVolatility, SampleFrequency, SampledHistory, ValueAtRisk, VolatilityBlendWeights,
+        VolatilityWindow, WindowVolatility,
+    };
+    pub use crate::weights::{LiquidityWeights, RiskWeightsConfig, VolatilityWeights};
+}
+
+/// The lending protocols assessed, and the registry comparing them
+pub mod protocols {
+    pub use crate::kamino::KaminoRisk;
+    pub use crate::marginfi::{
+        MarginfiAccounts, MarginfiRisk, MARGINFI_MAIN_GROUP, MARGINFI_USDC_BANK,
+    };
+    pub use crate::registry::{
+        PartialAssessment, ProtocolAssessment, ProtocolComparison, ProtocolRegistry,
+        RegisteredProtocol, UnavailableProtocol,
+    };
+    pub use crate::risk_model::{Protocol, ProtocolRisk};
+}
@@ -0,0 +1,317 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use axum::{
+    extract::{Query, State},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    registry::{ProtocolAssessment, COMPARED_ASSET},
+    risk_model::{
+        json_response, timings_requested, DebugQuery, LiquidityRiskMetrics, Protocol,
+        RiskCalculationError,
+    },
+    state::AppState,
+    timings::with_timings,
+};
+
+/// Share of the one-day depth a single position may take by default
+pub const DEFAULT_MAX_DEPTH_SHARE: f64 = 0.1;
+
+static POSITION_LIMITS: OnceLock<PositionLimitConfig> = OnceLock::new();
+
+/// How soon the funds have to be out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WithdrawalHorizon {
+    /// Within the current slot, only the idle liquidity
+    Block,
+    Hour,
+    Day,
+}
+
+impl WithdrawalHorizon {
+    pub const ALL: [WithdrawalHorizon; 3] = [
+        WithdrawalHorizon::Block,
+        WithdrawalHorizon::Hour,
+        WithdrawalHorizon::Day,
+    ];
+
+    pub fn hours(&self) -> f64 {
+        match self {
+            // One 400ms slot
+            WithdrawalHorizon::Block => 0.4 / 3600.0,
+            WithdrawalHorizon::Hour => 1.0,
+            WithdrawalHorizon::Day => 24.0,
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "block" => Some(WithdrawalHorizon::Block),
+            "hour" => Some(WithdrawalHorizon::Hour),
+            "day" => Some(WithdrawalHorizon::Day),
+            _ => None,
+        }
+    }
+}
+
+/// How large a position can be for the reserve's depth, see
+/// [`LiquidityDepthCurve::position_limit`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PositionLimitConfig {
+    /// How soon a position has to be able to exit
+    pub horizon: WithdrawalHorizon,
+    /// Share of the depth within the horizon a single position may take
+    pub max_share: f64,
+}
+
+impl Default for PositionLimitConfig {
+    fn default() -> Self {
+        PositionLimitConfig {
+            horizon: WithdrawalHorizon::Day,
+            max_share: DEFAULT_MAX_DEPTH_SHARE,
+        }
+    }
+}
+
+impl PositionLimitConfig {
+    /// The configuration read at first use
+    pub fn global() -> &'static Self {
+        POSITION_LIMITS
+            .get_or_init(|| Self::from_env().expect("position limit configuration must be valid"))
+    }
+
+    /// Reads `POSITION_LIMIT_HORIZON` and `POSITION_LIMIT_MAX_DEPTH_SHARE`, the
+    /// defaults for those not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let mut config = PositionLimitConfig::default();
+        if let Ok(horizon) = std::env::var("POSITION_LIMIT_HORIZON") {
+            config.horizon = WithdrawalHorizon::parse(&horizon).ok_or_else(|| {
+                RiskCalculationError::ParseError(format!(
+                    "POSITION_LIMIT_HORIZON must be block, hour or day: {:?}",
+                    horizon
+                ))
+            })?;
+        }
+        if let Ok(share) = std::env::var("POSITION_LIMIT_MAX_DEPTH_SHARE") {
+            config.max_share = match share.trim().parse::<f64>() {
+                Ok(share) if share > 0.0 && share <= 1.0 => share,
+                _ => {
+                    return Err(RiskCalculationError::ParseError(format!(
+                        "POSITION_LIMIT_MAX_DEPTH_SHARE must be in (0, 1]: {:?}",
+                        share
+                    )))
+                }
+            };
+        }
+        Ok(config)
+    }
+
+    /// Position limits of every ranked protocol of `curves`
+    pub fn limits(&self, curves: &[LiquidityDepthCurve]) -> HashMap<Protocol, u64> {
+        position_limits(curves, self.horizon, self.max_share)
+    }
+}
+
+/// How fast borrowers repay as rates climb
+///
+/// Below the `kink` utilization borrowers repay `base_daily` of their debt per
+/// day. Above it the rate curve steepens and repayments rise linearly up to
+/// `max_daily` at full utilization, which is what eventually frees liquidity in
+/// a drained pool.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct RepaymentModel {
+    pub base_daily: f64,
+    pub max_daily: f64,
+    /// Utilization as a fraction, e.g. 0.8
+    pub kink: f64,
+}
+
+impl Default for RepaymentModel {
+    fn default() -> Self {
+        RepaymentModel {
+            base_daily: 0.01,
+            max_daily: 0.25,
+            kink: 0.8,
+        }
+    }
+}
+
+impl RepaymentModel {
+    /// Fraction of the borrows repaid per day at `utilization` (a fraction)
+    pub fn daily_repayment(&self, utilization: f64) -> f64 {
+        let above_kink = ((utilization - self.kink) / (1.0 - self.kink)).clamp(0.0, 1.0);
+        self.base_daily + (self.max_daily - self.base_daily) * above_kink
+    }
+
+    /// Borrows expected to be repaid within `hours`, compounding the daily rate
+    pub fn repaid(&self, total_borrows: f64, utilization: f64, hours: f64) -> f64 {
+        let daily = self.daily_repayment(utilization).clamp(0.0, 1.0);
+        total_borrows * (1.0 - (1.0 - daily).powf(hours / 24.0))
+    }
+}
+
+/// Tokens withdrawable within a horizon
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DepthPoint {
+    pub horizon: WithdrawalHorizon,
+    pub withdrawable: f64,
+}
+
+/// Withdrawal depth of a reserve over every horizon
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiquidityDepthCurve {
+    pub protocol: Protocol,
+    pub scope: String,
+    pub available_liquidity: f64,
+    pub points: Vec<DepthPoint>,
+}
+
+impl LiquidityDepthCurve {
+    /// Idle liquidity plus the repayments expected within each horizon, never
+    /// more than the whole supply
+    pub fn new(
+        protocol: Protocol,
+        scope: String,
+        liquidity: &LiquidityRiskMetrics,
+        model: &RepaymentModel,
+    ) -> Self {
+        let available_liquidity = (liquidity.total_supply - liquidity.total_borrows).max(0.0);
+        let utilization = liquidity.utilization_rate / 100.0;
+        let points = WithdrawalHorizon::ALL
+            .iter()
+            .map(|horizon| DepthPoint {
+                horizon: *horizon,
+                withdrawable: (available_liquidity
+                    + model.repaid(liquidity.total_borrows, utilization, horizon.hours()))
+                .min(liquidity.total_supply.max(0.0)),
+            })
+            .collect();
+        LiquidityDepthCurve {
+            protocol,
+            scope,
+            available_liquidity,
+            points,
+        }
+    }
+
+    pub fn from_assessment(assessment: &ProtocolAssessment, model: &RepaymentModel) -> Self {
+        Self::new(
+            assessment.protocol.clone(),
+            assessment.scope.clone(),
+            &assessment.risk_metrics.liquidity_risk,
+            model,
+        )
+    }
+
+    pub fn withdrawable(&self, horizon: WithdrawalHorizon) -> f64 {
+        self.points
+            .iter()
+            .find(|point| point.horizon == horizon)
+            .map_or(0.0, |point| point.withdrawable)
+    }
+
+    /// Largest position, in native units of [`COMPARED_ASSET`], that could exit
+    /// within `horizon` using at most `max_share` of the depth
+    pub fn position_limit(&self, horizon: WithdrawalHorizon, max_share: f64) -> u64 {
+        (self.withdrawable(horizon) * max_share * 10f64.powi(COMPARED_ASSET.decimals() as i32))
+            as u64
+    }
+}
+
+/// Position limits of every ranked protocol, for the rebalancer
+pub fn position_limits(
+    curves: &[LiquidityDepthCurve],
+    horizon: WithdrawalHorizon,
+    max_share: f64,
+) -> HashMap<Protocol, u64> {
+    curves
+        .iter()
+        .map(|curve| {
+            (
+                curve.protocol.clone(),
+                curve.position_limit(horizon, max_share),
+            )
+        })
+        .collect()
+}
+
+pub async fn liquidity_depth(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let snapshot = state.registry.cached_snapshot().await?;
+        let model = RepaymentModel::default();
+        let curves: Vec<_> = snapshot
+            .comparison
+            .ranking
+            .iter()
+            .map(|assessment| LiquidityDepthCurve::from_assessment(assessment, &model))
+            .collect();
+
+        Ok::<_, RiskCalculationError>(serde_json::json!({
+            "snapshot_id": snapshot.snapshot_id,
+            "repayment_model": model,
+            "position_limits": PositionLimitConfig::global().limits(&curves),
+            "curves": curves,
+        }))
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn liquidity(total_borrows: f64, total_supply: f64) -> LiquidityRiskMetrics {
+        LiquidityRiskMetrics {
+            total_borrows,
+            total_supply,
+            utilization_rate: total_borrows / total_supply * 100.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_depth_curve() {
+        let model = RepaymentModel::default();
+        assert_eq!(model.daily_repayment(0.5), 0.01);
+        assert!((model.daily_repayment(0.9) - 0.13).abs() < 1e-9);
+        assert_eq!(model.daily_repayment(1.0), 0.25);
+
+        // 90% utilized: 100 idle, 13% of the 900 borrowed repaid over a day
+        let curve = LiquidityDepthCurve::new(
+            Protocol::Kamino,
+            "kamino".to_string(),
+            &liquidity(900.0, 1_000.0),
+            &model,
+        );
+        let block = curve.withdrawable(WithdrawalHorizon::Block);
+        let hour = curve.withdrawable(WithdrawalHorizon::Hour);
+        let day = curve.withdrawable(WithdrawalHorizon::Day);
+        assert!((block - 100.0).abs() < 0.01);
+        assert!(block < hour && hour < day);
+        assert!((day - 217.0).abs() < 1e-6);
+        assert_eq!(
+            curve.position_limit(WithdrawalHorizon::Day, DEFAULT_MAX_DEPTH_SHARE),
+            21_700_000
+        );
+
+        // Repayments can't free more than the supply
+        let drained = LiquidityDepthCurve::new(
+            Protocol::Kamino,
+            "kamino".to_string(),
+            &liquidity(1_000.0, 1_000.0),
+            &RepaymentModel {
+                max_daily: 1.0,
+                ..model
+            },
+        );
+        assert_eq!(drained.withdrawable(WithdrawalHorizon::Day), 1_000.0);
+    }
+}
@@ -1,5 +1,14 @@
+use std::sync::OnceLock;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use tracing::info;
 
+use crate::{
+    risk_model::{LiquidityRiskMetrics, RiskCalculationError},
+    weights::LiquidityWeights,
+};
+
 /// Calculates the liquidity risk score for a lending pool
 ///
 /// The liquidity risk (Rl,l) is calculated using the formula:
@@ -21,7 +30,6 @@ use tracing::info;
 /// * `reserve_id` - The ID of the specific reserve
 /// * `rpc_url` - The Solana RPC URL for querying deposit data
 /// * `program_id` - The program ID for the lending protocol
-
 pub fn calculate_liquidity_risk(
     deposit_concentration: f64,
     utilization_rate: f64,
@@ -46,9 +54,9 @@ pub fn calculate_liquidity_risk(
 ///
 /// # Returns
 /// * `Option<f64>` - The deposit concentration as a decimal between 0 and 1,
-///                   or None if there are no deposits
+///   or None if there are no deposits
 pub fn calculate_concentration(deposits: Vec<u128>) -> Option<f64> {
-    if deposits.len() == 0 {
+    if deposits.is_empty() {
         return None;
     }
     let total_deposits = deposits.iter().sum::<u128>();
@@ -72,7 +80,7 @@ pub fn calculate_concentration(deposits: Vec<u128>) -> Option<f64> {
 ///
 /// # Returns
 /// * `Option<f64>` - The utilization rate as a percentage between 0 and 100,
-///                   or None if total supply is 0
+///   or None if total supply is 0
 pub fn calculate_utilization_rate(total_borrows: f64, total_supply: f64) -> Option<f64> {
     if total_supply > 0.0 {
         Some((total_borrows / total_supply) * 100.0) // Convert to percentage
@@ -80,3 +88,496 @@ pub fn calculate_utilization_rate(total_borrows: f64, total_supply: f64) -> Opti
         None
     }
 }
+
+/// Computes the liquidity pillar from the deposits, borrows and supply of a reserve
+///
+/// Shared by every protocol and the dry run, so they score identical inputs
+/// identically. Data quorum, liquidation risk and caps, see [`with_caps`], are
+/// left for the caller to add.
+pub fn liquidity_metrics(
+    largest_deposit: u128,
+    total_deposits: u128,
+    total_borrows: f64,
+    total_supply: f64,
+    borrower_concentration: Option<BorrowerConcentration>,
+    tvl_trend: Option<TvlTrend>,
+    weights: &LiquidityWeights,
+) -> Result<LiquidityRiskMetrics, RiskCalculationError> {
+    let deposit_concentration = (largest_deposit as f64) / (total_deposits as f64);
+    let utilization_rate = calculate_utilization_rate(total_borrows, total_supply).ok_or(
+        RiskCalculationError::CustomError("Total supply is 0".to_string()),
+    )?;
+    let tvl_change_24h_pct = tvl_trend.map(|trend| trend.tvl_change_24h_pct);
+    let liquidity_risk = score_liquidity(
+        scored_concentration(deposit_concentration, borrower_concentration.as_ref()),
+        utilization_rate,
+        tvl_change_24h_pct,
+        weights,
+    );
+    Ok(LiquidityRiskMetrics {
+        total_borrows,
+        total_supply,
+        utilization_rate,
+        largest_deposit,
+        total_deposits,
+        deposit_concentration,
+        liquidity_risk,
+        data_quorum: None,
+        liquidation_risk: None,
+        borrower_concentration,
+        tvl_change_24h_pct,
+        tvl_change_7d_pct: tvl_trend.and_then(|trend| trend.tvl_change_7d_pct),
+        available_liquidity: None,
+        supply_cap_utilization: None,
+        borrow_cap_utilization: None,
+        reserve_collateral_supply: None,
+        concentration_denominator: ConcentrationDenominator::Scanned,
+        data_completeness: None,
+    })
+}
+
+static CONCENTRATION_DENOMINATOR: OnceLock<ConcentrationDenominator> = OnceLock::new();
+
+/// What the largest deposit is divided by for the deposit concentration
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConcentrationDenominator {
+    /// Sum of the scanned deposits
+    #[default]
+    Scanned,
+    /// Collateral minted by the reserve, read from its account
+    ///
+    /// Doesn't depend on the scan, so obligations it misses or reads twice
+    /// don't skew the concentration.
+    ReserveSupply,
+}
+
+impl ConcentrationDenominator {
+    /// The denominator read at first use
+    pub fn global() -> Self {
+        *CONCENTRATION_DENOMINATOR.get_or_init(|| {
+            Self::from_env().expect("concentration denominator configuration must be valid")
+        })
+    }
+
+    /// Reads `CONCENTRATION_DENOMINATOR`, one of `scanned` and `reserve_supply`,
+    /// `scanned` when not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        match std::env::var("CONCENTRATION_DENOMINATOR").as_deref() {
+            Err(_) | Ok("scanned") => Ok(ConcentrationDenominator::Scanned),
+            Ok("reserve_supply") => Ok(ConcentrationDenominator::ReserveSupply),
+            Ok(other) => Err(RiskCalculationError::ParseError(format!(
+                "invalid CONCENTRATION_DENOMINATOR {:?}, expected scanned or reserve_supply",
+                other
+            ))),
+        }
+    }
+}
+
+/// Total the concentration is divided by, and the denominator it is
+///
+/// The scanned total stands in when the reserve supply wasn't read or is 0.
+pub fn concentration_total(
+    configured: ConcentrationDenominator,
+    scanned_total: u128,
+    reserve_supply: Option<u128>,
+) -> (u128, ConcentrationDenominator) {
+    match (configured, reserve_supply) {
+        (ConcentrationDenominator::ReserveSupply, Some(supply)) if supply > 0 => {
+            (supply, ConcentrationDenominator::ReserveSupply)
+        }
+        _ => (scanned_total, ConcentrationDenominator::Scanned),
+    }
+}
+
+/// `metrics` of a reserve with `caps`, its risk raised by [`cap_penalty`]
+pub fn with_caps(metrics: LiquidityRiskMetrics, caps: ReserveCaps) -> LiquidityRiskMetrics {
+    let supply_cap_utilization = cap_utilization(metrics.total_supply, caps.supply_cap);
+    let borrow_cap_utilization = cap_utilization(metrics.total_borrows, caps.borrow_cap);
+    LiquidityRiskMetrics {
+        liquidity_risk: (metrics.liquidity_risk
+            + cap_penalty(supply_cap_utilization, borrow_cap_utilization))
+        .min(100.0),
+        available_liquidity: Some(caps.available_liquidity),
+        supply_cap_utilization,
+        borrow_cap_utilization,
+        ..metrics
+    }
+}
+
+/// Liquidity risk from the scored concentration, utilization and, when known, TVL trend
+///
+/// Every component is in percent, so the risk is between 0 and 100. Without a
+/// TVL trend, the concentration and utilization weights are scaled to sum to 1
+/// so the score stays on the same scale.
+pub fn score_liquidity(
+    concentration: f64,
+    utilization_rate: f64,
+    tvl_change_24h_pct: Option<f64>,
+    weights: &LiquidityWeights,
+) -> f64 {
+    let risk = calculate_liquidity_risk(
+        concentration * 100.0,
+        utilization_rate,
+        weights.utilization,
+        weights.deposit_concentration,
+    );
+    let scored_weight = weights.utilization + weights.deposit_concentration;
+    match tvl_change_24h_pct {
+        Some(change) => risk + weights.tvl_outflow * outflow_risk(change),
+        None if scored_weight > 0.0 => risk / scored_weight,
+        None => risk,
+    }
+}
+
+/// Outflow over 24 hours, in percent of the TVL, scored as the maximum risk
+const MAX_RISK_OUTFLOW_PCT: f64 = 20.0;
+
+/// Net deposit flow of a reserve, from the history of its total supply
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TvlTrend {
+    /// Change of the total supply over the last 24 hours, in percent
+    pub tvl_change_24h_pct: f64,
+    /// Over the last 7 days, `None` with less history than that
+    pub tvl_change_7d_pct: Option<f64>,
+}
+
+/// Calculates the TVL trend from a total supply series, oldest first
+///
+/// `None` with less than 24 hours of history or an empty reserve at its start.
+pub fn calculate_tvl_trend(supplies: &[f64], sample_interval_hours: u64) -> Option<TvlTrend> {
+    let latest = *supplies.last()?;
+    let change_pct = |hours: u64| {
+        let points = hours.checked_div(sample_interval_hours)? as usize;
+        let past = *supplies.iter().rev().nth(points)?;
+        (points > 0 && past > 0.0).then(|| (latest - past) / past * 100.0)
+    };
+    Some(TvlTrend {
+        tvl_change_24h_pct: change_pct(24)?,
+        tvl_change_7d_pct: change_pct(24 * 7),
+    })
+}
+
+/// Risk of a TVL change, between 0 for no outflow and 100 from a 20% outflow
+pub fn outflow_risk(tvl_change_24h_pct: f64) -> f64 {
+    (-tvl_change_24h_pct / MAX_RISK_OUTFLOW_PCT * 100.0).clamp(0.0, 100.0)
+}
+
+/// Cap utilization, in percent, from which the liquidity risk is penalized
+const CAP_PENALTY_START_PCT: f64 = 80.0;
+/// Risk added to the liquidity risk of a reserve at one of its caps
+const MAX_CAP_PENALTY: f64 = 25.0;
+
+/// Caps and idle liquidity of a reserve, in token units
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReserveCaps {
+    /// Liquidity not lent out, what can be withdrawn right away
+    pub available_liquidity: f64,
+    /// Most that can be supplied, `None` without a cap
+    pub supply_cap: Option<f64>,
+    /// Most that can be borrowed, `None` without a cap
+    pub borrow_cap: Option<f64>,
+}
+
+/// Share of `cap` taken by `amount`, in percent, `None` without a cap
+pub fn cap_utilization(amount: f64, cap: Option<f64>) -> Option<f64> {
+    cap.filter(|cap| *cap > 0.0).map(|cap| amount / cap * 100.0)
+}
+
+/// Risk added as a reserve nears a cap
+///
+/// 0 while both caps are used below 80%, then growing linearly with the most
+/// used cap to 25 at the cap: deposits can't grow past it and borrowers
+/// locked out of it compete for the available liquidity.
+pub fn cap_penalty(
+    supply_cap_utilization: Option<f64>,
+    borrow_cap_utilization: Option<f64>,
+) -> f64 {
+    let nearest = supply_cap_utilization
+        .into_iter()
+        .chain(borrow_cap_utilization)
+        .fold(0.0, f64::max);
+    let proximity = (nearest - CAP_PENALTY_START_PCT) / (100.0 - CAP_PENALTY_START_PCT);
+    proximity.clamp(0.0, 1.0) * MAX_CAP_PENALTY
+}
+
+/// How concentrated a reserve's borrows are among borrowers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct BorrowerConcentration {
+    pub borrowers: usize,
+    /// Share of the borrows owed by the largest borrower
+    pub largest_borrower_share: f64,
+    /// Herfindahl-Hirschman index of the borrower shares, between 0 and 1
+    pub hhi: f64,
+}
+
+/// Herfindahl-Hirschman index of the deposit shares, between 0 and 1
+///
+/// `None` without any deposits.
+pub fn calculate_deposit_hhi(deposits: &[u128]) -> Option<f64> {
+    let total = deposits.iter().sum::<u128>();
+    if total == 0 {
+        return None;
+    }
+    Some(
+        deposits
+            .iter()
+            .map(|deposit| (*deposit as f64 / total as f64).powi(2))
+            .sum(),
+    )
+}
+
+/// Calculates borrower concentration from what every borrower owes the reserve
+///
+/// `None` without any borrows.
+pub fn calculate_borrower_concentration(borrows: &[f64]) -> Option<BorrowerConcentration> {
+    let borrows: Vec<f64> = borrows.iter().copied().filter(|b| *b > 0.0).collect();
+    let total: f64 = borrows.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let largest = borrows.iter().copied().fold(0.0, f64::max);
+    Some(BorrowerConcentration {
+        borrowers: borrows.len(),
+        largest_borrower_share: largest / total,
+        hhi: borrows.iter().map(|b| (b / total).powi(2)).sum(),
+    })
+}
+
+/// Concentration the liquidity risk is scored on
+///
+/// A whale borrower repaying or being liquidated moves utilization as much as a
+/// whale depositor exiting, so the worse of the two is used.
+pub fn scored_concentration(
+    deposit_concentration: f64,
+    borrower_concentration: Option<&BorrowerConcentration>,
+) -> f64 {
+    borrower_concentration.map_or(deposit_concentration, |borrowers| {
+        deposit_concentration.max(borrowers.largest_borrower_share)
+    })
+}
+
+/// Health factors at or below this are within 5% of liquidation
+pub const NEAR_LIQUIDATION_HEALTH_FACTOR: f64 = 1.05;
+/// Upper bounds of the health factor buckets, the last bucket is unbounded
+const HEALTH_FACTOR_BUCKETS: [f64; 6] = [1.0, 1.05, 1.1, 1.25, 1.5, 2.0];
+
+/// Collateral and debt of one borrower account, in USD
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccountHealth {
+    pub deposited_value: f64,
+    pub borrowed_value: f64,
+    /// Debt adjusted by the protocol's borrow factors, compared to `unhealthy_borrow_value`
+    pub adjusted_debt_value: f64,
+    /// Adjusted debt at which the account can be liquidated
+    pub unhealthy_borrow_value: f64,
+}
+
+impl AccountHealth {
+    /// `None` for accounts without debt, which can't be liquidated
+    pub fn health_factor(&self) -> Option<f64> {
+        (self.adjusted_debt_value > 0.0)
+            .then(|| self.unhealthy_borrow_value / self.adjusted_debt_value)
+    }
+
+    pub fn loan_to_value(&self) -> Option<f64> {
+        (self.deposited_value > 0.0).then(|| self.borrowed_value / self.deposited_value)
+    }
+}
+
+/// Accounts with debt whose health factor is below `upper_bound`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct HealthFactorBucket {
+    /// `None` for the last, unbounded bucket
+    pub upper_bound: Option<f64>,
+    pub accounts: usize,
+    pub deposited_value: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LiquidationRiskMetrics {
+    pub accounts_with_debt: usize,
+    pub health_factor_distribution: Vec<HealthFactorBucket>,
+    pub median_loan_to_value: f64,
+    pub p90_loan_to_value: f64,
+    /// Share of the deposited value held by accounts within 5% of liquidation
+    pub near_liquidation_share: f64,
+}
+
+/// Calculates the health factor and LTV distribution of a market's accounts
+///
+/// The deposited value of every account, with or without debt, makes up the
+/// TVL the near-liquidation share is relative to. `None` without any deposits.
+pub fn calculate_liquidation_risk(accounts: &[AccountHealth]) -> Option<LiquidationRiskMetrics> {
+    let total_value: f64 = accounts.iter().map(|a| a.deposited_value).sum();
+    if total_value <= 0.0 {
+        return None;
+    }
+    let indebted: Vec<(f64, &AccountHealth)> = accounts
+        .iter()
+        .filter_map(|account| Some((account.health_factor()?, account)))
+        .collect();
+
+    let mut health_factor_distribution: Vec<HealthFactorBucket> = HEALTH_FACTOR_BUCKETS
+        .iter()
+        .map(|bound| Some(*bound))
+        .chain(std::iter::once(None))
+        .map(|upper_bound| HealthFactorBucket {
+            upper_bound,
+            accounts: 0,
+            deposited_value: 0.0,
+        })
+        .collect();
+    for (health_factor, account) in &indebted {
+        let bucket = HEALTH_FACTOR_BUCKETS
+            .iter()
+            .position(|bound| health_factor < bound)
+            .unwrap_or(HEALTH_FACTOR_BUCKETS.len());
+        health_factor_distribution[bucket].accounts += 1;
+        health_factor_distribution[bucket].deposited_value += account.deposited_value;
+    }
+
+    let near_liquidation_value: f64 = indebted
+        .iter()
+        .filter(|(health_factor, _)| *health_factor <= NEAR_LIQUIDATION_HEALTH_FACTOR)
+        .map(|(_, account)| account.deposited_value)
+        .sum();
+
+    let mut loan_to_values: Vec<f64> = indebted
+        .iter()
+        .filter_map(|(_, account)| account.loan_to_value())
+        .collect();
+    loan_to_values.sort_by(f64::total_cmp);
+    let percentile = |p: f64| {
+        if loan_to_values.is_empty() {
+            return 0.0;
+        }
+        loan_to_values[((loan_to_values.len() - 1) as f64 * p).round() as usize]
+    };
+
+    Some(LiquidationRiskMetrics {
+        accounts_with_debt: indebted.len(),
+        health_factor_distribution,
+        median_loan_to_value: percentile(0.5),
+        p90_loan_to_value: percentile(0.9),
+        near_liquidation_share: near_liquidation_value / total_value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(deposited_value: f64, borrowed_value: f64, health_factor: f64) -> AccountHealth {
+        AccountHealth {
+            deposited_value,
+            borrowed_value,
+            adjusted_debt_value: borrowed_value,
+            unhealthy_borrow_value: borrowed_value * health_factor,
+        }
+    }
+
+    #[test]
+    fn test_liquidation_risk() {
+        let accounts = [
+            // Only deposits
+            account(500.0, 0.0, 0.0),
+            account(200.0, 160.0, 1.02),
+            account(200.0, 100.0, 1.6),
+            account(100.0, 10.0, 5.0),
+        ];
+        let metrics = calculate_liquidation_risk(&accounts).unwrap();
+        assert_eq!(metrics.accounts_with_debt, 3);
+        assert!((metrics.near_liquidation_share - 0.2).abs() < 1e-12);
+        let bucket = |upper_bound| {
+            metrics
+                .health_factor_distribution
+                .iter()
+                .find(|bucket| bucket.upper_bound == upper_bound)
+                .unwrap()
+                .accounts
+        };
+        assert_eq!(bucket(Some(1.05)), 1);
+        assert_eq!(bucket(Some(2.0)), 1);
+        assert_eq!(bucket(None), 1);
+        // LTVs 0.1, 0.5, 0.8
+        assert_eq!(metrics.median_loan_to_value, 0.5);
+        assert_eq!(metrics.p90_loan_to_value, 0.8);
+
+        assert!(calculate_liquidation_risk(&[]).is_none());
+    }
+
+    #[test]
+    fn test_tvl_trend() {
+        // 8 days of hourly supply, down 10% over the last day
+        let mut supplies = vec![200.0; 24 * 7];
+        supplies.extend(vec![100.0; 24]);
+        supplies.push(90.0);
+        let trend = calculate_tvl_trend(&supplies, 1).unwrap();
+        assert!((trend.tvl_change_24h_pct + 10.0).abs() < 1e-12);
+        assert!((trend.tvl_change_7d_pct.unwrap() + 55.0).abs() < 1e-12);
+        assert_eq!(outflow_risk(trend.tvl_change_24h_pct), 50.0);
+        assert_eq!(outflow_risk(5.0), 0.0);
+        assert_eq!(outflow_risk(-40.0), 100.0);
+
+        let trend = calculate_tvl_trend(&supplies[24 * 6..], 1).unwrap();
+        assert!(trend.tvl_change_7d_pct.is_none());
+        assert!(calculate_tvl_trend(&supplies[..24], 1).is_none());
+        assert!(calculate_tvl_trend(&supplies, 0).is_none());
+
+        // Without a trend, the other weights are scaled as if it weren't weighted
+        let weights = LiquidityWeights::default();
+        let unweighted = LiquidityWeights {
+            tvl_outflow: 0.0,
+            ..weights
+        };
+        let without_trend = score_liquidity(0.5, 60.0, None, &weights);
+        assert!((without_trend - score_liquidity(0.5, 60.0, None, &unweighted)).abs() < 1e-9);
+        assert!(score_liquidity(0.5, 60.0, Some(-20.0), &weights) > without_trend);
+    }
+
+    #[test]
+    fn test_cap_penalty() {
+        assert_eq!(cap_utilization(50.0, Some(200.0)), Some(25.0));
+        assert_eq!(cap_utilization(50.0, None), None);
+        assert_eq!(cap_penalty(None, None), 0.0);
+        assert_eq!(cap_penalty(Some(80.0), Some(25.0)), 0.0);
+        assert_eq!(cap_penalty(Some(25.0), Some(90.0)), 12.5);
+        // Past the cap, e.g. after the cap was lowered
+        assert_eq!(cap_penalty(Some(120.0), None), 25.0);
+    }
+
+    #[test]
+    fn test_concentration_total() {
+        use ConcentrationDenominator::*;
+        assert_eq!(
+            concentration_total(Scanned, 900, Some(1_000)),
+            (900, Scanned)
+        );
+        assert_eq!(
+            concentration_total(ReserveSupply, 900, Some(1_000)),
+            (1_000, ReserveSupply)
+        );
+        // Not read, or read from an emptied reserve
+        assert_eq!(
+            concentration_total(ReserveSupply, 900, None),
+            (900, Scanned)
+        );
+        assert_eq!(
+            concentration_total(ReserveSupply, 900, Some(0)),
+            (900, Scanned)
+        );
+    }
+
+    #[test]
+    fn test_borrower_concentration() {
+        let concentration = calculate_borrower_concentration(&[600.0, 200.0, 200.0, 0.0]).unwrap();
+        assert_eq!(concentration.borrowers, 3);
+        assert!((concentration.largest_borrower_share - 0.6).abs() < 1e-12);
+        assert!((concentration.hhi - 0.44).abs() < 1e-12);
+        assert!(calculate_borrower_concentration(&[]).is_none());
+
+        assert_eq!(scored_concentration(0.3, Some(&concentration)), 0.6);
+        assert_eq!(scored_concentration(0.3, None), 0.3);
+    }
+}
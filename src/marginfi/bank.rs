@@ -0,0 +1,257 @@
+use anchor_client::solana_sdk::pubkey::Pubkey;
+
+use crate::{
+    cluster::rpc_url,
+    defillama::get_stablecoin_borrows_and_supply,
+    quorum::{resolve, DataSource, QuorumReport, SourceReading, QUORUM_SIZE, QUORUM_TOLERANCE},
+    risk_model::RiskCalculationError,
+    timings::{timed, Timing},
+    upstream::{self, Upstream},
+};
+
+use super::MarginfiAccounts;
+
+/// Anchor discriminator of the marginfi `Bank` account
+pub const BANK_DISCRIMINATOR: [u8; 8] = [142, 49, 166, 242, 50, 66, 97, 188];
+
+// Byte offsets into the bank account data (including the 8 byte discriminator)
+const MINT_OFFSET: usize = 8;
+const MINT_DECIMALS_OFFSET: usize = 8 + 32;
+const ASSET_SHARE_VALUE_OFFSET: usize = 8 + 72;
+const LIABILITY_SHARE_VALUE_OFFSET: usize = 8 + 88;
+const TOTAL_LIABILITY_SHARES_OFFSET: usize = 8 + 248;
+const TOTAL_ASSET_SHARES_OFFSET: usize = 8 + 264;
+const OPTIMAL_UTILIZATION_RATE_OFFSET: usize = 8 + 360;
+const PLATEAU_INTEREST_RATE_OFFSET: usize = 8 + 376;
+const MAX_INTEREST_RATE_OFFSET: usize = 8 + 392;
+const ORACLE_SETUP_OFFSET: usize = 8 + 601;
+const ORACLE_KEYS_OFFSET: usize = 8 + 602;
+/// `OracleSetup` of a bank reading a Pyth v2 price account
+const ORACLE_SETUP_PYTH_LEGACY: u8 = 1;
+
+/// The subset of the marginfi `Bank` account needed for risk calculations
+#[derive(Debug, Clone)]
+pub struct Bank {
+    pub mint: Pubkey,
+    pub mint_decimals: u8,
+    pub asset_share_value: f64,
+    pub liability_share_value: f64,
+    pub total_asset_shares: f64,
+    pub total_liability_shares: f64,
+    /// Interest rate curve, the borrow rate reaches the plateau at the optimal
+    /// utilization and the max at full utilization
+    pub optimal_utilization_rate: f64,
+    pub plateau_interest_rate: f64,
+    pub max_interest_rate: f64,
+    pub oracle_setup: u8,
+    /// First of the oracle keys, the price account of a Pyth bank
+    pub oracle: Pubkey,
+}
+
+impl Bank {
+    /// Parses a raw bank account, validating the discriminator first
+    pub fn from_account_data(data: &[u8]) -> Result<Self, RiskCalculationError> {
+        if data.len() < ORACLE_KEYS_OFFSET + 32 {
+            return Err(RiskCalculationError::ParseError(format!(
+                "Bank account too small: {} bytes",
+                data.len()
+            )));
+        }
+        if data[..8] != BANK_DISCRIMINATOR {
+            return Err(RiskCalculationError::ParseError(
+                "Account is not a marginfi bank".to_string(),
+            ));
+        }
+        Ok(Bank {
+            mint: read_pubkey(data, MINT_OFFSET),
+            mint_decimals: data[MINT_DECIMALS_OFFSET],
+            asset_share_value: read_i80f48(data, ASSET_SHARE_VALUE_OFFSET),
+            liability_share_value: read_i80f48(data, LIABILITY_SHARE_VALUE_OFFSET),
+            total_asset_shares: read_i80f48(data, TOTAL_ASSET_SHARES_OFFSET),
+            total_liability_shares: read_i80f48(data, TOTAL_LIABILITY_SHARES_OFFSET),
+            optimal_utilization_rate: read_i80f48(data, OPTIMAL_UTILIZATION_RATE_OFFSET),
+            plateau_interest_rate: read_i80f48(data, PLATEAU_INTEREST_RATE_OFFSET),
+            max_interest_rate: read_i80f48(data, MAX_INTEREST_RATE_OFFSET),
+            oracle_setup: data[ORACLE_SETUP_OFFSET],
+            oracle: read_pubkey(data, ORACLE_KEYS_OFFSET),
+        })
+    }
+
+    /// Total deposits of the bank in token units
+    pub fn total_supply(&self) -> f64 {
+        self.total_asset_shares * self.asset_share_value / self.token_scale()
+    }
+
+    /// Total borrows of the bank in token units
+    pub fn total_borrows(&self) -> f64 {
+        self.total_liability_shares * self.liability_share_value / self.token_scale()
+    }
+
+    /// Share of the deposits lent out, between 0 and 1
+    pub fn utilization_rate(&self) -> f64 {
+        let supply = self.total_supply();
+        if supply > 0.0 {
+            (self.total_borrows() / supply).min(1.0)
+        } else {
+            0.0
+        }
+    }
+
+    /// Yearly yield of the deposits at the current utilization, compounded continuously
+    pub fn supply_apy(&self) -> f64 {
+        let utilization = self.utilization_rate();
+        let optimal = self.optimal_utilization_rate;
+        let (plateau, max) = (self.plateau_interest_rate, self.max_interest_rate);
+        let borrow_rate = if utilization <= optimal {
+            if optimal > 0.0 {
+                utilization / optimal * plateau
+            } else {
+                plateau
+            }
+        } else {
+            (utilization - optimal) / (1.0 - optimal) * (max - plateau) + plateau
+        };
+        (borrow_rate * utilization).exp_m1()
+    }
+
+    /// Pyth price account of the bank's asset, `None` when priced by another oracle
+    pub fn pyth_price_account(&self) -> Option<Pubkey> {
+        (self.oracle_setup == ORACLE_SETUP_PYTH_LEGACY).then_some(self.oracle)
+    }
+
+    fn token_scale(&self) -> f64 {
+        10f64.powi(self.mint_decimals as i32)
+    }
+}
+
+fn read_pubkey(data: &[u8], offset: usize) -> Pubkey {
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(&data[offset..offset + 32]);
+    Pubkey::new_from_array(bytes)
+}
+
+/// Reads a little-endian `I80F48` fixed point number
+///
+/// marginfi stores all share values and share counts as `I80F48`, i.e. an
+/// `i128` with 48 fractional bits.
+pub fn read_i80f48(data: &[u8], offset: usize) -> f64 {
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&data[offset..offset + 16]);
+    i128::from_le_bytes(bytes) as f64 / (1u64 << 48) as f64
+}
+
+pub async fn fetch_bank() -> Result<Bank, RiskCalculationError> {
+    let rpc_url = rpc_url()?;
+    let client = crate::upstream_fixtures::rpc_client(rpc_url);
+    let MarginfiAccounts {
+        program_id, bank, ..
+    } = MarginfiAccounts::global();
+    let account = client
+        .get_account(bank)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    if account.owner != *program_id {
+        return Err(RiskCalculationError::CustomError(format!(
+            "Bank {} is not owned by the marginfi program",
+            bank
+        )));
+    }
+    Bank::from_account_data(&account.data)
+}
+
+/// Returns the current `(total_borrows, total_supply)` of the bank in token units
+pub async fn get_total_borrows_and_supply() -> Result<(f64, f64), RiskCalculationError> {
+    let bank = fetch_bank().await?;
+    Ok((bank.total_borrows(), bank.total_supply()))
+}
+
+/// Reads borrows and supply from the bank account and DefiLlama and publishes the
+/// figure they agree on
+pub async fn get_total_borrows_and_supply_quorum() -> Result<QuorumReport, RiskCalculationError> {
+    let to_reading = |(total_borrows, total_supply)| SourceReading {
+        total_borrows,
+        total_supply,
+    };
+    // DefiLlama only tracks the mainnet USDC bank
+    if *MarginfiAccounts::global() != MarginfiAccounts::mainnet_usdc() {
+        let on_chain = timed(
+            Timing::Rpc,
+            upstream::call(Upstream::Rpc, get_total_borrows_and_supply()),
+        )
+        .await;
+        return resolve(
+            vec![(DataSource::OnChain, on_chain.map(to_reading))],
+            QUORUM_TOLERANCE,
+            QUORUM_SIZE,
+        );
+    }
+    let (on_chain, defillama) = futures::join!(
+        timed(
+            Timing::Rpc,
+            upstream::call(Upstream::Rpc, get_total_borrows_and_supply())
+        ),
+        timed(
+            Timing::ExternalApi,
+            upstream::call(
+                Upstream::DefiLlama,
+                get_stablecoin_borrows_and_supply("marginfi", "USDC")
+            )
+        ),
+    );
+    resolve(
+        vec![
+            (DataSource::OnChain, on_chain.map(to_reading)),
+            (DataSource::DefiLlama, defillama.map(to_reading)),
+        ],
+        QUORUM_TOLERANCE,
+        QUORUM_SIZE,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_i80f48(data: &mut [u8], offset: usize, value: f64) {
+        let raw = (value * (1u64 << 48) as f64) as i128;
+        data[offset..offset + 16].copy_from_slice(&raw.to_le_bytes());
+    }
+
+    #[test]
+    fn test_parse_bank() {
+        let mut data = vec![0u8; 1864 + 8];
+        data[..8].copy_from_slice(&BANK_DISCRIMINATOR);
+        data[MINT_DECIMALS_OFFSET] = 6;
+        write_i80f48(&mut data, ASSET_SHARE_VALUE_OFFSET, 1.5);
+        write_i80f48(&mut data, LIABILITY_SHARE_VALUE_OFFSET, 2.0);
+        write_i80f48(&mut data, TOTAL_ASSET_SHARES_OFFSET, 4_000_000.0);
+        write_i80f48(&mut data, TOTAL_LIABILITY_SHARES_OFFSET, 1_500_000.0);
+
+        let bank = Bank::from_account_data(&data).unwrap();
+        assert_eq!(bank.mint_decimals, 6);
+        assert_eq!(bank.pyth_price_account(), None);
+        assert_eq!(bank.total_supply(), 6.0);
+        assert_eq!(bank.total_borrows(), 3.0);
+        assert_eq!(bank.utilization_rate(), 0.5);
+        assert_eq!(bank.supply_apy(), 0.0);
+
+        write_i80f48(&mut data, OPTIMAL_UTILIZATION_RATE_OFFSET, 0.8);
+        write_i80f48(&mut data, PLATEAU_INTEREST_RATE_OFFSET, 0.1);
+        write_i80f48(&mut data, MAX_INTEREST_RATE_OFFSET, 1.0);
+        let bank = Bank::from_account_data(&data).unwrap();
+        // A borrow rate of 6.25% on half of the deposits
+        assert!((bank.supply_apy() - (0.0625f64 * 0.5).exp_m1()).abs() < 1e-9);
+
+        let oracle = Pubkey::new_unique();
+        data[ORACLE_SETUP_OFFSET] = ORACLE_SETUP_PYTH_LEGACY;
+        data[ORACLE_KEYS_OFFSET..ORACLE_KEYS_OFFSET + 32].copy_from_slice(oracle.as_ref());
+        let bank = Bank::from_account_data(&data).unwrap();
+        assert_eq!(bank.pyth_price_account(), Some(oracle));
+    }
+
+    #[test]
+    fn test_parse_bank_rejects_wrong_discriminator() {
+        let data = vec![0u8; 1864 + 8];
+        assert!(Bank::from_account_data(&data).is_err());
+    }
+}
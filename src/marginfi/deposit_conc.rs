@@ -0,0 +1,145 @@
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use solana_account_decoder::UiDataSliceConfig;
+use solana_client::{
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
+};
+
+use crate::{
+    risk_model::RiskCalculationError,
+    rpc_pool::{ChunkedFetch, DataCompleteness, RpcPool},
+};
+
+use super::{
+    bank::{fetch_bank, read_i80f48},
+    MarginfiAccounts,
+};
+
+/// Anchor discriminator of the marginfi `MarginfiAccount` account
+pub(super) const MARGINFI_ACCOUNT_DISCRIMINATOR: [u8; 8] = [67, 178, 130, 109, 126, 114, 28, 42];
+
+// `MarginfiAccount` layout: discriminator, group, authority, then the lending account balances
+pub(super) const BALANCES_OFFSET: usize = 8 + 32 + 32;
+pub(super) const MAX_BALANCES: usize = 16;
+pub(super) const BALANCE_SIZE: usize = 104;
+
+// Offsets inside a single `Balance`
+const BALANCE_ACTIVE_OFFSET: usize = 0;
+const BALANCE_BANK_OFFSET: usize = 1;
+const BALANCE_ASSET_SHARES_OFFSET: usize = 40;
+
+/// Fetches the USDC deposits of every marginfi account in the main group
+///
+/// Deposits are returned in native token units (asset shares converted with the
+/// bank's current asset share value), with how many of the accounts were read.
+pub async fn fetch_deposits() -> Result<(Vec<u128>, DataCompleteness), RiskCalculationError> {
+    let pool = RpcPool::global();
+    let MarginfiAccounts {
+        program_id,
+        group,
+        bank: usdc_bank,
+    } = MarginfiAccounts::global().clone();
+    let asset_share_value = fetch_bank().await?.asset_share_value;
+
+    // First get all account public keys without data
+    let fetched_accounts: Vec<Pubkey> = pool
+        .call(|client| {
+            client.get_program_accounts_with_config(
+                &program_id,
+                RpcProgramAccountsConfig {
+                    filters: Some(vec![
+                        RpcFilterType::Memcmp(Memcmp::new(
+                            0,
+                            MemcmpEncodedBytes::Bytes(MARGINFI_ACCOUNT_DISCRIMINATOR.to_vec()),
+                        )),
+                        RpcFilterType::Memcmp(Memcmp::new(
+                            8,
+                            MemcmpEncodedBytes::Bytes(group.to_bytes().to_vec()),
+                        )),
+                    ]),
+                    account_config: RpcAccountInfoConfig {
+                        encoding: None,
+                        data_slice: Some(UiDataSliceConfig {
+                            offset: 0,
+                            length: 0,
+                        }),
+                        commitment: None,
+                        min_context_slot: None,
+                    },
+                    with_context: None,
+                },
+            )
+        })
+        .await
+        .map_err(RiskCalculationError::from)?
+        .into_iter()
+        .map(|(pk, _)| pk)
+        .collect();
+
+    ChunkedFetch::global()
+        .fetch(
+            "marginfi accounts",
+            &fetched_accounts,
+            |pubkeys| async move {
+                let account_infos = pool
+                    .call(|client| {
+                        client.get_multiple_accounts_with_config(
+                            &pubkeys,
+                            RpcAccountInfoConfig {
+                                data_slice: Some(UiDataSliceConfig {
+                                    offset: BALANCES_OFFSET,
+                                    length: MAX_BALANCES * BALANCE_SIZE,
+                                }),
+                                encoding: None,
+                                commitment: None,
+                                min_context_slot: None,
+                            },
+                        )
+                    })
+                    .await?;
+                let mut chunk_deposits = Vec::new();
+                for account_info in account_infos.value.into_iter().flatten() {
+                    let asset_shares = bank_asset_shares(&account_info.data, &usdc_bank);
+                    let deposit = (asset_shares * asset_share_value) as u128;
+                    if deposit > 0 {
+                        chunk_deposits.push(deposit);
+                    }
+                }
+                Ok(chunk_deposits)
+            },
+        )
+        .await
+}
+
+/// Sums the asset shares an account holds in `bank` across its active balances
+pub(super) fn bank_asset_shares(balances: &[u8], bank: &Pubkey) -> f64 {
+    balances
+        .chunks_exact(BALANCE_SIZE)
+        .filter(|balance| balance[BALANCE_ACTIVE_OFFSET] != 0)
+        .filter(|balance| &balance[BALANCE_BANK_OFFSET..BALANCE_BANK_OFFSET + 32] == bank.as_ref())
+        .map(|balance| read_i80f48(balance, BALANCE_ASSET_SHARES_OFFSET))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bank_asset_shares() {
+        let bank = Pubkey::new_unique();
+        let other_bank = Pubkey::new_unique();
+        let mut balances = vec![0u8; MAX_BALANCES * BALANCE_SIZE];
+        for (i, (pk, active, shares)) in [(bank, 1, 10i128), (other_bank, 1, 5), (bank, 0, 7)]
+            .iter()
+            .enumerate()
+        {
+            let balance = &mut balances[i * BALANCE_SIZE..(i + 1) * BALANCE_SIZE];
+            balance[BALANCE_ACTIVE_OFFSET] = *active;
+            balance[BALANCE_BANK_OFFSET..BALANCE_BANK_OFFSET + 32].copy_from_slice(pk.as_ref());
+            balance[BALANCE_ASSET_SHARES_OFFSET..BALANCE_ASSET_SHARES_OFFSET + 16]
+                .copy_from_slice(&(shares << 48).to_le_bytes());
+        }
+        assert_eq!(bank_asset_shares(&balances, &bank), 10.0);
+    }
+}
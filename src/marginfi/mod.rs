@@ -0,0 +1,399 @@
+use std::{str::FromStr, sync::OnceLock};
+
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use bank::{fetch_bank, get_total_borrows_and_supply_quorum};
+use deposit_conc::fetch_deposits;
+use tracing::info;
+use yield_data::{fetch_yield_and_utilization_rates, HOURLY_POINTS};
+
+use crate::{
+    cluster::Cluster,
+    liquidity_risk::liquidity_metrics,
+    market_history::load_market_history,
+    oracle_risk::{fetch_oracle_risk, OracleFeed, OracleRiskMetrics},
+    protocol_rubric::ProtocolRubric,
+    quorum::QuorumReport,
+    risk_model::{
+        LiquidityRiskMetrics, Protocol, ProtocolRisk, ProtocolRiskMetrics, RiskCalculationError,
+        VolatilityRiskMetrics,
+    },
+    timings::{timed, timed_sync, Timing},
+    upstream::{self, Upstream},
+    volatility_risk::{calculate_volatility_surface_from, SampledHistory},
+};
+
+mod bank;
+mod deposit_conc;
+pub mod positions;
+pub mod yield_data;
+
+/// Marginfi main group on mainnet
+pub const MARGINFI_MAIN_GROUP: &str = "4qp6Fx6tnZkY5Wropq9wUYgtFxXKwE6viZxFHg3rdAG8";
+/// USDC bank of the main group on mainnet
+pub const MARGINFI_USDC_BANK: &str = "2s37akK2eyBbp8DZgCm7RtsaEz8eJP3Nxd4urLHQv7yB";
+/// Scope of marginfi's snapshots and stored market history
+pub const MARGINFI_SCOPE: &str = "marginfi";
+
+static MARGINFI_ACCOUNTS: OnceLock<MarginfiAccounts> = OnceLock::new();
+
+/// Program, group and bank marginfi risk is computed for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MarginfiAccounts {
+    pub program_id: Pubkey,
+    pub group: Pubkey,
+    pub bank: Pubkey,
+}
+
+impl MarginfiAccounts {
+    /// The accounts read at startup
+    pub fn global() -> &'static Self {
+        MARGINFI_ACCOUNTS.get_or_init(|| {
+            Self::from_env().expect("MARGINFI_GROUP and MARGINFI_BANK must be valid")
+        })
+    }
+
+    /// The USDC bank of the main group on mainnet
+    pub fn mainnet_usdc() -> Self {
+        MarginfiAccounts {
+            program_id: Pubkey::from_str(Cluster::MainnetBeta.marginfi_program_id())
+                .expect("valid marginfi program"),
+            group: Pubkey::from_str(MARGINFI_MAIN_GROUP).expect("valid main group"),
+            bank: Pubkey::from_str(MARGINFI_USDC_BANK).expect("valid USDC bank"),
+        }
+    }
+
+    /// Reads `MARGINFI_GROUP` and `MARGINFI_BANK`
+    ///
+    /// Mainnet defaults to the USDC bank of the main group, other clusters have
+    /// no default and need both.
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let cluster = Cluster::global();
+        let (group, bank) = match (
+            std::env::var("MARGINFI_GROUP"),
+            std::env::var("MARGINFI_BANK"),
+        ) {
+            (Ok(group), Ok(bank)) => (group, bank),
+            _ if cluster == Cluster::MainnetBeta => (
+                MARGINFI_MAIN_GROUP.to_string(),
+                MARGINFI_USDC_BANK.to_string(),
+            ),
+            _ => {
+                return Err(RiskCalculationError::ParseError(format!(
+                    "MARGINFI_GROUP and MARGINFI_BANK must be set on {}",
+                    cluster.as_str()
+                )))
+            }
+        };
+        let parse = |name: &str, pubkey: &str| {
+            Pubkey::from_str(pubkey.trim())
+                .map_err(|e| RiskCalculationError::ParseError(format!("{}: {}", name, e)))
+        };
+        Ok(MarginfiAccounts {
+            program_id: parse("marginfi program", cluster.marginfi_program_id())?,
+            group: parse("MARGINFI_GROUP", &group)?,
+            bank: parse("MARGINFI_BANK", &bank)?,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct MarginfiRisk {
+    pub redis_client: redis::Client,
+}
+
+impl ProtocolRisk for MarginfiRisk {
+    fn redis_client(&self) -> &redis::Client {
+        &self.redis_client
+    }
+    fn protocol(&self) -> Protocol {
+        Protocol::Marginfy
+    }
+    fn cache_namespace(&self) -> String {
+        format!("marginfi:{}", MarginfiAccounts::global().bank)
+    }
+    async fn calculate_liquidity_risk(&self) -> Result<LiquidityRiskMetrics, RiskCalculationError> {
+        // Try to get cached deposit data
+        let largest_deposit_key = "deposits:largest";
+        let total_deposits_key = "deposits:total";
+        let completeness_key = "deposits:completeness";
+
+        let (largest_deposit, total_deposits, data_completeness) = if let (Ok(largest), Ok(total)) = (
+            self.cache_get(largest_deposit_key).await,
+            self.cache_get(total_deposits_key).await,
+        ) {
+            (
+                largest
+                    .parse::<u128>()
+                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                total
+                    .parse::<u128>()
+                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                self.cache_get(completeness_key)
+                    .await
+                    .ok()
+                    .and_then(|completeness| serde_json::from_str(&completeness).ok()),
+            )
+        } else {
+            info!("Fetching marginfi deposits...");
+            let (deposits, completeness) =
+                timed(Timing::Rpc, upstream::call(Upstream::Rpc, fetch_deposits())).await?;
+            if !completeness.is_complete() {
+                tracing::warn!(
+                    "Marginfi deposits scan missing {} of {} accounts",
+                    completeness.missing_accounts,
+                    completeness.accounts
+                );
+            }
+            let largest = *deposits
+                .iter()
+                .max()
+                .ok_or(RiskCalculationError::CustomError(
+                    "No deposits found".to_string(),
+                ))?;
+            let total = deposits.iter().sum::<u128>();
+
+            // Cache deposits data
+            self.cache_set_liquidity_input(largest_deposit_key, &largest.to_string())
+                .await?;
+            self.cache_set_liquidity_input(total_deposits_key, &total.to_string())
+                .await?;
+            self.cache_set_liquidity_input(
+                completeness_key,
+                &serde_json::to_string(&completeness)
+                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+            )
+            .await?;
+
+            (largest, total, Some(completeness))
+        };
+
+        // Try to get the cached borrows and supply quorum
+        let quorum_key = "utilization:quorum";
+
+        let data_quorum: QuorumReport = if let Ok(cached) = self.cache_get(quorum_key).await {
+            serde_json::from_str(&cached)
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?
+        } else {
+            info!("Fetching marginfi borrows and supply from all sources...");
+            let data_quorum = get_total_borrows_and_supply_quorum().await?;
+
+            // Cache the published figure together with how the sources compared
+            self.cache_set_liquidity_input(
+                quorum_key,
+                &serde_json::to_string(&data_quorum)
+                    .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+            )
+            .await?;
+
+            data_quorum
+        };
+        let (total_borrows, total_supply) = (data_quorum.total_borrows, data_quorum.total_supply);
+
+        info!("Calculating marginfi liquidity risk...");
+        let metrics = timed_sync(Timing::Compute, || {
+            liquidity_metrics(
+                largest_deposit,
+                total_deposits,
+                total_borrows,
+                total_supply,
+                None,
+                None,
+                &self.weights().liquidity,
+            )
+        })?;
+
+        Ok(LiquidityRiskMetrics {
+            data_completeness,
+            data_quorum: Some(data_quorum),
+            ..metrics
+        })
+    }
+
+    async fn calculate_volatility_risk(
+        &self,
+    ) -> Result<VolatilityRiskMetrics, RiskCalculationError> {
+        let yields_key = "volatility:yields";
+        let utilization_rates_key = "volatility:utilization_rates";
+        let history_window_key = "volatility:history_window";
+
+        let (yields_percent, utilization_rates_percent, history_window) =
+            if let (Ok(yields), Ok(util_rates)) = (
+                self.cache_get(yields_key).await,
+                self.cache_get(utilization_rates_key).await,
+            ) {
+                (
+                    serde_json::from_str(&yields)
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                    serde_json::from_str(&util_rates)
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                    // Not cached along the history before it was kept
+                    self.cache_get(history_window_key)
+                        .await
+                        .ok()
+                        .and_then(|window| serde_json::from_str(&window).ok()),
+                )
+            } else {
+                info!("Fetching marginfi yield and utilization rates...");
+                let data = timed(
+                    Timing::ExternalApi,
+                    upstream::call(Upstream::DefiLlama, fetch_yield_and_utilization_rates()),
+                )
+                .await?;
+                info!(
+                    "Fetched marginfi history from {} to {}",
+                    data.start, data.end
+                );
+
+                self.cache_set_until_next_hour(
+                    yields_key,
+                    &serde_json::to_string(&data.yields_percent)
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                )
+                .await?;
+                self.cache_set_until_next_hour(
+                    utilization_rates_key,
+                    &serde_json::to_string(&data.utilization_rates_percent)
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                )
+                .await?;
+                self.cache_set_until_next_hour(
+                    history_window_key,
+                    &serde_json::to_string(&data.history_window)
+                        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?,
+                )
+                .await?;
+
+                (
+                    data.yields_percent,
+                    data.utilization_rates_percent,
+                    Some(data.history_window),
+                )
+            };
+
+        // The daily history is too coarse for the shortest lookback, it's taken
+        // from the hours recorded from the bank
+        let now = self.clock().now();
+        let hourly = load_market_history(
+            &self.redis_client,
+            MARGINFI_SCOPE,
+            now - chrono::Duration::hours(HOURLY_POINTS as i64),
+            now,
+        )
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("Failed to load the hourly marginfi history: {}", e);
+            Vec::new()
+        });
+        let hourly_yields: Vec<f64> = hourly.iter().map(|p| p.supply_apy_percent).collect();
+        let hourly_utilization_rates: Vec<f64> =
+            hourly.iter().map(|p| p.utilization_percent).collect();
+
+        info!("Calculating marginfi volatility risk...");
+        timed_sync(Timing::Compute, || {
+            calculate_volatility_surface_from(
+                &[
+                    SampledHistory {
+                        yields: &hourly_yields,
+                        utilization_rates: &hourly_utilization_rates,
+                        sample_interval_hours: 1,
+                    },
+                    // DefiLlama history is daily
+                    SampledHistory {
+                        yields: &yields_percent,
+                        utilization_rates: &utilization_rates_percent,
+                        sample_interval_hours: 24,
+                    },
+                ],
+                self.weights().volatility.apy,
+                self.weights().volatility.utilization,
+                &self.weights().volatility_blend,
+            )
+        })
+        .map(|metrics| VolatilityRiskMetrics {
+            history_window,
+            ..metrics
+        })
+        .ok_or(RiskCalculationError::CustomError(
+            "Insufficient data".to_string(),
+        ))
+    }
+
+    async fn calculate_protocol_risk(&self) -> Result<ProtocolRiskMetrics, RiskCalculationError> {
+        ProtocolRubric::global().protocol_risk(&Protocol::Marginfy, self.clock().now().date_naive())
+    }
+
+    async fn calculate_oracle_risk(&self) -> Result<OracleRiskMetrics, RiskCalculationError> {
+        let bank = timed(Timing::Rpc, upstream::call(Upstream::Rpc, fetch_bank())).await?;
+        fetch_oracle_risk(&[OracleFeed::of_mint(&bank.mint, bank.pyth_price_account())?]).await
+    }
+}
+
+#[cfg(test)]
+mod marginfi_tests {
+    use solana_sdk::pubkey::Pubkey;
+
+    use super::{bank::Bank, yield_data::fixture_yield_and_utilization_rates};
+    use crate::{
+        liquidity_risk::{
+            calculate_concentration, calculate_liquidity_risk, calculate_utilization_rate,
+        },
+        volatility_risk::calculate_lending_pool_risk,
+    };
+
+    #[test]
+    fn test_liquidity_risk() {
+        let bank = Bank {
+            mint: Pubkey::new_unique(),
+            mint_decimals: 6,
+            asset_share_value: 1.05,
+            liability_share_value: 1.2,
+            total_asset_shares: 40_000_000e6,
+            total_liability_shares: 28_000_000e6,
+            optimal_utilization_rate: 0.8,
+            plateau_interest_rate: 0.1,
+            max_interest_rate: 1.0,
+            oracle_setup: 0,
+            oracle: Pubkey::default(),
+        };
+        // Deposits are asset shares valued at the bank's asset share value
+        let deposits = [4_000_000e6f64, 1_000_000e6, 250_000e6, 750_000e6]
+            .iter()
+            .map(|shares| (shares * bank.asset_share_value) as u128)
+            .collect();
+        let deposit_concentration = calculate_concentration(deposits).unwrap();
+        assert!((deposit_concentration - 4.0 / 6.0).abs() < 1e-6);
+
+        let utilization_rate =
+            calculate_utilization_rate(bank.total_borrows(), bank.total_supply()).unwrap();
+        assert!((utilization_rate - 80.0).abs() < 1e-9);
+        assert!((utilization_rate / 100.0 - bank.utilization_rate()).abs() < 1e-12);
+
+        let liquidity_risk =
+            calculate_liquidity_risk(deposit_concentration, utilization_rate, 0.6, 0.4);
+        assert!((0.0..=100.0).contains(&liquidity_risk));
+    }
+
+    #[test]
+    fn test_calculate_sigma_apy() {
+        let data = fixture_yield_and_utilization_rates().unwrap();
+        // The last 30 days, the missing one repeats the day before it
+        assert_eq!(data.yields_percent.len(), 30);
+        assert_eq!(data.utilization_rates_percent.len(), 30);
+        assert_eq!(data.history_window.filled_gaps, 1);
+        assert_eq!(data.start.to_rfc3339(), "2024-04-06T23:01:12+00:00");
+        assert_eq!(data.end.to_rfc3339(), "2024-05-05T23:01:12+00:00");
+        assert!(data
+            .utilization_rates_percent
+            .iter()
+            .all(|rate| (70.0..90.0).contains(rate)));
+
+        let risk = calculate_lending_pool_risk(
+            data.yields_percent,
+            data.utilization_rates_percent,
+            0.7,
+            0.3,
+        )
+        .unwrap();
+        assert!(risk.sigma_apy > 0.0 && risk.sigma_utilization > 0.0);
+    }
+}
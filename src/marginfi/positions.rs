@@ -0,0 +1,73 @@
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use solana_account_decoder::UiDataSliceConfig;
+use solana_client::{
+    rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+    rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
+};
+
+use crate::{cluster::rpc_url, risk_model::RiskCalculationError};
+
+use super::{
+    bank::fetch_bank,
+    deposit_conc::{
+        bank_asset_shares, BALANCES_OFFSET, BALANCE_SIZE, MARGINFI_ACCOUNT_DISCRIMINATOR,
+        MAX_BALANCES,
+    },
+    MarginfiAccounts,
+};
+
+/// Offset of the `authority` field of a `MarginfiAccount`
+const AUTHORITY_OFFSET: usize = 8 + 32;
+
+/// USDC a wallet has deposited in the main group, in native token units
+///
+/// Sums the USDC balances of every marginfi account the wallet is the authority of.
+pub async fn fetch_wallet_deposit(wallet: &Pubkey) -> Result<u64, RiskCalculationError> {
+    let rpc_url = rpc_url()?;
+    let client = crate::upstream_fixtures::rpc_client(rpc_url);
+    let MarginfiAccounts {
+        program_id,
+        group,
+        bank: usdc_bank,
+    } = MarginfiAccounts::global().clone();
+    let asset_share_value = fetch_bank().await?.asset_share_value;
+
+    let accounts = client
+        .get_program_accounts_with_config(
+            &program_id,
+            RpcProgramAccountsConfig {
+                filters: Some(vec![
+                    RpcFilterType::Memcmp(Memcmp::new(
+                        0,
+                        MemcmpEncodedBytes::Bytes(MARGINFI_ACCOUNT_DISCRIMINATOR.to_vec()),
+                    )),
+                    RpcFilterType::Memcmp(Memcmp::new(
+                        8,
+                        MemcmpEncodedBytes::Bytes(group.to_bytes().to_vec()),
+                    )),
+                    RpcFilterType::Memcmp(Memcmp::new(
+                        AUTHORITY_OFFSET,
+                        MemcmpEncodedBytes::Bytes(wallet.to_bytes().to_vec()),
+                    )),
+                ]),
+                account_config: RpcAccountInfoConfig {
+                    encoding: None,
+                    data_slice: Some(UiDataSliceConfig {
+                        offset: BALANCES_OFFSET,
+                        length: MAX_BALANCES * BALANCE_SIZE,
+                    }),
+                    commitment: None,
+                    min_context_slot: None,
+                },
+                with_context: None,
+            },
+        )
+        .await
+        .map_err(RiskCalculationError::from)?;
+
+    let asset_shares: f64 = accounts
+        .iter()
+        .map(|(_, account)| bank_asset_shares(&account.data, &usdc_bank))
+        .sum();
+    Ok((asset_shares * asset_share_value) as u64)
+}
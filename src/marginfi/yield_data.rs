@@ -0,0 +1,160 @@
+use chrono::{DateTime, DurationRound, Utc};
+use serde::Deserialize;
+
+use crate::{
+    defillama::find_pool,
+    market_history::{record_market_points, MarketPoint},
+    risk_model::RiskCalculationError,
+    upstream::{self, Upstream},
+    upstream_fixtures::get_text,
+    volatility_risk::{fill_history_gaps, HistoryWindow, Lookback},
+};
+
+use super::{bank::fetch_bank, MARGINFI_SCOPE};
+
+/// marginfi does not publish a metrics history API, so the yield and
+/// utilization history is taken from DefiLlama's lend/borrow pool charts.
+const DEFILLAMA_CHART_URL: &str = "https://yields.llama.fi/chartLendBorrow";
+
+/// Number of most recent (daily) data points used for volatility, covering the longest lookback
+const HISTORY_POINTS: usize = (Lookback::LONGEST_HOURS / 24) as usize;
+/// Number of most recent hourly points used for volatility, covering the 24h lookback
+pub const HOURLY_POINTS: usize = 24;
+
+#[derive(Debug, Deserialize)]
+struct ChartResponse {
+    data: Vec<ChartEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChartEntry {
+    timestamp: DateTime<Utc>,
+    total_supply_usd: Option<f64>,
+    total_borrow_usd: Option<f64>,
+    apy_base: Option<f64>,
+}
+
+#[derive(Debug)]
+pub struct YieldData {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub yields_percent: Vec<f64>,
+    pub utilization_rates_percent: Vec<f64>,
+    /// The history actually returned, with its gaps filled
+    pub history_window: HistoryWindow,
+}
+
+/// Daily entries of the mainnet USDC pool, oldest first
+///
+/// DefiLlama only tracks mainnet, deployments on other clusters get the
+/// history of the mainnet USDC pool.
+async fn fetch_chart() -> Result<Vec<ChartEntry>, RiskCalculationError> {
+    let pool_id = find_pool("marginfi", "USDC").await?.pool;
+    let url = format!("{}/{}", DEFILLAMA_CHART_URL, pool_id);
+
+    parse_chart(&get_text(&url).await?)
+}
+
+/// Entries of a DefiLlama lend/borrow chart that have a yield
+fn parse_chart(raw_data: &str) -> Result<Vec<ChartEntry>, RiskCalculationError> {
+    let chart: ChartResponse =
+        serde_json::from_str(raw_data).map_err(RiskCalculationError::SerdeError)?;
+
+    Ok(chart
+        .data
+        .into_iter()
+        .filter(|entry| entry.apy_base.is_some())
+        .collect())
+}
+
+fn utilization_rate_percent(entry: &ChartEntry) -> f64 {
+    match (entry.total_borrow_usd, entry.total_supply_usd) {
+        (Some(borrows), Some(supply)) if supply > 0.0 => (borrows / supply) * 100.0,
+        _ => 0.0,
+    }
+}
+
+fn market_point(entry: &ChartEntry) -> MarketPoint {
+    MarketPoint {
+        timestamp: entry.timestamp,
+        supply_apy_percent: entry.apy_base.unwrap_or_default(), // Already a percentage
+        utilization_percent: utilization_rate_percent(entry),
+        tvl_usd: entry.total_supply_usd,
+    }
+}
+
+pub async fn fetch_yield_and_utilization_rates() -> Result<YieldData, RiskCalculationError> {
+    chart_yield_data(&fetch_chart().await?)
+}
+
+/// Yields and utilization rates of the last [`HISTORY_POINTS`] days of `entries`
+fn chart_yield_data(entries: &[ChartEntry]) -> Result<YieldData, RiskCalculationError> {
+    let Some(latest) = entries.last().map(|entry| entry.timestamp) else {
+        return Err(RiskCalculationError::UpstreamUnavailable(
+            "No yield data available".to_string(),
+        ));
+    };
+    // The days covered by the last HISTORY_POINTS points, had none been missing
+    let since = latest - chrono::Duration::days(HISTORY_POINTS as i64);
+    let points: Vec<MarketPoint> = entries
+        .iter()
+        .filter(|entry| entry.timestamp > since)
+        .map(market_point)
+        .collect();
+    let Some((points, history_window)) = fill_history_gaps(&points, 24) else {
+        return Err(RiskCalculationError::UpstreamUnavailable(
+            "No yield data available".to_string(),
+        ));
+    };
+    if history_window.filled_gaps > 0 {
+        tracing::warn!(
+            "Filled {} missing days of the marginfi history",
+            history_window.filled_gaps
+        );
+    }
+
+    Ok(YieldData {
+        start: history_window.start,
+        end: history_window.end,
+        yields_percent: points.iter().map(|p| p.supply_apy_percent).collect(),
+        utilization_rates_percent: points.iter().map(|p| p.utilization_percent).collect(),
+        history_window,
+    })
+}
+
+/// Yields and utilization rates of a recorded chart of the USDC pool in
+/// `fixtures/`, missing a day
+#[cfg(test)]
+pub fn fixture_yield_and_utilization_rates() -> Result<YieldData, RiskCalculationError> {
+    chart_yield_data(&parse_chart(include_str!("fixtures/usdc_chart.json"))?)
+}
+
+/// Daily market points of the pool since `from`, oldest first
+pub async fn fetch_market_points(
+    from: DateTime<Utc>,
+) -> Result<Vec<MarketPoint>, RiskCalculationError> {
+    Ok(fetch_chart()
+        .await?
+        .iter()
+        .filter(|entry| entry.timestamp >= from)
+        .map(market_point)
+        .collect())
+}
+
+/// Records the current hour of the bank into the market history
+///
+/// DefiLlama only has a daily history of marginfi, the hourly one the short
+/// volatility lookback needs is built up by the hourly refresh.
+pub async fn record_hourly_point(redis_client: &redis::Client) -> Result<(), RiskCalculationError> {
+    let bank = upstream::call(Upstream::Rpc, fetch_bank()).await?;
+    let point = MarketPoint {
+        timestamp: Utc::now()
+            .duration_trunc(chrono::Duration::hours(1))
+            .map_err(|e| RiskCalculationError::CustomError(e.to_string()))?,
+        supply_apy_percent: bank.supply_apy() * 100.0,
+        utilization_percent: bank.utilization_rate() * 100.0,
+        tvl_usd: None,
+    };
+    record_market_points(redis_client, MARGINFI_SCOPE, &[point]).await
+}
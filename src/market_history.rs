@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::Asset,
+    cache_schema::versioned_key,
+    defillama::fetch_tvl_history,
+    kamino::{
+        reserve::{KaminoReserveConfig, KAMINO_MAIN_MARKET},
+        reserve_account::fetch_reserve_account,
+        yield_data::fetch_metrics_history,
+    },
+    redis_connection::shared_connection,
+    registry::RegisteredProtocol,
+    risk_model::{Protocol, RiskCalculationError},
+};
+
+/// How long market history is kept, and how far back a fresh deployment backfills
+const MARKET_HISTORY_DAYS: i64 = 90;
+/// History re-fetched by the hourly refresh, so late corrections of the sources land too
+const REFRESH_DAYS: i64 = 1;
+
+/// Yield and utilization of a pool at one hour
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MarketPoint {
+    pub timestamp: DateTime<Utc>,
+    pub supply_apy_percent: f64,
+    pub utilization_percent: f64,
+    /// TVL of the point's day, DefiLlama only reports daily values
+    pub tvl_usd: Option<f64>,
+}
+
+/// Sorted set of a scope's market history, scored by the unix timestamp of each point
+pub fn market_history_key(scope: &str) -> String {
+    versioned_key(&format!("market_history:{}", scope))
+}
+
+/// Stores points of a scope, replacing stored points of the same timestamps
+///
+/// Points older than the retention are dropped.
+pub async fn record_market_points(
+    redis_client: &redis::Client,
+    scope: &str,
+    points: &[MarketPoint],
+) -> Result<(), RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let key = market_history_key(scope);
+    let retention_start = Utc::now() - Duration::days(MARKET_HISTORY_DAYS);
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for point in points {
+        let timestamp = point.timestamp.timestamp();
+        pipe.zrembyscore(&key, timestamp, timestamp)
+            .ignore()
+            .zadd(
+                &key,
+                serde_json::to_string(point).map_err(RiskCalculationError::SerdeError)?,
+                timestamp,
+            )
+            .ignore();
+    }
+    pipe.zrembyscore(&key, "-inf", format!("({}", retention_start.timestamp()))
+        .ignore();
+    let _: () = pipe
+        .query_async(&mut connection)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    Ok(())
+}
+
+/// Reads the market history of a scope between `from` and `to` (inclusive), oldest first
+pub async fn load_market_history(
+    redis_client: &redis::Client,
+    scope: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<MarketPoint>, RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let points: Vec<String> = connection
+        .zrangebyscore(market_history_key(scope), from.timestamp(), to.timestamp())
+        .await
+        .map_err(RiskCalculationError::from)?;
+    points
+        .iter()
+        .map(|point| serde_json::from_str(point).map_err(RiskCalculationError::SerdeError))
+        .collect()
+}
+
+/// Sets the TVL of each point to the TVL of its day, where known
+pub fn apply_daily_tvl(points: &mut [MarketPoint], tvl: &[(DateTime<Utc>, f64)]) {
+    let tvl_by_day: HashMap<NaiveDate, f64> = tvl
+        .iter()
+        .map(|(timestamp, tvl_usd)| (timestamp.date_naive(), *tvl_usd))
+        .collect();
+    for point in points {
+        point.tvl_usd = tvl_by_day.get(&point.timestamp.date_naive()).copied();
+    }
+}
+
+/// Hourly points of a Kamino reserve over the last `days`
+pub async fn fetch_kamino_points(
+    reserve: &KaminoReserveConfig,
+    days: i64,
+) -> Result<Vec<MarketPoint>, RiskCalculationError> {
+    let end = Utc::now();
+    fetch_kamino_range(reserve, end - Duration::days(days), end).await
+}
+
+/// Daily TVL of a Kamino reserve, from the DefiLlama pool of its liquidity
+///
+/// DefiLlama only tracks the main market, one pool per asset.
+async fn fetch_kamino_tvl_history(
+    reserve: &KaminoReserveConfig,
+) -> Result<Vec<(DateTime<Utc>, f64)>, RiskCalculationError> {
+    if reserve.market.to_string() != KAMINO_MAIN_MARKET {
+        return Err(RiskCalculationError::UpstreamUnavailable(format!(
+            "DefiLlama doesn't track Kamino market {}",
+            reserve.market
+        )));
+    }
+    let liquidity_mint = fetch_reserve_account(reserve).await?.liquidity_mint;
+    let asset = Asset::from_mint(&liquidity_mint).ok_or_else(|| {
+        RiskCalculationError::UpstreamUnavailable(format!(
+            "DefiLlama pool of mint {} is unknown",
+            liquidity_mint
+        ))
+    })?;
+    fetch_tvl_history("kamino-lend", asset.symbol()).await
+}
+
+/// Hourly points of a Kamino reserve between `start` and `end`
+///
+/// TVL is taken from DefiLlama, points are kept without it when that fails.
+pub async fn fetch_kamino_range(
+    reserve: &KaminoReserveConfig,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<MarketPoint>, RiskCalculationError> {
+    let (metrics, tvl) = futures::join!(
+        fetch_metrics_history(reserve, start, end),
+        fetch_kamino_tvl_history(reserve),
+    );
+    let mut points = metrics?
+        .history
+        .into_iter()
+        .map(|entry| {
+            Ok(MarketPoint {
+                timestamp: entry.timestamp()?,
+                supply_apy_percent: entry.metrics.supply_interest_apy * 100.0,
+                utilization_percent: entry.metrics.utilization_rate_percent()?,
+                tvl_usd: None,
+            })
+        })
+        .collect::<Result<Vec<_>, RiskCalculationError>>()?;
+    match tvl {
+        Ok(tvl) => apply_daily_tvl(&mut points, &tvl),
+        Err(e) => tracing::error!("Failed to fetch Kamino TVL history: {}", e),
+    }
+    Ok(points)
+}
+
+fn kamino_scope(
+    redis_client: &redis::Client,
+    reserve: &KaminoReserveConfig,
+) -> Result<String, RiskCalculationError> {
+    Ok(
+        RegisteredProtocol::for_protocol(&Protocol::Kamino, redis_client.clone(), reserve.clone())?
+            .scope(),
+    )
+}
+
+/// Hourly points of a Kamino reserve between `from` and `to`, oldest first
+///
+/// Served from the stored history when it reaches back to `from`, otherwise
+/// fetched from the Kamino API, which goes back further than the retention.
+pub async fn kamino_history(
+    redis_client: &redis::Client,
+    reserve: &KaminoReserveConfig,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<MarketPoint>, RiskCalculationError> {
+    let stored = load_market_history(
+        redis_client,
+        &kamino_scope(redis_client, reserve)?,
+        from,
+        to,
+    )
+    .await?;
+    if stored
+        .first()
+        .is_some_and(|first| first.timestamp <= from + Duration::hours(1))
+    {
+        return Ok(stored);
+    }
+    Ok(fetch_kamino_range(reserve, from, to)
+        .await?
+        .into_iter()
+        .filter(|point| (from..=to).contains(&point.timestamp))
+        .collect())
+}
+
+/// Backfills the market history of the configured Kamino reserve on a fresh deployment
+///
+/// Long lookbacks would otherwise only work after weeks of hourly refreshes.
+/// Does nothing when the stored history already reaches back the retention.
+pub async fn bootstrap(redis_client: &redis::Client) -> Result<(), RiskCalculationError> {
+    let reserve = KaminoReserveConfig::from_env()?;
+    let scope = kamino_scope(redis_client, &reserve)?;
+    let now = Utc::now();
+    let covered_from = now - Duration::days(MARKET_HISTORY_DAYS - 1);
+    if !load_market_history(
+        redis_client,
+        &scope,
+        now - Duration::days(MARKET_HISTORY_DAYS),
+        covered_from,
+    )
+    .await?
+    .is_empty()
+    {
+        tracing::info!("Market history of {} already bootstrapped", scope);
+        return Ok(());
+    }
+
+    let points = fetch_kamino_points(&reserve, MARKET_HISTORY_DAYS).await?;
+    record_market_points(redis_client, &scope, &points).await?;
+    tracing::info!(
+        "Bootstrapped {} points of market history for {}",
+        points.len(),
+        scope
+    );
+    Ok(())
+}
+
+/// Appends the latest points of the configured Kamino reserve, run by the hourly refresh
+pub async fn refresh_market_history(
+    redis_client: &redis::Client,
+    reserve: &KaminoReserveConfig,
+) -> Result<(), RiskCalculationError> {
+    let points = fetch_kamino_points(reserve, REFRESH_DAYS).await?;
+    record_market_points(redis_client, &kamino_scope(redis_client, reserve)?, &points).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_daily_tvl() {
+        let at = |timestamp: &str| timestamp.parse::<DateTime<Utc>>().unwrap();
+        let point = |timestamp| MarketPoint {
+            timestamp: at(timestamp),
+            supply_apy_percent: 5.0,
+            utilization_percent: 80.0,
+            tvl_usd: None,
+        };
+        let mut points = vec![
+            point("2024-05-01T00:00:00Z"),
+            point("2024-05-01T23:00:00Z"),
+            point("2024-05-02T00:00:00Z"),
+        ];
+        apply_daily_tvl(&mut points, &[(at("2024-05-01T00:00:00Z"), 1_000_000.0)]);
+
+        assert_eq!(points[0].tvl_usd, Some(1_000_000.0));
+        assert_eq!(points[1].tvl_usd, Some(1_000_000.0));
+        assert_eq!(points[2].tvl_usd, None);
+        assert_eq!(market_history_key("marginfi"), "v2:market_history:marginfi");
+    }
+}
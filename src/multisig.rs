@@ -0,0 +1,530 @@
+use std::str::FromStr;
+
+use anchor_client::solana_sdk::{pubkey::Pubkey, signature::Signature};
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::Response,
+};
+use chrono::{DateTime, Duration, Utc};
+use redis::AsyncCommands;
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    cache_schema::record_key,
+    cluster::{rpc_url, Cluster},
+    redis_connection::shared_connection,
+    risk_model::{json_response, timings_requested, RiskCalculationError},
+    state::AppState,
+    timings::{timed, with_timings, Timing},
+    upstream::{self, Upstream},
+};
+
+/// Squads v4 program, deployed under the same id on both clusters
+pub const SQUADS_PROGRAM_ID: &str = "SQDS4ep65T869zMMBKyuUq6SqA2vZ4PSrsiyDpPVZxG1";
+/// Header carrying the [`MultisigApproval`] of commands without a body, as JSON
+pub const APPROVAL_HEADER: &str = "x-multisig-approval";
+/// Approvals can't be valid for longer than this, which bounds how long nonces are kept
+const MAX_APPROVAL_LIFETIME_HOURS: i64 = 24;
+/// Audit events kept in the log, older ones are dropped
+const MAX_STORED_AUDIT_EVENTS: isize = 1000;
+/// Audit events returned when `limit` isn't given
+const DEFAULT_AUDIT_LIMIT: usize = 50;
+
+// Byte offsets into the Squads v4 `Multisig` account data (including the 8 byte discriminator)
+const THRESHOLD_OFFSET: usize = 72;
+const RENT_COLLECTOR_OFFSET: usize = 94;
+/// Member permission allowing to vote on proposals
+const VOTE_PERMISSION: u8 = 1 << 1;
+
+/// The voting setup of a Squads multisig
+#[derive(Debug, Clone, PartialEq)]
+pub struct SquadsMultisig {
+    pub address: Pubkey,
+    pub threshold: u16,
+    /// Members with the vote permission, only their signatures count
+    pub voters: Vec<Pubkey>,
+}
+
+impl SquadsMultisig {
+    pub fn from_account_data(address: Pubkey, data: &[u8]) -> Result<Self, RiskCalculationError> {
+        let invalid = || {
+            RiskCalculationError::ParseError(format!(
+                "{} is not a Squads v4 multisig account",
+                address
+            ))
+        };
+        let discriminator =
+            &anchor_client::solana_sdk::hash::hash(b"account:Multisig").to_bytes()[..8];
+        if data.get(..8) != Some(discriminator) {
+            return Err(invalid());
+        }
+        let threshold = u16::from_le_bytes(
+            data.get(THRESHOLD_OFFSET..THRESHOLD_OFFSET + 2)
+                .ok_or_else(invalid)?
+                .try_into()
+                .map_err(|_| invalid())?,
+        );
+        // `rent_collector: Option<Pubkey>` then `bump: u8`, then the members
+        let mut offset = match data.get(RENT_COLLECTOR_OFFSET).ok_or_else(invalid)? {
+            0 => RENT_COLLECTOR_OFFSET + 1,
+            _ => RENT_COLLECTOR_OFFSET + 1 + 32,
+        } + 1;
+        let member_count = u32::from_le_bytes(
+            data.get(offset..offset + 4)
+                .ok_or_else(invalid)?
+                .try_into()
+                .map_err(|_| invalid())?,
+        );
+        offset += 4;
+        let mut voters = Vec::new();
+        for _ in 0..member_count {
+            let member = data.get(offset..offset + 33).ok_or_else(invalid)?;
+            if member[32] & VOTE_PERMISSION != 0 {
+                voters.push(Pubkey::try_from(&member[..32]).map_err(|_| invalid())?);
+            }
+            offset += 33;
+        }
+        Ok(SquadsMultisig {
+            address,
+            threshold,
+            voters,
+        })
+    }
+}
+
+/// Reads the multisig configured with `SQUADS_MULTISIG`
+///
+/// Admin commands are refused when it isn't set.
+pub async fn fetch_multisig() -> Result<SquadsMultisig, RiskCalculationError> {
+    let address = std::env::var("SQUADS_MULTISIG").map_err(|_| {
+        RiskCalculationError::Unauthorized(
+            "SQUADS_MULTISIG is not set, admin commands are disabled".to_string(),
+        )
+    })?;
+    let address = Pubkey::from_str(&address)
+        .map_err(|e| RiskCalculationError::ParseError(format!("SQUADS_MULTISIG: {}", e)))?;
+    let client = crate::upstream_fixtures::rpc_client(rpc_url()?);
+    let account = timed(
+        Timing::Rpc,
+        upstream::call(Upstream::Rpc, client.get_account(&address)),
+    )
+    .await?;
+    if account.owner.to_string() != SQUADS_PROGRAM_ID {
+        return Err(RiskCalculationError::ParseError(format!(
+            "{} is not owned by the Squads program",
+            address
+        )));
+    }
+    SquadsMultisig::from_account_data(address, &account.data)
+}
+
+/// Admin commands that need the multisig's approval
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminAction {
+    AddPenalty,
+    RebuildProjections,
+    ApproveProposal,
+    RejectProposal,
+    ExportState,
+    ImportState,
+    InvalidateCache,
+    WarmCache,
+    SavePortfolio,
+    DeletePortfolio,
+    ListPortfolios,
+}
+
+/// The message the multisig members sign, as JSON
+///
+/// It carries the command itself, so what the members approved is exactly what
+/// gets executed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ApprovedMessage {
+    pub action: AdminAction,
+    /// Approvals are only valid on the cluster they were signed for
+    pub cluster: Cluster,
+    /// Unique per approval, an approval can only be used once
+    pub nonce: String,
+    pub expires_at: DateTime<Utc>,
+    /// The command's request body
+    pub params: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MemberSignature {
+    /// Base58 pubkey of the member
+    pub signer: String,
+    /// Base58 ed25519 signature of the message bytes
+    pub signature: String,
+}
+
+/// Body of admin commands: the signed message and the members' signatures
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MultisigApproval {
+    pub message: String,
+    pub signatures: Vec<MemberSignature>,
+}
+
+/// An approval that passed verification
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifiedApproval {
+    pub message: ApprovedMessage,
+    /// Base58 sha256 of the message bytes
+    pub message_hash: String,
+    pub approvers: Vec<Pubkey>,
+}
+
+/// Checks that enough voting members signed a valid message for `action`
+///
+/// Every signature must verify, and only distinct voting members count
+/// towards the threshold.
+pub fn verify_approval(
+    approval: &MultisigApproval,
+    action: AdminAction,
+    multisig: &SquadsMultisig,
+    now: DateTime<Utc>,
+) -> Result<VerifiedApproval, RiskCalculationError> {
+    let message: ApprovedMessage = serde_json::from_str(&approval.message)
+        .map_err(|e| RiskCalculationError::InvalidParameter(format!("approval message: {}", e)))?;
+    if message.action != action {
+        return Err(RiskCalculationError::Unauthorized(format!(
+            "approval is for {:?}, not {:?}",
+            message.action, action
+        )));
+    }
+    if message.cluster != Cluster::global() {
+        return Err(RiskCalculationError::Unauthorized(format!(
+            "approval is for {}",
+            message.cluster.as_str()
+        )));
+    }
+    if message.expires_at <= now {
+        return Err(RiskCalculationError::Unauthorized(format!(
+            "approval expired at {}",
+            message.expires_at
+        )));
+    }
+    if message.expires_at > now + Duration::hours(MAX_APPROVAL_LIFETIME_HOURS) {
+        return Err(RiskCalculationError::Unauthorized(format!(
+            "approvals can't be valid for more than {} hours",
+            MAX_APPROVAL_LIFETIME_HOURS
+        )));
+    }
+
+    let mut approvers: Vec<Pubkey> = Vec::new();
+    for MemberSignature { signer, signature } in &approval.signatures {
+        let signer = Pubkey::from_str(signer).map_err(|e| {
+            RiskCalculationError::InvalidParameter(format!("signer {}: {}", signer, e))
+        })?;
+        let signature = Signature::from_str(signature).map_err(|e| {
+            RiskCalculationError::InvalidParameter(format!("signature of {}: {}", signer, e))
+        })?;
+        if !signature.verify(signer.as_ref(), approval.message.as_bytes()) {
+            return Err(RiskCalculationError::Unauthorized(format!(
+                "invalid signature from {}",
+                signer
+            )));
+        }
+        if multisig.voters.contains(&signer) && !approvers.contains(&signer) {
+            approvers.push(signer);
+        }
+    }
+    if approvers.len() < multisig.threshold as usize {
+        return Err(RiskCalculationError::Unauthorized(format!(
+            "{} of the {} approvals required by {}",
+            approvers.len(),
+            multisig.threshold,
+            multisig.address
+        )));
+    }
+
+    Ok(VerifiedApproval {
+        message_hash: anchor_client::solana_sdk::hash::hash(approval.message.as_bytes())
+            .to_string(),
+        message,
+        approvers,
+    })
+}
+
+/// Log of executed admin commands, newest first
+///
+/// Like the alerts it's a record that can't be recomputed, so the key isn't versioned.
+fn audit_key() -> String {
+    record_key("admin_audit")
+}
+
+fn nonce_key(nonce: &str) -> String {
+    record_key(&format!("admin_nonce:{}", nonce))
+}
+
+/// An admin command executed with the multisig's approval
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminAuditEvent {
+    pub action: AdminAction,
+    pub multisig: String,
+    pub approvers: Vec<String>,
+    pub message_hash: String,
+    pub nonce: String,
+    pub params: serde_json::Value,
+    pub authorized_at: DateTime<Utc>,
+}
+
+/// The approval of a command without a body, from its [`APPROVAL_HEADER`]
+pub fn approval_from_headers(
+    headers: &HeaderMap,
+) -> Result<MultisigApproval, RiskCalculationError> {
+    let approval = headers
+        .get(APPROVAL_HEADER)
+        .ok_or_else(|| {
+            RiskCalculationError::Unauthorized(format!("{} header is required", APPROVAL_HEADER))
+        })?
+        .to_str()
+        .map_err(|e| {
+            RiskCalculationError::InvalidParameter(format!("{}: {}", APPROVAL_HEADER, e))
+        })?;
+    serde_json::from_str(approval)
+        .map_err(|e| RiskCalculationError::InvalidParameter(format!("{}: {}", APPROVAL_HEADER, e)))
+}
+
+/// The command's request an approval carries, before it's verified
+///
+/// Lets a command check its request before [`authorize`] spends the approval,
+/// a command that can't run then doesn't burn an approval or log an action
+/// that never happened. Once verified the request is the same, it's signed.
+pub fn unverified_params<T: DeserializeOwned>(
+    approval: &MultisigApproval,
+) -> Result<T, RiskCalculationError> {
+    let message: ApprovedMessage = serde_json::from_str(&approval.message)
+        .map_err(|e| RiskCalculationError::InvalidParameter(format!("approval message: {}", e)))?;
+    serde_json::from_value(message.params)
+        .map_err(|e| RiskCalculationError::InvalidParameter(format!("approval params: {}", e)))
+}
+
+/// Verifies an approval for `action` and returns the command's request
+///
+/// The approval's nonce is spent and the command is added to the audit log
+/// before returning, so no command runs without its audit trail and no
+/// approval runs twice.
+pub async fn authorize<T: DeserializeOwned>(
+    redis_client: &redis::Client,
+    approval: &MultisigApproval,
+    action: AdminAction,
+) -> Result<T, RiskCalculationError> {
+    let multisig = fetch_multisig().await?;
+    let now = Utc::now();
+    let verified = verify_approval(approval, action, &multisig, now)?;
+    let request = serde_json::from_value(verified.message.params.clone())
+        .map_err(|e| RiskCalculationError::InvalidParameter(format!("approval params: {}", e)))?;
+
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let ttl = (verified.message.expires_at - now).num_seconds().max(1) as u64;
+    let unused: bool = redis::cmd("SET")
+        .arg(nonce_key(&verified.message.nonce))
+        .arg(&verified.message_hash)
+        .arg("NX")
+        .arg("EX")
+        .arg(ttl)
+        .query_async::<Option<String>>(&mut connection)
+        .await
+        .map_err(RiskCalculationError::from)?
+        .is_some();
+    if !unused {
+        return Err(RiskCalculationError::Unauthorized(format!(
+            "approval {} was already used",
+            verified.message.nonce
+        )));
+    }
+
+    let event = AdminAuditEvent {
+        action,
+        multisig: multisig.address.to_string(),
+        approvers: verified
+            .approvers
+            .iter()
+            .map(|approver| approver.to_string())
+            .collect(),
+        message_hash: verified.message_hash,
+        nonce: verified.message.nonce,
+        params: verified.message.params,
+        authorized_at: now,
+    };
+    let body = serde_json::to_string(&event).map_err(RiskCalculationError::SerdeError)?;
+    let _: () = redis::pipe()
+        .atomic()
+        .lpush(audit_key(), body)
+        .ignore()
+        .ltrim(audit_key(), 0, MAX_STORED_AUDIT_EVENTS - 1)
+        .ignore()
+        .query_async(&mut connection)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    tracing::info!(
+        "{:?} approved by {} of {} ({})",
+        action,
+        event.approvers.join(", "),
+        event.multisig,
+        event.message_hash
+    );
+    Ok(request)
+}
+
+/// The most recent admin commands, newest first
+pub async fn load_audit_events(
+    redis_client: &redis::Client,
+    limit: usize,
+) -> Result<Vec<AdminAuditEvent>, RiskCalculationError> {
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let events: Vec<String> = timed(
+        Timing::CacheRead,
+        connection.lrange(audit_key(), 0, limit as isize - 1),
+    )
+    .await
+    .map_err(RiskCalculationError::from)?;
+    events
+        .iter()
+        .map(|event| serde_json::from_str(event).map_err(RiskCalculationError::SerdeError))
+        .collect()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    /// Number of events to return, 50 by default
+    pub limit: Option<usize>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+/// Audit log of the admin commands, `/admin/audit`
+pub async fn admin_audit(
+    State(state): State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        load_audit_events(
+            &state.redis_client,
+            query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT),
+        )
+        .await
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anchor_client::solana_sdk::signature::{Keypair, Signer};
+
+    fn multisig_account(threshold: u16, members: &[(Pubkey, u8)]) -> Vec<u8> {
+        let mut data =
+            anchor_client::solana_sdk::hash::hash(b"account:Multisig").to_bytes()[..8].to_vec();
+        data.extend([0u8; 64]);
+        data.extend(threshold.to_le_bytes());
+        data.extend([0u8; 4 + 8 + 8]);
+        // Some(rent_collector), bump
+        data.push(1);
+        data.extend([7u8; 32]);
+        data.push(255);
+        data.extend((members.len() as u32).to_le_bytes());
+        for (key, permissions) in members {
+            data.extend(key.as_ref());
+            data.push(*permissions);
+        }
+        data
+    }
+
+    fn approval(message: &ApprovedMessage, signers: &[&Keypair]) -> MultisigApproval {
+        let message = serde_json::to_string(message).unwrap();
+        MultisigApproval {
+            signatures: signers
+                .iter()
+                .map(|signer| MemberSignature {
+                    signer: signer.pubkey().to_string(),
+                    signature: signer.sign_message(message.as_bytes()).to_string(),
+                })
+                .collect(),
+            message,
+        }
+    }
+
+    #[test]
+    fn test_parse_multisig_account() {
+        let (voter, proposer) = (Pubkey::new_unique(), Pubkey::new_unique());
+        let address = Pubkey::new_unique();
+        let multisig = SquadsMultisig::from_account_data(
+            address,
+            &multisig_account(2, &[(voter, 0b111), (proposer, 0b001)]),
+        )
+        .unwrap();
+        assert_eq!(multisig.threshold, 2);
+        assert_eq!(multisig.voters, vec![voter]);
+        assert!(SquadsMultisig::from_account_data(address, &[0u8; 200]).is_err());
+    }
+
+    #[test]
+    fn test_verify_approval() {
+        let members = [Keypair::new(), Keypair::new(), Keypair::new()];
+        let multisig = SquadsMultisig {
+            address: Pubkey::new_unique(),
+            threshold: 2,
+            voters: members.iter().map(|member| member.pubkey()).collect(),
+        };
+        let now = Utc::now();
+        let message = ApprovedMessage {
+            action: AdminAction::AddPenalty,
+            cluster: Cluster::global(),
+            nonce: "incident-42".to_string(),
+            expires_at: now + Duration::hours(1),
+            params: serde_json::json!({ "protocol": "Kamino" }),
+        };
+
+        let approved = approval(&message, &[&members[0], &members[2]]);
+        let verified = verify_approval(&approved, AdminAction::AddPenalty, &multisig, now).unwrap();
+        assert_eq!(
+            verified.approvers,
+            vec![members[0].pubkey(), members[2].pubkey()]
+        );
+        assert!(
+            verify_approval(&approved, AdminAction::RebuildProjections, &multisig, now).is_err()
+        );
+        assert!(verify_approval(
+            &approved,
+            AdminAction::AddPenalty,
+            &multisig,
+            now + Duration::hours(2)
+        )
+        .is_err());
+
+        // The same member twice and an outsider don't make a quorum
+        let outsider = Keypair::new();
+        let short = approval(&message, &[&members[1], &members[1], &outsider]);
+        assert!(matches!(
+            verify_approval(&short, AdminAction::AddPenalty, &multisig, now),
+            Err(RiskCalculationError::Unauthorized(_))
+        ));
+
+        // Signatures are over the exact message bytes
+        let mut tampered = approved.clone();
+        tampered.message = tampered.message.replace("Kamino", "Solend");
+        assert!(verify_approval(&tampered, AdminAction::AddPenalty, &multisig, now).is_err());
+
+        let params: serde_json::Value = unverified_params(&approved).unwrap();
+        assert_eq!(params, serde_json::json!({ "protocol": "Kamino" }));
+        assert!(unverified_params::<serde_json::Value>(&MultisigApproval {
+            message: "not json".to_string(),
+            signatures: Vec::new(),
+        })
+        .is_err());
+    }
+}
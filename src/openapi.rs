@@ -0,0 +1,685 @@
+use axum::{response::Html, Json};
+use schemars::{gen::SchemaSettings, JsonSchema};
+use serde_json::{json, Value};
+
+use crate::{
+    alerts::AlertsResponse,
+    batch::{BatchRequest, BatchResult},
+    correlation::PortfolioRiskRequest,
+    multisig::MultisigApproval,
+    portfolio::{AmountRequest, ImportRequest},
+    portfolio_events::{PortfolioEvent, PortfolioHistory, PortfolioProjection, SaveRequest},
+    prices::Valued,
+    profiles::Questionnaire,
+    proposals::WeightProposal,
+    rebalancing::{RebalancePlan, TransactionSystemDeposits, TransactionSystemWithdrawals},
+    risk_model::{PartialRiskModelResponse, RiskModelResponse, RiskProfile, RiskResponse},
+    server::API_PREFIX,
+    state_export::StateSnapshot,
+    stress::{PortfolioStressRequest, StressScenario, WalletStressReport},
+};
+
+/// Codes of [`RiskCalculationError::code`], the `code` of error bodies, in
+/// the order of the variants
+///
+/// [`RiskCalculationError::code`]: crate::risk_model::RiskCalculationError::code
+const ERROR_CODES: [&str; 13] = [
+    "serde_error",
+    "parse_error",
+    "upstream_request_failed",
+    "rpc_call_failed",
+    "cache_unavailable",
+    "internal_error",
+    "not_ready",
+    "unauthorized",
+    "upstream_unavailable",
+    "stale_data",
+    "invalid_parameter",
+    "not_found",
+    "conflict",
+];
+
+/// Paths served outside of [`API_PREFIX`]
+const UNVERSIONED_PATHS: [&str; 4] = ["/health", "/ready", "/metrics", "/openapi.json"];
+
+/// Swagger UI rendering `/openapi.json`, assets from the swagger-ui-dist package
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+  <title>Risk model API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>"##;
+
+/// `GET /openapi.json`
+pub async fn openapi() -> Json<Value> {
+    Json(spec())
+}
+
+/// `GET /docs`, only routed when the server config enables it
+pub async fn swagger_ui() -> Html<&'static str> {
+    Html(SWAGGER_UI)
+}
+
+fn schema(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// Reference to the component schema of `T`, see [`component_schemas`]
+fn schema_of<T: JsonSchema>() -> Value {
+    schema(&T::schema_name())
+}
+
+fn json_content(schema: Value) -> Value {
+    json!({ "content": { "application/json": { "schema": schema } } })
+}
+
+fn request_body(name: &str) -> Value {
+    let mut body = json_content(schema(name));
+    body["required"] = json!(true);
+    body
+}
+
+/// A 200 with `ok` as body, errors described by the default response
+fn responses(ok: Value) -> Value {
+    let mut success = json_content(ok);
+    success["description"] = json!("OK");
+    let mut error = json_content(schema("Error"));
+    error["description"] = json!(
+        "400 invalid parameter, 403 unauthorized, 404 not found, \
+         502 upstream failure, 503 not ready or stale, 500 otherwise"
+    );
+    json!({ "200": success, "default": error })
+}
+
+fn object() -> Value {
+    json!({ "type": "object" })
+}
+
+fn query(name: &str, schema: Value, description: &str) -> Value {
+    json!({ "name": name, "in": "query", "schema": schema, "description": description })
+}
+
+fn path(name: &str, description: &str) -> Value {
+    json!({
+        "name": name,
+        "in": "path",
+        "required": true,
+        "schema": { "type": "string" },
+        "description": description,
+    })
+}
+
+/// `Idempotency-Key` of operations that replay their response to retries
+fn idempotency_key() -> Value {
+    json!({
+        "name": "Idempotency-Key",
+        "in": "header",
+        "schema": { "type": "string", "minLength": 1, "maxLength": 255 },
+        "description": "Retries with the same key within 24 hours get the first response, flagged with `Idempotent-Replayed: true`, instead of running again",
+    })
+}
+
+/// Operation answering conditional GETs with 304 while its snapshot is unchanged
+fn conditional(mut operation: Value) -> Value {
+    for (name, description) in [
+        (
+            "If-None-Match",
+            "`ETag` of the cached response, 304 while it's current",
+        ),
+        (
+            "If-Modified-Since",
+            "`Last-Modified` of the cached response, 304 while it's current",
+        ),
+    ] {
+        operation["parameters"]
+            .as_array_mut()
+            .expect("operations have parameters")
+            .push(json!({
+                "name": name,
+                "in": "header",
+                "schema": { "type": "string" },
+                "description": description,
+            }));
+    }
+    operation["responses"]["304"] =
+        json!({ "description": "The cached response is still current" });
+    operation
+}
+
+fn debug() -> Value {
+    query(
+        "debug",
+        json!({ "type": "string", "enum": ["timings"] }),
+        "`timings` adds a latency breakdown to the response, list responses move under `data`",
+    )
+}
+
+fn wallet() -> Value {
+    path("wallet", "Base58 wallet address")
+}
+
+/// The multisig approval of an admin command without a body
+fn approval_header() -> Value {
+    json!({
+        "name": "X-Multisig-Approval",
+        "in": "header",
+        "required": true,
+        "schema": { "type": "string" },
+        "description": "Multisig approval of the command, as JSON",
+    })
+}
+
+fn operation(summary: &str, parameters: Vec<Value>, ok: Value) -> Value {
+    json!({ "summary": summary, "parameters": parameters, "responses": responses(ok) })
+}
+
+/// Operation with a JSON body of the `body` schema
+fn operation_with_body(summary: &str, parameters: Vec<Value>, body: &str, ok: Value) -> Value {
+    let mut operation = operation(summary, parameters, ok);
+    operation["requestBody"] = request_body(body);
+    operation
+}
+
+fn number() -> Value {
+    json!({ "type": "number" })
+}
+
+fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+/// Object schema whose `required` properties must be present
+fn properties(required: &[&str], properties: Value) -> Value {
+    json!({ "type": "object", "required": required, "properties": properties })
+}
+
+/// The OpenAPI 3.0 document of the service
+///
+/// Paths are maintained by hand, keep them in step with
+/// [`crate::server::router`]. Bodies reference the schemas derived from their
+/// types, see [`component_schemas`].
+pub fn spec() -> Value {
+    let mut spec = unversioned_spec();
+    let paths = spec["paths"].as_object_mut().expect("the spec has paths");
+    *paths = std::mem::take(paths)
+        .into_iter()
+        .map(|(path, item)| {
+            if UNVERSIONED_PATHS.contains(&path.as_str()) {
+                (path, item)
+            } else {
+                (format!("{}{}", API_PREFIX, path), item)
+            }
+        })
+        .collect();
+    spec
+}
+
+/// Schemas of the bodies, derived from the types they're (de)serialized with
+/// along with every type they nest
+fn component_schemas() -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    generator.subschema_for::<RiskResponse>();
+    generator.subschema_for::<RiskModelResponse>();
+    generator.subschema_for::<PartialRiskModelResponse>();
+    generator.subschema_for::<RiskProfile>();
+    generator.subschema_for::<BatchRequest>();
+    generator.subschema_for::<BatchResult>();
+    generator.subschema_for::<StressScenario>();
+    generator.subschema_for::<Questionnaire>();
+    generator.subschema_for::<ImportRequest>();
+    generator.subschema_for::<AmountRequest>();
+    generator.subschema_for::<SaveRequest>();
+    generator.subschema_for::<MultisigApproval>();
+    generator.subschema_for::<PortfolioStressRequest>();
+    generator.subschema_for::<PortfolioRiskRequest>();
+    generator.subschema_for::<StateSnapshot>();
+    generator.subschema_for::<Valued<PortfolioProjection>>();
+    generator.subschema_for::<Valued<TransactionSystemDeposits>>();
+    generator.subschema_for::<Valued<TransactionSystemWithdrawals>>();
+    generator.subschema_for::<Valued<RebalancePlan>>();
+    generator.subschema_for::<PortfolioEvent>();
+    generator.subschema_for::<PortfolioHistory>();
+    generator.subschema_for::<WalletStressReport>();
+    generator.subschema_for::<WeightProposal>();
+    generator.subschema_for::<AlertsResponse>();
+    let mut schemas = json!(generator.take_definitions());
+    // Error bodies are built from a RiskCalculationError rather than serialized
+    schemas["Error"] = properties(
+        &["error", "code"],
+        json!({
+            "error": { "type": "string" },
+            "code": { "type": "string", "enum": ERROR_CODES },
+        }),
+    );
+    schemas
+}
+
+/// The document with every path relative to [`API_PREFIX`]
+fn unversioned_spec() -> Value {
+    let wallet_amount = |summary: &str| {
+        operation_with_body(summary, vec![wallet(), debug()], "AmountRequest", object())
+    };
+    let idempotent_wallet_amount = |summary: &str, ok: Value| {
+        operation_with_body(
+            summary,
+            vec![wallet(), idempotency_key(), debug()],
+            "AmountRequest",
+            ok,
+        )
+    };
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Risk model",
+            "description": "Risk of Solana lending protocols and the portfolios allocated across them",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/health": { "get": operation("Liveness", vec![], object()) },
+            "/ready": { "get": operation("Readiness of every backend", vec![], object()) },
+            "/metrics": { "get": {
+                "summary": "RPC credits spent and left of the hourly budget, in the Prometheus text format",
+                "responses": { "200": {
+                    "description": "OK",
+                    "content": { "text/plain": { "schema": { "type": "string" } } },
+                } },
+            } },
+            "/risk_model": { "get": conditional(operation(
+                "Protocols ranked by overall risk",
+                vec![
+                    query("what_if", json!({ "type": "boolean" }), "Include the protocol choice under each weight preset"),
+                    query("market", json!({ "type": "string" }), "Kamino lending market of the reserve to assess, requires `reserve`"),
+                    query("reserve", json!({ "type": "string" }), "Kamino reserve to assess, requires `market`"),
+                    query("lang", json!({ "type": "string", "enum": ["en", "es"] }), "Language of `choice_reason`"),
+                    query("allow_partial", json!({ "type": "boolean" }), "Serve protocols from the pillars that could be computed"),
+                    query("volatility_window", json!({ "type": "string", "example": "14d" }), "Add every protocol's volatility over this window, in hours (`48h`) or days (`14d`)"),
+                    query("volatility_frequency", json!({ "type": "string", "enum": ["hour", "day"] }), "Sampling of `volatility_window`, that of its unit by default"),
+                    debug(),
+                ],
+                json!({ "oneOf": [schema("RiskModelResponse"), schema("PartialRiskModelResponse")] }),
+            )) },
+            "/risk_model/stream": { "get": {
+                "summary": "Server-sent `risk_update` events after every refresh",
+                "responses": { "200": {
+                    "description": "Event stream",
+                    "content": { "text/event-stream": { "schema": { "type": "string" } } },
+                } },
+            } },
+            "/risk_model/compute": { "post": {
+                "summary": "Scores metrics given in the body without fetching anything",
+                "requestBody": { "required": true, "content": { "application/json": { "schema": object() } } },
+                "responses": responses(schema("RiskResponse")),
+            } },
+            "/backtest": { "get": operation(
+                "Replays the Kamino reserve's history and compares its risk with the adverse events that followed",
+                vec![
+                    query("from", json!({ "type": "string", "format": "date-time" }), "Start of the replay, defaults to 30 days before to"),
+                    query("to", json!({ "type": "string", "format": "date-time" }), "End of the replay, defaults to now"),
+                    query("threshold", number(), "Risk from which an hour counts as a warning, defaults to 50"),
+                    query("horizon_hours", json!({ "type": "integer" }), "How far ahead of an event a warning counts, defaults to 24"),
+                    debug(),
+                ],
+                object(),
+            ) },
+            "/risk_model/batch": { "post": operation_with_body(
+                "Risk of several protocols or Kamino reserves in one round trip, in the order of the targets",
+                vec![debug()],
+                "BatchRequest",
+                properties(&["results"], json!({ "results": array(schema("BatchResult")) })),
+            ) },
+            "/risk_model/stress": { "post": operation_with_body(
+                "Every protocol's utilization, concentration and risk under a shock scenario",
+                vec![debug()],
+                "StressScenario",
+                object(),
+            ) },
+            "/risk_model/kamino": { "get": operation(
+                "Risk of every configured Kamino market and their TVL weighted aggregate",
+                vec![
+                    query("market", json!({ "type": "string" }), "all, the default, or the name or lending market of a configured market"),
+                    debug(),
+                ],
+                object(),
+            ) },
+            "/risk_model/kamino/concentration/history": { "get": operation(
+                "Hourly largest deposit, total deposits and HHI of a Kamino reserve",
+                vec![
+                    query("from", json!({ "type": "string", "format": "date-time" }), "Start of the range, defaults to a week before to"),
+                    query("to", json!({ "type": "string", "format": "date-time" }), "End of the range, defaults to now"),
+                    debug(),
+                ],
+                object(),
+            ) },
+            "/protocols/kamino/top_depositors": { "get": operation(
+                "Largest depositors of a Kamino reserve, with masked obligations and bucketed amounts",
+                vec![query("limit", json!({ "type": "integer", "minimum": 1, "maximum": 100 }), "Number of depositors, defaults to 20"), debug()],
+                object(),
+            ) },
+            "/protocols/{protocol}/anomalies": { "get": operation(
+                "Hours whose utilization deviated from the trailing mean by more than the configured number of standard deviations",
+                vec![
+                    path("protocol", "kamino or marginfi"),
+                    query("from", json!({ "type": "string", "format": "date-time" }), "Start of the range, defaults to a week before to"),
+                    query("to", json!({ "type": "string", "format": "date-time" }), "End of the range, defaults to now"),
+                    query("market", json!({ "type": "string" }), "Kamino lending market of the reserve, requires `reserve`"),
+                    query("reserve", json!({ "type": "string" }), "Kamino reserve, requires `market`"),
+                    debug(),
+                ],
+                object(),
+            ) },
+            "/risk_history": { "get": operation(
+                "Overall risk of a protocol over time",
+                vec![query("protocol", json!({ "type": "string" }), "kamino or marginfi"), debug()],
+                object(),
+            ) },
+            "/risk_history/export": { "get": {
+                "summary": "Risk history of a protocol as a CSV or Parquet file, one row per point",
+                "parameters": [
+                    query("format", json!({ "type": "string", "enum": ["csv", "parquet"] }), "csv or parquet"),
+                    query("protocol", json!({ "type": "string" }), "kamino or marginfi"),
+                    query("from", json!({ "type": "string", "format": "date-time" }), "Defaults to a week before `to`"),
+                    query("to", json!({ "type": "string", "format": "date-time" }), "Defaults to now"),
+                    query("market", json!({ "type": "string" }), "Kamino lending market, requires `reserve`"),
+                    query("reserve", json!({ "type": "string" }), "Kamino reserve, requires `market`"),
+                ],
+                "responses": {
+                    "200": {
+                        "description": "OK",
+                        "content": {
+                            "text/csv": { "schema": { "type": "string" } },
+                            "application/vnd.apache.parquet": { "schema": { "type": "string", "format": "binary" } },
+                        },
+                    },
+                    "default": responses(object())["default"],
+                },
+            } },
+            "/alerts": { "get": operation("Recent risk delta alerts", vec![debug()], schema_of::<AlertsResponse>()) },
+            "/reports/daily/{date}": { "get": {
+                "summary": "Minimum, maximum and average risk scores of every protocol over a past day, with their notable changes",
+                "parameters": [
+                    path("date", "Day in UTC, YYYY-MM-DD"),
+                    query("format", json!({ "type": "string", "enum": ["json", "markdown"] }), "Defaults to json"),
+                    debug(),
+                ],
+                "responses": {
+                    "200": {
+                        "description": "OK",
+                        "content": {
+                            "application/json": { "schema": object() },
+                            "text/markdown": { "schema": { "type": "string" } },
+                        },
+                    },
+                    "default": responses(object())["default"],
+                },
+            } },
+            "/liquidity_depth": { "get": operation("Exit liquidity per protocol", vec![debug()], object()) },
+            "/weights/{profile}": { "get": conditional(operation(
+                "Allocation weights of a risk profile",
+                vec![path("profile", "low, medium, high or custom:<target_risk>:<max_per_protocol_bps>"), debug()],
+                object(),
+            )) },
+            "/profiles/{profile}/weights": { "get": conditional(operation(
+                "Allocation weights of a risk profile with the risk scores they were derived from",
+                vec![path("profile", "low, medium, high or custom:<target_risk>:<max_per_protocol_bps>"), debug()],
+                object(),
+            )) },
+            "/profiles/suggest": { "post": operation_with_body(
+                "Risk profile matching a questionnaire, with its recommended weights",
+                vec![debug()],
+                "Questionnaire",
+                properties(&["profile", "score", "max_score", "allocation"], json!({
+                    "profile": schema("RiskProfile"),
+                    "score": { "type": "integer", "description": "Risk appetite from 0 to max_score" },
+                    "max_score": { "type": "integer" },
+                    "allocation": object(),
+                })),
+            ) },
+            "/profiles/{profile}/simulate": { "get": operation(
+                "Monte Carlo distribution of 30-day returns and drawdowns of a risk profile's weights",
+                vec![
+                    path("profile", "low, medium, high or custom:<target_risk>:<max_per_protocol_bps>"),
+                    query("n", json!({ "type": "integer", "minimum": 1, "maximum": 100000 }), "Number of simulated paths, defaults to 1000"),
+                    query("seed", json!({ "type": "integer" }), "Seeds the draws, for reproducible results"),
+                    debug(),
+                ],
+                object(),
+            ) },
+            "/strategies": { "get": operation(
+                "Configured strategies ranked by overall risk",
+                vec![
+                    query("amount", json!({ "type": "integer", "minimum": 0 }), "Amount to split across each strategy's protocols"),
+                    debug(),
+                ],
+                object(),
+            ) },
+            "/portfolio/{wallet}": {
+                "get": operation(
+                    "The wallet's portfolio, valued in USD at the current prices",
+                    vec![wallet(), query("at", json!({ "type": "string", "format": "date-time" }), "Show the portfolio as it was at this time"), debug()],
+                    schema_of::<Valued<PortfolioProjection>>(),
+                ),
+                "put": operation_with_body("Replaces the wallet's holdings of an asset with a multisig approval of a SaveRequest", vec![wallet(), debug()], "MultisigApproval", schema_of::<Valued<PortfolioProjection>>()),
+                "delete": operation("Deletes the wallet's portfolio with a multisig approval", vec![wallet(), approval_header(), debug()], properties(&["deleted"], json!({
+                    "deleted": { "type": "boolean", "description": "Whether the wallet had a portfolio" },
+                }))),
+            },
+            "/portfolio/{wallet}/events": { "get": operation(
+                "Events the wallet's portfolio is built from",
+                vec![wallet(), debug()],
+                array(schema_of::<PortfolioEvent>()),
+            ) },
+            "/portfolio/{wallet}/history": { "get": operation(
+                "Deposits, withdrawals and rebalances of the wallet with the weights and transfers decided, newest first",
+                vec![
+                    wallet(),
+                    query("since", json!({ "type": "string", "format": "date-time" }), "Only events recorded at or after this time"),
+                    query("until", json!({ "type": "string", "format": "date-time" }), "Only events recorded at or before this time"),
+                    query("limit", json!({ "type": "integer", "minimum": 1, "maximum": 1000 }), "Number of events, defaults to 100"),
+                    debug(),
+                ],
+                schema_of::<PortfolioHistory>(),
+            ) },
+            "/portfolio/{wallet}/import": { "post": operation_with_body(
+                "Imports the wallet's on-chain positions",
+                vec![wallet()],
+                "ImportRequest",
+                object(),
+            ) },
+            "/portfolio/{wallet}/deposit": { "post": idempotent_wallet_amount("Allocates a deposit across protocols", schema_of::<Valued<TransactionSystemDeposits>>()) },
+            "/portfolio/{wallet}/deposit/build": { "post": wallet_amount("Builds the deposit transactions") },
+            "/portfolio/{wallet}/withdraw": { "post": idempotent_wallet_amount("Withdraws from the portfolio", schema_of::<Valued<TransactionSystemWithdrawals>>()) },
+            "/portfolio/{wallet}/rebalance": { "post": operation(
+                "Rebalances the portfolio to its target weights",
+                vec![wallet(), debug()],
+                schema_of::<Valued<RebalancePlan>>(),
+            ) },
+            "/portfolio/{wallet}/stress": { "post": operation_with_body(
+                "The portfolio under a stress scenario",
+                vec![wallet(), debug()],
+                "PortfolioStressRequest",
+                schema_of::<WalletStressReport>(),
+            ) },
+            "/portfolio/risk": { "post": operation_with_body(
+                "Correlation aware risk of an allocation across protocols",
+                vec![debug()],
+                "PortfolioRiskRequest",
+                object(),
+            ) },
+            "/proposals": { "get": operation("Weight change proposals", vec![debug()], array(schema_of::<WeightProposal>())) },
+            "/proposals/{id}/approve": { "post": operation("Approves a proposal with a multisig approval", vec![path("id", "Proposal id"), debug()], object()) },
+            "/proposals/{id}/reject": { "post": operation("Rejects a proposal with a multisig approval", vec![path("id", "Proposal id"), debug()], object()) },
+            "/admin/portfolios": { "get": operation("Every stored wallet, with a multisig approval", vec![approval_header(), debug()], array(json!({ "type": "string" }))) },
+            "/admin/audit": { "get": operation("Admin actions and their approvals", vec![debug()], array(object())) },
+            "/admin/portfolio/rebuild": { "post": operation("Rebuilds portfolio projections from their events", vec![debug()], object()) },
+            "/admin/protocol_penalties": { "post": operation("Adds a protocol risk penalty with a multisig approval", vec![debug()], object()) },
+            "/admin/export": { "get": {
+                "summary": "Snapshot of every cached risk metric, history series and portfolio, with a multisig approval",
+                "parameters": [debug(), approval_header()],
+                "responses": responses(schema("StateSnapshot")),
+            } },
+            "/admin/import": { "post": {
+                "summary": "Restores a snapshot, approved by its sha256",
+                "parameters": [debug()],
+                "requestBody": { "required": true, "content": { "application/json": { "schema": properties(&["approval", "snapshot"], json!({
+                    "approval": object(),
+                    "snapshot": schema("StateSnapshot"),
+                })) } } },
+                "responses": responses(properties(&["imported", "deleted"], json!({
+                    "imported": { "type": "integer" },
+                    "deleted": { "type": "integer" },
+                }))),
+            } },
+            "/admin/cache/invalidate": { "post": operation("Drops cached values of a protocol or key pattern with a multisig approval", vec![debug()], properties(&["deleted"], json!({
+                "deleted": { "type": "integer" },
+            }))) },
+            "/admin/cache/warm": { "post": operation("Refreshes the risk snapshots now with a multisig approval", vec![debug()], properties(&["deleted", "snapshot_id"], json!({
+                "deleted": { "type": "integer" },
+                "snapshot_id": { "type": "string" },
+            }))) },
+            "/admin/rpc_budget": { "get": operation("RPC credits spent and left of the hourly budget", vec![debug()], properties(&["consumed_credits", "requests", "deferred_refreshes", "nearly_exhausted"], json!({
+                "hourly_credits": { "type": "integer", "nullable": true },
+                "available_credits": { "type": "number", "nullable": true },
+                "consumed_credits": { "type": "integer" },
+                "requests": { "type": "integer" },
+                "deferred_refreshes": { "type": "integer" },
+                "nearly_exhausted": { "type": "boolean" },
+            }))) },
+            "/openapi.json": { "get": operation("This document", vec![], object()) },
+        },
+        "components": { "schemas": component_schemas() },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        oracle_risk::OracleRiskMetrics,
+        risk_model::{ProtocolRiskMetrics, RiskCalculationError, RiskScore},
+    };
+
+    fn refs<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+        match value {
+            Value::Object(object) => {
+                if let Some(Value::String(reference)) = object.get("$ref") {
+                    found.push(reference);
+                }
+                object.values().for_each(|value| refs(value, found));
+            }
+            Value::Array(values) => values.iter().for_each(|value| refs(value, found)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_spec_references_resolve() {
+        let spec = spec();
+        let mut found = Vec::new();
+        refs(&spec, &mut found);
+        assert!(!found.is_empty());
+        for reference in found {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            assert!(
+                spec["components"]["schemas"].get(name).is_some(),
+                "{} is not defined",
+                reference
+            );
+        }
+        assert!(spec["paths"].get("/v1/risk_model").is_some());
+        assert!(spec["paths"].get("/risk_model").is_none());
+        assert!(spec["paths"].get("/health").is_some());
+    }
+
+    #[test]
+    fn test_error_codes_cover_every_variant() {
+        // Adding a variant breaks this match until it's listed below too
+        let listed = |error: &RiskCalculationError| match error {
+            RiskCalculationError::SerdeError(_)
+            | RiskCalculationError::ParseError(_)
+            | RiskCalculationError::RequestError(_)
+            | RiskCalculationError::RpcCallError(_)
+            | RiskCalculationError::RedisError(_)
+            | RiskCalculationError::CustomError(_)
+            | RiskCalculationError::NotReady(_)
+            | RiskCalculationError::Unauthorized(_)
+            | RiskCalculationError::UpstreamUnavailable(_)
+            | RiskCalculationError::StaleData(_)
+            | RiskCalculationError::InvalidParameter(_)
+            | RiskCalculationError::NotFound(_)
+            | RiskCalculationError::Conflict(_) => true,
+        };
+        let errors = [
+            RiskCalculationError::SerdeError(serde_json::from_str::<u8>("").unwrap_err()),
+            RiskCalculationError::ParseError(String::new()),
+            reqwest::Client::new()
+                .get("not a url")
+                .build()
+                .unwrap_err()
+                .into(),
+            solana_client::client_error::ClientError::from(
+                solana_client::client_error::ClientErrorKind::Custom(String::new()),
+            )
+            .into(),
+            redis::RedisError::from((redis::ErrorKind::IoError, "")).into(),
+            RiskCalculationError::CustomError(String::new()),
+            RiskCalculationError::NotReady(String::new()),
+            RiskCalculationError::Unauthorized(String::new()),
+            RiskCalculationError::UpstreamUnavailable(String::new()),
+            RiskCalculationError::StaleData(String::new()),
+            RiskCalculationError::InvalidParameter(String::new()),
+            RiskCalculationError::NotFound(String::new()),
+            RiskCalculationError::Conflict(String::new()),
+        ];
+        assert!(errors.iter().all(listed));
+        assert_eq!(errors.map(|error| error.code()), ERROR_CODES);
+    }
+
+    #[test]
+    fn test_schemas_describe_every_serialized_field() {
+        let spec = spec();
+        for (name, body) in [
+            ("RiskScore", serde_json::to_value(RiskScore::default())),
+            (
+                "ProtocolRiskMetrics",
+                serde_json::to_value(ProtocolRiskMetrics::default()),
+            ),
+            (
+                "OracleRiskMetrics",
+                serde_json::to_value(OracleRiskMetrics::default()),
+            ),
+            (
+                "PortfolioHistory",
+                serde_json::to_value(PortfolioHistory {
+                    wallet: String::new(),
+                    total_events: 0,
+                    events: Vec::new(),
+                }),
+            ),
+            (
+                "Valued_for_PortfolioProjection",
+                serde_json::to_value(Valued {
+                    schema_version: 1,
+                    response: PortfolioProjection::empty("wallet"),
+                    valuation_currency: "USD",
+                    valuation: None,
+                }),
+            ),
+        ] {
+            let properties = &spec["components"]["schemas"][name]["properties"];
+            for field in body.unwrap().as_object().unwrap().keys() {
+                assert!(
+                    properties.get(field).is_some(),
+                    "{}.{} isn't described",
+                    name,
+                    field
+                );
+            }
+        }
+    }
+}
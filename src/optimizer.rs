@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+
+use crate::{
+    bps::Bps,
+    rebalancing::{cap_profile_weights, split_proportionally, RiskWeightModel},
+    risk_model::{Protocol, RiskCalculationError, RiskProfile},
+    snapshot::RiskSnapshot,
+};
+
+/// Bisection steps solving for the weights' multiplier, far below a basis point
+const SOLVER_ITERATIONS: usize = 100;
+
+/// What the live model's recommended weights optimize
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WeightObjective {
+    /// Weights inversely proportional to overall risk
+    #[default]
+    RiskScore,
+    /// Weights maximizing yield per unit of risk, see [`RiskAdjustedYieldModel`]
+    RiskAdjustedYield,
+}
+
+impl WeightObjective {
+    /// Reads `WEIGHT_OBJECTIVE`, `risk_score` when not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        match std::env::var("WEIGHT_OBJECTIVE").as_deref() {
+            Err(_) | Ok("risk_score") => Ok(WeightObjective::RiskScore),
+            Ok("risk_adjusted_yield") => Ok(WeightObjective::RiskAdjustedYield),
+            Ok(other) => Err(RiskCalculationError::ParseError(format!(
+                "invalid WEIGHT_OBJECTIVE {:?}, expected risk_score or risk_adjusted_yield",
+                other
+            ))),
+        }
+    }
+}
+
+/// Mean-variance risk aversion of a profile
+///
+/// Applies to APYs and risks scaled to their means. Custom profiles get the
+/// Medium aversion and keep their own cap.
+fn risk_aversion(profile: &RiskProfile) -> f64 {
+    match profile {
+        RiskProfile::Low => 8.0,
+        RiskProfile::Medium | RiskProfile::Custom { .. } => 2.0,
+        RiskProfile::High => 0.5,
+    }
+}
+
+/// Risk model trading each protocol's current supply APY against its risk score
+///
+/// Each protocol's overall risk stands in for the standard deviation `σ` of its
+/// yield `μ`, protocols taken as independent. Maximizing `Σ w·μ − λ/2 · Σ w²·σ²`
+/// over weights summing to one gives `w = max(0, μ − ν) / (λ·σ²)`, with `ν`
+/// solved so the weights sum to one. A risk averse profile (high `λ`) tends to
+/// the minimum variance weights `∝ 1/σ²`, a risk seeking one concentrates on
+/// the best yields. APYs and risks are scaled to their means first, so `λ`
+/// means the same whatever the scale of the scores.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RiskAdjustedYieldModel {
+    /// `(protocol, supply APY in percent, overall risk)`
+    candidates: Vec<(Protocol, f64, f64)>,
+    unavailable: Vec<Protocol>,
+}
+
+impl RiskAdjustedYieldModel {
+    /// Fails when no protocol has both an APY and a risk score
+    pub fn new(
+        candidates: Vec<(Protocol, f64, f64)>,
+        unavailable: Vec<Protocol>,
+    ) -> Result<Self, RiskCalculationError> {
+        if candidates.is_empty() {
+            return Err(RiskCalculationError::NotReady(
+                "No protocol has a supply APY and a risk score".to_string(),
+            ));
+        }
+        Ok(RiskAdjustedYieldModel {
+            candidates,
+            unavailable,
+        })
+    }
+
+    /// Protocols assessed before APYs were kept are left out
+    pub fn from_snapshot(snapshot: &RiskSnapshot) -> Result<Self, RiskCalculationError> {
+        Self::new(
+            snapshot
+                .comparison
+                .ranking
+                .iter()
+                .filter_map(|assessment| {
+                    Some((
+                        assessment.protocol.clone(),
+                        assessment.risk_metrics.volatility_risk.current_apy?,
+                        assessment.risk_metrics.overall_risk.overall_risk,
+                    ))
+                })
+                .collect(),
+            snapshot
+                .comparison
+                .unavailable
+                .iter()
+                .map(|unavailable| unavailable.protocol.clone())
+                .collect(),
+        )
+    }
+
+    /// Mean-variance weights for `risk_aversion`, as shares summing to one
+    fn optimal_weights(&self, risk_aversion: f64) -> Vec<(Protocol, f64)> {
+        let mean = |values: Vec<f64>| values.iter().sum::<f64>() / values.len() as f64;
+        let mean_apy = mean(self.candidates.iter().map(|(_, apy, _)| *apy).collect());
+        let mean_risk = mean(self.candidates.iter().map(|(_, _, risk)| *risk).collect());
+        let scaled: Vec<(f64, f64)> = self
+            .candidates
+            .iter()
+            .map(|(_, apy, risk)| {
+                let yield_ = if mean_apy > 0.0 { apy / mean_apy } else { 0.0 };
+                let sigma = (risk / mean_risk.max(1e-6)).max(1e-6);
+                (yield_, risk_aversion * sigma * sigma)
+            })
+            .collect();
+        let weights_at = |nu: f64| -> Vec<f64> {
+            scaled
+                .iter()
+                .map(|(yield_, penalty)| (yield_ - nu).max(0.0) / penalty)
+                .collect()
+        };
+
+        // The weights shrink as `ν` grows: none are left at the best yield, and
+        // every one is at least one at the worst yield less the largest penalty
+        let mut high = scaled
+            .iter()
+            .map(|(yield_, _)| *yield_)
+            .fold(f64::MIN, f64::max);
+        let mut low = scaled
+            .iter()
+            .map(|(yield_, _)| *yield_)
+            .fold(f64::MAX, f64::min)
+            - scaled
+                .iter()
+                .map(|(_, penalty)| *penalty)
+                .fold(0.0, f64::max);
+        for _ in 0..SOLVER_ITERATIONS {
+            let nu = (low + high) / 2.0;
+            if weights_at(nu).iter().sum::<f64>() > 1.0 {
+                low = nu;
+            } else {
+                high = nu;
+            }
+        }
+        let weights = weights_at(low);
+        let total: f64 = weights.iter().sum();
+        self.candidates
+            .iter()
+            .zip(weights)
+            .map(|((protocol, _, _), weight)| (protocol.clone(), weight / total))
+            .collect()
+    }
+}
+
+impl RiskWeightModel for RiskAdjustedYieldModel {
+    fn get_recommended_weights(&self, profile: &RiskProfile) -> HashMap<Protocol, u64> {
+        // Scaled up so rounding to basis points happens once, in `split_proportionally`
+        let shares: Vec<(Protocol, u64)> = self
+            .optimal_weights(risk_aversion(profile))
+            .into_iter()
+            .map(|(protocol, share)| (protocol, (share * 1_000_000.0).round() as u64))
+            .collect();
+        cap_profile_weights(
+            profile,
+            split_proportionally(Bps::FULL.0, &shares)
+                .into_iter()
+                .filter(|(_, weight)| *weight > 0)
+                .collect(),
+        )
+    }
+
+    fn unavailable_protocols(&self) -> Vec<Protocol> {
+        self.unavailable.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_risk_adjusted_yield_weights() {
+        // Kamino yields twice as much for 50% more risk
+        let model = RiskAdjustedYieldModel::new(
+            vec![
+                (Protocol::Kamino, 8.0, 30.0),
+                (Protocol::Marginfy, 4.0, 20.0),
+            ],
+            Vec::new(),
+        )
+        .unwrap();
+
+        let weights = |profile| model.get_recommended_weights(&profile);
+        for profile in RiskProfile::ALL {
+            assert_eq!(weights(profile).values().sum::<u64>(), 10_000);
+        }
+        // More risk seeking profiles hold more of the better yield, within the cap
+        assert!(
+            weights(RiskProfile::Low)[&Protocol::Kamino]
+                < weights(RiskProfile::Medium)[&Protocol::Kamino]
+        );
+        assert_eq!(weights(RiskProfile::High)[&Protocol::Kamino], 5_000);
+
+        // Risk averse enough, the weights tend to minimum variance, 1/σ² = 4:9
+        let averse = model.optimal_weights(1_000.0);
+        assert!((averse[1].1 - 9.0 / 13.0).abs() < 0.01);
+
+        assert!(RiskAdjustedYieldModel::new(Vec::new(), Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,298 @@
+use std::str::FromStr;
+
+use anchor_client::solana_sdk::pubkey::Pubkey;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::Asset, clock::process_clock, cluster::rpc_url, risk_model::RiskCalculationError,
+};
+
+/// Pyth price accounts of the assets, by symbol
+///
+/// Used for reserves and banks that price their asset with another oracle.
+const DEFAULT_ORACLE_FEEDS: [(&str, &str); 4] = [
+    ("SOL", "H6ARHf6YXhGYeQfUzQNGk6rDNnLBQKrenN712K4AQJEG"),
+    ("USDC", "Gnt27xtC473ZT2Mw5u8wZ68Z3gULkSTb5DuxJy7eJotD"),
+    ("ETH", "JBu1AL4obBcCMqKBBxhpWCNUt136ijcuMZLFvTP7iWdB"),
+    ("BTC", "GVXRSBjFk6e6J3NbVPXohDJetcTjaeeuykUpbQF8UoMU"),
+];
+
+/// A confidence interval this wide relative to the price scores 100
+const MAX_CONFIDENCE_RATIO: f64 = 0.01;
+/// A price this old scores 100
+const MAX_STALENESS_SECONDS: f64 = 60.0;
+
+// Byte offsets into a Pyth v2 price account
+const MAGIC: u32 = 0xa1b2c3d4;
+const PRICE_ACCOUNT_TYPE: u32 = 3;
+const ACCOUNT_TYPE_OFFSET: usize = 8;
+const EXPONENT_OFFSET: usize = 20;
+const TIMESTAMP_OFFSET: usize = 96;
+const AGGREGATE_PRICE_OFFSET: usize = 208;
+const AGGREGATE_CONFIDENCE_OFFSET: usize = 216;
+const AGGREGATE_STATUS_OFFSET: usize = 224;
+const PRICE_ACCOUNT_MIN_SIZE: usize = 240;
+/// Aggregate status of a price that is currently being published
+const STATUS_TRADING: u32 = 1;
+
+/// A Pyth price account of a collateral asset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OracleFeed {
+    pub asset: String,
+    pub price_account: Pubkey,
+}
+
+impl OracleFeed {
+    /// The feed of the asset minted by `mint`
+    ///
+    /// `configured` is the Pyth price account the reserve or bank reads, `None`
+    /// when it's priced by another oracle and the asset's default one is used.
+    pub fn of_mint(
+        mint: &Pubkey,
+        configured: Option<Pubkey>,
+    ) -> Result<Self, RiskCalculationError> {
+        let asset = Asset::from_mint(mint);
+        let symbol = asset.map_or_else(|| mint.to_string(), |asset| asset.symbol().to_string());
+        let price_account = configured
+            .or_else(|| asset.and_then(|asset| default_price_account(asset.symbol())))
+            .ok_or_else(|| {
+                RiskCalculationError::CustomError(format!("no Pyth price account for {}", symbol))
+            })?;
+        Ok(OracleFeed {
+            asset: symbol,
+            price_account,
+        })
+    }
+}
+
+/// Default Pyth price account of `asset`, by symbol
+pub fn default_price_account(asset: &str) -> Option<Pubkey> {
+    DEFAULT_ORACLE_FEEDS
+        .iter()
+        .find(|(symbol, _)| symbol.eq_ignore_ascii_case(asset))
+        .and_then(|(_, account)| Pubkey::from_str(account).ok())
+}
+
+/// The aggregate price of a Pyth price account
+#[derive(Debug, Clone, PartialEq)]
+pub struct PythPrice {
+    pub price: f64,
+    pub confidence: f64,
+    /// Unix timestamp of the aggregate price
+    pub publish_time: i64,
+    pub trading: bool,
+}
+
+impl PythPrice {
+    pub fn from_account_data(data: &[u8]) -> Result<Self, RiskCalculationError> {
+        if data.len() < PRICE_ACCOUNT_MIN_SIZE
+            || read_u32(data, 0) != MAGIC
+            || read_u32(data, ACCOUNT_TYPE_OFFSET) != PRICE_ACCOUNT_TYPE
+        {
+            return Err(RiskCalculationError::ParseError(
+                "not a Pyth price account".to_string(),
+            ));
+        }
+        let scale = 10f64.powi(read_u32(data, EXPONENT_OFFSET) as i32);
+        Ok(PythPrice {
+            price: read_u64(data, AGGREGATE_PRICE_OFFSET) as i64 as f64 * scale,
+            confidence: read_u64(data, AGGREGATE_CONFIDENCE_OFFSET) as f64 * scale,
+            publish_time: read_u64(data, TIMESTAMP_OFFSET) as i64,
+            trading: read_u32(data, AGGREGATE_STATUS_OFFSET) == STATUS_TRADING,
+        })
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Quality of a single collateral price feed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct OracleFeedMetrics {
+    pub asset: String,
+    pub price_account: String,
+    pub price: f64,
+    pub confidence: f64,
+    /// Confidence interval relative to the price
+    pub confidence_ratio: f64,
+    pub staleness_seconds: i64,
+    pub trading: bool,
+    pub oracle_risk: f64,
+}
+
+/// Quality of the price feeds liquidations of the reserve's borrowers rely on
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct OracleRiskMetrics {
+    pub feeds: Vec<OracleFeedMetrics>,
+    pub oracle_risk: f64,
+}
+
+/// Scores a feed between 0 and 100 by the worse of its confidence width and staleness
+///
+/// A feed that isn't trading can't be used for liquidations at all and scores 100.
+pub fn calculate_feed_risk(price: &PythPrice, now: i64) -> (f64, i64, f64) {
+    let confidence_ratio = if price.price != 0.0 {
+        price.confidence / price.price.abs()
+    } else {
+        f64::INFINITY
+    };
+    let staleness_seconds = (now - price.publish_time).max(0);
+    let risk = if price.trading {
+        let confidence_score = (confidence_ratio / MAX_CONFIDENCE_RATIO).min(1.0);
+        let staleness_score = (staleness_seconds as f64 / MAX_STALENESS_SECONDS).min(1.0);
+        confidence_score.max(staleness_score) * 100.0
+    } else {
+        100.0
+    };
+    (confidence_ratio, staleness_seconds, risk)
+}
+
+/// Combines feed metrics into the pillar
+///
+/// Bad debt builds up through whichever collateral is mispriced, so the pillar
+/// is as risky as its worst feed.
+pub fn calculate_oracle_risk(feeds: Vec<OracleFeedMetrics>) -> OracleRiskMetrics {
+    let oracle_risk = feeds
+        .iter()
+        .map(|feed| feed.oracle_risk)
+        .fold(0.0, f64::max);
+    OracleRiskMetrics { feeds, oracle_risk }
+}
+
+/// Fetches every feed's price account and scores the feeds
+pub async fn fetch_oracle_risk(
+    feeds: &[OracleFeed],
+) -> Result<OracleRiskMetrics, RiskCalculationError> {
+    let rpc_url = rpc_url()?;
+    let client = crate::upstream_fixtures::rpc_client(rpc_url);
+    let accounts = client
+        .get_multiple_accounts(
+            &feeds
+                .iter()
+                .map(|feed| feed.price_account)
+                .collect::<Vec<_>>(),
+        )
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let now = process_clock().now().timestamp();
+
+    let metrics = feeds
+        .iter()
+        .zip(accounts)
+        .map(|(feed, account)| {
+            let account = account.ok_or_else(|| {
+                RiskCalculationError::UpstreamUnavailable(format!(
+                    "{} price account {} not found",
+                    feed.asset, feed.price_account
+                ))
+            })?;
+            let price = PythPrice::from_account_data(&account.data)?;
+            let (confidence_ratio, staleness_seconds, oracle_risk) =
+                calculate_feed_risk(&price, now);
+            Ok(OracleFeedMetrics {
+                asset: feed.asset.clone(),
+                price_account: feed.price_account.to_string(),
+                price: price.price,
+                confidence: price.confidence,
+                confidence_ratio,
+                staleness_seconds,
+                trading: price.trading,
+                oracle_risk,
+            })
+        })
+        .collect::<Result<Vec<_>, RiskCalculationError>>()?;
+    Ok(calculate_oracle_risk(metrics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn price_account(price: i64, confidence: u64, exponent: i32, timestamp: i64) -> Vec<u8> {
+        let mut data = vec![0u8; 3312];
+        data[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        data[8..12].copy_from_slice(&PRICE_ACCOUNT_TYPE.to_le_bytes());
+        data[20..24].copy_from_slice(&exponent.to_le_bytes());
+        data[96..104].copy_from_slice(&timestamp.to_le_bytes());
+        data[208..216].copy_from_slice(&price.to_le_bytes());
+        data[216..224].copy_from_slice(&confidence.to_le_bytes());
+        data[224..228].copy_from_slice(&STATUS_TRADING.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_parse_price_account() {
+        let price =
+            PythPrice::from_account_data(&price_account(15_000_000_000, 7_500_000, -8, 1_000))
+                .unwrap();
+        assert!((price.price - 150.0).abs() < 1e-9);
+        assert!((price.confidence - 0.075).abs() < 1e-9);
+        assert_eq!(price.publish_time, 1_000);
+        assert!(price.trading);
+
+        assert!(PythPrice::from_account_data(&[0u8; 3312]).is_err());
+    }
+
+    #[test]
+    fn test_feed_of_mint() {
+        let usdc = OracleFeed::of_mint(&Asset::Usdc.mint(), None).unwrap();
+        assert_eq!(usdc.asset, "USDC");
+        assert_eq!(Some(usdc.price_account), default_price_account("USDC"));
+        let configured = Pubkey::new_unique();
+        assert_eq!(
+            OracleFeed::of_mint(&Asset::Sol.mint(), Some(configured))
+                .unwrap()
+                .price_account,
+            configured
+        );
+        assert!(OracleFeed::of_mint(&Pubkey::new_unique(), None).is_err());
+    }
+
+    #[test]
+    fn test_feed_risk() {
+        let price = PythPrice {
+            price: 150.0,
+            confidence: 0.75,
+            publish_time: 1_000,
+            trading: true,
+        };
+        // 0.5% wide and 10s old: the confidence dominates
+        let (ratio, staleness, risk) = calculate_feed_risk(&price, 1_010);
+        assert!((ratio - 0.005).abs() < 1e-12);
+        assert_eq!(staleness, 10);
+        assert!((risk - 50.0).abs() < 1e-9);
+        // 2 minutes old
+        assert_eq!(calculate_feed_risk(&price, 1_120).2, 100.0);
+        let halted = PythPrice {
+            trading: false,
+            ..price
+        };
+        assert_eq!(calculate_feed_risk(&halted, 1_000).2, 100.0);
+
+        let feed = |oracle_risk| OracleFeedMetrics {
+            asset: String::new(),
+            price_account: String::new(),
+            price: 1.0,
+            confidence: 0.0,
+            confidence_ratio: 0.0,
+            staleness_seconds: 0,
+            trading: true,
+            oracle_risk,
+        };
+        assert_eq!(
+            calculate_oracle_risk(vec![feed(10.0), feed(40.0)]).oracle_risk,
+            40.0
+        );
+        assert_eq!(calculate_oracle_risk(Vec::new()).oracle_risk, 0.0);
+    }
+}
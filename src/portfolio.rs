@@ -0,0 +1,843 @@
+use std::{collections::HashMap, str::FromStr};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{
+    assets::Asset,
+    bps::Bps,
+    cache_lock::with_lock,
+    cluster::rpc_url,
+    feasibility::{
+        check_legs, fee_lamports_per_leg, fetch_signer_balances, plan_signer, Feasibility, PlanLeg,
+        SignerBalances,
+    },
+    idempotency::{idempotency_key, idempotent, idempotent_response, key_from_headers},
+    kamino::{self, reserve::KaminoReserveConfig, reserve_account::fetch_reserve_account},
+    marginfi,
+    portfolio_events::{PortfolioEventKind, PortfolioStore, RedisPortfolioStore},
+    prices::Valued,
+    proposals::load_applied_weights,
+    rebalance_worker::{publish_plan, wallet_lock_key},
+    rebalancing::{
+        min_transfer_amounts, split_proportionally, LiveRiskModel, RebalancePlan, RebalanceSystem,
+        RebalancingSystem, UserPortfolio,
+    },
+    risk_model::{
+        json_response, timings_requested, DebugQuery, Protocol, RiskCalculationError, RiskProfile,
+    },
+    state::AppState,
+    timings::with_timings,
+    tx_builder::build_deposit_transactions,
+    upstream_fixtures::rpc_client,
+};
+
+/// Protocols whose positions can't be scanned yet
+const UNSUPPORTED_PROTOCOLS: [Protocol; 2] = [Protocol::Solend, Protocol::Drift];
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ImportRequest {
+    /// Profile the imported positions are assigned to and rebalanced towards
+    pub profile: RiskProfile,
+}
+
+/// One leg of the rebalance suggested after an import
+#[derive(Debug, Serialize, PartialEq, JsonSchema)]
+pub struct SuggestedAllocation {
+    pub protocol: Protocol,
+    pub current_amount: u64,
+    pub target_amount: u64,
+    pub target_basis_points: Bps,
+    /// Amount to move into (positive) or out of (negative) the protocol
+    pub delta: i128,
+    /// Whether the signer can execute the move, legs without a move aren't checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feasibility: Option<Feasibility>,
+}
+
+/// Target weights in basis points for a profile, given protocols ranked from lowest to highest risk
+///
+/// Low only uses the lowest-risk protocol, Medium the two lowest and High all of
+/// them. Within a profile, weights are inversely proportional to overall risk
+/// and always sum to 10,000. Custom profiles are solved for their target risk,
+/// see [`target_risk_weights`].
+pub fn profile_target_weights(
+    profile: &RiskProfile,
+    ranked_risks: &[(Protocol, f64)],
+) -> Vec<(Protocol, u64)> {
+    let protocol_count = match profile {
+        RiskProfile::Low => 1,
+        RiskProfile::Medium => 2,
+        RiskProfile::High => ranked_risks.len(),
+        RiskProfile::Custom {
+            target_risk,
+            max_per_protocol_bps,
+        } => return target_risk_weights(*target_risk, *max_per_protocol_bps, ranked_risks),
+    };
+    let inverse_risks: Vec<(Protocol, u64)> = ranked_risks
+        .iter()
+        .take(protocol_count)
+        .map(|(protocol, risk)| (protocol.clone(), (1_000_000.0 / risk.max(1e-6)) as u64))
+        .collect();
+    split_proportionally(Bps::FULL.0, &inverse_risks)
+}
+
+/// Weights whose average overall risk is as close to `target_risk` as the cap allows
+///
+/// Filling protocols up to the cap from the lowest-risk end gives the lowest
+/// reachable risk, filling from the highest-risk end the highest. Average risk
+/// is linear in the weights, so the blend of both allocations that hits the
+/// target stays within the cap. Targets outside the reachable range get the
+/// nearest end. A cap too low to place all the weight is raised to an even split.
+pub fn target_risk_weights(
+    target_risk: f64,
+    max_per_protocol_bps: u64,
+    ranked_risks: &[(Protocol, f64)],
+) -> Vec<(Protocol, u64)> {
+    if ranked_risks.is_empty() {
+        return Vec::new();
+    }
+    let cap =
+        max_per_protocol_bps.clamp(Bps::FULL.0.div_ceil(ranked_risks.len() as u64), Bps::FULL.0);
+    let fill = |order: Vec<usize>| {
+        let mut weights = vec![0; ranked_risks.len()];
+        let mut left = Bps::FULL.0;
+        for i in order {
+            weights[i] = cap.min(left);
+            left -= weights[i];
+        }
+        weights
+    };
+    let average_risk = |weights: &[u64]| {
+        weights
+            .iter()
+            .zip(ranked_risks)
+            .map(|(weight, (_, risk))| *weight as f64 * risk)
+            .sum::<f64>()
+            / Bps::FULL.0 as f64
+    };
+    let safest = fill((0..ranked_risks.len()).collect());
+    let riskiest = fill((0..ranked_risks.len()).rev().collect());
+    let (lowest, highest) = (average_risk(&safest), average_risk(&riskiest));
+    let blend = if highest > lowest {
+        ((target_risk - lowest) / (highest - lowest)).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    // Scaled up so rounding to basis points happens once, in `split_proportionally`
+    let blended: Vec<(Protocol, u64)> = ranked_risks
+        .iter()
+        .zip(safest.iter().zip(&riskiest))
+        .map(|((protocol, _), (safe, risky))| {
+            let weight = (1.0 - blend) * *safe as f64 + blend * *risky as f64;
+            (protocol.clone(), (weight * 1_000.0).round() as u64)
+        })
+        .collect();
+    split_proportionally(Bps::FULL.0, &blended)
+        .into_iter()
+        .filter(|(_, weight)| *weight > 0)
+        .collect()
+}
+
+/// Moves needed to bring `current` positions to `target_weights`
+pub fn suggest_rebalance(
+    current: &HashMap<Protocol, u64>,
+    target_weights: &[(Protocol, u64)],
+) -> Vec<SuggestedAllocation> {
+    let total_amount = current.values().sum::<u64>();
+    let targets = split_proportionally(total_amount, target_weights);
+
+    let mut suggestions: Vec<SuggestedAllocation> = target_weights
+        .iter()
+        .zip(targets)
+        .map(|((protocol, basis_points), (_, target_amount))| {
+            let current_amount = current.get(protocol).copied().unwrap_or(0);
+            SuggestedAllocation {
+                protocol: protocol.clone(),
+                current_amount,
+                target_amount,
+                target_basis_points: Bps(*basis_points),
+                delta: target_amount as i128 - current_amount as i128,
+                feasibility: None,
+            }
+        })
+        .collect();
+    // Positions in protocols outside the target are withdrawn entirely
+    for (protocol, current_amount) in current {
+        if !target_weights.iter().any(|(target, _)| target == protocol) {
+            suggestions.push(SuggestedAllocation {
+                protocol: protocol.clone(),
+                current_amount: *current_amount,
+                target_amount: 0,
+                target_basis_points: Bps::ZERO,
+                delta: -(*current_amount as i128),
+                feasibility: None,
+            });
+        }
+    }
+    suggestions
+}
+
+/// Flags every move of a suggested rebalance the signer's balances can't cover
+pub fn check_rebalance_feasibility(
+    suggestions: &mut [SuggestedAllocation],
+    balances: &SignerBalances,
+    fee_lamports_per_leg: u64,
+) {
+    let mut moves: Vec<&mut SuggestedAllocation> = suggestions
+        .iter_mut()
+        .filter(|suggestion| suggestion.delta != 0)
+        .collect();
+    let legs: Vec<PlanLeg> = moves
+        .iter()
+        .map(|suggestion| match suggestion.delta {
+            delta if delta > 0 => PlanLeg::Deposit(delta as u64),
+            delta => PlanLeg::Withdraw(delta.unsigned_abs() as u64),
+        })
+        .collect();
+    for (suggestion, feasibility) in
+        moves
+            .iter_mut()
+            .zip(check_legs(balances, &legs, fee_lamports_per_leg))
+    {
+        suggestion.feasibility = Some(feasibility);
+    }
+}
+
+/// Balances in `asset` of the account signing the wallet's plans and the fee budget per leg
+async fn signer_balances(
+    wallet: &Pubkey,
+    asset: Asset,
+) -> Result<(SignerBalances, u64), RiskCalculationError> {
+    let balances = fetch_signer_balances(&plan_signer(wallet)?, &asset.mint()).await?;
+    Ok((balances, fee_lamports_per_leg()?))
+}
+
+/// Current USDC positions of a wallet in every supported protocol, in native units
+pub async fn scan_positions(
+    wallet: &Pubkey,
+    kamino_reserve: &KaminoReserveConfig,
+) -> Result<HashMap<Protocol, u64>, RiskCalculationError> {
+    let (kamino_deposit, marginfi_deposit) = futures::try_join!(
+        kamino::positions::fetch_wallet_deposit(wallet, kamino_reserve),
+        marginfi::positions::fetch_wallet_deposit(wallet),
+    )?;
+    Ok([
+        (Protocol::Kamino, kamino_deposit),
+        (Protocol::Marginfy, marginfi_deposit),
+    ]
+    .into_iter()
+    .filter(|(_, amount)| *amount > 0)
+    .collect())
+}
+
+pub async fn import_portfolio(
+    State(state): State<AppState>,
+    Path(wallet): Path<String>,
+    Json(request): Json<ImportRequest>,
+) -> Response {
+    let result = async {
+        let wallet = Pubkey::from_str(&wallet)
+            .map_err(|e| RiskCalculationError::InvalidParameter(format!("wallet: {}", e)))?;
+        let kamino_reserve = &state.config.kamino_reserve;
+        let positions = scan_positions(&wallet, kamino_reserve).await?;
+        let snapshot = state.registry.cached_snapshot().await?;
+        let ranked_risks: Vec<(Protocol, f64)> = snapshot
+            .comparison
+            .ranking
+            .iter()
+            .map(|assessment| {
+                (
+                    assessment.protocol.clone(),
+                    assessment.risk_metrics.overall_risk.overall_risk,
+                )
+            })
+            .collect();
+        let target_weights = profile_target_weights(&request.profile, &ranked_risks);
+
+        let portfolio = RedisPortfolioStore::new(state.redis_client.clone())
+            .append(
+                &wallet.to_string(),
+                PortfolioEventKind::Imported {
+                    profile: request.profile.clone(),
+                    asset: Asset::Usdc,
+                    positions: positions.clone(),
+                },
+            )
+            .await?
+            .to_portfolio()?;
+        tracing::info!("Imported portfolio\n{}", portfolio);
+
+        let mut suggested_allocation = suggest_rebalance(&positions, &target_weights);
+        // Moves are only annotated when balances could be read, the import doesn't depend on it
+        match signer_balances(&wallet, Asset::Usdc).await {
+            Ok((balances, fee)) => {
+                check_rebalance_feasibility(&mut suggested_allocation, &balances, fee)
+            }
+            Err(e) => tracing::error!("Failed to check rebalance feasibility: {}", e),
+        }
+
+        let response = serde_json::json!({
+            "wallet": portfolio.user_wallet.to_string(),
+            "profile": request.profile,
+            "positions": positions
+                .iter()
+                .map(|(protocol, amount)| serde_json::json!({
+                    "protocol": protocol,
+                    "amount": amount,
+                }))
+                .collect::<Vec<_>>(),
+            "asset": Asset::Usdc,
+            "total_amount": portfolio
+                .allocation(Asset::Usdc, &request.profile)
+                .map_or(0, |allocation| allocation.total_amount),
+            "snapshot_id": snapshot.snapshot_id,
+            "suggested_allocation": suggested_allocation,
+            "unsupported_protocols": UNSUPPORTED_PROTOCOLS,
+        });
+        Ok::<_, RiskCalculationError>(Json(
+            Valued::new(response, &state.redis_client, &portfolio).await,
+        ))
+    }
+    .await;
+
+    match result {
+        Ok(json) => json.into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct AmountRequest {
+    pub profile: RiskProfile,
+    /// Symbol or mint of the asset, USDC by default
+    #[serde(default)]
+    pub asset: Asset,
+    /// In native units of the asset
+    pub amount: Option<u64>,
+    /// In tokens, converted with the asset's decimals, instead of `amount`
+    pub ui_amount: Option<f64>,
+}
+
+impl AmountRequest {
+    /// The amount in native units, from whichever of `amount` and `ui_amount` is set
+    pub fn native_amount(&self) -> Result<u64, RiskCalculationError> {
+        match (self.amount, self.ui_amount) {
+            (Some(amount), None) => Ok(amount),
+            (None, Some(ui_amount)) => self.asset.from_ui_amount(ui_amount),
+            _ => Err(RiskCalculationError::InvalidParameter(
+                "exactly one of amount and ui_amount is required".to_string(),
+            )),
+        }
+    }
+}
+
+/// The wallet's portfolio and a rebalancing system weighting by the latest snapshot
+async fn load_rebalancing(
+    state: &AppState,
+    wallet: &Pubkey,
+) -> Result<
+    (
+        RedisPortfolioStore,
+        UserPortfolio,
+        RebalancingSystem<LiveRiskModel>,
+    ),
+    RiskCalculationError,
+> {
+    // Weight changes only reach portfolios once their proposal is approved
+    let approved_weights = load_applied_weights(&state.redis_client)
+        .await?
+        .into_iter()
+        .map(|(profile, weights)| {
+            let weights = weights
+                .into_iter()
+                .map(|(protocol, weight)| (protocol, weight.0))
+                .collect();
+            (profile, weights)
+        })
+        .collect();
+    let model = LiveRiskModel::load(&state.registry)
+        .await?
+        .with_approved_weights(approved_weights);
+    let store = RedisPortfolioStore::new(state.redis_client.clone());
+    let portfolio = store
+        .load(wallet)
+        .await?
+        .unwrap_or_else(|| UserPortfolio::new(*wallet));
+    let mut rebalancing = RebalancingSystem::new(model);
+    rebalancing.min_transfer_amounts = min_transfer_amounts()?;
+    rebalancing.transfer_costs = state.config.transfer_costs.clone();
+    rebalancing.exposure_limits = state.config.exposure_limits.clone();
+    Ok((store, portfolio, rebalancing))
+}
+
+/// Runs `change` holding the wallet's lock, see [`wallet_lock_key`]
+///
+/// Every change to a portfolio reads it and appends an event computed from
+/// it, holding the lock keeps another change from landing in between.
+async fn with_wallet_lock<T, F>(
+    state: &AppState,
+    wallet: &Pubkey,
+    change: F,
+) -> Result<T, RiskCalculationError>
+where
+    F: std::future::Future<Output = Result<T, RiskCalculationError>>,
+{
+    with_lock(Some(&state.redis_client), &wallet_lock_key(wallet), change).await
+}
+
+/// Checks the portfolio holds `amount` of `asset` under `profile`, before
+/// withdrawing it
+fn check_withdrawable(
+    portfolio: &UserPortfolio,
+    asset: Asset,
+    profile: &RiskProfile,
+    amount: u64,
+) -> Result<(), RiskCalculationError> {
+    let Some(allocation) = portfolio
+        .assets
+        .get(&asset)
+        .and_then(|profiles| profiles.get(profile))
+    else {
+        return Err(RiskCalculationError::NotFound(format!(
+            "No {} held under the {} profile",
+            asset, profile
+        )));
+    };
+    if amount > allocation.total_amount {
+        return Err(RiskCalculationError::InvalidParameter(format!(
+            "Insufficient funds for withdrawal: {} held",
+            allocation.total_amount
+        )));
+    }
+    Ok(())
+}
+
+/// Fingerprint of a portfolio operation, a retry with the same `Idempotency-Key` must match it
+fn fingerprint(request: &AmountRequest) -> Result<String, RiskCalculationError> {
+    serde_json::to_string(request).map_err(RiskCalculationError::SerdeError)
+}
+
+/// Allocates a deposit by the latest risk scores and records it
+///
+/// Deposits the signer's balances can't cover are rejected, not recorded.
+/// Retries with the `Idempotency-Key` of an earlier deposit get its response
+/// instead of depositing again.
+pub async fn deposit(
+    State(state): State<AppState>,
+    Path(wallet): Path<String>,
+    Query(query): Query<DebugQuery>,
+    headers: HeaderMap,
+    Json(request): Json<AmountRequest>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let wallet = Pubkey::from_str(&wallet)
+            .map_err(|e| RiskCalculationError::InvalidParameter(format!("wallet: {}", e)))?;
+        let key = key_from_headers(&headers)?
+            .map(|key| idempotency_key("deposit", &wallet.to_string(), &key));
+        let deposit = with_wallet_lock(&state, &wallet, async {
+            let (store, mut portfolio, mut system) = load_rebalancing(&state, &wallet).await?;
+            let mut deposits = system
+                .deposit(
+                    &mut portfolio,
+                    request.asset,
+                    request.profile.clone(),
+                    request.native_amount()?,
+                )
+                .map_err(RiskCalculationError::Conflict)?;
+            match signer_balances(&wallet, request.asset).await {
+                Ok((balances, fee)) => deposits.check_feasibility(&balances, fee),
+                Err(e) => tracing::error!("Failed to check deposit feasibility: {}", e),
+            }
+            deposits
+                .ensure_feasible()
+                .map_err(RiskCalculationError::InvalidParameter)?;
+            store
+                .append(
+                    &wallet.to_string(),
+                    PortfolioEventKind::Deposited {
+                        profile: request.profile.clone(),
+                        asset: request.asset,
+                        allocations: deposits
+                            .deposits_to_execute
+                            .iter()
+                            .map(|deposit| (deposit.protocol.clone(), deposit.amount))
+                            .collect(),
+                        weights: deposits
+                            .deposits_to_execute
+                            .iter()
+                            .map(|deposit| {
+                                (deposit.protocol.clone(), deposit.allocation_basis_points)
+                            })
+                            .collect(),
+                    },
+                )
+                .await?;
+            Ok::<_, RiskCalculationError>(
+                Valued::new(deposits, &state.redis_client, &portfolio).await,
+            )
+        });
+        idempotent(&state.redis_client, key, fingerprint(&request)?, deposit).await
+    })
+    .await;
+
+    idempotent_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+/// Builds the transactions of a deposit for the signer to sign, without recording it
+///
+/// The deposit is recorded once the transactions land, through `/deposit`.
+pub async fn build_deposit(
+    State(state): State<AppState>,
+    Path(wallet): Path<String>,
+    Query(query): Query<DebugQuery>,
+    Json(request): Json<AmountRequest>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let wallet = Pubkey::from_str(&wallet)
+            .map_err(|e| RiskCalculationError::InvalidParameter(format!("wallet: {}", e)))?;
+        let (_, mut portfolio, mut system) = load_rebalancing(&state, &wallet).await?;
+        let deposits = system
+            .deposit(
+                &mut portfolio,
+                request.asset,
+                request.profile.clone(),
+                request.native_amount()?,
+            )
+            .map_err(RiskCalculationError::Conflict)?;
+        let client = rpc_client(rpc_url()?);
+        let (reserve_account, recent_blockhash) =
+            futures::try_join!(fetch_reserve_account(&state.config.kamino_reserve), async {
+                client
+                    .get_latest_blockhash()
+                    .await
+                    .map_err(RiskCalculationError::from)
+            },)?;
+        if reserve_account.liquidity_mint != request.asset.mint() {
+            return Err(RiskCalculationError::InvalidParameter(format!(
+                "the Kamino reserve doesn't hold {}",
+                request.asset
+            )));
+        }
+        build_deposit_transactions(
+            &plan_signer(&wallet)?,
+            &deposits,
+            &state.config.kamino_reserve,
+            &reserve_account,
+            recent_blockhash,
+        )
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+/// Withdraws from every protocol of a profile proportionally and records it
+///
+/// Withdrawals whose fees the signer can't cover are rejected, not recorded.
+/// Retries with the `Idempotency-Key` of an earlier withdrawal get its
+/// response instead of withdrawing again.
+pub async fn withdraw(
+    State(state): State<AppState>,
+    Path(wallet): Path<String>,
+    Query(query): Query<DebugQuery>,
+    headers: HeaderMap,
+    Json(request): Json<AmountRequest>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let wallet = Pubkey::from_str(&wallet)
+            .map_err(|e| RiskCalculationError::InvalidParameter(format!("wallet: {}", e)))?;
+        let key = key_from_headers(&headers)?
+            .map(|key| idempotency_key("withdraw", &wallet.to_string(), &key));
+        let withdrawal = with_wallet_lock(&state, &wallet, async {
+            let (store, mut portfolio, mut system) = load_rebalancing(&state, &wallet).await?;
+            let amount = request.native_amount()?;
+            check_withdrawable(&portfolio, request.asset, &request.profile, amount)?;
+            // What's left are the allocations disagreeing with the portfolio's total
+            let mut withdrawals = system
+                .withdraw(&mut portfolio, request.asset, &request.profile, amount)
+                .map_err(RiskCalculationError::Conflict)?;
+            match signer_balances(&wallet, request.asset).await {
+                Ok((balances, fee)) => withdrawals.check_feasibility(&balances, fee),
+                Err(e) => tracing::error!("Failed to check withdrawal feasibility: {}", e),
+            }
+            withdrawals
+                .ensure_feasible()
+                .map_err(RiskCalculationError::InvalidParameter)?;
+            store
+                .append(
+                    &wallet.to_string(),
+                    PortfolioEventKind::Withdrawn {
+                        profile: request.profile.clone(),
+                        asset: request.asset,
+                        withdrawals: withdrawals
+                            .withdrawals_to_execute
+                            .iter()
+                            .map(|withdrawal| (withdrawal.protocol.clone(), withdrawal.amount))
+                            .collect(),
+                    },
+                )
+                .await?;
+            Ok::<_, RiskCalculationError>(
+                Valued::new(withdrawals, &state.redis_client, &portfolio).await,
+            )
+        });
+        idempotent(&state.redis_client, key, fingerprint(&request)?, withdrawal).await
+    })
+    .await;
+
+    idempotent_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+/// Moves every profile of every asset of the portfolio to its target weights,
+/// recording it unless `dry_run`
+pub async fn rebalance_wallet(
+    state: &AppState,
+    wallet: &Pubkey,
+    dry_run: bool,
+) -> Result<RebalancePlan, RiskCalculationError> {
+    let rebalance = async {
+        let (store, mut portfolio, mut system) = load_rebalancing(state, wallet).await?;
+        let plan = system
+            .rebalance(&mut portfolio)
+            .map_err(RiskCalculationError::Conflict)?;
+        if !dry_run {
+            record_rebalance(&store, &portfolio, &plan).await?;
+        }
+        Ok(plan)
+    };
+    if dry_run {
+        return rebalance.await;
+    }
+    with_wallet_lock(state, wallet, rebalance).await
+}
+
+/// Like [`rebalance_wallet`], only when the portfolio holds something and its
+/// rebalance is due by time or drift, publishing the plan for the transaction system
+///
+/// The caller holds the wallet's lock. The plan is published before the
+/// rebalance is recorded, so a failed publish leaves the wallet due.
+pub async fn rebalance_wallet_if_due(
+    state: &AppState,
+    wallet: &Pubkey,
+) -> Result<Option<RebalancePlan>, RiskCalculationError> {
+    let (store, mut portfolio, mut system) = load_rebalancing(state, wallet).await?;
+    if portfolio.assets.is_empty() || !system.should_rebalance(&portfolio) {
+        return Ok(None);
+    }
+    let plan = system
+        .rebalance(&mut portfolio)
+        .map_err(RiskCalculationError::Conflict)?;
+    publish_plan(&state.redis_client, wallet, &plan).await?;
+    if let Err(e) = record_rebalance(&store, &portfolio, &plan).await {
+        tracing::error!(
+            "Published the rebalance of {} but failed to record it: {}",
+            wallet,
+            e
+        );
+        return Err(e);
+    }
+    Ok(Some(plan))
+}
+
+/// Appends a Rebalanced event per asset of the rebalanced `portfolio`
+async fn record_rebalance(
+    store: &RedisPortfolioStore,
+    portfolio: &UserPortfolio,
+    plan: &RebalancePlan,
+) -> Result<(), RiskCalculationError> {
+    let wallet = portfolio.user_wallet;
+    for (asset, profiles) in &portfolio.assets {
+        let decided = || {
+            plan.profiles
+                .iter()
+                .filter(move |rebalance| rebalance.asset == *asset)
+        };
+        store
+            .append(
+                &wallet.to_string(),
+                PortfolioEventKind::Rebalanced {
+                    asset: *asset,
+                    allocations: profiles
+                        .iter()
+                        .map(|(profile, allocation)| {
+                            (profile.clone(), allocation.pool_allocations.clone())
+                        })
+                        .collect(),
+                    target_weights: decided()
+                        .map(|rebalance| {
+                            let weights = rebalance.target_weights.iter().cloned().collect();
+                            (rebalance.profile.clone(), weights)
+                        })
+                        .collect(),
+                    transfers: decided()
+                        .map(|rebalance| (rebalance.profile.clone(), rebalance.transfers.clone()))
+                        .collect(),
+                },
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+/// Moves every profile of the portfolio to its target weights and records it
+pub async fn rebalance(
+    State(state): State<AppState>,
+    Path(wallet): Path<String>,
+    Query(query): Query<DebugQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let wallet = Pubkey::from_str(&wallet)
+            .map_err(|e| RiskCalculationError::InvalidParameter(format!("wallet: {}", e)))?;
+        let plan = rebalance_wallet(&state, &wallet, false).await?;
+        let portfolio = RedisPortfolioStore::new(state.redis_client.clone())
+            .load(&wallet)
+            .await?
+            .unwrap_or_else(|| UserPortfolio::new(wallet));
+        Ok::<_, RiskCalculationError>(Valued::new(plan, &state.redis_client, &portfolio).await)
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rebalancing::ProfileAllocation;
+
+    #[test]
+    fn test_profile_target_weights() {
+        let ranked = [(Protocol::Marginfy, 20.0), (Protocol::Kamino, 60.0)];
+        assert_eq!(
+            profile_target_weights(&RiskProfile::Low, &ranked),
+            vec![(Protocol::Marginfy, 10_000)]
+        );
+        let high = profile_target_weights(&RiskProfile::High, &ranked);
+        assert_eq!(
+            high,
+            vec![(Protocol::Marginfy, 7_500), (Protocol::Kamino, 2_500)]
+        );
+    }
+
+    #[test]
+    fn test_target_risk_weights() {
+        let ranked = [
+            (Protocol::Marginfy, 20.0),
+            (Protocol::Kamino, 40.0),
+            (Protocol::Solend, 80.0),
+        ];
+        let average_risk = |weights: &[(Protocol, u64)]| {
+            weights
+                .iter()
+                .map(|(protocol, weight)| {
+                    let risk = ranked
+                        .iter()
+                        .find(|(ranked, _)| ranked == protocol)
+                        .unwrap()
+                        .1;
+                    *weight as f64 * risk / 10_000.0
+                })
+                .sum::<f64>()
+        };
+
+        let weights = target_risk_weights(40.0, 6_000, &ranked);
+        assert!((average_risk(&weights) - 40.0).abs() < 0.01);
+        assert!(weights.iter().all(|(_, weight)| *weight <= 6_000));
+        assert_eq!(
+            weights.iter().map(|(_, weight)| weight).sum::<u64>(),
+            10_000
+        );
+
+        // Below the reachable range: as safe as the cap allows
+        assert_eq!(
+            target_risk_weights(5.0, 6_000, &ranked),
+            vec![(Protocol::Marginfy, 6_000), (Protocol::Kamino, 4_000)]
+        );
+        // A cap below an even split is raised to it
+        let even = target_risk_weights(0.0, 1_000, &ranked);
+        assert_eq!(even.len(), 3);
+
+        let custom = RiskProfile::custom(40.0, 6_000).unwrap();
+        assert_eq!(profile_target_weights(&custom, &ranked), weights);
+    }
+
+    #[test]
+    fn test_suggest_rebalance() {
+        let current = HashMap::from([(Protocol::Kamino, 300), (Protocol::Solend, 100)]);
+        let suggestions = suggest_rebalance(&current, &[(Protocol::Marginfy, 10_000)]);
+        let delta = |protocol| {
+            suggestions
+                .iter()
+                .find(|suggestion| suggestion.protocol == protocol)
+                .unwrap()
+                .delta
+        };
+        assert_eq!(suggestions.len(), 3);
+        assert_eq!(suggestions[0].target_amount, 400);
+        assert_eq!(delta(Protocol::Marginfy), 400);
+        assert_eq!(delta(Protocol::Kamino), -300);
+        assert_eq!(delta(Protocol::Solend), -100);
+    }
+
+    #[test]
+    fn test_check_rebalance_feasibility() {
+        let current = HashMap::from([(Protocol::Kamino, 300)]);
+        let mut suggestions = suggest_rebalance(
+            &current,
+            &[(Protocol::Kamino, 5_000), (Protocol::Marginfy, 5_000)],
+        );
+        let balances = SignerBalances {
+            lamports: 10_000_000,
+            deposit_asset: 0,
+        };
+        check_rebalance_feasibility(&mut suggestions, &balances, 5_000);
+        // The withdrawal from Kamino funds the Marginfi deposit
+        assert!(suggestions.iter().all(|suggestion| suggestion
+            .feasibility
+            .as_ref()
+            .unwrap()
+            .feasible));
+
+        let balances = SignerBalances {
+            lamports: 0,
+            ..balances
+        };
+        check_rebalance_feasibility(&mut suggestions, &balances, 5_000);
+        assert!(suggestions.iter().all(|suggestion| !suggestion
+            .feasibility
+            .as_ref()
+            .unwrap()
+            .feasible));
+    }
+
+    #[test]
+    fn test_check_withdrawable() {
+        let mut portfolio = UserPortfolio::new(Pubkey::new_unique());
+        let profile = RiskProfile::Medium;
+        assert!(matches!(
+            check_withdrawable(&portfolio, Asset::Usdc, &profile, 1),
+            Err(RiskCalculationError::NotFound(_))
+        ));
+        let mut allocation = ProfileAllocation::empty(profile.clone(), Asset::Usdc);
+        allocation.total_amount = 100;
+        portfolio
+            .assets
+            .entry(Asset::Usdc)
+            .or_default()
+            .insert(profile.clone(), allocation);
+        assert!(check_withdrawable(&portfolio, Asset::Usdc, &profile, 100).is_ok());
+        assert!(matches!(
+            check_withdrawable(&portfolio, Asset::Usdc, &profile, 101),
+            Err(RiskCalculationError::InvalidParameter(_))
+        ));
+    }
+}
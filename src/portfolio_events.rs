@@ -0,0 +1,998 @@
+use std::{collections::HashMap, str::FromStr, time::UNIX_EPOCH};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::Response,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{
+    assets::Asset,
+    bps::Bps,
+    cache_schema::{record_key, versioned_key},
+    multisig::{approval_from_headers, authorize, AdminAction, MultisigApproval},
+    prices::Valued,
+    rebalancing::{PoolTransfer, ProfileAllocation, UserPortfolio},
+    redis_builder::RedisConnection,
+    redis_connection::shared_connection,
+    risk_model::{
+        json_response, timings_requested, DebugQuery, Protocol, RiskCalculationError, RiskProfile,
+    },
+    state::AppState,
+    timings::{timed, with_timings, Timing},
+};
+
+/// Prefix of the event log keys within the cluster's namespace, see [`events_key`]
+fn events_prefix() -> String {
+    record_key("portfolio_events:")
+}
+
+/// Event log of a wallet, a redis list appended to in order
+///
+/// Events are the source of truth and can't be recomputed, so unlike cached
+/// values the key isn't versioned and survives cache migrations. Events only
+/// ever gain fields with defaults, so old events keep parsing.
+pub fn events_key(wallet: &str) -> String {
+    format!("{}{}", events_prefix(), wallet)
+}
+
+/// Stored projection of a wallet, rebuilt from the event log at any time
+pub fn projection_key(wallet: &str) -> String {
+    versioned_key(&format!("portfolio:{}", wallet))
+}
+
+/// Something that happened to a portfolio
+///
+/// Every event concerns a single asset, amounts are in its native units.
+/// Events recorded before portfolios held other assets are USDC.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PortfolioEventKind {
+    /// On-chain positions were scanned, they replace what the profile held
+    Imported {
+        profile: RiskProfile,
+        #[serde(default)]
+        asset: Asset,
+        positions: HashMap<Protocol, u64>,
+    },
+    Deposited {
+        profile: RiskProfile,
+        #[serde(default)]
+        asset: Asset,
+        allocations: HashMap<Protocol, u64>,
+        /// Weights the deposit was split by, empty for events recorded before
+        /// they were kept
+        #[serde(default)]
+        weights: HashMap<Protocol, Bps>,
+    },
+    Withdrawn {
+        profile: RiskProfile,
+        #[serde(default)]
+        asset: Asset,
+        withdrawals: HashMap<Protocol, u64>,
+    },
+    /// Allocations of the rebalanced profiles after the rebalance
+    Rebalanced {
+        #[serde(default)]
+        asset: Asset,
+        allocations: HashMap<RiskProfile, HashMap<Protocol, u64>>,
+        /// Weights every profile was rebalanced to, empty for events recorded
+        /// before they were kept
+        #[serde(default)]
+        target_weights: HashMap<RiskProfile, HashMap<Protocol, Bps>>,
+        /// Transfers the plan handed to the transaction system, to reconcile
+        /// with what it executed
+        #[serde(default)]
+        transfers: HashMap<RiskProfile, Vec<PoolTransfer>>,
+    },
+    /// The asset's holdings were saved, they replace every profile of the asset
+    Saved {
+        #[serde(default)]
+        asset: Asset,
+        allocations: HashMap<RiskProfile, HashMap<Protocol, u64>>,
+        last_rebalance: Option<DateTime<Utc>>,
+    },
+}
+
+impl PortfolioEventKind {
+    pub fn asset(&self) -> Asset {
+        match self {
+            PortfolioEventKind::Imported { asset, .. }
+            | PortfolioEventKind::Deposited { asset, .. }
+            | PortfolioEventKind::Withdrawn { asset, .. }
+            | PortfolioEventKind::Rebalanced { asset, .. }
+            | PortfolioEventKind::Saved { asset, .. } => *asset,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PortfolioEvent {
+    /// Position in the wallet's log starting at 1, assigned when the log is read
+    #[serde(skip_deserializing)]
+    pub sequence: u64,
+    pub recorded_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: PortfolioEventKind,
+}
+
+/// State of a portfolio after applying its events in order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PortfolioProjection {
+    pub wallet: String,
+    /// Sequence of the last applied event, 0 if none was
+    pub last_sequence: u64,
+    /// When the last applied event was recorded
+    pub as_of: Option<DateTime<Utc>>,
+    /// Profiles of every asset held
+    pub assets: HashMap<Asset, HashMap<RiskProfile, ProfileAllocation>>,
+    pub last_rebalance: Option<DateTime<Utc>>,
+}
+
+impl PortfolioProjection {
+    pub fn empty(wallet: &str) -> Self {
+        PortfolioProjection {
+            wallet: wallet.to_string(),
+            last_sequence: 0,
+            as_of: None,
+            assets: HashMap::new(),
+            last_rebalance: None,
+        }
+    }
+
+    /// Replays `events` (oldest first) recorded up to `until`, all of them if `None`
+    pub fn replay(wallet: &str, events: &[PortfolioEvent], until: Option<DateTime<Utc>>) -> Self {
+        let mut projection = Self::empty(wallet);
+        events
+            .iter()
+            .take_while(|event| until.is_none_or(|until| event.recorded_at <= until))
+            .for_each(|event| projection.apply(event));
+        projection
+    }
+
+    pub fn apply(&mut self, event: &PortfolioEvent) {
+        let asset = event.kind.asset();
+        let profiles = self.assets.entry(asset).or_default();
+        let mut touched = Vec::new();
+        match &event.kind {
+            PortfolioEventKind::Imported {
+                profile, positions, ..
+            } => {
+                profile_allocation(profiles, asset, profile).pool_allocations = positions.clone();
+                touched.push(profile.clone());
+            }
+            PortfolioEventKind::Deposited {
+                profile,
+                allocations,
+                ..
+            } => {
+                let pools = &mut profile_allocation(profiles, asset, profile).pool_allocations;
+                for (protocol, amount) in allocations {
+                    let pool = pools.entry(protocol.clone()).or_insert(0);
+                    *pool = pool.saturating_add(*amount);
+                }
+                touched.push(profile.clone());
+            }
+            PortfolioEventKind::Withdrawn {
+                profile,
+                withdrawals,
+                ..
+            } => {
+                let pools = &mut profile_allocation(profiles, asset, profile).pool_allocations;
+                for (protocol, amount) in withdrawals {
+                    let pool = pools.entry(protocol.clone()).or_insert(0);
+                    *pool = pool.saturating_sub(*amount);
+                }
+                touched.push(profile.clone());
+            }
+            PortfolioEventKind::Rebalanced { allocations, .. } => {
+                for (profile, pools) in allocations {
+                    profile_allocation(profiles, asset, profile).pool_allocations = pools.clone();
+                    touched.push(profile.clone());
+                }
+                self.last_rebalance = Some(event.recorded_at);
+            }
+            PortfolioEventKind::Saved {
+                allocations,
+                last_rebalance,
+                ..
+            } => {
+                profiles.clear();
+                for (profile, pools) in allocations {
+                    profile_allocation(profiles, asset, profile).pool_allocations = pools.clone();
+                    touched.push(profile.clone());
+                }
+                self.last_rebalance = *last_rebalance;
+            }
+        }
+        for profile in touched {
+            if let Some(allocation) = profiles.get_mut(&profile) {
+                allocation.pool_allocations.retain(|_, amount| *amount > 0);
+                allocation.total_amount = allocation.pool_allocations.values().sum();
+            }
+        }
+        if profiles.is_empty() {
+            self.assets.remove(&asset);
+        }
+        self.last_sequence = event.sequence;
+        self.as_of = Some(event.recorded_at);
+    }
+
+    /// The projection as the portfolio the rebalancer works on
+    pub fn to_portfolio(&self) -> Result<UserPortfolio, RiskCalculationError> {
+        Ok(UserPortfolio {
+            user_wallet: Pubkey::from_str(&self.wallet)
+                .map_err(|e| RiskCalculationError::InvalidParameter(format!("wallet: {}", e)))?,
+            assets: self.assets.clone(),
+            last_rebalance: self
+                .last_rebalance
+                .map_or(UNIX_EPOCH, std::time::SystemTime::from),
+        })
+    }
+}
+
+fn profile_allocation<'a>(
+    profiles: &'a mut HashMap<RiskProfile, ProfileAllocation>,
+    asset: Asset,
+    profile: &RiskProfile,
+) -> &'a mut ProfileAllocation {
+    profiles
+        .entry(profile.clone())
+        .or_insert_with(|| ProfileAllocation::empty(profile.clone(), asset))
+}
+
+/// Persistence of [`UserPortfolio`]s, keyed by wallet
+pub trait PortfolioStore {
+    /// The wallet's portfolio, `None` if nothing was ever recorded for it
+    async fn load(&self, wallet: &Pubkey) -> Result<Option<UserPortfolio>, RiskCalculationError>;
+    /// Replaces the wallet's portfolio with `portfolio`
+    async fn save(&self, portfolio: &UserPortfolio) -> Result<(), RiskCalculationError>;
+    /// Forgets the wallet's portfolio, returns whether there was one
+    async fn delete(&self, wallet: &Pubkey) -> Result<bool, RiskCalculationError>;
+    /// Every wallet with a stored portfolio
+    async fn list(&self) -> Result<Vec<Pubkey>, RiskCalculationError>;
+}
+
+/// Event log and projections of every portfolio
+pub struct RedisPortfolioStore {
+    redis_client: redis::Client,
+}
+
+impl RedisPortfolioStore {
+    pub fn new(redis_client: redis::Client) -> Self {
+        RedisPortfolioStore { redis_client }
+    }
+
+    async fn connection(&self) -> Result<RedisConnection, RiskCalculationError> {
+        shared_connection(&self.redis_client)
+            .await
+            .map_err(RiskCalculationError::from)
+    }
+
+    /// Records an event and brings the wallet's projection up to date
+    pub async fn append(
+        &self,
+        wallet: &str,
+        kind: PortfolioEventKind,
+    ) -> Result<PortfolioProjection, RiskCalculationError> {
+        let event = PortfolioEvent {
+            sequence: 0,
+            recorded_at: Utc::now(),
+            kind,
+        };
+        let body = serde_json::to_string(&event).map_err(RiskCalculationError::SerdeError)?;
+        let _: u64 = self
+            .connection()
+            .await?
+            .rpush(events_key(wallet), body)
+            .await
+            .map_err(RiskCalculationError::from)?;
+        // Replaying instead of applying to the stored projection keeps
+        // concurrent appends from overwriting each other's events
+        self.rebuild(wallet).await
+    }
+
+    /// The wallet's events, oldest first
+    pub async fn events(&self, wallet: &str) -> Result<Vec<PortfolioEvent>, RiskCalculationError> {
+        let events: Vec<String> = timed(
+            Timing::CacheRead,
+            self.connection().await?.lrange(events_key(wallet), 0, -1),
+        )
+        .await
+        .map_err(RiskCalculationError::from)?;
+        events
+            .iter()
+            .zip(1..)
+            .map(|(event, sequence)| {
+                let mut event: PortfolioEvent =
+                    serde_json::from_str(event).map_err(RiskCalculationError::SerdeError)?;
+                event.sequence = sequence;
+                Ok(event)
+            })
+            .collect()
+    }
+
+    /// The stored projection, or one replayed from the log if there's none
+    pub async fn projection(
+        &self,
+        wallet: &str,
+    ) -> Result<PortfolioProjection, RiskCalculationError> {
+        let stored: Option<String> = timed(
+            Timing::CacheRead,
+            self.connection().await?.get(projection_key(wallet)),
+        )
+        .await
+        .map_err(RiskCalculationError::from)?;
+        // Projections stored before they had their current shape are replayed as well
+        match stored.and_then(|stored| serde_json::from_str(&stored).ok()) {
+            Some(projection) => Ok(projection),
+            None => Ok(PortfolioProjection::replay(
+                wallet,
+                &self.events(wallet).await?,
+                None,
+            )),
+        }
+    }
+
+    /// The portfolio as it was at `at`, replayed from the log
+    pub async fn projection_at(
+        &self,
+        wallet: &str,
+        at: DateTime<Utc>,
+    ) -> Result<PortfolioProjection, RiskCalculationError> {
+        Ok(PortfolioProjection::replay(
+            wallet,
+            &self.events(wallet).await?,
+            Some(at),
+        ))
+    }
+
+    /// Replays the wallet's whole log and stores the result as its projection
+    pub async fn rebuild(&self, wallet: &str) -> Result<PortfolioProjection, RiskCalculationError> {
+        let projection = PortfolioProjection::replay(wallet, &self.events(wallet).await?, None);
+        let body = serde_json::to_string(&projection).map_err(RiskCalculationError::SerdeError)?;
+        let _: () = self
+            .connection()
+            .await?
+            .set(projection_key(wallet), body)
+            .await
+            .map_err(RiskCalculationError::from)?;
+        Ok(projection)
+    }
+
+    /// Every wallet with an event log
+    pub async fn wallets(&self) -> Result<Vec<String>, RiskCalculationError> {
+        let mut connection = self.connection().await?;
+        let mut keys: redis::AsyncIter<String> = connection
+            .scan_match(format!("{}*", events_prefix()))
+            .await
+            .map_err(RiskCalculationError::from)?;
+        let mut wallets = Vec::new();
+        while let Some(key) = keys.next_item().await {
+            if let Some(wallet) = key.strip_prefix(&events_prefix()) {
+                wallets.push(wallet.to_string());
+            }
+        }
+        Ok(wallets)
+    }
+}
+
+/// Portfolios are stored as their event log, saving appends a
+/// [`PortfolioEventKind::Saved`] event so the history stays complete
+impl PortfolioStore for RedisPortfolioStore {
+    async fn load(&self, wallet: &Pubkey) -> Result<Option<UserPortfolio>, RiskCalculationError> {
+        let projection = self.projection(&wallet.to_string()).await?;
+        if projection.last_sequence == 0 {
+            return Ok(None);
+        }
+        projection.to_portfolio().map(Some)
+    }
+
+    /// Appends a [`PortfolioEventKind::Saved`] event per asset held before or after
+    async fn save(&self, portfolio: &UserPortfolio) -> Result<(), RiskCalculationError> {
+        let wallet = portfolio.user_wallet.to_string();
+        let last_rebalance = (portfolio.last_rebalance > UNIX_EPOCH)
+            .then(|| DateTime::<Utc>::from(portfolio.last_rebalance));
+        let held = self.projection(&wallet).await?.assets;
+        for asset in Asset::ALL {
+            let profiles = portfolio.assets.get(&asset);
+            if profiles.is_none() && !held.contains_key(&asset) {
+                continue;
+            }
+            self.append(
+                &wallet,
+                PortfolioEventKind::Saved {
+                    asset,
+                    allocations: profiles
+                        .into_iter()
+                        .flatten()
+                        .map(|(profile, allocation)| {
+                            (profile.clone(), allocation.pool_allocations.clone())
+                        })
+                        .collect(),
+                    last_rebalance,
+                },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the event log along with the projection, the wallet's history is lost
+    async fn delete(&self, wallet: &Pubkey) -> Result<bool, RiskCalculationError> {
+        let wallet = wallet.to_string();
+        let deleted: u64 = self
+            .connection()
+            .await?
+            .del(&[events_key(&wallet), projection_key(&wallet)])
+            .await
+            .map_err(RiskCalculationError::from)?;
+        Ok(deleted > 0)
+    }
+
+    async fn list(&self) -> Result<Vec<Pubkey>, RiskCalculationError> {
+        self.wallets()
+            .await?
+            .iter()
+            .map(|wallet| {
+                Pubkey::from_str(wallet)
+                    .map_err(|e| RiskCalculationError::InvalidParameter(format!("wallet: {}", e)))
+            })
+            .collect()
+    }
+}
+
+fn store(state: &AppState) -> RedisPortfolioStore {
+    RedisPortfolioStore::new(state.redis_client.clone())
+}
+
+fn parse_wallet(wallet: &str) -> Result<String, RiskCalculationError> {
+    Pubkey::from_str(wallet)
+        .map(|wallet| wallet.to_string())
+        .map_err(|e| RiskCalculationError::InvalidParameter(format!("wallet: {}", e)))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PortfolioQuery {
+    /// Show the portfolio as it was at this time instead of now
+    pub at: Option<DateTime<Utc>>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+pub async fn portfolio(
+    State(state): State<AppState>,
+    Path(wallet): Path<String>,
+    Query(query): Query<PortfolioQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let wallet = parse_wallet(&wallet)?;
+        let store = store(&state);
+        let projection = match query.at {
+            Some(at) => store.projection_at(&wallet, at).await?,
+            None => store.projection(&wallet).await?,
+        };
+        // Valued at the current prices, also when looking back
+        let portfolio = projection.to_portfolio()?;
+        Ok::<_, RiskCalculationError>(
+            Valued::new(projection, &state.redis_client, &portfolio).await,
+        )
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+/// The wallet's event log, for audits
+pub async fn portfolio_events(
+    State(state): State<AppState>,
+    Path(wallet): Path<String>,
+    Query(query): Query<PortfolioQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let wallet = parse_wallet(&wallet)?;
+        let events = store(&state).events(&wallet).await?;
+        Ok::<_, RiskCalculationError>(
+            events
+                .into_iter()
+                .filter(|event| query.at.is_none_or(|at| event.recorded_at <= at))
+                .collect::<Vec<_>>(),
+        )
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+/// Events returned by `/history` unless the request asks for fewer or more
+const DEFAULT_HISTORY_LIMIT: usize = 100;
+const MAX_HISTORY_LIMIT: usize = 1_000;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryQuery {
+    /// Only events recorded at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Only events recorded at or before this time
+    pub until: Option<DateTime<Utc>>,
+    /// Number of events, 100 by default
+    pub limit: Option<usize>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+/// Body of `/history`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PortfolioHistory {
+    pub wallet: String,
+    /// Events in the wallet's whole log
+    pub total_events: usize,
+    /// Newest first
+    pub events: Vec<PortfolioEvent>,
+}
+
+/// The `limit` latest events recorded between `since` and `until`, newest first
+pub fn history(events: Vec<PortfolioEvent>, query: &HistoryQuery) -> Vec<PortfolioEvent> {
+    events
+        .into_iter()
+        .rev()
+        .filter(|event| query.since.is_none_or(|since| event.recorded_at >= since))
+        .filter(|event| query.until.is_none_or(|until| event.recorded_at <= until))
+        .take(query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT))
+        .collect()
+}
+
+/// Every deposit, withdrawal and rebalance of the wallet with the weights it
+/// was made with, newest first
+///
+/// Rebalances include the transfers the plan decided, to reconcile with what
+/// the transaction system executed.
+pub async fn portfolio_history(
+    State(state): State<AppState>,
+    Path(wallet): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let wallet = parse_wallet(&wallet)?;
+        if let Some(limit) = query
+            .limit
+            .filter(|limit| !(1..=MAX_HISTORY_LIMIT).contains(limit))
+        {
+            return Err(RiskCalculationError::InvalidParameter(format!(
+                "limit must be between 1 and {}: {}",
+                MAX_HISTORY_LIMIT, limit
+            )));
+        }
+        let events = store(&state).events(&wallet).await?;
+        Ok(PortfolioHistory {
+            wallet,
+            total_events: events.len(),
+            events: history(events, &query),
+        })
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SaveRequest {
+    /// The wallet of the path, so the approval can't be used on another wallet
+    pub wallet: String,
+    /// Asset the amounts are of, USDC by default
+    #[serde(default)]
+    pub asset: Asset,
+    /// Amount held in every protocol, per profile
+    pub risk_profiles: HashMap<RiskProfile, HashMap<Protocol, u64>>,
+}
+
+/// Params of the approval of a command on one wallet's portfolio
+#[derive(Debug, Deserialize)]
+pub struct WalletRequest {
+    pub wallet: String,
+}
+
+/// Params of an approval to list the portfolios, listing takes none
+#[derive(Debug, Default, Deserialize)]
+pub struct ListPortfoliosRequest {}
+
+/// The wallet of the path, refused when it isn't the one `approved`
+fn approved_wallet(wallet: &str, approved: &str) -> Result<Pubkey, RiskCalculationError> {
+    let wallet = Pubkey::from_str(wallet)
+        .map_err(|e| RiskCalculationError::InvalidParameter(format!("wallet: {}", e)))?;
+    if wallet.to_string() != approved {
+        return Err(RiskCalculationError::Unauthorized(format!(
+            "approval is for wallet {}, not {}",
+            approved, wallet
+        )));
+    }
+    Ok(wallet)
+}
+
+/// Admin command: replaces the wallet's holdings of an asset, e.g. when it's
+/// managed by another instance
+///
+/// The body is a [`MultisigApproval`] of a [`SaveRequest`].
+pub async fn save_portfolio(
+    State(state): State<AppState>,
+    Path(wallet): Path<String>,
+    Query(query): Query<DebugQuery>,
+    Json(approval): Json<MultisigApproval>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let request: SaveRequest =
+            authorize(&state.redis_client, &approval, AdminAction::SavePortfolio).await?;
+        let wallet = approved_wallet(&wallet, &request.wallet)?;
+        let store = store(&state);
+        let mut portfolio = store
+            .load(&wallet)
+            .await?
+            .unwrap_or_else(|| UserPortfolio::new(wallet));
+        let profiles = request
+            .risk_profiles
+            .into_iter()
+            .map(|(profile, pool_allocations)| {
+                let allocation = ProfileAllocation {
+                    risk_profile: profile.clone(),
+                    asset: request.asset,
+                    total_amount: pool_allocations.values().sum(),
+                    pool_allocations,
+                };
+                (profile, allocation)
+            })
+            .collect();
+        portfolio.assets.insert(request.asset, profiles);
+        store.save(&portfolio).await?;
+        let projection = store.projection(&wallet.to_string()).await?;
+        Ok::<_, RiskCalculationError>(
+            Valued::new(projection, &state.redis_client, &portfolio).await,
+        )
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+/// Admin command: forgets the wallet's portfolio along with its event log
+///
+/// The [`crate::multisig::APPROVAL_HEADER`] carries a [`MultisigApproval`] of
+/// a [`WalletRequest`], as JSON.
+pub async fn delete_portfolio(
+    State(state): State<AppState>,
+    Path(wallet): Path<String>,
+    Query(query): Query<DebugQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let approval = approval_from_headers(&headers)?;
+        let request: WalletRequest =
+            authorize(&state.redis_client, &approval, AdminAction::DeletePortfolio).await?;
+        let wallet = approved_wallet(&wallet, &request.wallet)?;
+        let deleted = store(&state).delete(&wallet).await?;
+        Ok::<_, RiskCalculationError>(serde_json::json!({ "deleted": deleted }))
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+/// Admin command: every wallet with a stored portfolio
+///
+/// The [`crate::multisig::APPROVAL_HEADER`] carries a [`MultisigApproval`] of
+/// a [`ListPortfoliosRequest`], as JSON.
+pub async fn list_portfolios(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let approval = approval_from_headers(&headers)?;
+        let _: ListPortfoliosRequest =
+            authorize(&state.redis_client, &approval, AdminAction::ListPortfolios).await?;
+        let wallets = store(&state).list().await?;
+        Ok::<_, RiskCalculationError>(serde_json::json!({
+            "wallets": wallets.iter().map(Pubkey::to_string).collect::<Vec<_>>(),
+        }))
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RebuildRequest {
+    /// Only rebuild this wallet's projection, every wallet's if not given
+    pub wallet: Option<String>,
+}
+
+/// Admin command: rebuilds projections from the event logs, e.g. after fixing
+/// a bug in how events are applied
+///
+/// The body is a [`MultisigApproval`] of a [`RebuildRequest`].
+pub async fn rebuild_projections(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+    Json(approval): Json<MultisigApproval>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let store = store(&state);
+        let request: RebuildRequest = authorize(
+            &store.redis_client,
+            &approval,
+            AdminAction::RebuildProjections,
+        )
+        .await?;
+        let wallets = match &request.wallet {
+            Some(wallet) => vec![parse_wallet(wallet)?],
+            None => store.wallets().await?,
+        };
+        let mut rebuilt = Vec::with_capacity(wallets.len());
+        for wallet in &wallets {
+            let projection = store.rebuild(wallet).await?;
+            tracing::info!(
+                "Rebuilt portfolio projection of {} from {} events",
+                wallet,
+                projection.last_sequence
+            );
+            rebuilt.push(serde_json::json!({
+                "wallet": wallet,
+                "last_sequence": projection.last_sequence,
+            }));
+        }
+        Ok::<_, RiskCalculationError>(serde_json::json!({ "rebuilt": rebuilt }))
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(sequence: u64, hour: u32, kind: PortfolioEventKind) -> PortfolioEvent {
+        PortfolioEvent {
+            sequence,
+            recorded_at: DateTime::parse_from_rfc3339(&format!("2025-01-01T{:02}:00:00Z", hour))
+                .unwrap()
+                .with_timezone(&Utc),
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_replay_events() {
+        let events = [
+            event(
+                1,
+                1,
+                PortfolioEventKind::Imported {
+                    profile: RiskProfile::Medium,
+                    asset: Asset::Usdc,
+                    positions: HashMap::from([(Protocol::Kamino, 600), (Protocol::Marginfy, 400)]),
+                },
+            ),
+            event(
+                2,
+                2,
+                PortfolioEventKind::Deposited {
+                    profile: RiskProfile::Medium,
+                    asset: Asset::Usdc,
+                    allocations: HashMap::from([(Protocol::Kamino, 100)]),
+                    weights: HashMap::new(),
+                },
+            ),
+            event(
+                3,
+                3,
+                PortfolioEventKind::Rebalanced {
+                    asset: Asset::Usdc,
+                    allocations: HashMap::from([(
+                        RiskProfile::Medium,
+                        HashMap::from([(Protocol::Kamino, 550), (Protocol::Marginfy, 550)]),
+                    )]),
+                    target_weights: HashMap::new(),
+                    transfers: HashMap::new(),
+                },
+            ),
+            event(
+                4,
+                4,
+                PortfolioEventKind::Withdrawn {
+                    profile: RiskProfile::Medium,
+                    asset: Asset::Usdc,
+                    withdrawals: HashMap::from([(Protocol::Marginfy, 550)]),
+                },
+            ),
+        ];
+
+        let now = PortfolioProjection::replay("wallet", &events, None);
+        assert_eq!(now.last_sequence, 4);
+        let medium = &now.assets[&Asset::Usdc][&RiskProfile::Medium];
+        assert_eq!(medium.total_amount, 550);
+        assert_eq!(
+            medium.pool_allocations,
+            HashMap::from([(Protocol::Kamino, 550)])
+        );
+        assert_eq!(now.last_rebalance, Some(events[2].recorded_at));
+
+        // Time travel to before the rebalance
+        let before = PortfolioProjection::replay("wallet", &events, Some(events[1].recorded_at));
+        assert_eq!(before.last_sequence, 2);
+        assert_eq!(before.last_rebalance, None);
+        assert_eq!(
+            before.assets[&Asset::Usdc][&RiskProfile::Medium].pool_allocations[&Protocol::Kamino],
+            700
+        );
+        assert_eq!(
+            before.assets[&Asset::Usdc][&RiskProfile::Medium].total_amount,
+            1_100
+        );
+    }
+
+    #[test]
+    fn test_saved_replaces_every_profile() {
+        let last_rebalance = DateTime::parse_from_rfc3339("2024-12-31T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let events = [
+            event(
+                1,
+                1,
+                PortfolioEventKind::Imported {
+                    profile: RiskProfile::Low,
+                    asset: Asset::Usdc,
+                    positions: HashMap::from([(Protocol::Kamino, 500)]),
+                },
+            ),
+            event(
+                2,
+                2,
+                PortfolioEventKind::Saved {
+                    asset: Asset::Usdc,
+                    allocations: HashMap::from([(
+                        RiskProfile::High,
+                        HashMap::from([(Protocol::Marginfy, 300), (Protocol::Kamino, 0)]),
+                    )]),
+                    last_rebalance: Some(last_rebalance),
+                },
+            ),
+        ];
+        let projection = PortfolioProjection::replay("wallet", &events, None);
+        assert!(!projection.assets[&Asset::Usdc].contains_key(&RiskProfile::Low));
+        let high = &projection.assets[&Asset::Usdc][&RiskProfile::High];
+        assert_eq!(high.total_amount, 300);
+        assert_eq!(high.pool_allocations.len(), 1);
+        assert_eq!(projection.last_rebalance, Some(last_rebalance));
+    }
+
+    #[test]
+    fn test_event_serialization() {
+        let event = event(
+            7,
+            1,
+            PortfolioEventKind::Deposited {
+                profile: RiskProfile::Low,
+                asset: Asset::Usdc,
+                allocations: HashMap::from([(Protocol::Kamino, 100)]),
+                weights: HashMap::from([(Protocol::Kamino, Bps::FULL)]),
+            },
+        );
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["type"], "deposited");
+        assert_eq!(json["allocations"]["Kamino"], 100);
+        // The sequence comes from the position in the log
+        let parsed: PortfolioEvent = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.sequence, 0);
+        assert_eq!(parsed.kind, event.kind);
+
+        // Events recorded before portfolios held other assets are USDC
+        let legacy: PortfolioEventKind = serde_json::from_str(
+            r#"{"type":"withdrawn","profile":"Low","withdrawals":{"Kamino":40}}"#,
+        )
+        .unwrap();
+        assert_eq!(legacy.asset(), Asset::Usdc);
+        // So are rebalances recorded before their weights and transfers were kept
+        let legacy: PortfolioEventKind =
+            serde_json::from_str(r#"{"type":"rebalanced","allocations":{"Low":{"Kamino":40}}}"#)
+                .unwrap();
+        assert!(matches!(
+            legacy,
+            PortfolioEventKind::Rebalanced { ref transfers, .. } if transfers.is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_history() {
+        let events: Vec<_> = (1..=4)
+            .map(|hour| {
+                event(
+                    hour as u64,
+                    hour,
+                    PortfolioEventKind::Withdrawn {
+                        profile: RiskProfile::Low,
+                        asset: Asset::Usdc,
+                        withdrawals: HashMap::from([(Protocol::Kamino, 10)]),
+                    },
+                )
+            })
+            .collect();
+        let sequences = |query: HistoryQuery| {
+            history(events.clone(), &query)
+                .iter()
+                .map(|event| event.sequence)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sequences(HistoryQuery::default()), vec![4, 3, 2, 1]);
+        assert_eq!(
+            sequences(HistoryQuery {
+                since: Some(events[1].recorded_at),
+                until: Some(events[2].recorded_at),
+                ..Default::default()
+            }),
+            vec![3, 2]
+        );
+        assert_eq!(
+            sequences(HistoryQuery {
+                limit: Some(1),
+                ..Default::default()
+            }),
+            vec![4]
+        );
+    }
+
+    #[test]
+    fn test_assets_are_kept_apart() {
+        let events = [
+            event(
+                1,
+                1,
+                PortfolioEventKind::Deposited {
+                    profile: RiskProfile::Low,
+                    asset: Asset::Usdc,
+                    allocations: HashMap::from([(Protocol::Kamino, 100)]),
+                    weights: HashMap::new(),
+                },
+            ),
+            event(
+                2,
+                2,
+                PortfolioEventKind::Deposited {
+                    profile: RiskProfile::Low,
+                    asset: Asset::Sol,
+                    allocations: HashMap::from([(Protocol::Kamino, 5_000)]),
+                    weights: HashMap::new(),
+                },
+            ),
+            event(
+                3,
+                3,
+                PortfolioEventKind::Saved {
+                    asset: Asset::Usdc,
+                    allocations: HashMap::new(),
+                    last_rebalance: None,
+                },
+            ),
+        ];
+        let before_save = PortfolioProjection::replay("wallet", &events[..2], None);
+        assert_eq!(
+            before_save.assets[&Asset::Usdc][&RiskProfile::Low].total_amount,
+            100
+        );
+        let sol = &before_save.assets[&Asset::Sol][&RiskProfile::Low];
+        assert_eq!((sol.asset, sol.total_amount), (Asset::Sol, 5_000));
+
+        // Saving the USDC holdings leaves the SOL ones alone
+        let projection = PortfolioProjection::replay("wallet", &events, None);
+        assert!(!projection.assets.contains_key(&Asset::Usdc));
+        assert_eq!(
+            projection.assets[&Asset::Sol],
+            before_save.assets[&Asset::Sol]
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use axum::{
+    extract::{Path, Query, RawQuery, State},
+    http::HeaderMap,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    bps::Bps,
+    cache_schema::versioned_key,
+    conditional::{conditional_response, Validators},
+    explain::{explain_choice, Locale},
+    privacy::PrivacyMode,
+    rebalancing::{LiveRiskModel, RenormalizedWeights, RiskWeightModel},
+    redis_connection::shared_connection,
+    registry::COMPARED_ASSET,
+    risk_model::{
+        json_response, timings_requested, DebugQuery, Protocol, RiskCalculationError,
+        RiskModelResponse, RiskProfile,
+    },
+    risk_stream::ProtocolScores,
+    snapshot::{snapshot_ttl, RiskSnapshot},
+    state::AppState,
+    timings::{timed, with_timings, Timing},
+};
+
+/// Precomputed `/risk_model` response of the default reserve, per language
+pub fn risk_model_key(locale: Locale) -> String {
+    versioned_key(&format!("precomputed:risk_model:{}", locale.as_str()))
+}
+
+/// Precomputed `/weights/:profile` response
+pub fn weights_key(profile: &RiskProfile) -> String {
+    versioned_key(&format!("precomputed:weights:{}", profile.as_str()))
+}
+
+/// Recommended weights of a profile, as of one snapshot
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProfileWeights {
+    pub profile: RiskProfile,
+    pub snapshot_id: String,
+    pub computed_at: DateTime<Utc>,
+    /// Largest weight first, summing to 100%
+    pub weights: Vec<(Protocol, Bps)>,
+    /// Set when the weights were renormalized around unavailable protocols
+    pub redistribution_note: Option<String>,
+    /// Scores the weights were derived from, lowest overall risk first
+    ///
+    /// Empty in weights stored before the scores were kept.
+    #[serde(default)]
+    pub risk_scores: Vec<ProtocolScores>,
+}
+
+impl ProfileWeights {
+    /// Weights before position limits, which depend on the size of the portfolio
+    ///
+    /// Unlike the weights stored by the refresh, they aren't smoothed, see
+    /// [`crate::weight_smoothing::smoothed_weights`].
+    pub fn from_snapshot(
+        profile: RiskProfile,
+        snapshot: &RiskSnapshot,
+    ) -> Result<Self, RiskCalculationError> {
+        Self::from_model(profile, snapshot, &LiveRiskModel::from_snapshot(snapshot)?)
+    }
+
+    /// Weights `model` recommends, with the snapshot's scores
+    pub fn from_model(
+        profile: RiskProfile,
+        snapshot: &RiskSnapshot,
+        model: &LiveRiskModel,
+    ) -> Result<Self, RiskCalculationError> {
+        let RenormalizedWeights { weights, note } = model
+            .get_available_weights(&profile, COMPARED_ASSET, 0)
+            .map_err(RiskCalculationError::CustomError)?;
+        let mut weights: Vec<(Protocol, Bps)> = weights
+            .into_iter()
+            .map(|(protocol, weight)| (protocol, Bps(weight)))
+            .collect();
+        weights.sort_by_key(|(_, weight)| std::cmp::Reverse(*weight));
+        Ok(ProfileWeights {
+            profile,
+            snapshot_id: snapshot.snapshot_id.clone(),
+            computed_at: snapshot.computed_at,
+            weights,
+            redistribution_note: note,
+            risk_scores: snapshot
+                .comparison
+                .ranking
+                .iter()
+                .map(ProtocolScores::from)
+                .collect(),
+        })
+    }
+}
+
+/// Assembles and stores the hot path responses of a freshly stored snapshot
+///
+/// Every `/risk_model` and `/weights/:profile` request of the default reserve
+/// asks the same question of the same snapshot, so the refresh answers it once
+/// and the handlers serve the stored answer with a single read. The responses
+/// expire with the snapshot they were built from, stored at `now`.
+pub async fn store_precomputed(
+    redis_client: &redis::Client,
+    snapshot: RiskSnapshot,
+    weights: &[ProfileWeights],
+    now: DateTime<Utc>,
+) -> Result<(), RiskCalculationError> {
+    let mut payloads = Vec::new();
+    for weights in weights {
+        payloads.push((
+            weights_key(&weights.profile),
+            serde_json::to_string(weights).map_err(RiskCalculationError::SerdeError)?,
+        ));
+    }
+
+    // `what_if` is always included and dropped on read when not asked for
+    let computed_at = snapshot.computed_at;
+    let mut response =
+        RiskModelResponse::from_snapshot(snapshot, computed_at, true, Locale::default())?;
+    response.apply_privacy(&PrivacyMode::from_env()?);
+    for locale in Locale::ALL {
+        response.choice_reason = explain_choice(
+            &response.chosen_protocol,
+            &response.attribution,
+            response.ranking.len(),
+            locale,
+        );
+        payloads.push((
+            risk_model_key(locale),
+            serde_json::to_string(&response).map_err(RiskCalculationError::SerdeError)?,
+        ));
+    }
+
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let ttl = snapshot_ttl(now);
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for (key, payload) in payloads {
+        pipe.set_ex(key, payload, ttl).ignore();
+    }
+    let _: () = pipe
+        .query_async(&mut connection)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    Ok(())
+}
+
+/// Deletes every precomputed response, so the handlers assemble them again
+/// until the next refresh stores new ones
+pub async fn drop_precomputed(redis_client: &redis::Client) -> Result<(), RiskCalculationError> {
+    let keys: Vec<String> = Locale::ALL
+        .into_iter()
+        .map(risk_model_key)
+        .chain(RiskProfile::ALL.iter().map(weights_key))
+        .collect();
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let _: () = connection
+        .del(keys)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    Ok(())
+}
+
+/// Reads a precomputed response, `None` if it expired or was never stored
+pub async fn load_precomputed<T: DeserializeOwned>(
+    redis_client: &redis::Client,
+    key: &str,
+) -> Result<Option<T>, RiskCalculationError> {
+    timed(Timing::CacheRead, async {
+        let mut connection = shared_connection(redis_client)
+            .await
+            .map_err(RiskCalculationError::from)?;
+        let payload: Option<String> = connection
+            .get(key)
+            .await
+            .map_err(RiskCalculationError::from)?;
+        payload
+            .map(|payload| serde_json::from_str(&payload).map_err(RiskCalculationError::SerdeError))
+            .transpose()
+    })
+    .await
+}
+
+/// The precomputed `/risk_model` response, brought up to date for `now`
+pub async fn precomputed_risk_model(
+    redis_client: &redis::Client,
+    locale: Locale,
+    include_what_if: bool,
+    now: DateTime<Utc>,
+) -> Result<Option<RiskModelResponse>, RiskCalculationError> {
+    let response: Option<RiskModelResponse> =
+        load_precomputed(redis_client, &risk_model_key(locale)).await?;
+    Ok(response.map(|mut response| {
+        response.freshness.age_seconds = (now - response.computed_at).num_seconds();
+        if !include_what_if {
+            response.what_if = None;
+        }
+        response
+    }))
+}
+
+/// Weights of `profile` as of the latest snapshot, precomputed when they were
+pub async fn profile_weights(
+    state: &AppState,
+    profile: RiskProfile,
+) -> Result<ProfileWeights, RiskCalculationError> {
+    match load_precomputed(&state.redis_client, &weights_key(&profile)).await {
+        Ok(Some(weights)) => return Ok(weights),
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to load precomputed weights: {}", e),
+    }
+    ProfileWeights::from_snapshot(profile, &state.registry.cached_snapshot().await?)
+}
+
+/// Recommended weights of a risk profile, `/weights/:profile` and
+/// `/profiles/:profile/weights`, with the scores behind them
+///
+/// Conditional GETs get a 304 while the snapshot hasn't changed.
+pub async fn weights(
+    State(state): State<AppState>,
+    Path(profile): Path<String>,
+    Query(query): Query<DebugQuery>,
+    RawQuery(raw_query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let (result, timings) =
+        with_timings(async { profile_weights(&state, RiskProfile::from_param(&profile)?).await })
+            .await;
+
+    let validators = result.as_ref().ok().map(|weights: &ProfileWeights| {
+        Validators::new(
+            &weights.snapshot_id,
+            &format!(
+                "{}?{}",
+                weights.profile.as_str(),
+                raw_query.unwrap_or_default()
+            ),
+            weights.computed_at,
+        )
+    });
+    conditional_response(
+        validators,
+        &headers,
+        json_response(result, timings_requested(&query.debug).then_some(timings)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precomputed_keys() {
+        assert_eq!(risk_model_key(Locale::Es), "v2:precomputed:risk_model:es");
+        assert_eq!(
+            weights_key(&RiskProfile::Medium),
+            "v2:precomputed:weights:medium"
+        );
+        assert_eq!(RiskProfile::from_param("High").unwrap(), RiskProfile::High);
+        assert!(RiskProfile::from_param("extreme").is_err());
+
+        let stored: ProfileWeights = serde_json::from_value(serde_json::json!({
+            "profile": "Medium",
+            "snapshot_id": "20240501T130203000",
+            "computed_at": "2024-05-01T13:02:03Z",
+            "weights": [["Kamino", 6000], ["Drift", 4000]],
+            "redistribution_note": null,
+        }))
+        .unwrap();
+        assert!(stored.risk_scores.is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::{
+    assets::Asset,
+    cache::{Cache, CacheBackend},
+    cache_schema::versioned_key,
+    cluster::rpc_url,
+    oracle_risk::{default_price_account, PythPrice},
+    rebalancing::{PortfolioValuation, UserPortfolio},
+    risk_model::{RiskCalculationError, SCHEMA_VERSION},
+    upstream_fixtures::rpc_client,
+};
+
+/// Currency portfolios are valued in
+pub const VALUATION_CURRENCY: &str = "USD";
+/// Prices are reused for a minute, valuations don't need them fresher
+const PRICE_TTL_SECONDS: u64 = 60;
+
+/// Cached USD price per token of an asset
+pub fn price_key(asset: Asset) -> String {
+    versioned_key(&format!("price:{}", String::from(asset)))
+}
+
+/// USD price per token of every asset, cached or read from its Pyth price account
+pub async fn fetch_prices(
+    redis_client: &redis::Client,
+    assets: &[Asset],
+) -> Result<HashMap<Asset, f64>, RiskCalculationError> {
+    let cache = CacheBackend::new(redis_client);
+    let mut prices = HashMap::new();
+    let mut missing = Vec::new();
+    for asset in assets {
+        match cache.get(&price_key(*asset)).await? {
+            Some(price) => {
+                let price = price.parse().map_err(|e| {
+                    RiskCalculationError::ParseError(format!("{} price: {}", asset, e))
+                })?;
+                prices.insert(*asset, price);
+            }
+            None => missing.push(*asset),
+        }
+    }
+    if missing.is_empty() {
+        return Ok(prices);
+    }
+
+    let price_accounts = missing
+        .iter()
+        .map(|asset| {
+            default_price_account(asset.symbol()).ok_or_else(|| {
+                RiskCalculationError::UpstreamUnavailable(format!("No price feed of {}", asset))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let accounts = rpc_client(rpc_url()?)
+        .get_multiple_accounts(&price_accounts)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    for (asset, account) in missing.into_iter().zip(accounts) {
+        let account = account.ok_or_else(|| {
+            RiskCalculationError::UpstreamUnavailable(format!("{} price account not found", asset))
+        })?;
+        let price = PythPrice::from_account_data(&account.data)?;
+        if !price.trading || price.price <= 0.0 {
+            return Err(RiskCalculationError::UpstreamUnavailable(format!(
+                "{} price isn't being published",
+                asset
+            )));
+        }
+        cache
+            .set_with_ttl(
+                &price_key(asset),
+                &price.price.to_string(),
+                PRICE_TTL_SECONDS,
+            )
+            .await?;
+        prices.insert(asset, price.price);
+    }
+    Ok(prices)
+}
+
+/// A portfolio response along with the portfolio's value
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Valued<T> {
+    /// See [`SCHEMA_VERSION`]
+    pub schema_version: u32,
+    #[serde(flatten)]
+    pub response: T,
+    pub valuation_currency: &'static str,
+    /// `None` when prices couldn't be read, amounts are in native units either way
+    pub valuation: Option<PortfolioValuation>,
+}
+
+impl<T: Serialize> Valued<T> {
+    /// Values `portfolio` at the current prices
+    ///
+    /// Responses don't depend on the price feed, a failure to value the
+    /// portfolio is logged and leaves `valuation` empty.
+    pub async fn new(response: T, redis_client: &redis::Client, portfolio: &UserPortfolio) -> Self {
+        let assets: Vec<Asset> = portfolio.assets.keys().copied().collect();
+        let valuation = fetch_prices(redis_client, &assets)
+            .await
+            .and_then(|prices| portfolio.valuation(&prices));
+        if let Err(e) = &valuation {
+            tracing::error!("Failed to value portfolio {}: {}", portfolio.user_wallet, e);
+        }
+        Valued {
+            schema_version: SCHEMA_VERSION,
+            response,
+            valuation_currency: VALUATION_CURRENCY,
+            valuation: valuation.ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        rebalancing::ProfileAllocation,
+        risk_model::{Protocol, RiskProfile},
+    };
+    use solana_sdk::pubkey::Pubkey;
+
+    #[test]
+    fn test_valuation() {
+        let mut portfolio = UserPortfolio::new(Pubkey::new_unique());
+        let allocation = |profile: RiskProfile, asset: Asset, pools: &[(Protocol, u64)]| {
+            HashMap::from([(
+                profile.clone(),
+                ProfileAllocation {
+                    risk_profile: profile,
+                    asset,
+                    pool_allocations: pools.iter().cloned().collect(),
+                    total_amount: pools.iter().map(|(_, amount)| amount).sum(),
+                },
+            )])
+        };
+        // 300 USDC and 2 SOL
+        portfolio.assets.insert(
+            Asset::Usdc,
+            allocation(
+                RiskProfile::Low,
+                Asset::Usdc,
+                &[(Protocol::Kamino, 300_000_000)],
+            ),
+        );
+        portfolio.assets.insert(
+            Asset::Sol,
+            allocation(
+                RiskProfile::High,
+                Asset::Sol,
+                &[
+                    (Protocol::Kamino, 1_500_000_000),
+                    (Protocol::Marginfy, 500_000_000),
+                ],
+            ),
+        );
+
+        let prices = HashMap::from([(Asset::Usdc, 1.0), (Asset::Sol, 300.0)]);
+        let valuation = portfolio.valuation(&prices).unwrap();
+        assert_eq!(valuation.valuation_currency, "USD");
+        assert!((valuation.total_value - 900.0).abs() < 1e-9);
+        // Raw amounts would put 87% of the portfolio in SOL
+        let sol = &valuation.profiles[0];
+        assert_eq!(
+            (sol.asset, sol.profile.clone()),
+            (Asset::Sol, RiskProfile::High)
+        );
+        assert!((sol.share - 2.0 / 3.0).abs() < 1e-9);
+        assert!((sol.pools[&Protocol::Marginfy] - 150.0).abs() < 1e-9);
+        assert!((valuation.profiles[1].value - 300.0).abs() < 1e-9);
+
+        assert!(portfolio
+            .valuation(&HashMap::from([(Asset::Usdc, 1.0)]))
+            .is_err());
+        assert_eq!(price_key(Asset::Sol), "v2:price:sol");
+    }
+}
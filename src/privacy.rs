@@ -0,0 +1,164 @@
+use std::sync::OnceLock;
+
+use anchor_client::solana_sdk::{hash::hashv, pubkey::Pubkey};
+use rand::Rng;
+
+use crate::risk_model::{LiquidityRiskMetrics, RiskCalculationError};
+
+/// Maximum relative noise added to an amount before it's bucketed
+const PERTURBATION: f64 = 0.05;
+
+/// Salt of the endpoints that always mask when `PRIVACY_SALT` isn't set
+static PROCESS_SALT: OnceLock<String> = OnceLock::new();
+
+/// How much depositor-level data a deployment exposes
+///
+/// Set with `DEPOSITOR_PRIVACY`: `full` (the default) for internal deployments,
+/// `public` for deployments anyone can query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PrivacyMode {
+    /// Exact amounts and pubkeys
+    Full,
+    /// Amounts are perturbed and bucketed, pubkeys are replaced with salted hashes
+    ///
+    /// The salt (`PRIVACY_SALT`) keeps masked pubkeys stable across responses,
+    /// so a depositor can be followed over time without being identified.
+    Public { salt: String },
+}
+
+impl PrivacyMode {
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        match std::env::var("DEPOSITOR_PRIVACY").as_deref() {
+            Err(_) | Ok("full") => Ok(PrivacyMode::Full),
+            Ok("public") => Ok(PrivacyMode::Public {
+                salt: std::env::var("PRIVACY_SALT").map_err(|_| {
+                    RiskCalculationError::CustomError(
+                        "PRIVACY_SALT must be set when DEPOSITOR_PRIVACY is public".to_string(),
+                    )
+                })?,
+            }),
+            Ok(other) => Err(RiskCalculationError::ParseError(format!(
+                "invalid DEPOSITOR_PRIVACY {:?}, expected full or public",
+                other
+            ))),
+        }
+    }
+
+    /// The mode of endpoints that always mask, whatever the deployment's
+    ///
+    /// `Full` deployments mask with `PRIVACY_SALT` when it's set, otherwise
+    /// with a salt drawn at startup, which keeps masked pubkeys stable for the
+    /// life of the process only.
+    pub fn masking(&self) -> PrivacyMode {
+        match self {
+            PrivacyMode::Public { .. } => self.clone(),
+            PrivacyMode::Full => PrivacyMode::Public {
+                salt: std::env::var("PRIVACY_SALT").unwrap_or_else(|_| {
+                    PROCESS_SALT
+                        .get_or_init(|| format!("{:032x}", rand::random::<u128>()))
+                        .clone()
+                }),
+            },
+        }
+    }
+
+    /// Amount of a single depositor as it may be exposed
+    pub fn amount(&self, amount: u128) -> u128 {
+        match self {
+            PrivacyMode::Full => amount,
+            PrivacyMode::Public { .. } => {
+                let noise = rand::thread_rng().gen_range(-PERTURBATION..=PERTURBATION);
+                bucket_amount((amount as f64 * (1.0 + noise)) as u128)
+            }
+        }
+    }
+
+    /// Pubkey of a depositor as it may be exposed
+    pub fn pubkey(&self, pubkey: &Pubkey) -> String {
+        match self {
+            PrivacyMode::Full => pubkey.to_string(),
+            PrivacyMode::Public { salt } => mask_pubkey(pubkey, salt),
+        }
+    }
+
+    /// Hides the largest deposit of liquidity metrics about to be served
+    ///
+    /// The concentration is recomputed from the hidden amount, as it would reveal
+    /// the exact deposit otherwise.
+    pub fn apply_to_liquidity(&self, metrics: &mut LiquidityRiskMetrics) {
+        if *self == PrivacyMode::Full {
+            return;
+        }
+        metrics.largest_deposit = self.amount(metrics.largest_deposit);
+        if metrics.total_deposits > 0 {
+            metrics.deposit_concentration =
+                metrics.largest_deposit as f64 / metrics.total_deposits as f64;
+        }
+    }
+}
+
+/// Rounds down to the 1-2-5 series (1, 2, 5, 10, 20, 50, ...)
+pub fn bucket_amount(amount: u128) -> u128 {
+    if amount == 0 {
+        return 0;
+    }
+    let mut magnitude = 1u128;
+    while magnitude <= amount / 10 {
+        magnitude *= 10;
+    }
+    [5, 2, 1]
+        .into_iter()
+        .map(|step| step * magnitude)
+        .find(|bucket| *bucket <= amount)
+        .unwrap_or(magnitude)
+}
+
+/// First 16 hex characters of `sha256(salt || pubkey)`
+pub fn mask_pubkey(pubkey: &Pubkey, salt: &str) -> String {
+    let hash = hashv(&[salt.as_bytes(), pubkey.as_ref()]);
+    hash.to_bytes()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_amount() {
+        assert_eq!(bucket_amount(0), 0);
+        assert_eq!(bucket_amount(7), 5);
+        assert_eq!(bucket_amount(10), 10);
+        assert_eq!(bucket_amount(19), 10);
+        assert_eq!(bucket_amount(2_345_678), 2_000_000);
+        assert_eq!(bucket_amount(9_999_999), 5_000_000);
+    }
+
+    #[test]
+    fn test_public_mode_hides_depositors() {
+        let public = PrivacyMode::Public {
+            salt: "salt".to_string(),
+        };
+        let pubkey = Pubkey::new_unique();
+        assert_eq!(PrivacyMode::Full.pubkey(&pubkey), pubkey.to_string());
+        assert_eq!(public.pubkey(&pubkey), public.pubkey(&pubkey));
+        assert_eq!(public.pubkey(&pubkey).len(), 16);
+        assert_ne!(public.pubkey(&pubkey), mask_pubkey(&pubkey, "other salt"));
+
+        // 5% noise around 3_000_000 always lands in the 2_000_000 bucket
+        for _ in 0..100 {
+            assert_eq!(public.amount(3_000_000), 2_000_000);
+        }
+        assert_eq!(PrivacyMode::Full.amount(3_000_001), 3_000_001);
+
+        assert_eq!(public.masking(), public);
+        let masking = PrivacyMode::Full.masking();
+        assert_ne!(masking.pubkey(&pubkey), pubkey.to_string());
+        assert_eq!(
+            masking.pubkey(&pubkey),
+            PrivacyMode::Full.masking().pubkey(&pubkey)
+        );
+    }
+}
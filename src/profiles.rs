@@ -0,0 +1,159 @@
+use axum::{
+    extract::{Query, State},
+    response::Response,
+    Json,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    precomputed::{profile_weights, ProfileWeights},
+    risk_model::{json_response, timings_requested, DebugQuery, RiskCalculationError, RiskProfile},
+    state::AppState,
+    timings::with_timings,
+};
+
+/// Highest score of a questionnaire, the most risk a wallet can take
+pub const MAX_SCORE: u32 = 8;
+/// Highest score suggesting [`RiskProfile::Low`]
+const LOW_MAX_SCORE: u32 = 2;
+/// Highest score suggesting [`RiskProfile::Medium`]
+const MEDIUM_MAX_SCORE: u32 = 5;
+
+/// How long the funds can stay allocated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Horizon {
+    /// Less than a month
+    Short,
+    /// Up to a year
+    Medium,
+    /// More than a year
+    Long,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    Low,
+    Medium,
+    High,
+}
+
+impl Level {
+    fn points(self) -> u32 {
+        match self {
+            Level::Low => 0,
+            Level::Medium => 1,
+            Level::High => 2,
+        }
+    }
+}
+
+/// Answers of a wallet about the risk it's willing to take
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Questionnaire {
+    pub horizon: Horizon,
+    /// How large a temporary loss the wallet accepts
+    pub drawdown_tolerance: Level,
+    /// How soon and how much of the funds may have to be withdrawn
+    pub liquidity_needs: Level,
+}
+
+impl Questionnaire {
+    /// Risk appetite from 0 to [`MAX_SCORE`]
+    ///
+    /// The drawdown tolerance counts twice, it's what the profiles differ by
+    /// the most. Liquidity needs count against the appetite.
+    pub fn score(&self) -> u32 {
+        let horizon = match self.horizon {
+            Horizon::Short => 0,
+            Horizon::Medium => 1,
+            Horizon::Long => 2,
+        };
+        horizon + 2 * self.drawdown_tolerance.points() + 2 - self.liquidity_needs.points()
+    }
+
+    /// The preset profile matching the answers
+    ///
+    /// High liquidity needs cap it at [`RiskProfile::Medium`]: withdrawals
+    /// from riskier protocols are the first to be stuck when utilization spikes.
+    pub fn suggested_profile(&self) -> RiskProfile {
+        match self.score() {
+            score if score <= LOW_MAX_SCORE => RiskProfile::Low,
+            _ if self.liquidity_needs == Level::High => RiskProfile::Medium,
+            score if score <= MEDIUM_MAX_SCORE => RiskProfile::Medium,
+            _ => RiskProfile::High,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProfileSuggestion {
+    pub profile: RiskProfile,
+    /// See [`Questionnaire::score`]
+    pub score: u32,
+    pub max_score: u32,
+    /// Weights recommended for the profile
+    pub allocation: ProfileWeights,
+}
+
+/// `POST /profiles/suggest`: the risk profile and weights matching a questionnaire
+pub async fn suggest_profile(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+    Json(questionnaire): Json<Questionnaire>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let profile = questionnaire.suggested_profile();
+        let allocation = profile_weights(&state, profile.clone()).await?;
+        Ok::<_, RiskCalculationError>(ProfileSuggestion {
+            profile,
+            score: questionnaire.score(),
+            max_score: MAX_SCORE,
+            allocation,
+        })
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suggested_profile() {
+        let cautious = Questionnaire {
+            horizon: Horizon::Short,
+            drawdown_tolerance: Level::Low,
+            liquidity_needs: Level::High,
+        };
+        assert_eq!(cautious.score(), 0);
+        assert_eq!(cautious.suggested_profile(), RiskProfile::Low);
+
+        let balanced = Questionnaire {
+            horizon: Horizon::Medium,
+            drawdown_tolerance: Level::Medium,
+            liquidity_needs: Level::Medium,
+        };
+        assert_eq!(balanced.score(), 4);
+        assert_eq!(balanced.suggested_profile(), RiskProfile::Medium);
+
+        let aggressive = Questionnaire {
+            horizon: Horizon::Long,
+            drawdown_tolerance: Level::High,
+            liquidity_needs: Level::Low,
+        };
+        assert_eq!(aggressive.score(), MAX_SCORE);
+        assert_eq!(aggressive.suggested_profile(), RiskProfile::High);
+
+        let needs_liquidity = Questionnaire {
+            liquidity_needs: Level::High,
+            ..aggressive
+        };
+        assert_eq!(needs_liquidity.score(), 6);
+        assert_eq!(needs_liquidity.suggested_profile(), RiskProfile::Medium);
+    }
+}
@@ -0,0 +1,607 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, Query, State},
+    response::Response,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bps::Bps,
+    cache_lock::with_lock,
+    cache_schema::record_key,
+    multisig::{authorize, unverified_params, AdminAction, MultisigApproval},
+    portfolio_events::{PortfolioStore, RedisPortfolioStore},
+    precomputed::ProfileWeights,
+    rebalancing::{Asset, ProfileAllocation, TransferCostModel},
+    redis_connection::shared_connection,
+    risk_model::{
+        json_response, timings_requested, DebugQuery, Protocol, RiskCalculationError, RiskProfile,
+    },
+    state::AppState,
+    timings::with_timings,
+};
+
+/// Changes below this don't make a proposal when `PROPOSAL_MIN_CHANGE_BPS` isn't set
+const DEFAULT_MIN_CHANGE_BPS: u64 = 100;
+/// Proposals up to this change are approved automatically when
+/// `PROPOSAL_AUTO_APPROVE_BPS` isn't set
+const DEFAULT_AUTO_APPROVE_BPS: u64 = 500;
+
+/// Every proposal by id
+///
+/// Proposals and the weights they applied are decisions, not caches, so like
+/// the penalties the keys aren't versioned.
+fn proposals_key() -> String {
+    record_key("weight_proposals")
+}
+
+/// Weights the rebalancer uses, per profile
+fn applied_weights_key() -> String {
+    record_key("applied_weights")
+}
+
+/// How large a weight change has to be to need a proposal, and to need a human
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProposalThresholds {
+    /// Smaller changes are ignored as noise
+    pub min_change: Bps,
+    /// Changes up to this are approved without waiting for anyone
+    pub auto_approve: Bps,
+}
+
+impl Default for ProposalThresholds {
+    fn default() -> Self {
+        ProposalThresholds {
+            min_change: Bps(DEFAULT_MIN_CHANGE_BPS),
+            auto_approve: Bps(DEFAULT_AUTO_APPROVE_BPS),
+        }
+    }
+}
+
+impl ProposalThresholds {
+    /// Reads `PROPOSAL_MIN_CHANGE_BPS` and `PROPOSAL_AUTO_APPROVE_BPS`
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let read = |name: &str, default: u64| match std::env::var(name) {
+            Ok(bps) => bps
+                .trim()
+                .parse()
+                .map(Bps)
+                .map_err(|e| RiskCalculationError::ParseError(format!("{}: {}", name, e))),
+            Err(_) => Ok(Bps(default)),
+        };
+        Ok(ProposalThresholds {
+            min_change: read("PROPOSAL_MIN_CHANGE_BPS", DEFAULT_MIN_CHANGE_BPS)?,
+            auto_approve: read("PROPOSAL_AUTO_APPROVE_BPS", DEFAULT_AUTO_APPROVE_BPS)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ProposalStatus {
+    Pending,
+    AutoApproved,
+    Approved,
+    Rejected,
+    /// A newer proposal for the same profile was made before this one was decided
+    Superseded,
+}
+
+/// A portfolio whose allocation changes with a proposal
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ImpactedPortfolio {
+    pub wallet: String,
+    /// Allocated to the profile, in native units
+    pub amount: u64,
+    /// What the portfolio moves between protocols
+    pub moved: u64,
+}
+
+/// A change of a profile's weights, applied to the rebalancer once approved
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct WeightProposal {
+    pub id: String,
+    pub profile: RiskProfile,
+    pub snapshot_id: String,
+    pub old_weights: Vec<(Protocol, Bps)>,
+    pub new_weights: Vec<(Protocol, Bps)>,
+    /// Largest change of a single protocol's weight
+    pub max_change: Bps,
+    pub reason: String,
+    pub impacted_portfolios: Vec<ImpactedPortfolio>,
+    /// Fees and slippage of moving the impacted portfolios, in native units
+    pub estimated_cost: u64,
+    pub status: ProposalStatus,
+    pub created_at: DateTime<Utc>,
+    pub decided_at: Option<DateTime<Utc>>,
+}
+
+/// Largest difference between two sets of weights, missing protocols weigh 0
+pub fn max_weight_change(old: &[(Protocol, Bps)], new: &[(Protocol, Bps)]) -> (Protocol, Bps) {
+    let weight_of = |weights: &[(Protocol, Bps)], protocol: &Protocol| {
+        weights
+            .iter()
+            .find(|(candidate, _)| candidate == protocol)
+            .map_or(0, |(_, weight)| weight.0)
+    };
+    old.iter()
+        .chain(new)
+        .map(|(protocol, _)| {
+            (
+                protocol.clone(),
+                Bps(weight_of(old, protocol).abs_diff(weight_of(new, protocol))),
+            )
+        })
+        .max_by_key(|(_, change)| *change)
+        .unwrap_or((Protocol::Kamino, Bps::ZERO))
+}
+
+/// What moving `allocation` from its current pools to `new_weights` would move and cost
+///
+/// The cost is estimated per protocol rather than per transfer: every protocol
+/// pays its leg cost on what flows in or out of it.
+pub fn estimate_move(
+    allocation: &ProfileAllocation,
+    new_weights: &[(Protocol, Bps)],
+    costs: &TransferCostModel,
+) -> (u64, u64) {
+    let mut targets: HashMap<Protocol, u64> = new_weights
+        .iter()
+        .map(|(protocol, weight)| {
+            (
+                protocol.clone(),
+                weight.apply_to(allocation.total_amount).unwrap_or(0),
+            )
+        })
+        .collect();
+    for protocol in allocation.pool_allocations.keys() {
+        targets.entry(protocol.clone()).or_insert(0);
+    }
+    let (mut moved, mut cost) = (0u64, 0u64);
+    for (protocol, target) in &targets {
+        let current = *allocation.pool_allocations.get(protocol).unwrap_or(&0);
+        let flow = target.abs_diff(current);
+        if flow == 0 {
+            continue;
+        }
+        if *target > current {
+            moved = moved.saturating_add(flow);
+        }
+        cost = cost.saturating_add(costs.leg_cost(protocol, allocation.asset, flow));
+    }
+    (moved, cost)
+}
+
+/// Builds the proposal of moving `profile` from `old_weights` to `weights`
+///
+/// `None` if the change is below the noise threshold.
+pub fn propose(
+    old_weights: &[(Protocol, Bps)],
+    weights: &ProfileWeights,
+    portfolios: &[(String, ProfileAllocation)],
+    costs: &TransferCostModel,
+    thresholds: &ProposalThresholds,
+    now: DateTime<Utc>,
+) -> Option<WeightProposal> {
+    let (protocol, max_change) = max_weight_change(old_weights, &weights.weights);
+    if max_change <= thresholds.min_change {
+        return None;
+    }
+    let weight_of = |weights: &[(Protocol, Bps)]| {
+        weights
+            .iter()
+            .find(|(candidate, _)| *candidate == protocol)
+            .map_or(Bps::ZERO, |(_, weight)| *weight)
+    };
+    let mut reason = format!(
+        "{:?} weight moves from {} to {} with snapshot {}",
+        protocol,
+        weight_of(old_weights),
+        weight_of(&weights.weights),
+        weights.snapshot_id
+    );
+    if let Some(note) = &weights.redistribution_note {
+        reason.push_str(&format!(". {}", note));
+    }
+
+    let mut estimated_cost = 0u64;
+    let impacted_portfolios = portfolios
+        .iter()
+        .filter_map(|(wallet, allocation)| {
+            let (moved, cost) = estimate_move(allocation, &weights.weights, costs);
+            estimated_cost = estimated_cost.saturating_add(cost);
+            (moved > 0).then(|| ImpactedPortfolio {
+                wallet: wallet.clone(),
+                amount: allocation.total_amount,
+                moved,
+            })
+        })
+        .collect();
+
+    let auto_approved = max_change <= thresholds.auto_approve;
+    Some(WeightProposal {
+        id: format!("{}-{}", weights.snapshot_id, weights.profile.as_str()),
+        profile: weights.profile.clone(),
+        snapshot_id: weights.snapshot_id.clone(),
+        old_weights: old_weights.to_vec(),
+        new_weights: weights.weights.clone(),
+        max_change,
+        reason,
+        impacted_portfolios,
+        estimated_cost,
+        status: if auto_approved {
+            ProposalStatus::AutoApproved
+        } else {
+            ProposalStatus::Pending
+        },
+        created_at: now,
+        decided_at: auto_approved.then_some(now),
+    })
+}
+
+/// Weights the rebalancer applies, per profile
+pub async fn load_applied_weights(
+    redis_client: &redis::Client,
+) -> Result<HashMap<RiskProfile, Vec<(Protocol, Bps)>>, RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let applied: HashMap<String, String> = connection
+        .hgetall(applied_weights_key())
+        .await
+        .map_err(RiskCalculationError::from)?;
+    applied
+        .iter()
+        .map(|(profile, weights)| {
+            Ok((
+                RiskProfile::from_param(profile)?,
+                serde_json::from_str(weights).map_err(RiskCalculationError::SerdeError)?,
+            ))
+        })
+        .collect()
+}
+
+/// Every proposal, newest first
+pub async fn load_proposals(
+    redis_client: &redis::Client,
+) -> Result<Vec<WeightProposal>, RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let proposals: Vec<String> = connection
+        .hvals(proposals_key())
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let mut proposals = proposals
+        .iter()
+        .map(|proposal| serde_json::from_str(proposal).map_err(RiskCalculationError::SerdeError))
+        .collect::<Result<Vec<WeightProposal>, _>>()?;
+    proposals.sort_by_key(|proposal| std::cmp::Reverse(proposal.created_at));
+    Ok(proposals)
+}
+
+/// Stores proposals, and applies the weights of the approved ones
+async fn store_proposals(
+    redis_client: &redis::Client,
+    proposals: &[WeightProposal],
+) -> Result<(), RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    for proposal in proposals {
+        let body = serde_json::to_string(proposal).map_err(RiskCalculationError::SerdeError)?;
+        pipe.hset(proposals_key(), &proposal.id, body).ignore();
+        if matches!(
+            proposal.status,
+            ProposalStatus::Approved | ProposalStatus::AutoApproved
+        ) {
+            let weights = serde_json::to_string(&proposal.new_weights)
+                .map_err(RiskCalculationError::SerdeError)?;
+            pipe.hset(applied_weights_key(), proposal.profile.as_str(), weights)
+                .ignore();
+        }
+    }
+    let _: () = pipe
+        .query_async(&mut connection)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    Ok(())
+}
+
+/// Compares a fresh snapshot's weights, one per profile, with the applied ones
+/// and proposes the changes
+///
+/// The first weights of a profile are applied directly, there is nothing to
+/// change from. A new proposal supersedes the profile's pending one. Runs
+/// under the proposals lock, so a proposal can't be approved while it's being
+/// superseded.
+pub async fn propose_weight_changes(
+    redis_client: &redis::Client,
+    profile_weights: &[ProfileWeights],
+) -> Result<Vec<WeightProposal>, RiskCalculationError> {
+    let thresholds = ProposalThresholds::from_env()?;
+    let costs = TransferCostModel::from_env()?;
+
+    let store = RedisPortfolioStore::new(redis_client.clone());
+    let mut portfolios = Vec::new();
+    for wallet in store.list().await? {
+        if let Some(portfolio) = store.load(&wallet).await? {
+            portfolios.push((wallet.to_string(), portfolio));
+        }
+    }
+
+    with_lock(Some(redis_client), &proposals_key(), async {
+        let applied = load_applied_weights(redis_client).await?;
+        let now = Utc::now();
+        let mut pending = load_proposals(redis_client).await?;
+        pending.retain(|proposal| proposal.status == ProposalStatus::Pending);
+
+        let mut changed = Vec::new();
+        for weights in profile_weights {
+            let profile = weights.profile.clone();
+            let Some(old_weights) = applied.get(&profile) else {
+                changed.push(WeightProposal {
+                    id: format!("{}-{}", weights.snapshot_id, profile.as_str()),
+                    profile: profile.clone(),
+                    snapshot_id: weights.snapshot_id.clone(),
+                    old_weights: Vec::new(),
+                    new_weights: weights.weights.clone(),
+                    max_change: Bps::FULL,
+                    reason: "Initial weights".to_string(),
+                    impacted_portfolios: Vec::new(),
+                    estimated_cost: 0,
+                    status: ProposalStatus::AutoApproved,
+                    created_at: now,
+                    decided_at: Some(now),
+                });
+                continue;
+            };
+            // The estimated cost is a single USDC amount, so only USDC holdings are costed
+            let allocations: Vec<(String, ProfileAllocation)> = portfolios
+                .iter()
+                .filter_map(|(wallet, portfolio)| {
+                    portfolio
+                        .allocation(Asset::Usdc, &profile)
+                        .map(|allocation| (wallet.clone(), allocation.clone()))
+                })
+                .collect();
+            let Some(proposal) =
+                propose(old_weights, weights, &allocations, &costs, &thresholds, now)
+            else {
+                continue;
+            };
+            for superseded in pending.iter_mut().filter(|p| p.profile == profile) {
+                superseded.status = ProposalStatus::Superseded;
+                superseded.decided_at = Some(now);
+                changed.push(superseded.clone());
+            }
+            tracing::info!(
+                "Weight proposal {} ({:?}): {}",
+                proposal.id,
+                proposal.status,
+                proposal.reason
+            );
+            changed.push(proposal);
+        }
+        store_proposals(redis_client, &changed).await?;
+        Ok(changed)
+    })
+    .await
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ProposalsQuery {
+    /// Only proposals with this status
+    pub status: Option<ProposalStatus>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+/// Weight proposals, newest first, `/proposals`
+pub async fn proposals(
+    State(state): State<AppState>,
+    Query(query): Query<ProposalsQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let mut proposals = load_proposals(&state.redis_client).await?;
+        if let Some(status) = query.status {
+            proposals.retain(|proposal| proposal.status == status);
+        }
+        Ok(proposals)
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+/// Parameters of an approve or reject command
+#[derive(Debug, Deserialize)]
+pub struct DecisionRequest {
+    pub id: String,
+}
+
+/// The pending proposal `id` among `proposals`
+fn pending_proposal(
+    proposals: Vec<WeightProposal>,
+    id: &str,
+) -> Result<WeightProposal, RiskCalculationError> {
+    let proposal = proposals
+        .into_iter()
+        .find(|proposal| proposal.id == id)
+        .ok_or(RiskCalculationError::NotFound(format!(
+            "no proposal {}",
+            id
+        )))?;
+    if proposal.status != ProposalStatus::Pending {
+        return Err(RiskCalculationError::CustomError(format!(
+            "proposal {} is already {:?}",
+            id, proposal.status
+        )));
+    }
+    Ok(proposal)
+}
+
+/// Approves or rejects a pending proposal
+///
+/// The proposal is checked before the approval is spent, and decided under
+/// the proposals lock, so a refresh superseding it can't interleave.
+async fn decide(
+    redis_client: &redis::Client,
+    id: &str,
+    approval: &MultisigApproval,
+    approve: bool,
+) -> Result<WeightProposal, RiskCalculationError> {
+    let action = if approve {
+        AdminAction::ApproveProposal
+    } else {
+        AdminAction::RejectProposal
+    };
+    let request: DecisionRequest = unverified_params(approval)?;
+    if request.id != id {
+        return Err(RiskCalculationError::Unauthorized(format!(
+            "approval is for proposal {}",
+            request.id
+        )));
+    }
+    with_lock(Some(redis_client), &proposals_key(), async {
+        let mut proposal = pending_proposal(load_proposals(redis_client).await?, id)?;
+        let _: DecisionRequest = authorize(redis_client, approval, action).await?;
+        proposal.status = if approve {
+            ProposalStatus::Approved
+        } else {
+            ProposalStatus::Rejected
+        };
+        proposal.decided_at = Some(Utc::now());
+        store_proposals(redis_client, std::slice::from_ref(&proposal)).await?;
+        tracing::info!("Weight proposal {} {:?}", proposal.id, proposal.status);
+        Ok(proposal)
+    })
+    .await
+}
+
+/// Admin command: applies a pending proposal's weights to the rebalancer
+///
+/// The body is a [`MultisigApproval`] of a [`DecisionRequest`].
+pub async fn approve_proposal(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<DebugQuery>,
+    Json(approval): Json<MultisigApproval>,
+) -> Response {
+    let (result, timings) = with_timings(decide(&state.redis_client, &id, &approval, true)).await;
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+/// Admin command: keeps the applied weights and drops a pending proposal
+///
+/// The body is a [`MultisigApproval`] of a [`DecisionRequest`].
+pub async fn reject_proposal(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<DebugQuery>,
+    Json(approval): Json<MultisigApproval>,
+) -> Response {
+    let (result, timings) = with_timings(decide(&state.redis_client, &id, &approval, false)).await;
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weights(weights: &[(Protocol, u64)]) -> ProfileWeights {
+        ProfileWeights {
+            profile: RiskProfile::Medium,
+            snapshot_id: "20240501T130203000".to_string(),
+            computed_at: Utc::now(),
+            weights: weights
+                .iter()
+                .map(|(protocol, weight)| (protocol.clone(), Bps(*weight)))
+                .collect(),
+            redistribution_note: None,
+            risk_scores: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_pending_proposal() {
+        let proposal = |id: &str, status: ProposalStatus| WeightProposal {
+            id: id.to_string(),
+            profile: RiskProfile::Medium,
+            snapshot_id: "20240501T130203000".to_string(),
+            old_weights: Vec::new(),
+            new_weights: Vec::new(),
+            max_change: Bps::ZERO,
+            reason: String::new(),
+            impacted_portfolios: Vec::new(),
+            estimated_cost: 0,
+            status,
+            created_at: Utc::now(),
+            decided_at: None,
+        };
+        let proposals = || {
+            vec![
+                proposal("a-medium", ProposalStatus::Superseded),
+                proposal("b-medium", ProposalStatus::Pending),
+            ]
+        };
+        assert_eq!(
+            pending_proposal(proposals(), "b-medium").unwrap().id,
+            "b-medium"
+        );
+        assert!(matches!(
+            pending_proposal(proposals(), "c-medium"),
+            Err(RiskCalculationError::NotFound(_))
+        ));
+        // A superseded proposal can't be approved over the newer weights
+        assert!(pending_proposal(proposals(), "a-medium").is_err());
+    }
+
+    #[test]
+    fn test_propose() {
+        let old = weights(&[(Protocol::Kamino, 6_000), (Protocol::Marginfy, 4_000)]).weights;
+        let allocation = ProfileAllocation {
+            risk_profile: RiskProfile::Medium,
+            asset: Asset::Usdc,
+            pool_allocations: HashMap::from([
+                (Protocol::Kamino, 600_000),
+                (Protocol::Marginfy, 400_000),
+            ]),
+            total_amount: 1_000_000,
+        };
+        let portfolios = vec![("wallet".to_string(), allocation)];
+        let costs =
+            TransferCostModel::parse("kamino:usdc:0:10,marginfi:usdc:0:10", Bps(0)).unwrap();
+        let thresholds = ProposalThresholds::default();
+        let now = Utc::now();
+
+        // Noise doesn't make a proposal
+        let noise = weights(&[(Protocol::Kamino, 5_950), (Protocol::Marginfy, 4_050)]);
+        assert!(propose(&old, &noise, &portfolios, &costs, &thresholds, now).is_none());
+
+        let small = weights(&[(Protocol::Kamino, 5_600), (Protocol::Marginfy, 4_400)]);
+        let proposal = propose(&old, &small, &portfolios, &costs, &thresholds, now).unwrap();
+        assert_eq!(proposal.status, ProposalStatus::AutoApproved);
+        assert_eq!(proposal.max_change, Bps(400));
+        assert_eq!(proposal.impacted_portfolios[0].moved, 40_000);
+        // 0.1% out of Kamino and 0.1% into Marginfi
+        assert_eq!(proposal.estimated_cost, 80);
+
+        let large = weights(&[(Protocol::Marginfy, 10_000)]);
+        let proposal = propose(&old, &large, &portfolios, &costs, &thresholds, now).unwrap();
+        assert_eq!(proposal.status, ProposalStatus::Pending);
+        assert_eq!(proposal.decided_at, None);
+        assert_eq!(proposal.max_change, Bps(6_000));
+        assert!(proposal
+            .reason
+            .starts_with("Marginfy weight moves from 40.0% to 100.0%"));
+        assert_eq!(proposal.id, "20240501T130203000-medium");
+    }
+}
@@ -0,0 +1,379 @@
+use std::{collections::HashSet, sync::OnceLock};
+
+use chrono::NaiveDate;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::risk_model::{Protocol, ProtocolRiskMetrics, RiskCalculationError};
+
+/// The rubric maintained along the code, used unless `PROTOCOL_RUBRIC` is set
+const DEFAULT_RUBRIC: &str = include_str!("../protocol_rubric.toml");
+/// Audits older than this count as stale
+const AUDIT_STALE_DAYS: f64 = 730.0;
+/// Days an exploit's risk takes to halve
+const EXPLOIT_HALF_LIFE_DAYS: f64 = 365.0;
+/// Risk of an exploit that lost funds, and of a disclosed one that didn't
+const EXPLOIT_LOSS_RISK: f64 = 100.0;
+const EXPLOIT_DISCLOSED_RISK: f64 = 30.0;
+/// Days the risk of a young protocol takes to halve
+const TVL_AGE_HALF_LIFE_DAYS: f64 = 365.0;
+/// Risk of a multisig whose threshold and signers aren't known
+const UNKNOWN_MULTISIG_RISK: f64 = 50.0;
+/// Timelocks this long give depositors time to exit before admin changes land
+const MIN_TIMELOCK_HOURS: f64 = 24.0;
+/// Insurance covering this share of the TVL scores no risk
+const FULL_INSURANCE_RATIO: f64 = 0.02;
+
+static PROTOCOL_RUBRIC: OnceLock<ProtocolRubric> = OnceLock::new();
+
+/// An audit of the protocol's programs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Audit {
+    pub auditor: String,
+    pub date: NaiveDate,
+}
+
+/// An exploit of the protocol, or a vulnerability disclosed before one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Exploit {
+    pub date: NaiveDate,
+    /// Zero for vulnerabilities fixed before any funds were lost
+    #[serde(default)]
+    pub loss_usd: f64,
+}
+
+/// Who can upgrade the programs and change the markets
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdminConfig {
+    /// A single key holds the upgrade authority otherwise
+    pub multisig: bool,
+    #[serde(default)]
+    pub threshold: Option<u32>,
+    #[serde(default)]
+    pub signers: Option<u32>,
+    /// Delay before admin changes take effect
+    #[serde(default)]
+    pub timelock_hours: f64,
+}
+
+/// What the rubric knows about one protocol
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolProfile {
+    pub protocol: Protocol,
+    /// When the entry was last checked against its sources
+    pub reviewed: NaiveDate,
+    #[serde(default)]
+    pub sources: Vec<String>,
+    /// When the protocol started taking deposits
+    pub launched: NaiveDate,
+    #[serde(default)]
+    pub audits: Vec<Audit>,
+    #[serde(default)]
+    pub exploits: Vec<Exploit>,
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub insurance_fund_usd: Option<f64>,
+    /// TVL the insurance fund covers, as of `reviewed`
+    #[serde(default)]
+    pub tvl_usd: Option<f64>,
+}
+
+/// Part of the rubric, every part scores between 0 and 100
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RubricComponent {
+    /// How many audits there were and how recent the latest is
+    Audits,
+    /// Past exploits, fading with their age
+    Exploits,
+    /// How long the protocol has held deposits
+    TvlAge,
+    /// Who controls upgrades
+    Admin,
+    /// Insurance fund relative to the TVL
+    Insurance,
+}
+
+impl RubricComponent {
+    pub const ALL: [RubricComponent; 5] = [
+        RubricComponent::Audits,
+        RubricComponent::Exploits,
+        RubricComponent::TvlAge,
+        RubricComponent::Admin,
+        RubricComponent::Insurance,
+    ];
+
+    pub fn weight(&self) -> f64 {
+        match self {
+            RubricComponent::Audits => 0.25,
+            RubricComponent::Exploits => 0.3,
+            RubricComponent::TvlAge => 0.15,
+            RubricComponent::Admin => 0.2,
+            RubricComponent::Insurance => 0.1,
+        }
+    }
+}
+
+/// Score of one part of the rubric
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ComponentScore {
+    pub component: RubricComponent,
+    pub weight: f64,
+    /// Between 0 and 100
+    pub risk: f64,
+}
+
+impl ProtocolProfile {
+    /// Risk of `component` as of `today`, the same inputs always scoring the same
+    pub fn component_risk(&self, component: RubricComponent, today: NaiveDate) -> f64 {
+        let days_since = |date: NaiveDate| (today - date).num_days().max(0) as f64;
+        match component {
+            RubricComponent::Audits => {
+                let audits: Vec<_> = self
+                    .audits
+                    .iter()
+                    .filter(|audit| audit.date <= today)
+                    .collect();
+                match audits.iter().map(|audit| audit.date).max() {
+                    Some(latest) => {
+                        let count_risk = 100.0 * 0.5f64.powi(audits.len() as i32);
+                        let age_risk = (days_since(latest) / AUDIT_STALE_DAYS).min(1.0) * 100.0;
+                        (count_risk + age_risk) / 2.0
+                    }
+                    None => 100.0,
+                }
+            }
+            RubricComponent::Exploits => self
+                .exploits
+                .iter()
+                .filter(|exploit| exploit.date <= today)
+                .map(|exploit| {
+                    let risk = if exploit.loss_usd > 0.0 {
+                        EXPLOIT_LOSS_RISK
+                    } else {
+                        EXPLOIT_DISCLOSED_RISK
+                    };
+                    risk * 0.5f64.powf(days_since(exploit.date) / EXPLOIT_HALF_LIFE_DAYS)
+                })
+                .sum::<f64>()
+                .min(100.0),
+            RubricComponent::TvlAge => {
+                100.0 * 0.5f64.powf(days_since(self.launched) / TVL_AGE_HALF_LIFE_DAYS)
+            }
+            RubricComponent::Admin => {
+                let admin = &self.admin;
+                let risk = match (admin.multisig, admin.threshold, admin.signers) {
+                    (false, _, _) => 100.0,
+                    // Every signer has to be compromised with an n-of-n, any one with a 1-of-n
+                    (true, Some(threshold), Some(signers)) if signers > 1 => {
+                        100.0 * (1.0 - threshold.saturating_sub(1) as f64 / (signers - 1) as f64)
+                    }
+                    (true, Some(_), Some(_)) => 100.0,
+                    (true, _, _) => UNKNOWN_MULTISIG_RISK,
+                };
+                if admin.timelock_hours >= MIN_TIMELOCK_HOURS {
+                    risk / 2.0
+                } else {
+                    risk
+                }
+            }
+            RubricComponent::Insurance => match (self.insurance_fund_usd, self.tvl_usd) {
+                (Some(fund), Some(tvl)) if tvl > 0.0 => {
+                    (100.0 * (1.0 - fund / tvl / FULL_INSURANCE_RATIO)).clamp(0.0, 100.0)
+                }
+                _ => 100.0,
+            },
+        }
+    }
+
+    /// Protocol risk as of `today`, with the score of every part of the rubric
+    pub fn protocol_risk(&self, today: NaiveDate) -> ProtocolRiskMetrics {
+        let components: Vec<ComponentScore> = RubricComponent::ALL
+            .iter()
+            .map(|component| ComponentScore {
+                component: *component,
+                weight: component.weight(),
+                risk: self.component_risk(*component, today),
+            })
+            .collect();
+        ProtocolRiskMetrics {
+            protocol_risk: components
+                .iter()
+                .map(|component| component.weight * component.risk)
+                .sum(),
+            components,
+            ..Default::default()
+        }
+    }
+
+    fn validate(&self) -> Result<(), RiskCalculationError> {
+        let invalid = |reason: &str| {
+            Err(RiskCalculationError::ParseError(format!(
+                "{:?} rubric entry: {}",
+                self.protocol, reason
+            )))
+        };
+        if let (Some(threshold), Some(signers)) = (self.admin.threshold, self.admin.signers) {
+            if threshold == 0 || threshold > signers {
+                return invalid("the multisig threshold must be between 1 and its signers");
+            }
+        }
+        if self.exploits.iter().any(|exploit| exploit.loss_usd < 0.0)
+            || self.insurance_fund_usd.is_some_and(|fund| fund < 0.0)
+            || self.tvl_usd.is_some_and(|tvl| tvl < 0.0)
+            || self.admin.timelock_hours < 0.0
+        {
+            return invalid("amounts and durations can't be negative");
+        }
+        Ok(())
+    }
+}
+
+/// Inputs protocol risk is scored from, one profile per protocol
+///
+/// Loaded once from the TOML file at `PROTOCOL_RUBRIC`, or the rubric built
+/// into the binary, see `protocol_rubric.toml` at the root of the repository.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolRubric {
+    pub protocols: Vec<ProtocolProfile>,
+}
+
+impl ProtocolRubric {
+    /// The rubric loaded at startup
+    ///
+    /// Panics if the rubric is invalid, which is why the server loads it before
+    /// serving anything.
+    pub fn global() -> &'static Self {
+        PROTOCOL_RUBRIC.get_or_init(|| Self::from_env().expect("protocol rubric must be valid"))
+    }
+
+    pub fn from_toml(rubric: &str) -> Result<Self, RiskCalculationError> {
+        let rubric: ProtocolRubric =
+            toml::from_str(rubric).map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
+        let mut protocols = HashSet::new();
+        for profile in &rubric.protocols {
+            if !protocols.insert(&profile.protocol) {
+                return Err(RiskCalculationError::ParseError(format!(
+                    "{:?} has more than one rubric entry",
+                    profile.protocol
+                )));
+            }
+            profile.validate()?;
+        }
+        Ok(rubric)
+    }
+
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        match std::env::var("PROTOCOL_RUBRIC") {
+            Ok(path) => {
+                let rubric = std::fs::read_to_string(&path).map_err(|e| {
+                    RiskCalculationError::CustomError(format!("reading {}: {}", path, e))
+                })?;
+                Self::from_toml(&rubric)
+            }
+            Err(_) => Self::from_toml(DEFAULT_RUBRIC),
+        }
+    }
+
+    /// Protocol risk of `protocol` as of `today`, an error when it has no entry
+    pub fn protocol_risk(
+        &self,
+        protocol: &Protocol,
+        today: NaiveDate,
+    ) -> Result<ProtocolRiskMetrics, RiskCalculationError> {
+        self.protocols
+            .iter()
+            .find(|profile| profile.protocol == *protocol)
+            .map(|profile| profile.protocol_risk(today))
+            .ok_or_else(|| {
+                RiskCalculationError::CustomError(format!(
+                    "{:?} has no protocol rubric entry",
+                    protocol
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(date: &str) -> NaiveDate {
+        date.parse().unwrap()
+    }
+
+    #[test]
+    fn test_protocol_risk() {
+        let rubric = ProtocolRubric::from_toml(
+            r#"
+            [[protocols]]
+            protocol = "Drift"
+            reviewed = "2026-01-01"
+            launched = "2024-01-01"
+            audits = [
+                { auditor = "A", date = "2025-01-01" },
+                { auditor = "B", date = "2025-06-01" },
+            ]
+            exploits = [{ date = "2025-01-01", loss_usd = 1000000.0 }]
+            admin = { multisig = true, threshold = 3, signers = 5, timelock_hours = 48 }
+            insurance_fund_usd = 1000000.0
+            tvl_usd = 100000000.0
+            "#,
+        )
+        .unwrap();
+        let today = date("2026-01-01");
+        let metrics = rubric.protocol_risk(&Protocol::Drift, today).unwrap();
+        let risk = |component| {
+            metrics
+                .components
+                .iter()
+                .find(|score| score.component == component)
+                .unwrap()
+                .risk
+        };
+        // Two audits, the latest 214 days old
+        assert!(
+            (risk(RubricComponent::Audits) - (25.0 + 214.0 / 730.0 * 100.0) / 2.0).abs() < 1e-9
+        );
+        assert!(
+            (risk(RubricComponent::Exploits) - 100.0 * 0.5f64.powf(365.0 / 365.0)).abs() < 1e-9
+        );
+        assert!((risk(RubricComponent::TvlAge) - 100.0 * 0.5f64.powf(731.0 / 365.0)).abs() < 1e-9);
+        // 3-of-5 behind a timelock
+        assert_eq!(risk(RubricComponent::Admin), 25.0);
+        // 1% of the TVL is half the target coverage
+        assert!((risk(RubricComponent::Insurance) - 50.0).abs() < 1e-9);
+        let weighted: f64 = metrics.components.iter().map(|c| c.weight * c.risk).sum();
+        assert_eq!(metrics.protocol_risk, weighted);
+        // Reproducible from the same inputs
+        assert_eq!(
+            rubric
+                .protocol_risk(&Protocol::Drift, today)
+                .unwrap()
+                .protocol_risk,
+            metrics.protocol_risk
+        );
+
+        assert!(rubric.protocol_risk(&Protocol::Solend, today).is_err());
+        assert!(ProtocolRubric::from_toml(
+            r#"
+            [[protocols]]
+            protocol = "Drift"
+            reviewed = "2026-01-01"
+            launched = "2024-01-01"
+            admin = { multisig = true, threshold = 6, signers = 5 }
+            "#,
+        )
+        .is_err());
+
+        // The built-in rubric covers the protocols that are assessed
+        let default = ProtocolRubric::from_toml(DEFAULT_RUBRIC).unwrap();
+        for protocol in [Protocol::Kamino, Protocol::Marginfy] {
+            let risk = default
+                .protocol_risk(&protocol, today)
+                .unwrap()
+                .protocol_risk;
+            assert!((0.0..=100.0).contains(&risk));
+        }
+    }
+}
@@ -0,0 +1,202 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::risk_model::RiskCalculationError;
+
+/// Maximum relative difference for two sources to count as agreeing
+pub const QUORUM_TOLERANCE: f64 = 0.02;
+/// Number of agreeing sources required before a figure is published unflagged
+pub const QUORUM_SIZE: usize = 2;
+
+/// Where a utilization and supply figure was read from, in order of preference
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DataSource {
+    /// Decoded directly from the on-chain reserve or bank account
+    OnChain,
+    /// The protocol's own API
+    ProtocolApi,
+    DefiLlama,
+}
+
+/// Borrows and supply of a reserve as reported by one source, in token units
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SourceReading {
+    pub total_borrows: f64,
+    pub total_supply: f64,
+}
+
+impl SourceReading {
+    fn utilization(&self) -> f64 {
+        if self.total_supply > 0.0 {
+            self.total_borrows / self.total_supply
+        } else {
+            0.0
+        }
+    }
+
+    fn agrees_with(&self, other: &SourceReading, tolerance: f64) -> bool {
+        relative_difference(self.total_supply, other.total_supply) <= tolerance
+            && relative_difference(self.utilization(), other.utilization()) <= tolerance
+    }
+}
+
+fn relative_difference(a: f64, b: f64) -> f64 {
+    let scale = a.abs().max(b.abs());
+    if scale == 0.0 {
+        0.0
+    } else {
+        (a - b).abs() / scale
+    }
+}
+
+/// The published borrows and supply figure and how the sources compared
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct QuorumReport {
+    pub total_borrows: f64,
+    pub total_supply: f64,
+    /// Source the published figure was taken from
+    pub source: DataSource,
+    /// Sources within tolerance of the published figure, including `source`
+    pub agreeing_sources: Vec<DataSource>,
+    pub disagreeing_sources: Vec<DataSource>,
+    /// Sources that could not be read
+    pub failed_sources: Vec<DataSource>,
+    /// Set when fewer than the quorum of sources agreed
+    pub flagged: bool,
+}
+
+/// Checks the readings of several sources against each other
+///
+/// The figure is taken from the most preferred source that could be read (the
+/// on-chain value whenever available) and is published unflagged when at least
+/// `quorum` sources agree within `tolerance`. Without a quorum the on-chain value
+/// is still published but flagged; if the on-chain value is missing too, nothing
+/// is published, as an off-chain source alone can't be trusted.
+pub fn resolve(
+    mut readings: Vec<(DataSource, Result<SourceReading, RiskCalculationError>)>,
+    tolerance: f64,
+    quorum: usize,
+) -> Result<QuorumReport, RiskCalculationError> {
+    readings.sort_by_key(|(source, _)| *source as u8);
+
+    let mut read = Vec::new();
+    let mut failed_sources = Vec::new();
+    for (source, reading) in readings {
+        match reading {
+            Ok(reading) => read.push((source, reading)),
+            Err(e) => {
+                tracing::warn!("Failed to read {:?}: {}", source, e);
+                failed_sources.push(source);
+            }
+        }
+    }
+    let (source, reference) = *read
+        .first()
+        .ok_or(RiskCalculationError::UpstreamUnavailable(
+            "No data source could be read".to_string(),
+        ))?;
+
+    let (agreeing_sources, disagreeing_sources): (Vec<_>, Vec<_>) = read
+        .iter()
+        .partition(|(_, reading)| reading.agrees_with(&reference, tolerance));
+    let agreeing_sources: Vec<DataSource> = agreeing_sources.iter().map(|(s, _)| *s).collect();
+    let disagreeing_sources: Vec<DataSource> =
+        disagreeing_sources.iter().map(|(s, _)| *s).collect();
+
+    let flagged = agreeing_sources.len() < quorum;
+    if flagged {
+        if source != DataSource::OnChain {
+            return Err(RiskCalculationError::CustomError(format!(
+                "No quorum without on-chain data: {:?} agree, {:?} disagree, {:?} failed",
+                agreeing_sources, disagreeing_sources, failed_sources
+            )));
+        }
+        tracing::warn!(
+            "Data sources disagree, using on-chain value: {:?} disagree, {:?} failed",
+            disagreeing_sources,
+            failed_sources
+        );
+    }
+
+    Ok(QuorumReport {
+        total_borrows: reference.total_borrows,
+        total_supply: reference.total_supply,
+        source,
+        agreeing_sources,
+        disagreeing_sources,
+        failed_sources,
+        flagged,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(
+        total_borrows: f64,
+        total_supply: f64,
+    ) -> Result<SourceReading, RiskCalculationError> {
+        Ok(SourceReading {
+            total_borrows,
+            total_supply,
+        })
+    }
+
+    fn failed() -> Result<SourceReading, RiskCalculationError> {
+        Err(RiskCalculationError::CustomError("unreachable".to_string()))
+    }
+
+    #[test]
+    fn test_quorum_reached() {
+        let report = resolve(
+            vec![
+                (DataSource::DefiLlama, reading(800.0, 1000.0)),
+                (DataSource::OnChain, reading(801.0, 1001.0)),
+                (DataSource::ProtocolApi, reading(400.0, 1000.0)),
+            ],
+            QUORUM_TOLERANCE,
+            QUORUM_SIZE,
+        )
+        .unwrap();
+        assert!(!report.flagged);
+        assert_eq!(report.source, DataSource::OnChain);
+        assert_eq!(report.total_supply, 1001.0);
+        assert_eq!(
+            report.agreeing_sources,
+            vec![DataSource::OnChain, DataSource::DefiLlama]
+        );
+        assert_eq!(report.disagreeing_sources, vec![DataSource::ProtocolApi]);
+    }
+
+    #[test]
+    fn test_disagreement_prefers_on_chain() {
+        let report = resolve(
+            vec![
+                (DataSource::OnChain, reading(800.0, 1000.0)),
+                (DataSource::ProtocolApi, reading(100.0, 5000.0)),
+                (DataSource::DefiLlama, failed()),
+            ],
+            QUORUM_TOLERANCE,
+            QUORUM_SIZE,
+        )
+        .unwrap();
+        assert!(report.flagged);
+        assert_eq!(report.total_borrows, 800.0);
+        assert_eq!(report.failed_sources, vec![DataSource::DefiLlama]);
+    }
+
+    #[test]
+    fn test_no_quorum_without_on_chain() {
+        assert!(resolve(
+            vec![
+                (DataSource::OnChain, failed()),
+                (DataSource::ProtocolApi, reading(800.0, 1000.0)),
+            ],
+            QUORUM_TOLERANCE,
+            QUORUM_SIZE,
+        )
+        .is_err());
+    }
+}
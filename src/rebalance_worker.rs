@@ -0,0 +1,213 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::StreamExt;
+use serde::Serialize;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{
+    cache_lock::compute_if_unlocked,
+    cache_schema::{record_key, versioned_key},
+    portfolio::rebalance_wallet_if_due,
+    portfolio_events::{PortfolioStore, RedisPortfolioStore},
+    rebalancing::RebalancePlan,
+    redis_connection::shared_connection,
+    risk_model::RiskCalculationError,
+    server::parse_positive_env,
+    shutdown,
+    state::AppState,
+};
+
+const DEFAULT_CONCURRENCY: usize = 4;
+/// Plans kept in the stream, the transaction system is expected to keep up
+const PLANS_STREAM_MAX_LEN: usize = 10_000;
+
+/// How often the worker checks every portfolio, and how many it rebalances at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RebalanceWorkerConfig {
+    pub interval: Duration,
+    pub concurrency: usize,
+}
+
+impl RebalanceWorkerConfig {
+    /// Reads `REBALANCE_WORKER_INTERVAL_SECS`, the worker is disabled when not
+    /// set, and `REBALANCE_WORKER_CONCURRENCY`, 4 by default
+    pub fn from_env() -> Result<Option<Self>, RiskCalculationError> {
+        let Some(interval) = parse_positive_env("REBALANCE_WORKER_INTERVAL_SECS")? else {
+            return Ok(None);
+        };
+        Ok(Some(RebalanceWorkerConfig {
+            interval: Duration::from_secs(interval),
+            concurrency: parse_positive_env("REBALANCE_WORKER_CONCURRENCY")?
+                .unwrap_or(DEFAULT_CONCURRENCY),
+        }))
+    }
+}
+
+/// Redis stream the transaction system reads rebalance plans from
+///
+/// Like the event log, plans can't be recomputed, so the key isn't versioned.
+pub fn plans_stream_key() -> String {
+    record_key("rebalance_plans")
+}
+
+/// Held while a wallet's portfolio changes, so instances don't rebalance it
+/// twice and a deposit can't land between a rebalance's read and its write
+pub fn wallet_lock_key(wallet: &Pubkey) -> String {
+    versioned_key(&format!("rebalance:{}", wallet))
+}
+
+/// What happened to a portfolio in a run of the worker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalletOutcome {
+    Rebalanced,
+    NotDue,
+    /// Another instance was rebalancing it, or a deposit or withdrawal was changing it
+    Locked,
+    Failed,
+}
+
+/// Tally of a run of the worker over every portfolio
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct RebalanceRun {
+    pub rebalanced: usize,
+    pub not_due: usize,
+    pub locked: usize,
+    pub failed: usize,
+}
+
+impl RebalanceRun {
+    pub fn record(&mut self, outcome: WalletOutcome) {
+        match outcome {
+            WalletOutcome::Rebalanced => self.rebalanced += 1,
+            WalletOutcome::NotDue => self.not_due += 1,
+            WalletOutcome::Locked => self.locked += 1,
+            WalletOutcome::Failed => self.failed += 1,
+        }
+    }
+}
+
+/// Appends a plan to [`plans_stream_key`] for the transaction system
+pub async fn publish_plan(
+    redis_client: &redis::Client,
+    wallet: &Pubkey,
+    plan: &RebalancePlan,
+) -> Result<(), RiskCalculationError> {
+    let plan = serde_json::to_string(plan).map_err(RiskCalculationError::SerdeError)?;
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    redis::cmd("XADD")
+        .arg(plans_stream_key())
+        .arg("MAXLEN")
+        .arg("~")
+        .arg(PLANS_STREAM_MAX_LEN)
+        .arg("*")
+        .arg("wallet")
+        .arg(wallet.to_string())
+        .arg("planned_at")
+        .arg(Utc::now().to_rfc3339())
+        .arg("plan")
+        .arg(plan)
+        .query_async::<String>(&mut connection)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    Ok(())
+}
+
+/// Rebalances `wallet` if it's due and nothing else is changing it, publishing the plan
+async fn rebalance_one(state: &AppState, wallet: &Pubkey) -> WalletOutcome {
+    let rebalanced =
+        compute_if_unlocked(Some(&state.redis_client), &wallet_lock_key(wallet), async {
+            Ok(rebalance_wallet_if_due(state, wallet).await?.is_some())
+        })
+        .await;
+    match rebalanced {
+        Ok(Some(true)) => WalletOutcome::Rebalanced,
+        Ok(Some(false)) => WalletOutcome::NotDue,
+        Ok(None) => WalletOutcome::Locked,
+        Err(e) => {
+            tracing::error!("Failed to rebalance {}: {}", wallet, e);
+            WalletOutcome::Failed
+        }
+    }
+}
+
+/// Checks every stored portfolio, rebalancing the due ones `concurrency` at a time
+pub async fn rebalance_due(
+    state: &AppState,
+    concurrency: usize,
+) -> Result<RebalanceRun, RiskCalculationError> {
+    let wallets = RedisPortfolioStore::new(state.redis_client.clone())
+        .list()
+        .await?;
+    let mut run = RebalanceRun::default();
+    let mut outcomes = futures::stream::iter(wallets)
+        .map(|wallet| {
+            let state = state.clone();
+            async move { rebalance_one(&state, &wallet).await }
+        })
+        .buffer_unordered(concurrency);
+    while let Some(outcome) = outcomes.next().await {
+        run.record(outcome);
+    }
+    Ok(run)
+}
+
+/// Spawns the worker rebalancing due portfolios every `config.interval`
+///
+/// Once `state.shutdown` is cancelled the worker stops waiting for the next
+/// run, a run in progress still completes.
+pub fn spawn_rebalance_worker(
+    state: AppState,
+    config: RebalanceWorkerConfig,
+) -> tokio::task::JoinHandle<()> {
+    shutdown::spawn_background(async move {
+        while !state.shutdown.is_cancelled() {
+            match rebalance_due(&state, config.concurrency).await {
+                Ok(run) => tracing::info!(
+                    "Rebalanced {} portfolios, {} not due, {} locked, {} failed",
+                    run.rebalanced,
+                    run.not_due,
+                    run.locked,
+                    run.failed
+                ),
+                Err(e) => tracing::error!("Rebalancing worker failed: {}", e),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(config.interval) => {}
+                _ = state.shutdown.cancelled() => {}
+            }
+        }
+        tracing::info!("Rebalancing worker stopped");
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rebalance_run() {
+        let mut run = RebalanceRun::default();
+        for outcome in [
+            WalletOutcome::Rebalanced,
+            WalletOutcome::NotDue,
+            WalletOutcome::NotDue,
+            WalletOutcome::Locked,
+        ] {
+            run.record(outcome);
+        }
+        assert_eq!(
+            run,
+            RebalanceRun {
+                rebalanced: 1,
+                not_due: 2,
+                locked: 1,
+                failed: 0,
+            }
+        );
+        let wallet = Pubkey::new_unique();
+        assert_eq!(wallet_lock_key(&wallet), format!("v2:rebalance:{}", wallet));
+    }
+}
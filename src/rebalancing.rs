@@ -1,10 +1,25 @@
 use std::collections::HashMap;
 use std::fmt::{self, Display};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use solana_sdk::pubkey::Pubkey;
 
-use crate::risk_model::{Protocol, RiskProfile};
+pub use crate::assets::Asset;
+pub use crate::bps::Bps;
+use crate::clock::{Clock, SystemClock};
+use crate::feasibility::{check_legs, Feasibility, PlanLeg, SignerBalances};
+pub use crate::liquidity_depth::{
+    LiquidityDepthCurve, PositionLimitConfig, RepaymentModel, WithdrawalHorizon,
+};
+pub use crate::optimizer::{RiskAdjustedYieldModel, WeightObjective};
+pub use crate::portfolio::{profile_target_weights, target_risk_weights};
+pub use crate::prices::VALUATION_CURRENCY;
+use crate::registry::{ProtocolRegistry, COMPARED_ASSET};
+use crate::risk_model::{Protocol, RiskCalculationError, RiskProfile};
+use crate::snapshot::RiskSnapshot;
 
 /// Represents a pool where funds can be allocated
 #[derive(Debug, Clone, PartialEq)]
@@ -13,19 +28,34 @@ pub struct Pool {
     pub balance: u64,
 }
 
-/// Portfolio for a single user containing multiple risk profiles
-#[derive(Debug, Clone, PartialEq)]
+/// Portfolio for a single user containing multiple risk profiles per asset
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct UserPortfolio {
     pub user_wallet: Pubkey,
-    pub risk_profiles: HashMap<RiskProfile, ProfileAllocation>,
+    /// Profiles of every asset held, amounts in the asset's native units
+    pub assets: HashMap<Asset, HashMap<RiskProfile, ProfileAllocation>>,
     pub last_rebalance: SystemTime,
 }
 
-/// Display a basis point value as a percentage string
-fn format_basis_points(basis_points: u64) -> String {
-    let whole_percent = basis_points / 100;
-    let decimal = (basis_points % 100) / 10; // First decimal place
-    format!("{}.{}%", whole_percent, decimal)
+impl UserPortfolio {
+    /// A portfolio holding nothing, never rebalanced
+    pub fn new(user_wallet: Pubkey) -> Self {
+        UserPortfolio {
+            user_wallet,
+            assets: HashMap::new(),
+            last_rebalance: std::time::UNIX_EPOCH,
+        }
+    }
+
+    /// What `profile` holds of `asset`
+    pub fn allocation(&self, asset: Asset, profile: &RiskProfile) -> Option<&ProfileAllocation> {
+        self.assets.get(&asset)?.get(profile)
+    }
+
+    /// Every profile of every asset
+    pub fn allocations(&self) -> impl Iterator<Item = &ProfileAllocation> {
+        self.assets.values().flat_map(|profiles| profiles.values())
+    }
 }
 
 impl Display for UserPortfolio {
@@ -40,60 +70,54 @@ impl Display for UserPortfolio {
             "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━"
         )?;
 
-        if self.risk_profiles.is_empty() {
+        if self.allocations().next().is_none() {
             writeln!(f, "📝 No risk profiles found in portfolio")?;
         } else {
-            let mut total_value = 0;
-
-            // Calculate overall total
-            for allocation in self.risk_profiles.values() {
-                total_value = total_value + allocation.total_amount;
-            }
-
-            writeln!(f, "📊 TOTAL VALUE | {}", format_amount(total_value))?;
             writeln!(f, "⏰ LAST REBALANCE | {:?}", self.last_rebalance)?;
-            writeln!(f, "\n📋 RISK PROFILES")?;
-
-            for (risk_profile, allocation) in &self.risk_profiles {
-                // Calculate percentage in basis points (10000 = 100%)
-                let percentage_bps = if total_value > 0 {
-                    // Scale up first to avoid precision loss
-                    (allocation.total_amount as u128)
-                        .saturating_mul(10_000)
-                        .saturating_div(total_value as u128) as u64
-                } else {
-                    0
-                };
+
+            // Totals are per asset, amounts of different assets don't add up
+            for (asset, profiles) in &self.assets {
+                let total_value = profiles
+                    .values()
+                    .map(|allocation| allocation.total_amount)
+                    .fold(0u64, u64::saturating_add);
 
                 writeln!(
                     f,
-                    "\n🔹 {} | {} ({} of portfolio)",
-                    risk_profile,
-                    format_amount(allocation.total_amount),
-                    format_basis_points(percentage_bps)
+                    "\n📊 {} | TOTAL VALUE | {}",
+                    asset,
+                    asset.format_amount(total_value)
                 )?;
+                writeln!(f, "📋 RISK PROFILES")?;
 
-                writeln!(f, "  Protocol   | Amount        | Allocation")?;
-                writeln!(f, "  -----------|---------------|-------------")?;
-
-                for (protocol, amount) in &allocation.pool_allocations {
-                    // Calculate protocol percentage in basis points
-                    let protocol_bps = if allocation.total_amount > 0 {
-                        (*amount as u128)
-                            .saturating_mul(10_000)
-                            .saturating_div(allocation.total_amount as u128)
-                            as u64
-                    } else {
-                        0
-                    };
+                for (risk_profile, allocation) in profiles {
+                    let percentage_bps =
+                        Bps::from_parts(allocation.total_amount, total_value).unwrap_or(Bps::ZERO);
 
                     writeln!(
                         f,
-                        "  {} | {:12} | {}",
-                        protocol,
-                        format_amount(*amount),
-                        format_basis_points(protocol_bps)
+                        "\n🔹 {} | {} ({} of {} holdings)",
+                        risk_profile,
+                        asset.format_amount(allocation.total_amount),
+                        percentage_bps,
+                        asset
                     )?;
+
+                    writeln!(f, "  Protocol   | Amount        | Allocation")?;
+                    writeln!(f, "  -----------|---------------|-------------")?;
+
+                    for (protocol, amount) in &allocation.pool_allocations {
+                        let protocol_bps =
+                            Bps::from_parts(*amount, allocation.total_amount).unwrap_or(Bps::ZERO);
+
+                        writeln!(
+                            f,
+                            "  {} | {:12} | {}",
+                            protocol,
+                            asset.format_amount(*amount),
+                            protocol_bps
+                        )?;
+                    }
                 }
             }
         }
@@ -106,6 +130,102 @@ impl Display for UserPortfolio {
     }
 }
 
+/// Value of a portfolio in [`VALUATION_CURRENCY`] at the prices it was valued at
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct PortfolioValuation {
+    pub valuation_currency: &'static str,
+    pub total_value: f64,
+    /// Price per token of every asset held
+    pub prices: HashMap<Asset, f64>,
+    /// Most valuable first
+    pub profiles: Vec<ProfileValuation>,
+}
+
+/// Value of what a profile holds of one asset
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct ProfileValuation {
+    pub asset: Asset,
+    pub profile: RiskProfile,
+    pub value: f64,
+    /// Share of the portfolio's value, between 0 and 1
+    pub share: f64,
+    /// Value held in every protocol
+    pub pools: HashMap<Protocol, f64>,
+}
+
+impl UserPortfolio {
+    /// Values every profile at `prices`, per token of each asset
+    ///
+    /// Unlike native amounts, values of different assets add up, so shares
+    /// are of the whole portfolio. Fails when an asset held has no price.
+    pub fn valuation(
+        &self,
+        prices: &HashMap<Asset, f64>,
+    ) -> Result<PortfolioValuation, RiskCalculationError> {
+        let mut profiles = Vec::new();
+        for (asset, asset_profiles) in &self.assets {
+            let price = *prices.get(asset).ok_or_else(|| {
+                RiskCalculationError::UpstreamUnavailable(format!("No price of {}", asset))
+            })?;
+            let value = |amount: u64| asset.to_ui_amount(amount) * price;
+            for (profile, allocation) in asset_profiles {
+                profiles.push(ProfileValuation {
+                    asset: *asset,
+                    profile: profile.clone(),
+                    value: value(allocation.total_amount),
+                    share: 0.0,
+                    pools: allocation
+                        .pool_allocations
+                        .iter()
+                        .map(|(protocol, amount)| (protocol.clone(), value(*amount)))
+                        .collect(),
+                });
+            }
+        }
+        let total_value = profiles.iter().map(|profile| profile.value).sum::<f64>();
+        for profile in &mut profiles {
+            if total_value > 0.0 {
+                profile.share = profile.value / total_value;
+            }
+        }
+        profiles.sort_by(|a, b| b.value.total_cmp(&a.value));
+
+        Ok(PortfolioValuation {
+            valuation_currency: VALUATION_CURRENCY,
+            total_value,
+            prices: self
+                .assets
+                .keys()
+                .filter_map(|asset| Some((*asset, *prices.get(asset)?)))
+                .collect(),
+            profiles,
+        })
+    }
+}
+
+impl Display for PortfolioValuation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "💵 PORTFOLIO VALUE | {:.2} {}",
+            self.total_value, self.valuation_currency
+        )?;
+        writeln!(f, "  Profile    | Asset | Value           | Share")?;
+        writeln!(f, "  -----------|-------|-----------------|-------")?;
+        for profile in &self.profiles {
+            writeln!(
+                f,
+                "  {} | {} | {:15.2} | {:.2}%",
+                profile.profile,
+                profile.asset,
+                profile.value,
+                profile.share * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
 // Add a standalone format_amount function for use in Display implementation
 fn format_amount(amount: u64) -> String {
     if amount >= 1_000_000_000 {
@@ -119,21 +239,36 @@ fn format_amount(amount: u64) -> String {
     }
 }
 
-/// Allocation for a specific risk profile
-#[derive(Debug, Clone, PartialEq)]
+/// Allocation for a specific risk profile, in native units of its asset
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct ProfileAllocation {
     pub risk_profile: RiskProfile,
+    /// USDC for allocations recorded before portfolios held other assets
+    #[serde(default)]
+    pub asset: Asset,
     pub pool_allocations: HashMap<Protocol, u64>, // Pool ID -> Amount
     pub total_amount: u64,
 }
 
+impl ProfileAllocation {
+    pub fn empty(risk_profile: RiskProfile, asset: Asset) -> Self {
+        ProfileAllocation {
+            risk_profile,
+            asset,
+            pool_allocations: HashMap::new(),
+            total_amount: 0,
+        }
+    }
+}
+
 impl Display for ProfileAllocation {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(
             f,
-            "📊 PROFILE ALLOCATION | {} | Total: {}",
+            "📊 PROFILE ALLOCATION | {} | {} | Total: {}",
             self.risk_profile,
-            format_amount(self.total_amount)
+            self.asset,
+            self.asset.format_amount(self.total_amount)
         )?;
 
         if self.pool_allocations.is_empty() {
@@ -143,21 +278,14 @@ impl Display for ProfileAllocation {
             writeln!(f, "  -----------|---------------|-------------")?;
 
             for (protocol, amount) in &self.pool_allocations {
-                // Calculate protocol percentage in basis points
-                let protocol_bps = if self.total_amount > 0 {
-                    (*amount as u128)
-                        .saturating_mul(10_000)
-                        .saturating_div(self.total_amount as u128) as u64
-                } else {
-                    0
-                };
+                let protocol_bps = Bps::from_parts(*amount, self.total_amount).unwrap_or(Bps::ZERO);
 
                 writeln!(
                     f,
                     "  {} | {:12} | {}",
                     protocol,
-                    format_amount(*amount),
-                    format_basis_points(protocol_bps)
+                    self.asset.format_amount(*amount),
+                    protocol_bps
                 )?;
             }
         }
@@ -166,46 +294,348 @@ impl Display for ProfileAllocation {
     }
 }
 
+/// Splits `amount` proportionally to `weights` using the largest remainder method
+///
+/// Every part is first rounded down, then the units lost to rounding are handed
+/// out one at a time to the parts with the largest fractional remainders. The
+/// parts therefore always sum to exactly `amount` (unless all weights are zero,
+/// in which case every part is zero), and no part exceeds its exact share
+/// rounded up. Parts are returned in the same order as `weights`.
+pub fn split_proportionally(amount: u64, weights: &[(Protocol, u64)]) -> Vec<(Protocol, u64)> {
+    let total_weight = weights
+        .iter()
+        .map(|(_, weight)| *weight as u128)
+        .sum::<u128>();
+    if total_weight == 0 {
+        return weights
+            .iter()
+            .map(|(pool_id, _)| (pool_id.clone(), 0))
+            .collect();
+    }
+
+    let mut parts: Vec<(Protocol, u64)> = Vec::with_capacity(weights.len());
+    let mut remainders: Vec<(usize, u128)> = Vec::with_capacity(weights.len());
+    let mut allocated: u128 = 0;
+    for (i, (pool_id, weight)) in weights.iter().enumerate() {
+        let exact = amount as u128 * *weight as u128;
+        let part = exact / total_weight;
+        allocated += part;
+        parts.push((pool_id.clone(), part as u64));
+        remainders.push((i, exact % total_weight));
+    }
+
+    // Hand out the rounding shortfall, largest remainder first (ties keep input order)
+    remainders.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    let shortfall = (amount as u128 - allocated) as usize;
+    for (i, _) in remainders.into_iter().take(shortfall) {
+        parts[i].1 += 1;
+    }
+    parts
+}
+
+/// Recommended weights once unavailable protocols are taken out
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenormalizedWeights {
+    /// Basis points per protocol, summing to 10,000
+    pub weights: Vec<(Protocol, u64)>,
+    /// Explains how the weight of unavailable protocols was redistributed
+    pub note: Option<String>,
+}
+
+/// Redistributes the weight of `unavailable` protocols among the remaining ones
+///
+/// The freed weight goes to the remaining protocols proportionally to their own
+/// weight. A protocol that would exceed its cap is held at the cap and the excess
+/// goes to the others, so the weights still sum to 10,000. Fails if nothing is
+/// left to redistribute to or the caps can't absorb the weight.
+pub fn renormalize_weights(
+    weights: &HashMap<Protocol, u64>,
+    unavailable: &[Protocol],
+    caps: &HashMap<Protocol, u64>,
+) -> Result<RenormalizedWeights, String> {
+    let mut sorted: Vec<(Protocol, u64)> = weights
+        .iter()
+        .filter(|(_, weight)| **weight > 0)
+        .map(|(protocol, weight)| (protocol.clone(), *weight))
+        .collect();
+    sorted.sort_by_key(|(_, weight)| std::cmp::Reverse(*weight));
+    let (removed, mut open): (Vec<_>, Vec<_>) = sorted
+        .into_iter()
+        .partition(|(protocol, _)| unavailable.contains(protocol));
+    let within_caps = open
+        .iter()
+        .all(|(protocol, weight)| caps.get(protocol).is_none_or(|cap| weight <= cap));
+    if removed.is_empty() && within_caps {
+        return Ok(RenormalizedWeights {
+            weights: open,
+            note: None,
+        });
+    }
+    if open.is_empty() {
+        return Err("Every recommended protocol is unavailable".to_string());
+    }
+
+    let mut capped: Vec<(Protocol, u64)> = Vec::new();
+    let redistributed = loop {
+        let budget = Bps(capped.iter().map(|(_, weight)| *weight).sum())
+            .remainder()
+            .0;
+        let parts = split_proportionally(budget, &open);
+        let over_cap: Vec<Protocol> = parts
+            .iter()
+            .filter(|(protocol, part)| caps.get(protocol).is_some_and(|cap| part > cap))
+            .map(|(protocol, _)| protocol.clone())
+            .collect();
+        if over_cap.is_empty() {
+            break parts;
+        }
+        open.retain(|(protocol, _)| !over_cap.contains(protocol));
+        capped.extend(over_cap.into_iter().map(|protocol| {
+            let cap = caps[&protocol];
+            (protocol, cap)
+        }));
+        if open.is_empty() {
+            return Err(format!(
+                "Caps leave {} unallocated",
+                Bps(capped.iter().map(|(_, weight)| *weight).sum()).remainder()
+            ));
+        }
+    };
+
+    let describe = |weights: &[(Protocol, u64)]| {
+        weights
+            .iter()
+            .map(|(protocol, weight)| format!("{} ({})", protocol.to_string().trim(), Bps(*weight)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut note = if removed.is_empty() {
+        format!(
+            "Weight above the caps was redistributed proportionally to {}",
+            describe(&redistributed)
+        )
+    } else {
+        format!(
+            "Unavailable: {}. Their weight was redistributed proportionally to {}",
+            describe(&removed),
+            describe(&redistributed)
+        )
+    };
+    if !capped.is_empty() {
+        note.push_str(&format!(", holding {} at their cap", describe(&capped)));
+    }
+
+    let mut weights = capped;
+    weights.extend(redistributed);
+    Ok(RenormalizedWeights {
+        weights,
+        note: Some(note),
+    })
+}
+
 /// System A: AI Risk Model interface
 pub trait RiskWeightModel {
     /// Get recommended pool weights for a given risk profile
     fn get_recommended_weights(&self, profile: &RiskProfile) -> HashMap<Protocol, u64>;
+    /// Protocols whose data is degraded or frozen, their weight is redistributed
+    fn unavailable_protocols(&self) -> Vec<Protocol> {
+        Vec::new()
+    }
+    /// Maximum weight per protocol, in basis points
+    fn weight_caps(&self) -> HashMap<Protocol, u64> {
+        HashMap::new()
+    }
+    /// Largest position per protocol in `asset`, in its native units
+    ///
+    /// See [`LiquidityDepthCurve::position_limit`].
+    fn position_limits(&self, _asset: Asset) -> HashMap<Protocol, u64> {
+        HashMap::new()
+    }
+    /// Recommended weights renormalized over the available protocols, within
+    /// the caps and the position limits for a profile of `total_amount` of `asset`
+    fn get_available_weights(
+        &self,
+        profile: &RiskProfile,
+        asset: Asset,
+        total_amount: u64,
+    ) -> Result<RenormalizedWeights, String> {
+        self.get_limited_weights(profile, asset, total_amount, &HashMap::new())
+    }
+    /// Like [`Self::get_available_weights`], also within `limits`, in basis points
+    fn get_limited_weights(
+        &self,
+        profile: &RiskProfile,
+        asset: Asset,
+        total_amount: u64,
+        limits: &HashMap<Protocol, u64>,
+    ) -> Result<RenormalizedWeights, String> {
+        let mut caps = self.weight_caps();
+        for (protocol, limit) in limits {
+            caps.entry(protocol.clone())
+                .and_modify(|cap| *cap = (*cap).min(*limit))
+                .or_insert(*limit);
+        }
+        for (protocol, limit) in self.position_limits(asset) {
+            let limit = Bps::from_parts(limit, total_amount).unwrap_or(Bps::FULL).0;
+            caps.entry(protocol)
+                .and_modify(|cap| *cap = (*cap).min(limit))
+                .or_insert(limit);
+        }
+        renormalize_weights(
+            &self.get_recommended_weights(profile),
+            &self.unavailable_protocols(),
+            &caps,
+        )
+    }
+}
+
+/// What makes a portfolio due for a rebalance
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RebalanceTrigger {
+    /// Once `rebalance_interval` has elapsed since the last rebalance
+    #[default]
+    Time,
+    /// As soon as a pool drifts from its target weight by more than `drift_threshold`
+    Drift,
+    /// Whichever of the two comes first
+    Both,
+}
+
+/// Largest gap between a profile's current pool weights and `target_weights`
+///
+/// Pools the profile holds but no longer targets count with a target of zero.
+/// An empty profile has no drift.
+pub fn allocation_drift(allocation: &ProfileAllocation, target_weights: &[(Protocol, u64)]) -> Bps {
+    let current_weight = |pool_id: &Protocol| {
+        let amount = *allocation.pool_allocations.get(pool_id).unwrap_or(&0);
+        Bps::from_parts(amount, allocation.total_amount).unwrap_or(Bps::ZERO)
+    };
+    let targeted = target_weights
+        .iter()
+        .map(|(pool_id, weight)| current_weight(pool_id).0.abs_diff(*weight));
+    let untargeted = allocation
+        .pool_allocations
+        .keys()
+        .filter(|pool_id| !target_weights.iter().any(|(id, _)| id == *pool_id))
+        .map(|pool_id| current_weight(pool_id).0);
+    Bps(targeted.chain(untargeted).max().unwrap_or(0))
 }
 
 /// Rebalancing system that connects risk model with transaction execution
 pub struct RebalancingSystem<R: RiskWeightModel> {
     pub risk_model: R,
     pub rebalance_interval: Duration,
+    pub trigger: RebalanceTrigger,
+    /// Drift from the target weights that triggers a rebalance in drift mode
+    pub drift_threshold: Bps,
+    /// Transfers below these amounts are skipped, in native units of each asset
+    pub min_transfer_amounts: HashMap<Asset, u64>,
+    pub transfer_costs: TransferCostModel,
+    pub exposure_limits: ExposureLimits,
+    /// Clock rebalance intervals are measured with
+    pub clock: Arc<dyn Clock>,
+}
+
+impl<R: RiskWeightModel> RebalancingSystem<R> {
+    /// Target weights of a profile of `total_amount` within the exposure
+    /// limits, with the limits that held a protocol below its recommended weight
+    ///
+    /// The weight a limit holds back goes to the other protocols, see [`renormalize_weights`].
+    pub fn limited_weights(
+        &self,
+        profile: &RiskProfile,
+        asset: Asset,
+        total_amount: u64,
+    ) -> Result<(RenormalizedWeights, Vec<AppliedCap>), String> {
+        let recommended = self
+            .risk_model
+            .get_available_weights(profile, asset, total_amount)?;
+        let caps = self.exposure_limits.weight_caps(asset, total_amount);
+        if caps.is_empty() {
+            return Ok((recommended, Vec::new()));
+        }
+        let limited = self
+            .risk_model
+            .get_limited_weights(profile, asset, total_amount, &caps)?;
+        let applied = recommended
+            .weights
+            .iter()
+            .filter_map(|(protocol, weight)| {
+                let cap = *caps.get(protocol)?;
+                (*weight > cap).then(|| AppliedCap {
+                    protocol: protocol.clone(),
+                    recommended: Bps(*weight),
+                    cap: Bps(cap),
+                    max_amount: Bps(cap).apply_to(total_amount).unwrap_or(total_amount),
+                })
+            })
+            .collect();
+        Ok((limited, applied))
+    }
+
+    /// Why `transfer` of `asset` shouldn't be executed, if it shouldn't
+    pub fn skip_reason(&self, asset: Asset, transfer: &PoolTransfer) -> Option<SkipReason> {
+        let min_transfer_amount = self.min_transfer_amounts.get(&asset).copied().unwrap_or(0);
+        if transfer.amount < min_transfer_amount {
+            return Some(SkipReason::BelowMinimum {
+                min_transfer_amount,
+            });
+        }
+        let cost = self.transfer_costs.cost(asset, transfer);
+        let benefit = self.transfer_costs.benefit(transfer);
+        (cost > benefit).then_some(SkipReason::CostExceedsBenefit { cost, benefit })
+    }
 }
 
 pub trait RebalanceSystem<R: RiskWeightModel> {
+    #[allow(clippy::new_ret_no_self)]
     fn new(risk_model: R) -> RebalancingSystem<R> {
-        println!("📊 SYSTEM INIT | Creating new rebalancing system with 1 hour interval");
         RebalancingSystem {
             risk_model,
-            rebalance_interval: Duration::from_secs(1 * 60 * 60), // 1 hour
+            rebalance_interval: Duration::from_secs(60 * 60), // 1 hour
+            trigger: RebalanceTrigger::Time,
+            drift_threshold: Bps(500),
+            min_transfer_amounts: HashMap::new(),
+            transfer_costs: TransferCostModel::default(),
+            exposure_limits: ExposureLimits::default(),
+            clock: Arc::new(SystemClock),
         }
     }
     fn should_rebalance(&self, portfolio: &UserPortfolio) -> bool;
-    fn rebalance(&mut self, portfolio: &mut UserPortfolio) -> Result<(), String>;
+    fn rebalance(&mut self, portfolio: &mut UserPortfolio) -> Result<RebalancePlan, String>;
+    fn rebalance_profile(
+        &mut self,
+        profile: &RiskProfile,
+        allocation: &mut ProfileAllocation,
+    ) -> Result<ProfileRebalance, String>;
     fn deposit(
         &mut self,
         portfolio: &mut UserPortfolio,
+        asset: Asset,
         profile: RiskProfile,
         amount: u64,
     ) -> Result<TransactionSystemDeposits, String>;
     fn withdraw(
         &mut self,
         portfolio: &mut UserPortfolio,
+        asset: Asset,
         profile: &RiskProfile,
         amount: u64,
-    ) -> Result<(), String>;
+    ) -> Result<TransactionSystemWithdrawals, String>;
 }
 
 /// Response from the transaction system API containing deposits that need to be executed
+#[derive(Debug, Clone, Serialize, JsonSchema)]
 pub struct TransactionSystemDeposits {
+    /// Asset deposited, amounts are in its native units
+    pub asset: Asset,
     /// List of deposits that need to be processed by the transaction system
     pub deposits_to_execute: Vec<DepositToExecute>,
+    /// Set when the weights were renormalized around unavailable protocols
+    pub redistribution_note: Option<String>,
+    /// Exposure limits that sent part of the deposit to other protocols
+    pub applied_caps: Vec<AppliedCap>,
 }
 impl Display for TransactionSystemDeposits {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -213,10 +643,16 @@ impl Display for TransactionSystemDeposits {
             f,
             "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━"
         )?;
-        writeln!(f, "💰 DEPOSITS TO EXECUTE")?;
+        writeln!(f, "💰 DEPOSITS TO EXECUTE | {}", self.asset)?;
         for deposit in &self.deposits_to_execute {
             writeln!(f, "{}", deposit)?;
         }
+        if let Some(note) = &self.redistribution_note {
+            writeln!(f, "⚠️ REDISTRIBUTION | {}", note)?;
+        }
+        for cap in &self.applied_caps {
+            writeln!(f, "🧢 EXPOSURE CAP | {}", cap)?;
+        }
         writeln!(
             f,
             "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━"
@@ -225,11 +661,66 @@ impl Display for TransactionSystemDeposits {
     }
 }
 
-#[derive(Debug, Clone)]
+impl TransactionSystemDeposits {
+    /// Flags every deposit the signer's balances can't cover
+    ///
+    /// Run before handing the deposits to the transaction system, so it
+    /// doesn't discover failures on-chain.
+    pub fn check_feasibility(&mut self, balances: &SignerBalances, fee_lamports_per_leg: u64) {
+        let legs: Vec<PlanLeg> = self
+            .deposits_to_execute
+            .iter()
+            .map(|deposit| PlanLeg::Deposit(deposit.amount))
+            .collect();
+        for (deposit, feasibility) in self.deposits_to_execute.iter_mut().zip(check_legs(
+            balances,
+            &legs,
+            fee_lamports_per_leg,
+        )) {
+            deposit.feasibility = Some(feasibility);
+        }
+    }
+
+    /// Fails with the reasons of the deposits flagged infeasible, if any
+    pub fn ensure_feasible(&self) -> Result<(), String> {
+        ensure_feasible(
+            self.deposits_to_execute
+                .iter()
+                .map(|deposit| (&deposit.protocol, &deposit.feasibility)),
+        )
+    }
+}
+
+/// Fails with the reasons of the legs flagged infeasible, legs not checked pass
+fn ensure_feasible<'a>(
+    legs: impl Iterator<Item = (&'a Protocol, &'a Option<Feasibility>)>,
+) -> Result<(), String> {
+    let reasons: Vec<String> = legs
+        .filter_map(|(protocol, feasibility)| match feasibility {
+            Some(Feasibility {
+                feasible: false,
+                reason,
+            }) => Some(format!(
+                "{}: {}",
+                protocol,
+                reason.as_deref().unwrap_or("infeasible")
+            )),
+            _ => None,
+        })
+        .collect();
+    if reasons.is_empty() {
+        return Ok(());
+    }
+    Err(reasons.join("; "))
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
 pub struct DepositToExecute {
     pub protocol: Protocol,
     pub amount: u64,
-    pub allocation_basis_points: u64,
+    pub allocation_basis_points: Bps,
+    /// `None` until checked with [`TransactionSystemDeposits::check_feasibility`]
+    pub feasibility: Option<Feasibility>,
 }
 
 impl Display for DepositToExecute {
@@ -239,350 +730,1201 @@ impl Display for DepositToExecute {
             "{} | {} | {} allocation",
             self.protocol,
             format_amount(self.amount),
-            format_basis_points(self.allocation_basis_points)
-        )
+            self.allocation_basis_points
+        )?;
+        if let Some(Feasibility {
+            feasible: false,
+            reason,
+        }) = &self.feasibility
+        {
+            write!(f, " | ❌ {}", reason.as_deref().unwrap_or("infeasible"))?;
+        }
+        Ok(())
     }
 }
 
-impl<R: RiskWeightModel> RebalanceSystem<R> for RebalancingSystem<R> {
-    /// Deposit funds into a risk profile
-    fn deposit(
-        &mut self,
-        portfolio: &mut UserPortfolio,
-        profile: RiskProfile,
-        amount: u64,
-    ) -> Result<TransactionSystemDeposits, String> {
-        let weights = self.risk_model.get_recommended_weights(&profile);
-
-        // Create or update profile allocation
-        let profile_allocation = portfolio
-            .risk_profiles
-            .entry(profile.clone())
-            .or_insert_with(|| ProfileAllocation {
-                risk_profile: profile.clone(),
-                pool_allocations: HashMap::new(),
-                total_amount: 0,
-            });
+/// Response from the transaction system API containing withdrawals that need to be executed
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct TransactionSystemWithdrawals {
+    /// Asset withdrawn, amounts are in its native units
+    pub asset: Asset,
+    /// List of withdrawals that need to be processed by the transaction system
+    pub withdrawals_to_execute: Vec<WithdrawalToExecute>,
+    /// Share of the profile's holdings withdrawn
+    pub proportion_basis_points: Bps,
+}
+impl Display for TransactionSystemWithdrawals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━"
+        )?;
+        writeln!(
+            f,
+            "💸 WITHDRAWALS TO EXECUTE | {} | {} of total holdings",
+            self.asset, self.proportion_basis_points
+        )?;
+        for withdrawal in &self.withdrawals_to_execute {
+            writeln!(f, "{}", withdrawal)?;
+        }
+        writeln!(
+            f,
+            "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━"
+        )?;
+        Ok(())
+    }
+}
 
-        // Add amount to total
-        profile_allocation.total_amount = profile_allocation.total_amount.saturating_add(amount);
+impl TransactionSystemWithdrawals {
+    /// Flags every withdrawal whose fees the signer's SOL can't cover
+    pub fn check_feasibility(&mut self, balances: &SignerBalances, fee_lamports_per_leg: u64) {
+        let legs: Vec<PlanLeg> = self
+            .withdrawals_to_execute
+            .iter()
+            .map(|withdrawal| PlanLeg::Withdraw(withdrawal.amount))
+            .collect();
+        for (withdrawal, feasibility) in self.withdrawals_to_execute.iter_mut().zip(check_legs(
+            balances,
+            &legs,
+            fee_lamports_per_leg,
+        )) {
+            withdrawal.feasibility = Some(feasibility);
+        }
+    }
 
-        // Allocate funds according to weights and prepare deposits
-        let mut deposits_to_execute = Vec::new();
-        for (pool_id, basis_points) in weights {
-            // Calculate allocation amount (scaled to maintain precision)
-            let allocation_amount = (amount as u128)
-                .saturating_mul(basis_points as u128)
-                .saturating_div(10_000) as u64;
+    /// Fails with the reasons of the withdrawals flagged infeasible, if any
+    pub fn ensure_feasible(&self) -> Result<(), String> {
+        ensure_feasible(
+            self.withdrawals_to_execute
+                .iter()
+                .map(|withdrawal| (&withdrawal.protocol, &withdrawal.feasibility)),
+        )
+    }
+}
 
-            // Update pool allocation
-            *profile_allocation
-                .pool_allocations
-                .entry(pool_id.clone())
-                .or_insert(0) = profile_allocation
-                .pool_allocations
-                .get(&pool_id)
-                .unwrap_or(&0)
-                .saturating_add(allocation_amount);
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct WithdrawalToExecute {
+    pub protocol: Protocol,
+    pub amount: u64,
+    /// Share of the withdrawal pulled from this protocol
+    pub allocation_basis_points: Bps,
+    /// What the profile keeps in the protocol afterwards
+    pub remaining: u64,
+    /// `None` until checked with [`TransactionSystemWithdrawals::check_feasibility`]
+    pub feasibility: Option<Feasibility>,
+}
 
-            deposits_to_execute.push(DepositToExecute {
-                protocol: pool_id,
-                amount: allocation_amount,
-                allocation_basis_points: basis_points,
-            });
+impl Display for WithdrawalToExecute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} | {} | {} allocation | {} remaining",
+            self.protocol,
+            format_amount(self.amount),
+            self.allocation_basis_points,
+            format_amount(self.remaining)
+        )?;
+        if let Some(Feasibility {
+            feasible: false,
+            reason,
+        }) = &self.feasibility
+        {
+            write!(f, " | ❌ {}", reason.as_deref().unwrap_or("infeasible"))?;
         }
+        Ok(())
+    }
+}
 
-        Ok(TransactionSystemDeposits {
-            deposits_to_execute,
-        })
+/// A move of funds between two protocols within a profile
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PoolTransfer {
+    pub from: Protocol,
+    pub to: Protocol,
+    pub amount: u64,
+}
+
+impl Display for PoolTransfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ➡️ {} | Amount: {}",
+            self.from,
+            self.to,
+            format_amount(self.amount)
+        )
     }
+}
 
-    /// Check if rebalancing is needed for a portfolio
-    fn should_rebalance(&self, portfolio: &UserPortfolio) -> bool {
-        let time_since_last = SystemTime::now()
-            .duration_since(portfolio.last_rebalance)
-            .unwrap_or(Duration::from_secs(0));
+/// Cost of moving funds in or out of a protocol
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferCost {
+    /// Flat cost per transfer, in native units of the transferred asset
+    pub fixed: u64,
+    /// Share of the amount lost to fees and slippage
+    pub slippage: Bps,
+}
 
-        let should_rebalance = time_since_last >= self.rebalance_interval;
+/// Fees and slippage of rebalancing transfers, and what a transfer is worth
+///
+/// The default model has no costs, so no transfer is skipped for its cost.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransferCostModel {
+    /// Costs per asset and protocol, those without an entry are free to move
+    /// in and out of
+    pub costs: HashMap<Asset, HashMap<Protocol, TransferCost>>,
+    /// Share of a transfer gained by holding it at the target weight until the
+    /// next rebalance
+    pub expected_benefit: Bps,
+}
 
-        should_rebalance
+impl TransferCostModel {
+    /// Reads `REBALANCE_COSTS` and `REBALANCE_BENEFIT_BPS`, no costs if not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let Ok(costs) = std::env::var("REBALANCE_COSTS") else {
+            return Ok(Self::default());
+        };
+        let expected_benefit = std::env::var("REBALANCE_BENEFIT_BPS").map_err(|_| {
+            RiskCalculationError::ParseError(
+                "REBALANCE_BENEFIT_BPS must be set with REBALANCE_COSTS".to_string(),
+            )
+        })?;
+        let expected_benefit = expected_benefit.trim().parse().map_err(|e| {
+            RiskCalculationError::ParseError(format!("REBALANCE_BENEFIT_BPS: {}", e))
+        })?;
+        Self::parse(&costs, Bps(expected_benefit))
     }
 
-    /// Rebalance a user's portfolio
-    fn rebalance(&mut self, portfolio: &mut UserPortfolio) -> Result<(), String> {
-        println!(
-            "\n━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━"
-        );
-        println!("🔄 REBALANCE | Starting portfolio rebalance");
-
-        for (profile, allocation) in &mut portfolio.risk_profiles {
-            println!(
-                "\n📊 REBALANCING PROFILE | {} | Total: {}",
-                profile,
-                format_amount(allocation.total_amount)
-            );
-            self.rebalance_profile(profile, allocation)?;
+    /// Parses comma separated `protocol:asset:fixed:slippage_bps` entries, the
+    /// fixed cost in native units of the asset, e.g.
+    /// `kamino:usdc:0:5,marginfi:usdc:20000:10,marginfi:sol:100000:10`
+    pub fn parse(costs: &str, expected_benefit: Bps) -> Result<Self, RiskCalculationError> {
+        let mut model = TransferCostModel {
+            costs: HashMap::new(),
+            expected_benefit,
+        };
+        for entry in costs.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let invalid = |reason: String| {
+                RiskCalculationError::ParseError(format!(
+                    "REBALANCE_COSTS entry {:?}: {}",
+                    entry, reason
+                ))
+            };
+            let [protocol, asset, fixed, slippage] = entry.split(':').collect::<Vec<_>>()[..]
+            else {
+                return Err(invalid(
+                    "expected protocol:asset:fixed:slippage_bps".to_string(),
+                ));
+            };
+            let cost = TransferCost {
+                fixed: fixed
+                    .trim()
+                    .parse()
+                    .map_err(|e| invalid(format!("{}", e)))?,
+                slippage: Bps(slippage
+                    .trim()
+                    .parse()
+                    .map_err(|e| invalid(format!("{}", e)))?),
+            };
+            model
+                .costs
+                .entry(Asset::from_param(asset.trim())?)
+                .or_default()
+                .insert(Protocol::from_param(protocol)?, cost);
         }
+        Ok(model)
+    }
 
-        // Update last rebalance time
-        portfolio.last_rebalance = SystemTime::now();
-        println!(
-            "\n✅ REBALANCE COMPLETE | New rebalance time: {:?}",
-            portfolio.last_rebalance
-        );
-        println!(
-            "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━\n"
-        );
-
-        Ok(())
+    /// Cost of moving `amount` of `asset` in or out of `protocol`
+    pub fn leg_cost(&self, protocol: &Protocol, asset: Asset, amount: u64) -> u64 {
+        let cost = self
+            .costs
+            .get(&asset)
+            .and_then(|costs| costs.get(protocol))
+            .copied()
+            .unwrap_or_default();
+        cost.fixed
+            .saturating_add(cost.slippage.apply_to(amount).unwrap_or(u64::MAX))
     }
 
-    /// Rebalance a specific risk profile
-    fn rebalance_profile(
-        &mut self,
-        profile: &RiskProfile,
-        allocation: &mut ProfileAllocation,
-    ) -> Result<(), String> {
-        // Get recommended weights from risk model (in basis points)
-        let target_weights = self.risk_model.get_recommended_weights(profile);
+    /// Cost of withdrawing a transfer of `asset` from its source and depositing
+    /// it into its destination
+    pub fn cost(&self, asset: Asset, transfer: &PoolTransfer) -> u64 {
+        self.leg_cost(&transfer.from, asset, transfer.amount)
+            .saturating_add(self.leg_cost(&transfer.to, asset, transfer.amount))
+    }
 
-        // Calculate target amounts
-        let mut target_amounts = HashMap::new();
-        let mut current_amounts = HashMap::new();
+    pub fn benefit(&self, transfer: &PoolTransfer) -> u64 {
+        self.expected_benefit
+            .apply_to(transfer.amount)
+            .unwrap_or(u64::MAX)
+    }
+}
 
-        for (pool_id, basis_points) in &target_weights {
-            // Calculate target amount (scaled to maintain precision)
-            let target_amount = (allocation.total_amount as u128)
-                .saturating_mul(*basis_points as u128)
-                .saturating_div(10_000) as u64;
+/// Most a profile may hold in a single protocol
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExposureLimit {
+    /// In native units of the asset the limit is set for
+    pub max_amount: Option<u64>,
+    /// Share of the profile
+    pub max_share: Option<Bps>,
+}
 
-            target_amounts.insert(pool_id.clone(), target_amount);
+/// Exposure limits per asset and protocol, those without an entry are unlimited
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExposureLimits {
+    pub limits: HashMap<Asset, HashMap<Protocol, ExposureLimit>>,
+}
 
-            // Store current amount
-            let current_amount = *allocation.pool_allocations.get(pool_id).unwrap_or(&0);
-            current_amounts.insert(pool_id.clone(), current_amount);
+impl ExposureLimits {
+    /// Reads `EXPOSURE_LIMITS`, no limits if not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        match std::env::var("EXPOSURE_LIMITS") {
+            Ok(limits) => Self::parse(&limits),
+            Err(_) => Ok(Self::default()),
         }
+    }
 
-        // Calculate deltas between current and target allocations
-        let mut deltas = HashMap::new();
-        for (pool_id, target_amount) in &target_amounts {
-            let current_amount = *current_amounts.get(pool_id).unwrap_or(&0);
-
-            // Calculate delta (can be negative)
-            let delta = match target_amount.checked_sub(current_amount) {
-                Some(positive_delta) => positive_delta as i64,
-                None => -(current_amount as i64 - *target_amount as i64),
+    /// Parses comma separated `protocol:asset:max_amount:max_share_bps` entries,
+    /// either limit may be left empty, e.g.
+    /// `drift:usdc:5000000000:3000,drift:sol:50000000000:3000,marginfi:usdc::2500`
+    pub fn parse(limits: &str) -> Result<Self, RiskCalculationError> {
+        let mut parsed = ExposureLimits::default();
+        for entry in limits.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let invalid = |reason: String| {
+                RiskCalculationError::ParseError(format!(
+                    "EXPOSURE_LIMITS entry {:?}: {}",
+                    entry, reason
+                ))
             };
-
-            deltas.insert(pool_id.clone(), delta);
+            let [protocol, asset, max_amount, max_share] = entry.split(':').collect::<Vec<_>>()[..]
+            else {
+                return Err(invalid(
+                    "expected protocol:asset:max_amount:max_share_bps".to_string(),
+                ));
+            };
+            let optional = |value: &str| -> Result<Option<u64>, RiskCalculationError> {
+                match value.trim() {
+                    "" => Ok(None),
+                    value => value
+                        .parse()
+                        .map(Some)
+                        .map_err(|e| invalid(format!("{}", e))),
+                }
+            };
+            let limit = ExposureLimit {
+                max_amount: optional(max_amount)?,
+                max_share: optional(max_share)?.map(Bps),
+            };
+            if limit.max_share.is_some_and(|share| share > Bps::FULL) {
+                return Err(invalid("max_share_bps exceeds 10000".to_string()));
+            }
+            parsed
+                .limits
+                .entry(Asset::from_param(asset.trim())?)
+                .or_default()
+                .insert(Protocol::from_param(protocol)?, limit);
         }
+        Ok(parsed)
+    }
 
-        // Execute transfers to rebalance
-        let mut positive_deltas: Vec<_> = deltas.iter().filter(|(_, delta)| **delta > 0).collect();
-        let mut negative_deltas: Vec<_> = deltas.iter().filter(|(_, delta)| **delta < 0).collect();
-
-        // Sort by absolute delta value
-        positive_deltas.sort_by(|a, b| b.1.cmp(a.1));
-        negative_deltas.sort_by(|a, b| a.1.cmp(b.1)); // Most negative first
-
-        let mut transfers = Vec::new();
+    /// Largest amount a profile of `total_amount` of `asset` may hold in `protocol`
+    pub fn max_amount(&self, protocol: &Protocol, asset: Asset, total_amount: u64) -> u64 {
+        let Some(limit) = self
+            .limits
+            .get(&asset)
+            .and_then(|limits| limits.get(protocol))
+        else {
+            return u64::MAX;
+        };
+        let share = limit
+            .max_share
+            .map_or(u64::MAX, |share| share.apply_to(total_amount).unwrap_or(0));
+        share.min(limit.max_amount.unwrap_or(u64::MAX))
+    }
 
-        // Execute transfers
-        for (to_pool, positive_delta) in positive_deltas {
-            let mut remaining_delta = *positive_delta;
+    /// Limits of a profile of `total_amount` of `asset`, in basis points
+    pub fn weight_caps(&self, asset: Asset, total_amount: u64) -> HashMap<Protocol, u64> {
+        let Some(limits) = self.limits.get(&asset) else {
+            return HashMap::new();
+        };
+        limits
+            .iter()
+            .map(|(protocol, limit)| {
+                let amount_cap = limit.max_amount.map_or(Bps::FULL, |max_amount| {
+                    Bps::from_parts(max_amount, total_amount)
+                        .unwrap_or(Bps::FULL)
+                        .min(Bps::FULL)
+                });
+                let cap = limit.max_share.unwrap_or(Bps::FULL).min(amount_cap);
+                (protocol.clone(), cap.0)
+            })
+            .collect()
+    }
+}
 
-            for (from_pool, negative_delta) in negative_deltas.clone() {
-                if remaining_delta <= 0 || *negative_delta >= 0 {
-                    continue;
-                }
+/// An exposure limit that held a protocol below its recommended weight
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct AppliedCap {
+    pub protocol: Protocol,
+    pub recommended: Bps,
+    /// The excess went to the other protocols
+    pub cap: Bps,
+    /// Most the protocol may hold, in native units
+    pub max_amount: u64,
+}
 
-                let transfer_amount =
-                    std::cmp::min(remaining_delta as u64, negative_delta.abs() as u64);
+impl Display for AppliedCap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} held at {} instead of {}, at most {}",
+            self.protocol,
+            self.cap,
+            self.recommended,
+            format_amount(self.max_amount)
+        )
+    }
+}
 
-                if transfer_amount > 0 {
-                    transfers.push((from_pool.clone(), to_pool.clone(), transfer_amount));
+/// Reads `REBALANCE_MIN_TRANSFER`, comma separated `asset:amount` entries in
+/// native units of each asset, e.g. `usdc:1000000,sol:10000000`, no minimum
+/// for assets without an entry
+pub fn min_transfer_amounts() -> Result<HashMap<Asset, u64>, RiskCalculationError> {
+    match std::env::var("REBALANCE_MIN_TRANSFER") {
+        Ok(amounts) => parse_min_transfer_amounts(&amounts),
+        Err(_) => Ok(HashMap::new()),
+    }
+}
 
-                    // Update allocations
-                    *allocation
-                        .pool_allocations
-                        .entry(to_pool.clone())
-                        .or_insert(0) = allocation
-                        .pool_allocations
-                        .get(to_pool)
-                        .unwrap_or(&0)
-                        .saturating_add(transfer_amount);
+fn parse_min_transfer_amounts(amounts: &str) -> Result<HashMap<Asset, u64>, RiskCalculationError> {
+    amounts
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let invalid = |reason: String| {
+                RiskCalculationError::ParseError(format!(
+                    "REBALANCE_MIN_TRANSFER entry {:?}: {}",
+                    entry, reason
+                ))
+            };
+            let [asset, amount] = entry.split(':').collect::<Vec<_>>()[..] else {
+                return Err(invalid("expected asset:amount".to_string()));
+            };
+            Ok((
+                Asset::from_param(asset.trim())?,
+                amount
+                    .trim()
+                    .parse()
+                    .map_err(|e| invalid(format!("{}", e)))?,
+            ))
+        })
+        .collect()
+}
 
-                    *allocation
-                        .pool_allocations
-                        .entry(from_pool.clone())
-                        .or_insert(0) = allocation
-                        .pool_allocations
-                        .get(from_pool)
-                        .unwrap_or(&0)
-                        .saturating_sub(transfer_amount);
+/// Why a transfer of the plan isn't executed
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum SkipReason {
+    BelowMinimum { min_transfer_amount: u64 },
+    CostExceedsBenefit { cost: u64, benefit: u64 },
+}
 
-                    // Update remaining delta
-                    remaining_delta = remaining_delta.saturating_sub(transfer_amount as i64);
-                }
+impl Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::BelowMinimum {
+                min_transfer_amount,
+            } => write!(
+                f,
+                "below the minimum of {}",
+                format_amount(*min_transfer_amount)
+            ),
+            SkipReason::CostExceedsBenefit { cost, benefit } => write!(
+                f,
+                "costs {} for a benefit of {}",
+                format_amount(*cost),
+                format_amount(*benefit)
+            ),
+        }
+    }
+}
 
-                if remaining_delta <= 0 {
-                    break;
+/// A transfer left out of the plan, its funds stay where they are
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct SkippedTransfer {
+    #[serde(flatten)]
+    pub transfer: PoolTransfer,
+    #[serde(flatten)]
+    pub reason: SkipReason,
+}
+
+/// Where a protocol's allocation stands against its target
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct PoolDelta {
+    pub protocol: Protocol,
+    pub current: u64,
+    pub target: u64,
+    /// `target - current`, negative when funds leave the protocol
+    pub delta: i64,
+}
+
+impl Display for PoolDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let change_symbol = match self.delta.signum() {
+            1 => "+",
+            -1 => "-",
+            _ => " ",
+        };
+        let abs_delta = self.delta.unsigned_abs();
+        // 100% change if no current amount
+        let change_bps = Bps::from_parts(abs_delta, self.current).unwrap_or(Bps::FULL);
+        write!(
+            f,
+            "{} | {:12} | {:12} | {}{} ({})",
+            self.protocol,
+            format_amount(self.current),
+            format_amount(self.target),
+            change_symbol,
+            format_amount(abs_delta),
+            change_bps
+        )
+    }
+}
+
+/// Transfers bringing one profile to its target weights
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ProfileRebalance {
+    pub profile: RiskProfile,
+    /// Asset the profile holds, transfers are in its native units
+    pub asset: Asset,
+    pub target_weights: Vec<(Protocol, Bps)>,
+    /// One entry per protocol the profile holds or targets
+    pub deltas: Vec<PoolDelta>,
+    pub transfers: Vec<PoolTransfer>,
+    /// Transfers too small or too costly to execute
+    pub skipped: Vec<SkippedTransfer>,
+    /// Set when the weights were renormalized around unavailable protocols
+    pub redistribution_note: Option<String>,
+    /// Exposure limits that held protocols below their recommended weight
+    pub applied_caps: Vec<AppliedCap>,
+}
+
+impl Display for ProfileRebalance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "🔄 REBALANCE OPERATION | {} | {}",
+            self.profile, self.asset
+        )?;
+
+        writeln!(f, "\n📈 TARGET WEIGHTS")?;
+        for (protocol, weight) in &self.target_weights {
+            writeln!(f, "    {}: {}", protocol, weight)?;
+        }
+        if let Some(note) = &self.redistribution_note {
+            writeln!(f, "⚠️ REDISTRIBUTION | {}", note)?;
+        }
+        for cap in &self.applied_caps {
+            writeln!(f, "🧢 EXPOSURE CAP | {}", cap)?;
+        }
+
+        writeln!(f, "\n📊 ALLOCATION CHANGES")?;
+        writeln!(f, "Protocol   | Current       | Target        | Change")?;
+        writeln!(
+            f,
+            "-----------+---------------+---------------+---------------"
+        )?;
+        for delta in &self.deltas {
+            writeln!(f, "{}", delta)?;
+        }
+
+        if self.transfers.is_empty() {
+            writeln!(f, "\n✅ NO TRANSFERS NEEDED")?;
+        } else {
+            writeln!(f, "\n🔄 TRANSFERS")?;
+            for transfer in &self.transfers {
+                writeln!(f, "    {}", transfer)?;
+            }
+        }
+        if !self.skipped.is_empty() {
+            writeln!(f, "\n⏭️ SKIPPED TRANSFERS")?;
+            for SkippedTransfer { transfer, reason } in &self.skipped {
+                writeln!(f, "    {} | {}", transfer, reason)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Transfers the transaction system needs to execute to rebalance a portfolio
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct RebalancePlan {
+    pub profiles: Vec<ProfileRebalance>,
+}
+
+impl Display for RebalancePlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━"
+        )?;
+        for profile in &self.profiles {
+            writeln!(f, "{}", profile)?;
+        }
+        writeln!(
+            f,
+            "━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━"
+        )
+    }
+}
+
+impl<R: RiskWeightModel> RebalanceSystem<R> for RebalancingSystem<R> {
+    /// Deposit funds of an asset into a risk profile
+    fn deposit(
+        &mut self,
+        portfolio: &mut UserPortfolio,
+        asset: Asset,
+        profile: RiskProfile,
+        amount: u64,
+    ) -> Result<TransactionSystemDeposits, String> {
+        let total_after = portfolio
+            .allocation(asset, &profile)
+            .map_or(0, |allocation| allocation.total_amount)
+            .saturating_add(amount);
+        let (RenormalizedWeights { weights, note }, mut applied_caps) =
+            self.limited_weights(&profile, asset, total_after)?;
+
+        // Holdings above a protocol's limit spill to the next protocols with room
+        let held = portfolio.allocation(asset, &profile);
+        let room = |pool_id: &Protocol| {
+            let current = held.and_then(|allocation| allocation.pool_allocations.get(pool_id));
+            self.exposure_limits
+                .max_amount(pool_id, asset, total_after)
+                .saturating_sub(*current.unwrap_or(&0))
+        };
+        let mut parts = split_proportionally(amount, &weights);
+        let mut excess = 0u64;
+        for (pool_id, part) in &mut parts {
+            let room = room(pool_id);
+            if *part > room {
+                excess += *part - room;
+                *part = room;
+                if !applied_caps.iter().any(|cap| cap.protocol == *pool_id) {
+                    let cap = self
+                        .exposure_limits
+                        .weight_caps(asset, total_after)
+                        .get(pool_id)
+                        .map_or(Bps::FULL, |cap| Bps(*cap));
+                    applied_caps.push(AppliedCap {
+                        protocol: pool_id.clone(),
+                        recommended: weights
+                            .iter()
+                            .find(|(id, _)| id == pool_id)
+                            .map_or(Bps::ZERO, |(_, weight)| Bps(*weight)),
+                        cap,
+                        max_amount: self.exposure_limits.max_amount(pool_id, asset, total_after),
+                    });
                 }
             }
         }
+        for (pool_id, part) in &mut parts {
+            let spilled = excess.min(room(pool_id).saturating_sub(*part));
+            *part += spilled;
+            excess -= spilled;
+        }
+        if excess > 0 {
+            return Err(format!(
+                "Exposure limits leave {} of the deposit unallocated",
+                format_amount(excess)
+            ));
+        }
+
+        // Create or update profile allocation
+        let profile_allocation = portfolio
+            .assets
+            .entry(asset)
+            .or_default()
+            .entry(profile.clone())
+            .or_insert_with(|| ProfileAllocation::empty(profile.clone(), asset));
+
+        // Add amount to total
+        profile_allocation.total_amount = profile_allocation.total_amount.saturating_add(amount);
+
+        // Allocate funds according to weights and prepare deposits
+        let mut deposits_to_execute = Vec::new();
+        for ((pool_id, basis_points), (_, allocation_amount)) in weights.iter().zip(parts) {
+            // Update pool allocation
+            let pool_amount = profile_allocation
+                .pool_allocations
+                .entry(pool_id.clone())
+                .or_insert(0);
+            *pool_amount = pool_amount.saturating_add(allocation_amount);
+
+            deposits_to_execute.push(DepositToExecute {
+                protocol: pool_id.clone(),
+                amount: allocation_amount,
+                allocation_basis_points: Bps(*basis_points),
+                feasibility: None,
+            });
+        }
+
+        for deposit in &deposits_to_execute {
+            tracing::info!(
+                wallet = %portfolio.user_wallet,
+                asset = %asset,
+                profile = %profile,
+                protocol = %deposit.protocol,
+                amount = deposit.amount,
+                amount_bps = deposit.allocation_basis_points.0,
+                "Deposit allocated"
+            );
+        }
+        Ok(TransactionSystemDeposits {
+            asset,
+            deposits_to_execute,
+            redistribution_note: note,
+            applied_caps,
+        })
+    }
+
+    /// Check if rebalancing is needed for a portfolio
+    fn should_rebalance(&self, portfolio: &UserPortfolio) -> bool {
+        let time_since_last = SystemTime::from(self.clock.now())
+            .duration_since(portfolio.last_rebalance)
+            .unwrap_or(Duration::from_secs(0));
 
-        println!("🔄 REBALANCE OPERATION | {}", profile);
+        let time_due = time_since_last >= self.rebalance_interval;
+        let drift_due = || {
+            portfolio.allocations().any(|allocation| {
+                let profile = &allocation.risk_profile;
+                match self.risk_model.get_available_weights(
+                    profile,
+                    allocation.asset,
+                    allocation.total_amount,
+                ) {
+                    Ok(target) => {
+                        allocation_drift(allocation, &target.weights) > self.drift_threshold
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            wallet = %portfolio.user_wallet,
+                            profile = %profile,
+                            error = %e,
+                            "Drift check failed"
+                        );
+                        false
+                    }
+                }
+            })
+        };
 
-        // Display target weights
-        println!("\n📈 TARGET WEIGHTS");
-        for (protocol, weight) in target_weights {
-            println!("    {}: {}", protocol, format_basis_points(weight));
+        match self.trigger {
+            RebalanceTrigger::Time => time_due,
+            RebalanceTrigger::Drift => drift_due(),
+            RebalanceTrigger::Both => time_due || drift_due(),
         }
+    }
+
+    /// Rebalance a user's portfolio
+    fn rebalance(&mut self, portfolio: &mut UserPortfolio) -> Result<RebalancePlan, String> {
+        // Events of the profiles carry the wallet
+        let _span = tracing::info_span!("rebalance", wallet = %portfolio.user_wallet).entered();
+
+        let mut profiles = Vec::new();
+        // Every asset is rebalanced on its own, funds never move between assets
+        for (asset, asset_profiles) in &mut portfolio.assets {
+            for (profile, allocation) in asset_profiles {
+                tracing::debug!(
+                    asset = %asset,
+                    profile = %profile,
+                    total_amount = allocation.total_amount,
+                    "Rebalancing profile"
+                );
+                profiles.push(self.rebalance_profile(profile, allocation)?);
+            }
+        }
+
+        // Update last rebalance time
+        portfolio.last_rebalance = self.clock.now().into();
+        tracing::info!(
+            profiles = profiles.len(),
+            transfers = profiles
+                .iter()
+                .map(|profile| profile.transfers.len())
+                .sum::<usize>(),
+            "Portfolio rebalanced"
+        );
 
-        // Display allocation changes
-        println!("\n📊 ALLOCATION CHANGES");
-        println!("Protocol   | Current       | Target        | Change");
-        println!("-----------+---------------+---------------+---------------");
+        Ok(RebalancePlan { profiles })
+    }
+
+    /// Rebalance a specific risk profile
+    fn rebalance_profile(
+        &mut self,
+        profile: &RiskProfile,
+        allocation: &mut ProfileAllocation,
+    ) -> Result<ProfileRebalance, String> {
+        // Get recommended weights from risk model (in basis points)
+        let (
+            RenormalizedWeights {
+                weights: target_weights,
+                note,
+            },
+            applied_caps,
+        ) = self.limited_weights(profile, allocation.asset, allocation.total_amount)?;
+
+        // Calculate target amounts
+        let mut target_amounts = HashMap::new();
+        let mut current_amounts = HashMap::new();
+
+        for (pool_id, basis_points) in &target_weights {
+            let target_amount = Bps(*basis_points)
+                .apply_to(allocation.total_amount)
+                .ok_or(format!("Weight of {} exceeds 100%", pool_id))?;
+
+            target_amounts.insert(pool_id.clone(), target_amount);
 
+            // Store current amount
+            let current_amount = *allocation.pool_allocations.get(pool_id).unwrap_or(&0);
+            current_amounts.insert(pool_id.clone(), current_amount);
+        }
+        // Pools that are no longer targeted are emptied
+        for (pool_id, current_amount) in &allocation.pool_allocations {
+            if !target_amounts.contains_key(pool_id) {
+                target_amounts.insert(pool_id.clone(), 0);
+                current_amounts.insert(pool_id.clone(), *current_amount);
+            }
+        }
+
+        // Calculate deltas between current and target allocations
+        let mut deltas = HashMap::new();
         for (pool_id, target_amount) in &target_amounts {
             let current_amount = *current_amounts.get(pool_id).unwrap_or(&0);
-            let delta = if let Some(d) = deltas.get(pool_id) {
-                *d
-            } else {
-                0
-            };
 
-            // Format for display
-            let change_symbol = if delta > 0 {
-                "+"
-            } else if delta < 0 {
-                "-"
-            } else {
-                " "
-            };
-            let abs_delta = delta.abs() as u64;
-
-            // Calculate change percentage in basis points
-            let change_bps = if current_amount > 0 {
-                ((abs_delta as u128)
-                    .saturating_mul(10_000)
-                    .saturating_div(current_amount as u128)) as u64
-            } else {
-                10_000 // 100% change if no current amount
+            // Calculate delta (can be negative)
+            let delta = match target_amount.checked_sub(current_amount) {
+                Some(positive_delta) => positive_delta as i64,
+                None => -(current_amount as i64 - *target_amount as i64),
             };
 
-            println!(
-                "{} | {:12} | {:12} | {}{} ({})",
-                pool_id,
-                format_amount(current_amount),
-                format_amount(*target_amount),
-                change_symbol,
-                format_amount(abs_delta),
-                format_basis_points(change_bps)
-            );
+            deltas.insert(pool_id.clone(), delta);
         }
 
-        // Display transfers
-        if !transfers.is_empty() {
-            println!("\n🔄 TRANSFERS");
-            for (from_pool, to_pool, amount) in &transfers {
-                println!(
-                    "    {} ➡️ {} | Amount: {}",
-                    from_pool,
-                    to_pool,
-                    format_amount(*amount)
-                );
+        // Execute transfers to rebalance
+        let mut positive_deltas: Vec<_> = deltas.iter().filter(|(_, delta)| **delta > 0).collect();
+        // What each source pool has left to give, reduced by every transfer it funds
+        let mut sources: Vec<(&Protocol, u64)> = deltas
+            .iter()
+            .filter(|(_, delta)| **delta < 0)
+            .map(|(pool_id, delta)| (pool_id, delta.unsigned_abs()))
+            .collect();
+
+        // Sort by absolute delta value
+        positive_deltas.sort_by(|a, b| b.1.cmp(a.1));
+        sources.sort_by_key(|&(_, available)| std::cmp::Reverse(available)); // Most to give first
+
+        let mut transfers = Vec::new();
+        let mut skipped = Vec::new();
+
+        // Execute transfers
+        for (to_pool, positive_delta) in positive_deltas {
+            let mut remaining_delta = *positive_delta as u64;
+
+            for (from_pool, available) in sources.iter_mut() {
+                if remaining_delta == 0 {
+                    break;
+                }
+                // Exhausted by earlier transfers
+                if *available == 0 {
+                    continue;
+                }
+
+                let transfer_amount = std::cmp::min(remaining_delta, *available);
+                let transfer = PoolTransfer {
+                    from: (*from_pool).clone(),
+                    to: to_pool.clone(),
+                    amount: transfer_amount,
+                };
+                if let Some(reason) = self.skip_reason(allocation.asset, &transfer) {
+                    skipped.push(SkippedTransfer { transfer, reason });
+                    continue;
+                }
+                transfers.push(transfer);
+
+                // Update allocations
+                *allocation
+                    .pool_allocations
+                    .entry(to_pool.clone())
+                    .or_insert(0) += transfer_amount;
+                if let Some(from_amount) = allocation.pool_allocations.get_mut(*from_pool) {
+                    *from_amount = from_amount.saturating_sub(transfer_amount);
+                }
+
+                *available -= transfer_amount;
+                remaining_delta -= transfer_amount;
             }
-        } else {
-            println!("\n✅ NO TRANSFERS NEEDED");
         }
 
-        Ok(())
+        // Report every pool, targeted ones first
+        let pool_deltas: Vec<PoolDelta> = target_weights
+            .iter()
+            .map(|(pool_id, _)| pool_id)
+            .chain(
+                current_amounts
+                    .keys()
+                    .filter(|pool_id| !target_weights.iter().any(|(id, _)| id == *pool_id)),
+            )
+            .map(|pool_id| PoolDelta {
+                protocol: pool_id.clone(),
+                current: *current_amounts.get(pool_id).unwrap_or(&0),
+                target: *target_amounts.get(pool_id).unwrap_or(&0),
+                delta: *deltas.get(pool_id).unwrap_or(&0),
+            })
+            .collect();
+
+        let rebalance = ProfileRebalance {
+            profile: profile.clone(),
+            asset: allocation.asset,
+            target_weights: target_weights
+                .into_iter()
+                .map(|(protocol, weight)| (protocol, Bps(weight)))
+                .collect(),
+            deltas: pool_deltas,
+            transfers,
+            skipped,
+            redistribution_note: note,
+            applied_caps,
+        };
+        for (protocol, weight) in &rebalance.target_weights {
+            tracing::debug!(
+                asset = %rebalance.asset,
+                profile = %profile,
+                protocol = %protocol,
+                amount_bps = weight.0,
+                "Target weight"
+            );
+        }
+        for transfer in &rebalance.transfers {
+            tracing::info!(
+                asset = %rebalance.asset,
+                profile = %profile,
+                from = %transfer.from,
+                to = %transfer.to,
+                amount = transfer.amount,
+                "Transfer planned"
+            );
+        }
+        for SkippedTransfer { transfer, reason } in &rebalance.skipped {
+            tracing::debug!(
+                asset = %rebalance.asset,
+                profile = %profile,
+                from = %transfer.from,
+                to = %transfer.to,
+                amount = transfer.amount,
+                reason = %reason,
+                "Transfer skipped"
+            );
+        }
+        if let Some(note) = &rebalance.redistribution_note {
+            tracing::warn!(profile = %profile, note = %note, "Weights redistributed");
+        }
+        for cap in &rebalance.applied_caps {
+            tracing::info!(profile = %profile, cap = %cap, "Exposure cap applied");
+        }
+        Ok(rebalance)
     }
 
-    /// Withdraw funds from a risk profile
+    /// Withdraw funds of an asset from a risk profile
     fn withdraw(
         &mut self,
         portfolio: &mut UserPortfolio,
+        asset: Asset,
         profile: &RiskProfile,
         amount: u64,
-    ) -> Result<(), String> {
-        let profile_allocation = match portfolio.risk_profiles.get_mut(profile) {
+    ) -> Result<TransactionSystemWithdrawals, String> {
+        let profile_allocation = match portfolio
+            .assets
+            .get_mut(&asset)
+            .and_then(|profiles| profiles.get_mut(profile))
+        {
             Some(allocation) => allocation,
-            None => return Err(format!("Risk profile not found in portfolio")),
+            None => return Err("Risk profile not found in portfolio".to_string()),
         };
 
         if amount > profile_allocation.total_amount {
-            println!(
-                "❌ WITHDRAWAL FAILED | Insufficient funds | Requested: {} | Available: {}",
-                format_amount(amount),
-                format_amount(profile_allocation.total_amount)
+            tracing::warn!(
+                wallet = %portfolio.user_wallet,
+                asset = %asset,
+                profile = %profile,
+                amount,
+                available = profile_allocation.total_amount,
+                "Insufficient funds for withdrawal"
             );
-            return Err(format!("Insufficient funds for withdrawal"));
+            return Err("Insufficient funds for withdrawal".to_string());
         }
 
-        // Calculate proportion to withdraw from each pool (in basis points)
-        let proportion_bps = (amount as u128)
-            .saturating_mul(10_000)
-            .saturating_div(profile_allocation.total_amount as u128)
-            as u64;
-
-        let mut withdrawals = Vec::new();
-
-        for (pool_id, pool_amount) in &profile_allocation.pool_allocations {
-            // Calculate withdrawal amount (scaled for precision)
-            let withdrawal_amount = (*pool_amount as u128)
-                .saturating_mul(proportion_bps as u128)
-                .saturating_div(10_000) as u64;
+        let proportion_basis_points =
+            Bps::from_parts(amount, profile_allocation.total_amount).unwrap_or(Bps::ZERO);
+
+        let pool_balances: Vec<(Protocol, u64)> = profile_allocation
+            .pool_allocations
+            .iter()
+            .map(|(pool_id, pool_amount)| (pool_id.clone(), *pool_amount))
+            .collect();
+        let allocated_amount = pool_balances
+            .iter()
+            .map(|(_, balance)| *balance as u128)
+            .sum::<u128>();
+        if amount as u128 > allocated_amount {
+            return Err(format!(
+                "Withdrawal of {} exceeds the {} allocated across pools",
+                amount, allocated_amount
+            ));
+        }
 
-            let remaining = pool_amount.saturating_sub(withdrawal_amount);
-            withdrawals.push((pool_id.clone(), withdrawal_amount, remaining));
+        // Split the withdrawal proportionally to pool balances so the parts sum to exactly `amount`
+        let mut withdrawals_to_execute = Vec::new();
+        for (pool_id, withdrawal_amount) in split_proportionally(amount, &pool_balances) {
+            let pool_amount = profile_allocation.pool_allocations[&pool_id];
+            let remaining = pool_amount
+                .checked_sub(withdrawal_amount)
+                .ok_or(format!("Withdrawal from {} exceeds its balance", pool_id))?;
+            withdrawals_to_execute.push(WithdrawalToExecute {
+                protocol: pool_id,
+                amount: withdrawal_amount,
+                allocation_basis_points: Bps::from_parts(withdrawal_amount, amount)
+                    .unwrap_or(Bps::ZERO),
+                remaining,
+                feasibility: None,
+            });
         }
+        debug_assert_eq!(
+            withdrawals_to_execute
+                .iter()
+                .map(|withdrawal| withdrawal.amount as u128)
+                .sum::<u128>(),
+            amount as u128
+        );
 
         // Execute withdrawals
-        for (pool_id, withdrawal_amount, remaining) in &withdrawals {
+        for withdrawal in &withdrawals_to_execute {
             // Update pool allocation
-            if let Some(pool_amount) = profile_allocation.pool_allocations.get_mut(pool_id) {
-                *pool_amount = *remaining;
+            if let Some(pool_amount) = profile_allocation
+                .pool_allocations
+                .get_mut(&withdrawal.protocol)
+            {
+                *pool_amount = withdrawal.remaining;
             }
         }
 
         // Update total amount
         profile_allocation.total_amount = profile_allocation.total_amount.saturating_sub(amount);
 
-        println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
-        println!(
-            "💸 WITHDRAW | Amount: {} | Risk Profile: {}",
-            format_amount(amount),
-            profile
+        for withdrawal in &withdrawals_to_execute {
+            tracing::info!(
+                wallet = %portfolio.user_wallet,
+                asset = %asset,
+                profile = %profile,
+                protocol = %withdrawal.protocol,
+                amount = withdrawal.amount,
+                amount_bps = withdrawal.allocation_basis_points.0,
+                "Withdrawal allocated"
+            );
+        }
+        tracing::info!(
+            wallet = %portfolio.user_wallet,
+            asset = %asset,
+            profile = %profile,
+            amount,
+            remaining = profile_allocation.total_amount,
+            "Withdrawal complete"
         );
 
-        println!(
-            "\n📊 WITHDRAWAL PROPORTION | {} of total holdings",
-            format_basis_points(proportion_bps)
-        );
+        Ok(TransactionSystemWithdrawals {
+            asset,
+            withdrawals_to_execute,
+            proportion_basis_points,
+        })
+    }
+}
 
-        println!("\n🔄 WITHDRAWING FROM POOLS");
-        println!("    Protocol   | Amount        | Remaining");
-        println!("    -----------|---------------|---------------");
+/// Largest weight a single protocol gets within a profile
+fn profile_weight_cap(profile: &RiskProfile) -> Bps {
+    match profile {
+        RiskProfile::Low => Bps::FULL,
+        RiskProfile::Medium => Bps(7_000),
+        RiskProfile::High => Bps(5_000),
+        RiskProfile::Custom {
+            max_per_protocol_bps,
+            ..
+        } => Bps(*max_per_protocol_bps),
+    }
+}
 
-        for (protocol, amount, remaining) in &withdrawals {
-            println!(
-                "    {} | {:12} | {}",
-                protocol,
-                format_amount(*amount),
-                format_amount(*remaining)
-            );
+/// Holds every protocol of `weights` at the profile's cap
+///
+/// Weights are returned as they are when there are too few protocols for the
+/// cap, e.g. a single scored protocol.
+pub fn cap_profile_weights(
+    profile: &RiskProfile,
+    weights: HashMap<Protocol, u64>,
+) -> HashMap<Protocol, u64> {
+    let cap = profile_weight_cap(profile).0;
+    let caps = weights
+        .keys()
+        .map(|protocol| (protocol.clone(), cap))
+        .collect();
+    match renormalize_weights(&weights, &[], &caps) {
+        Ok(capped) => capped.weights.into_iter().collect(),
+        Err(_) => weights,
+    }
+}
+
+/// Risk model weighting protocols by their latest computed risk scores
+///
+/// Weights are inversely proportional to overall risk over the protocols of a
+/// profile (see [`profile_target_weights`]), with no protocol above the
+/// profile's cap unless there are too few protocols to spread the weight over.
+/// With `WEIGHT_OBJECTIVE=risk_adjusted_yield` the weights of a
+/// [`RiskAdjustedYieldModel`] are recommended instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiveRiskModel {
+    /// Overall risk per protocol, from lowest to highest
+    ranked_risks: Vec<(Protocol, f64)>,
+    unavailable: Vec<Protocol>,
+    /// Weights of the approved proposals, used instead of the scores' weights
+    approved_weights: HashMap<RiskProfile, Vec<(Protocol, u64)>>,
+    risk_adjusted_yield: Option<RiskAdjustedYieldModel>,
+    /// Largest position per protocol for the depth of its reserve, in native
+    /// units of the reserve's asset, no limits for other assets
+    position_limits: HashMap<Asset, HashMap<Protocol, u64>>,
+}
+
+impl LiveRiskModel {
+    /// Fails when no protocol has a score, as no weights could be recommended
+    pub fn from_scores(
+        mut ranked_risks: Vec<(Protocol, f64)>,
+        unavailable: Vec<Protocol>,
+    ) -> Result<Self, RiskCalculationError> {
+        if ranked_risks.is_empty() {
+            return Err(RiskCalculationError::NotReady(
+                "No protocol has a risk score".to_string(),
+            ));
         }
+        ranked_risks.sort_by(|a, b| a.1.total_cmp(&b.1));
+        Ok(LiveRiskModel {
+            ranked_risks,
+            unavailable,
+            approved_weights: HashMap::new(),
+            risk_adjusted_yield: None,
+            position_limits: HashMap::new(),
+        })
+    }
+
+    /// Limits positions in `asset` to `position_limits`, in its native units
+    pub fn with_position_limits(
+        mut self,
+        asset: Asset,
+        position_limits: HashMap<Protocol, u64>,
+    ) -> Self {
+        self.position_limits.insert(asset, position_limits);
+        self
+    }
 
-        println!(
-            "\n💼 PORTFOLIO | Updated total amount: {}",
-            format_amount(profile_allocation.total_amount)
+    /// Recommends `approved_weights` for the profiles they cover
+    pub fn with_approved_weights(
+        mut self,
+        approved_weights: HashMap<RiskProfile, Vec<(Protocol, u64)>>,
+    ) -> Self {
+        self.approved_weights = approved_weights;
+        self
+    }
+
+    pub fn from_snapshot(snapshot: &RiskSnapshot) -> Result<Self, RiskCalculationError> {
+        let mut model = Self::from_scores(
+            snapshot
+                .comparison
+                .ranking
+                .iter()
+                .map(|assessment| {
+                    (
+                        assessment.protocol.clone(),
+                        assessment.risk_metrics.overall_risk.overall_risk,
+                    )
+                })
+                .collect(),
+            snapshot
+                .comparison
+                .unavailable
+                .iter()
+                .map(|unavailable| unavailable.protocol.clone())
+                .collect(),
+        )?;
+        let repayments = RepaymentModel::default();
+        let curves: Vec<_> = snapshot
+            .comparison
+            .ranking
+            .iter()
+            .map(|assessment| LiquidityDepthCurve::from_assessment(assessment, &repayments))
+            .collect();
+        model = model.with_position_limits(
+            COMPARED_ASSET,
+            PositionLimitConfig::global().limits(&curves),
         );
-        println!("✅ WITHDRAWAL COMPLETE");
-        println!("━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━");
+        if WeightObjective::from_env()? == WeightObjective::RiskAdjustedYield {
+            match RiskAdjustedYieldModel::from_snapshot(snapshot) {
+                Ok(risk_adjusted_yield) => model.risk_adjusted_yield = Some(risk_adjusted_yield),
+                Err(e) => tracing::error!(
+                    "Falling back to risk score weights, no risk-adjusted yield: {}",
+                    e
+                ),
+            }
+        }
+        Ok(model)
+    }
 
-        Ok(())
+    /// Weights by `risks` instead of the scores it was built from, e.g. smoothed ones
+    pub fn with_risks(mut self, risks: &HashMap<Protocol, f64>) -> Self {
+        for (protocol, risk) in &mut self.ranked_risks {
+            if let Some(replacement) = risks.get(protocol) {
+                *risk = *replacement;
+            }
+        }
+        self.ranked_risks.sort_by(|a, b| a.1.total_cmp(&b.1));
+        self
+    }
+
+    /// Model of the registry's latest stored snapshot, kept current by the hourly refresh
+    pub async fn load(registry: &ProtocolRegistry) -> Result<Self, RiskCalculationError> {
+        Self::from_snapshot(&registry.cached_snapshot().await?)
+    }
+}
+
+impl RiskWeightModel for LiveRiskModel {
+    fn get_recommended_weights(&self, profile: &RiskProfile) -> HashMap<Protocol, u64> {
+        if let Some(approved) = self.approved_weights.get(profile) {
+            return approved.iter().cloned().collect();
+        }
+        if let Some(model) = &self.risk_adjusted_yield {
+            return model.get_recommended_weights(profile);
+        }
+        cap_profile_weights(
+            profile,
+            profile_target_weights(profile, &self.ranked_risks)
+                .into_iter()
+                .collect(),
+        )
+    }
+
+    fn unavailable_protocols(&self) -> Vec<Protocol> {
+        self.unavailable.clone()
+    }
+
+    fn position_limits(&self, asset: Asset) -> HashMap<Protocol, u64> {
+        self.position_limits
+            .get(&asset)
+            .cloned()
+            .unwrap_or_default()
     }
 }
 
@@ -590,6 +1932,7 @@ impl<R: RiskWeightModel> RebalanceSystem<R> for RebalancingSystem<R> {
 mod tests {
 
     use super::*;
+    use crate::clock::MockClock;
 
     // Mock implementation of RiskWeightModel
     struct MockRiskModel;
@@ -598,7 +1941,7 @@ mod tests {
         fn get_recommended_weights(&self, profile: &RiskProfile) -> HashMap<Protocol, u64> {
             let mut weights = HashMap::new();
             match profile {
-                RiskProfile::Low => {
+                RiskProfile::Low | RiskProfile::Custom { .. } => {
                     weights.insert(Protocol::Kamino, 10000);
                 }
                 RiskProfile::Medium => {
@@ -631,29 +1974,38 @@ mod tests {
 
     #[test]
     fn rebalancing_system_test() {
+        let clock = Arc::new(MockClock::at("2024-05-01T10:30:00Z"));
         let mut rebalancing_system = RebalancingSystem::new(MockRiskModel);
+        rebalancing_system.clock = clock.clone();
         let mut portfolio = UserPortfolio {
             user_wallet: Pubkey::default(),
-            risk_profiles: HashMap::new(),
-            last_rebalance: SystemTime::now(),
+            assets: HashMap::new(),
+            last_rebalance: clock.now().into(),
         };
         println!("{}", portfolio);
         let deposits_to_execute = rebalancing_system
-            .deposit(&mut portfolio, RiskProfile::High, 1_000_000_000)
+            .deposit(
+                &mut portfolio,
+                Asset::Usdc,
+                RiskProfile::High,
+                1_000_000_000,
+            )
             .unwrap();
         println!("{}", deposits_to_execute);
         println!("{}", portfolio);
-
-        std::thread::sleep(Duration::from_secs(10));
-
-        let result = rebalancing_system.rebalance(&mut portfolio).unwrap();
-        println!("{}", portfolio);
-        std::thread::sleep(Duration::from_secs(10));
-        let result = rebalancing_system.rebalance(&mut portfolio).unwrap();
-        println!("{}", portfolio);
-        std::thread::sleep(Duration::from_secs(10));
-        let result = rebalancing_system.rebalance(&mut portfolio).unwrap();
-        println!("{}", portfolio);
+        assert!(!rebalancing_system.should_rebalance(&portfolio));
+
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(59 * 60));
+            assert!(!rebalancing_system.should_rebalance(&portfolio));
+            clock.advance(Duration::from_secs(60));
+            assert!(rebalancing_system.should_rebalance(&portfolio));
+
+            rebalancing_system.rebalance(&mut portfolio).unwrap();
+            println!("{}", portfolio);
+            assert_eq!(portfolio.last_rebalance, SystemTime::from(clock.now()));
+            assert!(!rebalancing_system.should_rebalance(&portfolio));
+        }
     }
     #[test]
     fn test_deposit() {
@@ -662,11 +2014,748 @@ mod tests {
 
     #[test]
     fn test_rebalance() {
-        // We would implement a test for rebalance here
+        let mut rebalancing_system = RebalancingSystem::new(MockRiskModel);
+        let profile = RiskProfile::Low;
+        let mut portfolio = portfolio_with(
+            profile.clone(),
+            &[(Protocol::Drift, 600), (Protocol::Kamino, 400)],
+        );
+        let plan = rebalancing_system.rebalance(&mut portfolio).unwrap();
+        assert_eq!(plan.profiles.len(), 1);
+        assert_eq!(
+            plan.profiles[0].transfers,
+            vec![PoolTransfer {
+                from: Protocol::Drift,
+                to: Protocol::Kamino,
+                amount: 600,
+            }]
+        );
+        assert_eq!(
+            portfolio.assets[&Asset::Usdc][&profile].pool_allocations[&Protocol::Kamino],
+            1_000
+        );
+        assert_eq!(
+            plan.profiles[0].deltas,
+            vec![
+                PoolDelta {
+                    protocol: Protocol::Kamino,
+                    current: 400,
+                    target: 1_000,
+                    delta: 600,
+                },
+                PoolDelta {
+                    protocol: Protocol::Drift,
+                    current: 600,
+                    target: 0,
+                    delta: -600,
+                },
+            ]
+        );
+        assert!(plan.to_string().contains("Amount: 600"));
+
+        let withdrawals = rebalancing_system
+            .withdraw(&mut portfolio, Asset::Usdc, &profile, 250)
+            .unwrap();
+        let withdrawn: u64 = withdrawals
+            .withdrawals_to_execute
+            .iter()
+            .map(|withdrawal| withdrawal.amount)
+            .sum();
+        assert_eq!(withdrawn, 250);
+    }
+
+    #[test]
+    fn test_skipped_transfers() {
+        let mut rebalancing_system = RebalancingSystem::new(MockRiskModel);
+        rebalancing_system.min_transfer_amounts = HashMap::from([(Asset::Usdc, 1_000)]);
+        let mut portfolio = portfolio_with(
+            RiskProfile::Low,
+            &[(Protocol::Drift, 600), (Protocol::Kamino, 400)],
+        );
+        let plan = rebalancing_system.rebalance(&mut portfolio).unwrap();
+        assert!(plan.profiles[0].transfers.is_empty());
+        assert_eq!(
+            plan.profiles[0].skipped[0].reason,
+            SkipReason::BelowMinimum {
+                min_transfer_amount: 1_000
+            }
+        );
+        // Skipped funds stay where they are
+        assert_eq!(
+            portfolio.assets[&Asset::Usdc][&RiskProfile::Low].pool_allocations[&Protocol::Drift],
+            600
+        );
+
+        // 0.3% lost moving out of Drift and into Kamino, for a 0.2% benefit
+        rebalancing_system.min_transfer_amounts = HashMap::new();
+        rebalancing_system.transfer_costs =
+            TransferCostModel::parse("drift:usdc:0:20, kamino:usdc:0:10", Bps(20)).unwrap();
+        let mut portfolio = portfolio_with(
+            RiskProfile::Low,
+            &[(Protocol::Drift, 600_000), (Protocol::Kamino, 400_000)],
+        );
+        let plan = rebalancing_system.rebalance(&mut portfolio).unwrap();
+        assert_eq!(
+            plan.profiles[0].skipped[0].reason,
+            SkipReason::CostExceedsBenefit {
+                cost: 1_800,
+                benefit: 1_200
+            }
+        );
+
+        rebalancing_system.transfer_costs.expected_benefit = Bps(50);
+        let plan = rebalancing_system.rebalance(&mut portfolio).unwrap();
+        assert_eq!(plan.profiles[0].transfers.len(), 1);
+        assert!(plan.profiles[0].skipped.is_empty());
+
+        assert!(TransferCostModel::parse("kamino:0:5", Bps(0)).is_err());
+        assert!(TransferCostModel::parse("aave:usdc:0:5", Bps(0)).is_err());
+        assert!(TransferCostModel::parse("kamino:eth:0:5", Bps(0)).is_err());
+        // Fixed costs are per asset, a SOL transfer doesn't pay the USDC one
+        let costs = TransferCostModel::parse("kamino:usdc:5000:0,kamino:sol:10:0", Bps(0)).unwrap();
+        let transfer = PoolTransfer {
+            from: Protocol::Drift,
+            to: Protocol::Kamino,
+            amount: 1_000_000,
+        };
+        assert_eq!(costs.cost(Asset::Usdc, &transfer), 5_000);
+        assert_eq!(costs.cost(Asset::Sol, &transfer), 10);
+
+        assert_eq!(
+            parse_min_transfer_amounts("usdc:1000000, sol:10000000").unwrap(),
+            HashMap::from([(Asset::Usdc, 1_000_000), (Asset::Sol, 10_000_000)])
+        );
+        assert!(parse_min_transfer_amounts("1000000").is_err());
+    }
+
+    #[test]
+    fn test_drift_trigger() {
+        let mut rebalancing_system = RebalancingSystem::new(MockRiskModel);
+        // Low targets Kamino only, 40% of the profile sits elsewhere
+        let mut portfolio = portfolio_with(
+            RiskProfile::Low,
+            &[(Protocol::Drift, 400), (Protocol::Kamino, 600)],
+        );
+        assert_eq!(
+            allocation_drift(
+                &portfolio.assets[&Asset::Usdc][&RiskProfile::Low],
+                &[(Protocol::Kamino, 10_000)]
+            ),
+            Bps(4_000)
+        );
+
+        // Rebalanced just now, the interval hasn't elapsed
+        assert!(!rebalancing_system.should_rebalance(&portfolio));
+        rebalancing_system.trigger = RebalanceTrigger::Drift;
+        assert!(rebalancing_system.should_rebalance(&portfolio));
+        rebalancing_system.trigger = RebalanceTrigger::Both;
+        assert!(rebalancing_system.should_rebalance(&portfolio));
+
+        rebalancing_system.rebalance(&mut portfolio).unwrap();
+        rebalancing_system.trigger = RebalanceTrigger::Drift;
+        assert!(!rebalancing_system.should_rebalance(&portfolio));
+
+        rebalancing_system.drift_threshold = Bps(5_000);
+        portfolio = portfolio_with(
+            RiskProfile::Low,
+            &[(Protocol::Drift, 400), (Protocol::Kamino, 600)],
+        );
+        assert!(!rebalancing_system.should_rebalance(&portfolio));
+    }
+
+    fn portfolio_with(profile: RiskProfile, pools: &[(Protocol, u64)]) -> UserPortfolio {
+        let pool_allocations: HashMap<Protocol, u64> = pools.iter().cloned().collect();
+        let total_amount = pools.iter().map(|(_, amount)| amount).sum();
+        UserPortfolio {
+            user_wallet: Pubkey::default(),
+            assets: HashMap::from([(
+                Asset::Usdc,
+                HashMap::from([(
+                    profile.clone(),
+                    ProfileAllocation {
+                        risk_profile: profile,
+                        asset: Asset::Usdc,
+                        pool_allocations,
+                        total_amount,
+                    },
+                )]),
+            )]),
+            last_rebalance: SystemTime::now(),
+        }
+    }
+
+    fn allocated(portfolio: &UserPortfolio, profile: &RiskProfile) -> u64 {
+        portfolio.assets[&Asset::Usdc][profile]
+            .pool_allocations
+            .values()
+            .sum()
+    }
+
+    #[test]
+    fn test_split_proportionally_sums_exactly() {
+        let weights = [
+            (Protocol::Kamino, 3333),
+            (Protocol::Drift, 3333),
+            (Protocol::Solend, 3334),
+        ];
+        for amount in [0, 1, 2, 7, 10_000, 999_999_999, u64::MAX] {
+            let parts = split_proportionally(amount, &weights);
+            let sum = parts.iter().map(|(_, part)| *part as u128).sum::<u128>();
+            assert_eq!(sum, amount as u128, "amount {}", amount);
+        }
+        // The largest remainder gets the leftover unit
+        assert_eq!(
+            split_proportionally(1, &weights),
+            vec![
+                (Protocol::Kamino, 0),
+                (Protocol::Drift, 0),
+                (Protocol::Solend, 1)
+            ]
+        );
+        assert_eq!(
+            split_proportionally(5, &[(Protocol::Kamino, 0)]),
+            vec![(Protocol::Kamino, 0)]
+        );
     }
 
     #[test]
     fn test_withdraw() {
-        // We would implement a test for withdraw here
+        let mut rebalancing_system = RebalancingSystem::new(MockRiskModel);
+        let profile = RiskProfile::High;
+        let mut portfolio = portfolio_with(
+            profile.clone(),
+            &[
+                (Protocol::Kamino, 333),
+                (Protocol::Drift, 333),
+                (Protocol::Solend, 334),
+            ],
+        );
+
+        // Truncating basis-point math would withdraw 0 from every pool here
+        let withdrawals = rebalancing_system
+            .withdraw(&mut portfolio, Asset::Usdc, &profile, 1)
+            .unwrap();
+        assert_eq!(portfolio.assets[&Asset::Usdc][&profile].total_amount, 999);
+        assert_eq!(allocated(&portfolio, &profile), 999);
+        assert_eq!(withdrawals.proportion_basis_points, Bps(10));
+        let pulled: Vec<_> = withdrawals
+            .withdrawals_to_execute
+            .iter()
+            .filter(|withdrawal| withdrawal.amount > 0)
+            .collect();
+        assert_eq!(pulled.len(), 1);
+        assert_eq!(pulled[0].allocation_basis_points, Bps::FULL);
+        assert_eq!(
+            pulled[0].remaining,
+            portfolio.assets[&Asset::Usdc][&profile].pool_allocations[&pulled[0].protocol]
+        );
+
+        let withdrawals = rebalancing_system
+            .withdraw(&mut portfolio, Asset::Usdc, &profile, 500)
+            .unwrap();
+        // Shares are rounded down, by less than a basis point each
+        let shares: u64 = withdrawals
+            .withdrawals_to_execute
+            .iter()
+            .map(|withdrawal| withdrawal.allocation_basis_points.0)
+            .sum();
+        assert!(shares <= Bps::FULL.0 && shares > Bps::FULL.0 - 3);
+        assert_eq!(portfolio.assets[&Asset::Usdc][&profile].total_amount, 499);
+        assert_eq!(allocated(&portfolio, &profile), 499);
+
+        // Withdrawing everything empties every pool
+        rebalancing_system
+            .withdraw(&mut portfolio, Asset::Usdc, &profile, 499)
+            .unwrap();
+        assert_eq!(allocated(&portfolio, &profile), 0);
+        assert!(rebalancing_system
+            .withdraw(&mut portfolio, Asset::Usdc, &profile, 1)
+            .is_err());
+    }
+
+    #[test]
+    fn test_withdraw_random_amounts_keep_accounting_exact() {
+        let mut rebalancing_system = RebalancingSystem::new(MockRiskModel);
+        let profile = RiskProfile::High;
+        for _ in 0..200 {
+            let pools: Vec<(Protocol, u64)> = [
+                Protocol::Kamino,
+                Protocol::Drift,
+                Protocol::Solend,
+                Protocol::Marginfy,
+            ]
+            .into_iter()
+            .map(|protocol| (protocol, rand::random::<u64>() % 1_000_000_007))
+            .collect();
+            let mut portfolio = portfolio_with(profile.clone(), &pools);
+            let total = portfolio.assets[&Asset::Usdc][&profile].total_amount;
+            let amount = rand::random::<u64>() % (total + 1);
+
+            rebalancing_system
+                .withdraw(&mut portfolio, Asset::Usdc, &profile, amount)
+                .unwrap();
+
+            let allocation = &portfolio.assets[&Asset::Usdc][&profile];
+            assert_eq!(allocation.total_amount, total - amount);
+            assert_eq!(allocated(&portfolio, &profile), total - amount);
+            for (protocol, before) in &pools {
+                assert!(allocation.pool_allocations[protocol] <= *before);
+            }
+        }
+    }
+
+    #[test]
+    fn test_deposit_allocates_exact_amount() {
+        let mut rebalancing_system = RebalancingSystem::new(MockRiskModel);
+        let mut portfolio = UserPortfolio {
+            user_wallet: Pubkey::default(),
+            assets: HashMap::new(),
+            last_rebalance: SystemTime::now(),
+        };
+        let deposits = rebalancing_system
+            .deposit(
+                &mut portfolio,
+                Asset::Usdc,
+                RiskProfile::High,
+                1_000_000_001,
+            )
+            .unwrap();
+        let deposited: u64 = deposits
+            .deposits_to_execute
+            .iter()
+            .map(|deposit| deposit.amount)
+            .sum();
+        assert_eq!(deposited, 1_000_000_001);
+        assert_eq!(allocated(&portfolio, &RiskProfile::High), 1_000_000_001);
+    }
+
+    #[test]
+    fn test_assets_are_kept_apart() {
+        let mut rebalancing_system = RebalancingSystem::new(MockRiskModel);
+        let mut portfolio = UserPortfolio::new(Pubkey::new_unique());
+        let profile = RiskProfile::High;
+        rebalancing_system
+            .deposit(&mut portfolio, Asset::Usdc, profile.clone(), 1_000)
+            .unwrap();
+        let deposits = rebalancing_system
+            .deposit(&mut portfolio, Asset::Sol, profile.clone(), 5_000)
+            .unwrap();
+        assert_eq!(deposits.asset, Asset::Sol);
+        assert_eq!(
+            portfolio
+                .allocation(Asset::Usdc, &profile)
+                .unwrap()
+                .total_amount,
+            1_000
+        );
+        let sol = portfolio.allocation(Asset::Sol, &profile).unwrap();
+        assert_eq!((sol.asset, sol.total_amount), (Asset::Sol, 5_000));
+
+        // USDC can't be withdrawn from the SOL the profile holds
+        assert!(rebalancing_system
+            .withdraw(&mut portfolio, Asset::Usdc, &profile, 2_000)
+            .is_err());
+        let withdrawals = rebalancing_system
+            .withdraw(&mut portfolio, Asset::Sol, &profile, 2_000)
+            .unwrap();
+        assert_eq!(withdrawals.asset, Asset::Sol);
+        assert_eq!(
+            portfolio
+                .allocation(Asset::Usdc, &profile)
+                .unwrap()
+                .total_amount,
+            1_000
+        );
+
+        let plan = rebalancing_system.rebalance(&mut portfolio).unwrap();
+        let mut assets: Vec<Asset> = plan
+            .profiles
+            .iter()
+            .map(|rebalance| rebalance.asset)
+            .collect();
+        assets.sort_by_key(|asset| asset.symbol());
+        assert_eq!(assets, vec![Asset::Sol, Asset::Usdc]);
+    }
+
+    #[test]
+    fn test_deposit_feasibility() {
+        let mut rebalancing_system = RebalancingSystem::new(MockRiskModel);
+        let mut portfolio = UserPortfolio {
+            user_wallet: Pubkey::new_unique(),
+            assets: HashMap::new(),
+            last_rebalance: SystemTime::now(),
+        };
+        let mut deposits = rebalancing_system
+            .deposit(&mut portfolio, Asset::Usdc, RiskProfile::High, 1_000)
+            .unwrap();
+        assert!(deposits
+            .deposits_to_execute
+            .iter()
+            .all(|deposit| deposit.feasibility.is_none()));
+        assert!(deposits.ensure_feasible().is_ok());
+
+        // Half of the deposit asset is in the wallet
+        let balances = SignerBalances {
+            lamports: 1_000_000_000,
+            deposit_asset: 500,
+        };
+        deposits.check_feasibility(&balances, 5_000);
+        assert!(deposits.deposits_to_execute.iter().any(|deposit| !deposit
+            .feasibility
+            .as_ref()
+            .unwrap()
+            .feasible));
+        assert!(deposits.ensure_feasible().is_err());
+    }
+
+    #[test]
+    fn test_renormalize_weights() {
+        let weights = HashMap::from([
+            (Protocol::Kamino, 5_000),
+            (Protocol::Drift, 3_000),
+            (Protocol::Marginfy, 1_000),
+            (Protocol::Solend, 1_000),
+        ]);
+        let unchanged = renormalize_weights(&weights, &[], &HashMap::new()).unwrap();
+        assert!(unchanged.note.is_none());
+        assert_eq!(unchanged.weights[0], (Protocol::Kamino, 5_000));
+
+        let renormalized =
+            renormalize_weights(&weights, &[Protocol::Kamino], &HashMap::new()).unwrap();
+        let weight = |renormalized: &RenormalizedWeights, protocol| {
+            renormalized
+                .weights
+                .iter()
+                .find(|(p, _)| *p == protocol)
+                .map(|(_, weight)| *weight)
+        };
+        assert_eq!(weight(&renormalized, Protocol::Kamino), None);
+        assert_eq!(weight(&renormalized, Protocol::Drift), Some(6_000));
+        assert_eq!(weight(&renormalized, Protocol::Marginfy), Some(2_000));
+        assert!(renormalized.note.unwrap().contains("Kamino (50.0%)"));
+
+        // Drift's cap pushes the excess to the others
+        let caps = HashMap::from([(Protocol::Drift, 4_000)]);
+        let capped = renormalize_weights(&weights, &[Protocol::Kamino], &caps).unwrap();
+        assert_eq!(weight(&capped, Protocol::Drift), Some(4_000));
+        assert_eq!(weight(&capped, Protocol::Marginfy), Some(3_000));
+        assert_eq!(weight(&capped, Protocol::Solend), Some(3_000));
+
+        let tight_caps = HashMap::from([
+            (Protocol::Drift, 1_000),
+            (Protocol::Marginfy, 1_000),
+            (Protocol::Solend, 1_000),
+        ]);
+        assert!(renormalize_weights(&weights, &[Protocol::Kamino], &tight_caps).is_err());
+        assert!(renormalize_weights(
+            &HashMap::from([(Protocol::Kamino, 10_000)]),
+            &[Protocol::Kamino],
+            &HashMap::new()
+        )
+        .is_err());
+    }
+
+    /// The mock model with Kamino positions limited by its withdrawal depth
+    struct DepthLimitedRiskModel;
+
+    impl RiskWeightModel for DepthLimitedRiskModel {
+        fn get_recommended_weights(&self, profile: &RiskProfile) -> HashMap<Protocol, u64> {
+            MockRiskModel.get_recommended_weights(profile)
+        }
+        fn position_limits(&self, _asset: Asset) -> HashMap<Protocol, u64> {
+            HashMap::from([(Protocol::Kamino, 100_000)])
+        }
+    }
+
+    #[test]
+    fn test_position_limits_bound_weights() {
+        let model = DepthLimitedRiskModel;
+        let unbounded = model
+            .get_available_weights(&RiskProfile::High, Asset::Usdc, 100_000)
+            .unwrap();
+        assert!(unbounded.note.is_none());
+
+        // 100,000 is 10% of 1,000,000
+        let bounded = model
+            .get_available_weights(&RiskProfile::High, Asset::Usdc, 1_000_000)
+            .unwrap();
+        assert!(bounded.weights.contains(&(Protocol::Kamino, 1_000)));
+        assert_eq!(
+            bounded
+                .weights
+                .iter()
+                .map(|(_, weight)| weight)
+                .sum::<u64>(),
+            10_000
+        );
+        assert!(bounded.note.unwrap().contains("Kamino"));
+    }
+
+    /// 60/40 Kamino/Drift for every profile
+    struct FixedRiskModel;
+
+    impl RiskWeightModel for FixedRiskModel {
+        fn get_recommended_weights(&self, _profile: &RiskProfile) -> HashMap<Protocol, u64> {
+            HashMap::from([(Protocol::Kamino, 6_000), (Protocol::Drift, 4_000)])
+        }
+    }
+
+    #[test]
+    fn test_exposure_limits() {
+        let limits =
+            ExposureLimits::parse("drift:usdc:5000:3000, drift:sol:50:, marginfi:usdc::2500")
+                .unwrap();
+        assert_eq!(
+            limits.limits[&Asset::Usdc][&Protocol::Marginfy],
+            ExposureLimit {
+                max_amount: None,
+                max_share: Some(Bps(2_500)),
+            }
+        );
+        assert_eq!(
+            limits.max_amount(&Protocol::Drift, Asset::Usdc, 100_000),
+            5_000
+        );
+        assert_eq!(
+            limits.max_amount(&Protocol::Drift, Asset::Usdc, 10_000),
+            3_000
+        );
+        assert_eq!(limits.max_amount(&Protocol::Drift, Asset::Sol, 100_000), 50);
+        assert_eq!(
+            limits.max_amount(&Protocol::Kamino, Asset::Usdc, 10_000),
+            u64::MAX
+        );
+        assert_eq!(
+            limits.max_amount(&Protocol::Marginfy, Asset::Sol, 10_000),
+            u64::MAX
+        );
+        assert!(ExposureLimits::parse("kamino:1:").is_err());
+        assert!(ExposureLimits::parse("kamino:usdc::20000").is_err());
+
+        let profile = RiskProfile::Medium;
+        let mut rebalancing_system = RebalancingSystem::new(FixedRiskModel);
+        rebalancing_system.exposure_limits = ExposureLimits::parse("kamino:usdc::5000").unwrap();
+        let mut portfolio = UserPortfolio::new(Pubkey::new_unique());
+        let deposits = rebalancing_system
+            .deposit(&mut portfolio, Asset::Usdc, profile.clone(), 10_000)
+            .unwrap();
+        let amounts: HashMap<Protocol, u64> = deposits
+            .deposits_to_execute
+            .iter()
+            .map(|deposit| (deposit.protocol.clone(), deposit.amount))
+            .collect();
+        assert_eq!(amounts[&Protocol::Kamino], 5_000);
+        assert_eq!(amounts[&Protocol::Drift], 5_000);
+        assert_eq!(
+            deposits.applied_caps,
+            vec![AppliedCap {
+                protocol: Protocol::Kamino,
+                recommended: Bps(6_000),
+                cap: Bps(5_000),
+                max_amount: 5_000,
+            }]
+        );
+
+        // Holdings already at the limit spill the deposit to the other protocols
+        rebalancing_system.exposure_limits = ExposureLimits::parse("kamino:usdc:9000:").unwrap();
+        let mut portfolio = portfolio_with(
+            profile.clone(),
+            &[(Protocol::Kamino, 8_000), (Protocol::Drift, 2_000)],
+        );
+        let deposits = rebalancing_system
+            .deposit(&mut portfolio, Asset::Usdc, profile.clone(), 10_000)
+            .unwrap();
+        let held = &portfolio.assets[&Asset::Usdc][&profile].pool_allocations;
+        assert_eq!(held[&Protocol::Kamino], 9_000);
+        assert_eq!(held[&Protocol::Drift], 11_000);
+        assert_eq!(deposits.applied_caps[0].max_amount, 9_000);
+
+        let allocation = portfolio
+            .assets
+            .get_mut(&Asset::Usdc)
+            .unwrap()
+            .get_mut(&profile)
+            .unwrap();
+        let rebalance = rebalancing_system
+            .rebalance_profile(&profile, allocation)
+            .unwrap();
+        assert!(rebalance.transfers.is_empty());
+        assert_eq!(rebalance.applied_caps[0].cap, Bps(4_500));
+
+        // Limits that can't hold the whole profile fail the deposit
+        rebalancing_system.exposure_limits =
+            ExposureLimits::parse("kamino:usdc::5000,drift:usdc::4000").unwrap();
+        let mut portfolio = UserPortfolio::new(Pubkey::new_unique());
+        assert!(rebalancing_system
+            .deposit(&mut portfolio, Asset::Usdc, profile, 10_000)
+            .is_err());
+        assert!(portfolio.assets.is_empty());
+    }
+
+    #[test]
+    fn test_transfers_drain_each_source_once() {
+        let profile = RiskProfile::Medium;
+        let mut rebalancing_system = RebalancingSystem::new(FixedRiskModel);
+        let mut portfolio = portfolio_with(
+            profile.clone(),
+            &[(Protocol::Solend, 500), (Protocol::Marginfy, 500)],
+        );
+        let plan = rebalancing_system.rebalance(&mut portfolio).unwrap();
+        let mut sent = HashMap::new();
+        let mut received = HashMap::new();
+        for transfer in &plan.profiles[0].transfers {
+            *sent.entry(transfer.from.clone()).or_insert(0) += transfer.amount;
+            *received.entry(transfer.to.clone()).or_insert(0) += transfer.amount;
+        }
+        assert_eq!(
+            sent,
+            HashMap::from([(Protocol::Solend, 500), (Protocol::Marginfy, 500)])
+        );
+        assert_eq!(
+            received,
+            HashMap::from([(Protocol::Kamino, 600), (Protocol::Drift, 400)])
+        );
+
+        let allocation = &portfolio.assets[&Asset::Usdc][&profile];
+        assert_eq!(
+            allocation.pool_allocations.values().sum::<u64>(),
+            allocation.total_amount
+        );
+        assert_eq!(allocation.pool_allocations[&Protocol::Solend], 0);
+        assert_eq!(allocation.pool_allocations[&Protocol::Kamino], 600);
+    }
+
+    /// The mock model with Drift frozen
+    struct DegradedRiskModel;
+
+    impl RiskWeightModel for DegradedRiskModel {
+        fn get_recommended_weights(&self, profile: &RiskProfile) -> HashMap<Protocol, u64> {
+            MockRiskModel.get_recommended_weights(profile)
+        }
+        fn unavailable_protocols(&self) -> Vec<Protocol> {
+            vec![Protocol::Drift]
+        }
+    }
+
+    #[test]
+    fn test_deposit_skips_unavailable_protocols() {
+        let mut rebalancing_system = RebalancingSystem::new(DegradedRiskModel);
+        let mut portfolio = portfolio_with(RiskProfile::High, &[]);
+        let deposits = rebalancing_system
+            .deposit(&mut portfolio, Asset::Usdc, RiskProfile::High, 1_000_000)
+            .unwrap();
+        assert!(deposits.redistribution_note.is_some());
+        assert!(deposits
+            .deposits_to_execute
+            .iter()
+            .all(|deposit| deposit.protocol != Protocol::Drift));
+        assert_eq!(
+            deposits
+                .deposits_to_execute
+                .iter()
+                .map(|deposit| deposit.allocation_basis_points.0)
+                .sum::<u64>(),
+            10_000
+        );
+        assert_eq!(allocated(&portfolio, &RiskProfile::High), 1_000_000);
+    }
+
+    #[test]
+    fn test_live_risk_model_weights() {
+        let model = LiveRiskModel::from_scores(
+            vec![
+                (Protocol::Kamino, 60.0),
+                (Protocol::Marginfy, 10.0),
+                (Protocol::Drift, 30.0),
+            ],
+            vec![Protocol::Solend],
+        )
+        .unwrap();
+        for profile in [RiskProfile::Low, RiskProfile::Medium, RiskProfile::High] {
+            let weights = model.get_recommended_weights(&profile);
+            assert_eq!(weights.values().sum::<u64>(), 10_000);
+            assert!(weights
+                .values()
+                .all(|weight| *weight <= profile_weight_cap(&profile).0));
+        }
+        assert_eq!(
+            model.get_recommended_weights(&RiskProfile::Low),
+            HashMap::from([(Protocol::Marginfy, 10_000)])
+        );
+        // Inverse risk would give Marginfi 75%, above the Medium cap
+        assert_eq!(
+            model.get_recommended_weights(&RiskProfile::Medium),
+            HashMap::from([(Protocol::Marginfy, 7_000), (Protocol::Drift, 3_000)])
+        );
+        assert_eq!(model.unavailable_protocols(), vec![Protocol::Solend]);
+
+        // A single protocol takes all the weight, whatever the cap
+        let single =
+            LiveRiskModel::from_scores(vec![(Protocol::Kamino, 40.0)], Vec::new()).unwrap();
+        assert_eq!(
+            single.get_recommended_weights(&RiskProfile::High),
+            HashMap::from([(Protocol::Kamino, 10_000)])
+        );
+        assert!(LiveRiskModel::from_scores(Vec::new(), Vec::new()).is_err());
+
+        // Positions are bounded by the depth of their reserve
+        let limited = LiveRiskModel::from_scores(
+            vec![(Protocol::Kamino, 40.0), (Protocol::Marginfy, 40.0)],
+            Vec::new(),
+        )
+        .unwrap()
+        .with_position_limits(Asset::Usdc, HashMap::from([(Protocol::Kamino, 100_000)]));
+        let weights = limited
+            .get_available_weights(&RiskProfile::High, Asset::Usdc, 1_000_000)
+            .unwrap();
+        assert!(weights.weights.contains(&(Protocol::Kamino, 1_000)));
+        // Limits of the USDC reserves don't bound SOL positions
+        let weights = limited
+            .get_available_weights(&RiskProfile::High, Asset::Sol, 1_000_000)
+            .unwrap();
+        assert!(weights.note.is_none());
+    }
+
+    #[test]
+    fn test_live_risk_model_of_degraded_snapshot() {
+        use crate::registry::{ProtocolAssessment, ProtocolComparison, UnavailableProtocol};
+        use crate::risk_model::{RiskResponse, RiskScore};
+
+        let assessment = |protocol: Protocol, overall_risk: f64| ProtocolAssessment {
+            protocol,
+            scope: String::new(),
+            risk_metrics: RiskResponse {
+                overall_risk: RiskScore {
+                    overall_risk,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        };
+        // Marginfi failed this refresh, the snapshot is stored as it is
+        let snapshot = RiskSnapshot::new(
+            ProtocolComparison {
+                ranking: vec![assessment(Protocol::Kamino, 40.0)],
+                unavailable: vec![UnavailableProtocol {
+                    protocol: Protocol::Marginfy,
+                    error: "RPC timeout".to_string(),
+                }],
+            },
+            chrono::Utc::now(),
+        );
+        assert!(snapshot.is_degraded());
+        let model = LiveRiskModel::from_snapshot(&snapshot).unwrap();
+        assert_eq!(model.unavailable_protocols(), vec![Protocol::Marginfy]);
+
+        // Approved weights still holding Marginfi are renormalized around it
+        let model = model.with_approved_weights(HashMap::from([(
+            RiskProfile::Medium,
+            vec![(Protocol::Kamino, 6_000), (Protocol::Marginfy, 4_000)],
+        )]));
+        let weights = model
+            .get_available_weights(&RiskProfile::Medium, Asset::Usdc, 0)
+            .unwrap();
+        assert_eq!(weights.weights, vec![(Protocol::Kamino, 10_000)]);
+        assert!(weights.note.is_some());
     }
 }
@@ -0,0 +1,297 @@
+use std::{future::Future, pin::Pin, sync::OnceLock};
+
+use redis::{
+    aio::{ConnectionLike, MultiplexedConnection},
+    cluster::ClusterClient,
+    cluster_async::ClusterConnection,
+    cluster_routing::{get_slot, Route, RoutingInfo, SingleNodeRoutingInfo, SlotAddr},
+    sentinel::{Sentinel, SentinelNodeConnectionInfo},
+    Cmd, ConnectionAddr, Pipeline, RedisFuture, RedisResult, TlsMode, Value,
+};
+
+use crate::risk_model::RiskCalculationError;
+
+static REDIS_TOPOLOGY: OnceLock<RedisTopology> = OnceLock::new();
+
+/// Drives a multiplexed connection, ends when the connection closes
+pub type Driver = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// How the service reaches Redis
+///
+/// Set with `REDIS_MODE`, `standalone` by default. `REDIS_URL` is used in
+/// every mode, `rediss://` URLs connect over TLS, `rediss://...#insecure`
+/// without verifying the certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RedisTopology {
+    /// The single server at `REDIS_URL`
+    Standalone,
+    /// A Redis Cluster, discovered from `REDIS_URL` and the optional comma
+    /// separated `REDIS_CLUSTER_NODES`
+    ///
+    /// Keys share a hash tag, see [`crate::cache_schema::versioned_key`] and
+    /// [`crate::cache_schema::record_key`], so they live in a single slot:
+    /// pipelines, transactions and scripts span several keys, which a cluster
+    /// only serves within a slot, and a scan of that slot's node sees them all.
+    Cluster { nodes: Vec<String> },
+    /// The master the comma separated `REDIS_SENTINELS` elect for the
+    /// `REDIS_SENTINEL_MASTER` service, looked up again after a failover
+    ///
+    /// The master is connected to with the TLS, credentials and database of `REDIS_URL`.
+    Sentinel {
+        sentinels: Vec<String>,
+        master: String,
+    },
+}
+
+impl RedisTopology {
+    /// The topology read at first use
+    pub fn global() -> &'static Self {
+        REDIS_TOPOLOGY
+            .get_or_init(|| Self::from_env().expect("Redis topology configuration must be valid"))
+    }
+
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Result<Self, RiskCalculationError> {
+        let list = |name: &str| -> Vec<String> {
+            var(name)
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect()
+        };
+        match var("REDIS_MODE").as_deref().map(str::trim) {
+            None | Some("standalone") => Ok(RedisTopology::Standalone),
+            Some("cluster") => Ok(RedisTopology::Cluster {
+                nodes: list("REDIS_CLUSTER_NODES"),
+            }),
+            Some("sentinel") => {
+                let sentinels = list("REDIS_SENTINELS");
+                if sentinels.is_empty() {
+                    return Err(RiskCalculationError::ParseError(
+                        "REDIS_SENTINELS must be set when REDIS_MODE is sentinel".to_string(),
+                    ));
+                }
+                let master = var("REDIS_SENTINEL_MASTER").ok_or_else(|| {
+                    RiskCalculationError::ParseError(
+                        "REDIS_SENTINEL_MASTER must be set when REDIS_MODE is sentinel".to_string(),
+                    )
+                })?;
+                Ok(RedisTopology::Sentinel { sentinels, master })
+            }
+            Some(other) => Err(RiskCalculationError::ParseError(format!(
+                "invalid REDIS_MODE {:?}, expected standalone, cluster or sentinel",
+                other
+            ))),
+        }
+    }
+
+    pub fn is_cluster(&self) -> bool {
+        matches!(self, RedisTopology::Cluster { .. })
+    }
+
+    /// Identifies what `redis_client` connects to, connections are shared by key
+    pub fn connection_key(&self, redis_client: &redis::Client) -> String {
+        let info = redis_client.get_connection_info();
+        let server = format!(
+            "{}/{}/{}",
+            info.addr,
+            info.redis.db,
+            info.redis.username.as_deref().unwrap_or_default()
+        );
+        match self {
+            RedisTopology::Standalone => server,
+            RedisTopology::Cluster { .. } => format!("cluster:{}", server),
+            RedisTopology::Sentinel { master, .. } => format!("sentinel:{}:{}", master, server),
+        }
+    }
+
+    /// Opens a connection, with the future driving it when it must be spawned
+    ///
+    /// Cluster connections reconnect to their nodes on their own and have none.
+    pub async fn connect(
+        &self,
+        redis_client: &redis::Client,
+    ) -> RedisResult<(RedisConnection, Option<Driver>)> {
+        match self {
+            RedisTopology::Standalone => multiplexed(redis_client).await,
+            // Boxed, so callers of every command don't carry their large futures
+            _ => Box::pin(self.connect_distributed(redis_client)).await,
+        }
+    }
+
+    async fn connect_distributed(
+        &self,
+        redis_client: &redis::Client,
+    ) -> RedisResult<(RedisConnection, Option<Driver>)> {
+        match self {
+            RedisTopology::Standalone => multiplexed(redis_client).await,
+            RedisTopology::Cluster { nodes } => {
+                let mut seeds = vec![redis_client.get_connection_info().clone()];
+                for node in nodes {
+                    seeds.push(redis::IntoConnectionInfo::into_connection_info(
+                        node.as_str(),
+                    )?);
+                }
+                let connection = ClusterClient::new(seeds)?.get_async_connection().await?;
+                Ok((RedisConnection::Cluster(connection), None))
+            }
+            RedisTopology::Sentinel { sentinels, master } => {
+                let info = redis_client.get_connection_info();
+                let node = SentinelNodeConnectionInfo {
+                    tls_mode: match info.addr {
+                        ConnectionAddr::TcpTls { insecure: true, .. } => Some(TlsMode::Insecure),
+                        ConnectionAddr::TcpTls { .. } => Some(TlsMode::Secure),
+                        _ => None,
+                    },
+                    redis_connection_info: Some(info.redis.clone()),
+                };
+                let master_client = Sentinel::build(sentinels.clone())?
+                    .async_master_for(master, Some(&node))
+                    .await?;
+                multiplexed(&master_client).await
+            }
+        }
+    }
+}
+
+async fn multiplexed(
+    redis_client: &redis::Client,
+) -> RedisResult<(RedisConnection, Option<Driver>)> {
+    let (connection, driver) = redis_client.create_multiplexed_tokio_connection().await?;
+    Ok((RedisConnection::Single(connection), Some(Box::pin(driver))))
+}
+
+/// Slot every key is stored in on a Redis Cluster, see [`crate::cache_schema::versioned_key`]
+///
+/// It doesn't change with the schema version, records outlive it.
+pub fn key_hash_tag() -> &'static str {
+    "risk_model"
+}
+
+/// A connection to a single server or to a cluster, commands are sent the same way
+#[derive(Clone)]
+pub enum RedisConnection {
+    Single(MultiplexedConnection),
+    Cluster(ClusterConnection),
+}
+
+/// Whether `cmd` is a `SCAN`, which a cluster would otherwise send to any node
+fn is_scan(cmd: &Cmd) -> bool {
+    cmd.args_iter().next().is_some_and(|name| match name {
+        redis::Arg::Simple(name) => name.eq_ignore_ascii_case(b"SCAN"),
+        redis::Arg::Cursor => false,
+    })
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            RedisConnection::Single(connection) => connection.req_packed_command(cmd),
+            RedisConnection::Cluster(connection) => {
+                if !is_scan(cmd) {
+                    return connection.req_packed_command(cmd);
+                }
+                // Scans the node holding every key
+                let slot = get_slot(format!("{{{}}}", key_hash_tag()).as_bytes());
+                let routing = RoutingInfo::SingleNode(SingleNodeRoutingInfo::SpecificNode(
+                    Route::new(slot, SlotAddr::Master),
+                ));
+                Box::pin(connection.route_command(cmd, routing))
+            }
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            RedisConnection::Single(connection) => {
+                connection.req_packed_commands(cmd, offset, count)
+            }
+            RedisConnection::Cluster(connection) => {
+                connection.req_packed_commands(cmd, offset, count)
+            }
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Single(connection) => connection.get_db(),
+            RedisConnection::Cluster(connection) => connection.get_db(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_redis_topology() {
+        let topology = |vars: &[(&str, &str)]| {
+            let vars: HashMap<String, String> = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            RedisTopology::from_vars(|name| vars.get(name).cloned())
+        };
+        assert_eq!(topology(&[]).unwrap(), RedisTopology::Standalone);
+        assert_eq!(
+            topology(&[
+                ("REDIS_MODE", "cluster"),
+                (
+                    "REDIS_CLUSTER_NODES",
+                    "rediss://node-1:6379, rediss://node-2:6379,"
+                ),
+            ])
+            .unwrap(),
+            RedisTopology::Cluster {
+                nodes: vec![
+                    "rediss://node-1:6379".to_string(),
+                    "rediss://node-2:6379".to_string()
+                ],
+            }
+        );
+        assert!(topology(&[
+            ("REDIS_MODE", "sentinel"),
+            ("REDIS_SENTINELS", "redis://s:26379")
+        ])
+        .is_err());
+        let sentinel = topology(&[
+            ("REDIS_MODE", "sentinel"),
+            ("REDIS_SENTINELS", "redis://s-1:26379,redis://s-2:26379"),
+            ("REDIS_SENTINEL_MASTER", "mymaster"),
+        ])
+        .unwrap();
+        assert!(!sentinel.is_cluster());
+        assert!(topology(&[("REDIS_MODE", "replicated")]).is_err());
+
+        let client = |url| redis::Client::open(url).unwrap();
+        let standalone = RedisTopology::Standalone;
+        assert_eq!(
+            standalone.connection_key(&client("redis://127.0.0.1/")),
+            standalone.connection_key(&client("redis://127.0.0.1:6379/0"))
+        );
+        assert_ne!(
+            standalone.connection_key(&client("redis://127.0.0.1/")),
+            standalone.connection_key(&client("redis://127.0.0.1/1"))
+        );
+        assert_ne!(
+            sentinel.connection_key(&client("redis://127.0.0.1/")),
+            standalone.connection_key(&client("redis://127.0.0.1/"))
+        );
+
+        assert!(is_scan(redis::cmd("scan").arg(0)));
+        assert!(!is_scan(redis::cmd("GET").arg("scan")));
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use redis::RedisResult;
+
+use crate::redis_builder::{RedisConnection, RedisTopology};
+
+type Connections = Mutex<HashMap<String, (u64, RedisConnection)>>;
+
+/// Open connections by server, with the generation that opened them
+static CONNECTIONS: OnceLock<Connections> = OnceLock::new();
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+fn connections() -> &'static Connections {
+    CONNECTIONS.get_or_init(Default::default)
+}
+
+/// The connection shared by every caller of `redis_client`'s server, opened on first use
+///
+/// A multiplexed connection pipelines concurrent commands over one socket, so
+/// handlers and scans don't pay for a new connection per call. Clones are
+/// cheap. Once the connection closes, the next call opens a new one, on a
+/// failover to the newly elected master with [`RedisTopology::Sentinel`].
+/// Concurrent first calls may each connect, the last one is kept.
+pub async fn shared_connection(redis_client: &redis::Client) -> RedisResult<RedisConnection> {
+    let topology = RedisTopology::global();
+    let key = topology.connection_key(redis_client);
+    let open = connections().lock().ok().and_then(|connections| {
+        connections
+            .get(&key)
+            .map(|(_, connection)| connection.clone())
+    });
+    if let Some(connection) = open {
+        return Ok(connection);
+    }
+
+    let (connection, driver) = topology.connect(redis_client).await?;
+    let generation = GENERATION.fetch_add(1, Ordering::Relaxed);
+    if let Ok(mut connections) = connections().lock() {
+        connections.insert(key.clone(), (generation, connection.clone()));
+    }
+    if let Some(driver) = driver {
+        tokio::spawn(async move {
+            // Also forgets the connection when the runtime driving it shuts down
+            let _closed = Closed { key, generation };
+            driver.await;
+        });
+    }
+    Ok(connection)
+}
+
+/// Forgets a connection once its driver stopped
+struct Closed {
+    key: String,
+    generation: u64,
+}
+
+impl Drop for Closed {
+    fn drop(&mut self) {
+        tracing::warn!("Redis connection to {} closed", self.key);
+        if let Ok(mut connections) = connections().lock() {
+            // A newer connection may already have replaced this one
+            if connections
+                .get(&self.key)
+                .is_some_and(|(generation, _)| *generation == self.generation)
+            {
+                connections.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shared_connection() {
+        // Nothing listens on port 1, failed connections aren't kept
+        let unreachable = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+        assert!(shared_connection(&unreachable).await.is_err());
+        assert!(!connections()
+            .lock()
+            .unwrap()
+            .contains_key(&RedisTopology::global().connection_key(&unreachable)));
+    }
+}
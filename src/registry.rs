@@ -0,0 +1,742 @@
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assets::Asset,
+    cache::CacheBackend,
+    cache_lock::compute_once,
+    clock::process_clock,
+    history::record_history,
+    kamino::{reserve::KaminoReserveConfig, sources::LiveSources, KaminoRisk},
+    marginfi::{yield_data::fetch_market_points, MarginfiRisk, MARGINFI_SCOPE},
+    market_history::{load_market_history, MarketPoint},
+    risk_model::{
+        PartialRiskResponse, Protocol, ProtocolRisk, ProtocolSubScores, RiskCalculationError,
+        RiskResponse,
+    },
+    snapshot::{latest_snapshot_key, load_latest_snapshot, store_snapshot, RiskSnapshot},
+    timings::spawn_timed,
+    volatility_risk::{calculate_window_volatility, VolatilityWindow, WindowVolatility},
+    weights::RiskWeightsConfig,
+};
+
+/// Asset of the reserves the registry compares, amounts in their metrics are in it
+pub const COMPARED_ASSET: Asset = Asset::Usdc;
+
+/// A protocol risk implementation known to the registry
+#[derive(Clone)]
+pub enum RegisteredProtocol {
+    Kamino(KaminoRisk),
+    Marginfi(MarginfiRisk),
+}
+
+impl RegisteredProtocol {
+    /// The implementation of `protocol`, Kamino assessed on `kamino_reserve`
+    pub fn for_protocol(
+        protocol: &Protocol,
+        redis_client: redis::Client,
+        kamino_reserve: KaminoReserveConfig,
+    ) -> Result<Self, RiskCalculationError> {
+        match protocol {
+            Protocol::Kamino => Ok(RegisteredProtocol::Kamino(KaminoRisk {
+                redis_client,
+                reserve: kamino_reserve,
+                sources: LiveSources,
+            })),
+            Protocol::Marginfy => Ok(RegisteredProtocol::Marginfi(MarginfiRisk { redis_client })),
+            other => Err(RiskCalculationError::InvalidParameter(format!(
+                "{:?} is not supported",
+                other
+            ))),
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            RegisteredProtocol::Kamino(_) => Protocol::Kamino,
+            RegisteredProtocol::Marginfi(_) => Protocol::Marginfy,
+        }
+    }
+
+    /// Identifies the data this protocol is assessed on, used to scope snapshots and history
+    pub fn scope(&self) -> String {
+        match self {
+            RegisteredProtocol::Kamino(risk) => risk.scope(),
+            RegisteredProtocol::Marginfi(_) => MARGINFI_SCOPE.to_string(),
+        }
+    }
+
+    /// Yield and utilization history of this protocol since `from`, oldest first
+    ///
+    /// Kamino is read from the stored hourly market history, marginfi from
+    /// DefiLlama's daily chart.
+    pub async fn market_history(
+        &self,
+        from: DateTime<Utc>,
+    ) -> Result<Vec<MarketPoint>, RiskCalculationError> {
+        match self {
+            RegisteredProtocol::Kamino(risk) => {
+                load_market_history(&risk.redis_client, &self.scope(), from, Utc::now()).await
+            }
+            RegisteredProtocol::Marginfi(_) => fetch_market_points(from).await,
+        }
+    }
+
+    /// Computes all risk pillars concurrently and the overall score for this protocol
+    pub async fn assess(&self) -> Result<RiskResponse, RiskCalculationError> {
+        match self {
+            RegisteredProtocol::Kamino(risk) => risk.calculate_all().await,
+            RegisteredProtocol::Marginfi(risk) => risk.calculate_all().await,
+        }
+    }
+
+    /// Computes the pillars that can be computed, see [`ProtocolRisk::calculate_partial`]
+    pub async fn assess_partial(&self) -> Result<PartialRiskResponse, RiskCalculationError> {
+        match self {
+            RegisteredProtocol::Kamino(risk) => risk.calculate_partial().await,
+            RegisteredProtocol::Marginfi(risk) => risk.calculate_partial().await,
+        }
+    }
+}
+
+/// Risk metrics of a single protocol within a comparison
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ProtocolAssessment {
+    pub protocol: Protocol,
+    /// See [`RegisteredProtocol::scope`]
+    #[serde(default)]
+    pub scope: String,
+    pub risk_metrics: RiskResponse,
+}
+
+impl ProtocolAssessment {
+    /// The pillars normalized as the overall score weighs them
+    pub fn sub_scores(&self) -> ProtocolSubScores {
+        let metrics = &self.risk_metrics;
+        let normalization = RiskWeightsConfig::global().normalization;
+        ProtocolSubScores {
+            protocol: self.protocol.clone(),
+            liquidity_risk: normalization
+                .liquidity
+                .apply(metrics.liquidity_risk.liquidity_risk),
+            volatility_risk: normalization
+                .volatility
+                .apply(metrics.volatility_risk.volatility_risk),
+            protocol_risk: normalization
+                .protocol
+                .apply(metrics.protocol_risk.protocol_risk),
+            oracle_risk: normalization.oracle.apply(metrics.oracle_risk.oracle_risk),
+        }
+    }
+}
+
+/// A protocol whose risk could not be computed
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct UnavailableProtocol {
+    pub protocol: Protocol,
+    pub error: String,
+}
+
+/// Volatility of a protocol over the window a client asked for
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ProtocolWindowVolatility {
+    pub protocol: Protocol,
+    /// `None` when the protocol's history doesn't cover the window
+    pub volatility: Option<WindowVolatility>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A protocol assessed from the pillars that could be computed
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PartialAssessment {
+    pub protocol: Protocol,
+    pub scope: String,
+    pub risk_metrics: PartialRiskResponse,
+}
+
+/// Result of comparing every registered protocol
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProtocolComparison {
+    /// Protocols ordered from lowest to highest overall risk
+    pub ranking: Vec<ProtocolAssessment>,
+    pub unavailable: Vec<UnavailableProtocol>,
+}
+
+impl ProtocolComparison {
+    /// The protocol with the lowest overall risk
+    pub fn chosen(&self) -> Option<&ProtocolAssessment> {
+        self.ranking.first()
+    }
+}
+
+/// Holds every registered `ProtocolRisk` implementor
+pub struct ProtocolRegistry {
+    protocols: Vec<RegisteredProtocol>,
+    /// Where comparison snapshots are stored
+    redis_client: redis::Client,
+}
+
+impl ProtocolRegistry {
+    pub fn new(redis_client: redis::Client) -> Self {
+        ProtocolRegistry {
+            protocols: Vec::new(),
+            redis_client,
+        }
+    }
+
+    /// Registry with every supported protocol sharing one redis client
+    pub fn with_all_protocols(
+        redis_client: redis::Client,
+        kamino_reserve: KaminoReserveConfig,
+    ) -> Self {
+        let mut registry = Self::new(redis_client.clone());
+        registry.register(RegisteredProtocol::Kamino(KaminoRisk {
+            redis_client: redis_client.clone(),
+            reserve: kamino_reserve,
+            sources: LiveSources,
+        }));
+        registry.register(RegisteredProtocol::Marginfi(MarginfiRisk { redis_client }));
+        registry
+    }
+
+    pub fn register(&mut self, protocol: RegisteredProtocol) {
+        self.protocols.push(protocol);
+    }
+
+    /// Computes the risk of every registered protocol in parallel and ranks them
+    ///
+    /// Every protocol is assessed on its own task, so they run on separate
+    /// threads of the runtime. Protocols that fail, or whose task panics, are
+    /// reported as unavailable; the comparison only errors when no protocol
+    /// could be assessed at all.
+    pub async fn compare(&self) -> Result<ProtocolComparison, RiskCalculationError> {
+        let tasks = self.protocols.iter().map(|protocol| {
+            let protocol = protocol.clone();
+            spawn_timed(async move { protocol.assess().await })
+        });
+        let results = join_all(tasks)
+            .await
+            .into_iter()
+            .zip(&self.protocols)
+            .map(|(result, protocol)| {
+                let result = result.unwrap_or_else(|e| {
+                    Err(RiskCalculationError::CustomError(format!(
+                        "Assessment task failed: {}",
+                        e
+                    )))
+                });
+                (protocol.protocol(), protocol.scope(), result)
+            })
+            .collect();
+        comparison_of(results)
+    }
+
+    /// Assesses the registered `protocols` from whichever pillars can be computed
+    ///
+    /// Returns them from lowest to highest overall risk over their computed
+    /// pillars, and the protocols none of whose pillars could be computed.
+    pub async fn compare_partial(
+        &self,
+        protocols: &[Protocol],
+    ) -> (Vec<PartialAssessment>, Vec<UnavailableProtocol>) {
+        let selected: Vec<&RegisteredProtocol> = self
+            .protocols
+            .iter()
+            .filter(|registered| protocols.contains(&registered.protocol()))
+            .collect();
+        let tasks = selected.iter().map(|protocol| {
+            let protocol = (*protocol).clone();
+            spawn_timed(async move { protocol.assess_partial().await })
+        });
+        let results = join_all(tasks)
+            .await
+            .into_iter()
+            .zip(selected)
+            .map(|(result, registered)| {
+                let result = result.unwrap_or_else(|e| {
+                    Err(RiskCalculationError::CustomError(format!(
+                        "Assessment task failed: {}",
+                        e
+                    )))
+                });
+                (registered.protocol(), registered.scope(), result)
+            })
+            .collect();
+        partial_comparison_of(results)
+    }
+
+    /// The implementation registered for `protocol`
+    pub fn registered(&self, protocol: &Protocol) -> Option<&RegisteredProtocol> {
+        self.protocols
+            .iter()
+            .find(|registered| registered.protocol() == *protocol)
+    }
+
+    /// Every registered protocol
+    pub fn protocols(&self) -> Vec<Protocol> {
+        self.protocols
+            .iter()
+            .map(|registered| registered.protocol())
+            .collect()
+    }
+
+    /// Volatility of every registered protocol over `window` of its market history
+    pub async fn window_volatility(
+        &self,
+        window: &VolatilityWindow,
+    ) -> Vec<ProtocolWindowVolatility> {
+        let weights = &RiskWeightsConfig::global().volatility;
+        // A sample more than the window, the first one can fall before it
+        let from =
+            Utc::now() - chrono::Duration::hours((window.hours + window.frequency.hours()) as i64);
+        join_all(self.protocols.iter().map(|registered| async move {
+            let volatility = registered.market_history(from).await.and_then(|points| {
+                calculate_window_volatility(&points, window, weights.apy, weights.utilization)
+            });
+            if let Err(e) = &volatility {
+                tracing::warn!(
+                    "Failed to compute the volatility window of {}: {}",
+                    registered.protocol(),
+                    e
+                );
+            }
+            ProtocolWindowVolatility {
+                protocol: registered.protocol(),
+                error: volatility.as_ref().err().map(ToString::to_string),
+                volatility: volatility.ok(),
+            }
+        }))
+        .await
+    }
+}
+
+impl ProtocolRegistry {
+    /// Combined scope of every registered protocol
+    pub fn scope(&self) -> String {
+        self.protocols
+            .iter()
+            .map(|protocol| protocol.scope())
+            .collect::<Vec<_>>()
+            .join("+")
+    }
+
+    /// Returns the latest internally consistent snapshot of all protocol metrics
+    ///
+    /// Serves the stored snapshot when one exists for the current hour, otherwise
+    /// computes a new one with [`Self::refresh`], once for concurrent requests.
+    /// A degraded snapshot is computed again, retrying its unavailable protocols.
+    pub async fn snapshot(&self) -> Result<RiskSnapshot, RiskCalculationError> {
+        let scope = self.scope();
+        let cache = CacheBackend::new(&self.redis_client);
+        let current = || async {
+            match load_latest_snapshot(&cache, &scope).await {
+                Ok(Some(snapshot)) if is_served(&snapshot, process_clock().now()) => Some(snapshot),
+                Ok(_) => None,
+                Err(e) => {
+                    tracing::error!("Failed to load risk snapshot: {}", e);
+                    None
+                }
+            }
+        };
+        if let Some(snapshot) = current().await {
+            return Ok(snapshot);
+        }
+        compute_once(
+            cache.redis_client(),
+            &latest_snapshot_key(&scope),
+            current,
+            self.refresh(),
+        )
+        .await
+    }
+
+    /// Returns the latest stored snapshot without computing anything
+    ///
+    /// Used for scopes the background refresh keeps up to date; the previous
+    /// hour's snapshot is served while the current one is being computed.
+    pub async fn cached_snapshot(&self) -> Result<RiskSnapshot, RiskCalculationError> {
+        load_latest_snapshot(&CacheBackend::new(&self.redis_client), &self.scope())
+            .await?
+            .ok_or(RiskCalculationError::NotReady(
+                "Risk snapshot hasn't been computed yet".to_string(),
+            ))
+    }
+
+    /// Compares all protocols and stores the result as the latest snapshot
+    ///
+    /// Snapshots with unavailable protocols are stored too, so the weights
+    /// derived from the latest snapshot renormalize around them instead of
+    /// keeping their last weights. [`Self::snapshot`] doesn't serve them as
+    /// current, so the failing protocols are retried. The protocols that were
+    /// assessed are added to the risk history either way.
+    pub async fn refresh(&self) -> Result<RiskSnapshot, RiskCalculationError> {
+        let comparison = self.compare().await?;
+        self.publish(
+            &CacheBackend::new(&self.redis_client),
+            comparison,
+            process_clock().now(),
+        )
+        .await
+    }
+
+    /// Records the assessed protocols of `comparison` and stores it in `cache`
+    /// as the latest snapshot, see [`Self::refresh`]
+    async fn publish(
+        &self,
+        cache: &CacheBackend<'_>,
+        comparison: ProtocolComparison,
+        now: DateTime<Utc>,
+    ) -> Result<RiskSnapshot, RiskCalculationError> {
+        let snapshot = RiskSnapshot::new(comparison, now);
+        if let Err(e) = record_history(
+            &self.redis_client,
+            snapshot.computed_at,
+            &snapshot.comparison.ranking,
+        )
+        .await
+        {
+            tracing::error!("Failed to record risk history: {}", e);
+        }
+        store_snapshot(cache, &self.scope(), &snapshot, now).await?;
+        Ok(snapshot)
+    }
+}
+
+/// Whether [`ProtocolRegistry::snapshot`] serves a stored `snapshot` at `now`
+///
+/// Only complete snapshots of the current hour are, degraded ones are stored
+/// for the weights derived from them but computed again on request.
+fn is_served(snapshot: &RiskSnapshot, now: DateTime<Utc>) -> bool {
+    snapshot.is_current(now) && !snapshot.is_degraded()
+}
+
+/// Ranks the protocols that were assessed and reports the others as unavailable
+///
+/// Errors when no protocol could be assessed at all.
+fn comparison_of(
+    results: Vec<(Protocol, String, Result<RiskResponse, RiskCalculationError>)>,
+) -> Result<ProtocolComparison, RiskCalculationError> {
+    let registered = results.len();
+    let mut ranking = Vec::new();
+    let mut unavailable = Vec::new();
+    for (protocol, scope, result) in results {
+        match result {
+            Ok(risk_metrics) => ranking.push(ProtocolAssessment {
+                protocol,
+                scope,
+                risk_metrics,
+            }),
+            Err(e) => {
+                tracing::error!("Failed to compute risk for {:?}: {}", protocol, e);
+                unavailable.push(UnavailableProtocol {
+                    protocol,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    if ranking.is_empty() {
+        return Err(RiskCalculationError::CustomError(format!(
+            "Risk could not be computed for any of the {} registered protocols",
+            registered
+        )));
+    }
+    rank_assessments(&mut ranking);
+
+    Ok(ProtocolComparison {
+        ranking,
+        unavailable,
+    })
+}
+
+/// Orders the partially assessed protocols from lowest to highest overall risk
+/// over their computed pillars and reports the others as unavailable
+fn partial_comparison_of(
+    results: Vec<(
+        Protocol,
+        String,
+        Result<PartialRiskResponse, RiskCalculationError>,
+    )>,
+) -> (Vec<PartialAssessment>, Vec<UnavailableProtocol>) {
+    let mut ranking = Vec::new();
+    let mut unavailable = Vec::new();
+    for (protocol, scope, result) in results {
+        match result {
+            Ok(risk_metrics) => ranking.push(PartialAssessment {
+                protocol,
+                scope,
+                risk_metrics,
+            }),
+            Err(e) => {
+                tracing::error!("Failed to compute partial risk for {:?}: {}", protocol, e);
+                unavailable.push(UnavailableProtocol {
+                    protocol,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+    ranking.sort_by(|a, b| {
+        a.risk_metrics
+            .overall_risk
+            .overall_risk
+            .total_cmp(&b.risk_metrics.overall_risk.overall_risk)
+    });
+    (ranking, unavailable)
+}
+
+/// Orders assessments from lowest to highest overall risk
+fn rank_assessments(assessments: &mut [ProtocolAssessment]) {
+    assessments.sort_by(|a, b| {
+        a.risk_metrics
+            .overall_risk
+            .overall_risk
+            .total_cmp(&b.risk_metrics.overall_risk.overall_risk)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::{
+        cache::MemoryCache,
+        kamino::sources::{fixtures::FixtureSources, YieldHistorySource},
+        marginfi::yield_data::fixture_yield_and_utilization_rates,
+        risk_model::{RiskScore, VolatilityRiskMetrics, SCHEMA_VERSION},
+        volatility_risk::calculate_lending_pool_risk,
+    };
+
+    /// Volatility of a fixture history, the pillar the fixture risks are scored on
+    fn fixture_volatility(yields: Vec<f64>, utilization_rates: Vec<f64>) -> VolatilityRiskMetrics {
+        let weights = RiskWeightsConfig::default().volatility;
+        calculate_lending_pool_risk(yields, utilization_rates, weights.apy, weights.utilization)
+            .unwrap()
+    }
+
+    /// Volatility of the Kamino and marginfi USDC fixture histories
+    async fn fixture_volatilities() -> (VolatilityRiskMetrics, VolatilityRiskMetrics) {
+        let kamino = FixtureSources::usdc()
+            .yield_and_utilization_rates(&KaminoReserveConfig::usdc())
+            .await
+            .unwrap();
+        let marginfi = fixture_yield_and_utilization_rates().unwrap();
+        (
+            fixture_volatility(kamino.yields_percent, kamino.utilization_rates_percent),
+            fixture_volatility(marginfi.yields_percent, marginfi.utilization_rates_percent),
+        )
+    }
+
+    fn overall_risk(volatility_risk: &VolatilityRiskMetrics) -> RiskScore {
+        RiskScore::from_raw(
+            &RiskWeightsConfig::default(),
+            None,
+            Some(volatility_risk.volatility_risk),
+            None,
+            None,
+        )
+        .unwrap()
+    }
+
+    fn risk_response(volatility_risk: VolatilityRiskMetrics) -> RiskResponse {
+        RiskResponse {
+            overall_risk: overall_risk(&volatility_risk),
+            volatility_risk,
+            ..RiskResponse::default()
+        }
+    }
+
+    fn partial_risk_response(volatility_risk: VolatilityRiskMetrics) -> PartialRiskResponse {
+        PartialRiskResponse {
+            schema_version: SCHEMA_VERSION,
+            liquidity_risk: None,
+            overall_risk: overall_risk(&volatility_risk),
+            volatility_risk: Some(volatility_risk),
+            protocol_risk: None,
+            oracle_risk: None,
+            computed_at: None,
+            stale: false,
+            errors: Vec::new(),
+        }
+    }
+
+    fn unavailable(
+        protocol: Protocol,
+    ) -> (Protocol, String, Result<RiskResponse, RiskCalculationError>) {
+        (
+            protocol,
+            String::new(),
+            Err(RiskCalculationError::UpstreamUnavailable(
+                "no data".to_string(),
+            )),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_comparison_ranks_assessed_protocols() {
+        let (kamino, marginfi) = fixture_volatilities().await;
+        // The daily marginfi history swings more than Kamino's hourly one
+        assert!(
+            overall_risk(&kamino).overall_risk < overall_risk(&marginfi).overall_risk,
+            "{} >= {}",
+            overall_risk(&kamino).overall_risk,
+            overall_risk(&marginfi).overall_risk
+        );
+        let riskiest = RiskResponse {
+            overall_risk: RiskScore {
+                overall_risk: 100.0,
+                ..RiskScore::default()
+            },
+            ..RiskResponse::default()
+        };
+
+        let comparison = comparison_of(vec![
+            (Protocol::Drift, "drift".to_string(), Ok(riskiest)),
+            unavailable(Protocol::Solend),
+            (
+                Protocol::Kamino,
+                "kamino".to_string(),
+                Ok(risk_response(kamino)),
+            ),
+            (
+                Protocol::Marginfy,
+                "marginfi".to_string(),
+                Ok(risk_response(marginfi)),
+            ),
+        ])
+        .unwrap();
+        let ranking: Vec<_> = comparison
+            .ranking
+            .iter()
+            .map(|assessment| (assessment.protocol.clone(), assessment.scope.as_str()))
+            .collect();
+        assert_eq!(
+            ranking,
+            vec![
+                (Protocol::Kamino, "kamino"),
+                (Protocol::Marginfy, "marginfi"),
+                (Protocol::Drift, "drift"),
+            ]
+        );
+        assert_eq!(comparison.chosen().unwrap().protocol, Protocol::Kamino);
+        assert_eq!(comparison.unavailable.len(), 1);
+        assert_eq!(comparison.unavailable[0].protocol, Protocol::Solend);
+        assert_eq!(
+            comparison.unavailable[0].error,
+            "Upstream unavailable: no data"
+        );
+
+        // Nothing to rank
+        assert!(comparison_of(vec![unavailable(Protocol::Kamino)]).is_err());
+        assert!(comparison_of(Vec::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_partial_comparison_splits_unavailable() {
+        let (kamino, marginfi) = fixture_volatilities().await;
+        let (ranking, unavailable) = partial_comparison_of(vec![
+            (
+                Protocol::Marginfy,
+                "marginfi".to_string(),
+                Ok(partial_risk_response(marginfi)),
+            ),
+            (
+                Protocol::Drift,
+                "drift".to_string(),
+                Err(RiskCalculationError::StaleData("too old".to_string())),
+            ),
+            (
+                Protocol::Kamino,
+                "kamino".to_string(),
+                Ok(partial_risk_response(kamino)),
+            ),
+        ]);
+        let ranked: Vec<_> = ranking
+            .iter()
+            .map(|assessment| assessment.protocol.clone())
+            .collect();
+        assert_eq!(ranked, vec![Protocol::Kamino, Protocol::Marginfy]);
+        assert!(ranking[0].risk_metrics.liquidity_risk.is_none());
+        assert_eq!(unavailable.len(), 1);
+        assert_eq!(unavailable[0].protocol, Protocol::Drift);
+        assert_eq!(unavailable[0].error, "Stale data: too old");
+
+        // Partial comparisons don't error when nothing could be assessed
+        let (ranking, unavailable) = partial_comparison_of(vec![(
+            Protocol::Kamino,
+            String::new(),
+            Err(RiskCalculationError::NotReady("scanning".to_string())),
+        )]);
+        assert!(ranking.is_empty());
+        assert_eq!(unavailable.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_degraded_snapshot_is_stored_but_not_served() {
+        // Unreachable, the risk history isn't recorded
+        let redis_client = redis::Client::open("redis://127.0.0.1:1/").unwrap();
+        let registry =
+            ProtocolRegistry::with_all_protocols(redis_client, KaminoReserveConfig::usdc());
+        let memory: &'static MemoryCache = Box::leak(Box::default());
+        let cache = CacheBackend::Memory(memory);
+        let (kamino, marginfi) = fixture_volatilities().await;
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 13, 2, 3).unwrap();
+
+        let degraded = comparison_of(vec![
+            (
+                Protocol::Kamino,
+                "kamino".to_string(),
+                Ok(risk_response(kamino.clone())),
+            ),
+            unavailable(Protocol::Marginfy),
+        ])
+        .unwrap();
+        let snapshot = registry.publish(&cache, degraded, now).await.unwrap();
+        assert!(snapshot.is_degraded());
+        let stored = load_latest_snapshot(&cache, &registry.scope())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.snapshot_id, snapshot.snapshot_id);
+        assert_eq!(stored.comparison.ranking.len(), 1);
+        assert_eq!(
+            stored.comparison.unavailable[0].protocol,
+            Protocol::Marginfy
+        );
+        // Stored for the weights, but computed again on request
+        assert!(stored.is_current(now));
+        assert!(!is_served(&stored, now));
+
+        let later = now + chrono::Duration::minutes(1);
+        let complete = comparison_of(vec![
+            (
+                Protocol::Kamino,
+                "kamino".to_string(),
+                Ok(risk_response(kamino)),
+            ),
+            (
+                Protocol::Marginfy,
+                "marginfi".to_string(),
+                Ok(risk_response(marginfi)),
+            ),
+        ])
+        .unwrap();
+        let snapshot = registry.publish(&cache, complete, later).await.unwrap();
+        let stored = load_latest_snapshot(&cache, &registry.scope())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.snapshot_id, snapshot.snapshot_id);
+        assert!(is_served(&stored, later));
+        // Not past the hour it was computed in
+        assert!(!is_served(
+            &stored,
+            Utc.with_ymd_and_hms(2024, 5, 1, 14, 0, 0).unwrap()
+        ));
+    }
+}
@@ -0,0 +1,435 @@
+use std::{fmt::Write, sync::OnceLock};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alerts::{post_webhook, webhook_url_from_env, AlertMetric},
+    cache_schema::versioned_key,
+    history::{load_history, RiskHistoryPoint},
+    kamino::reserve::KaminoReserveConfig,
+    redis_connection::shared_connection,
+    registry::ProtocolRegistry,
+    risk_model::{json_response, timings_requested, Protocol, RiskCalculationError},
+    state::AppState,
+    timings::with_timings,
+};
+
+static REPORT_CONFIG: OnceLock<ReportConfig> = OnceLock::new();
+
+/// How long reports are kept, like the history they're made of
+const REPORT_RETENTION_DAYS: i64 = 90;
+/// Move of a score between consecutive points from which it's called out
+const NOTABLE_CHANGE_POINTS: f64 = 5.0;
+/// Scores summarized by reports, all of them between 0 and 100
+const REPORTED_METRICS: [AlertMetric; 5] = [
+    AlertMetric::OverallRisk,
+    AlertMetric::LiquidityRisk,
+    AlertMetric::VolatilityRisk,
+    AlertMetric::ProtocolRisk,
+    AlertMetric::OracleRisk,
+];
+
+/// Where daily reports are pushed, from the environment
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReportConfig {
+    /// Receives every new report, see [`post_webhook`]
+    pub webhook_url: Option<String>,
+}
+
+impl ReportConfig {
+    /// The configuration read at first use
+    pub fn global() -> &'static Self {
+        REPORT_CONFIG.get_or_init(|| Self::from_env().expect("report configuration must be valid"))
+    }
+
+    /// Reads `REPORT_WEBHOOK_URL`, reports aren't pushed when it isn't set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        Ok(ReportConfig {
+            webhook_url: webhook_url_from_env("REPORT_WEBHOOK_URL")?,
+        })
+    }
+}
+
+/// Range of a score over the day
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ScoreSummary {
+    pub metric: AlertMetric,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    /// Last point of the day minus the first
+    pub change: f64,
+}
+
+/// A score that moved by at least [`NOTABLE_CHANGE_POINTS`] within an hour
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NotableChange {
+    pub metric: AlertMetric,
+    pub at: DateTime<Utc>,
+    pub from: f64,
+    pub to: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolReport {
+    pub protocol: Protocol,
+    pub scope: String,
+    /// Hourly points of the day in the history
+    pub points: usize,
+    /// Empty without points
+    pub scores: Vec<ScoreSummary>,
+    /// Oldest first
+    pub notable_changes: Vec<NotableChange>,
+}
+
+impl ProtocolReport {
+    /// Summarizes `points`, oldest first
+    pub fn new(protocol: Protocol, scope: String, points: &[RiskHistoryPoint]) -> Self {
+        let mut scores = Vec::new();
+        let mut notable_changes = Vec::new();
+        if let (Some(first), Some(last)) = (points.first(), points.last()) {
+            for metric in REPORTED_METRICS {
+                let values: Vec<f64> = points
+                    .iter()
+                    .map(|point| metric.value(&point.risk_metrics))
+                    .collect();
+                scores.push(ScoreSummary {
+                    metric,
+                    min: values.iter().copied().fold(f64::INFINITY, f64::min),
+                    max: values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                    avg: values.iter().sum::<f64>() / values.len() as f64,
+                    change: metric.value(&last.risk_metrics) - metric.value(&first.risk_metrics),
+                });
+            }
+            for pair in points.windows(2) {
+                for metric in REPORTED_METRICS {
+                    let from = metric.value(&pair[0].risk_metrics);
+                    let to = metric.value(&pair[1].risk_metrics);
+                    if (to - from).abs() >= NOTABLE_CHANGE_POINTS {
+                        notable_changes.push(NotableChange {
+                            metric,
+                            at: pair[1].computed_at,
+                            from,
+                            to,
+                        });
+                    }
+                }
+            }
+        }
+        ProtocolReport {
+            protocol,
+            scope,
+            points: points.len(),
+            scores,
+            notable_changes,
+        }
+    }
+}
+
+/// Risk of every protocol over a day, from the stored risk history
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DailyReport {
+    pub date: NaiveDate,
+    pub generated_at: DateTime<Utc>,
+    pub protocols: Vec<ProtocolReport>,
+}
+
+impl DailyReport {
+    /// Start and end (exclusive) of `date`, in UTC
+    ///
+    /// Fails for the last day chrono can represent, which has no end.
+    pub fn day(date: NaiveDate) -> Result<(DateTime<Utc>, DateTime<Utc>), RiskCalculationError> {
+        let start = date.and_time(chrono::NaiveTime::MIN).and_utc();
+        let end = start.checked_add_signed(Duration::days(1)).ok_or_else(|| {
+            RiskCalculationError::InvalidParameter(format!("{} is out of range", date))
+        })?;
+        Ok((start, end))
+    }
+
+    /// Start and end of `date` if it can be reported on at `now`: it's over,
+    /// and its history is still kept
+    pub fn reportable_day(
+        date: NaiveDate,
+        now: DateTime<Utc>,
+    ) -> Result<(DateTime<Utc>, DateTime<Utc>), RiskCalculationError> {
+        let (start, end) = Self::day(date)?;
+        if end > now {
+            return Err(RiskCalculationError::NotFound(format!(
+                "{} isn't over yet",
+                date
+            )));
+        }
+        if start < now - Duration::days(REPORT_RETENTION_DAYS) {
+            return Err(RiskCalculationError::InvalidParameter(format!(
+                "{} is older than the {} days of history kept",
+                date, REPORT_RETENTION_DAYS
+            )));
+        }
+        Ok((start, end))
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut markdown = format!("# Daily risk report, {}\n", self.date);
+        for protocol in &self.protocols {
+            let _ = write!(
+                markdown,
+                "\n## {:?} ({})\n\n{} hourly points\n",
+                protocol.protocol, protocol.scope, protocol.points
+            );
+            if !protocol.scores.is_empty() {
+                markdown
+                    .push_str("\n| Score | Min | Max | Avg | Change |\n|---|---|---|---|---|\n");
+                for score in &protocol.scores {
+                    let _ = writeln!(
+                        markdown,
+                        "| {} | {:.2} | {:.2} | {:.2} | {:+.2} |",
+                        score.metric.as_str(),
+                        score.min,
+                        score.max,
+                        score.avg,
+                        score.change
+                    );
+                }
+            }
+            if !protocol.notable_changes.is_empty() {
+                markdown.push_str("\nNotable changes:\n\n");
+                for change in &protocol.notable_changes {
+                    let _ = writeln!(
+                        markdown,
+                        "- {} {} {:.2} -> {:.2}",
+                        change.at.format("%H:%M UTC"),
+                        change.metric.as_str(),
+                        change.from,
+                        change.to
+                    );
+                }
+            }
+        }
+        markdown
+    }
+}
+
+fn report_key(date: NaiveDate) -> String {
+    versioned_key(&format!("report:daily:{}", date))
+}
+
+/// Summarizes the history of every protocol over `date`
+pub async fn generate_daily_report(
+    redis_client: &redis::Client,
+    kamino_reserve: KaminoReserveConfig,
+    date: NaiveDate,
+) -> Result<DailyReport, RiskCalculationError> {
+    let registry = ProtocolRegistry::with_all_protocols(redis_client.clone(), kamino_reserve);
+    let (start, end) = DailyReport::day(date)?;
+    let mut protocols = Vec::new();
+    for protocol in registry.protocols() {
+        let Some(registered) = registry.registered(&protocol) else {
+            continue;
+        };
+        let scope = registered.scope();
+        // The history's range is inclusive
+        let points = load_history(redis_client, &scope, start, end - Duration::seconds(1)).await?;
+        protocols.push(ProtocolReport::new(protocol, scope, &points));
+    }
+    Ok(DailyReport {
+        date,
+        generated_at: Utc::now(),
+        protocols,
+    })
+}
+
+pub async fn store_report(
+    redis_client: &redis::Client,
+    report: &DailyReport,
+) -> Result<(), RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let value = serde_json::to_string(report).map_err(RiskCalculationError::SerdeError)?;
+    connection
+        .set_ex::<_, _, ()>(
+            report_key(report.date),
+            value,
+            Duration::days(REPORT_RETENTION_DAYS).num_seconds() as u64,
+        )
+        .await
+        .map_err(RiskCalculationError::from)
+}
+
+pub async fn load_report(
+    redis_client: &redis::Client,
+    date: NaiveDate,
+) -> Result<Option<DailyReport>, RiskCalculationError> {
+    let mut connection = shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    let stored: Option<String> = connection
+        .get(report_key(date))
+        .await
+        .map_err(RiskCalculationError::from)?;
+    stored
+        .map(|stored| serde_json::from_str(&stored).map_err(RiskCalculationError::SerdeError))
+        .transpose()
+}
+
+/// Generates, stores and pushes the report of `date`, unless it already was
+///
+/// Failing to push the report is only logged, it can still be read.
+pub async fn publish_daily_report(
+    redis_client: &redis::Client,
+    kamino_reserve: KaminoReserveConfig,
+    date: NaiveDate,
+) -> Result<(), RiskCalculationError> {
+    if load_report(redis_client, date).await?.is_some() {
+        return Ok(());
+    }
+    let report = generate_daily_report(redis_client, kamino_reserve, date).await?;
+    store_report(redis_client, &report).await?;
+    tracing::info!("Daily risk report of {} generated", date);
+    if let Some(url) = &ReportConfig::global().webhook_url {
+        if let Err(e) = post_webhook(url, report.to_markdown(), &report).await {
+            tracing::error!("Failed to push the daily risk report of {}: {}", date, e);
+        }
+    }
+    Ok(())
+}
+
+/// Body of `GET /reports/daily/:date`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    #[default]
+    Json,
+    Markdown,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReportQuery {
+    #[serde(default)]
+    pub format: ReportFormat,
+    /// `timings` adds a latency breakdown to JSON responses
+    pub debug: Option<String>,
+}
+
+/// The daily report of a past day, `YYYY-MM-DD`
+///
+/// Days the scheduler didn't report on, e.g. while the service was down, are
+/// reported on from the history when first requested.
+pub async fn daily_report(
+    State(state): State<AppState>,
+    Path(date): Path<String>,
+    Query(query): Query<ReportQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let date = NaiveDate::parse_from_str(&date, "%Y-%m-%d").map_err(|e| {
+            RiskCalculationError::InvalidParameter(format!(
+                "date must be YYYY-MM-DD, got {:?}: {}",
+                date, e
+            ))
+        })?;
+        DailyReport::reportable_day(date, Utc::now())?;
+        if let Some(report) = load_report(&state.redis_client, date).await? {
+            return Ok(report);
+        }
+        let report = generate_daily_report(
+            &state.redis_client,
+            state.config.kamino_reserve.clone(),
+            date,
+        )
+        .await?;
+        store_report(&state.redis_client, &report).await?;
+        Ok(report)
+    })
+    .await;
+
+    match (query.format, result) {
+        (ReportFormat::Markdown, Ok(report)) => (
+            [(header::CONTENT_TYPE, "text/markdown; charset=utf-8")],
+            report.to_markdown(),
+        )
+            .into_response(),
+        (_, result) => json_response(result, timings_requested(&query.debug).then_some(timings)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::risk_model::{
+        LiquidityRiskMetrics, RiskResponse, RiskScore, VolatilityRiskMetrics, SCHEMA_VERSION,
+    };
+
+    fn point(hour: i64, overall_risk: f64) -> RiskHistoryPoint {
+        let date = NaiveDate::from_ymd_opt(2024, 5, 1).unwrap();
+        RiskHistoryPoint {
+            computed_at: DailyReport::day(date).unwrap().0 + Duration::hours(hour),
+            risk_metrics: RiskResponse {
+                schema_version: SCHEMA_VERSION,
+                liquidity_risk: LiquidityRiskMetrics {
+                    total_borrows: 80.0,
+                    total_supply: 100.0,
+                    utilization_rate: 80.0,
+                    deposit_concentration: 0.1,
+                    liquidity_risk: 30.0,
+                    ..Default::default()
+                },
+                volatility_risk: VolatilityRiskMetrics {
+                    volatility_risk: 20.0,
+                    ..Default::default()
+                },
+                overall_risk: RiskScore {
+                    overall_risk,
+                    ..RiskScore::default()
+                },
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_daily_report() {
+        let points = [point(0, 20.0), point(1, 22.0), point(2, 30.0)];
+        let report = ProtocolReport::new(Protocol::Kamino, "kamino:reserve".into(), &points);
+        assert_eq!(report.points, 3);
+        let overall = report.scores[0];
+        assert_eq!(overall.metric, AlertMetric::OverallRisk);
+        assert_eq!((overall.min, overall.max, overall.avg), (20.0, 30.0, 24.0));
+        assert_eq!(overall.change, 10.0);
+        assert_eq!(report.scores[1].change, 0.0);
+        assert_eq!(
+            report.notable_changes,
+            vec![NotableChange {
+                metric: AlertMetric::OverallRisk,
+                at: points[2].computed_at,
+                from: 22.0,
+                to: 30.0,
+            }]
+        );
+
+        let empty = ProtocolReport::new(Protocol::Marginfy, "marginfi".into(), &[]);
+        assert!(empty.scores.is_empty());
+
+        let now: DateTime<Utc> = "2024-05-02T12:00:00Z".parse().unwrap();
+        let day = |date: &str| NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap();
+        assert!(DailyReport::reportable_day(day("2024-05-01"), now).is_ok());
+        assert!(DailyReport::reportable_day(day("2024-05-02"), now).is_err());
+        assert!(DailyReport::reportable_day(day("2023-01-01"), now).is_err());
+        assert!(DailyReport::day(NaiveDate::MAX).is_err());
+        let markdown = DailyReport {
+            date: NaiveDate::from_ymd_opt(2024, 5, 1).unwrap(),
+            generated_at: Utc::now(),
+            protocols: vec![report, empty],
+        }
+        .to_markdown();
+        assert!(markdown.starts_with("# Daily risk report, 2024-05-01\n"));
+        assert!(markdown.contains("| overall_risk | 20.00 | 30.00 | 24.00 | +10.00 |"));
+        assert!(markdown.contains("- 02:00 UTC overall_risk 22.00 -> 30.00"));
+    }
+}
@@ -1,30 +1,222 @@
 #![allow(unused)]
-use std::fmt::Display;
+use std::{fmt::Display, future::Future};
 
-use axum::response::{IntoResponse, Response};
+use axum::{
+    extract::{Query, RawQuery, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, DurationRound, Utc};
 use redis::AsyncCommands;
-use serde::Serialize;
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{Deserialize, Serialize};
+use tracing::Instrument;
 
-use crate::kamino::KaminoRisk;
+use crate::{
+    bps::Bps,
+    cache::{Cache, CacheBackend, CacheKind},
+    cache_lock::{compute_if_unlocked, compute_once},
+    clock::{process_clock, seconds_until_next_hour, Clock, SystemClock},
+    conditional::{conditional_response, Validators},
+    encoding::ResponseFormat,
+    explain::{attribute, explain_choice, Locale, PillarAttribution},
+    incidents::{load_penalties, PenaltyStatus},
+    kamino::reserve::KaminoReserveConfig,
+    liquidity_risk::{BorrowerConcentration, ConcentrationDenominator, LiquidationRiskMetrics},
+    oracle_risk::OracleRiskMetrics,
+    precomputed::precomputed_risk_model,
+    privacy::PrivacyMode,
+    protocol_rubric::ComponentScore,
+    quorum::QuorumReport,
+    registry::{
+        PartialAssessment, ProtocolAssessment, ProtocolRegistry, ProtocolWindowVolatility,
+        UnavailableProtocol,
+    },
+    rpc_pool::DataCompleteness,
+    shutdown,
+    snapshot::RiskSnapshot,
+    state::AppState,
+    timings::{record_recomputed, timed, with_timings, Timing, TimingsReport},
+    volatility_risk::{DownsideRisk, HistoryWindow, LookbackVolatility, VolatilityWindow},
+    weights::RiskWeightsConfig,
+};
 
 /// Risk profile types available to users
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+///
+/// Serialized as a string so profiles can key maps: the preset's name, or
+/// `Custom:<target_risk>:<max_per_protocol_bps>`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
 pub enum RiskProfile {
     Low,
     Medium,
     High,
+    /// Weights whose average overall risk matches `target_risk`, no protocol above the cap
+    Custom {
+        target_risk: f64,
+        max_per_protocol_bps: u64,
+    },
 }
+impl RiskProfile {
+    /// The preset profiles
+    pub const ALL: [RiskProfile; 3] = [RiskProfile::Low, RiskProfile::Medium, RiskProfile::High];
+
+    /// Fails on a negative or non-finite target and a cap outside (0, 10,000]
+    pub fn custom(
+        target_risk: f64,
+        max_per_protocol_bps: u64,
+    ) -> Result<Self, RiskCalculationError> {
+        if !target_risk.is_finite() || target_risk < 0.0 {
+            return Err(RiskCalculationError::InvalidParameter(format!(
+                "target risk must be a non-negative number, got {}",
+                target_risk
+            )));
+        }
+        if max_per_protocol_bps == 0 || max_per_protocol_bps > 10_000 {
+            return Err(RiskCalculationError::InvalidParameter(format!(
+                "max_per_protocol_bps must be within 1 and 10000, got {}",
+                max_per_protocol_bps
+            )));
+        }
+        Ok(RiskProfile::Custom {
+            target_risk,
+            max_per_protocol_bps,
+        })
+    }
+
+    /// Lowercase name used in paths and keys
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RiskProfile::Low => "low",
+            RiskProfile::Medium => "medium",
+            RiskProfile::High => "high",
+            RiskProfile::Custom { .. } => "custom",
+        }
+    }
+
+    /// The profile as [`RiskProfile::from_param`] reads it, with the parameters
+    /// of a custom profile
+    pub fn to_param(&self) -> String {
+        match self {
+            RiskProfile::Custom {
+                target_risk,
+                max_per_protocol_bps,
+            } => format!("custom:{}:{}", target_risk, max_per_protocol_bps),
+            preset => preset.as_str().to_string(),
+        }
+    }
+
+    /// Reads a profile from a path segment, case insensitive
+    ///
+    /// Custom profiles are given as `custom:<target_risk>:<max_per_protocol_bps>`.
+    pub fn from_param(profile: &str) -> Result<Self, RiskCalculationError> {
+        let mut parts = profile.split(':');
+        if parts
+            .next()
+            .is_some_and(|name| name.eq_ignore_ascii_case("custom"))
+        {
+            return match (parts.next(), parts.next(), parts.next()) {
+                (Some(target_risk), Some(max_per_protocol_bps), None) => Self::custom(
+                    target_risk.trim().parse().map_err(|e| {
+                        RiskCalculationError::InvalidParameter(format!("target risk: {}", e))
+                    })?,
+                    max_per_protocol_bps.trim().parse().map_err(|e| {
+                        RiskCalculationError::InvalidParameter(format!(
+                            "max_per_protocol_bps: {}",
+                            e
+                        ))
+                    })?,
+                ),
+                _ => Err(RiskCalculationError::InvalidParameter(format!(
+                    "expected custom:<target_risk>:<max_per_protocol_bps>, got {:?}",
+                    profile
+                ))),
+            };
+        }
+        Self::ALL
+            .into_iter()
+            .find(|candidate| candidate.as_str().eq_ignore_ascii_case(profile))
+            .ok_or(RiskCalculationError::InvalidParameter(format!(
+                "unknown risk profile {:?}, expected low, medium, high or custom",
+                profile
+            )))
+    }
+}
+
+// Custom profiles are only created with a finite target, which makes equality reflexive
+impl Eq for RiskProfile {}
+
+impl std::hash::Hash for RiskProfile {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        if let RiskProfile::Custom {
+            target_risk,
+            max_per_protocol_bps,
+        } = self
+        {
+            // Adding zero turns -0.0 into 0.0, which compare equal
+            (target_risk + 0.0).to_bits().hash(state);
+            max_per_protocol_bps.hash(state);
+        }
+    }
+}
+
+impl From<RiskProfile> for String {
+    fn from(profile: RiskProfile) -> Self {
+        match profile {
+            RiskProfile::Low => "Low".to_string(),
+            RiskProfile::Medium => "Medium".to_string(),
+            RiskProfile::High => "High".to_string(),
+            RiskProfile::Custom {
+                target_risk,
+                max_per_protocol_bps,
+            } => format!("Custom:{}:{}", target_risk, max_per_protocol_bps),
+        }
+    }
+}
+
+impl TryFrom<String> for RiskProfile {
+    type Error = RiskCalculationError;
+
+    fn try_from(profile: String) -> Result<Self, Self::Error> {
+        Self::from_param(&profile)
+    }
+}
+
+impl JsonSchema for RiskProfile {
+    fn schema_name() -> String {
+        "RiskProfile".to_string()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let mut schema = String::json_schema(gen).into_object();
+        schema.metadata().description =
+            Some("Low, Medium, High or Custom:<target_risk>:<max_per_protocol_bps>".to_string());
+        schema.into()
+    }
+}
+
 impl Display for RiskProfile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             RiskProfile::Low => write!(f, "Low\t"),
             RiskProfile::Medium => write!(f, "Medium\t"),
             RiskProfile::High => write!(f, "High\t"),
+            RiskProfile::Custom {
+                target_risk,
+                max_per_protocol_bps,
+            } => write!(
+                f,
+                "Custom (risk {}, cap {})\t",
+                target_risk,
+                Bps(*max_per_protocol_bps)
+            ),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
 pub enum Protocol {
     Kamino,
     Solend,
@@ -32,6 +224,32 @@ pub enum Protocol {
     Marginfy,
 }
 
+impl Protocol {
+    /// Lowercase name, as accepted by [`Self::from_param`]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Kamino => "kamino",
+            Protocol::Solend => "solend",
+            Protocol::Drift => "drift",
+            Protocol::Marginfy => "marginfi",
+        }
+    }
+
+    /// Reads a protocol from a query parameter or config value, case insensitive
+    pub fn from_param(protocol: &str) -> Result<Self, RiskCalculationError> {
+        match protocol.trim().to_lowercase().as_str() {
+            "kamino" => Ok(Protocol::Kamino),
+            "marginfi" | "marginfy" => Ok(Protocol::Marginfy),
+            "solend" => Ok(Protocol::Solend),
+            "drift" => Ok(Protocol::Drift),
+            other => Err(RiskCalculationError::InvalidParameter(format!(
+                "unsupported protocol {:?}",
+                other
+            ))),
+        }
+    }
+}
+
 impl Display for Protocol {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -49,37 +267,179 @@ pub enum ProtocolWithRisk {
     Drift(RiskScore),
     Marginfy(RiskScore),
 }
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum RiskCalculationError {
-    SerdeError(serde_json::Error),
+    #[error("Serde error: {0}")]
+    SerdeError(#[from] serde_json::Error),
+    #[error("Parse error: {0}")]
     ParseError(String),
-    RequestError(reqwest::Error),
-    RpcCallError(solana_client::client_error::ClientError),
-    RedisError(redis::RedisError),
+    #[error("Request error: {0}")]
+    RequestError(Box<reqwest::Error>),
+    #[error("RPC call error: {0}")]
+    RpcCallError(Box<solana_client::client_error::ClientError>),
+    #[error("Redis error: {0}")]
+    RedisError(Box<redis::RedisError>),
+    #[error("Custom error: {0}")]
     CustomError(String),
+    /// Requested data hasn't been computed yet, the client should retry later
+    #[error("Not ready: {0}")]
+    NotReady(String),
+    /// An admin command without a valid multisig approval
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+    /// An external API or RPC answered, but without the data needed
+    #[error("Upstream unavailable: {0}")]
+    UpstreamUnavailable(String),
+    /// The freshest data available is too old to score with
+    #[error("Stale data: {0}")]
+    StaleData(String),
+    /// A query parameter, path segment or body field the client sent is invalid
+    #[error("Invalid parameter: {0}")]
+    InvalidParameter(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    /// The request clashes with one still being processed, the client should retry later
+    #[error("Conflict: {0}")]
+    Conflict(String),
 }
-impl Display for RiskCalculationError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+
+impl From<reqwest::Error> for RiskCalculationError {
+    fn from(err: reqwest::Error) -> Self {
+        RiskCalculationError::RequestError(Box::new(err))
+    }
+}
+
+impl From<solana_client::client_error::ClientError> for RiskCalculationError {
+    fn from(err: solana_client::client_error::ClientError) -> Self {
+        RiskCalculationError::RpcCallError(Box::new(err))
+    }
+}
+
+impl From<redis::RedisError> for RiskCalculationError {
+    fn from(err: redis::RedisError) -> Self {
+        RiskCalculationError::RedisError(Box::new(err))
+    }
+}
+
+impl RiskCalculationError {
+    /// HTTP status handlers respond with for this error
+    ///
+    /// 502 when an upstream failed, 503 when data isn't available yet or the
+    /// cache can't be reached, both worth retrying.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            RiskCalculationError::InvalidParameter(_) => StatusCode::BAD_REQUEST,
+            RiskCalculationError::Unauthorized(_) => StatusCode::FORBIDDEN,
+            RiskCalculationError::NotFound(_) => StatusCode::NOT_FOUND,
+            RiskCalculationError::Conflict(_) => StatusCode::CONFLICT,
+            RiskCalculationError::RequestError(_)
+            | RiskCalculationError::RpcCallError(_)
+            | RiskCalculationError::UpstreamUnavailable(_) => StatusCode::BAD_GATEWAY,
+            RiskCalculationError::NotReady(_)
+            | RiskCalculationError::StaleData(_)
+            | RiskCalculationError::RedisError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            RiskCalculationError::SerdeError(_)
+            | RiskCalculationError::ParseError(_)
+            | RiskCalculationError::CustomError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine-readable code sent along the message, stable across releases
+    pub fn code(&self) -> &'static str {
         match self {
-            RiskCalculationError::SerdeError(e) => write!(f, "Serde error: {}", e),
-            RiskCalculationError::ParseError(e) => write!(f, "Parse error: {}", e),
-            RiskCalculationError::RequestError(e) => write!(f, "Request error: {}", e),
-            RiskCalculationError::RpcCallError(e) => write!(f, "RPC call error: {}", e),
-            RiskCalculationError::RedisError(e) => write!(f, "Redis error: {}", e),
-            RiskCalculationError::CustomError(e) => write!(f, "Custom error: {}", e),
+            RiskCalculationError::SerdeError(_) => "serde_error",
+            RiskCalculationError::ParseError(_) => "parse_error",
+            RiskCalculationError::RequestError(_) => "upstream_request_failed",
+            RiskCalculationError::RpcCallError(_) => "rpc_call_failed",
+            RiskCalculationError::RedisError(_) => "cache_unavailable",
+            RiskCalculationError::CustomError(_) => "internal_error",
+            RiskCalculationError::NotReady(_) => "not_ready",
+            RiskCalculationError::Unauthorized(_) => "unauthorized",
+            RiskCalculationError::UpstreamUnavailable(_) => "upstream_unavailable",
+            RiskCalculationError::StaleData(_) => "stale_data",
+            RiskCalculationError::InvalidParameter(_) => "invalid_parameter",
+            RiskCalculationError::NotFound(_) => "not_found",
+            RiskCalculationError::Conflict(_) => "conflict",
         }
     }
+
+    /// Body of error responses, `{"error": <message>, "code": <code>}`
+    pub fn body(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": self.to_string(),
+            "code": self.code(),
+        })
+    }
 }
 
-#[derive(Debug, Serialize)]
+impl IntoResponse for RiskCalculationError {
+    fn into_response(self) -> Response {
+        (self.status_code(), Json(self.body())).into_response()
+    }
+}
+
+/// Version of the risk and portfolio payloads
+///
+/// Bump it when a field or a formula changes in a way clients have to adapt
+/// to, along with the version in the path of the routes, see
+/// [`crate::server::API_PREFIX`].
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Schema of payloads stored before they were versioned
+fn unversioned_schema() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct RiskResponse {
+    /// [`SCHEMA_VERSION`] the response was computed with
+    #[serde(default = "unversioned_schema")]
+    pub schema_version: u32,
     pub liquidity_risk: LiquidityRiskMetrics,
     pub volatility_risk: VolatilityRiskMetrics,
     pub protocol_risk: ProtocolRiskMetrics,
+    /// Absent from responses computed before the pillar existed
+    #[serde(default)]
+    pub oracle_risk: OracleRiskMetrics,
     pub overall_risk: RiskScore,
+    /// When the oldest of the pillars was computed, absent from responses
+    /// computed before it was kept
+    #[serde(default)]
+    pub computed_at: Option<DateTime<Utc>>,
+    /// Whether a pillar was served past its TTL while it's being recomputed,
+    /// see [`CacheMode::StaleWhileRevalidate`]
+    #[serde(default)]
+    pub stale: bool,
 }
 
-#[derive(Debug, Serialize)]
+/// A pillar that couldn't be computed for a partial response
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PillarError {
+    pub pillar: Pillar,
+    /// See [`RiskCalculationError::code`]
+    pub code: String,
+    pub error: String,
+}
+
+/// Risk of a protocol from the pillars that could be computed, served with `?allow_partial=true`
+///
+/// Failed pillars are null and described in `errors`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PartialRiskResponse {
+    #[serde(default = "unversioned_schema")]
+    pub schema_version: u32,
+    pub liquidity_risk: Option<LiquidityRiskMetrics>,
+    pub volatility_risk: Option<VolatilityRiskMetrics>,
+    pub protocol_risk: Option<ProtocolRiskMetrics>,
+    pub oracle_risk: Option<OracleRiskMetrics>,
+    /// Over the computed pillars, their weights scaled to sum to 1
+    pub overall_risk: RiskScore,
+    pub computed_at: Option<DateTime<Utc>>,
+    pub stale: bool,
+    pub errors: Vec<PillarError>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct LiquidityRiskMetrics {
     pub total_borrows: f64,
     pub total_supply: f64,
@@ -88,136 +448,1835 @@ pub struct LiquidityRiskMetrics {
     pub total_deposits: u128,
     pub deposit_concentration: f64,
     pub liquidity_risk: f64,
+    /// How the data sources of `total_borrows` and `total_supply` compared
+    #[serde(default)]
+    pub data_quorum: Option<QuorumReport>,
+    /// Health of the market's borrowers, `None` where borrower accounts aren't scanned
+    #[serde(default)]
+    pub liquidation_risk: Option<LiquidationRiskMetrics>,
+    /// Scored with the deposit concentration when known, see [`scored_concentration`]
+    ///
+    /// [`scored_concentration`]: crate::liquidity_risk::scored_concentration
+    #[serde(default)]
+    pub borrower_concentration: Option<BorrowerConcentration>,
+    /// Change of the reserve's total supply over the last 24 hours, in percent,
+    /// `None` where its history isn't fetched
+    #[serde(default)]
+    pub tvl_change_24h_pct: Option<f64>,
+    #[serde(default)]
+    pub tvl_change_7d_pct: Option<f64>,
+    /// Liquidity not lent out, in token units, `None` where the caps aren't read
+    #[serde(default)]
+    pub available_liquidity: Option<f64>,
+    /// Share of the supply cap taken by `total_supply`, in percent, `None` without a cap
+    #[serde(default)]
+    pub supply_cap_utilization: Option<f64>,
+    /// Share of the borrow cap taken by `total_borrows`, in percent, `None` without a cap
+    #[serde(default)]
+    pub borrow_cap_utilization: Option<f64>,
+    /// Collateral minted by the reserve, comparable to `total_deposits`, `None`
+    /// unless read for [`ConcentrationDenominator::ReserveSupply`]
+    ///
+    /// [`ConcentrationDenominator::ReserveSupply`]: crate::liquidity_risk::ConcentrationDenominator::ReserveSupply
+    #[serde(default)]
+    pub reserve_collateral_supply: Option<u128>,
+    /// What `deposit_concentration` was divided by
+    #[serde(default)]
+    pub concentration_denominator: ConcentrationDenominator,
+    /// How much of the deposits scan was fetched, `None` where the deposits
+    /// weren't scanned, e.g. read from the deposit index
+    ///
+    /// The concentration of a partial scan is from the deposits it did fetch.
+    #[serde(default)]
+    pub data_completeness: Option<DataCompleteness>,
 }
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct VolatilityRiskMetrics {
     pub sigma_apy: f64,
     pub sigma_utilization: f64,
     pub volatility_risk: f64,
+    /// Volatility per lookback, the fields above blend these
+    #[serde(default)]
+    pub surface: Vec<LookbackVolatility>,
+    /// VaR and max drawdown of the APY series, `None` with too few points
+    #[serde(default)]
+    pub downside: Option<DownsideRisk>,
+    /// Latest APY of the series, in percent, absent from responses computed before it was kept
+    #[serde(default)]
+    pub current_apy: Option<f64>,
+    /// History the sigmas were computed over, `None` where it isn't tracked
+    #[serde(default)]
+    pub history_window: Option<HistoryWindow>,
 }
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct ProtocolRiskMetrics {
+    /// Baseline risk plus the penalties still in effect
     pub protocol_risk: f64,
+    /// Decaying penalties of recent incidents and upgrades
+    #[serde(default)]
+    pub penalties: Vec<PenaltyStatus>,
+    /// Scores of the rubric the baseline is weighted from
+    #[serde(default)]
+    pub components: Vec<ComponentScore>,
 }
-#[derive(Debug, Clone, Serialize)]
+/// Overall risk of a protocol, between 0 and 100
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct RiskScore {
+    /// Weighted over the normalized pillars
     pub overall_risk: f64,
+    /// Pillars as weighted, the metrics of each pillar keep their raw risk
+    ///
+    /// Empty in responses computed before pillars were normalized.
+    #[serde(default)]
+    pub normalized: NormalizedScores,
+}
+
+impl RiskScore {
+    /// Normalizes the raw pillar risks given and weighs them into the overall score
+    ///
+    /// Pillars left out have their weight spread over the others, `None` when no
+    /// pillar with a positive weight is given.
+    pub fn from_raw(
+        weights: &RiskWeightsConfig,
+        liquidity_risk: Option<f64>,
+        volatility_risk: Option<f64>,
+        protocol_risk: Option<f64>,
+        oracle_risk: Option<f64>,
+    ) -> Option<Self> {
+        let normalized = weights.normalization.normalize(
+            liquidity_risk,
+            volatility_risk,
+            protocol_risk,
+            oracle_risk,
+        );
+        let overall_risk = weights.overall.partial_score(
+            normalized.liquidity,
+            normalized.volatility,
+            normalized.protocol,
+            normalized.oracle,
+        )?;
+        Some(RiskScore {
+            overall_risk,
+            normalized,
+        })
+    }
 }
-pub trait ProtocolRisk {
+/// Risk computation of a protocol
+///
+/// Every computation is `Send` so protocols can be assessed on separate tasks of
+/// the multi-threaded runtime. Implementors can still write `async fn`s as long
+/// as what they hold across awaits is `Send`.
+pub trait ProtocolRisk: Sync {
     fn redis_client(&self) -> &redis::Client;
-    const W_LIQ_D_CONC: f64;
-    const W_LIQ_UTIL: f64;
-    const W_VOL_APY: f64;
-    const W_VOL_UTIL: f64;
-    const W_LIQUIDITY: f64;
-    const W_VOLATILITY: f64;
-    const W_PROTOCOL: f64;
-    async fn calculate_liquidity_risk(&self) -> Result<LiquidityRiskMetrics, RiskCalculationError>;
-    async fn calculate_volatility_risk(
-        &self,
-    ) -> Result<VolatilityRiskMetrics, RiskCalculationError>;
-    async fn calculate_protocol_risk(&self) -> Result<ProtocolRiskMetrics, RiskCalculationError>;
+    /// The assessed protocol, penalties are tracked per protocol
+    fn protocol(&self) -> Protocol;
+    /// Prefix of every cache key of this implementor, e.g. `kamino:<reserve>`
+    ///
+    /// Must be unique per protocol and reserve so their cached data never mixes.
+    fn cache_namespace(&self) -> String;
+    /// Weights of the model, the ones configured at startup by default
+    fn weights(&self) -> &RiskWeightsConfig {
+        RiskWeightsConfig::global()
+    }
+    /// Clock the freshness of cached pillars is judged by, [`process_clock`] by default
+    fn clock(&self) -> &dyn Clock {
+        process_clock()
+    }
+    fn calculate_liquidity_risk(
+        &self,
+    ) -> impl Future<Output = Result<LiquidityRiskMetrics, RiskCalculationError>> + Send;
+    fn calculate_volatility_risk(
+        &self,
+    ) -> impl Future<Output = Result<VolatilityRiskMetrics, RiskCalculationError>> + Send;
+    fn calculate_protocol_risk(
+        &self,
+    ) -> impl Future<Output = Result<ProtocolRiskMetrics, RiskCalculationError>> + Send;
+    fn calculate_oracle_risk(
+        &self,
+    ) -> impl Future<Output = Result<OracleRiskMetrics, RiskCalculationError>> + Send;
     fn calculate_risk_score(
         &self,
         liquidity_risk: f64,
         volatility_risk: f64,
         protocol_risk: f64,
+        oracle_risk: f64,
     ) -> Result<RiskScore, RiskCalculationError> {
-        let liquidity_risk_score = liquidity_risk * Self::W_LIQUIDITY;
-        let volatility_risk_score = volatility_risk * Self::W_VOLATILITY;
-        let protocol_risk_score = protocol_risk * Self::W_PROTOCOL;
-        let overall_risk = liquidity_risk_score + volatility_risk_score + protocol_risk_score;
-        Ok(RiskScore { overall_risk })
+        RiskScore::from_raw(
+            self.weights(),
+            Some(liquidity_risk),
+            Some(volatility_risk),
+            Some(protocol_risk),
+            Some(oracle_risk),
+        )
+        .ok_or_else(|| RiskCalculationError::CustomError("every risk weight is 0".to_string()))
     }
-    async fn redis_set_until_next_hour(
+    /// Computes the four risk pillars concurrently and combines them into the overall score
+    ///
+    /// The pillars are independent, so latency is that of the slowest one. Each
+    /// pillar is cached with its own TTL and only recomputed once it expired.
+    /// The assembled response is cached too, until the next hour or the first
+    /// of its pillars expires, unless a pillar was served stale.
+    fn calculate_all(
+        &self,
+    ) -> impl Future<Output = Result<RiskResponse, RiskCalculationError>> + Send
+    where
+        Self: Clone + Send + 'static,
+    {
+        let span = tracing::info_span!("calculate_all", protocol = %self.protocol());
+        async move {
+            if let Ok(cached) = self.cache_get(RISK_RESPONSE_KEY).await {
+                match serde_json::from_str(&cached) {
+                    Ok(response) => return Ok(response),
+                    Err(e) => tracing::warn!(
+                        "Discarding cached {}: {}",
+                        self.cache_key(RISK_RESPONSE_KEY),
+                        e
+                    ),
+                }
+            }
+
+            let ttls = SubScoreTtls::global();
+            let (liquidity, volatility, protocol, oracle) = futures::try_join!(
+                self.cached_pillar(
+                    Pillar::Liquidity,
+                    ttls.liquidity,
+                    self.calculate_liquidity_risk()
+                ),
+                self.cached_pillar(
+                    Pillar::Volatility,
+                    ttls.volatility,
+                    self.calculate_volatility_risk()
+                ),
+                self.cached_pillar(
+                    Pillar::Protocol,
+                    ttls.protocol,
+                    self.calculate_protocol_risk()
+                ),
+                self.cached_pillar(Pillar::Oracle, ttls.oracle, self.calculate_oracle_risk()),
+            )?;
+            let now = self.clock().now();
+            let computed_at = [
+                liquidity.computed_at,
+                volatility.computed_at,
+                protocol.computed_at,
+                oracle.computed_at,
+            ]
+            .into_iter()
+            .min();
+            let stale = liquidity.is_stale(ttls.liquidity, now)
+                || volatility.is_stale(ttls.volatility, now)
+                || protocol.is_stale(ttls.protocol, now)
+                || oracle.is_stale(ttls.oracle, now);
+            let response_ttl = [
+                liquidity.remaining_seconds(ttls.liquidity, now),
+                volatility.remaining_seconds(ttls.volatility, now),
+                protocol.remaining_seconds(ttls.protocol, now),
+                oracle.remaining_seconds(ttls.oracle, now),
+                seconds_until_next_hour(now),
+            ]
+            .into_iter()
+            .min()
+            .unwrap_or_default();
+
+            let protocol_risk = self.apply_penalties(protocol.metrics, now).await;
+            let overall_risk = self.calculate_risk_score(
+                liquidity.metrics.liquidity_risk,
+                volatility.metrics.volatility_risk,
+                protocol_risk.protocol_risk,
+                oracle.metrics.oracle_risk,
+            )?;
+            let response = RiskResponse {
+                schema_version: SCHEMA_VERSION,
+                liquidity_risk: liquidity.metrics,
+                volatility_risk: volatility.metrics,
+                protocol_risk,
+                oracle_risk: oracle.metrics,
+                overall_risk,
+                computed_at,
+                stale,
+            };
+            if !stale && response_ttl > 0 {
+                let stored = match serde_json::to_string(&response) {
+                    Ok(value) => {
+                        self.cache_set_with_ttl(RISK_RESPONSE_KEY, &value, response_ttl)
+                            .await
+                    }
+                    Err(e) => Err(RiskCalculationError::SerdeError(e)),
+                };
+                if let Err(e) = stored {
+                    tracing::warn!(
+                        "Failed to cache {}: {}",
+                        self.cache_key(RISK_RESPONSE_KEY),
+                        e
+                    );
+                }
+            }
+            Ok(response)
+        }
+        .instrument(span)
+    }
+    /// Like [`Self::calculate_all`], keeping the pillars that could be computed
+    /// when others fail
+    ///
+    /// Only fails when no pillar could be computed.
+    fn calculate_partial(
+        &self,
+    ) -> impl Future<Output = Result<PartialRiskResponse, RiskCalculationError>> + Send
+    where
+        Self: Clone + Send + 'static,
+    {
+        async move {
+            let ttls = SubScoreTtls::global();
+            let (liquidity, volatility, protocol, oracle) = futures::join!(
+                self.cached_pillar(
+                    Pillar::Liquidity,
+                    ttls.liquidity,
+                    self.calculate_liquidity_risk()
+                ),
+                self.cached_pillar(
+                    Pillar::Volatility,
+                    ttls.volatility,
+                    self.calculate_volatility_risk()
+                ),
+                self.cached_pillar(
+                    Pillar::Protocol,
+                    ttls.protocol,
+                    self.calculate_protocol_risk()
+                ),
+                self.cached_pillar(Pillar::Oracle, ttls.oracle, self.calculate_oracle_risk()),
+            );
+            let mut errors = Vec::new();
+            let liquidity = computed_pillar(Pillar::Liquidity, liquidity, &mut errors);
+            let volatility = computed_pillar(Pillar::Volatility, volatility, &mut errors);
+            let protocol = computed_pillar(Pillar::Protocol, protocol, &mut errors);
+            let oracle = computed_pillar(Pillar::Oracle, oracle, &mut errors);
+
+            let now = self.clock().now();
+            let computed_at = [
+                liquidity.as_ref().map(|pillar| pillar.computed_at),
+                volatility.as_ref().map(|pillar| pillar.computed_at),
+                protocol.as_ref().map(|pillar| pillar.computed_at),
+                oracle.as_ref().map(|pillar| pillar.computed_at),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            let stale = liquidity
+                .as_ref()
+                .is_some_and(|pillar| pillar.is_stale(ttls.liquidity, now))
+                || volatility
+                    .as_ref()
+                    .is_some_and(|pillar| pillar.is_stale(ttls.volatility, now))
+                || protocol
+                    .as_ref()
+                    .is_some_and(|pillar| pillar.is_stale(ttls.protocol, now))
+                || oracle
+                    .as_ref()
+                    .is_some_and(|pillar| pillar.is_stale(ttls.oracle, now));
+
+            let protocol_risk = match protocol {
+                Some(protocol) => Some(self.apply_penalties(protocol.metrics, now).await),
+                None => None,
+            };
+            let liquidity_risk = liquidity.map(|pillar| pillar.metrics);
+            let volatility_risk = volatility.map(|pillar| pillar.metrics);
+            let oracle_risk = oracle.map(|pillar| pillar.metrics);
+            let overall_risk = RiskScore::from_raw(
+                self.weights(),
+                liquidity_risk
+                    .as_ref()
+                    .map(|metrics| metrics.liquidity_risk),
+                volatility_risk
+                    .as_ref()
+                    .map(|metrics| metrics.volatility_risk),
+                protocol_risk.as_ref().map(|metrics| metrics.protocol_risk),
+                oracle_risk.as_ref().map(|metrics| metrics.oracle_risk),
+            )
+            .ok_or_else(|| {
+                RiskCalculationError::UpstreamUnavailable(format!(
+                    "no risk pillar of {:?} could be computed: {}",
+                    self.protocol(),
+                    errors
+                        .iter()
+                        .map(|error| format!("{}: {}", error.pillar.as_str(), error.error))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            })?;
+            Ok(PartialRiskResponse {
+                schema_version: SCHEMA_VERSION,
+                liquidity_risk,
+                volatility_risk,
+                protocol_risk,
+                oracle_risk,
+                overall_risk,
+                computed_at,
+                stale,
+                errors,
+            })
+        }
+    }
+    /// Adds the penalties of the protocol in effect at `now` to its baseline risk
+    ///
+    /// Penalties aren't cached with the pillar, so they decay between refreshes
+    /// and new ones apply on the next one. Failing to read them is only logged.
+    fn apply_penalties(
+        &self,
+        mut metrics: ProtocolRiskMetrics,
+        now: DateTime<Utc>,
+    ) -> impl Future<Output = ProtocolRiskMetrics> + Send {
+        async move {
+            match load_penalties(self.redis_client(), &self.protocol(), now).await {
+                Ok(penalties) => {
+                    metrics.protocol_risk += penalties
+                        .iter()
+                        .map(|penalty| penalty.current_bump)
+                        .sum::<f64>();
+                    metrics.penalties = penalties;
+                }
+                Err(e) => tracing::warn!("Failed to load {:?} penalties: {}", self.protocol(), e),
+            }
+            metrics
+        }
+    }
+    /// Reads a pillar's metrics from the cache, or computes and caches them for `ttl_seconds`
+    ///
+    /// The cache is an optimization only: failing to read or write it never fails
+    /// the computation. Concurrent misses compute the metrics once, see
+    /// `compute_once`. In [`CacheMode::StaleWhileRevalidate`], metrics past
+    /// `ttl_seconds` are still served while they're recomputed in the background.
+    fn cached_pillar<T, F>(
+        &self,
+        pillar: Pillar,
+        ttl_seconds: u64,
+        compute: F,
+    ) -> impl Future<Output = Result<CachedPillar<T>, RiskCalculationError>> + Send
+    where
+        Self: Clone + Send + 'static,
+        T: Serialize + serde::de::DeserializeOwned + Send,
+        F: Future<Output = Result<T, RiskCalculationError>> + Send,
+    {
+        let span = tracing::info_span!(
+            "risk_pillar",
+            protocol = %self.protocol(),
+            pillar = pillar.as_str()
+        );
+        async move {
+            let key = pillar_key(pillar);
+            let cached = || async {
+                let cached = self.cache_get(&key).await.ok()?;
+                match serde_json::from_str(&cached) {
+                    Ok(cached) => Some(cached),
+                    Err(e) => {
+                        tracing::warn!("Discarding cached {}: {}", self.cache_key(&key), e);
+                        None
+                    }
+                }
+            };
+            if let Some(cached) = cached().await {
+                if CacheMode::global().revalidates(&cached, ttl_seconds, self.clock().now()) {
+                    self.revalidate(pillar, ttl_seconds);
+                }
+                return Ok(cached);
+            }
+
+            let computed = compute_once(
+                self.cache().redis_client(),
+                &self.cache_key(&key),
+                cached,
+                self.store_pillar(pillar, ttl_seconds, compute),
+            )
+            .await;
+            match computed {
+                Err(e @ RiskCalculationError::UpstreamUnavailable(_)) => {
+                    let last_known = self.cache_get(&last_known_pillar_key(pillar)).await;
+                    match last_known.map(|last_known| serde_json::from_str(&last_known)) {
+                        Ok(Ok(last_known)) => {
+                            tracing::warn!("Serving the last known metrics: {}", e);
+                            Ok(last_known)
+                        }
+                        _ => Err(e),
+                    }
+                }
+                computed => computed,
+            }
+        }
+        .instrument(span)
+    }
+    /// Computes a pillar's metrics and caches them until the hard expiry of `ttl_seconds`
+    ///
+    /// They're also kept for a week as the last known metrics, served while an
+    /// upstream is unavailable.
+    fn store_pillar<T, F>(
+        &self,
+        pillar: Pillar,
+        ttl_seconds: u64,
+        compute: F,
+    ) -> impl Future<Output = Result<CachedPillar<T>, RiskCalculationError>> + Send
+    where
+        T: Serialize + Send,
+        F: Future<Output = Result<T, RiskCalculationError>> + Send,
+    {
+        async move {
+            let key = pillar_key(pillar);
+            record_recomputed(format!("{}:{}", self.cache_namespace(), pillar.as_str()));
+            let cached = CachedPillar {
+                computed_at: self.clock().now(),
+                metrics: compute.await?,
+            };
+            match serde_json::to_string(&cached) {
+                Ok(value) => {
+                    let stored = [
+                        (key, CacheMode::global().hard_ttl(ttl_seconds)),
+                        (last_known_pillar_key(pillar), LAST_KNOWN_TTL_SECONDS),
+                    ];
+                    for (key, ttl_seconds) in stored {
+                        if let Err(e) = self.cache_set_with_ttl(&key, &value, ttl_seconds).await {
+                            tracing::warn!("Failed to cache {}: {}", self.cache_key(&key), e);
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!("Failed to cache {}: {}", self.cache_key(&key), e),
+            }
+            Ok(cached)
+        }
+    }
+    /// Recomputes a stale pillar on a background task
+    ///
+    /// Only one instance revalidates a pillar at a time, requests arriving
+    /// meanwhile keep being served the stale metrics.
+    fn revalidate(&self, pillar: Pillar, ttl_seconds: u64)
+    where
+        Self: Clone + Send + 'static,
+    {
+        let protocol = self.clone();
+        shutdown::spawn_background(async move {
+            let key = protocol.cache_key(&pillar_key(pillar));
+            let revalidated = compute_if_unlocked(protocol.cache().redis_client(), &key, async {
+                match pillar {
+                    Pillar::Liquidity => protocol
+                        .store_pillar(pillar, ttl_seconds, protocol.calculate_liquidity_risk())
+                        .await
+                        .map(|_| ()),
+                    Pillar::Volatility => protocol
+                        .store_pillar(pillar, ttl_seconds, protocol.calculate_volatility_risk())
+                        .await
+                        .map(|_| ()),
+                    Pillar::Protocol => protocol
+                        .store_pillar(pillar, ttl_seconds, protocol.calculate_protocol_risk())
+                        .await
+                        .map(|_| ()),
+                    Pillar::Oracle => protocol
+                        .store_pillar(pillar, ttl_seconds, protocol.calculate_oracle_risk())
+                        .await
+                        .map(|_| ()),
+                }
+            })
+            .await;
+            if let Err(e) = revalidated {
+                tracing::warn!("Failed to revalidate {}: {}", key, e);
+            }
+        });
+    }
+    /// The configured cache, see [`CacheKind`]
+    fn cache(&self) -> CacheBackend<'_> {
+        CacheBackend::new(self.redis_client())
+    }
+    /// Full, versioned cache key of `key` within this implementor's namespace
+    fn cache_key(&self, key: &str) -> String {
+        crate::cache_schema::versioned_key(&format!("{}:{}", self.cache_namespace(), key))
+    }
+    /// Caches `value` under the namespaced `key` until the next hour
+    fn cache_set_until_next_hour(
         &self,
         key: &str,
         value: &str,
-    ) -> Result<(), RiskCalculationError> {
-        let mut connection = self
-            .redis_client()
-            .get_multiplexed_async_connection()
-            .await
-            .map_err(|e| RiskCalculationError::RedisError(e))?;
-        let _: () = connection
-            .set_ex(key, value, get_seconds_until_next_hour())
-            .await
-            .map_err(|e| RiskCalculationError::RedisError(e))?;
-        Ok(())
+    ) -> impl Future<Output = Result<(), RiskCalculationError>> + Send {
+        async move {
+            let ttl = seconds_until_next_hour(self.clock().now());
+            self.cache_set_with_ttl(key, value, ttl).await
+        }
     }
-    async fn redis_get(&self, key: &str) -> Result<String, RiskCalculationError> {
-        let mut connection = self
-            .redis_client()
-            .get_multiplexed_async_connection()
-            .await
-            .map_err(|e| RiskCalculationError::RedisError(e))?;
-        let value: String = connection
-            .get(key)
-            .await
-            .map_err(|e| RiskCalculationError::RedisError(e))?;
-        Ok(value)
+    /// Caches an input of the liquidity pillar until the pillar itself expires
+    ///
+    /// Inputs cached any longer would be reused by every recomputation of the
+    /// pillar in between, leaving its TTL without effect.
+    fn cache_set_liquidity_input(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> impl Future<Output = Result<(), RiskCalculationError>> + Send {
+        self.cache_set_with_ttl(key, value, SubScoreTtls::global().liquidity)
+    }
+    /// Caches `value` under the namespaced `key` for `ttl_seconds`
+    fn cache_set_with_ttl(
+        &self,
+        key: &str,
+        value: &str,
+        ttl_seconds: u64,
+    ) -> impl Future<Output = Result<(), RiskCalculationError>> + Send {
+        async move {
+            self.cache()
+                .set_with_ttl(&self.cache_key(key), value, ttl_seconds)
+                .await
+        }
+    }
+    /// Reads the namespaced `key`, failing when it isn't cached
+    fn cache_get(
+        &self,
+        key: &str,
+    ) -> impl Future<Output = Result<String, RiskCalculationError>> + Send {
+        async move {
+            let key = self.cache_key(key);
+            timed(Timing::CacheRead, self.cache().get(&key))
+                .await?
+                .ok_or(RiskCalculationError::NotReady(format!(
+                    "{} isn't cached",
+                    key
+                )))
+        }
+    }
+}
+
+/// A pillar's metrics as cached, with when they were computed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedPillar<T> {
+    pub computed_at: DateTime<Utc>,
+    pub metrics: T,
+}
+
+impl<T> CachedPillar<T> {
+    /// Whether the metrics are older than their TTL at `now`
+    pub fn is_stale(&self, ttl_seconds: u64, now: DateTime<Utc>) -> bool {
+        now - self.computed_at >= chrono::Duration::seconds(ttl_seconds as i64)
+    }
+
+    /// Seconds left until the metrics are older than their TTL, 0 once they are
+    pub fn remaining_seconds(&self, ttl_seconds: u64, now: DateTime<Utc>) -> u64 {
+        (ttl_seconds as i64 - (now - self.computed_at).num_seconds()).max(0) as u64
+    }
+}
+
+/// The pillar if it was computed, otherwise records why it wasn't in `errors`
+fn computed_pillar<T>(
+    pillar: Pillar,
+    result: Result<CachedPillar<T>, RiskCalculationError>,
+    errors: &mut Vec<PillarError>,
+) -> Option<CachedPillar<T>> {
+    match result {
+        Ok(computed) => Some(computed),
+        Err(e) => {
+            tracing::warn!("Failed to compute {} risk: {}", pillar.as_str(), e);
+            errors.push(PillarError {
+                pillar,
+                code: e.code().to_string(),
+                error: e.to_string(),
+            });
+            None
+        }
+    }
+}
+
+/// Cache key of the assembled [`RiskResponse`], within an implementor's namespace
+pub(crate) const RISK_RESPONSE_KEY: &str = "risk_response";
+
+/// Cache key of a pillar's metrics, within an implementor's namespace
+fn pillar_key(pillar: Pillar) -> String {
+    format!("pillar:{}", pillar.as_str())
+}
+
+/// How long a pillar's last computed metrics are kept to fall back on
+const LAST_KNOWN_TTL_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+/// Cache key of a pillar's last computed metrics, served when an upstream is
+/// unavailable after the pillar expired
+fn last_known_pillar_key(pillar: Pillar) -> String {
+    format!("pillar:{}:last_known", pillar.as_str())
+}
+
+static CACHE_MODE: std::sync::OnceLock<CacheMode> = std::sync::OnceLock::new();
+
+/// What happens to cached pillars once their TTL passed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    /// Metrics expire at their TTL, the next request recomputes them
+    #[default]
+    Expire,
+    /// Metrics are kept for another TTL and served as stale while a
+    /// background task recomputes them, so no request waits on the refresh
+    StaleWhileRevalidate,
+}
+
+impl CacheMode {
+    /// The mode read at startup
+    pub fn global() -> &'static Self {
+        CACHE_MODE.get_or_init(|| Self::from_env().expect("CACHE_MODE must be valid"))
+    }
+
+    /// Reads `CACHE_MODE`, `expire` when not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        match std::env::var("CACHE_MODE").as_deref() {
+            Err(_) | Ok("expire") => Ok(CacheMode::Expire),
+            Ok("stale_while_revalidate") => Ok(CacheMode::StaleWhileRevalidate),
+            Ok(other) => Err(RiskCalculationError::ParseError(format!(
+                "invalid CACHE_MODE {:?}, expected expire or stale_while_revalidate",
+                other
+            ))),
+        }
+    }
+
+    /// How long metrics with a soft TTL of `ttl_seconds` stay in the cache
+    pub fn hard_ttl(&self, ttl_seconds: u64) -> u64 {
+        match self {
+            CacheMode::Expire => ttl_seconds,
+            CacheMode::StaleWhileRevalidate => ttl_seconds * 2,
+        }
     }
+
+    /// Whether serving `cached` at `now` should start a background refresh
+    pub fn revalidates<T>(
+        &self,
+        cached: &CachedPillar<T>,
+        ttl_seconds: u64,
+        now: DateTime<Utc>,
+    ) -> bool {
+        *self == CacheMode::StaleWhileRevalidate && cached.is_stale(ttl_seconds, now)
+    }
+}
+
+/// The components of the overall risk score
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Pillar {
+    Liquidity,
+    Volatility,
+    Protocol,
+    Oracle,
+}
+
+impl Pillar {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Pillar::Liquidity => "liquidity",
+            Pillar::Volatility => "volatility",
+            Pillar::Protocol => "protocol",
+            Pillar::Oracle => "oracle",
+        }
+    }
+}
+
+static SUB_SCORE_TTLS: std::sync::OnceLock<SubScoreTtls> = std::sync::OnceLock::new();
+
+/// How long each pillar's metrics are cached, in seconds
+///
+/// Protocol risk moves slowly while liquidity can change within the hour, so
+/// each pillar expires on its own instead of all of them at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubScoreTtls {
+    pub liquidity: u64,
+    pub volatility: u64,
+    pub protocol: u64,
+    /// Prices move by the second, so oracle quality is only cached briefly
+    pub oracle: u64,
 }
 
-pub fn get_seconds_until_next_hour() -> u64 {
-    let now = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap();
-    let seconds_until_next_hour = 3600 - (now.as_secs() % 3600);
-    seconds_until_next_hour
+impl Default for SubScoreTtls {
+    fn default() -> Self {
+        SubScoreTtls {
+            liquidity: 15 * 60,
+            volatility: 60 * 60,
+            protocol: 24 * 60 * 60,
+            oracle: 60,
+        }
+    }
 }
 
-pub async fn risk_model() -> Response {
-    let result = async {
-        let kamino_risk = KaminoRisk {
-            redis_client: redis::Client::open(std::env::var("REDIS_URL").unwrap())
-                .map_err(|e| RiskCalculationError::RedisError(e))?,
+impl SubScoreTtls {
+    /// The TTLs read at startup
+    pub fn global() -> &'static Self {
+        SUB_SCORE_TTLS.get_or_init(|| Self::from_env().expect("SUBSCORE_TTLS must be valid"))
+    }
+
+    /// Reads `SUBSCORE_TTLS` as `liquidity,volatility,protocol[,oracle]` seconds,
+    /// e.g. `900,3600,86400,60`
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let Ok(ttls) = std::env::var("SUBSCORE_TTLS") else {
+            return Ok(Self::default());
+        };
+        let ttls = ttls
+            .split(',')
+            .map(|ttl| ttl.trim().parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
+        let (liquidity, volatility, protocol, oracle) = match ttls[..] {
+            [liquidity, volatility, protocol] => {
+                (liquidity, volatility, protocol, Self::default().oracle)
+            }
+            [liquidity, volatility, protocol, oracle] => (liquidity, volatility, protocol, oracle),
+            _ => (0, 0, 0, 0),
         };
+        if [liquidity, volatility, protocol, oracle].contains(&0) {
+            return Err(RiskCalculationError::ParseError(
+                "SUBSCORE_TTLS needs 3 or 4 comma separated, non-zero TTLs".to_string(),
+            ));
+        }
+        Ok(SubScoreTtls {
+            liquidity,
+            volatility,
+            protocol,
+            oracle,
+        })
+    }
+}
 
-        let liquidity_risk = kamino_risk.calculate_liquidity_risk().await?;
-        let volatility_risk = kamino_risk.calculate_volatility_risk().await?;
-        let protocol_risk = kamino_risk.calculate_protocol_risk().await?;
-        let overall_risk = kamino_risk.calculate_risk_score(
-            liquidity_risk.liquidity_risk,
-            volatility_risk.volatility_risk,
-            protocol_risk.protocol_risk,
-        )?;
+/// Weights used to combine the risk pillars into one score
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct OverallRiskWeights {
+    pub liquidity: f64,
+    pub volatility: f64,
+    pub protocol: f64,
+    /// Zero when left out, so configurations written for three pillars stay valid
+    #[serde(default)]
+    pub oracle: f64,
+}
 
-        // Create enhanced response with protocol comparison
-        let response = serde_json::json!({
-            "choice_reason": "Kamino currently shows the lowest risk profile among evaluated protocols and gives you most bang for your buck",
-            "chosen_protocol": {
-                "protocol": "Kamino",
-                "risk_metrics": {
-                    "liquidity_risk": liquidity_risk,
-                    "volatility_risk": volatility_risk,
-                    "protocol_risk": protocol_risk,
-                    "overall_risk": overall_risk
-                }
+impl Default for OverallRiskWeights {
+    fn default() -> Self {
+        OverallRiskWeights {
+            liquidity: 0.35,
+            volatility: 0.25,
+            protocol: 0.25,
+            oracle: 0.15,
+        }
+    }
+}
+
+impl OverallRiskWeights {
+    pub fn score(
+        &self,
+        liquidity_risk: f64,
+        volatility_risk: f64,
+        protocol_risk: f64,
+        oracle_risk: f64,
+    ) -> f64 {
+        liquidity_risk * self.liquidity
+            + volatility_risk * self.volatility
+            + protocol_risk * self.protocol
+            + oracle_risk * self.oracle
+    }
+
+    /// Score over the pillars given, their weights renormalized to sum to 1
+    ///
+    /// `None` when no pillar with a positive weight is given.
+    pub fn partial_score(
+        &self,
+        liquidity_risk: Option<f64>,
+        volatility_risk: Option<f64>,
+        protocol_risk: Option<f64>,
+        oracle_risk: Option<f64>,
+    ) -> Option<f64> {
+        let weighted = [
+            (liquidity_risk, self.liquidity),
+            (volatility_risk, self.volatility),
+            (protocol_risk, self.protocol),
+            (oracle_risk, self.oracle),
+        ]
+        .into_iter()
+        .filter_map(|(risk, weight)| risk.map(|risk| (risk, weight)));
+        let (score, total_weight) = weighted.fold((0.0, 0.0), |(score, total), (risk, weight)| {
+            (score + risk * weight, total + weight)
+        });
+        (total_weight > 0.0).then(|| score / total_weight)
+    }
+}
+
+/// How a raw pillar risk is mapped onto the 0–100 scale the overall score combines
+///
+/// Configured per pillar under `[normalization.<pillar>]`, e.g.
+///
+/// ```toml
+/// [normalization.volatility]
+/// method = "logistic"
+/// midpoint = 5.0
+/// steepness = 0.5
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Normalization {
+    /// Linear from 0 at `min` to 100 at `max`, clamped outside of them
+    MinMax { min: f64, max: f64 },
+    /// Logistic curve through 50 at `midpoint`, for raw risks without an upper bound
+    ///
+    /// `steepness` is per unit of the raw risk.
+    Logistic { midpoint: f64, steepness: f64 },
+}
+
+impl Normalization {
+    /// Raw risks already between 0 and 100, only clamped
+    pub const PERCENT: Normalization = Normalization::MinMax {
+        min: 0.0,
+        max: 100.0,
+    };
+
+    pub fn apply(&self, raw: f64) -> f64 {
+        match *self {
+            Normalization::MinMax { min, max } => {
+                ((raw - min) / (max - min) * 100.0).clamp(0.0, 100.0)
+            }
+            Normalization::Logistic {
+                midpoint,
+                steepness,
+            } => 100.0 / (1.0 + (-steepness * (raw - midpoint)).exp()),
+        }
+    }
+
+    fn validate(&self, pillar: &str) -> Result<(), RiskCalculationError> {
+        let valid = match *self {
+            Normalization::MinMax { min, max } => min.is_finite() && max.is_finite() && min < max,
+            Normalization::Logistic {
+                midpoint,
+                steepness,
+            } => midpoint.is_finite() && steepness.is_finite() && steepness > 0.0,
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(RiskCalculationError::ParseError(format!(
+                "{} normalization needs min < max or a positive steepness: {:?}",
+                pillar, self
+            )))
+        }
+    }
+}
+
+/// Normalization of every pillar, so protocols are compared on one 0–100 scale
+///
+/// Liquidity, protocol and oracle risk are scored in percent already. Volatility
+/// is a standard deviation without an upper bound, squashed so a sigma of 5
+/// lands in the middle of the scale.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScoreNormalization {
+    pub liquidity: Normalization,
+    pub volatility: Normalization,
+    pub protocol: Normalization,
+    pub oracle: Normalization,
+}
+
+impl Default for ScoreNormalization {
+    fn default() -> Self {
+        ScoreNormalization {
+            liquidity: Normalization::PERCENT,
+            volatility: Normalization::Logistic {
+                midpoint: 5.0,
+                steepness: 0.5,
+            },
+            protocol: Normalization::PERCENT,
+            oracle: Normalization::PERCENT,
+        }
+    }
+}
+
+impl ScoreNormalization {
+    pub fn normalize(
+        &self,
+        liquidity_risk: Option<f64>,
+        volatility_risk: Option<f64>,
+        protocol_risk: Option<f64>,
+        oracle_risk: Option<f64>,
+    ) -> NormalizedScores {
+        NormalizedScores {
+            liquidity: liquidity_risk.map(|risk| self.liquidity.apply(risk)),
+            volatility: volatility_risk.map(|risk| self.volatility.apply(risk)),
+            protocol: protocol_risk.map(|risk| self.protocol.apply(risk)),
+            oracle: oracle_risk.map(|risk| self.oracle.apply(risk)),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), RiskCalculationError> {
+        self.liquidity.validate("liquidity")?;
+        self.volatility.validate("volatility")?;
+        self.protocol.validate("protocol")?;
+        self.oracle.validate("oracle")
+    }
+}
+
+/// Pillar risks between 0 and 100, `None` for pillars that weren't computed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct NormalizedScores {
+    pub liquidity: Option<f64>,
+    pub volatility: Option<f64>,
+    pub protocol: Option<f64>,
+    pub oracle: Option<f64>,
+}
+
+/// Named weight presets used to show how sensitive the protocol choice is to weighting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WeightPreset {
+    /// Favours protocol safety and the ability to exit over yield stability
+    Conservative,
+    /// The configured weights used for the overall risk score
+    Balanced,
+    /// Mostly cares about liquidity and yield swings, tolerates protocol risk
+    Aggressive,
+}
+
+impl WeightPreset {
+    pub const ALL: [WeightPreset; 3] = [
+        WeightPreset::Conservative,
+        WeightPreset::Balanced,
+        WeightPreset::Aggressive,
+    ];
+
+    pub fn weights(&self) -> OverallRiskWeights {
+        match self {
+            WeightPreset::Conservative => OverallRiskWeights {
+                liquidity: 0.4,
+                volatility: 0.1,
+                protocol: 0.3,
+                oracle: 0.2,
             },
-            "other_protocols": {
-                "solend": null,
-                "drift": null,
-                "marginfy": null
+            WeightPreset::Balanced => RiskWeightsConfig::global().overall,
+            WeightPreset::Aggressive => OverallRiskWeights {
+                liquidity: 0.45,
+                volatility: 0.35,
+                protocol: 0.1,
+                oracle: 0.1,
             },
+        }
+    }
+}
+
+/// Sub-scores of a single protocol, as fed into the what-if comparison
+#[derive(Debug, Clone)]
+pub struct ProtocolSubScores {
+    pub protocol: Protocol,
+    pub liquidity_risk: f64,
+    pub volatility_risk: f64,
+    pub protocol_risk: f64,
+    pub oracle_risk: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ProtocolOverallRisk {
+    pub protocol: Protocol,
+    pub overall_risk: f64,
+}
+
+/// Outcome of the comparison under a single weight preset
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct WhatIfResult {
+    pub preset: WeightPreset,
+    pub weights: OverallRiskWeights,
+    pub chosen_protocol: Protocol,
+    pub overall_risks: Vec<ProtocolOverallRisk>,
+}
+
+/// Scores every protocol under each weight preset and picks the lowest risk one
+///
+/// Returns an empty vector when there are no protocols to compare.
+pub fn what_if(sub_scores: &[ProtocolSubScores]) -> Vec<WhatIfResult> {
+    WeightPreset::ALL
+        .iter()
+        .filter_map(|preset| {
+            let weights = preset.weights();
+            let overall_risks: Vec<ProtocolOverallRisk> = sub_scores
+                .iter()
+                .map(|scores| ProtocolOverallRisk {
+                    protocol: scores.protocol.clone(),
+                    overall_risk: weights.score(
+                        scores.liquidity_risk,
+                        scores.volatility_risk,
+                        scores.protocol_risk,
+                        scores.oracle_risk,
+                    ),
+                })
+                .collect();
+            let chosen_protocol = overall_risks
+                .iter()
+                .min_by(|a, b| a.overall_risk.total_cmp(&b.overall_risk))?
+                .protocol
+                .clone();
+            Some(WhatIfResult {
+                preset: *preset,
+                weights,
+                chosen_protocol,
+                overall_risks,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RiskModelQuery {
+    /// Include the protocol choice under each weight preset
+    #[serde(default)]
+    pub what_if: bool,
+    /// Kamino lending market of the reserve to assess, requires `reserve`
+    pub market: Option<String>,
+    /// Kamino reserve to assess, requires `market`
+    pub reserve: Option<String>,
+    /// Language of `choice_reason`, `en` or `es`
+    pub lang: Option<String>,
+    /// Serve protocols some pillars of which failed from their remaining
+    /// pillars, instead of only listing them as unavailable
+    #[serde(default)]
+    pub allow_partial: bool,
+    /// Adds the volatility of every protocol over this window, e.g. `48h` or `14d`
+    pub volatility_window: Option<String>,
+    /// Sampling of `volatility_window`, `hour` or `day`
+    pub volatility_frequency: Option<String>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+impl RiskModelQuery {
+    /// The volatility window asked for, `None` without `volatility_window`
+    pub fn volatility_window(&self) -> Result<Option<VolatilityWindow>, RiskCalculationError> {
+        match (&self.volatility_window, &self.volatility_frequency) {
+            (Some(window), frequency) => {
+                VolatilityWindow::parse(window, frequency.as_deref()).map(Some)
+            }
+            (None, Some(_)) => Err(RiskCalculationError::InvalidParameter(
+                "volatility_frequency requires volatility_window".to_string(),
+            )),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// The Kamino reserve selected by the query, falling back to `default`
+    pub fn kamino_reserve(
+        &self,
+        default: &KaminoReserveConfig,
+    ) -> Result<KaminoReserveConfig, RiskCalculationError> {
+        KaminoReserveConfig::from_params(self.market.as_deref(), self.reserve.as_deref(), default)
+    }
+}
+
+/// Query parameters of endpoints that only support debug output
+#[derive(Debug, Default, Deserialize)]
+pub struct DebugQuery {
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+/// Whether a `debug` query parameter asks for the latency breakdown
+pub fn timings_requested(debug: &Option<String>) -> bool {
+    debug.as_deref() == Some("timings")
+}
+
+/// Turns a handler result into a response, optionally with a `timings` block
+///
+/// The body is JSON unless the client negotiated another format, see
+/// [`ResponseFormat::negotiated`].
+pub fn json_response<T: Serialize>(
+    result: Result<T, RiskCalculationError>,
+    timings: Option<TimingsReport>,
+) -> Response {
+    let format = ResponseFormat::negotiated();
+    let result = result
+        .and_then(|body| serde_json::to_value(body).map_err(RiskCalculationError::SerdeError));
+    let (status, mut body) = match result {
+        Ok(body) => (StatusCode::OK, body),
+        Err(e) => (e.status_code(), e.body()),
+    };
+    if let Some(timings) = timings {
+        body = with_timings_block(body, timings);
+    }
+    (
+        status,
+        [(axum::http::header::CONTENT_TYPE, format.content_type())],
+        format.encode(&body),
+    )
+        .into_response()
+}
+
+/// Adds `timings` to an object body, other bodies such as lists are wrapped
+/// as `{"data": ..., "timings": ...}`
+fn with_timings_block(body: serde_json::Value, timings: TimingsReport) -> serde_json::Value {
+    match body {
+        serde_json::Value::Object(mut fields) => {
+            fields.insert("timings".to_string(), serde_json::json!(timings));
+            serde_json::Value::Object(fields)
+        }
+        data => serde_json::json!({ "data": data, "timings": timings }),
+    }
+}
+
+/// Position of a protocol in the ranking of a comparison
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RankedProtocol {
+    /// 1 is the lowest overall risk
+    pub rank: usize,
+    pub protocol: Protocol,
+    pub overall_risk: f64,
+}
+
+/// How old the served metrics are
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct CacheFreshness {
+    pub age_seconds: i64,
+    /// When the hourly refresh replaces the metrics
+    pub refreshes_at: DateTime<Utc>,
+    /// Whether the metrics are served past their refresh while newer ones are computed
+    #[serde(default)]
+    pub stale: bool,
+}
+
+/// Response of `/risk_model`
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RiskModelResponse {
+    pub snapshot_id: String,
+    pub computed_at: DateTime<Utc>,
+    pub freshness: CacheFreshness,
+    /// Plain language summary of why the protocol was chosen, see [`explain_choice`]
+    pub choice_reason: String,
+    /// What each pillar adds to the chosen protocol's overall risk, largest first
+    #[serde(default)]
+    pub attribution: Vec<PillarAttribution>,
+    pub chosen_protocol: ProtocolAssessment,
+    /// The remaining assessed protocols, lowest overall risk first
+    pub other_protocols: Vec<ProtocolAssessment>,
+    pub ranking: Vec<RankedProtocol>,
+    pub unavailable_protocols: Vec<UnavailableProtocol>,
+    /// Protocol choice under each weight preset, only included with `?what_if=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub what_if: Option<Vec<WhatIfResult>>,
+    /// The unavailable protocols assessed from the pillars that could be
+    /// computed, only included with `?allow_partial=true`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub partial_protocols: Vec<PartialAssessment>,
+    /// Volatility of every protocol over the window asked for, only included
+    /// with `?volatility_window=`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub window_volatility: Vec<ProtocolWindowVolatility>,
+}
+
+/// Body of `/risk_model?allow_partial=true` when no complete snapshot could be served
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PartialRiskModelResponse {
+    pub computed_at: DateTime<Utc>,
+    /// Always true, tells this body apart from a complete one
+    pub partial: bool,
+    /// Why no complete snapshot could be served, as in error responses
+    pub error: serde_json::Value,
+    /// Protocols from lowest to highest overall risk over their computed pillars
+    pub ranking: Vec<PartialAssessment>,
+    pub unavailable_protocols: Vec<UnavailableProtocol>,
+}
+
+impl PartialRiskModelResponse {
+    /// Assesses every protocol of `registry` from the pillars that can be computed
+    ///
+    /// Fails with `error`, what kept the complete snapshot from being served,
+    /// when not even one pillar of any protocol could be computed.
+    pub async fn assess(
+        registry: &ProtocolRegistry,
+        error: RiskCalculationError,
+    ) -> Result<Self, RiskCalculationError> {
+        let (ranking, unavailable_protocols) =
+            registry.compare_partial(&registry.protocols()).await;
+        if ranking.is_empty() {
+            return Err(error);
+        }
+        Ok(PartialRiskModelResponse {
+            computed_at: Utc::now(),
+            partial: true,
+            error: error.body(),
+            ranking,
+            unavailable_protocols,
+        })
+    }
+}
+
+/// Body of `/risk_model`
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum RiskModelBody {
+    Complete(Box<RiskModelResponse>),
+    Partial(PartialRiskModelResponse),
+}
+
+impl RiskModelResponse {
+    pub fn from_snapshot(
+        snapshot: RiskSnapshot,
+        now: DateTime<Utc>,
+        include_what_if: bool,
+        locale: Locale,
+    ) -> Result<Self, RiskCalculationError> {
+        let RiskSnapshot {
+            snapshot_id,
+            computed_at,
+            comparison,
+        } = snapshot;
+        let ranking: Vec<RankedProtocol> = comparison
+            .ranking
+            .iter()
+            .enumerate()
+            .map(|(i, assessment)| RankedProtocol {
+                rank: i + 1,
+                protocol: assessment.protocol.clone(),
+                overall_risk: assessment.risk_metrics.overall_risk.overall_risk,
+            })
+            .collect();
+        let what_if = include_what_if.then(|| {
+            let sub_scores: Vec<ProtocolSubScores> = comparison
+                .ranking
+                .iter()
+                .map(|assessment| assessment.sub_scores())
+                .collect();
+            what_if(&sub_scores)
         });
 
-        Ok::<_, RiskCalculationError>(axum::Json(response))
+        let mut assessments = comparison.ranking.into_iter();
+        let chosen_protocol = assessments.next().ok_or(RiskCalculationError::CustomError(
+            "No protocol could be assessed".to_string(),
+        ))?;
+        let stale = chosen_protocol.risk_metrics.stale
+            || assessments
+                .as_slice()
+                .iter()
+                .any(|assessment| assessment.risk_metrics.stale);
+        let attribution = attribute(&chosen_protocol, &RiskWeightsConfig::global().overall);
+        let choice_reason = explain_choice(&chosen_protocol, &attribution, ranking.len(), locale);
+        let refreshes_at = computed_at
+            .duration_trunc(chrono::Duration::hours(1))
+            .map_err(|e| RiskCalculationError::CustomError(e.to_string()))?
+            + chrono::Duration::hours(1);
+
+        Ok(RiskModelResponse {
+            snapshot_id,
+            computed_at,
+            freshness: CacheFreshness {
+                age_seconds: (now - computed_at).num_seconds(),
+                refreshes_at,
+                stale: now >= refreshes_at || stale,
+            },
+            choice_reason,
+            attribution,
+            chosen_protocol,
+            other_protocols: assessments.collect(),
+            ranking,
+            unavailable_protocols: comparison.unavailable,
+            what_if,
+            partial_protocols: Vec::new(),
+            window_volatility: Vec::new(),
+        })
+    }
+}
+
+impl RiskModelResponse {
+    /// Hides depositor-level data according to the deployment's privacy mode
+    pub fn apply_privacy(&mut self, privacy: &PrivacyMode) {
+        for assessment in
+            std::iter::once(&mut self.chosen_protocol).chain(self.other_protocols.iter_mut())
+        {
+            privacy.apply_to_liquidity(&mut assessment.risk_metrics.liquidity_risk);
+        }
+        apply_partial_privacy(&mut self.partial_protocols, privacy);
+    }
+}
+
+impl PartialRiskModelResponse {
+    /// Hides depositor-level data according to the deployment's privacy mode
+    pub fn apply_privacy(&mut self, privacy: &PrivacyMode) {
+        apply_partial_privacy(&mut self.ranking, privacy);
+    }
+}
+
+fn apply_partial_privacy(assessments: &mut [PartialAssessment], privacy: &PrivacyMode) {
+    for assessment in assessments {
+        if let Some(liquidity_risk) = &mut assessment.risk_metrics.liquidity_risk {
+            privacy.apply_to_liquidity(liquidity_risk);
+        }
+    }
+}
+
+/// Body of `/risk_model` for the reserve of `selected_registry`, the default one when `None`
+async fn risk_model_body(
+    state: &AppState,
+    query: &RiskModelQuery,
+    selected_registry: Option<&ProtocolRegistry>,
+    locale: Locale,
+) -> Result<RiskModelBody, RiskCalculationError> {
+    let registry = selected_registry.unwrap_or(&state.registry);
+    // The default reserve is kept up to date by the background refresh
+    let snapshot = if selected_registry.is_none() {
+        match precomputed_risk_model(&state.redis_client, locale, query.what_if, Utc::now()).await {
+            Ok(Some(response)) => return Ok(RiskModelBody::Complete(Box::new(response))),
+            Ok(None) => {}
+            Err(e) => tracing::error!("Failed to load precomputed risk model: {}", e),
+        }
+        registry.cached_snapshot().await
+    } else {
+        registry.snapshot().await
+    };
+    let snapshot = match snapshot {
+        Ok(snapshot) => snapshot,
+        Err(e) if query.allow_partial => {
+            let mut response = PartialRiskModelResponse::assess(registry, e).await?;
+            response.apply_privacy(&state.config.privacy);
+            return Ok(RiskModelBody::Partial(response));
+        }
+        Err(e) => return Err(e),
+    };
+    let mut response =
+        RiskModelResponse::from_snapshot(snapshot, Utc::now(), query.what_if, locale)?;
+    if query.allow_partial && !response.unavailable_protocols.is_empty() {
+        let unavailable: Vec<Protocol> = response
+            .unavailable_protocols
+            .iter()
+            .map(|unavailable| unavailable.protocol.clone())
+            .collect();
+        response.partial_protocols = registry.compare_partial(&unavailable).await.0;
     }
+    response.apply_privacy(&state.config.privacy);
+    Ok(RiskModelBody::Complete(Box::new(response)))
+}
+
+/// Conditional GETs get a 304 while the snapshot served hasn't changed
+pub async fn risk_model(
+    State(state): State<AppState>,
+    Query(query): Query<RiskModelQuery>,
+    RawQuery(raw_query): RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let locale = Locale::from_param(query.lang.as_deref())?;
+        let volatility_window = query.volatility_window()?;
+        let default_reserve = query.market.is_none() && query.reserve.is_none();
+        let selected_registry = if default_reserve {
+            None
+        } else {
+            Some(ProtocolRegistry::with_all_protocols(
+                state.redis_client.clone(),
+                query.kamino_reserve(&state.config.kamino_reserve)?,
+            ))
+        };
+
+        let mut body = risk_model_body(&state, &query, selected_registry.as_ref(), locale).await?;
+        if let (Some(window), RiskModelBody::Complete(response)) = (volatility_window, &mut body) {
+            let registry = selected_registry.as_ref().unwrap_or(&state.registry);
+            response.window_volatility = registry.window_volatility(&window).await;
+        }
+        Ok(body)
+    })
     .await;
 
-    match result {
-        Ok(json) => json.into_response(),
-        Err(e) => {
-            let error_response = serde_json::json!({
-                "error": e.to_string(),
-                "error_type": format!("{:?}", e)
-            });
-            (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                axum::Json(error_response),
+    let validators = match &result {
+        Ok(RiskModelBody::Complete(response)) => Some(Validators::new(
+            &response.snapshot_id,
+            raw_query.as_deref().unwrap_or_default(),
+            response.computed_at,
+        )),
+        _ => None,
+    };
+    conditional_response(
+        validators,
+        &headers,
+        json_response(result, timings_requested(&query.debug).then_some(timings)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timings_block() {
+        let timings = TimingsReport {
+            cache_read_ms: 1.0,
+            rpc_ms: 0.0,
+            external_api_ms: 0.0,
+            compute_ms: 0.5,
+            total_ms: 1.5,
+            recomputed: Vec::new(),
+        };
+        let object = with_timings_block(serde_json::json!({ "id": 1 }), timings.clone());
+        assert_eq!(object["id"], 1);
+        assert_eq!(object["timings"]["total_ms"], 1.5);
+
+        let list = with_timings_block(serde_json::json!([{ "id": 1 }, { "id": 2 }]), timings);
+        assert_eq!(list["data"], serde_json::json!([{ "id": 1 }, { "id": 2 }]));
+        assert_eq!(list["timings"]["cache_read_ms"], 1.0);
+    }
+
+    #[test]
+    fn test_what_if_choice_depends_on_preset() {
+        let sub_scores = [
+            // Low protocol risk but poor liquidity
+            ProtocolSubScores {
+                protocol: Protocol::Kamino,
+                liquidity_risk: 60.0,
+                volatility_risk: 1.0,
+                protocol_risk: 10.0,
+                oracle_risk: 0.0,
+            },
+            // Great liquidity but high protocol risk
+            ProtocolSubScores {
+                protocol: Protocol::Marginfy,
+                liquidity_risk: 30.0,
+                volatility_risk: 1.0,
+                protocol_risk: 80.0,
+                oracle_risk: 0.0,
+            },
+        ];
+        let results = what_if(&sub_scores);
+        assert_eq!(results.len(), WeightPreset::ALL.len());
+
+        let chosen = |preset| {
+            results
+                .iter()
+                .find(|result| result.preset == preset)
+                .unwrap()
+                .chosen_protocol
+                .clone()
+        };
+        assert_eq!(chosen(WeightPreset::Conservative), Protocol::Kamino);
+        assert_eq!(chosen(WeightPreset::Aggressive), Protocol::Marginfy);
+    }
+
+    #[test]
+    fn test_risk_profile_serde() {
+        let custom = RiskProfile::from_param("custom:35.5:6000").unwrap();
+        assert_eq!(custom, RiskProfile::custom(35.5, 6_000).unwrap());
+        assert_eq!(
+            serde_json::to_string(&custom).unwrap(),
+            "\"Custom:35.5:6000\""
+        );
+        assert_eq!(custom.to_param(), "custom:35.5:6000");
+        assert_eq!(RiskProfile::from_param(&custom.to_param()).unwrap(), custom);
+        assert_eq!(RiskProfile::High.to_param(), "high");
+        // Presets keep their stored form, profiles key stored portfolios
+        assert_eq!(
+            serde_json::to_string(&RiskProfile::Medium).unwrap(),
+            "\"Medium\""
+        );
+        let portfolio: std::collections::HashMap<RiskProfile, u64> =
+            serde_json::from_str(r#"{"Low": 1, "Custom:35.5:6000": 2}"#).unwrap();
+        assert_eq!(portfolio[&custom], 2);
+
+        assert!(RiskProfile::from_param("custom:35.5").is_err());
+        assert!(RiskProfile::from_param("custom:-1:6000").is_err());
+        assert!(RiskProfile::from_param("custom:35:20000").is_err());
+    }
+
+    #[test]
+    fn test_normalization() {
+        let min_max = Normalization::MinMax {
+            min: 10.0,
+            max: 30.0,
+        };
+        assert_eq!(min_max.apply(20.0), 50.0);
+        assert_eq!(min_max.apply(0.0), 0.0);
+        assert_eq!(min_max.apply(50.0), 100.0);
+
+        let logistic = Normalization::Logistic {
+            midpoint: 5.0,
+            steepness: 0.5,
+        };
+        assert_eq!(logistic.apply(5.0), 50.0);
+        assert!(logistic.apply(0.0) > 0.0 && logistic.apply(0.0) < 10.0);
+        assert!(logistic.apply(1e6) <= 100.0 && logistic.apply(15.0) > 99.0);
+
+        let weights = RiskWeightsConfig::default();
+        let score =
+            RiskScore::from_raw(&weights, Some(150.0), Some(5.0), Some(30.0), None).unwrap();
+        assert_eq!(score.normalized.liquidity, Some(100.0));
+        assert_eq!(score.normalized.volatility, Some(50.0));
+        assert!(score.normalized.oracle.is_none());
+        assert!((score.overall_risk - (35.0 + 12.5 + 7.5) / 0.85).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_error_status_codes() {
+        let invalid = RiskProfile::from_param("reckless").unwrap_err();
+        assert_eq!(invalid.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(invalid.body()["code"], "invalid_parameter");
+        assert_eq!(
+            RiskCalculationError::UpstreamUnavailable("down".to_string()).status_code(),
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(
+            RiskCalculationError::NotReady("cold".to_string()).status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            RiskCalculationError::CustomError("bug".to_string()).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            RiskCalculationError::Conflict("in progress".to_string()).status_code(),
+            StatusCode::CONFLICT
+        );
+    }
+
+    #[test]
+    fn test_cache_keys_are_namespaced() {
+        let redis_client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let kamino = crate::kamino::KaminoRisk {
+            redis_client: redis_client.clone(),
+            reserve: KaminoReserveConfig::usdc(),
+            sources: crate::kamino::sources::LiveSources,
+        };
+        let marginfi = crate::marginfi::MarginfiRisk { redis_client };
+        assert_eq!(
+            kamino.cache_key("deposits:largest"),
+            format!(
+                "v2:kamino:{}:deposits:largest",
+                crate::kamino::reserve::KAMINO_USDC_RESERVE
             )
-                .into_response()
+        );
+        assert_ne!(
+            kamino.cache_key("deposits:largest"),
+            marginfi.cache_key("deposits:largest")
+        );
+    }
+
+    /// Every pillar takes 50ms, so sequential computation would take 200ms
+    #[derive(Clone)]
+    struct SlowProtocol {
+        redis_client: redis::Client,
+        /// Fails the liquidity pillar, as when the deposits scan fails
+        fail_liquidity: bool,
+        /// When each pillar computation started and finished, in order
+        events: std::sync::Arc<std::sync::Mutex<Vec<&'static str>>>,
+    }
+
+    impl SlowProtocol {
+        fn new(redis_url: &str, fail_liquidity: bool) -> Self {
+            SlowProtocol {
+                redis_client: redis::Client::open(redis_url).unwrap(),
+                fail_liquidity,
+                events: Default::default(),
+            }
+        }
+
+        async fn compute(&self) {
+            self.events.lock().unwrap().push("started");
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            self.events.lock().unwrap().push("finished");
+        }
+    }
+
+    impl ProtocolRisk for SlowProtocol {
+        fn redis_client(&self) -> &redis::Client {
+            &self.redis_client
+        }
+        fn protocol(&self) -> Protocol {
+            Protocol::Drift
+        }
+        fn cache_namespace(&self) -> String {
+            if self.fail_liquidity {
+                "slow-no-liquidity".to_string()
+            } else {
+                "slow".to_string()
+            }
+        }
+        async fn calculate_liquidity_risk(
+            &self,
+        ) -> Result<LiquidityRiskMetrics, RiskCalculationError> {
+            self.compute().await;
+            if self.fail_liquidity {
+                return Err(RiskCalculationError::UpstreamUnavailable(
+                    "deposits scan failed".to_string(),
+                ));
+            }
+            Ok(LiquidityRiskMetrics {
+                liquidity_risk: 10.0,
+                ..Default::default()
+            })
+        }
+        async fn calculate_volatility_risk(
+            &self,
+        ) -> Result<VolatilityRiskMetrics, RiskCalculationError> {
+            self.compute().await;
+            Ok(VolatilityRiskMetrics {
+                volatility_risk: 20.0,
+                ..Default::default()
+            })
+        }
+        async fn calculate_protocol_risk(
+            &self,
+        ) -> Result<ProtocolRiskMetrics, RiskCalculationError> {
+            self.compute().await;
+            Ok(ProtocolRiskMetrics {
+                protocol_risk: 30.0,
+                penalties: Vec::new(),
+                components: Vec::new(),
+            })
+        }
+        async fn calculate_oracle_risk(&self) -> Result<OracleRiskMetrics, RiskCalculationError> {
+            self.compute().await;
+            Ok(OracleRiskMetrics {
+                feeds: Vec::new(),
+                oracle_risk: 40.0,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_calculate_all_runs_pillars_concurrently() {
+        let protocol = SlowProtocol::new("redis://127.0.0.1:1/", false);
+        let response = protocol.calculate_all().await.unwrap();
+        // Every pillar started before the first one finished
+        assert_eq!(
+            protocol.events.lock().unwrap()[..],
+            [
+                "started", "started", "started", "started", "finished", "finished", "finished",
+                "finished"
+            ]
+        );
+        assert!((response.overall_risk.overall_risk - slow_overall_risk()).abs() < 1e-9);
+        // The raw volatility is kept, the normalized one is weighted
+        assert_eq!(response.volatility_risk.volatility_risk, 20.0);
+        assert_eq!(
+            response.overall_risk.normalized.volatility,
+            Some(slow_volatility())
+        );
+    }
+
+    /// Volatility of [`SlowProtocol`] on the 0–100 scale, the other pillars are in percent
+    fn slow_volatility() -> f64 {
+        ScoreNormalization::default().volatility.apply(20.0)
+    }
+
+    fn slow_overall_risk() -> f64 {
+        0.35 * 10.0 + 0.25 * slow_volatility() + 0.25 * 30.0 + 0.15 * 40.0
+    }
+
+    #[tokio::test]
+    async fn test_calculate_partial() {
+        let protocol = SlowProtocol::new("redis://127.0.0.1:1/", true);
+        assert!(protocol.calculate_all().await.is_err());
+        let response = protocol.calculate_partial().await.unwrap();
+        assert!(response.liquidity_risk.is_none());
+        assert_eq!(response.errors.len(), 1);
+        assert_eq!(response.errors[0].pillar, Pillar::Liquidity);
+        assert_eq!(response.errors[0].code, "upstream_unavailable");
+        let expected = (0.25 * slow_volatility() + 0.25 * 30.0 + 0.15 * 40.0) / 0.65;
+        assert!((response.overall_risk.overall_risk - expected).abs() < 1e-9);
+        assert!(response.overall_risk.normalized.liquidity.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_calculate_all_can_be_spawned() {
+        let protocol = SlowProtocol::new("redis://127.0.0.1/", false);
+        let response = tokio::spawn(async move { protocol.calculate_all().await })
+            .await
+            .unwrap()
+            .unwrap();
+        assert!((response.overall_risk.overall_risk - slow_overall_risk()).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_cache_recomputes_every_pillar() {
+        // Nothing listens on port 1, so every cached pillar has to be recomputed
+        let protocol = SlowProtocol::new("redis://127.0.0.1:1/", false);
+        let (response, timings) = with_timings(protocol.calculate_all()).await;
+        assert!(response.is_ok());
+        assert_eq!(
+            timings.recomputed.len(),
+            4,
+            "recomputed {:?}",
+            timings.recomputed
+        );
+        assert!(timings.recomputed.contains(&"slow:protocol".to_string()));
+    }
+
+    #[test]
+    fn test_stale_while_revalidate() {
+        let now = Utc::now();
+        let cached = CachedPillar {
+            computed_at: now - chrono::Duration::seconds(90),
+            metrics: (),
+        };
+        assert!(cached.is_stale(60, now));
+        assert!(!cached.is_stale(120, now));
+
+        // Expiring caches never hold stale metrics to revalidate
+        assert!(!CacheMode::Expire.revalidates(&cached, 60, now));
+        assert!(CacheMode::StaleWhileRevalidate.revalidates(&cached, 60, now));
+        assert!(!CacheMode::StaleWhileRevalidate.revalidates(&cached, 120, now));
+        assert_eq!(CacheMode::Expire.hard_ttl(60), 60);
+        assert_eq!(CacheMode::StaleWhileRevalidate.hard_ttl(60), 120);
+    }
+
+    #[test]
+    fn test_what_if_without_protocols() {
+        assert!(what_if(&[]).is_empty());
+    }
+
+    fn assessment(protocol: Protocol, overall_risk: f64) -> ProtocolAssessment {
+        ProtocolAssessment {
+            protocol,
+            scope: String::new(),
+            risk_metrics: RiskResponse {
+                schema_version: SCHEMA_VERSION,
+                liquidity_risk: LiquidityRiskMetrics {
+                    liquidity_risk: overall_risk,
+                    ..Default::default()
+                },
+                volatility_risk: VolatilityRiskMetrics {
+                    volatility_risk: overall_risk,
+                    ..Default::default()
+                },
+                protocol_risk: ProtocolRiskMetrics {
+                    protocol_risk: overall_risk,
+                    penalties: Vec::new(),
+                    components: Vec::new(),
+                },
+                oracle_risk: OracleRiskMetrics {
+                    feeds: Vec::new(),
+                    oracle_risk: overall_risk,
+                },
+                overall_risk: RiskScore {
+                    overall_risk,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
         }
     }
+
+    #[test]
+    fn test_risk_model_response() {
+        use chrono::TimeZone;
+
+        let computed_at = Utc.with_ymd_and_hms(2024, 5, 1, 13, 2, 3).unwrap();
+        let snapshot = RiskSnapshot {
+            snapshot_id: crate::snapshot::snapshot_id(computed_at),
+            computed_at,
+            comparison: crate::registry::ProtocolComparison {
+                ranking: vec![
+                    assessment(Protocol::Marginfy, 10.0),
+                    assessment(Protocol::Kamino, 20.0),
+                ],
+                unavailable: Vec::new(),
+            },
+        };
+        let now = computed_at + chrono::Duration::minutes(5);
+        let response = RiskModelResponse::from_snapshot(snapshot, now, false, Locale::En).unwrap();
+        assert_eq!(response.chosen_protocol.protocol, Protocol::Marginfy);
+        assert_eq!(response.other_protocols.len(), 1);
+        assert_eq!(response.ranking[1].rank, 2);
+        assert_eq!(response.ranking[1].protocol, Protocol::Kamino);
+        assert_eq!(response.attribution.len(), 4);
+        assert!(response
+            .choice_reason
+            .starts_with("Marginfy shows the lowest overall risk (10.0)"));
+        assert_eq!(response.freshness.age_seconds, 300);
+        assert_eq!(
+            response.freshness.refreshes_at,
+            Utc.with_ymd_and_hms(2024, 5, 1, 14, 0, 0).unwrap()
+        );
+        assert!(!response.freshness.stale);
+
+        // Typed clients can read the response back
+        let json = serde_json::to_value(&response).unwrap();
+        assert!(json.get("what_if").is_none());
+        let parsed: RiskModelResponse = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.ranking, response.ranking);
+    }
 }
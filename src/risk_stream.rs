@@ -0,0 +1,171 @@
+use std::{sync::OnceLock, time::Duration};
+
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    registry::ProtocolAssessment, risk_model::Protocol, snapshot::RiskSnapshot, state::AppState,
+};
+
+/// Updates a subscriber can fall behind by before it misses some
+const UPDATE_BUFFER: usize = 16;
+/// Comment sent on idle streams so proxies don't close them
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+static UPDATES: OnceLock<broadcast::Sender<RiskUpdate>> = OnceLock::new();
+
+fn updates() -> &'static broadcast::Sender<RiskUpdate> {
+    UPDATES.get_or_init(|| broadcast::channel(UPDATE_BUFFER).0)
+}
+
+/// Scores of one protocol in an update
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolScores {
+    pub protocol: Protocol,
+    pub overall_risk: f64,
+    pub liquidity_risk: f64,
+    pub volatility_risk: f64,
+    pub protocol_risk: f64,
+    pub oracle_risk: f64,
+}
+
+impl From<&ProtocolAssessment> for ProtocolScores {
+    fn from(assessment: &ProtocolAssessment) -> Self {
+        let metrics = &assessment.risk_metrics;
+        ProtocolScores {
+            protocol: assessment.protocol.clone(),
+            overall_risk: metrics.overall_risk.overall_risk,
+            liquidity_risk: metrics.liquidity_risk.liquidity_risk,
+            volatility_risk: metrics.volatility_risk.volatility_risk,
+            protocol_risk: metrics.protocol_risk.protocol_risk,
+            oracle_risk: metrics.oracle_risk.oracle_risk,
+        }
+    }
+}
+
+/// Pushed to `/risk_model/stream` after every background refresh
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RiskUpdate {
+    pub snapshot_id: String,
+    pub computed_at: DateTime<Utc>,
+    /// Protocols ordered from lowest to highest overall risk
+    pub ranking: Vec<ProtocolScores>,
+    /// Protocols that couldn't be assessed in this refresh, their last scores still apply
+    pub unavailable: Vec<Protocol>,
+}
+
+impl From<&RiskSnapshot> for RiskUpdate {
+    fn from(snapshot: &RiskSnapshot) -> Self {
+        RiskUpdate {
+            snapshot_id: snapshot.snapshot_id.clone(),
+            computed_at: snapshot.computed_at,
+            ranking: snapshot
+                .comparison
+                .ranking
+                .iter()
+                .map(ProtocolScores::from)
+                .collect(),
+            unavailable: snapshot
+                .comparison
+                .unavailable
+                .iter()
+                .map(|unavailable| unavailable.protocol.clone())
+                .collect(),
+        }
+    }
+}
+
+/// Sends the scores of a fresh snapshot to every open stream of this instance
+pub fn publish(snapshot: &RiskSnapshot) {
+    // Fails only when nobody is subscribed
+    let _ = updates().send(RiskUpdate::from(snapshot));
+}
+
+/// `GET /risk_model/stream`, server-sent `risk_update` events with the scores
+/// of every refresh, instead of polling `/risk_model`
+///
+/// Streams end when the server shuts down, so they don't hold up draining.
+pub async fn risk_stream(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    Sse::new(update_events(updates().subscribe(), state.shutdown))
+        .keep_alive(KeepAlive::new().interval(KEEP_ALIVE).text("keep-alive"))
+}
+
+/// Every update published from now on, until `shutdown` is cancelled
+///
+/// Updates a slow subscriber fell behind on are skipped, the next one carries
+/// every protocol.
+pub fn subscribe(shutdown: CancellationToken) -> impl Stream<Item = RiskUpdate> {
+    update_stream(updates().subscribe(), shutdown)
+}
+
+fn update_stream(
+    receiver: broadcast::Receiver<RiskUpdate>,
+    shutdown: CancellationToken,
+) -> impl Stream<Item = RiskUpdate> {
+    stream::unfold(
+        (receiver, shutdown),
+        |(mut receiver, shutdown)| async move {
+            loop {
+                let update = tokio::select! {
+                    update = receiver.recv() => update,
+                    _ = shutdown.cancelled() => return None,
+                };
+                match update {
+                    Ok(update) => return Some((update, (receiver, shutdown))),
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::debug!("Risk stream skipped {} updates", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    )
+}
+
+fn update_events(
+    receiver: broadcast::Receiver<RiskUpdate>,
+    shutdown: CancellationToken,
+) -> impl Stream<Item = Result<Event, axum::Error>> {
+    update_stream(receiver, shutdown).map(|update| {
+        Event::default()
+            .event("risk_update")
+            .id(update.snapshot_id.clone())
+            .json_data(&update)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::ProtocolComparison;
+
+    #[tokio::test]
+    async fn test_update_events() {
+        let shutdown = CancellationToken::new();
+        let (sender, receiver) = broadcast::channel(UPDATE_BUFFER);
+        let events = update_events(receiver, shutdown.clone());
+        tokio::pin!(events);
+
+        let snapshot = RiskSnapshot::new(
+            ProtocolComparison {
+                ranking: vec![],
+                unavailable: vec![],
+            },
+            Utc::now(),
+        );
+        sender.send(RiskUpdate::from(&snapshot)).unwrap();
+        assert!(events.next().await.unwrap().is_ok());
+
+        shutdown.cancel();
+        assert!(events.next().await.is_none());
+    }
+}
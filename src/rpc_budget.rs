@@ -0,0 +1,317 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Instant,
+};
+
+use axum::{
+    extract::Query,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use solana_client::rpc_request::RpcRequest;
+
+use crate::{
+    risk_model::{json_response, timings_requested, DebugQuery, RiskCalculationError},
+    timings::with_timings,
+};
+
+static CREDIT_BUDGET: OnceLock<CreditBudget> = OnceLock::new();
+
+/// Share of the hourly budget kept for requests, non-urgent refreshes wait below it
+const DEFAULT_RESERVE_SHARE: f64 = 0.1;
+/// Credits of a `getProgramAccounts` scan, billed more than other calls
+const DEFAULT_SCAN_CREDITS: u64 = 10;
+const SECS_PER_HOUR: f64 = 3600.0;
+
+/// RPC credits that can be spent per hour and what the calls cost
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CreditBudgetConfig {
+    /// `None` only counts the credits spent, nothing is ever deferred
+    pub hourly_credits: Option<u64>,
+    /// Share of `hourly_credits` left under which non-urgent refreshes are deferred
+    pub reserve_share: f64,
+    pub scan_credits: u64,
+}
+
+impl Default for CreditBudgetConfig {
+    fn default() -> Self {
+        CreditBudgetConfig {
+            hourly_credits: None,
+            reserve_share: DEFAULT_RESERVE_SHARE,
+            scan_credits: DEFAULT_SCAN_CREDITS,
+        }
+    }
+}
+
+impl CreditBudgetConfig {
+    /// Reads `RPC_HOURLY_CREDITS`, `RPC_BUDGET_RESERVE_SHARE` and
+    /// `RPC_SCAN_CREDITS`, the defaults for those not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let parse_error = |name: &str, e: String| {
+            RiskCalculationError::ParseError(format!("{} must be a number: {}", name, e))
+        };
+        let mut config = CreditBudgetConfig::default();
+        if let Ok(credits) = std::env::var("RPC_HOURLY_CREDITS") {
+            config.hourly_credits = Some(
+                credits
+                    .trim()
+                    .parse()
+                    .map_err(|e| parse_error("RPC_HOURLY_CREDITS", format!("{}", e)))?,
+            );
+        }
+        if let Ok(share) = std::env::var("RPC_BUDGET_RESERVE_SHARE") {
+            config.reserve_share = share
+                .trim()
+                .parse()
+                .map_err(|e| parse_error("RPC_BUDGET_RESERVE_SHARE", format!("{}", e)))?;
+            if !(0.0..=1.0).contains(&config.reserve_share) {
+                return Err(RiskCalculationError::ParseError(
+                    "RPC_BUDGET_RESERVE_SHARE must be between 0 and 1".to_string(),
+                ));
+            }
+        }
+        if let Ok(credits) = std::env::var("RPC_SCAN_CREDITS") {
+            config.scan_credits = credits
+                .trim()
+                .parse()
+                .map_err(|e| parse_error("RPC_SCAN_CREDITS", format!("{}", e)))?;
+        }
+        Ok(config)
+    }
+}
+
+/// Credits left, refilled continuously up to the hourly budget
+#[derive(Debug)]
+struct Bucket {
+    /// Below 0 once urgent calls overspent, which later refills pay back
+    available: f64,
+    refilled_at: Instant,
+}
+
+/// Token bucket of the RPC provider's credits, e.g. the Helius plan's
+///
+/// Every call going upstream is charged, urgent ones even past the budget.
+/// Background refreshes check [`CreditBudget::defer`] first, so the credits
+/// left near the end of the budget go to requests.
+#[derive(Debug)]
+pub struct CreditBudget {
+    config: CreditBudgetConfig,
+    bucket: Mutex<Bucket>,
+    consumed: AtomicU64,
+    requests: AtomicU64,
+    deferred: AtomicU64,
+}
+
+/// What has been spent since startup and what's left of the budget
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BudgetStatus {
+    pub hourly_credits: Option<u64>,
+    /// `None` without a budget
+    pub available_credits: Option<f64>,
+    pub consumed_credits: u64,
+    pub requests: u64,
+    pub deferred_refreshes: u64,
+    pub nearly_exhausted: bool,
+}
+
+impl CreditBudget {
+    /// The budget read at first use
+    pub fn global() -> &'static Self {
+        CREDIT_BUDGET.get_or_init(|| {
+            Self::new(CreditBudgetConfig::from_env().expect("RPC credit budget must be valid"))
+        })
+    }
+
+    pub fn new(config: CreditBudgetConfig) -> Self {
+        CreditBudget {
+            config,
+            bucket: Mutex::new(Bucket {
+                available: config.hourly_credits.unwrap_or_default() as f64,
+                refilled_at: Instant::now(),
+            }),
+            consumed: AtomicU64::new(0),
+            requests: AtomicU64::new(0),
+            deferred: AtomicU64::new(0),
+        }
+    }
+
+    /// Credits `request` costs
+    pub fn credits(&self, request: &RpcRequest) -> u64 {
+        match request {
+            RpcRequest::GetProgramAccounts => self.config.scan_credits,
+            _ => 1,
+        }
+    }
+
+    /// Charges a call of `request` going upstream
+    pub fn charge(&self, request: &RpcRequest) {
+        self.charge_at(self.credits(request), Instant::now());
+    }
+
+    fn charge_at(&self, credits: u64, now: Instant) {
+        self.consumed.fetch_add(credits, Ordering::Relaxed);
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(mut bucket) = self.refilled(now) {
+            bucket.available -= credits as f64;
+        }
+    }
+
+    /// The bucket refilled for the time since it last was, `None` without a budget
+    fn refilled(&self, now: Instant) -> Option<std::sync::MutexGuard<'_, Bucket>> {
+        let hourly_credits = self.config.hourly_credits? as f64;
+        let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.available = (bucket.available
+            + hourly_credits * elapsed.as_secs_f64() / SECS_PER_HOUR)
+            .min(hourly_credits);
+        bucket.refilled_at = now;
+        Some(bucket)
+    }
+
+    fn nearly_exhausted_at(&self, now: Instant) -> bool {
+        let Some(hourly_credits) = self.config.hourly_credits else {
+            return false;
+        };
+        self.refilled(now).is_some_and(|bucket| {
+            bucket.available < hourly_credits as f64 * self.config.reserve_share
+        })
+    }
+
+    /// Whether `task` should wait for the budget to refill, counted and logged when it should
+    pub fn defer(&self, task: &str) -> bool {
+        let defer = self.nearly_exhausted_at(Instant::now());
+        if defer {
+            self.deferred.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("RPC credit budget nearly exhausted, deferring {}", task);
+        }
+        defer
+    }
+
+    pub fn status(&self) -> BudgetStatus {
+        self.status_at(Instant::now())
+    }
+
+    fn status_at(&self, now: Instant) -> BudgetStatus {
+        BudgetStatus {
+            hourly_credits: self.config.hourly_credits,
+            available_credits: self.refilled(now).map(|bucket| bucket.available),
+            consumed_credits: self.consumed.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            deferred_refreshes: self.deferred.load(Ordering::Relaxed),
+            nearly_exhausted: self.nearly_exhausted_at(now),
+        }
+    }
+}
+
+impl BudgetStatus {
+    /// The status in the Prometheus text format
+    pub fn to_prometheus(&self) -> String {
+        let mut metrics = vec![
+            (
+                "rpc_credits_consumed_total",
+                "counter",
+                "RPC credits spent since startup",
+                self.consumed_credits as f64,
+            ),
+            (
+                "rpc_requests_total",
+                "counter",
+                "RPC calls sent upstream since startup",
+                self.requests as f64,
+            ),
+            (
+                "rpc_refreshes_deferred_total",
+                "counter",
+                "Background refreshes deferred to save credits",
+                self.deferred_refreshes as f64,
+            ),
+        ];
+        if let (Some(hourly_credits), Some(available_credits)) =
+            (self.hourly_credits, self.available_credits)
+        {
+            metrics.push((
+                "rpc_credits_hourly_budget",
+                "gauge",
+                "RPC credits that can be spent per hour",
+                hourly_credits as f64,
+            ));
+            metrics.push((
+                "rpc_credits_available",
+                "gauge",
+                "RPC credits left in the budget",
+                available_credits,
+            ));
+        }
+        metrics
+            .into_iter()
+            .map(|(name, kind, help, value)| {
+                format!(
+                    "# HELP {0} {1}\n# TYPE {0} {2}\n{0} {3}\n",
+                    name, help, kind, value
+                )
+            })
+            .collect()
+    }
+}
+
+/// `GET /metrics`: the RPC credit consumption for Prometheus
+pub async fn metrics() -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        CreditBudget::global().status().to_prometheus(),
+    )
+        .into_response()
+}
+
+/// `GET /admin/rpc_budget`: credits spent and left of the hourly budget
+pub async fn rpc_budget(Query(query): Query<DebugQuery>) -> Response {
+    let (result, timings) =
+        with_timings(async { Ok::<_, RiskCalculationError>(CreditBudget::global().status()) })
+            .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_credit_budget() {
+        let budget = CreditBudget::new(CreditBudgetConfig {
+            hourly_credits: Some(3_600),
+            ..CreditBudgetConfig::default()
+        });
+        let start = Instant::now();
+        assert_eq!(budget.credits(&RpcRequest::GetProgramAccounts), 10);
+        assert_eq!(budget.credits(&RpcRequest::GetMultipleAccounts), 1);
+
+        budget.charge_at(3_300, start);
+        let status = budget.status_at(start);
+        assert_eq!(status.available_credits, Some(300.0));
+        assert!(status.nearly_exhausted);
+        assert_eq!((status.consumed_credits, status.requests), (3_300, 1));
+
+        // A credit a second refills the reserve after a minute
+        let later = budget.status_at(start + Duration::from_secs(60));
+        assert_eq!(later.available_credits, Some(360.0));
+        assert!(!later.nearly_exhausted);
+        // Never past the hourly budget
+        let refilled = budget.status_at(start + Duration::from_secs(2 * 3_600));
+        assert_eq!(refilled.available_credits, Some(3_600.0));
+        assert!(refilled
+            .to_prometheus()
+            .contains("rpc_credits_consumed_total 3300\n"));
+
+        let unlimited = CreditBudget::new(CreditBudgetConfig::default());
+        unlimited.charge_at(1_000_000, start);
+        assert!(!unlimited.nearly_exhausted_at(start));
+        assert_eq!(unlimited.status_at(start).available_credits, None);
+    }
+}
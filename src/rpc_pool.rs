@@ -0,0 +1,520 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
+};
+
+use futures::{stream, StreamExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    nonblocking::rpc_client::RpcClient,
+};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{cluster::Cluster, risk_model::RiskCalculationError, upstream_fixtures::rpc_client};
+
+static RPC_POOL: OnceLock<RpcPool> = OnceLock::new();
+static CHUNKED_FETCH: OnceLock<ChunkedFetch> = OnceLock::new();
+
+/// Accounts fetched per `getMultipleAccounts` call, the RPC's own limit
+pub const CHUNK_SIZE: usize = 100;
+/// Delay before the first retry of a failed chunk, doubling with every retry
+const CHUNK_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+struct Endpoint {
+    client: RpcClient,
+    /// Host of the url, urls carry API keys
+    name: String,
+    failures: AtomicU64,
+}
+
+/// RPC endpoints shared by concurrent requests, rotating away from failing ones
+///
+/// Every call goes to the current endpoint. When it fails, e.g. rate limited,
+/// the pool moves on to the next endpoint for this and all later calls, so one
+/// provider's limits don't fail a whole account scan. Failures are counted per
+/// endpoint and logged with the count.
+pub struct RpcPool {
+    endpoints: Vec<Endpoint>,
+    current: AtomicUsize,
+}
+
+impl RpcPool {
+    /// The pool read at first use, shared by every scan
+    pub fn global() -> &'static Self {
+        RPC_POOL.get_or_init(|| Self::from_env().expect("RPC endpoints must be configured"))
+    }
+
+    /// Fails without endpoints
+    pub fn new(urls: Vec<String>) -> Result<Self, RiskCalculationError> {
+        if urls.is_empty() {
+            return Err(RiskCalculationError::ParseError(
+                "HELIUS_API_KEY or RPC_FALLBACK_URLS must be set".to_string(),
+            ));
+        }
+        let endpoints = urls
+            .into_iter()
+            .map(|url| {
+                let name = reqwest::Url::parse(&url)
+                    .map_err(|e| RiskCalculationError::ParseError(format!("RPC url: {}", e)))?
+                    .host_str()
+                    .unwrap_or_default()
+                    .to_string();
+                Ok(Endpoint {
+                    client: rpc_client(url),
+                    name,
+                    failures: AtomicU64::new(0),
+                })
+            })
+            .collect::<Result<_, RiskCalculationError>>()?;
+        Ok(RpcPool {
+            endpoints,
+            current: AtomicUsize::new(0),
+        })
+    }
+
+    /// The cluster's Helius endpoint when `HELIUS_API_KEY` is set, followed by
+    /// the comma separated `RPC_FALLBACK_URLS` in the order they're tried
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let mut urls = Vec::new();
+        if let Ok(api_key) = std::env::var("HELIUS_API_KEY") {
+            urls.push(Cluster::global().rpc_url(&api_key));
+        }
+        if let Ok(fallbacks) = std::env::var("RPC_FALLBACK_URLS") {
+            urls.extend(
+                fallbacks
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|url| !url.is_empty())
+                    .map(String::from),
+            );
+        }
+        Self::new(urls)
+    }
+
+    /// Runs `call` on the current endpoint, moving on to the next one on failure
+    ///
+    /// Gives up with the last error once every endpoint failed the call.
+    pub async fn call<'a, T, F, Fut>(&'a self, call: F) -> Result<T, ClientError>
+    where
+        F: Fn(&'a RpcClient) -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        let mut index = self.current.load(Ordering::Relaxed) % self.endpoints.len();
+        let mut attempts = 0;
+        loop {
+            let endpoint = &self.endpoints[index];
+            let error = match call(&endpoint.client).await {
+                Ok(result) => return Ok(result),
+                Err(error) => error,
+            };
+            let failures = endpoint.failures.fetch_add(1, Ordering::Relaxed) + 1;
+            tracing::warn!(
+                "RPC endpoint {} {} ({} failures): {}",
+                endpoint.name,
+                if is_rate_limited(&error) {
+                    "rate limited"
+                } else {
+                    "failed"
+                },
+                failures,
+                error
+            );
+            attempts += 1;
+            if attempts == self.endpoints.len() {
+                return Err(error);
+            }
+            let next = (index + 1) % self.endpoints.len();
+            // Calls failing on the same endpoint concurrently only rotate once
+            let _ =
+                self.current
+                    .compare_exchange(index, next, Ordering::Relaxed, Ordering::Relaxed);
+            index = next;
+        }
+    }
+}
+
+/// How much of a scan has data, chunks that failed left their accounts out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct DataCompleteness {
+    /// Accounts the scan covers
+    pub accounts: usize,
+    /// Accounts left without data by the chunks that failed
+    pub missing_accounts: usize,
+}
+
+impl DataCompleteness {
+    /// Share of the accounts without data, between 0 and 1
+    pub fn missing_share(&self) -> f64 {
+        if self.accounts == 0 {
+            return 0.0;
+        }
+        self.missing_accounts as f64 / self.accounts as f64
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing_accounts == 0
+    }
+}
+
+/// How account scans fetch their accounts, in chunks of [`CHUNK_SIZE`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChunkedFetch {
+    /// Chunks in flight at once, so large scans don't flood the RPC
+    pub concurrency: usize,
+    /// Retries of a failing chunk before its accounts are left out
+    pub retries: u32,
+    /// Percent of a scan's accounts failed chunks can leave without data
+    /// before the whole scan fails
+    pub max_failed_pct: f64,
+}
+
+impl Default for ChunkedFetch {
+    fn default() -> Self {
+        ChunkedFetch {
+            concurrency: 8,
+            retries: 2,
+            max_failed_pct: 5.0,
+        }
+    }
+}
+
+impl ChunkedFetch {
+    /// The limits read at first use
+    pub fn global() -> Self {
+        *CHUNKED_FETCH
+            .get_or_init(|| Self::from_env().expect("RPC chunk configuration must be valid"))
+    }
+
+    /// Reads `RPC_CHUNK_CONCURRENCY`, `RPC_CHUNK_RETRIES` and
+    /// `RPC_CHUNK_MAX_FAILED_PCT`, defaults for the unset ones
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let mut config = Self::default();
+        if let Ok(concurrency) = std::env::var("RPC_CHUNK_CONCURRENCY") {
+            config.concurrency = concurrency
+                .trim()
+                .parse()
+                .ok()
+                .filter(|concurrency| *concurrency > 0)
+                .ok_or_else(|| {
+                    RiskCalculationError::ParseError(format!(
+                        "RPC_CHUNK_CONCURRENCY must be a positive integer: {:?}",
+                        concurrency
+                    ))
+                })?;
+        }
+        if let Ok(retries) = std::env::var("RPC_CHUNK_RETRIES") {
+            config.retries = retries.trim().parse().map_err(|_| {
+                RiskCalculationError::ParseError(format!(
+                    "RPC_CHUNK_RETRIES must be a non-negative integer: {:?}",
+                    retries
+                ))
+            })?;
+        }
+        if let Ok(max_failed_pct) = std::env::var("RPC_CHUNK_MAX_FAILED_PCT") {
+            config.max_failed_pct = max_failed_pct
+                .trim()
+                .parse()
+                .ok()
+                .filter(|pct| (0.0..=100.0).contains(pct))
+                .ok_or_else(|| {
+                    RiskCalculationError::ParseError(format!(
+                        "RPC_CHUNK_MAX_FAILED_PCT must be between 0 and 100: {:?}",
+                        max_failed_pct
+                    ))
+                })?;
+        }
+        Ok(config)
+    }
+
+    /// Runs `fetch` on every chunk of `accounts`, see [`Self::fetch_partial`]
+    ///
+    /// Returns everything the chunks yielded and how complete that is, see
+    /// [`Self::check_completeness`].
+    pub async fn fetch<T, F, Fut>(
+        &self,
+        label: &str,
+        accounts: &[Pubkey],
+        fetch: F,
+    ) -> Result<(Vec<T>, DataCompleteness), RiskCalculationError>
+    where
+        F: Fn(Vec<Pubkey>) -> Fut,
+        Fut: Future<Output = Result<Vec<T>, ClientError>>,
+    {
+        let (fetched, missing) = self.fetch_partial(label, accounts, fetch).await;
+        let completeness = self.check_completeness(
+            label,
+            DataCompleteness {
+                accounts: accounts.len(),
+                missing_accounts: missing.len(),
+            },
+        )?;
+        Ok((fetched, completeness))
+    }
+
+    /// Fails when more than [`Self::max_failed_pct`] of a scan's accounts are
+    /// without data
+    ///
+    /// Scans with another source for some accounts of the failed chunks only
+    /// count the others as missing.
+    pub fn check_completeness(
+        &self,
+        label: &str,
+        completeness: DataCompleteness,
+    ) -> Result<DataCompleteness, RiskCalculationError> {
+        if !completeness.is_complete() {
+            tracing::warn!(
+                "{} of {} {} are without data",
+                completeness.missing_accounts,
+                completeness.accounts,
+                label
+            );
+        }
+        if completeness.missing_share() * 100.0 > self.max_failed_pct {
+            return Err(RiskCalculationError::UpstreamUnavailable(format!(
+                "{} of {} {} are without data, more than {}%",
+                completeness.missing_accounts, completeness.accounts, label, self.max_failed_pct
+            )));
+        }
+        Ok(completeness)
+    }
+
+    /// Runs `fetch` on every chunk of `accounts`, at most [`Self::concurrency`] at once
+    ///
+    /// Chunks still failing after their retries are retried once more after
+    /// the others, when a rate limit may have lifted. Returns everything the
+    /// chunks yielded and the accounts of the chunks that still failed.
+    /// Progress is logged every tenth of the chunks.
+    pub async fn fetch_partial<T, F, Fut>(
+        &self,
+        label: &str,
+        accounts: &[Pubkey],
+        fetch: F,
+    ) -> (Vec<T>, Vec<Pubkey>)
+    where
+        F: Fn(Vec<Pubkey>) -> Fut,
+        Fut: Future<Output = Result<Vec<T>, ClientError>>,
+    {
+        let total = accounts.len().div_ceil(CHUNK_SIZE);
+        let progress_every = (total / 10).max(1);
+        // Owned chunks, futures borrowing them wouldn't be `Send` for every lifetime
+        let chunks: Vec<Vec<Pubkey>> = accounts.chunks(CHUNK_SIZE).map(<[_]>::to_vec).collect();
+        let mut chunks = stream::iter(chunks)
+            .map(|chunk| async { (self.fetch_chunk(chunk.clone(), &fetch).await, chunk) })
+            .buffer_unordered(self.concurrency);
+
+        let (mut fetched, mut done, mut failed) = (Vec::new(), 0, Vec::new());
+        while let Some((result, chunk)) = chunks.next().await {
+            match result {
+                Ok(items) => fetched.extend(items),
+                Err(e) => {
+                    tracing::error!("Failed to fetch a chunk of {}: {}", label, e);
+                    failed.push(chunk);
+                }
+            }
+            done += 1;
+            if done % progress_every == 0 || done == total {
+                tracing::info!("Fetched {}/{} chunks of {}", done, total, label);
+            }
+        }
+        drop(chunks);
+
+        let mut missing = Vec::new();
+        if !failed.is_empty() {
+            tracing::info!("Retrying {} failed chunks of {}", failed.len(), label);
+            let mut retried = stream::iter(failed)
+                .map(|chunk| async { (fetch(chunk.clone()).await, chunk) })
+                .buffer_unordered(self.concurrency);
+            while let Some((result, chunk)) = retried.next().await {
+                match result {
+                    Ok(items) => fetched.extend(items),
+                    Err(e) => {
+                        tracing::error!("Failed to fetch a chunk of {} again: {}", label, e);
+                        missing.extend(chunk);
+                    }
+                }
+            }
+        }
+        (fetched, missing)
+    }
+
+    async fn fetch_chunk<T, F, Fut>(
+        &self,
+        chunk: Vec<Pubkey>,
+        fetch: &F,
+    ) -> Result<Vec<T>, ClientError>
+    where
+        F: Fn(Vec<Pubkey>) -> Fut,
+        Fut: Future<Output = Result<Vec<T>, ClientError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match fetch(chunk.clone()).await {
+                Ok(items) => return Ok(items),
+                Err(e) if attempt < self.retries => {
+                    tracing::debug!("Retrying a chunk after: {}", e);
+                    tokio::time::sleep(CHUNK_RETRY_DELAY * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+fn is_rate_limited(error: &ClientError) -> bool {
+    matches!(
+        error.kind(),
+        ClientErrorKind::Reqwest(error)
+            if error.status() == Some(reqwest::StatusCode::TOO_MANY_REQUESTS)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rotates_on_failure() {
+        let pool = RpcPool::new(vec![
+            "https://first.example".to_string(),
+            "https://second.example".to_string(),
+        ])
+        .unwrap();
+        let calls = AtomicUsize::new(0);
+        let result = pool
+            .call(|client| {
+                let attempt = calls.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    match attempt {
+                        0 => {
+                            Err(ClientErrorKind::Custom("429 Too Many Requests".to_string()).into())
+                        }
+                        _ => Ok(client.url()),
+                    }
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(result, "https://second.example");
+        assert_eq!(pool.endpoints[0].failures.load(Ordering::Relaxed), 1);
+        // Later calls start at the endpoint that worked
+        assert_eq!(pool.current.load(Ordering::Relaxed), 1);
+
+        let failed = pool
+            .call(|_| async {
+                Err::<(), ClientError>(ClientErrorKind::Custom("down".to_string()).into())
+            })
+            .await;
+        assert!(failed.is_err());
+        assert!(RpcPool::new(Vec::new()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_chunked_fetch() {
+        let accounts: Vec<_> = (0..450).map(|_| Pubkey::new_unique()).collect();
+        let config = ChunkedFetch {
+            concurrency: 2,
+            retries: 1,
+            max_failed_pct: 50.0,
+        };
+        let (in_flight, most_in_flight, calls) = (
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+            AtomicUsize::new(0),
+        );
+        let (fetched, completeness) = config
+            .fetch("accounts", &accounts, |chunk| {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                let (in_flight, most_in_flight) = (&in_flight, &most_in_flight);
+                async move {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    most_in_flight.fetch_max(now, Ordering::SeqCst);
+                    tokio::task::yield_now().await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    // The first call fails once and succeeds on its retry
+                    if call == 0 {
+                        return Err(ClientErrorKind::Custom("down".to_string()).into());
+                    }
+                    Ok(chunk)
+                }
+            })
+            .await
+            .unwrap();
+        assert!(completeness.is_complete());
+        assert_eq!(fetched.len(), accounts.len());
+        assert_eq!(calls.load(Ordering::SeqCst), 6);
+        assert!(most_in_flight.load(Ordering::SeqCst) <= 2);
+
+        // The chunk of the first account keeps failing, through its retries and the last one
+        let calls = AtomicUsize::new(0);
+        let (fetched, completeness) = config
+            .fetch("accounts", &accounts, |chunk| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                let failing = chunk.contains(&accounts[0]);
+                async move {
+                    if failing {
+                        return Err(ClientErrorKind::Custom("down".to_string()).into());
+                    }
+                    Ok(chunk)
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            completeness,
+            DataCompleteness {
+                accounts: 450,
+                missing_accounts: CHUNK_SIZE
+            }
+        );
+        assert_eq!(fetched.len(), accounts.len() - CHUNK_SIZE);
+        assert_eq!(calls.load(Ordering::SeqCst), 7);
+        let (_, missing) = config
+            .fetch_partial("accounts", &accounts, |chunk| {
+                let failing = chunk.contains(&accounts[0]);
+                async move {
+                    if failing {
+                        return Err(ClientErrorKind::Custom("down".to_string()).into());
+                    }
+                    Ok(chunk)
+                }
+            })
+            .await;
+        assert_eq!(missing, accounts[..CHUNK_SIZE]);
+        // Missing accounts with another source don't count
+        let strict = ChunkedFetch {
+            max_failed_pct: 5.0,
+            ..config
+        };
+        let partial = DataCompleteness {
+            accounts: 450,
+            missing_accounts: 100,
+        };
+        assert!(strict.check_completeness("accounts", partial).is_err());
+        assert!(strict
+            .check_completeness(
+                "accounts",
+                DataCompleteness {
+                    missing_accounts: 10,
+                    ..partial
+                }
+            )
+            .is_ok());
+
+        let failed = config
+            .fetch("accounts", &accounts, |_| async {
+                Err::<Vec<()>, ClientError>(ClientErrorKind::Custom("down".to_string()).into())
+            })
+            .await;
+        assert!(matches!(
+            failed,
+            Err(RiskCalculationError::UpstreamUnavailable(_))
+        ));
+    }
+}
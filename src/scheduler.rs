@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use chrono::{Days, Utc};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    alerts::evaluate_delta_alerts,
+    anomalies::detect_anomalies,
+    clock::{process_clock, seconds_until_next_day, seconds_until_next_hour},
+    kamino::reserve::KaminoReserveConfig,
+    marginfi::yield_data::record_hourly_point,
+    market_history::refresh_market_history,
+    precomputed::{store_precomputed, ProfileWeights},
+    proposals::propose_weight_changes,
+    registry::ProtocolRegistry,
+    reports::publish_daily_report,
+    risk_model::{RiskCalculationError, RiskProfile},
+    risk_stream,
+    rpc_budget::CreditBudget,
+    shutdown,
+    strategy::{assess_strategy, StrategyConfig},
+    weight_smoothing::smoothed_weights,
+};
+
+/// Delay after the hour boundary, so component caches have expired before refreshing
+const REFRESH_DELAY_SECS: u64 = 5;
+/// Delay before retrying a refresh that failed or had unavailable protocols
+const RETRY_DELAY_SECS: u64 = 60;
+/// Delay after midnight UTC before reporting on the day that ended
+const REPORT_DELAY_SECS: u64 = 10 * 60;
+
+/// Spawns the task that recomputes every served risk snapshot once an hour
+///
+/// HTTP handlers then only read the stored snapshots instead of paying for the
+/// RPC scans and API calls themselves. Once `shutdown` is cancelled the task
+/// stops waiting for the next hour, a refresh in progress still completes.
+///
+/// While the RPC credit budget is nearly exhausted the refresh waits for it to
+/// refill, the previous snapshot is served stale meanwhile.
+pub fn spawn_hourly_refresh(
+    redis_client: redis::Client,
+    shutdown: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    shutdown::spawn_background(async move {
+        while !shutdown.is_cancelled() {
+            let delay = if CreditBudget::global().defer("the hourly risk refresh") {
+                RETRY_DELAY_SECS
+            } else {
+                match refresh_all(&redis_client).await {
+                    Ok(_) => seconds_until_next_hour(process_clock().now()) + REFRESH_DELAY_SECS,
+                    Err(e) => {
+                        tracing::error!("Risk refresh failed, retrying: {}", e);
+                        RETRY_DELAY_SECS
+                    }
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(delay)) => {}
+                _ = shutdown.cancelled() => {}
+            }
+        }
+        tracing::info!("Hourly risk refresh stopped");
+    })
+}
+
+/// Spawns the task that reports on each day once it's over, see [`crate::reports`]
+///
+/// The previous day is reported on at startup too, if it wasn't yet. Like the
+/// hourly refresh, the report waits while the RPC credit budget is nearly exhausted.
+pub fn spawn_daily_report(
+    redis_client: redis::Client,
+    shutdown: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    shutdown::spawn_background(async move {
+        while !shutdown.is_cancelled() {
+            let now = Utc::now();
+            let yesterday = now.date_naive() - Days::new(1);
+            let report = match KaminoReserveConfig::from_env() {
+                _ if CreditBudget::global().defer("the daily risk report") => None,
+                Ok(kamino_reserve) => {
+                    Some(publish_daily_report(&redis_client, kamino_reserve, yesterday).await)
+                }
+                Err(e) => Some(Err(e)),
+            };
+            let delay = match report {
+                None => RETRY_DELAY_SECS,
+                Some(Ok(())) => seconds_until_next_day(now) + REPORT_DELAY_SECS,
+                Some(Err(e)) => {
+                    tracing::error!("Daily risk report failed, retrying: {}", e);
+                    RETRY_DELAY_SECS
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(delay)) => {}
+                _ = shutdown.cancelled() => {}
+            }
+        }
+        tracing::info!("Daily risk report stopped");
+    })
+}
+
+/// Recomputes the default protocol comparison and every configured strategy,
+/// returning the id of the new snapshot
+pub(crate) async fn refresh_all(
+    redis_client: &redis::Client,
+) -> Result<String, RiskCalculationError> {
+    tracing::info!("Refreshing risk snapshots...");
+    let kamino_reserve = KaminoReserveConfig::from_env()?;
+    let registry =
+        ProtocolRegistry::with_all_protocols(redis_client.clone(), kamino_reserve.clone());
+    let snapshot = registry.refresh().await?;
+    risk_stream::publish(&snapshot);
+    if let Err(e) = refresh_market_history(redis_client, &kamino_reserve).await {
+        tracing::error!("Failed to refresh market history: {}", e);
+    }
+    if let Err(e) = record_hourly_point(redis_client).await {
+        tracing::error!("Failed to record the hourly marginfi history: {}", e);
+    }
+    detect_anomalies(redis_client, &registry).await;
+    // Alerts are evaluated for the protocols that were assessed, even if others weren't
+    if let Err(e) = evaluate_delta_alerts(
+        redis_client,
+        snapshot.computed_at,
+        &snapshot.comparison.ranking,
+    )
+    .await
+    {
+        tracing::error!("Failed to evaluate delta alerts: {}", e);
+    }
+    let snapshot_weights = || {
+        RiskProfile::ALL
+            .into_iter()
+            .map(|profile| ProfileWeights::from_snapshot(profile, &snapshot))
+            .collect::<Result<Vec<_>, _>>()
+    };
+    // Degraded refreshes are retried every minute, smoothing them would advance
+    // the smoothing state that often. Their weights renormalize around the
+    // unavailable protocols right away instead.
+    let unavailable = snapshot.comparison.unavailable.len();
+    let weights = if unavailable > 0 {
+        snapshot_weights()?
+    } else {
+        match smoothed_weights(redis_client, &snapshot).await {
+            Ok(weights) => weights,
+            Err(e) => {
+                tracing::error!("Failed to smooth weights, using the snapshot's: {}", e);
+                snapshot_weights()?
+            }
+        }
+    };
+    if let Err(e) = propose_weight_changes(redis_client, &weights).await {
+        tracing::error!("Failed to propose weight changes: {}", e);
+    }
+    let snapshot_id = snapshot.snapshot_id.clone();
+    // Handlers fall back to assembling responses from the snapshot themselves
+    if let Err(e) = store_precomputed(redis_client, snapshot, &weights, process_clock().now()).await
+    {
+        tracing::error!("Failed to precompute responses: {}", e);
+    }
+    if unavailable > 0 {
+        return Err(RiskCalculationError::CustomError(format!(
+            "{} protocols unavailable",
+            unavailable
+        )));
+    }
+
+    // Strategies are only served from their snapshots, they can wait for credits
+    if !CreditBudget::global().defer("the strategy assessments") {
+        for strategy in StrategyConfig::from_env()?.strategies {
+            assess_strategy(&strategy, redis_client).await?;
+        }
+    }
+    tracing::info!("Risk snapshot {} refreshed", snapshot_id);
+    Ok(snapshot_id)
+}
@@ -0,0 +1,566 @@
+use std::{
+    fs::File,
+    future::Future,
+    io::BufReader,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{DefaultBodyLimit, MatchedPath, Request},
+    http::{header, HeaderValue},
+    middleware::{self, Next},
+    response::Response,
+    routing::{get, post},
+    Router,
+};
+use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+use serde::Deserialize;
+use tokio::net::TcpListener;
+use tokio_rustls::{rustls, TlsAcceptor};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{info, Instrument};
+
+use crate::{
+    alerts, anomalies, backtest, batch, cache, cache_admin, cache_schema, cluster,
+    concentration_history, correlation, dry_run,
+    encoding::negotiate_format,
+    grpc, health, history, incidents,
+    kamino::deposit_index::{self, DepositIndex},
+    kamino_markets, liquidity_depth, liquidity_risk, marginfi, multisig, openapi, portfolio,
+    portfolio_events, precomputed, profiles, proposals, protocol_rubric,
+    rebalance_worker::{self, RebalanceWorkerConfig},
+    redis_builder, reports,
+    risk_model::{self, RiskCalculationError},
+    risk_stream, rpc_budget, rpc_pool, scheduler, shutdown, simulation,
+    state::AppState,
+    state_export, strategy, stress, top_depositors, upstream, upstream_fixtures, weight_smoothing,
+    weights,
+};
+
+/// Path prefix of the current version of the API
+pub const API_PREFIX: &str = "/v1";
+/// axum's own default limit
+const DEFAULT_MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+/// Delay before accepting again after a failed accept, e.g. out of file descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Connections that don't finish the TLS handshake within this are closed,
+/// so stalled clients can't hold connections open
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificate chain and private key of the served TLS certificate, both PEM
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Where and how the HTTP server listens
+///
+/// Loaded from the TOML file at `SERVER_CONFIG`, every field optional, e.g.
+///
+/// ```toml
+/// host = "127.0.0.1"
+/// port = 8443
+/// max_body_bytes = 65536
+/// swagger_ui = true
+///
+/// [tls]
+/// cert = "/etc/risk_model/cert.pem"
+/// key = "/etc/risk_model/key.pem"
+/// ```
+///
+/// Fields can be overridden with `HOST`, `PORT`, `MAX_BODY_BYTES`, `SWAGGER_UI`
+/// and, together, `TLS_CERT` and `TLS_KEY`. Without TLS the server speaks plain HTTP.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub host: IpAddr,
+    pub port: u16,
+    pub tls: Option<TlsConfig>,
+    /// Largest request body accepted, larger ones are rejected with 413
+    pub max_body_bytes: usize,
+    /// Serve Swagger UI for `/openapi.json` at `/docs`
+    pub swagger_ui: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8000,
+            tls: None,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+            swagger_ui: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_toml(config: &str) -> Result<Self, RiskCalculationError> {
+        toml::from_str(config).map_err(|e| RiskCalculationError::ParseError(e.to_string()))
+    }
+
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let mut config = match std::env::var("SERVER_CONFIG") {
+            Ok(path) => {
+                let config = std::fs::read_to_string(&path).map_err(|e| {
+                    RiskCalculationError::CustomError(format!("reading {}: {}", path, e))
+                })?;
+                Self::from_toml(&config)?
+            }
+            Err(_) => Self::default(),
+        };
+        if let Some(host) = parse_env("HOST")? {
+            config.host = host;
+        }
+        if let Some(port) = parse_env("PORT")? {
+            config.port = port;
+        }
+        if let Some(max_body_bytes) = parse_env("MAX_BODY_BYTES")? {
+            config.max_body_bytes = max_body_bytes;
+        }
+        if let Some(swagger_ui) = parse_env("SWAGGER_UI")? {
+            config.swagger_ui = swagger_ui;
+        }
+        match (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+            (Ok(cert), Ok(key)) => {
+                config.tls = Some(TlsConfig {
+                    cert: cert.into(),
+                    key: key.into(),
+                })
+            }
+            (Err(_), Err(_)) => {}
+            _ => {
+                return Err(RiskCalculationError::ParseError(
+                    "TLS_CERT and TLS_KEY must be set together".to_string(),
+                ))
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+}
+
+/// The environment variable `name` parsed, `None` when it isn't set
+pub(crate) fn parse_env<T>(name: &str) -> Result<Option<T>, RiskCalculationError>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| RiskCalculationError::ParseError(format!("{}: {}", name, e))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Like [`parse_env`], for numbers that must be above zero
+pub(crate) fn parse_positive_env<T>(name: &str) -> Result<Option<T>, RiskCalculationError>
+where
+    T: std::str::FromStr + Default + PartialOrd + std::fmt::Display,
+    T::Err: std::fmt::Display,
+{
+    match parse_env::<T>(name)? {
+        Some(value) if value <= T::default() => Err(RiskCalculationError::ParseError(format!(
+            "{} must be positive: {}",
+            name, value
+        ))),
+        value => Ok(value),
+    }
+}
+
+impl TlsConfig {
+    /// Reads the certificate chain and key into an acceptor
+    pub fn acceptor(&self) -> Result<TlsAcceptor, RiskCalculationError> {
+        let certs = rustls_pemfile::certs(&mut open(&self.cert)?)
+            .map_err(|e| {
+                RiskCalculationError::ParseError(format!("{}: {}", self.cert.display(), e))
+            })?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect();
+        let key = rustls_pemfile::read_all(&mut open(&self.key)?)
+            .map_err(|e| {
+                RiskCalculationError::ParseError(format!("{}: {}", self.key.display(), e))
+            })?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                RiskCalculationError::ParseError(format!(
+                    "{}: no private key found",
+                    self.key.display()
+                ))
+            })?;
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| RiskCalculationError::ParseError(format!("TLS: {}", e)))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, RiskCalculationError> {
+    File::open(path).map(BufReader::new).map_err(|e| {
+        RiskCalculationError::CustomError(format!("reading {}: {}", path.display(), e))
+    })
+}
+
+/// Serves `app` on `listener` until `shutdown` resolves, then finishes the in-flight requests
+///
+/// Connections are served over TLS when `tls` is set.
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    tls: Option<TlsAcceptor>,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> std::io::Result<()> {
+    match tls {
+        None => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await
+        }
+        Some(acceptor) => {
+            serve_tls(listener, app, acceptor, shutdown).await;
+            Ok(())
+        }
+    }
+}
+
+async fn serve_tls(
+    listener: TcpListener,
+    app: Router,
+    acceptor: TlsAcceptor,
+    shutdown: impl Future<Output = ()>,
+) {
+    let stopping = CancellationToken::new();
+    let connections = TaskTracker::new();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, remote) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::error!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let acceptor = acceptor.clone();
+        let service = TowerToHyperService::new(app.clone());
+        let stopping = stopping.clone();
+        connections.spawn(async move {
+            let handshake = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+            let handshake = tokio::select! {
+                handshake = handshake => handshake,
+                // No request was received yet, there's nothing to finish
+                _ = stopping.cancelled() => return,
+            };
+            let stream = match handshake {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    tracing::debug!("TLS handshake with {} failed: {}", remote, e);
+                    return;
+                }
+                Err(_) => {
+                    tracing::debug!("TLS handshake with {} timed out", remote);
+                    return;
+                }
+            };
+            let connection = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+            let served = tokio::select! {
+                served = connection.as_mut() => served,
+                _ = stopping.cancelled() => {
+                    // Finishes the request in progress, then closes
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = served {
+                tracing::debug!("Connection from {} failed: {}", remote, e);
+            }
+        });
+    }
+    drop(listener);
+    stopping.cancel();
+    connections.close();
+    connections.wait().await;
+}
+
+/// Loads the configuration, failing fast when it's invalid, and migrates the cache
+pub(crate) async fn start() -> AppState {
+    let weights = weights::RiskWeightsConfig::global();
+    info!("Risk weights: {:?}", weights);
+    info!("Sub-score TTLs: {:?}", risk_model::SubScoreTtls::global());
+    info!(
+        "Cache: {:?} | Mode: {:?}",
+        cache::CacheKind::global(),
+        risk_model::CacheMode::global()
+    );
+    info!(
+        "Cluster: {} | Marginfi: {:?}",
+        cluster::Cluster::global().as_str(),
+        marginfi::MarginfiAccounts::global()
+    );
+    info!("RPC chunks: {:?}", rpc_pool::ChunkedFetch::global());
+    info!(
+        "Protocol rubric: {} protocols",
+        protocol_rubric::ProtocolRubric::global().protocols.len()
+    );
+    // Read at first use otherwise, so an invalid one would only fail a request
+    redis_builder::RedisTopology::global();
+    upstream::UpstreamConfig::global();
+    upstream_fixtures::UpstreamFixtures::global();
+    rpc_budget::CreditBudget::global();
+    batch::BatchConfig::global();
+    liquidity_risk::ConcentrationDenominator::global();
+    liquidity_depth::PositionLimitConfig::global();
+    weight_smoothing::WeightSmoothingConfig::global();
+    alerts::AlertWebhook::global();
+    anomalies::AnomalyConfig::global();
+    alerts::DeltaRule::global();
+    reports::ReportConfig::global();
+
+    let state = AppState::from_env().expect("Configuration must be valid");
+    // The memory cache starts empty, there's nothing to migrate
+    if *cache::CacheKind::global() == cache::CacheKind::Redis {
+        if let Err(e) = cache_schema::migrate(&state.redis_client).await {
+            tracing::error!("Cache migration failed: {}", e);
+        }
+    }
+    state
+}
+
+/// Serves the API with the configuration from the environment until SIGINT or
+/// SIGTERM, then drains the in-flight requests and background work
+///
+/// Panics if the configuration is invalid.
+pub async fn run() {
+    let state = start().await;
+    let config = ServerConfig::from_env().expect("Server configuration must be valid");
+    let tls = config
+        .tls
+        .as_ref()
+        .map(|tls| tls.acceptor().expect("TLS certificate must be valid"));
+    let shutdown_token = state.shutdown.clone();
+    scheduler::spawn_hourly_refresh(state.redis_client.clone(), shutdown_token.clone());
+    scheduler::spawn_daily_report(state.redis_client.clone(), shutdown_token.clone());
+    if DepositIndex::enabled().expect("OBLIGATION_SUBSCRIPTION must be valid") {
+        deposit_index::spawn_subscription(state.redis_client.clone(), shutdown_token.clone());
+    }
+    if let Some(worker) =
+        RebalanceWorkerConfig::from_env().expect("Rebalancing worker configuration must be valid")
+    {
+        rebalance_worker::spawn_rebalance_worker(state.clone(), worker);
+    }
+    if let Some(grpc) = grpc::GrpcConfig::from_env().expect("GRPC_PORT must be a valid port") {
+        grpc::spawn_grpc_server(state.clone(), SocketAddr::new(config.host, grpc.port));
+    }
+    let app = router(state, &config);
+
+    let listener = TcpListener::bind(config.addr())
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind to {}: {}", config.addr(), e));
+    info!(
+        "🚀 Server running on {}://{}",
+        if tls.is_some() { "https" } else { "http" },
+        listener.local_addr().unwrap()
+    );
+    // Stops accepting connections on SIGINT/SIGTERM and finishes the in-flight requests
+    serve(listener, app, tls, shutdown::signal(shutdown_token))
+        .await
+        .expect("Failed to serve");
+    shutdown::drain().await;
+    info!("Shut down");
+}
+
+/// Runs a request within a span, the root of its trace
+///
+/// The span is named after the route rather than the path, so requests for
+/// different wallets share a name.
+async fn trace_request(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| request.uri().path(), MatchedPath::as_str)
+        .to_string();
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        route = %route,
+        status = tracing::field::Empty,
+    );
+    let response = next.run(request).instrument(span.clone()).await;
+    span.record("status", response.status().as_u16());
+    response
+}
+
+/// Flags responses of the unversioned routes as deprecated, linking to the
+/// versioned route
+async fn deprecate_unversioned(request: Request, next: Next) -> Response {
+    let successor = format!(
+        "<{}{}>; rel=\"successor-version\"",
+        API_PREFIX,
+        request.uri().path()
+    );
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    if let Ok(successor) = HeaderValue::from_str(&successor) {
+        headers.insert(header::LINK, successor);
+    }
+    response
+}
+
+/// Every route of the API
+///
+/// The API is served under [`API_PREFIX`], and without it for the clients
+/// integrated before it was versioned. Health checks, metrics and the API
+/// description aren't versioned.
+pub fn router(state: AppState, config: &ServerConfig) -> Router {
+    let app = Router::new()
+        .route("/", get(|| async { "Hello, World!" }))
+        .route("/openapi.json", get(openapi::openapi))
+        .route("/health", get(health::health))
+        .route("/ready", get(health::ready))
+        .route("/metrics", get(rpc_budget::metrics))
+        .nest(API_PREFIX, api_routes())
+        .merge(api_routes().layer(middleware::from_fn(deprecate_unversioned)))
+        .layer(DefaultBodyLimit::max(config.max_body_bytes))
+        .layer(middleware::from_fn(negotiate_format))
+        .layer(middleware::from_fn(trace_request))
+        .with_state(state);
+    if config.swagger_ui {
+        app.route("/docs", get(openapi::swagger_ui))
+    } else {
+        app
+    }
+}
+
+/// Routes of the versioned API, relative to [`API_PREFIX`]
+fn api_routes() -> Router<AppState> {
+    Router::new()
+        .route("/risk_model", get(risk_model::risk_model))
+        .route("/risk_model/stream", get(risk_stream::risk_stream))
+        .route("/risk_model/batch", post(batch::risk_model_batch))
+        .route("/risk_model/compute", post(dry_run::compute_risk))
+        .route("/risk_model/stress", post(stress::stress_risk_model))
+        .route(
+            "/risk_model/kamino",
+            get(kamino_markets::kamino_markets_risk),
+        )
+        .route(
+            "/risk_model/kamino/concentration/history",
+            get(concentration_history::kamino_concentration_history),
+        )
+        .route("/risk_history", get(history::risk_history))
+        .route("/risk_history/export", get(history::export_history))
+        .route("/backtest", get(backtest::backtest_handler))
+        .route("/alerts", get(alerts::alerts))
+        .route("/reports/daily/:date", get(reports::daily_report))
+        .route("/liquidity_depth", get(liquidity_depth::liquidity_depth))
+        .route(
+            "/protocols/kamino/top_depositors",
+            get(top_depositors::kamino_top_depositors),
+        )
+        .route(
+            "/protocols/:protocol/anomalies",
+            get(anomalies::protocol_anomalies),
+        )
+        .route("/weights/:profile", get(precomputed::weights))
+        .route("/profiles/:profile/weights", get(precomputed::weights))
+        .route("/profiles/suggest", post(profiles::suggest_profile))
+        .route(
+            "/profiles/:profile/simulate",
+            get(simulation::simulate_profile),
+        )
+        .route("/strategies", get(strategy::strategies))
+        .route(
+            "/portfolio/:wallet",
+            get(portfolio_events::portfolio)
+                .put(portfolio_events::save_portfolio)
+                .delete(portfolio_events::delete_portfolio),
+        )
+        .route(
+            "/portfolio/:wallet/events",
+            get(portfolio_events::portfolio_events),
+        )
+        .route(
+            "/portfolio/:wallet/history",
+            get(portfolio_events::portfolio_history),
+        )
+        .route(
+            "/portfolio/:wallet/import",
+            post(portfolio::import_portfolio),
+        )
+        .route("/portfolio/risk", post(correlation::portfolio_risk_handler))
+        .route("/portfolio/:wallet/deposit", post(portfolio::deposit))
+        .route(
+            "/portfolio/:wallet/deposit/build",
+            post(portfolio::build_deposit),
+        )
+        .route("/portfolio/:wallet/withdraw", post(portfolio::withdraw))
+        .route("/portfolio/:wallet/rebalance", post(portfolio::rebalance))
+        .route("/portfolio/:wallet/stress", post(stress::stress_wallet))
+        .route("/proposals", get(proposals::proposals))
+        .route("/proposals/:id/approve", post(proposals::approve_proposal))
+        .route("/proposals/:id/reject", post(proposals::reject_proposal))
+        .route("/admin/portfolios", get(portfolio_events::list_portfolios))
+        .route("/admin/audit", get(multisig::admin_audit))
+        .route(
+            "/admin/portfolio/rebuild",
+            post(portfolio_events::rebuild_projections),
+        )
+        .route("/admin/protocol_penalties", post(incidents::add_penalty))
+        .route("/admin/export", get(state_export::export))
+        .route("/admin/import", post(state_export::import))
+        .route("/admin/cache/invalidate", post(cache_admin::invalidate))
+        .route("/admin/cache/warm", post(cache_admin::warm))
+        .route("/admin/rpc_budget", get(rpc_budget::rpc_budget))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_config_from_toml() {
+        assert_eq!(
+            ServerConfig::from_toml("").unwrap(),
+            ServerConfig::default()
+        );
+        let config = ServerConfig::from_toml(
+            "host = \"127.0.0.1\"\nport = 8443\n\n[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"",
+        )
+        .unwrap();
+        assert_eq!(config.addr(), "127.0.0.1:8443".parse().unwrap());
+        assert_eq!(config.max_body_bytes, DEFAULT_MAX_BODY_BYTES);
+        assert_eq!(
+            config.tls,
+            Some(TlsConfig {
+                cert: "cert.pem".into(),
+                key: "key.pem".into(),
+            })
+        );
+        assert!(ServerConfig::from_toml("port = 70000").is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use std::{future::Future, sync::OnceLock, time::Duration};
+
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+/// Longest the process waits for background work once the server stopped
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+static BACKGROUND_TASKS: OnceLock<TaskTracker> = OnceLock::new();
+
+fn background_tasks() -> &'static TaskTracker {
+    BACKGROUND_TASKS.get_or_init(TaskTracker::new)
+}
+
+/// Spawns work that outlives the request starting it, e.g. a cache write
+///
+/// Shutdown waits for it in [`drain`], so the write isn't lost mid-way.
+pub fn spawn_background<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    background_tasks().spawn(future)
+}
+
+/// Resolves on SIGINT or SIGTERM, cancelling `shutdown` for the background tasks
+pub async fn signal(shutdown: CancellationToken) {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = interrupt => tracing::info!("SIGINT received, shutting down"),
+        _ = terminate => tracing::info!("SIGTERM received, shutting down"),
+    }
+    shutdown.cancel();
+}
+
+/// Waits for the background tasks to finish, up to [`DRAIN_TIMEOUT`]
+///
+/// Runs once the server stopped accepting requests and finished the in-flight
+/// ones. Tasks still running after the timeout are abandoned.
+pub async fn drain() {
+    let tasks = background_tasks();
+    tasks.close();
+    if !tasks.is_empty() {
+        tracing::info!("Waiting for {} background tasks", tasks.len());
+    }
+    if tokio::time::timeout(DRAIN_TIMEOUT, tasks.wait())
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Abandoning {} background tasks after {:?}",
+            tasks.len(),
+            DRAIN_TIMEOUT
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_background_tasks() {
+        let written = Arc::new(AtomicBool::new(false));
+        let task_written = written.clone();
+        spawn_background(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            task_written.store(true, Ordering::SeqCst);
+        });
+        drain().await;
+        assert!(written.load(Ordering::SeqCst));
+    }
+}
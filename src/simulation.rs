@@ -0,0 +1,271 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, Query, State},
+    response::Response,
+};
+use chrono::{Duration, NaiveDate, Utc};
+use futures::future::join_all;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backtest::UTILIZATION_EVENT_PERCENT,
+    bps::Bps,
+    correlation::daily_means,
+    market_history::MarketPoint,
+    precomputed::ProfileWeights,
+    risk_model::{json_response, timings_requested, Protocol, RiskCalculationError, RiskProfile},
+    state::AppState,
+    timings::{timed_sync, with_timings, Timing},
+};
+
+/// History the days of a path are drawn from
+const HISTORY_DAYS: i64 = 90;
+/// Length of a path
+const HORIZON_DAYS: usize = 30;
+const DEFAULT_PATHS: usize = 1_000;
+/// Bounds the work of a single request
+const MAX_PATHS: usize = 100_000;
+
+/// Supply APY and utilization of every allocated protocol on one historical day
+type MarketDay = Vec<(f64, f64)>;
+
+/// Percentiles of a simulated quantity
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Distribution {
+    pub mean: f64,
+    pub p5: f64,
+    pub p50: f64,
+    pub p95: f64,
+}
+
+impl Distribution {
+    /// `None` for no values
+    fn of(mut values: Vec<f64>) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f64::total_cmp);
+        // Nearest rank
+        let percentile = |p: usize| values[((values.len() - 1) * p + 50) / 100];
+        Some(Distribution {
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            p5: percentile(5),
+            p50: percentile(50),
+            p95: percentile(95),
+        })
+    }
+}
+
+/// Outcomes of a profile's allocation over the simulated paths
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SimulationResult {
+    pub paths: usize,
+    pub horizon_days: usize,
+    /// Historical days the paths were drawn from
+    pub sampled_days: usize,
+    /// Return over the horizon, in percent
+    pub return_pct: Distribution,
+    /// Largest fall of the allocation's APY from its high within a path, in percentage points
+    pub max_apy_drawdown: Distribution,
+    /// Share of the paths in which an allocated protocol was utilized above 95%,
+    /// when withdrawals may have to wait
+    pub liquidity_lock_probability: f64,
+}
+
+/// Days on which every protocol of `histories` has a point, oldest first
+fn common_days(histories: &[Vec<MarketPoint>]) -> Vec<MarketDay> {
+    let daily: Vec<BTreeMap<NaiveDate, (f64, f64)>> =
+        histories.iter().map(|points| daily_means(points)).collect();
+    let Some((first, rest)) = daily.split_first() else {
+        return Vec::new();
+    };
+    first
+        .iter()
+        .filter_map(|(date, day)| {
+            let mut market_day = vec![(day.1, day.0)];
+            for other in rest {
+                let (utilization, apy) = other.get(date)?;
+                market_day.push((*apy, *utilization));
+            }
+            Some(market_day)
+        })
+        .collect()
+}
+
+/// Simulates `paths` paths of `weights` by drawing historical days with replacement
+///
+/// Each day of a path is one historical day across all protocols, so the
+/// protocols keep moving together as they did. Returns accrue the day's APY of
+/// each protocol, simple rather than compounded over the short horizon.
+pub fn simulate(
+    weights: &[Bps],
+    days: &[MarketDay],
+    paths: usize,
+    rng: &mut impl Rng,
+) -> Result<SimulationResult, RiskCalculationError> {
+    if days.is_empty() {
+        return Err(RiskCalculationError::UpstreamUnavailable(
+            "No common market history of the allocated protocols".to_string(),
+        ));
+    }
+    let distribution = |values| {
+        Distribution::of(values).ok_or(RiskCalculationError::InvalidParameter(
+            "no paths to simulate".to_string(),
+        ))
+    };
+    let shares: Vec<f64> = weights
+        .iter()
+        .map(|weight| weight.0 as f64 / Bps::FULL.0 as f64)
+        .collect();
+
+    let mut returns = Vec::with_capacity(paths);
+    let mut drawdowns = Vec::with_capacity(paths);
+    let mut locked_paths = 0;
+    for _ in 0..paths {
+        let (mut return_pct, mut high_apy, mut drawdown, mut locked) =
+            (0.0, f64::MIN, 0.0_f64, false);
+        for _ in 0..HORIZON_DAYS {
+            let day = &days[rng.gen_range(0..days.len())];
+            let apy = shares
+                .iter()
+                .zip(day)
+                .map(|(share, (apy, _))| share * apy)
+                .sum::<f64>();
+            return_pct += apy / 365.0;
+            high_apy = high_apy.max(apy);
+            drawdown = drawdown.max(high_apy - apy);
+            locked |= shares.iter().zip(day).any(|(share, (_, utilization))| {
+                *share > 0.0 && *utilization > UTILIZATION_EVENT_PERCENT
+            });
+        }
+        returns.push(return_pct);
+        drawdowns.push(drawdown);
+        locked_paths += locked as usize;
+    }
+
+    Ok(SimulationResult {
+        paths,
+        horizon_days: HORIZON_DAYS,
+        sampled_days: days.len(),
+        return_pct: distribution(returns)?,
+        max_apy_drawdown: distribution(drawdowns)?,
+        liquidity_lock_probability: locked_paths as f64 / paths as f64,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimulateQuery {
+    /// Number of paths, 1,000 by default
+    pub n: Option<usize>,
+    /// Seeds the draws, for reproducible results
+    pub seed: Option<u64>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+impl SimulateQuery {
+    fn paths(&self) -> Result<usize, RiskCalculationError> {
+        match self.n.unwrap_or(DEFAULT_PATHS) {
+            n @ 1..=MAX_PATHS => Ok(n),
+            n => Err(RiskCalculationError::InvalidParameter(format!(
+                "n must be between 1 and {}: {}",
+                MAX_PATHS, n
+            ))),
+        }
+    }
+}
+
+/// Monte Carlo outcomes of a profile's recommended weights, `/profiles/:profile/simulate`
+pub async fn simulate_profile(
+    State(state): State<AppState>,
+    Path(profile): Path<String>,
+    Query(query): Query<SimulateQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let profile = RiskProfile::from_param(&profile)?;
+        let paths = query.paths()?;
+        let weights =
+            ProfileWeights::from_snapshot(profile, &state.registry.cached_snapshot().await?)?;
+        let allocated: Vec<(Protocol, Bps)> = weights
+            .weights
+            .iter()
+            .filter(|(_, weight)| *weight > Bps::ZERO)
+            .cloned()
+            .collect();
+
+        let from = Utc::now() - Duration::days(HISTORY_DAYS);
+        let registry = &state.registry;
+        let histories = join_all(allocated.iter().map(|(protocol, _)| async move {
+            match registry.registered(protocol) {
+                Some(registered) => registered.market_history(from).await,
+                None => Err(RiskCalculationError::InvalidParameter(format!(
+                    "{:?} is not supported",
+                    protocol
+                ))),
+            }
+        }))
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, RiskCalculationError>>()?;
+
+        let days = common_days(&histories);
+        let bps: Vec<Bps> = allocated.iter().map(|(_, weight)| *weight).collect();
+        let mut rng = match query.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let simulation = timed_sync(Timing::Compute, || simulate(&bps, &days, paths, &mut rng))?;
+
+        Ok::<_, RiskCalculationError>(serde_json::json!({
+            "profile": weights.profile,
+            "snapshot_id": weights.snapshot_id,
+            "weights": allocated,
+            "simulation": simulation,
+        }))
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulate() {
+        // A calm day and a stressed one with a lower APY on the second protocol
+        let days = vec![
+            vec![(5.0, 60.0), (7.0, 70.0)],
+            vec![(5.0, 60.0), (1.0, 97.0)],
+        ];
+        let mut rng = StdRng::seed_from_u64(7);
+
+        let only_first = simulate(&[Bps::FULL, Bps::ZERO], &days, 500, &mut rng).unwrap();
+        let daily_return = 5.0 / 365.0;
+        assert!((only_first.return_pct.p50 - daily_return * HORIZON_DAYS as f64).abs() < 1e-9);
+        assert_eq!(only_first.max_apy_drawdown.p95, 0.0);
+        assert_eq!(only_first.liquidity_lock_probability, 0.0);
+
+        let split = simulate(&[Bps(5_000), Bps(5_000)], &days, 500, &mut rng).unwrap();
+        assert!(split.return_pct.p5 < split.return_pct.p95);
+        // The blended APY falls from 6% to 3% whenever a stressed day follows a calm one
+        assert_eq!(split.max_apy_drawdown.p50, 3.0);
+        // Thirty days without a stressed one are all but impossible
+        assert_eq!(split.liquidity_lock_probability, 1.0);
+
+        assert!(simulate(&[Bps::FULL], &[], 10, &mut rng).is_err());
+        assert!(simulate(&[Bps::FULL], &days, 0, &mut rng).is_err());
+        assert_eq!(
+            Distribution::of(vec![3.0, 1.0, 2.0]),
+            Some(Distribution {
+                mean: 2.0,
+                p5: 1.0,
+                p50: 2.0,
+                p95: 3.0
+            })
+        );
+    }
+}
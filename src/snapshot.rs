@@ -0,0 +1,159 @@
+use chrono::{DateTime, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{Cache, CacheBackend},
+    cache_schema::versioned_key,
+    clock::seconds_until_next_hour,
+    registry::ProtocolComparison,
+    risk_model::RiskCalculationError,
+    timings::{timed, Timing},
+};
+
+/// All protocol metrics of one comparison, stored and read as a single unit
+///
+/// Component caches can be filled at slightly different times, so responses are
+/// served from a snapshot instead: every metric in it was read in the same pass
+/// and the whole snapshot is labeled with one id.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RiskSnapshot {
+    pub snapshot_id: String,
+    pub computed_at: DateTime<Utc>,
+    pub comparison: ProtocolComparison,
+}
+
+impl RiskSnapshot {
+    pub fn new(comparison: ProtocolComparison, computed_at: DateTime<Utc>) -> Self {
+        RiskSnapshot {
+            snapshot_id: snapshot_id(computed_at),
+            computed_at,
+            comparison,
+        }
+    }
+
+    /// Whether the snapshot was computed during the hour `now` falls in
+    ///
+    /// Component caches are refreshed hourly, so a snapshot from an earlier hour
+    /// is stale even if it's still stored.
+    pub fn is_current(&self, now: DateTime<Utc>) -> bool {
+        self.computed_at.date_naive() == now.date_naive() && self.computed_at.hour() == now.hour()
+    }
+
+    /// Whether some protocols couldn't be assessed and are missing from the ranking
+    pub fn is_degraded(&self) -> bool {
+        !self.comparison.unavailable.is_empty()
+    }
+}
+
+/// Snapshot ids are the computation time down to the millisecond, so they sort
+/// chronologically and two snapshots from the same hour never collide.
+pub fn snapshot_id(computed_at: DateTime<Utc>) -> String {
+    computed_at.format("%Y%m%dT%H%M%S%3f").to_string()
+}
+
+/// Seconds a snapshot stored at `now` is kept, until the end of the next hour
+pub fn snapshot_ttl(now: DateTime<Utc>) -> u64 {
+    seconds_until_next_hour(now) + 3600
+}
+
+/// Snapshots are scoped by the set of protocols and reserves they compare
+pub fn snapshot_key(scope: &str, snapshot_id: &str) -> String {
+    versioned_key(&format!("risk_snapshot:{}:{}", scope, snapshot_id))
+}
+
+/// Points at the id of the most recently stored snapshot of a scope
+pub fn latest_snapshot_key(scope: &str) -> String {
+    snapshot_key(scope, "latest")
+}
+
+/// Loads the latest snapshot, or `None` if it expired or was never stored
+pub async fn load_latest_snapshot(
+    cache: &CacheBackend<'_>,
+    scope: &str,
+) -> Result<Option<RiskSnapshot>, RiskCalculationError> {
+    timed(Timing::CacheRead, read_latest_snapshot(cache, scope)).await
+}
+
+async fn read_latest_snapshot(
+    cache: &CacheBackend<'_>,
+    scope: &str,
+) -> Result<Option<RiskSnapshot>, RiskCalculationError> {
+    let Some(snapshot_id) = cache.get(&latest_snapshot_key(scope)).await? else {
+        return Ok(None);
+    };
+    let snapshot = cache.get(&snapshot_key(scope, &snapshot_id)).await?;
+    snapshot
+        .map(|snapshot| {
+            serde_json::from_str(&snapshot)
+                .map_err(|e| RiskCalculationError::ParseError(e.to_string()))
+        })
+        .transpose()
+}
+
+/// Stores a snapshot at `now` and makes it the latest one
+///
+/// Snapshots are kept until the end of the hour after the next one, see
+/// [`snapshot_ttl`], so the
+/// previous snapshot can still be served while the next one is being computed.
+/// The snapshot body is written before the pointer is moved, so readers never
+/// see a pointer to a snapshot that doesn't exist yet.
+pub async fn store_snapshot(
+    cache: &CacheBackend<'_>,
+    scope: &str,
+    snapshot: &RiskSnapshot,
+    now: DateTime<Utc>,
+) -> Result<(), RiskCalculationError> {
+    let body = serde_json::to_string(snapshot)
+        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
+    let ttl = snapshot_ttl(now);
+    cache
+        .set_with_ttl(&snapshot_key(scope, &snapshot.snapshot_id), &body, ttl)
+        .await?;
+    cache
+        .set_with_ttl(&latest_snapshot_key(scope), &snapshot.snapshot_id, ttl)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{Clock, MockClock};
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_snapshot_id() {
+        let computed_at = Utc.with_ymd_and_hms(2024, 5, 1, 13, 2, 3).unwrap();
+        assert_eq!(snapshot_id(computed_at), "20240501T130203000");
+        assert_eq!(
+            snapshot_key("kamino", &snapshot_id(computed_at)),
+            "v2:risk_snapshot:kamino:20240501T130203000"
+        );
+        assert_eq!(
+            latest_snapshot_key("kamino"),
+            "v2:risk_snapshot:kamino:latest"
+        );
+    }
+
+    #[test]
+    fn test_snapshot_is_current() {
+        let snapshot = RiskSnapshot {
+            snapshot_id: String::new(),
+            computed_at: Utc.with_ymd_and_hms(2024, 5, 1, 13, 2, 3).unwrap(),
+            comparison: ProtocolComparison {
+                ranking: Vec::new(),
+                unavailable: Vec::new(),
+            },
+        };
+        assert!(snapshot.is_current(Utc.with_ymd_and_hms(2024, 5, 1, 13, 59, 59).unwrap()));
+        assert!(!snapshot.is_current(Utc.with_ymd_and_hms(2024, 5, 1, 14, 0, 0).unwrap()));
+        assert!(!snapshot.is_current(Utc.with_ymd_and_hms(2024, 5, 2, 13, 2, 3).unwrap()));
+    }
+
+    #[test]
+    fn test_snapshot_ttl() {
+        let clock = MockClock::at("2024-05-01T13:00:00Z");
+        assert_eq!(snapshot_ttl(clock.now()), 7200);
+        clock.advance(std::time::Duration::from_secs(3599));
+        assert_eq!(snapshot_ttl(clock.now()), 3601);
+    }
+}
@@ -0,0 +1,77 @@
+use std::sync::Arc;
+
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    incidents::DecaySchedule,
+    kamino::reserve::KaminoReserveConfig,
+    kamino_markets::KaminoMarket,
+    privacy::PrivacyMode,
+    rebalancing::{ExposureLimits, TransferCostModel},
+    registry::ProtocolRegistry,
+    risk_model::RiskCalculationError,
+    strategy::StrategyConfig,
+};
+
+/// Configuration handlers read, from the environment
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    /// Reserve served when a request doesn't select one
+    pub kamino_reserve: KaminoReserveConfig,
+    /// Markets assessed by `/risk_model/kamino`
+    pub kamino_markets: Vec<KaminoMarket>,
+    pub privacy: PrivacyMode,
+    pub strategies: StrategyConfig,
+    pub transfer_costs: TransferCostModel,
+    pub exposure_limits: ExposureLimits,
+    /// Decay of penalties added without their own schedule
+    pub penalty_decay: DecaySchedule,
+}
+
+impl AppConfig {
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let kamino_reserve = KaminoReserveConfig::from_env()?;
+        Ok(AppConfig {
+            kamino_markets: KaminoMarket::from_env(&kamino_reserve)?,
+            kamino_reserve,
+            privacy: PrivacyMode::from_env()?,
+            strategies: StrategyConfig::from_env()?,
+            transfer_costs: TransferCostModel::from_env()?,
+            exposure_limits: ExposureLimits::from_env()?,
+            penalty_decay: DecaySchedule::from_env()?,
+        })
+    }
+}
+
+/// What every handler shares, built once at startup
+///
+/// Configuration is read and validated before the server binds, so a missing
+/// or invalid variable fails the deployment instead of every request.
+#[derive(Clone)]
+pub struct AppState {
+    pub redis_client: redis::Client,
+    /// Every protocol on the default reserve
+    pub registry: Arc<ProtocolRegistry>,
+    pub config: Arc<AppConfig>,
+    /// Cancelled on SIGINT/SIGTERM, ends long-lived responses and background tasks
+    pub shutdown: CancellationToken,
+}
+
+impl AppState {
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let redis_url = std::env::var("REDIS_URL")
+            .map_err(|_| RiskCalculationError::ParseError("REDIS_URL must be set".to_string()))?;
+        let redis_client = redis::Client::open(redis_url).map_err(RiskCalculationError::from)?;
+        let config = AppConfig::from_env()?;
+        let registry = ProtocolRegistry::with_all_protocols(
+            redis_client.clone(),
+            config.kamino_reserve.clone(),
+        );
+        Ok(AppState {
+            redis_client,
+            registry: Arc::new(registry),
+            config: Arc::new(config),
+            shutdown: CancellationToken::new(),
+        })
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::Response,
+    Json,
+};
+use chrono::{DateTime, Utc};
+use redis::AsyncCommands;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+
+use crate::{
+    cache_lock::lock_key,
+    cache_schema::{record_key, versioned_key, CACHE_SCHEMA_VERSION},
+    cluster::Cluster,
+    multisig::{approval_from_headers, authorize, AdminAction, MultisigApproval},
+    redis_builder::RedisConnection,
+    redis_connection::shared_connection,
+    risk_model::{json_response, timings_requested, DebugQuery, RiskCalculationError},
+    state::AppState,
+    timings::with_timings,
+};
+
+/// Version of the [`StateSnapshot`] format, bumped when it changes incompatibly
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// Unversioned records within the cluster's namespace that are part of a snapshot
+///
+/// Nonces of used approvals aren't, nor are the rebalance plans already handed
+/// to the transaction system: restoring them would execute them twice.
+const RECORD_PREFIXES: [&str; 6] = [
+    "risk_alerts",
+    "admin_audit",
+    "portfolio_events:",
+    "protocol_penalties:",
+    "weight_proposals",
+    "applied_weights",
+];
+
+/// Records an import never overwrites, the entries the snapshot adds are
+/// appended to them
+const APPEND_ONLY_RECORDS: [&str; 1] = ["admin_audit"];
+
+/// Versioned keys that only matter to the requests in flight
+const EPHEMERAL_PREFIXES: [&str; 2] = ["idempotency:", "rebalance:"];
+
+/// Everything the service stores in redis for a cluster, to restore elsewhere
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct StateSnapshot {
+    pub format_version: u32,
+    /// Versioned entries are only imported into the same schema
+    pub cache_schema_version: u32,
+    pub cluster: Cluster,
+    pub exported_at: DateTime<Utc>,
+    pub entries: Vec<SnapshotEntry>,
+}
+
+/// A single redis key
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SnapshotEntry {
+    /// Key within the cluster's namespace, without the schema version
+    pub key: String,
+    /// Cached values, history and projections are versioned, records aren't
+    pub versioned: bool,
+    /// Milliseconds left before the key expires, it never does if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_ms: Option<i64>,
+    #[serde(flatten)]
+    pub value: EntryValue,
+}
+
+impl SnapshotEntry {
+    /// The key in redis
+    pub fn redis_key(&self) -> String {
+        if self.versioned {
+            versioned_key(&self.key)
+        } else {
+            record_key(&self.key)
+        }
+    }
+}
+
+/// Value of a key, by redis type
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum EntryValue {
+    String(String),
+    List(Vec<String>),
+    Set(Vec<String>),
+    /// Members with their scores, from lowest to highest
+    SortedSet(Vec<(String, f64)>),
+    Hash(BTreeMap<String, String>),
+}
+
+/// The entry key of `key` and whether it's versioned, `None` if it isn't exported
+fn entry_key(key: &str) -> Option<(String, bool)> {
+    if key.ends_with(&lock_key("")) {
+        return None;
+    }
+    let cluster = Cluster::global();
+    if let Some(rest) = key.strip_prefix(&versioned_key("")) {
+        // Mainnet keys aren't namespaced, so its prefix also covers devnet's
+        let other_cluster = [Cluster::MainnetBeta, Cluster::Devnet]
+            .into_iter()
+            .filter(|other| *other != cluster)
+            .map(|other| other.namespaced_key(""))
+            .any(|namespace| !namespace.is_empty() && rest.starts_with(&namespace));
+        let ephemeral = EPHEMERAL_PREFIXES
+            .iter()
+            .any(|prefix| rest.starts_with(prefix));
+        return (!other_cluster && !ephemeral).then(|| (rest.to_string(), true));
+    }
+    let rest = key.strip_prefix(&record_key(""))?;
+    RECORD_PREFIXES
+        .iter()
+        .any(|prefix| rest.starts_with(prefix))
+        .then(|| (rest.to_string(), false))
+}
+
+/// Base58 sha256 of the snapshot as sent, what an import's approval names
+pub fn snapshot_hash(snapshot: &str) -> String {
+    anchor_client::solana_sdk::hash::hash(snapshot.as_bytes()).to_string()
+}
+
+async fn connection(redis_client: &redis::Client) -> Result<RedisConnection, RiskCalculationError> {
+    shared_connection(redis_client)
+        .await
+        .map_err(RiskCalculationError::from)
+}
+
+/// Every exported key in redis
+async fn exported_keys(
+    connection: &mut RedisConnection,
+) -> Result<Vec<(String, String, bool)>, RiskCalculationError> {
+    let mut patterns = vec![versioned_key("*")];
+    patterns.extend(
+        RECORD_PREFIXES
+            .iter()
+            .map(|prefix| format!("{}*", record_key(prefix))),
+    );
+    let mut keys = Vec::new();
+    for pattern in patterns {
+        let mut scan: redis::AsyncIter<String> = connection
+            .scan_match(pattern)
+            .await
+            .map_err(RiskCalculationError::from)?;
+        while let Some(key) = scan.next_item().await {
+            if let Some((entry_key, versioned)) = entry_key(&key) {
+                keys.push((key, entry_key, versioned));
+            }
+        }
+    }
+    keys.sort();
+    keys.dedup();
+    Ok(keys)
+}
+
+/// Reads every cached value, history, projection and record of the cluster
+pub async fn export_state(
+    redis_client: &redis::Client,
+) -> Result<StateSnapshot, RiskCalculationError> {
+    let mut connection = connection(redis_client).await?;
+    let mut entries = Vec::new();
+    for (key, entry_key, versioned) in exported_keys(&mut connection).await? {
+        let key_type: String = redis::cmd("TYPE")
+            .arg(&key)
+            .query_async(&mut connection)
+            .await
+            .map_err(RiskCalculationError::from)?;
+        let value = match key_type.as_str() {
+            "string" => connection.get(&key).await.map(EntryValue::String),
+            "list" => connection.lrange(&key, 0, -1).await.map(EntryValue::List),
+            "set" => connection.smembers(&key).await.map(EntryValue::Set),
+            "zset" => connection
+                .zrange_withscores(&key, 0, -1)
+                .await
+                .map(EntryValue::SortedSet),
+            "hash" => connection.hgetall(&key).await.map(EntryValue::Hash),
+            // Expired since the scan
+            "none" => continue,
+            other => {
+                tracing::warn!("Not exporting {}, unexpected {} key", key, other);
+                continue;
+            }
+        }
+        .map_err(RiskCalculationError::from)?;
+        let ttl: i64 = connection
+            .pttl(&key)
+            .await
+            .map_err(RiskCalculationError::from)?;
+        entries.push(SnapshotEntry {
+            key: entry_key,
+            versioned,
+            ttl_ms: (ttl > 0).then_some(ttl),
+            value,
+        });
+    }
+    Ok(StateSnapshot {
+        format_version: SNAPSHOT_FORMAT_VERSION,
+        cache_schema_version: CACHE_SCHEMA_VERSION,
+        cluster: Cluster::global(),
+        exported_at: Utc::now(),
+        entries,
+    })
+}
+
+/// Checks a snapshot can be imported into this deployment
+fn validate(snapshot: &StateSnapshot) -> Result<(), RiskCalculationError> {
+    if snapshot.format_version != SNAPSHOT_FORMAT_VERSION {
+        return Err(RiskCalculationError::InvalidParameter(format!(
+            "snapshot format {} is not supported, expected {}",
+            snapshot.format_version, SNAPSHOT_FORMAT_VERSION
+        )));
+    }
+    if snapshot.cluster != Cluster::global() {
+        return Err(RiskCalculationError::InvalidParameter(format!(
+            "snapshot of {} can't be imported into {}",
+            snapshot.cluster.as_str(),
+            Cluster::global().as_str()
+        )));
+    }
+    if snapshot.cache_schema_version != CACHE_SCHEMA_VERSION {
+        return Err(RiskCalculationError::InvalidParameter(format!(
+            "snapshot of cache schema {} can't be imported into schema {}",
+            snapshot.cache_schema_version, CACHE_SCHEMA_VERSION
+        )));
+    }
+    if let Some(entry) = snapshot.entries.iter().find(|entry| {
+        entry_key(&entry.redis_key()).as_ref() != Some(&(entry.key.clone(), entry.versioned))
+    }) {
+        return Err(RiskCalculationError::InvalidParameter(format!(
+            "snapshot entry {} is not state of this service",
+            entry.key
+        )));
+    }
+    Ok(())
+}
+
+/// The entries of `imported` not already in `existing`, in their order
+fn unrecorded<'a>(existing: &[String], imported: &'a [String]) -> Vec<&'a String> {
+    imported
+        .iter()
+        .filter(|entry| !existing.contains(entry))
+        .collect()
+}
+
+/// Writes every entry of the snapshot, replacing the keys it has
+///
+/// With `replace` the exported keys the snapshot doesn't have are deleted too,
+/// leaving redis as it was exported. The admin audit is kept either way, the
+/// snapshot's events it doesn't have are appended. Entries are written in a
+/// single transaction.
+pub async fn import_state(
+    redis_client: &redis::Client,
+    snapshot: &StateSnapshot,
+    replace: bool,
+) -> Result<ImportSummary, RiskCalculationError> {
+    validate(snapshot)?;
+    let mut connection = connection(redis_client).await?;
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    let mut deleted = 0;
+    let append_only = |key: &str, versioned: bool| !versioned && APPEND_ONLY_RECORDS.contains(&key);
+    if replace {
+        for (key, entry_key, versioned) in exported_keys(&mut connection).await? {
+            if append_only(&entry_key, versioned) {
+                continue;
+            }
+            pipe.del(key).ignore();
+            deleted += 1;
+        }
+    }
+    for entry in &snapshot.entries {
+        let key = entry.redis_key();
+        if append_only(&entry.key, entry.versioned) {
+            if let EntryValue::List(imported) = &entry.value {
+                let existing: Vec<String> = connection
+                    .lrange(&key, 0, -1)
+                    .await
+                    .map_err(RiskCalculationError::from)?;
+                let appended = unrecorded(&existing, imported);
+                if !appended.is_empty() {
+                    pipe.rpush(&key, appended).ignore();
+                }
+            }
+            continue;
+        }
+        pipe.del(&key).ignore();
+        match &entry.value {
+            EntryValue::String(value) => pipe.set(&key, value).ignore(),
+            EntryValue::List(values) if !values.is_empty() => pipe.rpush(&key, values).ignore(),
+            EntryValue::Set(members) if !members.is_empty() => pipe.sadd(&key, members).ignore(),
+            EntryValue::SortedSet(members) if !members.is_empty() => {
+                let members: Vec<(f64, &String)> = members
+                    .iter()
+                    .map(|(member, score)| (*score, member))
+                    .collect();
+                pipe.zadd_multiple(&key, &members).ignore()
+            }
+            EntryValue::Hash(fields) if !fields.is_empty() => {
+                let fields: Vec<(&String, &String)> = fields.iter().collect();
+                pipe.hset_multiple(&key, &fields).ignore()
+            }
+            // Redis doesn't keep empty collections
+            _ => continue,
+        };
+        if let Some(ttl) = entry.ttl_ms {
+            pipe.pexpire(&key, ttl).ignore();
+        }
+    }
+    let _: () = pipe
+        .query_async(&mut connection)
+        .await
+        .map_err(RiskCalculationError::from)?;
+    Ok(ImportSummary {
+        imported: snapshot.entries.len(),
+        deleted,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ImportSummary {
+    pub imported: usize,
+    /// Keys cleared before importing, when replacing
+    pub deleted: usize,
+}
+
+/// Params of an export's approval, exports take none
+#[derive(Debug, Default, Deserialize)]
+pub struct ExportRequest {}
+
+/// Params of an import's approval
+#[derive(Debug, Deserialize)]
+pub struct ImportRequest {
+    /// [`snapshot_hash`] of the snapshot, as sent in the body
+    pub snapshot_hash: String,
+    /// Delete the state the snapshot doesn't have
+    #[serde(default)]
+    pub replace: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportBody {
+    pub approval: MultisigApproval,
+    /// Kept as sent, so its hash is what the members approved
+    pub snapshot: Box<RawValue>,
+}
+
+/// Admin command: dumps the cluster's state as a [`StateSnapshot`]
+///
+/// The [`crate::multisig::APPROVAL_HEADER`] carries a [`MultisigApproval`] of
+/// an [`ExportRequest`], as JSON.
+pub async fn export(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let approval = approval_from_headers(&headers)?;
+        let _: ExportRequest =
+            authorize(&state.redis_client, &approval, AdminAction::ExportState).await?;
+        let snapshot = export_state(&state.redis_client).await?;
+        tracing::info!("Exported {} keys", snapshot.entries.len());
+        Ok(snapshot)
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+/// Admin command: restores a [`StateSnapshot`], e.g. into a new deployment
+///
+/// The body is an [`ImportBody`], whose approval is of an [`ImportRequest`].
+/// Snapshots of a whole deployment may need a larger `MAX_BODY_BYTES`.
+pub async fn import(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+    Json(body): Json<ImportBody>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let request: ImportRequest = authorize(
+            &state.redis_client,
+            &body.approval,
+            AdminAction::ImportState,
+        )
+        .await?;
+        let hash = snapshot_hash(body.snapshot.get());
+        if hash != request.snapshot_hash {
+            return Err(RiskCalculationError::Unauthorized(format!(
+                "snapshot hash {} is not the approved {}",
+                hash, request.snapshot_hash
+            )));
+        }
+        let snapshot: StateSnapshot = serde_json::from_str(body.snapshot.get())
+            .map_err(|e| RiskCalculationError::InvalidParameter(format!("snapshot: {}", e)))?;
+        let summary = import_state(&state.redis_client, &snapshot, request.replace).await?;
+        tracing::info!(
+            "Imported {} keys exported at {}, deleted {}",
+            summary.imported,
+            snapshot.exported_at,
+            summary.deleted
+        );
+        Ok(summary)
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_snapshot() {
+        assert_eq!(
+            entry_key(&versioned_key("risk_history:kamino")),
+            Some(("risk_history:kamino".to_string(), true))
+        );
+        assert_eq!(
+            entry_key("portfolio_events:wallet"),
+            Some(("portfolio_events:wallet".to_string(), false))
+        );
+        assert_eq!(entry_key(&lock_key(&versioned_key("kamino:reserve"))), None);
+        assert_eq!(entry_key(&versioned_key("idempotency:deposit:w:k")), None);
+        assert_eq!(entry_key(&versioned_key("devnet:kamino:reserve")), None);
+        assert_eq!(entry_key("admin_nonce:1"), None);
+        assert_eq!(entry_key("rebalance_plans"), None);
+
+        let entry = SnapshotEntry {
+            key: "risk_history:kamino".to_string(),
+            versioned: true,
+            ttl_ms: None,
+            value: EntryValue::SortedSet(vec![("{}".to_string(), 1.0)]),
+        };
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["type"], "sorted_set");
+        assert_eq!(
+            serde_json::from_value::<SnapshotEntry>(json).unwrap(),
+            entry
+        );
+
+        let mut snapshot = StateSnapshot {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            cache_schema_version: CACHE_SCHEMA_VERSION,
+            cluster: Cluster::global(),
+            exported_at: Utc::now(),
+            entries: vec![entry],
+        };
+        assert!(validate(&snapshot).is_ok());
+        snapshot.entries[0].key = "idempotency:deposit:w:k".to_string();
+        assert!(validate(&snapshot).is_err());
+        snapshot.entries.clear();
+        snapshot.cache_schema_version += 1;
+        assert!(validate(&snapshot).is_err());
+
+        let existing = vec!["b".to_string(), "a".to_string()];
+        let imported = vec!["a".to_string(), "z".to_string()];
+        assert_eq!(unrecorded(&existing, &imported), vec![&imported[1]]);
+
+        assert_eq!(snapshot_hash("{}"), snapshot_hash("{}"));
+        assert_ne!(snapshot_hash("{}"), snapshot_hash("{ }"));
+    }
+}
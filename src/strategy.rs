@@ -0,0 +1,327 @@
+use axum::{
+    extract::{Query, State},
+    response::Response,
+};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    kamino::{
+        reserve::{KaminoReserveConfig, KAMINO_MAIN_MARKET, KAMINO_USDC_RESERVE},
+        sources::LiveSources,
+        KaminoRisk,
+    },
+    marginfi::MarginfiRisk,
+    rebalancing::split_proportionally,
+    registry::{ProtocolRegistry, RegisteredProtocol},
+    risk_model::{json_response, timings_requested, Protocol, RiskCalculationError},
+    state::AppState,
+    timings::with_timings,
+};
+
+/// A reserve of a specific protocol that is part of a strategy
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "protocol", rename_all = "lowercase")]
+pub enum StrategyLeg {
+    Kamino {
+        market: String,
+        reserve: String,
+        /// Relative share of the strategy allocated to this leg
+        weight: u64,
+    },
+    /// The USDC bank of the marginfi main group
+    Marginfi { weight: u64 },
+}
+
+impl StrategyLeg {
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            StrategyLeg::Kamino { .. } => Protocol::Kamino,
+            StrategyLeg::Marginfi { .. } => Protocol::Marginfy,
+        }
+    }
+
+    pub fn weight(&self) -> u64 {
+        match self {
+            StrategyLeg::Kamino { weight, .. } | StrategyLeg::Marginfi { weight } => *weight,
+        }
+    }
+
+    /// The registry entry that computes this leg's risk
+    pub fn registered(
+        &self,
+        redis_client: redis::Client,
+    ) -> Result<RegisteredProtocol, RiskCalculationError> {
+        Ok(match self {
+            StrategyLeg::Kamino {
+                market, reserve, ..
+            } => RegisteredProtocol::Kamino(KaminoRisk {
+                redis_client,
+                reserve: KaminoReserveConfig::new(market, reserve)?,
+                sources: LiveSources,
+            }),
+            StrategyLeg::Marginfi { .. } => {
+                RegisteredProtocol::Marginfi(MarginfiRisk { redis_client })
+            }
+        })
+    }
+}
+
+/// A named group of reserves across protocols that users allocate to as one unit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Strategy {
+    pub name: String,
+    pub legs: Vec<StrategyLeg>,
+}
+
+impl Strategy {
+    /// Splits an amount allocated to the strategy across its legs by weight
+    ///
+    /// Legs of the same protocol are merged, as portfolios track allocations per protocol.
+    pub fn allocate(&self, amount: u64) -> Vec<(Protocol, u64)> {
+        let weights: Vec<(Protocol, u64)> = self
+            .legs
+            .iter()
+            .map(|leg| (leg.protocol(), leg.weight()))
+            .collect();
+        let mut allocations: Vec<(Protocol, u64)> = Vec::new();
+        for (protocol, part) in split_proportionally(amount, &weights) {
+            match allocations.iter_mut().find(|(p, _)| *p == protocol) {
+                Some((_, allocated)) => *allocated += part,
+                None => allocations.push((protocol, part)),
+            }
+        }
+        allocations
+    }
+
+    fn validate(&self) -> Result<(), RiskCalculationError> {
+        if self.legs.is_empty() {
+            return Err(RiskCalculationError::ParseError(format!(
+                "strategy {:?} has no legs",
+                self.name
+            )));
+        }
+        if self.legs.iter().all(|leg| leg.weight() == 0) {
+            return Err(RiskCalculationError::ParseError(format!(
+                "strategy {:?} has only zero weights",
+                self.name
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// The set of strategies offered to users
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StrategyConfig {
+    pub strategies: Vec<Strategy>,
+}
+
+impl Default for StrategyConfig {
+    fn default() -> Self {
+        StrategyConfig {
+            strategies: vec![Strategy {
+                name: "USDC lending".to_string(),
+                legs: vec![
+                    StrategyLeg::Kamino {
+                        market: KAMINO_MAIN_MARKET.to_string(),
+                        reserve: KAMINO_USDC_RESERVE.to_string(),
+                        weight: 1,
+                    },
+                    StrategyLeg::Marginfi { weight: 1 },
+                ],
+            }],
+        }
+    }
+}
+
+impl StrategyConfig {
+    pub fn from_toml(config: &str) -> Result<Self, RiskCalculationError> {
+        let config: StrategyConfig =
+            toml::from_str(config).map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
+        for strategy in &config.strategies {
+            strategy.validate()?;
+        }
+        Ok(config)
+    }
+
+    /// Reads the TOML file at `STRATEGIES_CONFIG`, defaulting to USDC lending only
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        match std::env::var("STRATEGIES_CONFIG") {
+            Ok(path) => {
+                let config = std::fs::read_to_string(&path).map_err(|e| {
+                    RiskCalculationError::CustomError(format!("reading {}: {}", path, e))
+                })?;
+                Self::from_toml(&config)
+            }
+            Err(_) => Ok(Self::default()),
+        }
+    }
+}
+
+/// Risk of one leg within a strategy
+#[derive(Debug, Serialize)]
+pub struct LegAssessment {
+    pub leg: StrategyLeg,
+    pub overall_risk: f64,
+    pub snapshot_id: String,
+}
+
+/// Risk of a strategy, the weighted average of its legs' overall risk
+#[derive(Debug, Serialize)]
+pub struct StrategyAssessment {
+    pub name: String,
+    pub overall_risk: f64,
+    pub legs: Vec<LegAssessment>,
+    /// The requested amount split across protocols by the strategy's weights
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allocation: Option<Vec<(Protocol, u64)>>,
+}
+
+/// Assesses every leg of a strategy from its own risk snapshot
+pub async fn assess_strategy(
+    strategy: &Strategy,
+    redis_client: &redis::Client,
+) -> Result<StrategyAssessment, RiskCalculationError> {
+    let legs = join_all(strategy.legs.iter().map(|leg| async move {
+        let mut registry = ProtocolRegistry::new(redis_client.clone());
+        registry.register(leg.registered(redis_client.clone())?);
+        let snapshot = registry.snapshot().await?;
+        let assessment = snapshot
+            .comparison
+            .chosen()
+            .ok_or(RiskCalculationError::CustomError(format!(
+                "{:?} leg could not be assessed",
+                leg.protocol()
+            )))?;
+        Ok::<_, RiskCalculationError>(LegAssessment {
+            leg: leg.clone(),
+            overall_risk: assessment.risk_metrics.overall_risk.overall_risk,
+            snapshot_id: snapshot.snapshot_id.clone(),
+        })
+    }))
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(StrategyAssessment {
+        name: strategy.name.clone(),
+        overall_risk: weighted_risk(&legs),
+        legs,
+        allocation: None,
+    })
+}
+
+fn weighted_risk(legs: &[LegAssessment]) -> f64 {
+    let total_weight = legs.iter().map(|leg| leg.leg.weight()).sum::<u64>() as f64;
+    legs.iter()
+        .map(|leg| leg.overall_risk * leg.leg.weight() as f64)
+        .sum::<f64>()
+        / total_weight
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StrategiesQuery {
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+    /// Amount to split across each strategy's protocols
+    pub amount: Option<u64>,
+}
+
+/// Compares all configured strategies, ordered from lowest to highest risk
+pub async fn strategies(
+    State(state): State<AppState>,
+    Query(query): Query<StrategiesQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let config = &state.config.strategies;
+
+        let results = join_all(
+            config
+                .strategies
+                .iter()
+                .map(|strategy| assess_strategy(strategy, &state.redis_client)),
+        )
+        .await;
+        let mut ranking = Vec::new();
+        let mut unavailable = Vec::new();
+        for (strategy, result) in config.strategies.iter().zip(results) {
+            match result {
+                Ok(mut assessment) => {
+                    assessment.allocation = query.amount.map(|amount| strategy.allocate(amount));
+                    ranking.push(assessment)
+                }
+                Err(e) => {
+                    tracing::error!("Failed to assess strategy {}: {}", strategy.name, e);
+                    unavailable.push(serde_json::json!({
+                        "name": strategy.name,
+                        "error": e.to_string(),
+                        "code": e.code(),
+                    }));
+                }
+            }
+        }
+        ranking.sort_by(|a, b| a.overall_risk.total_cmp(&b.overall_risk));
+
+        Ok::<_, RiskCalculationError>(serde_json::json!({
+            "ranking": ranking,
+            "unavailable_strategies": unavailable,
+        }))
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strategy_config_from_toml() {
+        let config = StrategyConfig::from_toml(&format!(
+            r#"
+            [[strategies]]
+            name = "USDC lending"
+
+            [[strategies.legs]]
+            protocol = "kamino"
+            market = "{}"
+            reserve = "{}"
+            weight = 3
+
+            [[strategies.legs]]
+            protocol = "marginfi"
+            weight = 1
+            "#,
+            KAMINO_MAIN_MARKET, KAMINO_USDC_RESERVE
+        ))
+        .unwrap();
+        assert_eq!(config, StrategyConfig::default_with_weights(3, 1));
+        assert!(StrategyConfig::from_toml("[[strategies]]\nname = \"empty\"\nlegs = []").is_err());
+    }
+
+    #[test]
+    fn test_strategy_allocate() {
+        let strategy = &StrategyConfig::default_with_weights(3, 1).strategies[0];
+        assert_eq!(
+            strategy.allocate(1_000),
+            vec![(Protocol::Kamino, 750), (Protocol::Marginfy, 250)]
+        );
+    }
+
+    impl StrategyConfig {
+        fn default_with_weights(kamino: u64, marginfi: u64) -> Self {
+            let mut config = Self::default();
+            config.strategies[0].legs = vec![
+                StrategyLeg::Kamino {
+                    market: KAMINO_MAIN_MARKET.to_string(),
+                    reserve: KAMINO_USDC_RESERVE.to_string(),
+                    weight: kamino,
+                },
+                StrategyLeg::Marginfi { weight: marginfi },
+            ];
+            config
+        }
+    }
+}
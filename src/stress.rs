@@ -0,0 +1,544 @@
+use std::{collections::HashMap, str::FromStr};
+
+use axum::{
+    extract::{Path, Query, State},
+    response::Response,
+    Json,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{
+    liquidity_risk::{calculate_utilization_rate, score_liquidity, scored_concentration},
+    portfolio::{profile_target_weights, scan_positions, suggest_rebalance, SuggestedAllocation},
+    registry::ProtocolAssessment,
+    risk_model::{
+        json_response, timings_requested, DebugQuery, Protocol, RiskCalculationError, RiskProfile,
+        RiskResponse, RiskScore,
+    },
+    state::AppState,
+    timings::with_timings,
+    weights::RiskWeightsConfig,
+};
+
+/// Positions are only scanned from USDC reserves, see [`scan_positions`]
+const USDC_DECIMALS: i32 = 6;
+
+/// Shock applied to every protocol at once
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+pub struct StressScenario {
+    /// Fraction of the supply withdrawn, e.g. 0.3 for 30%
+    pub supply_withdrawn: f64,
+    /// Factor APY volatility is scaled with, e.g. 2 when APY swings double
+    pub apy_multiplier: f64,
+    /// The largest depositor withdraws everything
+    pub largest_depositor_exits: bool,
+}
+
+impl Default for StressScenario {
+    fn default() -> Self {
+        StressScenario {
+            supply_withdrawn: 0.0,
+            apy_multiplier: 1.0,
+            largest_depositor_exits: false,
+        }
+    }
+}
+
+impl StressScenario {
+    pub fn validate(&self) -> Result<(), RiskCalculationError> {
+        if !(0.0..=1.0).contains(&self.supply_withdrawn) || self.apy_multiplier < 0.0 {
+            return Err(RiskCalculationError::InvalidParameter(format!(
+                "supply_withdrawn must be within 0 and 1 and apy_multiplier non-negative: {:?}",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Risk metrics of a protocol recomputed under `scenario`
+///
+/// Withdrawals are capped by the available liquidity, since borrowed funds can't
+/// leave the pool, and leave borrows untouched. After the largest depositor exits,
+/// the next largest deposit isn't known, so the exited deposit is kept as an upper
+/// bound for it. Protocol and oracle risk aren't affected by market shocks.
+pub fn stress_metrics(
+    metrics: &RiskResponse,
+    scenario: &StressScenario,
+    weights: &RiskWeightsConfig,
+) -> RiskResponse {
+    let mut stressed = metrics.clone();
+
+    let liquidity = &mut stressed.liquidity_risk;
+    let exiting_share = if scenario.largest_depositor_exits {
+        liquidity.deposit_concentration
+    } else {
+        0.0
+    };
+    let withdrawn_share = (exiting_share + scenario.supply_withdrawn).min(1.0);
+    let available_liquidity = (liquidity.total_supply - liquidity.total_borrows).max(0.0);
+    liquidity.total_supply -= (liquidity.total_supply * withdrawn_share).min(available_liquidity);
+    liquidity.utilization_rate =
+        calculate_utilization_rate(liquidity.total_borrows, liquidity.total_supply)
+            .unwrap_or(100.0);
+
+    let remaining_deposits = if scenario.largest_depositor_exits {
+        liquidity
+            .total_deposits
+            .saturating_sub(liquidity.largest_deposit)
+    } else {
+        liquidity.total_deposits
+    };
+    // The remaining depositors withdraw pro rata
+    liquidity.total_deposits =
+        (remaining_deposits as f64 * (1.0 - scenario.supply_withdrawn)) as u128;
+    liquidity.largest_deposit = if scenario.largest_depositor_exits {
+        liquidity.largest_deposit.min(liquidity.total_deposits)
+    } else {
+        (liquidity.largest_deposit as f64 * (1.0 - scenario.supply_withdrawn)) as u128
+    };
+    if liquidity.total_deposits > 0 {
+        liquidity.deposit_concentration =
+            liquidity.largest_deposit as f64 / liquidity.total_deposits as f64;
+    }
+    liquidity.liquidity_risk = score_liquidity(
+        scored_concentration(
+            liquidity.deposit_concentration,
+            liquidity.borrower_concentration.as_ref(),
+        ),
+        liquidity.utilization_rate,
+        liquidity.tvl_change_24h_pct,
+        &weights.liquidity,
+    );
+
+    let volatility = &mut stressed.volatility_risk;
+    let volatility_risk = |sigma_apy: f64, sigma_utilization: f64| {
+        weights.volatility.apy * sigma_apy + weights.volatility.utilization * sigma_utilization
+    };
+    volatility.sigma_apy *= scenario.apy_multiplier;
+    volatility.volatility_risk =
+        volatility_risk(volatility.sigma_apy, volatility.sigma_utilization);
+    for lookback in &mut volatility.surface {
+        lookback.sigma_apy *= scenario.apy_multiplier;
+        lookback.volatility_risk = volatility_risk(lookback.sigma_apy, lookback.sigma_utilization);
+    }
+    // Wider APY swings scale the downside measures alike
+    if let Some(downside) = &mut volatility.downside {
+        downside.max_drawdown *= scenario.apy_multiplier;
+        for var in &mut downside.value_at_risk {
+            var.parametric *= scenario.apy_multiplier;
+            var.historical *= scenario.apy_multiplier;
+        }
+    }
+
+    // The weights sum to 1, so every pillar given always makes a score
+    stressed.overall_risk = RiskScore::from_raw(
+        weights,
+        Some(stressed.liquidity_risk.liquidity_risk),
+        Some(stressed.volatility_risk.volatility_risk),
+        Some(stressed.protocol_risk.protocol_risk),
+        Some(stressed.oracle_risk.oracle_risk),
+    )
+    .unwrap_or_default();
+    stressed
+}
+
+/// Every assessment of a comparison under `scenario`, ranked by stressed overall risk
+pub fn stress_ranking(
+    ranking: &[ProtocolAssessment],
+    scenario: &StressScenario,
+    weights: &RiskWeightsConfig,
+) -> Vec<ProtocolAssessment> {
+    let mut stressed: Vec<ProtocolAssessment> = ranking
+        .iter()
+        .map(|assessment| ProtocolAssessment {
+            protocol: assessment.protocol.clone(),
+            scope: assessment.scope.clone(),
+            risk_metrics: stress_metrics(&assessment.risk_metrics, scenario, weights),
+        })
+        .collect();
+    stressed.sort_by(|a, b| {
+        a.risk_metrics
+            .overall_risk
+            .overall_risk
+            .total_cmp(&b.risk_metrics.overall_risk.overall_risk)
+    });
+    stressed
+}
+
+/// Key figures of a protocol before and after a shock
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ProtocolStress {
+    pub protocol: Protocol,
+    pub utilization_rate: f64,
+    pub stressed_utilization_rate: f64,
+    pub deposit_concentration: f64,
+    pub stressed_deposit_concentration: f64,
+    pub liquidity_risk: f64,
+    pub stressed_liquidity_risk: f64,
+    pub volatility_risk: f64,
+    pub stressed_volatility_risk: f64,
+    pub overall_risk: f64,
+    pub stressed_overall_risk: f64,
+}
+
+/// Compares every assessment of a comparison with itself under `scenario`
+///
+/// Kept in the order of `ranking`, so the stressed scores can be read against
+/// the current ranking.
+pub fn protocol_stress(
+    ranking: &[ProtocolAssessment],
+    scenario: &StressScenario,
+    weights: &RiskWeightsConfig,
+) -> Vec<ProtocolStress> {
+    ranking
+        .iter()
+        .map(|assessment| {
+            let metrics = &assessment.risk_metrics;
+            let stressed = stress_metrics(metrics, scenario, weights);
+            ProtocolStress {
+                protocol: assessment.protocol.clone(),
+                utilization_rate: metrics.liquidity_risk.utilization_rate,
+                stressed_utilization_rate: stressed.liquidity_risk.utilization_rate,
+                deposit_concentration: metrics.liquidity_risk.deposit_concentration,
+                stressed_deposit_concentration: stressed.liquidity_risk.deposit_concentration,
+                liquidity_risk: metrics.liquidity_risk.liquidity_risk,
+                stressed_liquidity_risk: stressed.liquidity_risk.liquidity_risk,
+                volatility_risk: metrics.volatility_risk.volatility_risk,
+                stressed_volatility_risk: stressed.volatility_risk.volatility_risk,
+                overall_risk: metrics.overall_risk.overall_risk,
+                stressed_overall_risk: stressed.overall_risk.overall_risk,
+            }
+        })
+        .collect()
+}
+
+/// Every protocol of the latest snapshot under a shock scenario
+pub async fn stress_risk_model(
+    State(state): State<AppState>,
+    Query(query): Query<DebugQuery>,
+    Json(scenario): Json<StressScenario>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        scenario.validate()?;
+        let snapshot = state.registry.cached_snapshot().await?;
+        let weights = RiskWeightsConfig::global();
+        let stressed_ranking = stress_ranking(&snapshot.comparison.ranking, &scenario, weights);
+
+        Ok::<_, RiskCalculationError>(serde_json::json!({
+            "snapshot_id": snapshot.snapshot_id,
+            "scenario": scenario,
+            "protocols": protocol_stress(&snapshot.comparison.ranking, &scenario, weights),
+            "stressed_ranking": stressed_ranking
+                .iter()
+                .map(|assessment| assessment.protocol.clone())
+                .collect::<Vec<_>>(),
+        }))
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct PortfolioStressRequest {
+    /// Profile whose target allocation the post-shock rebalance moves towards
+    pub profile: RiskProfile,
+    #[serde(default)]
+    pub scenario: StressScenario,
+}
+
+/// What the shock does to one position of the wallet
+#[derive(Debug, Serialize, PartialEq, JsonSchema)]
+pub struct PositionStress {
+    pub protocol: Protocol,
+    pub amount: u64,
+    /// Part of the position the pool has the liquidity to pay out after the shock
+    pub withdrawable_amount: u64,
+    pub overall_risk: f64,
+    pub stressed_overall_risk: f64,
+}
+
+/// Post-shock overall risk of the allocation a profile targets
+#[derive(Debug, Serialize, PartialEq, JsonSchema)]
+pub struct ProfileStress {
+    pub profile: RiskProfile,
+    pub target_weights: Vec<(Protocol, u64)>,
+    pub stressed_overall_risk: f64,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PortfolioStressReport {
+    pub scenario: StressScenario,
+    pub positions: Vec<PositionStress>,
+    /// Share of the portfolio that couldn't be withdrawn right after the shock
+    pub projected_drawdown: f64,
+    /// Amount weighted overall risk of the current positions
+    pub overall_risk: f64,
+    pub stressed_overall_risk: f64,
+    pub profiles: Vec<ProfileStress>,
+    /// Moves the system would trigger to bring the wallet back to its profile
+    pub rebalance: Vec<SuggestedAllocation>,
+}
+
+/// Body of `/portfolio/:wallet/stress`
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct WalletStressReport {
+    pub wallet: String,
+    pub profile: RiskProfile,
+    pub snapshot_id: String,
+    pub report: PortfolioStressReport,
+}
+
+fn overall_risk_of(ranking: &[ProtocolAssessment], protocol: &Protocol) -> Option<f64> {
+    ranking
+        .iter()
+        .find(|assessment| assessment.protocol == *protocol)
+        .map(|assessment| assessment.risk_metrics.overall_risk.overall_risk)
+}
+
+/// Applies `scenario` to the positions of a wallet
+///
+/// Positions in protocols missing from the ranking count as fully withdrawable
+/// and riskless, as nothing is known about them.
+pub fn stress_portfolio(
+    positions: &HashMap<Protocol, u64>,
+    profile: &RiskProfile,
+    ranking: &[ProtocolAssessment],
+    scenario: &StressScenario,
+    weights: &RiskWeightsConfig,
+) -> PortfolioStressReport {
+    let stressed_ranking = stress_ranking(ranking, scenario, weights);
+    let total_amount = positions.values().sum::<u64>();
+    let amount_weighted = |risks: &[(Protocol, f64)], amounts: &[(Protocol, u64)]| {
+        let total = amounts.iter().map(|(_, amount)| *amount).sum::<u64>();
+        if total == 0 {
+            return 0.0;
+        }
+        amounts
+            .iter()
+            .map(|(protocol, amount)| {
+                let risk = risks
+                    .iter()
+                    .find(|(ranked, _)| ranked == protocol)
+                    .map_or(0.0, |(_, risk)| *risk);
+                risk * *amount as f64
+            })
+            .sum::<f64>()
+            / total as f64
+    };
+
+    let mut position_stress: Vec<PositionStress> = positions
+        .iter()
+        .map(|(protocol, amount)| {
+            let withdrawable_amount = stressed_ranking
+                .iter()
+                .find(|assessment| assessment.protocol == *protocol)
+                .map_or(*amount, |assessment| {
+                    let liquidity = &assessment.risk_metrics.liquidity_risk;
+                    let available = (liquidity.total_supply - liquidity.total_borrows).max(0.0)
+                        * 10f64.powi(USDC_DECIMALS);
+                    (*amount).min(available as u64)
+                });
+            PositionStress {
+                protocol: protocol.clone(),
+                amount: *amount,
+                withdrawable_amount,
+                overall_risk: overall_risk_of(ranking, protocol).unwrap_or(0.0),
+                stressed_overall_risk: overall_risk_of(&stressed_ranking, protocol).unwrap_or(0.0),
+            }
+        })
+        .collect();
+    position_stress.sort_by_key(|position| std::cmp::Reverse(position.amount));
+    let locked_amount = position_stress
+        .iter()
+        .map(|position| position.amount - position.withdrawable_amount)
+        .sum::<u64>();
+
+    let ranked_risks = |ranking: &[ProtocolAssessment]| -> Vec<(Protocol, f64)> {
+        ranking
+            .iter()
+            .map(|assessment| {
+                (
+                    assessment.protocol.clone(),
+                    assessment.risk_metrics.overall_risk.overall_risk,
+                )
+            })
+            .collect()
+    };
+    let risks = ranked_risks(ranking);
+    let stressed_risks = ranked_risks(&stressed_ranking);
+    let current: Vec<(Protocol, u64)> = positions
+        .iter()
+        .map(|(protocol, amount)| (protocol.clone(), *amount))
+        .collect();
+
+    let profiles = [RiskProfile::Low, RiskProfile::Medium, RiskProfile::High]
+        .into_iter()
+        .map(|profile| {
+            let target_weights = profile_target_weights(&profile, &stressed_risks);
+            ProfileStress {
+                stressed_overall_risk: amount_weighted(&stressed_risks, &target_weights),
+                profile,
+                target_weights,
+            }
+        })
+        .collect();
+
+    PortfolioStressReport {
+        scenario: scenario.clone(),
+        positions: position_stress,
+        projected_drawdown: if total_amount > 0 {
+            locked_amount as f64 / total_amount as f64
+        } else {
+            0.0
+        },
+        overall_risk: amount_weighted(&risks, &current),
+        stressed_overall_risk: amount_weighted(&stressed_risks, &current),
+        profiles,
+        rebalance: suggest_rebalance(positions, &profile_target_weights(profile, &stressed_risks)),
+    }
+}
+
+pub async fn stress_wallet(
+    State(state): State<AppState>,
+    Path(wallet): Path<String>,
+    Query(query): Query<DebugQuery>,
+    Json(request): Json<PortfolioStressRequest>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        request.scenario.validate()?;
+        let wallet = Pubkey::from_str(&wallet)
+            .map_err(|e| RiskCalculationError::InvalidParameter(format!("wallet: {}", e)))?;
+        let positions = scan_positions(&wallet, &state.config.kamino_reserve).await?;
+        let snapshot = state.registry.cached_snapshot().await?;
+        let report = stress_portfolio(
+            &positions,
+            &request.profile,
+            &snapshot.comparison.ranking,
+            &request.scenario,
+            RiskWeightsConfig::global(),
+        );
+
+        Ok::<_, RiskCalculationError>(WalletStressReport {
+            wallet: wallet.to_string(),
+            profile: request.profile,
+            snapshot_id: snapshot.snapshot_id,
+            report,
+        })
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::risk_model::{
+        LiquidityRiskMetrics, ProtocolRiskMetrics, VolatilityRiskMetrics, SCHEMA_VERSION,
+    };
+
+    fn assessment(protocol: Protocol, total_borrows: f64, total_supply: f64) -> ProtocolAssessment {
+        let weights = RiskWeightsConfig::default();
+        let mut risk_metrics = RiskResponse {
+            schema_version: SCHEMA_VERSION,
+            liquidity_risk: LiquidityRiskMetrics {
+                total_borrows,
+                total_supply,
+                largest_deposit: 100,
+                total_deposits: 1_000,
+                deposit_concentration: 0.1,
+                ..Default::default()
+            },
+            volatility_risk: VolatilityRiskMetrics {
+                sigma_apy: 1.0,
+                sigma_utilization: 1.0,
+                volatility_risk: 1.0,
+                ..Default::default()
+            },
+            protocol_risk: ProtocolRiskMetrics {
+                protocol_risk: 10.0,
+                penalties: Vec::new(),
+                components: Vec::new(),
+            },
+            ..Default::default()
+        };
+        // Recompute the derived fields with a neutral shock
+        risk_metrics = stress_metrics(&risk_metrics, &StressScenario::default(), &weights);
+        ProtocolAssessment {
+            protocol,
+            scope: String::new(),
+            risk_metrics,
+        }
+    }
+
+    #[test]
+    fn test_stress_metrics() {
+        let weights = RiskWeightsConfig::default();
+        let baseline = assessment(Protocol::Kamino, 600.0, 1_000.0).risk_metrics;
+        assert!((baseline.liquidity_risk.utilization_rate - 60.0).abs() < 1e-9);
+
+        let scenario = StressScenario {
+            supply_withdrawn: 0.3,
+            apy_multiplier: 2.0,
+            largest_depositor_exits: true,
+        };
+        let stressed = stress_metrics(&baseline, &scenario, &weights);
+        // 40% of the supply would leave, but only 400 isn't borrowed
+        assert_eq!(stressed.liquidity_risk.total_supply, 600.0);
+        assert_eq!(stressed.liquidity_risk.utilization_rate, 100.0);
+        assert_eq!(stressed.liquidity_risk.total_deposits, 630);
+        assert_eq!(stressed.volatility_risk.sigma_apy, 2.0);
+        assert!(stressed.overall_risk.overall_risk > baseline.overall_risk.overall_risk);
+
+        let ranking = [assessment(Protocol::Kamino, 600.0, 1_000.0)];
+        let protocols = protocol_stress(&ranking, &scenario, &weights);
+        assert_eq!(protocols.len(), 1);
+        assert_eq!(protocols[0].utilization_rate, 60.0);
+        assert_eq!(protocols[0].stressed_utilization_rate, 100.0);
+        assert_eq!(
+            protocols[0].stressed_overall_risk,
+            stressed.overall_risk.overall_risk
+        );
+    }
+
+    #[test]
+    fn test_stress_portfolio() {
+        let ranking = vec![
+            // Only 100 is left unborrowed after the shock
+            assessment(Protocol::Kamino, 400.0, 1_000.0),
+            assessment(Protocol::Marginfy, 100.0, 1_000.0),
+        ];
+        let positions = HashMap::from([(Protocol::Kamino, 200_000_000), (Protocol::Marginfy, 100)]);
+        let scenario = StressScenario {
+            supply_withdrawn: 0.5,
+            ..Default::default()
+        };
+        let report = stress_portfolio(
+            &positions,
+            &RiskProfile::Low,
+            &ranking,
+            &scenario,
+            &RiskWeightsConfig::default(),
+        );
+        // 100 USDC of liquidity is left in Kamino for a 200 USDC position
+        assert_eq!(report.positions[0].withdrawable_amount, 100_000_000);
+        assert!((report.projected_drawdown - 100_000_000.0 / 200_000_100.0).abs() < 1e-9);
+        assert!(report.stressed_overall_risk > report.overall_risk);
+        assert_eq!(report.profiles.len(), 3);
+        // Low moves everything into the protocol least affected by the shock
+        assert_eq!(
+            report.profiles[0].target_weights,
+            vec![(Protocol::Marginfy, 10_000)]
+        );
+        assert!(report
+            .rebalance
+            .iter()
+            .any(|suggestion| suggestion.protocol == Protocol::Kamino
+                && suggestion.delta == -200_000_000));
+    }
+}
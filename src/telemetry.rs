@@ -0,0 +1,126 @@
+//! Logging and trace export of the `risk_model` binary
+
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use tracing_subscriber::{
+    filter::LevelFilter, fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt,
+    Layer,
+};
+
+use crate::risk_model::RiskCalculationError;
+
+const DEFAULT_SERVICE_NAME: &str = "risk_model";
+
+/// How log lines are written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Pretty,
+    /// One JSON object per line with the event's fields, for log pipelines
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(format: &str) -> Result<Self, RiskCalculationError> {
+        match format.trim() {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(RiskCalculationError::ParseError(format!(
+                "invalid LOG_FORMAT {:?}, expected pretty or json",
+                other
+            ))),
+        }
+    }
+
+    /// Reads `LOG_FORMAT`, `pretty` when not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        std::env::var("LOG_FORMAT").map_or(Ok(LogFormat::Pretty), |format| Self::parse(&format))
+    }
+}
+
+/// Flushes the spans not exported yet when dropped
+pub struct TelemetryGuard {
+    tracer_provider: Option<TracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if let Some(tracer_provider) = &self.tracer_provider {
+            if let Err(e) = tracer_provider.shutdown() {
+                eprintln!("Failed to export the remaining spans: {}", e);
+            }
+        }
+    }
+}
+
+/// Exports spans over OTLP/gRPC to `OTEL_EXPORTER_OTLP_ENDPOINT`, `None` when not set
+///
+/// Spans are named `OTEL_SERVICE_NAME`, `risk_model` by default.
+fn tracer_provider() -> Result<Option<TracerProvider>, RiskCalculationError> {
+    let Ok(endpoint) = std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT") else {
+        return Ok(None);
+    };
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()
+        .map_err(|e| RiskCalculationError::ParseError(format!("OTLP exporter: {}", e)))?;
+    let service_name =
+        std::env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| DEFAULT_SERVICE_NAME.to_string());
+    Ok(Some(
+        TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new([KeyValue::new("service.name", service_name)]))
+            .build(),
+    ))
+}
+
+/// Installs the global subscriber, logging to stderr when `to_stderr`
+///
+/// Must run within the Tokio runtime, which exports the spans. Keep the guard
+/// until the process exits.
+pub fn init(to_stderr: bool) -> Result<TelemetryGuard, RiskCalculationError> {
+    let format = LogFormat::from_env()?;
+    let tracer_provider = tracer_provider()?;
+
+    let writer = if to_stderr {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_target(false)
+        .with_level(true)
+        .with_file(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_writer(writer);
+    let fmt = match format {
+        LogFormat::Pretty => fmt.boxed(),
+        LogFormat::Json => fmt.json().flatten_event(true).boxed(),
+    };
+    let otel = tracer_provider.as_ref().map(|tracer_provider| {
+        tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer(DEFAULT_SERVICE_NAME))
+    });
+    tracing_subscriber::registry()
+        .with(fmt)
+        .with(otel)
+        .with(LevelFilter::INFO)
+        .try_init()
+        .map_err(|e| RiskCalculationError::CustomError(format!("tracing: {}", e)))?;
+    Ok(TelemetryGuard { tracer_provider })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!(LogFormat::parse("json").unwrap(), LogFormat::Json);
+        assert_eq!(LogFormat::parse(" pretty ").unwrap(), LogFormat::Pretty);
+        assert!(LogFormat::parse("logfmt").is_err());
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use serde::Serialize;
+use tracing::Instrument;
+
+tokio::task_local! {
+    static TIMINGS: Arc<Timings>;
+}
+
+/// Where time spent serving a request goes
+#[derive(Debug, Clone, Copy)]
+pub enum Timing {
+    CacheRead,
+    Rpc,
+    ExternalApi,
+    Compute,
+}
+
+impl Timing {
+    /// Span around the timed work, so traces show where a request's time goes
+    fn span(self) -> tracing::Span {
+        match self {
+            Timing::CacheRead => tracing::info_span!("cache_read"),
+            Timing::Rpc => tracing::info_span!("rpc"),
+            Timing::ExternalApi => tracing::info_span!("external_api"),
+            Timing::Compute => tracing::info_span!("compute"),
+        }
+    }
+}
+
+/// Time accumulated per category, in microseconds
+#[derive(Debug, Default)]
+struct Timings {
+    cache_read: AtomicU64,
+    rpc: AtomicU64,
+    external_api: AtomicU64,
+    compute: AtomicU64,
+    recomputed: Mutex<Vec<String>>,
+}
+
+impl Timings {
+    fn record(&self, timing: Timing, started: Instant) {
+        let counter = match timing {
+            Timing::CacheRead => &self.cache_read,
+            Timing::Rpc => &self.rpc,
+            Timing::ExternalApi => &self.external_api,
+            Timing::Compute => &self.compute,
+        };
+        counter.fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Latency breakdown returned with `?debug=timings`
+///
+/// Categories are summed over concurrent work, so together they can exceed `total_ms`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TimingsReport {
+    pub cache_read_ms: f64,
+    pub rpc_ms: f64,
+    pub external_api_ms: f64,
+    pub compute_ms: f64,
+    pub total_ms: f64,
+    /// Cached components that had expired and were computed again
+    pub recomputed: Vec<String>,
+}
+
+fn to_ms(micros: &AtomicU64) -> f64 {
+    micros.load(Ordering::Relaxed) as f64 / 1000.0
+}
+
+/// Runs `future` while collecting the timings recorded by everything it awaits
+pub async fn with_timings<F: Future>(future: F) -> (F::Output, TimingsReport) {
+    let timings = Arc::new(Timings::default());
+    let started = Instant::now();
+    let output = TIMINGS.scope(timings.clone(), future).await;
+    let report = TimingsReport {
+        cache_read_ms: to_ms(&timings.cache_read),
+        rpc_ms: to_ms(&timings.rpc),
+        external_api_ms: to_ms(&timings.external_api),
+        compute_ms: to_ms(&timings.compute),
+        total_ms: started.elapsed().as_micros() as f64 / 1000.0,
+        recomputed: timings
+            .recomputed
+            .lock()
+            .map(|recomputed| recomputed.clone())
+            .unwrap_or_default(),
+    };
+    (output, report)
+}
+
+/// Awaits `future`, attributing its duration to `timing`
+///
+/// Outside of [`with_timings`] nothing is recorded.
+pub async fn timed<F: Future>(timing: Timing, future: F) -> F::Output {
+    let started = Instant::now();
+    let output = future.instrument(timing.span()).await;
+    let _ = TIMINGS.try_with(|timings| timings.record(timing, started));
+    output
+}
+
+/// Runs `f`, attributing its duration to `timing`
+pub fn timed_sync<T>(timing: Timing, f: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let output = timing.span().in_scope(f);
+    let _ = TIMINGS.try_with(|timings| timings.record(timing, started));
+    output
+}
+
+/// Spawns `future` onto the runtime, its timings are recorded with those of the
+/// current [`with_timings`] scope and its spans within the current span
+pub fn spawn_timed<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let future = future.in_current_span();
+    match TIMINGS.try_with(Arc::clone) {
+        Ok(timings) => tokio::spawn(TIMINGS.scope(timings, future)),
+        Err(_) => tokio::spawn(future),
+    }
+}
+
+/// Notes that the cached `component` was computed again while serving the request
+pub fn record_recomputed(component: String) {
+    let _ = TIMINGS.try_with(|timings| {
+        if let Ok(mut recomputed) = timings.recomputed.lock() {
+            recomputed.push(component);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_with_timings() {
+        let (output, report) = with_timings(async {
+            timed(Timing::Rpc, tokio::time::sleep(Duration::from_millis(20))).await;
+            record_recomputed("kamino:liquidity".to_string());
+            timed_sync(Timing::Compute, || 42)
+        })
+        .await;
+        assert_eq!(output, 42);
+        assert!(report.rpc_ms >= 20.0);
+        assert_eq!(report.cache_read_ms, 0.0);
+        assert!(report.total_ms >= report.rpc_ms);
+        assert_eq!(report.recomputed, vec!["kamino:liquidity".to_string()]);
+
+        // Recording outside of `with_timings` is a no-op
+        assert_eq!(timed_sync(Timing::Compute, || 1), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_spawn_timed() {
+        let (output, report) = with_timings(async {
+            spawn_timed(async {
+                record_recomputed("marginfi:oracle".to_string());
+                timed(Timing::Rpc, tokio::time::sleep(Duration::from_millis(20))).await;
+                7
+            })
+            .await
+            .unwrap()
+        })
+        .await;
+        assert_eq!(output, 7);
+        assert!(report.rpc_ms >= 20.0);
+        assert_eq!(report.recomputed, vec!["marginfi:oracle".to_string()]);
+    }
+}
@@ -0,0 +1,172 @@
+use axum::{
+    extract::{Query, State},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use solana_sdk::pubkey::Pubkey;
+
+use crate::{
+    kamino::{reserve::KaminoReserveConfig, sources::LiveSources, KaminoRisk},
+    privacy::PrivacyMode,
+    risk_model::{json_response, timings_requested, RiskCalculationError},
+    state::AppState,
+    timings::with_timings,
+};
+
+/// Largest deposits kept per ranking, and the most a request can ask for
+pub const MAX_TOP_DEPOSITORS: usize = 100;
+const DEFAULT_TOP_DEPOSITORS: usize = 20;
+
+/// Largest deposits of a reserve, as cached between scans
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DepositRanking {
+    pub total_deposits: u128,
+    /// Largest first, at most [`MAX_TOP_DEPOSITORS`]
+    pub largest: Vec<(Pubkey, u128)>,
+}
+
+impl DepositRanking {
+    pub fn from_deposits(mut deposits: Vec<(Pubkey, u128)>) -> Self {
+        let total_deposits = deposits.iter().map(|(_, deposit)| *deposit).sum();
+        deposits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        deposits.truncate(MAX_TOP_DEPOSITORS);
+        DepositRanking {
+            total_deposits,
+            largest: deposits,
+        }
+    }
+
+    /// The `limit` largest depositors as `privacy` allows them to be exposed
+    ///
+    /// Shares are of the exposed amounts, as exact shares would reveal the
+    /// exact amounts.
+    pub fn top(&self, limit: usize, privacy: &PrivacyMode) -> Vec<TopDepositor> {
+        self.largest
+            .iter()
+            .take(limit)
+            .map(|(obligation, deposit)| {
+                let amount = privacy.amount(*deposit);
+                TopDepositor {
+                    obligation: privacy.pubkey(obligation),
+                    amount,
+                    share: if self.total_deposits > 0 {
+                        amount as f64 / self.total_deposits as f64
+                    } else {
+                        0.0
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+/// An obligation among the largest depositors of a reserve
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopDepositor {
+    /// Masked with a salted hash when served
+    pub obligation: String,
+    pub amount: u128,
+    /// Share of the reserve's deposits, between 0 and 1
+    pub share: f64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TopDepositorsQuery {
+    /// Number of depositors, 20 by default
+    pub limit: Option<usize>,
+    /// Kamino lending market, requires `reserve`
+    pub market: Option<String>,
+    /// Kamino reserve, requires `market`
+    pub reserve: Option<String>,
+    /// `timings` adds a latency breakdown to the response
+    pub debug: Option<String>,
+}
+
+/// Largest depositors of a Kamino reserve, `/protocols/kamino/top_depositors`
+///
+/// Depositors are always masked, see [`PrivacyMode::masking`].
+pub async fn kamino_top_depositors(
+    State(state): State<AppState>,
+    Query(query): Query<TopDepositorsQuery>,
+) -> Response {
+    let (result, timings) = with_timings(async {
+        let limit = match query.limit.unwrap_or(DEFAULT_TOP_DEPOSITORS) {
+            limit @ 1..=MAX_TOP_DEPOSITORS => limit,
+            limit => {
+                return Err(RiskCalculationError::InvalidParameter(format!(
+                    "limit must be between 1 and {}: {}",
+                    MAX_TOP_DEPOSITORS, limit
+                )))
+            }
+        };
+        let kamino = KaminoRisk {
+            redis_client: state.redis_client.clone(),
+            reserve: KaminoReserveConfig::from_params(
+                query.market.as_deref(),
+                query.reserve.as_deref(),
+                &state.config.kamino_reserve,
+            )?,
+            sources: LiveSources,
+        };
+        let ranking = kamino.deposit_ranking().await?;
+
+        Ok(serde_json::json!({
+            "scope": kamino.scope(),
+            "total_deposits": ranking.total_deposits,
+            "depositors": ranking.top(limit, &state.config.privacy.masking()),
+        }))
+    })
+    .await;
+
+    json_response(result, timings_requested(&query.debug).then_some(timings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deposit_ranking() {
+        let (whale, dolphin, fish) = (
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+        );
+        let ranking =
+            DepositRanking::from_deposits(vec![(fish, 100), (whale, 600), (dolphin, 300)]);
+        assert_eq!(ranking.total_deposits, 1_000);
+
+        let top = ranking.top(2, &PrivacyMode::Full);
+        assert_eq!(
+            top,
+            vec![
+                TopDepositor {
+                    obligation: whale.to_string(),
+                    amount: 600,
+                    share: 0.6,
+                },
+                TopDepositor {
+                    obligation: dolphin.to_string(),
+                    amount: 300,
+                    share: 0.3,
+                },
+            ]
+        );
+
+        let public = PrivacyMode::Public {
+            salt: "salt".to_string(),
+        };
+        let masked = ranking.top(1, &public);
+        assert_ne!(masked[0].obligation, whale.to_string());
+        // 600 with at most 5% of noise, rounded down to the 1-2-5 series
+        assert_eq!(masked[0].amount, 500);
+
+        let many = (0..MAX_TOP_DEPOSITORS + 5)
+            .map(|i| (Pubkey::new_unique(), i as u128 + 1))
+            .collect();
+        assert_eq!(
+            DepositRanking::from_deposits(many).largest.len(),
+            MAX_TOP_DEPOSITORS
+        );
+    }
+}
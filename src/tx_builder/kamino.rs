@@ -0,0 +1,144 @@
+use solana_sdk::{
+    hash::hash,
+    instruction::{AccountMeta, Instruction},
+    pubkey::Pubkey,
+    sysvar,
+};
+
+use crate::kamino::{reserve::KaminoReserveConfig, reserve_account::ReserveAccount};
+
+use super::{associated_token_address, create_associated_token_account_idempotent, token_program};
+
+/// Anchor discriminator of a klend instruction
+fn discriminator(instruction: &str) -> [u8; 8] {
+    let mut discriminator = [0u8; 8];
+    discriminator
+        .copy_from_slice(&hash(format!("global:{}", instruction).as_bytes()).to_bytes()[..8]);
+    discriminator
+}
+
+/// PDA signing for the lending market's vaults
+pub fn lending_market_authority(program_id: &Pubkey, market: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(&[b"lma", market.as_ref()], program_id).0
+}
+
+/// Anchor passes the program id in place of an optional account that isn't set
+fn optional_account(program_id: &Pubkey, account: Pubkey) -> AccountMeta {
+    if account == Pubkey::default() {
+        AccountMeta::new_readonly(*program_id, false)
+    } else {
+        AccountMeta::new_readonly(account, false)
+    }
+}
+
+/// `refresh_reserve`, klend rejects deposits into a reserve refreshed in an earlier slot
+pub fn refresh_reserve(
+    program_id: &Pubkey,
+    config: &KaminoReserveConfig,
+    reserve: &ReserveAccount,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new(config.reserve, false),
+            AccountMeta::new_readonly(config.market, false),
+            optional_account(program_id, reserve.oracles.pyth),
+            optional_account(program_id, reserve.oracles.switchboard_price),
+            optional_account(program_id, reserve.oracles.switchboard_twap),
+            optional_account(program_id, reserve.oracles.scope_prices),
+        ],
+        data: discriminator("refresh_reserve").to_vec(),
+    }
+}
+
+/// `deposit_reserve_liquidity` of `amount` native units from the owner's token account
+///
+/// The owner receives the reserve's collateral (kTokens) in its associated
+/// token account, which has to exist.
+pub fn deposit_reserve_liquidity(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    config: &KaminoReserveConfig,
+    reserve: &ReserveAccount,
+    amount: u64,
+) -> Instruction {
+    let collateral_token_program = token_program();
+    let mut data = discriminator("deposit_reserve_liquidity").to_vec();
+    data.extend(amount.to_le_bytes());
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*owner, true),
+            AccountMeta::new(config.reserve, false),
+            AccountMeta::new_readonly(config.market, false),
+            AccountMeta::new_readonly(lending_market_authority(program_id, &config.market), false),
+            AccountMeta::new(reserve.liquidity_mint, false),
+            AccountMeta::new(reserve.liquidity_supply_vault, false),
+            AccountMeta::new(reserve.collateral_mint, false),
+            AccountMeta::new(
+                associated_token_address(
+                    owner,
+                    &reserve.liquidity_mint,
+                    &reserve.liquidity_token_program,
+                ),
+                false,
+            ),
+            AccountMeta::new(
+                associated_token_address(
+                    owner,
+                    &reserve.collateral_mint,
+                    &collateral_token_program,
+                ),
+                false,
+            ),
+            AccountMeta::new_readonly(collateral_token_program, false),
+            AccountMeta::new_readonly(reserve.liquidity_token_program, false),
+            AccountMeta::new_readonly(sysvar::instructions::id(), false),
+        ],
+        data,
+    }
+}
+
+/// Instructions of a Kamino deposit: the collateral account, a reserve refresh and the deposit
+pub fn deposit_instructions(
+    program_id: &Pubkey,
+    owner: &Pubkey,
+    config: &KaminoReserveConfig,
+    reserve: &ReserveAccount,
+    amount: u64,
+) -> Vec<Instruction> {
+    vec![
+        create_associated_token_account_idempotent(
+            owner,
+            owner,
+            &reserve.collateral_mint,
+            &token_program(),
+        ),
+        refresh_reserve(program_id, config, reserve),
+        deposit_reserve_liquidity(program_id, owner, config, reserve, amount),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deposit_reserve_liquidity() {
+        let program_id = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let config = KaminoReserveConfig::usdc();
+        let reserve = ReserveAccount::empty(&config);
+
+        let instruction = deposit_reserve_liquidity(&program_id, &owner, &config, &reserve, 1_000);
+        assert_eq!(instruction.accounts.len(), 12);
+        assert!(instruction.accounts[0].is_signer);
+        assert_eq!(&instruction.data[8..], &1_000u64.to_le_bytes());
+
+        // Oracles that aren't configured are passed as the program id
+        let refresh = refresh_reserve(&program_id, &config, &reserve);
+        assert!(refresh.accounts[2..]
+            .iter()
+            .all(|account| account.pubkey == program_id));
+    }
+}
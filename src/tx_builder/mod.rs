@@ -0,0 +1,181 @@
+use std::str::FromStr;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Serialize;
+use solana_sdk::{
+    hash::Hash,
+    instruction::{AccountMeta, Instruction},
+    message::Message,
+    pubkey::Pubkey,
+    system_program,
+    transaction::Transaction,
+};
+
+use crate::{
+    cluster::Cluster,
+    kamino::{reserve::KaminoReserveConfig, reserve_account::ReserveAccount},
+    rebalancing::{DepositToExecute, TransactionSystemDeposits},
+    risk_model::{Protocol, RiskCalculationError},
+};
+
+pub mod kamino;
+
+/// SPL Token program
+const TOKEN_PROGRAM_ID: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
+/// SPL Associated Token Account program
+const ASSOCIATED_TOKEN_PROGRAM_ID: &str = "ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL";
+/// `CreateIdempotent` of the associated token account program
+const CREATE_IDEMPOTENT: u8 = 1;
+
+pub fn token_program() -> Pubkey {
+    Pubkey::from_str(TOKEN_PROGRAM_ID).unwrap()
+}
+
+fn associated_token_program() -> Pubkey {
+    Pubkey::from_str(ASSOCIATED_TOKEN_PROGRAM_ID).unwrap()
+}
+
+/// Associated token account of `owner` for `mint`
+pub fn associated_token_address(owner: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+    Pubkey::find_program_address(
+        &[owner.as_ref(), token_program.as_ref(), mint.as_ref()],
+        &associated_token_program(),
+    )
+    .0
+}
+
+/// Creates `owner`'s associated token account for `mint`, a no-op if it exists
+pub fn create_associated_token_account_idempotent(
+    payer: &Pubkey,
+    owner: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: associated_token_program(),
+        accounts: vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(associated_token_address(owner, mint, token_program), false),
+            AccountMeta::new_readonly(*owner, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(*token_program, false),
+        ],
+        data: vec![CREATE_IDEMPOTENT],
+    }
+}
+
+/// Serialized transaction, as wallets take them for signing
+pub fn encode_transaction(transaction: &Transaction) -> Result<String, RiskCalculationError> {
+    let bytes = bincode::serialize(transaction)
+        .map_err(|e| RiskCalculationError::CustomError(format!("transaction: {}", e)))?;
+    Ok(STANDARD.encode(bytes))
+}
+
+/// An unsigned transaction executing one deposit
+#[derive(Debug, Clone, Serialize)]
+pub struct BuiltTransaction {
+    pub protocol: Protocol,
+    pub amount: u64,
+    /// Base64 of the unsigned transaction, with the owner as fee payer
+    pub transaction: String,
+}
+
+/// The deposits of a plan as transactions for the owner to sign and submit
+#[derive(Debug, Clone, Serialize)]
+pub struct BuiltDeposits {
+    pub owner: String,
+    /// The transactions expire with this blockhash, after about a minute
+    pub recent_blockhash: String,
+    pub transactions: Vec<BuiltTransaction>,
+    /// Deposits into protocols transactions can't be built for yet
+    pub unsupported: Vec<DepositToExecute>,
+}
+
+/// Builds one unsigned transaction per deposit of the plan
+///
+/// Only Kamino deposits into `kamino_reserve` are supported so far, the other
+/// deposits are returned as unsupported.
+pub fn build_deposit_transactions(
+    owner: &Pubkey,
+    deposits: &TransactionSystemDeposits,
+    kamino_reserve: &KaminoReserveConfig,
+    reserve_account: &ReserveAccount,
+    recent_blockhash: Hash,
+) -> Result<BuiltDeposits, RiskCalculationError> {
+    let kamino_program_id = Pubkey::from_str(Cluster::global().kamino_program_id())
+        .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
+    let mut transactions = Vec::new();
+    let mut unsupported = Vec::new();
+    for deposit in &deposits.deposits_to_execute {
+        if deposit.amount == 0 {
+            continue;
+        }
+        let instructions = match deposit.protocol {
+            Protocol::Kamino => kamino::deposit_instructions(
+                &kamino_program_id,
+                owner,
+                kamino_reserve,
+                reserve_account,
+                deposit.amount,
+            ),
+            _ => {
+                unsupported.push(deposit.clone());
+                continue;
+            }
+        };
+        let message = Message::new_with_blockhash(&instructions, Some(owner), &recent_blockhash);
+        transactions.push(BuiltTransaction {
+            protocol: deposit.protocol.clone(),
+            amount: deposit.amount,
+            transaction: encode_transaction(&Transaction::new_unsigned(message))?,
+        });
+    }
+    Ok(BuiltDeposits {
+        owner: owner.to_string(),
+        recent_blockhash: recent_blockhash.to_string(),
+        transactions,
+        unsupported,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assets::Asset, bps::Bps};
+
+    #[test]
+    fn test_build_deposit_transactions() {
+        let owner = Pubkey::new_unique();
+        let config = KaminoReserveConfig::usdc();
+        let reserve = ReserveAccount::empty(&config);
+        let deposit = |protocol, amount| DepositToExecute {
+            protocol,
+            amount,
+            allocation_basis_points: Bps(5_000),
+            feasibility: None,
+        };
+        let deposits = TransactionSystemDeposits {
+            asset: Asset::Usdc,
+            deposits_to_execute: vec![
+                deposit(Protocol::Kamino, 500),
+                deposit(Protocol::Marginfy, 500),
+            ],
+            redistribution_note: None,
+            applied_caps: Vec::new(),
+        };
+
+        let built =
+            build_deposit_transactions(&owner, &deposits, &config, &reserve, Hash::new_unique())
+                .unwrap();
+        assert_eq!(built.transactions.len(), 1);
+        assert_eq!(built.unsupported.len(), 1);
+
+        let bytes = STANDARD.decode(&built.transactions[0].transaction).unwrap();
+        let transaction: Transaction = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(transaction.message.account_keys[0], owner);
+        assert_eq!(transaction.message.instructions.len(), 3);
+        // Unsigned, the wallet fills in its signature
+        assert_eq!(transaction.signatures, vec![Default::default()]);
+    }
+}
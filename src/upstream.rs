@@ -0,0 +1,264 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{risk_model::RiskCalculationError, server::parse_positive_env};
+
+static UPSTREAM_CONFIG: OnceLock<UpstreamConfig> = OnceLock::new();
+static BREAKERS: [CircuitBreaker; 4] = [
+    CircuitBreaker::new(),
+    CircuitBreaker::new(),
+    CircuitBreaker::new(),
+    CircuitBreaker::new(),
+];
+
+/// A dependency called while serving requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Upstream {
+    /// Helius or the fallback RPC endpoints
+    Rpc,
+    KaminoApi,
+    DefiLlama,
+    Redis,
+}
+
+impl Upstream {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Upstream::Rpc => "rpc",
+            Upstream::KaminoApi => "kamino_api",
+            Upstream::DefiLlama => "defillama",
+            Upstream::Redis => "redis",
+        }
+    }
+
+    fn breaker(self) -> &'static CircuitBreaker {
+        &BREAKERS[self as usize]
+    }
+}
+
+/// How long each dependency gets to answer, and when its circuit breaker opens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamConfig {
+    /// Account scans are the slowest calls, they still end before the 120s cache locks expire
+    pub rpc_timeout: Duration,
+    /// Kamino API and DefiLlama
+    pub api_timeout: Duration,
+    pub redis_timeout: Duration,
+    /// Consecutive failures opening a dependency's breaker
+    pub failure_threshold: u32,
+    /// How long an open breaker fails calls before letting one through again
+    pub open_for: Duration,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            rpc_timeout: Duration::from_secs(90),
+            api_timeout: Duration::from_secs(15),
+            redis_timeout: Duration::from_secs(2),
+            failure_threshold: 5,
+            open_for: Duration::from_secs(30),
+        }
+    }
+}
+
+impl UpstreamConfig {
+    /// The configuration read at first use
+    pub fn global() -> &'static Self {
+        UPSTREAM_CONFIG
+            .get_or_init(|| Self::from_env().expect("upstream timeouts must be positive integers"))
+    }
+
+    /// Reads `UPSTREAM_RPC_TIMEOUT_SECS`, `UPSTREAM_API_TIMEOUT_SECS`,
+    /// `UPSTREAM_REDIS_TIMEOUT_MS`, `CIRCUIT_BREAKER_FAILURES` and
+    /// `CIRCUIT_BREAKER_OPEN_SECS`, the defaults for those not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let positive = parse_positive_env::<u64>;
+        let default = UpstreamConfig::default();
+        Ok(UpstreamConfig {
+            rpc_timeout: positive("UPSTREAM_RPC_TIMEOUT_SECS")?
+                .map_or(default.rpc_timeout, Duration::from_secs),
+            api_timeout: positive("UPSTREAM_API_TIMEOUT_SECS")?
+                .map_or(default.api_timeout, Duration::from_secs),
+            redis_timeout: positive("UPSTREAM_REDIS_TIMEOUT_MS")?
+                .map_or(default.redis_timeout, Duration::from_millis),
+            failure_threshold: positive("CIRCUIT_BREAKER_FAILURES")?
+                .map_or(default.failure_threshold, |failures| failures as u32),
+            open_for: positive("CIRCUIT_BREAKER_OPEN_SECS")?
+                .map_or(default.open_for, Duration::from_secs),
+        })
+    }
+
+    fn timeout(&self, upstream: Upstream) -> Duration {
+        match upstream {
+            Upstream::Rpc => self.rpc_timeout,
+            Upstream::KaminoApi | Upstream::DefiLlama => self.api_timeout,
+            Upstream::Redis => self.redis_timeout,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    Closed,
+    /// Failing calls fast since the instant
+    Open(Instant),
+    /// A single trial call went through at the instant, the others fail fast
+    HalfOpen(Instant),
+}
+
+/// Counts a dependency's consecutive failures, failing calls fast once there
+/// are too many
+///
+/// After `open_for` a single trial call goes through. Its success closes the
+/// breaker, its failure opens it for another `open_for`. A trial whose outcome
+/// is never recorded, e.g. a dropped call, is replaced after `open_for`.
+#[derive(Debug)]
+struct CircuitBreaker {
+    failures: AtomicU32,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    const fn new() -> Self {
+        CircuitBreaker {
+            failures: AtomicU32::new(0),
+            state: Mutex::new(BreakerState::Closed),
+        }
+    }
+
+    /// Whether a call may go through at `now`, taking the trial call when there's one
+    fn allows(&self, now: Instant, open_for: Duration) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        match *state {
+            BreakerState::Closed => true,
+            BreakerState::Open(since) | BreakerState::HalfOpen(since)
+                if now.duration_since(since) >= open_for =>
+            {
+                *state = BreakerState::HalfOpen(now);
+                true
+            }
+            BreakerState::Open(_) | BreakerState::HalfOpen(_) => false,
+        }
+    }
+
+    /// Records a call's outcome, returns whether it opened the breaker
+    fn record(&self, succeeded: bool, now: Instant, failure_threshold: u32) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if succeeded {
+            self.failures.store(0, Ordering::Relaxed);
+            *state = BreakerState::Closed;
+            return false;
+        }
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < failure_threshold && *state == BreakerState::Closed {
+            return false;
+        }
+        *state = BreakerState::Open(now);
+        true
+    }
+}
+
+/// Whether `error` means the dependency is unreachable or failing, rather
+/// than it answering with data we can't use
+fn is_outage(error: &RiskCalculationError) -> bool {
+    matches!(
+        error,
+        RiskCalculationError::RequestError(_)
+            | RiskCalculationError::RpcCallError(_)
+            | RiskCalculationError::RedisError(_)
+            | RiskCalculationError::UpstreamUnavailable(_)
+    )
+}
+
+/// Calls `upstream`, giving up after its timeout
+///
+/// While the dependency's breaker is open, fails right away without calling
+/// it. Timeouts and breaker failures are [`RiskCalculationError::UpstreamUnavailable`],
+/// which cached pillars fall back to their last known metrics on.
+pub async fn call<T, E, F>(upstream: Upstream, future: F) -> Result<T, RiskCalculationError>
+where
+    E: Into<RiskCalculationError>,
+    F: Future<Output = Result<T, E>>,
+{
+    let config = UpstreamConfig::global();
+    let breaker = upstream.breaker();
+    if !breaker.allows(Instant::now(), config.open_for) {
+        return Err(RiskCalculationError::UpstreamUnavailable(format!(
+            "{} circuit breaker is open",
+            upstream.as_str()
+        )));
+    }
+    let timeout = config.timeout(upstream);
+    let result = match tokio::time::timeout(timeout, future).await {
+        Ok(result) => result.map_err(Into::into),
+        Err(_) => Err(RiskCalculationError::UpstreamUnavailable(format!(
+            "{} didn't answer within {:?}",
+            upstream.as_str(),
+            timeout
+        ))),
+    };
+    let succeeded = !matches!(&result, Err(e) if is_outage(e));
+    if breaker.record(succeeded, Instant::now(), config.failure_threshold) {
+        tracing::warn!(
+            upstream = upstream.as_str(),
+            "Circuit breaker opened for {:?}",
+            config.open_for
+        );
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let breaker = CircuitBreaker::new();
+        let open_for = Duration::from_secs(30);
+        let start = Instant::now();
+        assert!(!breaker.record(false, start, 3));
+        assert!(!breaker.record(false, start, 3));
+        assert!(breaker.allows(start, open_for));
+        assert!(breaker.record(false, start, 3));
+        assert!(!breaker.allows(start + Duration::from_secs(10), open_for));
+
+        // Half open, a single trial call goes through and its failure opens it again
+        let retry = start + open_for;
+        assert!(breaker.allows(retry, open_for));
+        assert!(!breaker.allows(retry, open_for));
+        assert!(breaker.record(false, retry, 3));
+        assert!(!breaker.allows(retry + Duration::from_secs(1), open_for));
+
+        let retry = retry + open_for;
+        assert!(breaker.allows(retry, open_for));
+        assert!(!breaker.allows(retry + Duration::from_secs(1), open_for));
+        assert!(!breaker.record(true, retry, 3));
+        assert!(breaker.allows(retry, open_for));
+        assert!(breaker.allows(retry, open_for));
+        assert!(!breaker.record(false, retry, 3));
+
+        // A trial that never reports back is replaced
+        let breaker = CircuitBreaker::new();
+        assert!(breaker.record(false, start, 1));
+        assert!(breaker.allows(start + open_for, open_for));
+        assert!(!breaker.allows(start + open_for, open_for));
+        assert!(breaker.allows(start + open_for * 2, open_for));
+
+        assert!(is_outage(&RiskCalculationError::UpstreamUnavailable(
+            String::new()
+        )));
+        assert!(!is_outage(&RiskCalculationError::ParseError(String::new())));
+        assert_eq!(
+            UpstreamConfig::default().timeout(Upstream::Redis),
+            Duration::from_secs(2)
+        );
+    }
+}
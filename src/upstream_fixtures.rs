@@ -0,0 +1,287 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind, Result as ClientResult},
+    nonblocking::rpc_client::RpcClient,
+    rpc_client::RpcClientConfig,
+    rpc_request::RpcRequest,
+    rpc_sender::{RpcSender, RpcTransportStats},
+};
+use solana_sdk::commitment_config::CommitmentConfig;
+
+use crate::{risk_model::RiskCalculationError, rpc_budget::CreditBudget};
+
+static UPSTREAM_FIXTURES: OnceLock<UpstreamFixtures> = OnceLock::new();
+
+/// Where responses are kept when `UPSTREAM_FIXTURES_DIR` isn't set
+const DEFAULT_DIR: &str = "fixtures/upstream";
+/// When the responses of a directory were recorded
+const MANIFEST: &str = "manifest.json";
+
+/// What happens to the responses of the external APIs and the RPC
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FixtureMode {
+    /// Requests go upstream, nothing is kept
+    #[default]
+    Off,
+    /// Requests go upstream and their responses are written to disk
+    Record,
+    /// Requests are answered with the responses written to disk, never going upstream
+    Replay,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    recorded_at: DateTime<Utc>,
+}
+
+/// A request and the raw response it got
+#[derive(Debug, Serialize, Deserialize)]
+struct Fixture {
+    request: Value,
+    response: Value,
+}
+
+/// Recorded upstream responses, so a whole risk computation can be replayed
+///
+/// Every response is kept in its own file, named after its kind and the hash
+/// of its request. Requests depend on the time, e.g. the range of the Kamino
+/// metrics history, so while recording or replaying the time is stopped at
+/// the recording's, see [`UpstreamFixtures::now`].
+#[derive(Debug)]
+pub struct UpstreamFixtures {
+    pub mode: FixtureMode,
+    pub dir: PathBuf,
+    pub recorded_at: DateTime<Utc>,
+}
+
+impl UpstreamFixtures {
+    /// The fixtures read at first use
+    pub fn global() -> &'static Self {
+        UPSTREAM_FIXTURES.get_or_init(|| {
+            Self::from_env().expect("upstream fixtures configuration must be valid")
+        })
+    }
+
+    /// Reads `UPSTREAM_FIXTURES`, one of `off`, `record` and `replay`, `off`
+    /// when not set, and the directory `UPSTREAM_FIXTURES_DIR`
+    ///
+    /// Replaying still needs the RPC endpoints configured, they're never called.
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let mode = match std::env::var("UPSTREAM_FIXTURES").as_deref() {
+            Err(_) | Ok("off") => FixtureMode::Off,
+            Ok("record") => FixtureMode::Record,
+            Ok("replay") => FixtureMode::Replay,
+            Ok(other) => {
+                return Err(RiskCalculationError::ParseError(format!(
+                    "invalid UPSTREAM_FIXTURES {:?}, expected off, record or replay",
+                    other
+                )))
+            }
+        };
+        let dir = std::env::var("UPSTREAM_FIXTURES_DIR").unwrap_or_else(|_| DEFAULT_DIR.into());
+        Self::open(mode, dir)
+    }
+
+    /// Starts a recording in `dir`, or reads when the one in it was made
+    pub fn open(mode: FixtureMode, dir: impl Into<PathBuf>) -> Result<Self, RiskCalculationError> {
+        let dir = dir.into();
+        let manifest = dir.join(MANIFEST);
+        let recorded_at = match mode {
+            FixtureMode::Off => Utc::now(),
+            FixtureMode::Record => {
+                let recorded_at = Utc::now();
+                std::fs::create_dir_all(&dir).map_err(|e| io_error(&dir, e))?;
+                let manifest_json = serde_json::to_string_pretty(&Manifest { recorded_at })?;
+                std::fs::write(&manifest, manifest_json).map_err(|e| io_error(&manifest, e))?;
+                tracing::info!("Recording upstream responses to {}", dir.display());
+                recorded_at
+            }
+            FixtureMode::Replay => {
+                let stored =
+                    std::fs::read_to_string(&manifest).map_err(|e| io_error(&manifest, e))?;
+                let Manifest { recorded_at } = serde_json::from_str(&stored)?;
+                tracing::info!(
+                    "Replaying upstream responses recorded at {} from {}",
+                    recorded_at,
+                    dir.display()
+                );
+                recorded_at
+            }
+        };
+        Ok(UpstreamFixtures {
+            mode,
+            dir,
+            recorded_at,
+        })
+    }
+
+    /// The time of the recording while recording or replaying
+    pub fn now(&self) -> Option<DateTime<Utc>> {
+        (self.mode != FixtureMode::Off).then_some(self.recorded_at)
+    }
+
+    fn path(&self, kind: &str, request: &Value) -> PathBuf {
+        let hash = anchor_client::solana_sdk::hash::hash(request.to_string().as_bytes());
+        self.dir.join(format!("{}-{}.json", kind, hash))
+    }
+
+    async fn record(
+        &self,
+        kind: &str,
+        request: Value,
+        response: Value,
+    ) -> Result<(), RiskCalculationError> {
+        let path = self.path(kind, &request);
+        let fixture = serde_json::to_string_pretty(&Fixture { request, response })?;
+        tokio::fs::write(&path, fixture)
+            .await
+            .map_err(|e| io_error(&path, e))
+    }
+
+    async fn replay(&self, kind: &str, request: &Value) -> Result<Value, RiskCalculationError> {
+        let path = self.path(kind, request);
+        let stored = match tokio::fs::read_to_string(&path).await {
+            Ok(stored) => stored,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(RiskCalculationError::UpstreamUnavailable(format!(
+                    "no recorded {} response for {}",
+                    kind, request
+                )))
+            }
+            Err(e) => return Err(io_error(&path, e)),
+        };
+        let fixture: Fixture = serde_json::from_str(&stored)?;
+        Ok(fixture.response)
+    }
+}
+
+fn io_error(path: &Path, error: std::io::Error) -> RiskCalculationError {
+    RiskCalculationError::CustomError(format!("{}: {}", path.display(), error))
+}
+
+/// Body of a GET of `url`, recorded or replayed as configured
+pub async fn get_text(url: &str) -> Result<String, RiskCalculationError> {
+    let fixtures = UpstreamFixtures::global();
+    let request = json!({ "url": url });
+    if fixtures.mode == FixtureMode::Replay {
+        return match fixtures.replay("http", &request).await? {
+            Value::String(body) => Ok(body),
+            other => Err(RiskCalculationError::ParseError(format!(
+                "recorded body of {} isn't text: {}",
+                url, other
+            ))),
+        };
+    }
+    let body = reqwest::get(url)
+        .await
+        .map_err(RiskCalculationError::from)?
+        .text()
+        .await
+        .map_err(RiskCalculationError::from)?;
+    if fixtures.mode == FixtureMode::Record {
+        fixtures
+            .record("http", request, Value::String(body.clone()))
+            .await?;
+    }
+    Ok(body)
+}
+
+/// RPC client of `url` whose calls are recorded or replayed as configured,
+/// and charged to the [`CreditBudget`] when going upstream
+pub fn rpc_client(url: String) -> RpcClient {
+    let fixtures = UpstreamFixtures::global();
+    RpcClient::new_sender(
+        FixtureSender {
+            client: RpcClient::new(url),
+            fixtures,
+        },
+        RpcClientConfig::with_commitment(CommitmentConfig::default()),
+    )
+}
+
+/// Sends RPC calls through `client`, recording or replaying their results
+///
+/// Calls that aren't replayed are charged to the [`CreditBudget`].
+///
+/// Calls are told apart by their method and parameters, not the endpoint, so
+/// recordings don't hold API keys and replay whichever endpoint is configured.
+struct FixtureSender {
+    client: RpcClient,
+    fixtures: &'static UpstreamFixtures,
+}
+
+#[async_trait]
+impl RpcSender for FixtureSender {
+    async fn send(&self, request: RpcRequest, params: Value) -> ClientResult<Value> {
+        let key = json!({ "method": request.to_string(), "params": params });
+        let fixture_error =
+            |e: RiskCalculationError| ClientError::from(ClientErrorKind::Custom(e.to_string()));
+        if self.fixtures.mode == FixtureMode::Replay {
+            return self
+                .fixtures
+                .replay("rpc", &key)
+                .await
+                .map_err(fixture_error);
+        }
+        CreditBudget::global().charge(&request);
+        let result: Value = self.client.send(request, params).await?;
+        if self.fixtures.mode == FixtureMode::Record {
+            self.fixtures
+                .record("rpc", key, result.clone())
+                .await
+                .map_err(fixture_error)?;
+        }
+        Ok(result)
+    }
+
+    fn get_transport_stats(&self) -> RpcTransportStats {
+        self.client.get_transport_stats()
+    }
+
+    fn url(&self) -> String {
+        self.client.url()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let dir = std::env::temp_dir().join(format!("upstream-fixtures-{}", std::process::id()));
+        let recording = UpstreamFixtures::open(FixtureMode::Record, &dir).unwrap();
+        let request = json!({ "url": "https://api.kamino.finance/metrics" });
+        recording
+            .record("http", request.clone(), Value::String("{}".into()))
+            .await
+            .unwrap();
+
+        let replay = UpstreamFixtures::open(FixtureMode::Replay, &dir).unwrap();
+        assert_eq!(replay.now(), Some(recording.recorded_at));
+        assert_eq!(
+            replay.replay("http", &request).await.unwrap(),
+            Value::String("{}".into())
+        );
+        let other = json!({ "url": "https://api.kamino.finance/other" });
+        assert!(matches!(
+            replay.replay("http", &other).await,
+            Err(RiskCalculationError::UpstreamUnavailable(_))
+        ));
+        assert_eq!(
+            UpstreamFixtures::open(FixtureMode::Off, &dir)
+                .unwrap()
+                .now(),
+            None
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
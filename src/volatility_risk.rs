@@ -1,9 +1,13 @@
 #![allow(unused)]
-use chrono::{DateTime, Timelike, Utc};
-use serde::Deserialize;
+use chrono::{DateTime, DurationRound, Timelike, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
 
-use crate::risk_model::VolatilityRiskMetrics;
+use crate::{
+    market_history::MarketPoint,
+    risk_model::{RiskCalculationError, VolatilityRiskMetrics},
+};
 
 /// Calculates the combined lending pool risk based on APY and utilization rate volatilities
 ///
@@ -30,33 +34,43 @@ pub fn calculate_lending_pool_risk(
     weight_apy_coefficient: f64,
     weight_utilization_coefficient: f64,
 ) -> Option<VolatilityRiskMetrics> {
-    let sigma_apy = calculate_sigma_apy(yields)?;
-    let sigma_util = calculate_sigma_utilization(utilization_rates)?;
+    let current_apy = yields.last().copied();
+    // Scores are weighted from the per-sample sigmas
+    let sigma_apy = calculate_sigma_apy(yields, 1.0)?;
+    let sigma_util = calculate_sigma_utilization(utilization_rates, 1.0)?;
 
     Some(VolatilityRiskMetrics {
         sigma_apy,
         sigma_utilization: sigma_util,
         volatility_risk: weight_apy_coefficient * sigma_apy
             + weight_utilization_coefficient * sigma_util,
+        surface: Vec::new(),
+        downside: None,
+        current_apy,
+        history_window: None,
     })
 }
 
-/// Calculates the annualized volatility (sigma) of APY values
+/// Calculates the volatility (sigma) of APY values
 ///
 /// # Formula
-/// σ = √(1/24 * ∑(APY_i - APY_avg)²)
+/// σ = √(1/n * ∑(APY_i - APY_avg)²) * a
 /// where:
-/// - σ (sigma) represents the annualized volatility
+/// - σ (sigma) represents the volatility
 /// - APY_i is the current APY value
 /// - APY_avg is the average of historical APY values
-/// - The factor 1/24 is used to annualize the daily volatility
+/// - n is the number of values, 24 for the hourly values of the last 24 hours,
+///   so sigmas of different lookbacks are comparable
+/// - a is the annualization factor, see [`SampleFrequency::annualization_factor`],
+///   1 for the per-sample volatility
 ///
 /// # Parameters
-/// * `yields` - Vector of historical APY values over the last 24 hours
+/// * `yields` - Vector of historical APY values over the lookback
+/// * `annualization_factor` - Scales the per-sample sigma
 ///
 /// # Returns
-/// Returns the annualized volatility as a f64
-fn calculate_sigma_apy(yields: Vec<f64>) -> Option<f64> {
+/// Returns the volatility as a f64
+fn calculate_sigma_apy(yields: Vec<f64>, annualization_factor: f64) -> Option<f64> {
     let n = yields.len() as f64;
     if n < 2.0 {
         // Need at least 2 points to calculate volatility
@@ -70,27 +84,29 @@ fn calculate_sigma_apy(yields: Vec<f64>) -> Option<f64> {
         .map(|&apy_i| (apy_i - avg_apy).powi(2))
         .sum::<f64>();
 
-    // Calculate annualized volatility (sigma)
-    // The factor 1/24 is used to annualize the daily volatility
-    Some((sum_squared_diff / 24.0).sqrt())
+    Some((sum_squared_diff / n).sqrt() * annualization_factor)
 }
 
-/// Calculates the annualized volatility (sigma) of utilization rates
+/// Calculates the volatility (sigma) of utilization rates
 ///
 /// # Formula
-/// σ_U = √(1/24 * ∑(U_i - U_avg)²)
+/// σ_U = √(1/n * ∑(U_i - U_avg)²) * a
 /// where:
-/// - σ_U represents the annualized volatility of utilization rates
+/// - σ_U represents the volatility of utilization rates
 /// - U_i is the current utilization rate
 /// - U_avg is the average of historical utilization rates
-/// - The factor 1/24 is used to annualize the daily volatility
+/// - n and a are as in [`calculate_sigma_apy`]
 ///
 /// # Parameters
-/// * `utilization_rates` - Vector of historical utilization rates over the last 24 hours
+/// * `utilization_rates` - Vector of historical utilization rates over the lookback
+/// * `annualization_factor` - Scales the per-sample sigma
 ///
 /// # Returns
-/// Returns the annualized volatility as a f64
-fn calculate_sigma_utilization(utilization_rates: Vec<f64>) -> Option<f64> {
+/// Returns the volatility as a f64
+fn calculate_sigma_utilization(
+    utilization_rates: Vec<f64>,
+    annualization_factor: f64,
+) -> Option<f64> {
     let n = utilization_rates.len() as f64;
     if n < 2.0 {
         // Need at least 2 points to calculate volatility
@@ -104,7 +120,721 @@ fn calculate_sigma_utilization(utilization_rates: Vec<f64>) -> Option<f64> {
         .map(|&util_i| (util_i - avg_utilization).powi(2))
         .sum::<f64>();
 
-    // Calculate annualized volatility (sigma)
-    // The factor 1/24 is used to annualize the daily volatility
-    Some((sum_squared_diff / 24.0).sqrt())
+    Some((sum_squared_diff / n).sqrt() * annualization_factor)
+}
+
+/// Window of history a volatility is computed over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum Lookback {
+    #[serde(rename = "24h")]
+    Day,
+    #[serde(rename = "7d")]
+    Week,
+    #[serde(rename = "30d")]
+    Month,
+}
+
+impl Lookback {
+    pub const ALL: [Lookback; 3] = [Lookback::Day, Lookback::Week, Lookback::Month];
+    /// History needed to compute every lookback
+    pub const LONGEST_HOURS: u64 = 30 * 24;
+
+    pub fn hours(&self) -> u64 {
+        match self {
+            Lookback::Day => 24,
+            Lookback::Week => 7 * 24,
+            Lookback::Month => 30 * 24,
+        }
+    }
+}
+
+/// Volatility over a single lookback
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct LookbackVolatility {
+    pub lookback: Lookback,
+    pub sigma_apy: f64,
+    pub sigma_utilization: f64,
+    pub volatility_risk: f64,
+}
+
+/// How much each lookback contributes to the blended volatility
+///
+/// The short lookback catches sudden spikes, the long ones structural volatility.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct VolatilityBlendWeights {
+    pub day: f64,
+    pub week: f64,
+    pub month: f64,
+}
+
+impl Default for VolatilityBlendWeights {
+    fn default() -> Self {
+        VolatilityBlendWeights {
+            day: 0.5,
+            week: 0.3,
+            month: 0.2,
+        }
+    }
+}
+
+impl VolatilityBlendWeights {
+    pub fn new(day: f64, week: f64, month: f64) -> Result<Self, RiskCalculationError> {
+        let weights = VolatilityBlendWeights { day, week, month };
+        if [day, week, month].iter().any(|weight| *weight < 0.0)
+            || ((day + week + month) - 1.0).abs() > 1e-9
+        {
+            return Err(RiskCalculationError::ParseError(format!(
+                "volatility blend weights must be non-negative and sum to 1: {:?}",
+                weights
+            )));
+        }
+        Ok(weights)
+    }
+
+    /// Reads `VOLATILITY_BLEND_WEIGHTS` as `day,week,month`, e.g. `0.5,0.3,0.2`
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let Ok(weights) = std::env::var("VOLATILITY_BLEND_WEIGHTS") else {
+            return Ok(Self::default());
+        };
+        let weights = weights
+            .split(',')
+            .map(|weight| weight.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
+        match weights[..] {
+            [day, week, month] => Self::new(day, week, month),
+            _ => Err(RiskCalculationError::ParseError(
+                "VOLATILITY_BLEND_WEIGHTS needs 3 comma separated weights".to_string(),
+            )),
+        }
+    }
+
+    pub fn weight(&self, lookback: Lookback) -> f64 {
+        match lookback {
+            Lookback::Day => self.day,
+            Lookback::Week => self.week,
+            Lookback::Month => self.month,
+        }
+    }
+}
+
+/// Fewest samples a lookback is computed from, coarser histories don't cover it
+const MIN_LOOKBACK_POINTS: u64 = 6;
+
+/// Recent yield and utilization history, oldest first
+#[derive(Debug, Clone, Copy)]
+pub struct SampledHistory<'a> {
+    pub yields: &'a [f64],
+    pub utilization_rates: &'a [f64],
+    pub sample_interval_hours: u64,
+}
+
+impl SampledHistory<'_> {
+    /// The tails of the history covering `lookback`, `None` when it's sampled
+    /// too coarsely or misses more than 10% of the lookback's points
+    fn covering(&self, lookback: Lookback) -> Option<(&[f64], &[f64])> {
+        let points = lookback.hours() / self.sample_interval_hours;
+        if points < MIN_LOOKBACK_POINTS {
+            return None;
+        }
+        let available = self.yields.len().min(self.utilization_rates.len());
+        if (available as f64) < points as f64 * 0.9 {
+            return None;
+        }
+        let points = (points as usize).min(available);
+        Some((
+            &self.yields[self.yields.len() - points..],
+            &self.utilization_rates[self.utilization_rates.len() - points..],
+        ))
+    }
+}
+
+/// Calculates the volatility over every lookback and blends them into one
+///
+/// `yields` and `utilization_rates` are the most recent history, oldest first,
+/// sampled every `sample_interval_hours`, see [`calculate_volatility_surface_from`].
+pub fn calculate_volatility_surface(
+    yields: &[f64],
+    utilization_rates: &[f64],
+    sample_interval_hours: u64,
+    weight_apy_coefficient: f64,
+    weight_utilization_coefficient: f64,
+    blend: &VolatilityBlendWeights,
+) -> Option<VolatilityRiskMetrics> {
+    calculate_volatility_surface_from(
+        &[SampledHistory {
+            yields,
+            utilization_rates,
+            sample_interval_hours,
+        }],
+        weight_apy_coefficient,
+        weight_utilization_coefficient,
+        blend,
+    )
+}
+
+/// Calculates the volatility over every lookback from the histories covering
+/// them and blends them into one
+///
+/// Each lookback uses the tail of the first history that samples it at least
+/// 6 times and has 90% of those points, finest first. Lookbacks no history
+/// covers are left out and the blend weights of the remaining ones are
+/// renormalized. The top level sigmas and risk are the blended values, the
+/// current APY is the first history's and the downside the last one's.
+pub fn calculate_volatility_surface_from(
+    histories: &[SampledHistory],
+    weight_apy_coefficient: f64,
+    weight_utilization_coefficient: f64,
+    blend: &VolatilityBlendWeights,
+) -> Option<VolatilityRiskMetrics> {
+    let surface: Vec<LookbackVolatility> = Lookback::ALL
+        .iter()
+        .filter_map(|lookback| {
+            let (yields, utilization_rates) = histories
+                .iter()
+                .find_map(|history| history.covering(*lookback))?;
+            let risk = calculate_lending_pool_risk(
+                yields.to_vec(),
+                utilization_rates.to_vec(),
+                weight_apy_coefficient,
+                weight_utilization_coefficient,
+            )?;
+            Some(LookbackVolatility {
+                lookback: *lookback,
+                sigma_apy: risk.sigma_apy,
+                sigma_utilization: risk.sigma_utilization,
+                volatility_risk: risk.volatility_risk,
+            })
+        })
+        .collect();
+
+    let total_weight: f64 = surface.iter().map(|v| blend.weight(v.lookback)).sum();
+    if total_weight <= 0.0 {
+        return None;
+    }
+    let blended = |value: fn(&LookbackVolatility) -> f64| {
+        surface
+            .iter()
+            .map(|v| value(v) * blend.weight(v.lookback))
+            .sum::<f64>()
+            / total_weight
+    };
+    Some(VolatilityRiskMetrics {
+        sigma_apy: blended(|v| v.sigma_apy),
+        sigma_utilization: blended(|v| v.sigma_utilization),
+        volatility_risk: blended(|v| v.volatility_risk),
+        surface,
+        downside: calculate_downside_risk(histories.last()?.yields),
+        current_apy: histories.first()?.yields.last().copied(),
+        history_window: None,
+    })
+}
+
+/// History a volatility was computed over, as the upstream returned it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct HistoryWindow {
+    /// Timestamp of the first sample
+    pub start: DateTime<Utc>,
+    /// Timestamp of the last sample
+    pub end: DateTime<Utc>,
+    /// Samples after filling gaps
+    pub points: usize,
+    /// Samples missing from the upstream's history, filled with the one before them
+    pub filled_gaps: usize,
+}
+
+/// Fills the samples missing from `points`, expected every `interval_hours`
+///
+/// A missing sample repeats the one before it, so every lookback covers the
+/// time it's meant to. Points not after the one before them are dropped.
+/// `None` without points.
+pub fn fill_history_gaps(
+    points: &[MarketPoint],
+    interval_hours: u64,
+) -> Option<(Vec<MarketPoint>, HistoryWindow)> {
+    let interval_minutes = (interval_hours * 60) as f64;
+    let mut filled: Vec<MarketPoint> = Vec::with_capacity(points.len());
+    let mut filled_gaps = 0;
+    for point in points {
+        if let Some(previous) = filled.last().cloned() {
+            let elapsed = (point.timestamp - previous.timestamp).num_minutes() as f64;
+            if elapsed <= 0.0 {
+                continue;
+            }
+            let missing = ((elapsed / interval_minutes).round() as usize).saturating_sub(1);
+            for i in 1..=missing {
+                filled.push(MarketPoint {
+                    timestamp: previous.timestamp
+                        + chrono::Duration::hours((interval_hours as usize * i) as i64),
+                    ..previous.clone()
+                });
+            }
+            filled_gaps += missing;
+        }
+        filled.push(point.clone());
+    }
+    let window = HistoryWindow {
+        start: filled.first()?.timestamp,
+        end: filled.last()?.timestamp,
+        points: filled.len(),
+        filled_gaps,
+    };
+    Some((filled, window))
+}
+
+/// Longest window [`calculate_window_volatility`] accepts
+const MAX_WINDOW_HOURS: u64 = 365 * 24;
+
+/// How often the history a window's volatility is computed over is sampled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFrequency {
+    Hour,
+    Day,
+}
+
+impl SampleFrequency {
+    pub fn parse(frequency: &str) -> Result<Self, RiskCalculationError> {
+        match frequency.trim() {
+            "hour" => Ok(SampleFrequency::Hour),
+            "day" => Ok(SampleFrequency::Day),
+            other => Err(RiskCalculationError::InvalidParameter(format!(
+                "invalid volatility_frequency {:?}, expected hour or day",
+                other
+            ))),
+        }
+    }
+
+    pub fn hours(&self) -> u64 {
+        match self {
+            SampleFrequency::Hour => 1,
+            SampleFrequency::Day => 24,
+        }
+    }
+
+    /// Scales a per-sample sigma to a yearly one, the square root of the samples per year
+    pub fn annualization_factor(&self) -> f64 {
+        ((365 * 24 / self.hours()) as f64).sqrt()
+    }
+}
+
+/// Window of history, and its sampling, a client asked the volatility of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VolatilityWindow {
+    pub hours: u64,
+    pub frequency: SampleFrequency,
+}
+
+impl VolatilityWindow {
+    /// Parses a window like `48h` or `14d`, sampled at `frequency`
+    ///
+    /// Without a frequency, windows in hours are sampled hourly and those in
+    /// days daily. The window must cover at least 2 samples.
+    pub fn parse(window: &str, frequency: Option<&str>) -> Result<Self, RiskCalculationError> {
+        let invalid = || {
+            RiskCalculationError::InvalidParameter(format!(
+                "invalid volatility_window {:?}, expected e.g. 48h or 14d",
+                window
+            ))
+        };
+        let window = window.trim();
+        let count = |count: &str| count.parse::<u64>().map_err(|_| invalid());
+        let (hours, default_frequency) = if let Some(hours) = window.strip_suffix('h') {
+            (count(hours)?, SampleFrequency::Hour)
+        } else if let Some(days) = window.strip_suffix('d') {
+            (count(days)?.saturating_mul(24), SampleFrequency::Day)
+        } else {
+            return Err(invalid());
+        };
+        let frequency = frequency.map_or(Ok(default_frequency), SampleFrequency::parse)?;
+        if hours > MAX_WINDOW_HOURS
+            || hours % frequency.hours() != 0
+            || hours / frequency.hours() < 2
+        {
+            return Err(RiskCalculationError::InvalidParameter(format!(
+                "volatility_window must be a multiple of the sampling interval covering 2 \
+                 samples to {} days",
+                MAX_WINDOW_HOURS / 24
+            )));
+        }
+        Ok(VolatilityWindow { hours, frequency })
+    }
+
+    /// Samples the window is made of
+    pub fn points(&self) -> usize {
+        (self.hours / self.frequency.hours()) as usize
+    }
+}
+
+/// Volatility over a window requested with the risk model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct WindowVolatility {
+    pub window_hours: u64,
+    pub frequency: SampleFrequency,
+    /// Samples found in the window, at least 90% of those it's made of
+    pub points: usize,
+    /// Per-sample sigmas, on the scale of the volatility pillar
+    pub sigma_apy: f64,
+    pub sigma_utilization: f64,
+    pub annualized_sigma_apy: f64,
+    pub annualized_sigma_utilization: f64,
+    /// Weighted from the per-sample sigmas like the pillar's risk
+    pub volatility_risk: f64,
+}
+
+/// Calculates the volatility over `window` of `points`, oldest first
+///
+/// The history is resampled to the window's frequency, keeping the last point
+/// of every hour or day, and the window ends at the latest one. Fails when
+/// the history has less than 90% of the window's samples, e.g. hourly
+/// samples of a daily history.
+pub fn calculate_window_volatility(
+    points: &[MarketPoint],
+    window: &VolatilityWindow,
+    weight_apy_coefficient: f64,
+    weight_utilization_coefficient: f64,
+) -> Result<WindowVolatility, RiskCalculationError> {
+    let interval = chrono::Duration::hours(window.frequency.hours() as i64);
+    let mut samples: Vec<(DateTime<Utc>, &MarketPoint)> = Vec::new();
+    for point in points {
+        let bucket = point
+            .timestamp
+            .duration_trunc(interval)
+            .map_err(|e| RiskCalculationError::CustomError(e.to_string()))?;
+        match samples.last_mut() {
+            Some((last, sample)) if *last == bucket => *sample = point,
+            _ => samples.push((bucket, point)),
+        }
+    }
+    let since = samples
+        .last()
+        .map(|(latest, _)| *latest - interval * (window.points() as i32 - 1));
+    let samples: Vec<&MarketPoint> = samples
+        .into_iter()
+        .filter(|(bucket, _)| Some(*bucket) >= since)
+        .map(|(_, point)| point)
+        .collect();
+    if (samples.len() as f64) < window.points() as f64 * 0.9 || samples.len() < 2 {
+        return Err(RiskCalculationError::NotReady(format!(
+            "only {} of the {} samples of the volatility window are in the history",
+            samples.len(),
+            window.points()
+        )));
+    }
+
+    let yields: Vec<f64> = samples.iter().map(|p| p.supply_apy_percent).collect();
+    let utilization_rates: Vec<f64> = samples.iter().map(|p| p.utilization_percent).collect();
+    // At least 2 samples, both sigmas are defined
+    let sigmas = |annualization_factor| {
+        (
+            calculate_sigma_apy(yields.clone(), annualization_factor).unwrap_or_default(),
+            calculate_sigma_utilization(utilization_rates.clone(), annualization_factor)
+                .unwrap_or_default(),
+        )
+    };
+    let (sigma_apy, sigma_utilization) = sigmas(1.0);
+    let (annualized_sigma_apy, annualized_sigma_utilization) =
+        sigmas(window.frequency.annualization_factor());
+    Ok(WindowVolatility {
+        window_hours: window.hours,
+        frequency: window.frequency,
+        points: samples.len(),
+        sigma_apy,
+        sigma_utilization,
+        annualized_sigma_apy,
+        annualized_sigma_utilization,
+        volatility_risk: weight_apy_coefficient * sigma_apy
+            + weight_utilization_coefficient * sigma_utilization,
+    })
+}
+
+/// Confidence levels VaR is reported at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum Confidence {
+    #[serde(rename = "95%")]
+    P95,
+    #[serde(rename = "99%")]
+    P99,
+}
+
+impl Confidence {
+    pub const ALL: [Confidence; 2] = [Confidence::P95, Confidence::P99];
+
+    /// Share of outcomes beyond the VaR, in percent
+    pub fn tail_percent(&self) -> usize {
+        match self {
+            Confidence::P95 => 5,
+            Confidence::P99 => 1,
+        }
+    }
+
+    /// One-sided standard normal quantile of the level
+    pub fn z_score(&self) -> f64 {
+        match self {
+            Confidence::P95 => 1.644_853_6,
+            Confidence::P99 => 2.326_347_9,
+        }
+    }
+}
+
+/// Largest APY drop between two samples, in APY points, that is only exceeded
+/// with probability `1 - confidence`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ValueAtRisk {
+    pub confidence: Confidence,
+    /// Assuming normally distributed APY changes
+    pub parametric: f64,
+    /// Empirical quantile of the observed APY changes
+    pub historical: f64,
+}
+
+/// Downside-oriented view of the APY series, which sigma alone can't convey
+/// since it weighs rises and drops alike
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DownsideRisk {
+    pub value_at_risk: Vec<ValueAtRisk>,
+    /// Largest fall from a running peak of the APY, in APY points
+    pub max_drawdown: f64,
+}
+
+/// Calculates VaR at every [`Confidence`] and the maximum drawdown of `yields`
+///
+/// VaR is taken over the changes between consecutive samples, so it's per sample
+/// interval, and reported as a positive loss (0 if even the tail is a gain).
+/// Needs at least 3 points, i.e. 2 changes.
+pub fn calculate_downside_risk(yields: &[f64]) -> Option<DownsideRisk> {
+    let changes: Vec<f64> = yields.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    if changes.len() < 2 {
+        return None;
+    }
+    let n = changes.len() as f64;
+    let mean = changes.iter().sum::<f64>() / n;
+    let sigma = (changes.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / n).sqrt();
+
+    let mut sorted = changes.clone();
+    sorted.sort_by(f64::total_cmp);
+    let value_at_risk = Confidence::ALL
+        .iter()
+        .map(|confidence| {
+            // The k-th worst change with k = ceil(tail * n), at least the worst one
+            let k = (changes.len() * confidence.tail_percent())
+                .div_ceil(100)
+                .max(1);
+            ValueAtRisk {
+                confidence: *confidence,
+                parametric: (confidence.z_score() * sigma - mean).max(0.0),
+                historical: (-sorted[k - 1]).max(0.0),
+            }
+        })
+        .collect();
+
+    let mut peak = f64::NEG_INFINITY;
+    let max_drawdown = yields.iter().fold(0.0_f64, |max_drawdown, &apy| {
+        peak = peak.max(apy);
+        max_drawdown.max(peak - apy)
+    });
+
+    Some(DownsideRisk {
+        value_at_risk,
+        max_drawdown,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_volatility_surface() {
+        // 30 days of hourly data: calm for 29 days, then a volatile last day
+        let yields: Vec<f64> = (0..720)
+            .map(|i| {
+                if i < 696 {
+                    5.0
+                } else if i % 2 == 0 {
+                    4.0
+                } else {
+                    6.0
+                }
+            })
+            .collect();
+        let utilization_rates = vec![80.0; 720];
+        let blend = VolatilityBlendWeights::default();
+        let metrics =
+            calculate_volatility_surface(&yields, &utilization_rates, 1, 1.0, 0.0, &blend).unwrap();
+
+        assert_eq!(metrics.surface.len(), 3);
+        let sigma = |lookback| {
+            metrics
+                .surface
+                .iter()
+                .find(|v| v.lookback == lookback)
+                .unwrap()
+                .sigma_apy
+        };
+        assert!((sigma(Lookback::Day) - 1.0).abs() < 1e-9);
+        assert!(sigma(Lookback::Week) < sigma(Lookback::Day));
+        assert!(sigma(Lookback::Month) < sigma(Lookback::Week));
+        let expected =
+            0.5 * sigma(Lookback::Day) + 0.3 * sigma(Lookback::Week) + 0.2 * sigma(Lookback::Month);
+        assert!((metrics.volatility_risk - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_window_volatility() {
+        let window = VolatilityWindow::parse("2d", None).unwrap();
+        assert_eq!(window.frequency, SampleFrequency::Day);
+        assert_eq!(window.points(), 2);
+        assert_eq!(VolatilityWindow::parse("48h", Some("day")).unwrap(), window);
+        assert!(VolatilityWindow::parse("36h", Some("day")).is_err());
+        assert!(VolatilityWindow::parse("1d", None).is_err());
+        assert!(VolatilityWindow::parse("2w", None).is_err());
+        // A multi-byte last character is rejected, not split inside
+        assert!(VolatilityWindow::parse("2é", None).is_err());
+        assert!(VolatilityWindow::parse("h", None).is_err());
+        assert!(SampleFrequency::parse("minute").is_err());
+
+        // 3 days of hourly points, the last of each day alternating between 4% and 6%
+        let start: DateTime<Utc> = "2024-05-01T00:00:00Z".parse().unwrap();
+        let points: Vec<MarketPoint> = (0..72)
+            .map(|i| MarketPoint {
+                timestamp: start + chrono::Duration::hours(i),
+                supply_apy_percent: if i == 23 || i == 71 { 4.0 } else { 6.0 },
+                utilization_percent: 80.0,
+                tvl_usd: None,
+            })
+            .collect();
+        let daily = VolatilityWindow::parse("3d", None).unwrap();
+        let volatility = calculate_window_volatility(&points, &daily, 1.0, 0.0).unwrap();
+        assert_eq!(volatility.points, 3);
+        // 4, 6, 4: sigma of sqrt(8/9)
+        let sigma = (8.0f64 / 9.0).sqrt();
+        assert!((volatility.sigma_apy - sigma).abs() < 1e-9);
+        assert!((volatility.annualized_sigma_apy - sigma * 365f64.sqrt()).abs() < 1e-9);
+        assert_eq!(volatility.sigma_utilization, 0.0);
+        assert!((volatility.volatility_risk - sigma).abs() < 1e-9);
+
+        // Hourly samples of the daily points fall short of the window
+        let daily_points: Vec<MarketPoint> = points.into_iter().step_by(24).collect();
+        let hourly = VolatilityWindow::parse("48h", None).unwrap();
+        assert!(calculate_window_volatility(&daily_points, &hourly, 1.0, 0.0).is_err());
+    }
+
+    #[test]
+    fn test_fill_history_gaps() {
+        let start: DateTime<Utc> = "2024-05-01T00:00:00Z".parse().unwrap();
+        let point = |hour: i64, apy: f64| MarketPoint {
+            timestamp: start + chrono::Duration::hours(hour),
+            supply_apy_percent: apy,
+            utilization_percent: 80.0,
+            tvl_usd: None,
+        };
+        // Hours 2 and 3 are missing, hour 4 is returned twice
+        let points = [
+            point(0, 1.0),
+            point(1, 2.0),
+            point(4, 5.0),
+            point(4, 6.0),
+            point(5, 7.0),
+        ];
+        let (filled, window) = fill_history_gaps(&points, 1).unwrap();
+        assert_eq!(
+            filled
+                .iter()
+                .map(|p| p.supply_apy_percent)
+                .collect::<Vec<_>>(),
+            vec![1.0, 2.0, 2.0, 2.0, 5.0, 7.0]
+        );
+        assert_eq!(filled[3].timestamp, start + chrono::Duration::hours(3));
+        assert_eq!(
+            window,
+            HistoryWindow {
+                start,
+                end: start + chrono::Duration::hours(5),
+                points: 6,
+                filled_gaps: 2,
+            }
+        );
+        assert!(fill_history_gaps(&[], 24).is_none());
+    }
+
+    #[test]
+    fn test_downside_risk() {
+        // Rises by 1 nineteen times, then drops by 10 once
+        let mut yields: Vec<f64> = (0..20).map(|i| i as f64).collect();
+        yields.push(9.0);
+        let downside = calculate_downside_risk(&yields).unwrap();
+        assert_eq!(downside.max_drawdown, 10.0);
+
+        let var = |confidence| {
+            downside
+                .value_at_risk
+                .iter()
+                .find(|var| var.confidence == confidence)
+                .unwrap()
+                .clone()
+        };
+        // 1 of the 20 changes is the drop: it's the 5% tail
+        assert_eq!(var(Confidence::P95).historical, 10.0);
+        assert_eq!(var(Confidence::P99).historical, 10.0);
+        // mean 0.45, sigma ~2.40
+        let sigma = (19.0 * 0.55f64.powi(2) + 10.45f64.powi(2)).sqrt() / 20f64.sqrt();
+        assert!((var(Confidence::P99).parametric - (2.326_347_9 * sigma - 0.45)).abs() < 1e-9);
+        assert!(var(Confidence::P95).parametric < var(Confidence::P99).parametric);
+
+        // Steadily rising yields have no downside
+        let rising = calculate_downside_risk(&[1.0, 2.0, 3.0, 4.0]).unwrap();
+        assert_eq!(rising.max_drawdown, 0.0);
+        assert!(rising.value_at_risk.iter().all(|var| var.historical == 0.0));
+        assert!(calculate_downside_risk(&[1.0, 2.0]).is_none());
+    }
+
+    #[test]
+    fn test_volatility_surface_skips_missing_lookbacks() {
+        // 10 daily points only cover the 7d lookback, 24h would take two of them
+        let yields: Vec<f64> = (0..10).map(|i| i as f64).collect();
+        let metrics = calculate_volatility_surface(
+            &yields,
+            &yields,
+            24,
+            0.7,
+            0.3,
+            &VolatilityBlendWeights::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            metrics
+                .surface
+                .iter()
+                .map(|v| v.lookback)
+                .collect::<Vec<_>>(),
+            vec![Lookback::Week]
+        );
+
+        // The 24h lookback is taken from the hourly history
+        let hourly = vec![5.0; 24];
+        let metrics = calculate_volatility_surface_from(
+            &[
+                SampledHistory {
+                    yields: &hourly,
+                    utilization_rates: &hourly,
+                    sample_interval_hours: 1,
+                },
+                SampledHistory {
+                    yields: &yields,
+                    utilization_rates: &yields,
+                    sample_interval_hours: 24,
+                },
+            ],
+            0.7,
+            0.3,
+            &VolatilityBlendWeights::default(),
+        )
+        .unwrap();
+        assert_eq!(metrics.surface[0].lookback, Lookback::Day);
+        assert_eq!(metrics.surface[0].sigma_apy, 0.0);
+        assert!(metrics.surface[1].sigma_apy > 0.0);
+        assert_eq!(metrics.current_apy, Some(5.0));
+        assert!(VolatilityBlendWeights::new(0.5, 0.5, 0.5).is_err());
+    }
 }
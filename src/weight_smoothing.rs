@@ -0,0 +1,404 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use chrono::{DateTime, Duration, Utc};
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bps::Bps,
+    cache_lock::with_lock,
+    cache_schema::versioned_key,
+    precomputed::ProfileWeights,
+    proposals::max_weight_change,
+    rebalancing::LiveRiskModel,
+    redis_connection::shared_connection,
+    risk_model::{Protocol, RiskCalculationError, RiskProfile},
+    snapshot::RiskSnapshot,
+};
+
+static WEIGHT_SMOOTHING: OnceLock<WeightSmoothingConfig> = OnceLock::new();
+
+/// Keeps recommended weights from thrashing on noisy risk scores
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WeightSmoothingConfig {
+    /// Weight of the latest scores in the smoothed ones, 1 to disable smoothing
+    pub score_alpha: f64,
+    /// Weights are kept until a protocol's weight changes by more than this
+    pub min_change: Bps,
+    /// How long after a protocol's weight moved it can't move back
+    pub reversal_cooldown: Duration,
+}
+
+impl Default for WeightSmoothingConfig {
+    fn default() -> Self {
+        WeightSmoothingConfig {
+            score_alpha: 0.5,
+            min_change: Bps(50),
+            reversal_cooldown: Duration::hours(6),
+        }
+    }
+}
+
+impl WeightSmoothingConfig {
+    /// The configuration read at first use
+    pub fn global() -> &'static Self {
+        WEIGHT_SMOOTHING
+            .get_or_init(|| Self::from_env().expect("weight smoothing configuration must be valid"))
+    }
+
+    /// Reads `RISK_SCORE_SMOOTHING`, `WEIGHT_MIN_CHANGE_BPS` and
+    /// `WEIGHT_REVERSAL_COOLDOWN_HOURS`, the defaults for those not set
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let var = |name: &str| std::env::var(name).ok();
+        let invalid = |name: &str, value: &str, expected: &str| {
+            RiskCalculationError::ParseError(format!("{} must be {}: {:?}", name, expected, value))
+        };
+        let mut config = WeightSmoothingConfig::default();
+        if let Some(alpha) = var("RISK_SCORE_SMOOTHING") {
+            config.score_alpha = match alpha.trim().parse::<f64>() {
+                Ok(alpha) if alpha > 0.0 && alpha <= 1.0 => alpha,
+                _ => return Err(invalid("RISK_SCORE_SMOOTHING", &alpha, "in (0, 1]")),
+            };
+        }
+        if let Some(bps) = var("WEIGHT_MIN_CHANGE_BPS") {
+            config.min_change = bps
+                .trim()
+                .parse()
+                .map(Bps)
+                .map_err(|_| invalid("WEIGHT_MIN_CHANGE_BPS", &bps, "basis points"))?;
+        }
+        if let Some(hours) = var("WEIGHT_REVERSAL_COOLDOWN_HOURS") {
+            config.reversal_cooldown =
+                hours.trim().parse().map(Duration::hours).map_err(|_| {
+                    invalid("WEIGHT_REVERSAL_COOLDOWN_HOURS", &hours, "whole hours")
+                })?;
+        }
+        Ok(config)
+    }
+}
+
+/// Smoothed scores and the last recommended weights, carried across refreshes
+fn state_key() -> String {
+    versioned_key("weight_smoothing")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Up,
+    Down,
+}
+
+/// The last change of a protocol's weight
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WeightMove {
+    pub direction: Direction,
+    pub at: DateTime<Utc>,
+}
+
+/// Weights last recommended for a profile
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProfileTrend {
+    pub weights: Vec<(Protocol, Bps)>,
+    pub moves: HashMap<Protocol, WeightMove>,
+}
+
+/// Why a profile's previous weights were kept
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hold {
+    BelowMinimum {
+        change: Bps,
+    },
+    Reversal {
+        protocol: Protocol,
+        since: DateTime<Utc>,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SmoothingState {
+    /// Exponentially smoothed overall risk per protocol
+    pub risks: HashMap<Protocol, f64>,
+    pub profiles: HashMap<RiskProfile, ProfileTrend>,
+    /// Snapshot the state was last advanced by, a snapshot only advances it once
+    #[serde(default)]
+    pub last: Option<SmoothedSnapshot>,
+}
+
+/// Weights smoothed for a snapshot, returned again when it's smoothed again
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SmoothedSnapshot {
+    pub snapshot_id: String,
+    pub weights: Vec<ProfileWeights>,
+}
+
+impl SmoothingState {
+    /// Blends the latest overall risks into the smoothed ones and returns them
+    ///
+    /// A protocol without a smoothed risk starts from its latest one.
+    pub fn smooth_risks(
+        &mut self,
+        latest: &[(Protocol, f64)],
+        alpha: f64,
+    ) -> HashMap<Protocol, f64> {
+        for (protocol, risk) in latest {
+            self.risks
+                .entry(protocol.clone())
+                .and_modify(|smoothed| *smoothed = alpha * risk + (1.0 - alpha) * *smoothed)
+                .or_insert(*risk);
+        }
+        latest
+            .iter()
+            .map(|(protocol, _)| (protocol.clone(), self.risks[protocol]))
+            .collect()
+    }
+
+    /// The weights to recommend for `profile` instead of `proposed`, and why
+    /// the previous ones were kept if they were
+    ///
+    /// Weights over a different set of protocols are never held: a protocol
+    /// becoming unavailable must lose its weight right away.
+    pub fn stabilize(
+        &mut self,
+        profile: &RiskProfile,
+        proposed: Vec<(Protocol, Bps)>,
+        config: &WeightSmoothingConfig,
+        now: DateTime<Utc>,
+    ) -> (Vec<(Protocol, Bps)>, Option<Hold>) {
+        let trend = self.profiles.entry(profile.clone()).or_default();
+        let weight_of = |weights: &[(Protocol, Bps)], protocol: &Protocol| {
+            weights
+                .iter()
+                .find(|(candidate, _)| candidate == protocol)
+                .map(|(_, weight)| weight.0)
+        };
+        let same_protocols = !trend.weights.is_empty()
+            && trend.weights.len() == proposed.len()
+            && proposed
+                .iter()
+                .all(|(protocol, _)| weight_of(&trend.weights, protocol).is_some());
+
+        let mut moves = Vec::new();
+        for (protocol, weight) in &proposed {
+            // Protocols gaining their first weight haven't moved yet
+            let Some(previous) = weight_of(&trend.weights, protocol) else {
+                continue;
+            };
+            if weight.0 != previous {
+                let direction = if weight.0 > previous {
+                    Direction::Up
+                } else {
+                    Direction::Down
+                };
+                moves.push((protocol.clone(), direction));
+            }
+        }
+        if same_protocols {
+            let (_, change) = max_weight_change(&trend.weights, &proposed);
+            if change <= config.min_change {
+                return (trend.weights.clone(), Some(Hold::BelowMinimum { change }));
+            }
+            let reversal = moves.iter().find_map(|(protocol, direction)| {
+                trend.moves.get(protocol).and_then(|last| {
+                    (last.direction != *direction && now - last.at < config.reversal_cooldown).then(
+                        || Hold::Reversal {
+                            protocol: protocol.clone(),
+                            since: last.at,
+                        },
+                    )
+                })
+            });
+            if reversal.is_some() {
+                return (trend.weights.clone(), reversal);
+            }
+        }
+        for (protocol, direction) in moves {
+            trend
+                .moves
+                .insert(protocol, WeightMove { direction, at: now });
+        }
+        trend.weights = proposed.clone();
+        (proposed, None)
+    }
+
+    /// Weights of every profile for `snapshot`, advancing the state once per snapshot
+    ///
+    /// Scores are smoothed before weighting, then each profile keeps its
+    /// previous weights while the change is small or would reverse a recent
+    /// move. Weights of the risk-adjusted yield objective aren't derived from
+    /// the scores and are only held. A snapshot smoothed again gets the same
+    /// weights, without advancing the state.
+    pub fn advance(
+        &mut self,
+        snapshot: &RiskSnapshot,
+        config: &WeightSmoothingConfig,
+    ) -> Result<Vec<ProfileWeights>, RiskCalculationError> {
+        if let Some(last) = &self.last {
+            if last.snapshot_id == snapshot.snapshot_id {
+                return Ok(last.weights.clone());
+            }
+        }
+        let latest: Vec<(Protocol, f64)> = snapshot
+            .comparison
+            .ranking
+            .iter()
+            .map(|assessment| {
+                (
+                    assessment.protocol.clone(),
+                    assessment.risk_metrics.overall_risk.overall_risk,
+                )
+            })
+            .collect();
+        let risks = self.smooth_risks(&latest, config.score_alpha);
+        let model = LiveRiskModel::from_snapshot(snapshot)?.with_risks(&risks);
+
+        let mut weights = Vec::new();
+        for profile in RiskProfile::ALL {
+            let mut profile_weights =
+                ProfileWeights::from_model(profile.clone(), snapshot, &model)?;
+            let (stable, hold) = self.stabilize(
+                &profile,
+                profile_weights.weights,
+                config,
+                snapshot.computed_at,
+            );
+            if let Some(hold) = hold {
+                tracing::info!("Keeping the {} weights: {:?}", profile.as_str(), hold);
+            }
+            profile_weights.weights = stable;
+            weights.push(profile_weights);
+        }
+        self.last = Some(SmoothedSnapshot {
+            snapshot_id: snapshot.snapshot_id.clone(),
+            weights: weights.clone(),
+        });
+        Ok(weights)
+    }
+}
+
+/// Weights of every profile for a fresh snapshot, smoothed against the previous refreshes
+///
+/// See [`SmoothingState::advance`]. The stored state is updated under a lock,
+/// so concurrent refreshes don't advance it from the same state.
+pub async fn smoothed_weights(
+    redis_client: &redis::Client,
+    snapshot: &RiskSnapshot,
+) -> Result<Vec<ProfileWeights>, RiskCalculationError> {
+    let config = WeightSmoothingConfig::global();
+    with_lock(Some(redis_client), &state_key(), async {
+        let mut connection = shared_connection(redis_client)
+            .await
+            .map_err(RiskCalculationError::from)?;
+        let stored: Option<String> = connection
+            .get(state_key())
+            .await
+            .map_err(RiskCalculationError::from)?;
+        let mut state: SmoothingState = stored
+            .map(|stored| serde_json::from_str(&stored))
+            .transpose()
+            .map_err(RiskCalculationError::SerdeError)?
+            .unwrap_or_default();
+        let weights = state.advance(snapshot, config)?;
+        let state = serde_json::to_string(&state).map_err(RiskCalculationError::SerdeError)?;
+        let _: () = connection
+            .set(state_key(), state)
+            .await
+            .map_err(RiskCalculationError::from)?;
+        Ok(weights)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::ProtocolComparison;
+
+    #[test]
+    fn test_weight_smoothing() {
+        let mut state = SmoothingState::default();
+        let risks = state.smooth_risks(&[(Protocol::Kamino, 40.0), (Protocol::Drift, 60.0)], 0.5);
+        assert_eq!(risks[&Protocol::Kamino], 40.0);
+        let risks = state.smooth_risks(&[(Protocol::Kamino, 60.0), (Protocol::Drift, 60.0)], 0.5);
+        assert_eq!(risks[&Protocol::Kamino], 50.0);
+        assert_eq!(risks[&Protocol::Drift], 60.0);
+
+        let config = WeightSmoothingConfig::default();
+        let start = DateTime::parse_from_rfc3339("2024-05-01T00:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let weights = |kamino: u64| {
+            vec![
+                (Protocol::Kamino, Bps(kamino)),
+                (Protocol::Drift, Bps(10_000 - kamino)),
+            ]
+        };
+        let profile = RiskProfile::Medium;
+
+        let (first, hold) = state.stabilize(&profile, weights(6000), &config, start);
+        assert_eq!((first, hold), (weights(6000), None));
+
+        let (kept, hold) =
+            state.stabilize(&profile, weights(6040), &config, start + Duration::hours(1));
+        assert_eq!(kept, weights(6000));
+        assert_eq!(hold, Some(Hold::BelowMinimum { change: Bps(40) }));
+
+        let (moved, hold) =
+            state.stabilize(&profile, weights(6500), &config, start + Duration::hours(2));
+        assert_eq!((moved, hold), (weights(6500), None));
+
+        // Moving Kamino back down within the cooldown is held
+        let (kept, hold) =
+            state.stabilize(&profile, weights(6000), &config, start + Duration::hours(3));
+        assert_eq!(kept, weights(6500));
+        assert!(matches!(hold, Some(Hold::Reversal { .. })));
+        let (moved, hold) =
+            state.stabilize(&profile, weights(6000), &config, start + Duration::hours(9));
+        assert_eq!((moved, hold), (weights(6000), None));
+
+        // A protocol dropping out is never held
+        let (moved, hold) = state.stabilize(
+            &profile,
+            vec![(Protocol::Kamino, Bps::FULL)],
+            &config,
+            start + Duration::hours(10),
+        );
+        assert_eq!((moved, hold), (vec![(Protocol::Kamino, Bps::FULL)], None));
+
+        let stored: SmoothingState =
+            serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(stored, state);
+    }
+
+    #[test]
+    fn test_advance_once_per_snapshot() {
+        let snapshot = RiskSnapshot {
+            snapshot_id: "2024-05-01T00".to_string(),
+            computed_at: Utc::now(),
+            comparison: ProtocolComparison {
+                ranking: Vec::new(),
+                unavailable: Vec::new(),
+            },
+        };
+        let weights = vec![ProfileWeights {
+            profile: RiskProfile::Medium,
+            snapshot_id: snapshot.snapshot_id.clone(),
+            computed_at: snapshot.computed_at,
+            weights: vec![(Protocol::Kamino, Bps::FULL)],
+            redistribution_note: None,
+            risk_scores: Vec::new(),
+        }];
+        let mut state = SmoothingState {
+            risks: HashMap::from([(Protocol::Kamino, 40.0)]),
+            last: Some(SmoothedSnapshot {
+                snapshot_id: snapshot.snapshot_id.clone(),
+                weights: weights.clone(),
+            }),
+            ..SmoothingState::default()
+        };
+        let before = state.clone();
+        let config = WeightSmoothingConfig::default();
+        assert_eq!(state.advance(&snapshot, &config).unwrap(), weights);
+        assert_eq!(state, before);
+    }
+}
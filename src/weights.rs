@@ -0,0 +1,223 @@
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    risk_model::{OverallRiskWeights, RiskCalculationError, ScoreNormalization},
+    volatility_risk::VolatilityBlendWeights,
+};
+
+static RISK_WEIGHTS: OnceLock<RiskWeightsConfig> = OnceLock::new();
+
+/// Weights of deposit concentration, utilization and TVL outflow within the liquidity pillar
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LiquidityWeights {
+    pub deposit_concentration: f64,
+    pub utilization: f64,
+    /// Left out of configurations written before it, which keep scoring without it
+    #[serde(default)]
+    pub tvl_outflow: f64,
+}
+
+impl Default for LiquidityWeights {
+    fn default() -> Self {
+        LiquidityWeights {
+            deposit_concentration: 0.34,
+            utilization: 0.51,
+            tvl_outflow: 0.15,
+        }
+    }
+}
+
+/// Weights of APY and utilization volatility within the volatility pillar
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct VolatilityWeights {
+    pub apy: f64,
+    pub utilization: f64,
+}
+
+impl Default for VolatilityWeights {
+    fn default() -> Self {
+        VolatilityWeights {
+            apy: 0.7,
+            utilization: 0.3,
+        }
+    }
+}
+
+/// Every weight of the risk model
+///
+/// Loaded once at startup from the TOML file at `RISK_WEIGHTS_CONFIG`, with any
+/// section left out falling back to the defaults, e.g.
+///
+/// ```toml
+/// [overall]
+/// liquidity = 0.5
+/// volatility = 0.2
+/// protocol = 0.3
+/// ```
+///
+/// Single weights can be overridden with env vars named after them (`W_LIQUIDITY`,
+/// `W_VOL_APY`, ...), the blend with `VOLATILITY_BLEND_WEIGHTS`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RiskWeightsConfig {
+    pub liquidity: LiquidityWeights,
+    pub volatility: VolatilityWeights,
+    pub overall: OverallRiskWeights,
+    pub volatility_blend: VolatilityBlendWeights,
+    /// How the pillars are brought to one scale before being weighted
+    pub normalization: ScoreNormalization,
+}
+
+impl RiskWeightsConfig {
+    /// The weights loaded at startup
+    ///
+    /// Panics if the configuration is invalid, which is why the server loads them
+    /// before serving anything.
+    pub fn global() -> &'static Self {
+        RISK_WEIGHTS
+            .get_or_init(|| Self::from_env().expect("risk weights configuration must be valid"))
+    }
+
+    pub fn from_toml(config: &str) -> Result<Self, RiskCalculationError> {
+        let config: RiskWeightsConfig =
+            toml::from_str(config).map_err(|e| RiskCalculationError::ParseError(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_env() -> Result<Self, RiskCalculationError> {
+        let mut config = match std::env::var("RISK_WEIGHTS_CONFIG") {
+            Ok(path) => {
+                let config = std::fs::read_to_string(&path).map_err(|e| {
+                    RiskCalculationError::CustomError(format!("reading {}: {}", path, e))
+                })?;
+                Self::from_toml(&config)?
+            }
+            Err(_) => Self::default(),
+        };
+        for (name, weight) in config.named_weights() {
+            if let Ok(value) = std::env::var(name) {
+                *weight = value
+                    .trim()
+                    .parse()
+                    .map_err(|e| RiskCalculationError::ParseError(format!("{}: {}", name, e)))?;
+            }
+        }
+        if std::env::var("VOLATILITY_BLEND_WEIGHTS").is_ok() {
+            config.volatility_blend = VolatilityBlendWeights::from_env()?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn named_weights(&mut self) -> [(&'static str, &mut f64); 9] {
+        [
+            ("W_LIQ_D_CONC", &mut self.liquidity.deposit_concentration),
+            ("W_LIQ_UTIL", &mut self.liquidity.utilization),
+            ("W_LIQ_TVL", &mut self.liquidity.tvl_outflow),
+            ("W_VOL_APY", &mut self.volatility.apy),
+            ("W_VOL_UTIL", &mut self.volatility.utilization),
+            ("W_LIQUIDITY", &mut self.overall.liquidity),
+            ("W_VOLATILITY", &mut self.overall.volatility),
+            ("W_PROTOCOL", &mut self.overall.protocol),
+            ("W_ORACLE", &mut self.overall.oracle),
+        ]
+    }
+
+    /// Every group of weights must be non-negative and sum to 1
+    pub fn validate(&self) -> Result<(), RiskCalculationError> {
+        let groups: [(&str, &[f64]); 3] = [
+            (
+                "liquidity",
+                &[
+                    self.liquidity.deposit_concentration,
+                    self.liquidity.utilization,
+                    self.liquidity.tvl_outflow,
+                ],
+            ),
+            (
+                "volatility",
+                &[self.volatility.apy, self.volatility.utilization],
+            ),
+            (
+                "overall",
+                &[
+                    self.overall.liquidity,
+                    self.overall.volatility,
+                    self.overall.protocol,
+                    self.overall.oracle,
+                ],
+            ),
+        ];
+        for (group, weights) in groups {
+            if weights.iter().any(|weight| *weight < 0.0)
+                || (weights.iter().sum::<f64>() - 1.0).abs() > 1e-9
+            {
+                return Err(RiskCalculationError::ParseError(format!(
+                    "{} weights must be non-negative and sum to 1: {:?}",
+                    group, weights
+                )));
+            }
+        }
+        let blend = self.volatility_blend;
+        VolatilityBlendWeights::new(blend.day, blend.week, blend.month)?;
+        self.normalization.validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_risk_weights_config() {
+        assert!(RiskWeightsConfig::default().validate().is_ok());
+
+        let config = RiskWeightsConfig::from_toml(
+            r#"
+            [overall]
+            liquidity = 0.5
+            volatility = 0.2
+            protocol = 0.3
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.overall.liquidity, 0.5);
+        assert_eq!(config.liquidity, LiquidityWeights::default());
+
+        assert!(RiskWeightsConfig::from_toml(
+            r#"
+            [volatility]
+            apy = 0.7
+            utilization = 0.7
+            "#,
+        )
+        .is_err());
+
+        let config = RiskWeightsConfig::from_toml(
+            r#"
+            [normalization.volatility]
+            method = "min_max"
+            min = 0.0
+            max = 20.0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.normalization.volatility.apply(5.0), 25.0);
+        assert_eq!(
+            config.normalization.liquidity,
+            ScoreNormalization::default().liquidity
+        );
+        assert!(RiskWeightsConfig::from_toml(
+            r#"
+            [normalization.volatility]
+            method = "logistic"
+            midpoint = 5.0
+            steepness = 0.0
+            "#,
+        )
+        .is_err());
+    }
+}